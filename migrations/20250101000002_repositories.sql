@@ -0,0 +1,7 @@
+-- Repositories table: every repository that has triggered a workflow
+CREATE TABLE IF NOT EXISTS repositories (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    full_name TEXT NOT NULL UNIQUE,
+    clone_url TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Record the branch (or PR ref) a workflow was triggered for
+ALTER TABLE workflows ADD COLUMN branch TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_workflows_repository ON workflows(repository, branch);
@@ -0,0 +1,2 @@
+-- Record the commit author so notifications can reach them
+ALTER TABLE workflows ADD COLUMN author_email TEXT;
@@ -0,0 +1,13 @@
+-- Notification targets per repository; they replace the globally configured
+-- targets of a notifier for that repository
+CREATE TABLE IF NOT EXISTS repository_notifications (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    repository_id INTEGER NOT NULL,
+    notifier TEXT NOT NULL,  -- email, slack, matrix, discord, telegram, ntfy, gotify
+    target TEXT NOT NULL,    -- address, channel, room, webhook URL, ... depending on the notifier
+    rules TEXT,              -- JSON encoded rules; the notifier's rules apply when NULL
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY (repository_id) REFERENCES repositories(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_repository_notifications_repository ON repository_notifications(repository_id);
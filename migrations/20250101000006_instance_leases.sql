@@ -0,0 +1,6 @@
+-- Leader lease held by the active icicle instance when several share a database
+CREATE TABLE IF NOT EXISTS instance_leases (
+    name TEXT PRIMARY KEY,
+    holder TEXT NOT NULL,       -- instance id of the current holder
+    expires_at INTEGER NOT NULL -- unix time after which another instance may take over
+);
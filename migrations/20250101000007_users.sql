@@ -0,0 +1,8 @@
+-- Users and the role governing what they may do: viewer, maintainer or admin.
+-- Credentials (API tokens, logins) reference these rows.
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    role TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
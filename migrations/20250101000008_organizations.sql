@@ -0,0 +1,32 @@
+-- Organizations group projects, and projects group repositories, so one
+-- instance can serve several teams
+CREATE TABLE IF NOT EXISTS organizations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    attic_cache_name TEXT,  -- cache the organization's builds are pushed to; the configured one when NULL
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS projects (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    organization_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    UNIQUE (organization_id, name),
+    FOREIGN KEY (organization_id) REFERENCES organizations(id)
+);
+
+-- Users belonging to an organization, who besides admins are the only ones to see
+-- its repositories, workflows and builds
+CREATE TABLE IF NOT EXISTS organization_members (
+    organization_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    PRIMARY KEY (organization_id, user_id),
+    FOREIGN KEY (organization_id) REFERENCES organizations(id),
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+-- Repositories outside any project are visible instance-wide
+ALTER TABLE repositories ADD COLUMN project_id INTEGER REFERENCES projects(id);
+
+CREATE INDEX IF NOT EXISTS idx_repositories_project ON repositories(project_id);
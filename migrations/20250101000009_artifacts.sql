@@ -0,0 +1,16 @@
+-- Files and directories published by builds through nix-support/hydra-build-products,
+-- copied out of the store into the artifact directory as <id>/<name>
+CREATE TABLE IF NOT EXISTS artifacts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    drv_path TEXT NOT NULL,
+    name TEXT NOT NULL,
+    kind TEXT NOT NULL,     -- file, doc, report, ... as declared by the build
+    subtype TEXT NOT NULL,  -- e.g. binary-dist, coverage, iso
+    is_dir INTEGER NOT NULL,
+    size INTEGER NOT NULL,  -- bytes, summed over all files of a directory
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY (drv_path) REFERENCES builds(drv_path)
+);
+
+CREATE INDEX IF NOT EXISTS idx_artifacts_drv_path ON artifacts(drv_path);
+CREATE INDEX IF NOT EXISTS idx_artifacts_created ON artifacts(created_at);
@@ -0,0 +1,14 @@
+-- Test cases reported by builds as JUnit XML in $out/junit/*.xml
+CREATE TABLE IF NOT EXISTS test_results (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    drv_path TEXT NOT NULL,
+    suite TEXT NOT NULL,
+    classname TEXT,
+    name TEXT NOT NULL,
+    status TEXT NOT NULL,  -- passed, failed, error, skipped
+    duration_ms INTEGER,
+    message TEXT,          -- failure message and output, truncated
+    FOREIGN KEY (drv_path) REFERENCES builds(drv_path)
+);
+
+CREATE INDEX IF NOT EXISTS idx_test_results_drv_path ON test_results(drv_path, status);
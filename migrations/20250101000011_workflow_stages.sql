@@ -0,0 +1,15 @@
+-- Ordered stages of a workflow, from the stages in the repository's .icicle.toml.
+-- Each stage evaluates and builds its attribute set once the previous one succeeded.
+CREATE TABLE IF NOT EXISTS workflow_stages (
+    workflow_id INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    attribute_set TEXT NOT NULL,
+    approval INTEGER NOT NULL,  -- whether the stage waits for manual approval
+    status TEXT NOT NULL,       -- pending, waiting_approval, running, succeeded, failed, skipped, canceled
+    approved_by TEXT,
+    started_at INTEGER,
+    finished_at INTEGER,
+    PRIMARY KEY (workflow_id, position),
+    FOREIGN KEY (workflow_id) REFERENCES workflows(id)
+);
@@ -0,0 +1,15 @@
+-- Deployments run for workflows that succeeded on a deploy target's branch
+CREATE TABLE IF NOT EXISTS deployments (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    workflow_id INTEGER NOT NULL,
+    target TEXT NOT NULL,
+    status TEXT NOT NULL,  -- queued, running, success, failed
+    output TEXT,           -- combined stdout and stderr of the deploy command
+    created_at INTEGER NOT NULL,
+    started_at INTEGER,
+    finished_at INTEGER,
+    FOREIGN KEY (workflow_id) REFERENCES workflows(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_deployments_target ON deployments(target, id);
+CREATE INDEX IF NOT EXISTS idx_deployments_workflow ON deployments(workflow_id);
@@ -0,0 +1,15 @@
+-- Locked revisions of a workflow's direct flake inputs, read from flake.lock
+CREATE TABLE IF NOT EXISTS workflow_inputs (
+    workflow_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    type TEXT NOT NULL,        -- github, git, path, tarball, ...
+    source TEXT NOT NULL,      -- the unlocked reference, e.g. github:NixOS/nixpkgs/nixos-unstable
+    rev TEXT,                  -- NULL for inputs without a revision, such as paths
+    last_modified INTEGER,
+    previous_rev TEXT,         -- rev in the previous workflow of the same branch
+    changed INTEGER NOT NULL,  -- whether the rev differs from the previous workflow's
+    PRIMARY KEY (workflow_id, name),
+    FOREIGN KEY (workflow_id) REFERENCES workflows(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_workflow_inputs_changed ON workflow_inputs(changed, workflow_id);
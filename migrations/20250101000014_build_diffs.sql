@@ -0,0 +1,8 @@
+-- How a failed build's derivation differs from the last successful build of the same attribute
+CREATE TABLE IF NOT EXISTS build_diffs (
+    drv_path TEXT PRIMARY KEY,
+    baseline_drv_path TEXT NOT NULL,  -- the last successful build it is compared with
+    changes TEXT NOT NULL,            -- JSON list of changed inputs, sources and env
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY (drv_path) REFERENCES builds(drv_path)
+);
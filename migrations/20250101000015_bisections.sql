@@ -0,0 +1,27 @@
+-- Searches for the commit that broke an attribute between the last good and first bad workflow
+CREATE TABLE IF NOT EXISTS bisections (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    workflow_id INTEGER NOT NULL,  -- the first failed workflow
+    attribute TEXT NOT NULL,       -- the failed attribute, within attribute_set
+    attribute_set TEXT NOT NULL,
+    good_commit TEXT NOT NULL,
+    bad_commit TEXT NOT NULL,
+    status TEXT NOT NULL,          -- queued, running, found, failed
+    culprit_commit TEXT,
+    error TEXT,
+    created_at INTEGER NOT NULL,
+    finished_at INTEGER,
+    FOREIGN KEY (workflow_id) REFERENCES workflows(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_bisections_workflow ON bisections(workflow_id);
+
+-- Commits built while bisecting
+CREATE TABLE IF NOT EXISTS bisection_steps (
+    bisection_id INTEGER NOT NULL,
+    commit_sha TEXT NOT NULL,
+    good INTEGER NOT NULL,         -- whether the attribute built at this commit
+    finished_at INTEGER NOT NULL,
+    PRIMARY KEY (bisection_id, commit_sha),
+    FOREIGN KEY (bisection_id) REFERENCES bisections(id)
+);
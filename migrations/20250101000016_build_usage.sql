@@ -0,0 +1,14 @@
+-- Build time charged to repositories, counted against their monthly quotas.
+-- A build requested by several repositories is charged to each.
+CREATE TABLE IF NOT EXISTS build_usage (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    drv_path TEXT NOT NULL,
+    repository TEXT NOT NULL,
+    seconds INTEGER NOT NULL,
+    finished_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_build_usage_repository ON build_usage(repository, finished_at);
+
+-- Jobs of workflows over quota only run when nothing else is ready
+ALTER TABLE workflows ADD COLUMN deprioritized INTEGER NOT NULL DEFAULT 0;
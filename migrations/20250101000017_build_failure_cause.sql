@@ -0,0 +1,2 @@
+-- Cause recognized in the log of a failed build, e.g. out_of_memory or hash_mismatch
+ALTER TABLE builds ADD COLUMN failure_cause TEXT;
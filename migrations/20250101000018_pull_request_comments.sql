@@ -0,0 +1,7 @@
+-- The comment summarizing build results that icicle keeps up to date on each pull request
+CREATE TABLE IF NOT EXISTS pull_request_comments (
+    repository TEXT NOT NULL,
+    pull_request INTEGER NOT NULL,
+    comment_id INTEGER NOT NULL,  -- GitHub's id of the issue comment
+    PRIMARY KEY (repository, pull_request)
+);
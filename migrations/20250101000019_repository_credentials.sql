@@ -0,0 +1,10 @@
+-- Credentials for cloning private repositories, set through the API.
+-- They replace the credentials configured for the same repository.
+CREATE TABLE IF NOT EXISTS repository_credentials (
+    repository_id INTEGER PRIMARY KEY,
+    ssh_key TEXT,                -- path of a private SSH key on the icicle host
+    token TEXT,                  -- HTTPS token
+    username TEXT NOT NULL,      -- sent with the token
+    updated_at INTEGER NOT NULL,
+    FOREIGN KEY (repository_id) REFERENCES repositories(id) ON DELETE CASCADE
+);
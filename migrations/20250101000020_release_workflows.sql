@@ -0,0 +1,2 @@
+-- Workflows triggered by pushing a tag, whose branch column holds the tag name
+ALTER TABLE workflows ADD COLUMN release INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,15 @@
+-- Workflows started on a cron schedule rather than by pushes
+CREATE TABLE IF NOT EXISTS schedules (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    repository TEXT NOT NULL,      -- full name, as in workflows
+    branch TEXT NOT NULL,          -- built at its head at the time
+    cron TEXT NOT NULL,            -- minute hour day-of-month month day-of-week, local time
+    attribute_set TEXT,            -- the configured attribute set when NULL
+    configured INTEGER NOT NULL DEFAULT 0,  -- from the configuration rather than the API
+    created_at INTEGER NOT NULL,
+    last_run_at INTEGER,
+    last_workflow_id INTEGER,
+    FOREIGN KEY (last_workflow_id) REFERENCES workflows(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_schedules_repository ON schedules(repository);
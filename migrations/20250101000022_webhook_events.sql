@@ -0,0 +1,16 @@
+-- Webhook deliveries that passed signature verification, kept so they can be replayed
+CREATE TABLE IF NOT EXISTS webhook_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    provider TEXT NOT NULL,        -- github, bitbucket
+    event TEXT NOT NULL,           -- e.g. push, repo:push
+    delivery_id TEXT,              -- the provider's ID of the delivery
+    headers TEXT NOT NULL,         -- JSON object, without credentials
+    payload BLOB NOT NULL,
+    received_at INTEGER NOT NULL,
+    status_code INTEGER,           -- of the response, once handled
+    response TEXT,                 -- response body, or the error message
+    replay_of INTEGER,             -- the event this replays
+    FOREIGN KEY (replay_of) REFERENCES webhook_events(id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_webhook_events_received ON webhook_events(received_at);
@@ -0,0 +1,8 @@
+-- Heads of the branches of polled repositories, as last seen
+CREATE TABLE IF NOT EXISTS polled_branches (
+    repository TEXT NOT NULL,
+    branch TEXT NOT NULL,
+    commit_sha TEXT NOT NULL,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (repository, branch)
+);
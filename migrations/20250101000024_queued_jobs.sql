@@ -0,0 +1,9 @@
+-- Derivations queued by the running stage of each workflow, so the build queue
+-- can be rebuilt after a restart. Removed once the stage's jobs are done.
+CREATE TABLE IF NOT EXISTS queued_jobs (
+    workflow_id INTEGER NOT NULL,
+    drv_path TEXT NOT NULL,
+    derivation TEXT NOT NULL,  -- JSON
+    PRIMARY KEY (workflow_id, drv_path),
+    FOREIGN KEY (workflow_id) REFERENCES workflows(id)
+);
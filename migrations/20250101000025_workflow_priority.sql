@@ -0,0 +1,3 @@
+-- Priority of a workflow's jobs, from its branch and the priority settings.
+-- Ready jobs of higher priority are built first.
+ALTER TABLE workflows ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- Times a build was retried after failing for a transient reason
+ALTER TABLE builds ADD COLUMN retries INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- Why a workflow failed before building anything, e.g. its evaluation timing out
+ALTER TABLE workflows ADD COLUMN error TEXT;
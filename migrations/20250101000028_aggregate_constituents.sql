@@ -0,0 +1,9 @@
+-- Constituents of aggregate jobs, which decide whether a workflow succeeded
+CREATE TABLE IF NOT EXISTS aggregate_constituents (
+    aggregate TEXT NOT NULL,    -- derivation path of the aggregate job
+    constituent TEXT NOT NULL,  -- derivation path of one of its constituents
+    PRIMARY KEY (aggregate, constituent)
+);
+
+CREATE INDEX IF NOT EXISTS idx_aggregate_constituents_constituent
+    ON aggregate_constituents(constituent);
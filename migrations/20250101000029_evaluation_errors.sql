@@ -0,0 +1,10 @@
+-- Attributes that failed to evaluate, which fail their stage like failed jobs
+CREATE TABLE IF NOT EXISTS evaluation_errors (
+    workflow_id INTEGER NOT NULL,
+    stage TEXT NOT NULL,        -- name of the stage whose attribute set has it
+    attr TEXT NOT NULL,
+    error TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (workflow_id, stage, attr),
+    FOREIGN KEY (workflow_id) REFERENCES workflows(id)
+);
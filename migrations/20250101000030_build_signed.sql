@@ -0,0 +1,3 @@
+-- Whether the outputs of a successful build were signed before their upload,
+-- NULL until they were uploaded
+ALTER TABLE builds ADD COLUMN signed BOOLEAN;
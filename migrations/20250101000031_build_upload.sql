@@ -0,0 +1,4 @@
+-- Where the background upload of a successful build's outputs stands: pending,
+-- uploading, done or failed, with the error of the last failed attempt
+ALTER TABLE builds ADD COLUMN upload_status TEXT;
+ALTER TABLE builds ADD COLUMN upload_error TEXT;
@@ -0,0 +1,9 @@
+-- Dashboard logins through the configured OAuth or OIDC provider, by the SHA-256
+-- of their cookie
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    token_hash TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL
+);
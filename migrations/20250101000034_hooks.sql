@@ -0,0 +1,28 @@
+-- Endpoints receiving events as signed JSON, and the log of deliveries to them.
+-- events is a JSON list of event types, all of them when empty.
+CREATE TABLE IF NOT EXISTS hooks (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    url TEXT NOT NULL,
+    secret TEXT,
+    events TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+-- status is pending (until delivered or out of attempts), delivered or failed
+CREATE TABLE IF NOT EXISTS hook_deliveries (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    hook_id INTEGER NOT NULL,
+    event TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    status TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at INTEGER,
+    response_status INTEGER,
+    error TEXT,
+    created_at INTEGER NOT NULL,
+    delivered_at INTEGER,
+    FOREIGN KEY (hook_id) REFERENCES hooks(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_hook_deliveries_due ON hook_deliveries(status, next_attempt_at);
+CREATE INDEX IF NOT EXISTS idx_hook_deliveries_hook ON hook_deliveries(hook_id, id);
@@ -0,0 +1,23 @@
+-- The derivation graph of evaluated workflows, kept after they finish: jobs, the
+-- derivations they take as inputs, and the jobs each workflow stage queued
+CREATE TABLE IF NOT EXISTS jobs (
+    drv_path TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    system TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS job_edges (
+    drv_path TEXT NOT NULL,
+    input_drv TEXT NOT NULL,
+    PRIMARY KEY (drv_path, input_drv)
+);
+
+CREATE TABLE IF NOT EXISTS workflow_jobs (
+    workflow_id INTEGER NOT NULL,
+    drv_path TEXT NOT NULL,
+    stage TEXT NOT NULL,
+    PRIMARY KEY (workflow_id, drv_path),
+    FOREIGN KEY (workflow_id) REFERENCES workflows(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_workflow_jobs_drv_path ON workflow_jobs(drv_path);
@@ -0,0 +1,16 @@
+-- Every finished build with its timing, for statistics: builds only keeps the
+-- latest run of each derivation. Builds wait for a builder from ready_at to
+-- started_at; cached ones are finished as they start.
+CREATE TABLE IF NOT EXISTS build_runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    drv_path TEXT NOT NULL,
+    status TEXT NOT NULL,
+    ready_at INTEGER,
+    started_at INTEGER NOT NULL,
+    finished_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_build_runs_finished ON build_runs(finished_at);
+CREATE INDEX IF NOT EXISTS idx_build_runs_drv_path ON build_runs(drv_path);
+
+ALTER TABLE workflows ADD COLUMN finished_at INTEGER;
@@ -0,0 +1,11 @@
+-- How long successful builds of each attribute take, to estimate when running
+-- builds and workflows finish. avg_secs is a running average favouring recent builds.
+CREATE TABLE IF NOT EXISTS build_durations (
+    attr TEXT NOT NULL,
+    system TEXT NOT NULL,
+    avg_secs REAL NOT NULL,
+    last_secs INTEGER NOT NULL,
+    samples INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (attr, system)
+);
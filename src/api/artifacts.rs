@@ -0,0 +1,225 @@
+use super::{authorize, authorize_build, authorize_workflow, drv_store_path, ApiError, ApiPath};
+use crate::{auth::Permission, sbom::SBOM_NAME};
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_SECURITY_POLICY, CONTENT_TYPE},
+        HeaderMap, HeaderValue, Uri,
+    },
+    response::{IntoResponse, Json, Redirect, Response},
+    routing::get,
+    Router,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tower_http::services::{ServeDir, ServeFile};
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new()
+        .route("/api/workflows/{id}/artifacts", get(list_artifacts))
+        .route("/api/builds/{drv}/sbom", get(build_sbom))
+        .route("/api/artifacts/{id}", get(download_artifact))
+        .route("/api/artifacts/{id}/", get(artifact_file))
+        .route("/api/artifacts/{id}/{*path}", get(artifact_file))
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct Artifact {
+    id: i64,
+    drv_path: String,
+    build: String,
+    name: String,
+    kind: String,
+    subtype: String,
+    is_dir: bool,
+    size: i64,
+    created_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct ArtifactEntry {
+    #[serde(flatten)]
+    artifact: Artifact,
+    url: String,
+}
+
+/// Artifacts of every build a workflow requested
+async fn list_artifacts(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<Vec<ArtifactEntry>>, ApiError> {
+    authorize_workflow(&app_state, &headers, Permission::View, id).await?;
+    super::fetch_workflow(&app_state, id).await?;
+
+    let artifacts = sqlx::query_as::<_, Artifact>(
+        r#"
+        SELECT a.id, a.drv_path, b.name AS build, a.name, a.kind, a.subtype, a.is_dir,
+               a.size, a.created_at
+        FROM artifacts a
+        JOIN build_workflows bw ON bw.drv_path = a.drv_path
+        JOIN builds b ON b.drv_path = a.drv_path
+        WHERE bw.workflow_id = ?
+        ORDER BY b.name, a.id
+        "#,
+    )
+    .bind(id)
+    .fetch_all(&app_state.db_pool)
+    .await?;
+
+    Ok(Json(
+        artifacts
+            .into_iter()
+            .map(|artifact| ArtifactEntry {
+                // Directories such as coverage reports are browsed from their index
+                url: if artifact.is_dir {
+                    format!("/api/artifacts/{}/", artifact.id)
+                } else {
+                    format!("/api/artifacts/{}", artifact.id)
+                },
+                artifact,
+            })
+            .collect(),
+    ))
+}
+
+/// An artifact of a build the caller may see
+async fn stored_artifact(
+    app_state: &crate::AppState,
+    headers: &HeaderMap,
+    id: i64,
+) -> Result<crate::artifacts::StoredArtifact, ApiError> {
+    let principal = authorize(app_state, headers, Permission::View).await?;
+    let drv_path: Option<String> =
+        sqlx::query_scalar("SELECT drv_path FROM artifacts WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&app_state.db_pool)
+            .await?;
+    if let Some(drv_path) = drv_path {
+        if !principal.sees_build(&app_state.db_pool, &drv_path).await? {
+            return Err(ApiError::not_found(format!("Artifact {} not found", id)));
+        }
+    }
+    app_state
+        .artifact_store
+        .get(id)
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("Artifact {} not found", id)))
+}
+
+/// Serve a JSON document icicle generated about a build, such as its SBOM
+pub(super) async fn generated_artifact(
+    app_state: &crate::AppState,
+    drv: &str,
+    name: &str,
+) -> Result<Response, ApiError> {
+    let drv_path = drv_store_path(drv)?;
+    let id: Option<i64> = sqlx::query_scalar(
+        "SELECT id FROM artifacts WHERE drv_path = ? AND name = ? AND subtype = 'icicle'",
+    )
+    .bind(&drv_path)
+    .bind(name)
+    .fetch_optional(&app_state.db_pool)
+    .await?;
+    let artifact = match id {
+        Some(id) => app_state.artifact_store.get(id).await?,
+        None => None,
+    }
+    .ok_or_else(|| ApiError::not_found(format!("No {} for {}", name, drv_path)))?;
+
+    let contents = tokio::fs::read(&artifact.path)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to read {}: {}", name, e)))?;
+    Ok(([(CONTENT_TYPE, "application/json")], contents).into_response())
+}
+
+/// CycloneDX SBOM of a successful build's runtime closure
+async fn build_sbom(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(drv): ApiPath<String>,
+) -> Result<Response, ApiError> {
+    authorize_build(
+        &app_state,
+        &headers,
+        Permission::View,
+        &drv_store_path(&drv)?,
+    )
+    .await?;
+    generated_artifact(&app_state, &drv, SBOM_NAME).await
+}
+
+/// Artifacts are arbitrary build output, so keep any HTML away from the API's origin
+fn sandboxed(mut response: Response) -> Response {
+    response
+        .headers_mut()
+        .insert(CONTENT_SECURITY_POLICY, HeaderValue::from_static("sandbox"));
+    response
+}
+
+async fn download_artifact(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+    request: Request,
+) -> Result<Response, ApiError> {
+    let artifact = stored_artifact(&app_state, &headers, id).await?;
+    if artifact.is_dir {
+        return Ok(Redirect::temporary(&format!("/api/artifacts/{}/", id)).into_response());
+    }
+
+    let mut response = ServeFile::new(&artifact.path)
+        .try_call(request)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to read artifact {}: {}", id, e)))?
+        .map(Body::new);
+    if let Ok(disposition) =
+        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", artifact.name))
+    {
+        response
+            .headers_mut()
+            .insert(CONTENT_DISPOSITION, disposition);
+    }
+    Ok(sandboxed(response))
+}
+
+/// Serve a file from within a directory artifact
+async fn artifact_file(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(params): ApiPath<Vec<(String, String)>>,
+    mut request: Request,
+) -> Result<Response, ApiError> {
+    let id = params
+        .iter()
+        .find(|(name, _)| name == "id")
+        .and_then(|(_, id)| id.parse().ok())
+        .ok_or_else(|| ApiError::bad_request("Invalid artifact id"))?;
+    let artifact = stored_artifact(&app_state, &headers, id).await?;
+    if !artifact.is_dir {
+        return Err(ApiError::not_found(format!(
+            "Artifact {} is a file, not a directory",
+            id
+        )));
+    }
+
+    // ServeDir resolves the still percent-encoded path below the artifact, refusing `..`
+    let prefix = format!("/api/artifacts/{}", id);
+    let path = request
+        .uri()
+        .path()
+        .strip_prefix(&prefix)
+        .unwrap_or("/")
+        .to_string();
+    *request.uri_mut() = path
+        .parse::<Uri>()
+        .map_err(|_| ApiError::bad_request("Invalid artifact path"))?;
+
+    let response = ServeDir::new(&artifact.path)
+        .try_call(request)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to read artifact {}: {}", id, e)))?
+        .map(Body::new);
+    Ok(sandboxed(response))
+}
@@ -0,0 +1,95 @@
+use super::{authorize, authorize_workflow, ApiError, ApiPath, ApiQuery};
+use crate::{
+    auth::Permission,
+    bisect::{self, Bisection, Step},
+};
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::Json,
+    routing::{get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new()
+        .route("/api/workflows/{id}/bisect", post(start_bisection))
+        .route("/api/workflows/{id}/bisections", get(workflow_bisections))
+        .route("/api/bisections/{id}", get(bisection))
+}
+
+#[derive(Debug, Serialize)]
+struct BisectionResponse {
+    #[serde(flatten)]
+    bisection: Bisection,
+    /// Commits built so far, in order
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BisectQuery {
+    /// The failed attribute to bisect, by default the workflow's first
+    attribute: Option<String>,
+}
+
+/// Bisect a failed workflow against the last successful workflow of its branch
+async fn start_bisection(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+    ApiQuery(query): ApiQuery<BisectQuery>,
+) -> Result<Json<Bisection>, ApiError> {
+    authorize_workflow(&app_state, &headers, Permission::Trigger, id).await?;
+    super::fetch_workflow(&app_state, id).await?;
+
+    let bisection = app_state
+        .bisector
+        .start(id, query.attribute)
+        .await?
+        .ok_or_else(|| {
+            ApiError::conflict(format!(
+                "Workflow {} has no such failed build after a successful workflow on its branch",
+                id
+            ))
+        })?;
+    info!(
+        "Bisection {} of workflow {} requested via API",
+        bisection.id, id
+    );
+    Ok(Json(bisection))
+}
+
+async fn workflow_bisections(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<Vec<Bisection>>, ApiError> {
+    authorize_workflow(&app_state, &headers, Permission::View, id).await?;
+    super::fetch_workflow(&app_state, id).await?;
+    Ok(Json(bisect::list(&app_state.db_pool, id).await?))
+}
+
+/// A bisection with the commits it built
+async fn bisection(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<BisectionResponse>, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::View).await?;
+    let bisection = bisect::get(&app_state.db_pool, id)
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("Bisection {} not found", id)))?;
+    if !principal
+        .sees_workflow(&app_state.db_pool, bisection.workflow_id)
+        .await?
+    {
+        return Err(ApiError::not_found(format!("Bisection {} not found", id)));
+    }
+    Ok(Json(BisectionResponse {
+        steps: bisect::steps(&app_state.db_pool, id).await?,
+        bisection,
+    }))
+}
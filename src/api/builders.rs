@@ -0,0 +1,17 @@
+use super::{authorize, ApiError};
+use crate::{auth::Permission, builders::BuilderStatus};
+use axum::{extract::State, http::HeaderMap, response::Json, routing::get, Router};
+use std::sync::Arc;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new().route("/api/builders", get(builders))
+}
+
+/// The remote builders with their running builds and whether they are reachable
+async fn builders(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<BuilderStatus>>, ApiError> {
+    authorize(&app_state, &headers, Permission::View).await?;
+    Ok(Json(app_state.builders.status()))
+}
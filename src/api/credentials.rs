@@ -0,0 +1,143 @@
+use super::{authorize, ApiError, ApiJson, ApiPath};
+use crate::{auth::Permission, credentials::Credential};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::Json,
+    routing::get,
+    Router,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::info;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new().route(
+        "/api/repos/{id}/credentials",
+        get(show_credential)
+            .put(set_credential)
+            .delete(delete_credential),
+    )
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct CredentialRow {
+    ssh_key: Option<String>,
+    token: Option<String>,
+    username: String,
+    updated_at: i64,
+}
+
+/// A repository's clone credential, without the token itself
+#[derive(Debug, Serialize)]
+struct CredentialInfo {
+    ssh_key: Option<String>,
+    has_token: bool,
+    username: String,
+    updated_at: i64,
+}
+
+impl From<CredentialRow> for CredentialInfo {
+    fn from(row: CredentialRow) -> Self {
+        CredentialInfo {
+            ssh_key: row.ssh_key,
+            has_token: row.token.is_some(),
+            username: row.username,
+            updated_at: row.updated_at,
+        }
+    }
+}
+
+async fn ensure_repository(app_state: &crate::AppState, id: i64) -> Result<(), ApiError> {
+    sqlx::query_scalar::<_, i64>("SELECT id FROM repositories WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&app_state.db_pool)
+        .await?
+        .map(|_| ())
+        .ok_or_else(|| ApiError::not_found(format!("Repository {} not found", id)))
+}
+
+/// The credential set through the API; configured ones are not shown
+async fn show_credential(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<CredentialInfo>, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+    ensure_repository(&app_state, id).await?;
+
+    sqlx::query_as::<_, CredentialRow>(
+        r#"
+        SELECT ssh_key, token, username, updated_at
+        FROM repository_credentials WHERE repository_id = ?
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(&app_state.db_pool)
+    .await?
+    .map(|row| Json(row.into()))
+    .ok_or_else(|| ApiError::not_found(format!("Repository {} has no credentials", id)))
+}
+
+/// Set the credential used to clone a repository, replacing any configured one
+async fn set_credential(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+    ApiJson(credential): ApiJson<Credential>,
+) -> Result<Json<CredentialInfo>, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+    ensure_repository(&app_state, id).await?;
+    credential
+        .validate()
+        .map_err(|e| ApiError::unprocessable(format!("{:#}", e)))?;
+
+    let ssh_key = credential
+        .ssh_key
+        .map(|key| key.to_string_lossy().into_owned());
+    let now = chrono::Utc::now().timestamp();
+    sqlx::query(
+        r#"
+        INSERT OR REPLACE INTO repository_credentials
+            (repository_id, ssh_key, token, username, updated_at)
+        VALUES (?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(id)
+    .bind(&ssh_key)
+    .bind(&credential.token)
+    .bind(&credential.username)
+    .bind(now)
+    .execute(&app_state.db_pool)
+    .await?;
+
+    info!("Set clone credentials of repository {}", id);
+    Ok(Json(CredentialInfo {
+        ssh_key,
+        has_token: credential.token.is_some(),
+        username: credential.username,
+        updated_at: now,
+    }))
+}
+
+async fn delete_credential(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<StatusCode, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let deleted = sqlx::query("DELETE FROM repository_credentials WHERE repository_id = ?")
+        .bind(id)
+        .execute(&app_state.db_pool)
+        .await?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(ApiError::not_found(format!(
+            "Repository {} has no credentials",
+            id
+        )));
+    }
+    info!("Removed clone credentials of repository {}", id);
+    Ok(StatusCode::NO_CONTENT)
+}
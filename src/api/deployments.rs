@@ -0,0 +1,99 @@
+use super::{authorize, authorize_workflow, ApiError, ApiPath};
+use crate::auth::Permission;
+use axum::{extract::State, http::HeaderMap, response::Json, routing::get, Router};
+use serde::Serialize;
+use std::sync::Arc;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new()
+        .route("/api/deployments", get(latest_deployments))
+        .route("/api/deployments/{id}", get(deployment))
+        .route("/api/workflows/{id}/deployments", get(workflow_deployments))
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct Deployment {
+    id: i64,
+    workflow_id: i64,
+    target: String,
+    /// queued, running, success, failed, or skipped when a newer workflow was deployed
+    status: String,
+    commit_sha: String,
+    created_at: i64,
+    started_at: Option<i64>,
+    finished_at: Option<i64>,
+    /// Output of the deploy command, only included for a single deployment
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+}
+
+const DEPLOYMENT_COLUMNS: &str = r#"
+    SELECT d.id, d.workflow_id, d.target, d.status, w.commit_sha, d.created_at,
+           d.started_at, d.finished_at
+    FROM deployments d JOIN workflows w ON w.id = d.workflow_id
+"#;
+
+/// The latest deployment to each target, leaving out the ones skipped as superseded
+async fn latest_deployments(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Deployment>>, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::View).await?;
+    let deployments = sqlx::query_as::<_, Deployment>(&format!(
+        r#"
+        {} WHERE d.id IN (
+            SELECT MAX(d.id) FROM deployments d JOIN workflows w ON w.id = d.workflow_id
+            WHERE w.repository NOT IN (SELECT value FROM json_each(?)) AND d.status != 'skipped'
+            GROUP BY d.target
+        )
+        ORDER BY d.target
+        "#,
+        DEPLOYMENT_COLUMNS
+    ))
+    .bind(principal.hidden_json())
+    .fetch_all(&app_state.db_pool)
+    .await?;
+    Ok(Json(deployments))
+}
+
+/// A deployment with the output of its command
+async fn deployment(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<Deployment>, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::View).await?;
+    let deployment = sqlx::query_as::<_, Deployment>(
+        r#"
+        SELECT d.id, d.workflow_id, d.target, d.status, w.commit_sha, d.created_at,
+               d.started_at, d.finished_at, d.output
+        FROM deployments d JOIN workflows w ON w.id = d.workflow_id
+        WHERE d.id = ? AND w.repository NOT IN (SELECT value FROM json_each(?))
+        "#,
+    )
+    .bind(id)
+    .bind(principal.hidden_json())
+    .fetch_optional(&app_state.db_pool)
+    .await?
+    .ok_or_else(|| ApiError::not_found(format!("Deployment {} not found", id)))?;
+    Ok(Json(deployment))
+}
+
+/// Deployments started by a workflow
+async fn workflow_deployments(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<Vec<Deployment>>, ApiError> {
+    authorize_workflow(&app_state, &headers, Permission::View, id).await?;
+    super::fetch_workflow(&app_state, id).await?;
+    let deployments = sqlx::query_as::<_, Deployment>(&format!(
+        "{} WHERE d.workflow_id = ? ORDER BY d.id",
+        DEPLOYMENT_COLUMNS
+    ))
+    .bind(id)
+    .fetch_all(&app_state.db_pool)
+    .await?;
+    Ok(Json(deployments))
+}
@@ -0,0 +1,25 @@
+use super::{authorize_build, drv_store_path, ApiError, ApiPath};
+use crate::{
+    auth::Permission,
+    drvdiff::{self, BuildDiff},
+};
+use axum::{extract::State, http::HeaderMap, response::Json, routing::get, Router};
+use std::sync::Arc;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new().route("/api/builds/{drv}/diff", get(build_diff))
+}
+
+/// What changed in a failed build's derivation since the attribute last built successfully
+async fn build_diff(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(drv): ApiPath<String>,
+) -> Result<Json<BuildDiff>, ApiError> {
+    let drv_path = drv_store_path(&drv)?;
+    authorize_build(&app_state, &headers, Permission::View, &drv_path).await?;
+    drvdiff::load(&app_state.db_pool, &drv_path)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("No diff recorded for {}", drv)))
+}
@@ -0,0 +1,201 @@
+use super::{authorize, ApiError, ApiJson, ApiPath, ApiQuery, Page, PageParams};
+use crate::{auth::Permission, events::EVENT_KINDS};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::Json,
+    routing::{delete, get},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new()
+        .route("/api/hooks", get(list_hooks).post(create_hook))
+        .route("/api/hooks/{id}", delete(delete_hook))
+        .route("/api/hooks/{id}/deliveries", get(list_deliveries))
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct HookRow {
+    id: i64,
+    url: String,
+    secret: Option<String>,
+    events: String,
+    created_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct Hook {
+    id: i64,
+    url: String,
+    /// Event types posted to the hook; all of them when empty
+    events: Vec<String>,
+    /// Whether payloads are signed in X-Icicle-Signature-256
+    signed: bool,
+    created_at: i64,
+}
+
+impl From<HookRow> for Hook {
+    fn from(row: HookRow) -> Self {
+        Hook {
+            id: row.id,
+            url: row.url,
+            events: serde_json::from_str(&row.events).unwrap_or_default(),
+            signed: row.secret.is_some(),
+            created_at: row.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct NewHook {
+    url: String,
+    /// Key of the HMAC-SHA256 signature sent with each payload
+    secret: Option<String>,
+    /// Event types to post, e.g. workflow_status and job_status; all when empty
+    #[serde(default)]
+    events: Vec<String>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct Delivery {
+    id: i64,
+    event: String,
+    /// pending, delivered or failed
+    status: String,
+    attempts: i64,
+    /// When the next attempt is due, while pending
+    next_attempt_at: Option<i64>,
+    response_status: Option<i64>,
+    /// Why the last attempt failed
+    error: Option<String>,
+    created_at: i64,
+    delivered_at: Option<i64>,
+}
+
+async fn ensure_hook(app_state: &crate::AppState, id: i64) -> Result<(), ApiError> {
+    sqlx::query_scalar::<_, i64>("SELECT id FROM hooks WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&app_state.db_pool)
+        .await?
+        .map(|_| ())
+        .ok_or_else(|| ApiError::not_found(format!("Hook {} not found", id)))
+}
+
+/// Hooks can embed secrets in their URL, so listing them needs the admin role
+async fn list_hooks(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Hook>>, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let rows = sqlx::query_as::<_, HookRow>(
+        "SELECT id, url, secret, events, created_at FROM hooks ORDER BY id",
+    )
+    .fetch_all(&app_state.db_pool)
+    .await?;
+    Ok(Json(rows.into_iter().map(Hook::from).collect()))
+}
+
+async fn create_hook(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiJson(request): ApiJson<NewHook>,
+) -> Result<(StatusCode, Json<Hook>), ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let url = request.url.trim();
+    if reqwest::Url::parse(url).map_or(true, |u| !matches!(u.scheme(), "http" | "https")) {
+        return Err(ApiError::unprocessable("URL must be an http(s) URL"));
+    }
+    if let Some(unknown) = request
+        .events
+        .iter()
+        .find(|e| !EVENT_KINDS.contains(&e.as_str()))
+    {
+        return Err(ApiError::unprocessable(format!(
+            "Unknown event '{}', expected one of: {}",
+            unknown,
+            EVENT_KINDS.join(", ")
+        )));
+    }
+    let secret = request.secret.filter(|s| !s.is_empty());
+    let events = serde_json::to_string(&request.events)
+        .map_err(|e| ApiError::internal(format!("Failed to encode events: {}", e)))?;
+    let now = chrono::Utc::now().timestamp();
+    let id = sqlx::query("INSERT INTO hooks (url, secret, events, created_at) VALUES (?, ?, ?, ?)")
+        .bind(url)
+        .bind(&secret)
+        .bind(&events)
+        .bind(now)
+        .execute(&app_state.db_pool)
+        .await?
+        .last_insert_rowid();
+
+    info!("{} added hook {} posting to {}", principal.name, id, url);
+    Ok((
+        StatusCode::CREATED,
+        Json(Hook {
+            id,
+            url: url.to_string(),
+            events: request.events,
+            signed: secret.is_some(),
+            created_at: now,
+        }),
+    ))
+}
+
+async fn delete_hook(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<StatusCode, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let mut tx = app_state.db_pool.begin().await?;
+    sqlx::query("DELETE FROM hook_deliveries WHERE hook_id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    let deleted = sqlx::query("DELETE FROM hooks WHERE id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(ApiError::not_found(format!("Hook {} not found", id)));
+    }
+    tx.commit().await?;
+    info!("{} removed hook {}", principal.name, id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Deliveries to a hook, newest first
+async fn list_deliveries(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+    ApiQuery(page): ApiQuery<PageParams>,
+) -> Result<Json<Page<Delivery>>, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+    ensure_hook(&app_state, id).await?;
+
+    let limit = page.limit();
+    let deliveries = sqlx::query_as::<_, Delivery>(
+        r#"
+        SELECT id, event, status, attempts, next_attempt_at, response_status, error,
+               created_at, delivered_at
+        FROM hook_deliveries WHERE hook_id = ? AND id < ?
+        ORDER BY id DESC LIMIT ?
+        "#,
+    )
+    .bind(id)
+    .bind(page.after()?.unwrap_or(i64::MAX))
+    .bind(limit + 1)
+    .fetch_all(&app_state.db_pool)
+    .await?;
+    Ok(Json(Page::new(deliveries, limit, |d| d.id)))
+}
@@ -0,0 +1,44 @@
+use super::{authorize_workflow, ApiError, ApiPath};
+use crate::auth::Permission;
+use axum::{extract::State, http::HeaderMap, response::Json, routing::get, Router};
+use serde::Serialize;
+use std::sync::Arc;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new().route("/api/workflows/{id}/inputs", get(workflow_inputs))
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct WorkflowInput {
+    name: String,
+    #[serde(rename = "type")]
+    #[sqlx(rename = "type")]
+    kind: String,
+    source: String,
+    rev: Option<String>,
+    last_modified: Option<i64>,
+    previous_rev: Option<String>,
+    changed: bool,
+}
+
+/// The flake inputs a workflow was evaluated with
+async fn workflow_inputs(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<Vec<WorkflowInput>>, ApiError> {
+    authorize_workflow(&app_state, &headers, Permission::View, id).await?;
+    super::fetch_workflow(&app_state, id).await?;
+
+    let inputs = sqlx::query_as::<_, WorkflowInput>(
+        r#"
+        SELECT name, type, source, rev, last_modified, previous_rev, changed
+        FROM workflow_inputs WHERE workflow_id = ?
+        ORDER BY name
+        "#,
+    )
+    .bind(id)
+    .fetch_all(&app_state.db_pool)
+    .await?;
+    Ok(Json(inputs))
+}
@@ -0,0 +1,1600 @@
+use crate::{
+    auth::{self, Permission, Principal},
+    build::{BuildStatus, JobGraph, WorkflowStatus},
+    cache::CacheClient,
+    events::Event,
+    hints::{Cause, Diagnosis},
+    nix::{self, EvalError, RemoteRef},
+    progress::BuildProgress,
+    webhook::{self, WorkflowOptions},
+};
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE},
+        HeaderMap, Method, StatusCode,
+    },
+    middleware::{self, Next},
+    response::{
+        sse::{Event as SseEvent, KeepAlive, Sse},
+        IntoResponse, Json, Redirect, Response,
+    },
+    routing::{get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{collections::HashSet, convert::Infallible, sync::Arc};
+use tokio::{
+    sync::broadcast::error::RecvError,
+    time::{timeout_at, Duration, Instant},
+};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use tokio_util::io::ReaderStream;
+use tracing::{info, warn};
+
+mod artifacts;
+mod bisections;
+mod builders;
+mod credentials;
+mod deployments;
+mod diffs;
+mod error;
+mod hooks;
+mod inputs;
+mod notifications;
+mod openapi;
+mod organizations;
+mod pagination;
+mod provenance;
+mod quotas;
+mod schedules;
+mod stages;
+mod stats;
+mod test_results;
+mod tokens;
+mod users;
+mod webhook_events;
+mod workers;
+
+pub use error::{ApiError, ApiJson, ApiPath, ApiQuery};
+pub use pagination::{Page, PageParams};
+
+#[derive(Debug, Clone)]
+pub struct ApiConfig {
+    pub admin_token: Option<String>,
+    /// Whether anonymous requests may view workflows, builds and repositories
+    pub public: bool,
+}
+
+pub fn routes(app_state: Arc<crate::AppState>) -> Router<Arc<crate::AppState>> {
+    Router::new()
+        .route("/api/events", get(events))
+        .route("/api/queue", get(queue))
+        .route("/api/workflows", get(workflows).post(create_workflow))
+        .route("/api/workflows/cancel", post(cancel_workflows))
+        .route("/api/workflows/{id}", get(workflow))
+        .route("/api/workflows/{id}/wait", get(wait_workflow))
+        .route("/api/workflows/{id}/graph", get(workflow_graph))
+        .route("/api/workflows/{id}/cancel", post(cancel_workflow))
+        .route("/api/builds", get(builds))
+        .route("/api/builds/{drv}", get(build))
+        .route("/api/builds/{drv}/log", get(build_log))
+        .route("/api/builds/{drv}/upload", post(reupload_build))
+        .route("/api/builds/{drv}/outputs/{output}", get(build_output))
+        .route("/api/repos", get(repositories))
+        .route("/api/repos/{id}/evaluate", post(evaluate_repository))
+        .route("/api/admin/pause", post(pause))
+        .route("/api/admin/resume", post(resume))
+        .merge(artifacts::routes())
+        .merge(bisections::routes())
+        .merge(builders::routes())
+        .merge(credentials::routes())
+        .merge(deployments::routes())
+        .merge(diffs::routes())
+        .merge(hooks::routes())
+        .merge(inputs::routes())
+        .merge(notifications::routes())
+        .merge(openapi::routes())
+        .merge(organizations::routes())
+        .merge(provenance::routes())
+        .merge(quotas::routes())
+        .merge(schedules::routes())
+        .merge(stages::routes())
+        .merge(stats::routes())
+        .merge(test_results::routes())
+        .merge(tokens::routes())
+        .merge(users::routes())
+        .merge(webhook_events::routes())
+        .merge(workers::routes())
+        .route_layer(middleware::from_fn_with_state(
+            app_state,
+            require_credentials,
+        ))
+}
+
+/// Refuse requests changing anything unless they carry valid credentials, whatever the
+/// handler goes on to check
+async fn require_credentials(
+    State(app_state): State<Arc<crate::AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let reads = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    // Workers authenticate with their own token
+    let worker = request
+        .uri()
+        .path()
+        .strip_prefix("/api/workers")
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+    if !reads && !worker {
+        auth::authenticate(&app_state.api_config, &app_state.db_pool, request.headers())
+            .await?
+            .ok_or_else(|| ApiError::unauthorized("Missing bearer token"))?;
+    }
+    Ok(next.run(request).await)
+}
+
+/// Check that the caller's role allows `permission`
+async fn authorize(
+    app_state: &crate::AppState,
+    headers: &HeaderMap,
+    permission: Permission,
+) -> Result<Principal, ApiError> {
+    Ok(auth::authorize(
+        &app_state.api_config,
+        &app_state.db_pool,
+        headers,
+        permission,
+    )
+    .await?)
+}
+
+/// Check `permission` on a workflow, which callers outside its organization are told
+/// does not exist
+async fn authorize_workflow(
+    app_state: &crate::AppState,
+    headers: &HeaderMap,
+    permission: Permission,
+    id: i64,
+) -> Result<Principal, ApiError> {
+    let principal = authorize(app_state, headers, permission).await?;
+    if !principal.sees_workflow(&app_state.db_pool, id).await? {
+        return Err(ApiError::not_found(format!("Workflow {} not found", id)));
+    }
+    Ok(principal)
+}
+
+/// Check `permission` on the build of a derivation, which callers outside the
+/// organizations of its workflows are told does not exist
+async fn authorize_build(
+    app_state: &crate::AppState,
+    headers: &HeaderMap,
+    permission: Permission,
+    drv_path: &str,
+) -> Result<Principal, ApiError> {
+    let principal = authorize(app_state, headers, permission).await?;
+    if !principal.sees_build(&app_state.db_pool, drv_path).await? {
+        return Err(ApiError::not_found(format!("No build for {}", drv_path)));
+    }
+    Ok(principal)
+}
+
+#[derive(Debug, Deserialize)]
+struct EventsQuery {
+    /// Only stream events concerning this workflow
+    workflow: Option<i64>,
+}
+
+/// Stream every status transition as server-sent JSON events
+async fn events(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiQuery(query): ApiQuery<EventsQuery>,
+) -> Result<Sse<impl Stream<Item = Result<SseEvent, Infallible>>>, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::View).await?;
+    // Subscribe before listing the hidden workflows so none created in between is missed
+    let events = app_state.events.subscribe();
+    let mut hidden = principal.hidden_workflows(&app_state.db_pool).await?;
+    let stream = BroadcastStream::new(events).filter_map(move |event| {
+        // Lagged receivers just skip the events they missed
+        let mut event = event.ok()?;
+        if let Some(id) = query.workflow {
+            if !event.concerns_workflow(id) {
+                return None;
+            }
+        }
+        if let Event::WorkflowCreated {
+            workflow_id,
+            repository,
+            ..
+        } = &event
+        {
+            if !principal.sees(repository) {
+                hidden.insert(*workflow_id);
+            }
+        }
+        if event.workflow_id().is_some_and(|id| hidden.contains(&id)) {
+            return None;
+        }
+        if let Event::JobStatus { workflows, .. } = &mut event {
+            if !workflows.is_empty() {
+                workflows.retain(|id| !hidden.contains(id));
+                if workflows.is_empty() {
+                    return None;
+                }
+            }
+        }
+        SseEvent::default().json_data(&event).ok().map(Ok)
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Debug, Deserialize)]
+struct QueueQuery {
+    status: Option<BuildStatus>,
+    workflow: Option<i64>,
+    system: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct QueueResponse {
+    queued: usize,
+    ready: usize,
+    running: usize,
+    jobs: Vec<QueueEntry>,
+}
+
+#[derive(Debug, Serialize)]
+struct QueueEntry {
+    name: String,
+    drv_path: String,
+    system: String,
+    status: BuildStatus,
+    workflows: Vec<i64>,
+    /// Derivations that must finish before this job can start
+    blocked_by: Vec<String>,
+    /// What the build is doing, while it runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    progress: Option<BuildProgress>,
+}
+
+/// List unfinished jobs in the queue with their blocking edges and workflow membership
+async fn queue(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiQuery(query): ApiQuery<QueueQuery>,
+) -> Result<Json<QueueResponse>, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::View).await?;
+    let hidden = principal.hidden_workflows(&app_state.db_pool).await?;
+    let mut jobs: Vec<QueueEntry> = app_state
+        .build_queue
+        .get_pending_jobs()
+        .into_iter()
+        .filter(|(job, _)| job.requested_by.is_empty() || !job.requested_by.is_subset(&hidden))
+        .filter(|(job, _)| query.status.is_none_or(|s| s == job.status))
+        .filter(|(job, _)| query.workflow.is_none_or(|w| job.requested_by.contains(&w)))
+        .filter(|(job, _)| {
+            query
+                .system
+                .as_ref()
+                .is_none_or(|s| *s == job.derivation.system)
+        })
+        .map(|(job, blocked_by)| {
+            let mut workflows: Vec<i64> = job
+                .requested_by
+                .into_iter()
+                .filter(|id| !hidden.contains(id))
+                .collect();
+            workflows.sort();
+            QueueEntry {
+                name: job.derivation.name,
+                drv_path: job.derivation.drv_path,
+                system: job.derivation.system,
+                status: job.status,
+                workflows,
+                blocked_by,
+                progress: job.progress,
+            }
+        })
+        .collect();
+
+    // Running first, then ready, then queued
+    jobs.sort_by_key(|job| {
+        let order = match job.status {
+            BuildStatus::Running => 0,
+            BuildStatus::Ready => 1,
+            _ => 2,
+        };
+        (order, job.name.clone())
+    });
+
+    let count = |status: BuildStatus| jobs.iter().filter(|j| j.status == status).count();
+    Ok(Json(QueueResponse {
+        queued: count(BuildStatus::Queued),
+        ready: count(BuildStatus::Ready),
+        running: count(BuildStatus::Running),
+        jobs,
+    }))
+}
+
+/// Stop the executor from starting new builds; running builds are left to finish
+async fn pause(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+    app_state.build_queue.set_paused(true);
+    info!("Building paused via admin API");
+    Ok(Json(json!({ "paused": true })))
+}
+
+async fn resume(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+    app_state.build_queue.set_paused(false);
+    info!("Building resumed via admin API");
+    Ok(Json(json!({ "paused": false })))
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct WorkflowRow {
+    id: i64,
+    repository: String,
+    commit_sha: String,
+    branch: Option<String>,
+    attribute_set: String,
+    status: String,
+    created_at: i64,
+    /// Triggered by a tag, which `branch` holds
+    release: bool,
+    /// Its jobs are built before those of workflows of lower priority
+    priority: i64,
+    /// Why it failed before building, e.g. as its evaluation timed out
+    error: Option<String>,
+    /// Attributes that failed to evaluate, which count as failed jobs
+    #[sqlx(skip)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    evaluation_errors: Vec<EvalError>,
+}
+
+/// A workflow with how its builds went so far
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct WorkflowSummary {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    workflow: WorkflowRow,
+    total_builds: i64,
+    /// Builds that succeeded or whose outputs were cached
+    succeeded_builds: i64,
+    /// Builds that failed or timed out
+    failed_builds: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct WorkflowFilter {
+    /// Only workflows of this repository, e.g. "owner/repo"
+    repository: Option<String>,
+    branch: Option<String>,
+    /// Only workflows with this status, e.g. "failed", ignoring case
+    status: Option<String>,
+}
+
+/// Workflows, newest first
+async fn workflows(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiQuery(page): ApiQuery<PageParams>,
+    ApiQuery(filter): ApiQuery<WorkflowFilter>,
+) -> Result<Json<Page<WorkflowSummary>>, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::View).await?;
+    let limit = page.limit();
+    let workflows = sqlx::query_as::<_, WorkflowSummary>(
+        r#"
+        SELECT w.id, w.repository, w.commit_sha, w.branch, w.attribute_set, w.status,
+               w.created_at, w.release, w.priority, w.error,
+               COUNT(b.drv_path) AS total_builds,
+               COALESCE(SUM(b.status IN ('success', 'cached')), 0) AS succeeded_builds,
+               COALESCE(SUM(b.status IN ('failed', 'timed out')), 0) AS failed_builds
+        FROM workflows w
+        LEFT JOIN build_workflows bw ON bw.workflow_id = w.id
+        LEFT JOIN builds b ON b.drv_path = bw.drv_path
+        WHERE w.id < ?1
+          AND (?2 IS NULL OR w.repository = ?2)
+          AND (?3 IS NULL OR w.branch = ?3)
+          AND (?4 IS NULL OR LOWER(w.status) = LOWER(?4))
+          AND w.repository NOT IN (SELECT value FROM json_each(?6))
+        GROUP BY w.id
+        ORDER BY w.id DESC LIMIT ?5
+        "#,
+    )
+    .bind(page.after()?.unwrap_or(i64::MAX))
+    .bind(&filter.repository)
+    .bind(&filter.branch)
+    .bind(&filter.status)
+    .bind(limit + 1)
+    .bind(principal.hidden_json())
+    .fetch_all(&app_state.db_pool)
+    .await?;
+    Ok(Json(Page::new(workflows, limit, |w| w.workflow.id)))
+}
+
+async fn workflow(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<WorkflowRow>, ApiError> {
+    authorize_workflow(&app_state, &headers, Permission::View, id).await?;
+    Ok(Json(fetch_workflow(&app_state, id).await?))
+}
+
+/// The jobs of a workflow with their dependencies, live while they are queued
+async fn workflow_graph(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<JobGraph>, ApiError> {
+    authorize_workflow(&app_state, &headers, Permission::View, id).await?;
+    fetch_workflow(&app_state, id).await?;
+    Ok(Json(app_state.pipeline.graph(id).await?))
+}
+
+#[derive(Debug, Deserialize)]
+struct NewWorkflow {
+    /// URL to clone the repository from; by default the one it is registered with
+    clone_url: Option<String>,
+    /// Branch, tag or full commit SHA to build
+    #[serde(rename = "ref")]
+    git_ref: String,
+    /// Attribute set to evaluate; defaults to the configured one
+    attribute_set: Option<String>,
+    /// Full name of the repository; by default that of the repository registered
+    /// with this clone URL, or the last two components of the URL
+    repository: Option<String>,
+    /// Branch a commit belongs to; by default that of its previous workflow
+    branch: Option<String>,
+}
+
+/// "owner/repo" from a clone URL such as https://github.com/owner/repo.git or
+/// git@github.com:owner/repo.git
+fn repository_name(clone_url: &str) -> Option<String> {
+    let path = clone_url.trim_end_matches('/').trim_end_matches(".git");
+    let mut components = path.rsplit(['/', ':']).filter(|c| !c.is_empty());
+    let repo = components.next()?;
+    let owner = components.next()?;
+    Some(format!("{}/{}", owner, repo))
+}
+
+fn is_commit_sha(git_ref: &str) -> bool {
+    git_ref.len() == 40 && git_ref.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Start a workflow the way a webhook would, e.g. to re-run one without pushing
+async fn create_workflow(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiJson(request): ApiJson<NewWorkflow>,
+) -> Result<(StatusCode, Json<WorkflowRow>), ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Trigger).await?;
+
+    let registered: Option<String> =
+        sqlx::query_scalar("SELECT full_name FROM repositories WHERE clone_url = ?")
+            .bind(&request.clone_url)
+            .fetch_optional(&app_state.db_pool)
+            .await?;
+    let repository = request
+        .repository
+        .or(registered.clone())
+        .or_else(|| request.clone_url.as_deref().and_then(repository_name))
+        .ok_or_else(|| {
+            ApiError::unprocessable("No repository name in the clone URL, give it as repository")
+        })?;
+    if !principal.sees(&repository) || registered.as_deref().is_some_and(|r| !principal.sees(r)) {
+        return Err(ApiError::forbidden(format!(
+            "{} is not a member of the organization of {}",
+            principal.name, repository
+        )));
+    }
+    webhook::check_repository(&app_state, &repository)?;
+
+    // Credentials of a repository are only ever used with the clone URL it is
+    // registered with, never one given by the caller
+    let registered_url: Option<String> =
+        sqlx::query_scalar("SELECT clone_url FROM repositories WHERE full_name = ?")
+            .bind(&repository)
+            .fetch_optional(&app_state.db_pool)
+            .await?;
+    let credential = app_state.credentials.get(&repository).await?;
+    let clone_url = match (registered_url, request.clone_url) {
+        (Some(url), Some(given)) if url != given => {
+            return Err(ApiError::unprocessable(format!(
+                "{} is registered with another clone URL",
+                repository
+            )))
+        }
+        (Some(url), _) => url,
+        (None, _) if credential.is_some() => {
+            return Err(ApiError::unprocessable(format!(
+                "{} has credentials but is not registered, let its forge trigger it first",
+                repository
+            )))
+        }
+        (None, Some(given)) => given,
+        (None, None) => {
+            return Err(ApiError::unprocessable(format!(
+                "{} is not registered, give its clone_url",
+                repository
+            )))
+        }
+    };
+    let resolved = nix::resolve_ref(&clone_url, &request.git_ref, credential.as_ref())
+        .await
+        .map_err(|e| ApiError::unprocessable(format!("{:#}", e)))?;
+    let (commit_sha, branch, release) = match resolved {
+        Some(RemoteRef::Branch(commit_sha)) => (commit_sha, request.git_ref.clone(), false),
+        Some(RemoteRef::Tag(commit_sha)) => (commit_sha, request.git_ref.clone(), true),
+        None if is_commit_sha(&request.git_ref) => {
+            let previous: Option<String> = sqlx::query_scalar(
+                r#"
+                SELECT branch FROM workflows
+                WHERE repository = ? AND commit_sha = ? AND branch IS NOT NULL
+                ORDER BY id DESC LIMIT 1
+                "#,
+            )
+            .bind(&repository)
+            .bind(&request.git_ref)
+            .fetch_optional(&app_state.db_pool)
+            .await?;
+            let branch = request.branch.or(previous).ok_or_else(|| {
+                ApiError::unprocessable(format!(
+                    "Commit {} was not built before, give the branch it belongs to",
+                    request.git_ref
+                ))
+            })?;
+            (request.git_ref.clone(), branch, false)
+        }
+        None => {
+            return Err(ApiError::unprocessable(format!(
+                "{} is not a branch, tag or full commit SHA of {}",
+                request.git_ref, clone_url
+            )))
+        }
+    };
+
+    let deprioritized = webhook::check_quota(&app_state, &repository).await?;
+    let id = webhook::create_workflow(
+        &app_state,
+        &repository,
+        &commit_sha,
+        &branch,
+        &clone_url,
+        None,
+        WorkflowOptions {
+            deprioritized,
+            release,
+            attribute_set: request.attribute_set,
+            keep_clone_url: true,
+        },
+    )
+    .await?;
+    info!(
+        "{} started workflow {} of {} at {}",
+        principal.name, id, repository, request.git_ref
+    );
+    Ok((
+        StatusCode::CREATED,
+        Json(fetch_workflow(&app_state, id).await?),
+    ))
+}
+
+async fn fetch_workflow(app_state: &crate::AppState, id: i64) -> Result<WorkflowRow, ApiError> {
+    let mut workflow = sqlx::query_as::<_, WorkflowRow>(
+        r#"
+        SELECT id, repository, commit_sha, branch, attribute_set, status, created_at, release,
+               priority, error
+        FROM workflows WHERE id = ?
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(&app_state.db_pool)
+    .await?
+    .ok_or_else(|| ApiError::not_found(format!("Workflow {} not found", id)))?;
+    workflow.evaluation_errors = sqlx::query_as::<_, EvalError>(
+        "SELECT attr, error FROM evaluation_errors WHERE workflow_id = ? ORDER BY stage, attr",
+    )
+    .bind(id)
+    .fetch_all(&app_state.db_pool)
+    .await?;
+    Ok(workflow)
+}
+
+fn is_terminal(status: &str) -> bool {
+    matches!(status, "Completed" | "Failed" | "Canceled")
+}
+
+/// Default and maximum time a wait request is held open
+const DEFAULT_WAIT_SECS: u64 = 300;
+const MAX_WAIT_SECS: u64 = 3600;
+
+#[derive(Debug, Deserialize)]
+struct WaitQuery {
+    /// Seconds to wait before giving up
+    timeout: Option<u64>,
+}
+
+/// Long-poll until the workflow reaches a terminal status
+async fn wait_workflow(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+    ApiQuery(query): ApiQuery<WaitQuery>,
+) -> Result<Json<WorkflowRow>, ApiError> {
+    authorize_workflow(&app_state, &headers, Permission::View, id).await?;
+    let wait = query
+        .timeout
+        .unwrap_or(DEFAULT_WAIT_SECS)
+        .min(MAX_WAIT_SECS);
+    let deadline = Instant::now() + Duration::from_secs(wait);
+
+    // Subscribe before reading the status so a transition in between is not missed
+    let mut events = app_state.events.subscribe();
+    let workflow = fetch_workflow(&app_state, id).await?;
+    if is_terminal(&workflow.status) {
+        return Ok(Json(workflow));
+    }
+
+    loop {
+        let changed = match timeout_at(deadline, events.recv()).await {
+            Ok(Ok(event)) => {
+                matches!(event, Event::WorkflowStatus { workflow_id, .. } if workflow_id == id)
+            }
+            // Missed some events, so re-check the status
+            Ok(Err(RecvError::Lagged(_))) => true,
+            Ok(Err(RecvError::Closed)) => return Err(ApiError::internal("Event stream closed")),
+            Err(_) => {
+                let workflow = fetch_workflow(&app_state, id).await?;
+                return Err(ApiError::new(
+                    StatusCode::REQUEST_TIMEOUT,
+                    "timeout",
+                    format!("Workflow {} did not finish within {}s", id, wait),
+                )
+                .with_details(json!({ "status": workflow.status })));
+            }
+        };
+        if changed {
+            let workflow = fetch_workflow(&app_state, id).await?;
+            if is_terminal(&workflow.status) {
+                return Ok(Json(workflow));
+            }
+        }
+    }
+}
+
+/// Cancel an unfinished workflow and drop the jobs only it needed
+async fn cancel_workflow(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<Value>, ApiError> {
+    authorize_workflow(&app_state, &headers, Permission::Cancel, id).await?;
+
+    if !cancel(&app_state, id).await? {
+        return Err(ApiError::conflict(format!(
+            "Workflow {} does not exist or already finished",
+            id
+        )));
+    }
+    info!("Workflow {} canceled via API", id);
+
+    Ok(Json(json!({ "workflow_id": id, "status": "Canceled" })))
+}
+
+#[derive(Debug, Deserialize)]
+struct CancelFilter {
+    repository: Option<String>,
+    branch: Option<String>,
+    /// Only cancel workflows created at least this many seconds ago
+    older_than_secs: Option<i64>,
+}
+
+/// Cancel every unfinished workflow matching the filter
+async fn cancel_workflows(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiJson(filter): ApiJson<CancelFilter>,
+) -> Result<Json<Value>, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Cancel).await?;
+
+    if filter.repository.is_none() && filter.branch.is_none() && filter.older_than_secs.is_none() {
+        // Refuse to cancel everything by accident
+        return Err(ApiError::bad_request(
+            "At least one of repository, branch or older_than_secs is required",
+        ));
+    }
+
+    let mut query = sqlx::QueryBuilder::<sqlx::Sqlite>::new(
+        "SELECT id FROM workflows WHERE status IN ('Pending', 'Running')",
+    );
+    query
+        .push(" AND repository NOT IN (SELECT value FROM json_each(")
+        .push_bind(principal.hidden_json())
+        .push("))");
+    if let Some(repository) = &filter.repository {
+        query.push(" AND repository = ").push_bind(repository);
+    }
+    if let Some(branch) = &filter.branch {
+        query.push(" AND branch = ").push_bind(branch);
+    }
+    if let Some(older_than) = filter.older_than_secs {
+        let cutoff = chrono::Utc::now().timestamp() - older_than;
+        query.push(" AND created_at <= ").push_bind(cutoff);
+    }
+
+    let ids: Vec<i64> = query
+        .build_query_scalar()
+        .fetch_all(&app_state.db_pool)
+        .await?;
+
+    let mut canceled = Vec::new();
+    for id in ids {
+        if cancel(&app_state, id).await? {
+            canceled.push(id);
+        }
+    }
+    info!("Bulk canceled {} workflows: {:?}", canceled.len(), canceled);
+
+    Ok(Json(json!({ "canceled": canceled })))
+}
+
+/// Mark a workflow canceled and remove it from the queue.
+/// Returns false if the workflow does not exist or already finished.
+async fn cancel(app_state: &crate::AppState, id: i64) -> Result<bool, ApiError> {
+    let result = sqlx::query(
+        r#"
+        UPDATE workflows SET status = 'Canceled', finished_at = ?
+        WHERE id = ? AND status IN ('Pending', 'Running')
+        "#,
+    )
+    .bind(chrono::Utc::now().timestamp())
+    .bind(id)
+    .execute(&app_state.db_pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    let jobs = app_state.build_queue.cancel_workflow(id);
+    info!(
+        "Workflow {} canceled, dropping {} unfinished jobs",
+        id, jobs
+    );
+    crate::pipeline::skip_remaining(&app_state.db_pool, id).await?;
+    app_state.events.publish(Event::WorkflowStatus {
+        workflow_id: id,
+        status: WorkflowStatus::Canceled,
+    });
+    Ok(true)
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct BuildRow {
+    drv_path: String,
+    name: String,
+    system: String,
+    status: String,
+    started_at: Option<i64>,
+    finished_at: Option<i64>,
+    error_message: Option<String>,
+    failure_cause: Option<String>,
+    retries: i64,
+    signed: Option<bool>,
+    upload_status: Option<String>,
+    upload_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct BuildResponse {
+    drv_path: String,
+    name: String,
+    system: String,
+    status: String,
+    started_at: Option<i64>,
+    finished_at: Option<i64>,
+    error_message: Option<String>,
+    /// The likely cause of a failure, when the log gave it away
+    failure: Option<Diagnosis>,
+    /// Times the build was retried after failing for a transient reason
+    retries: i64,
+    /// Whether the outputs were signed before their upload; null until uploaded
+    signed: Option<bool>,
+    /// "pending", "uploading", "done" or "failed" once a successful build is queued
+    /// for upload
+    upload_status: Option<String>,
+    /// Why the last upload attempt failed
+    upload_error: Option<String>,
+    /// For an aggregate job, the derivations it is made of
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    constituents: Vec<String>,
+    /// The aggregate jobs this derivation is a constituent of
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    aggregates: Vec<String>,
+    /// What the build is doing, while it runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    progress: Option<BuildProgress>,
+}
+
+impl BuildResponse {
+    fn new(
+        app_state: &crate::AppState,
+        row: BuildRow,
+        constituents: Vec<String>,
+        aggregates: Vec<String>,
+    ) -> Self {
+        BuildResponse {
+            failure: row
+                .failure_cause
+                .as_deref()
+                .and_then(Cause::parse)
+                .map(Diagnosis::from),
+            progress: app_state.build_queue.progress(&row.drv_path),
+            drv_path: row.drv_path,
+            name: row.name,
+            system: row.system,
+            status: row.status,
+            started_at: row.started_at,
+            finished_at: row.finished_at,
+            error_message: row.error_message,
+            retries: row.retries,
+            signed: row.signed,
+            upload_status: row.upload_status,
+            upload_error: row.upload_error,
+            constituents,
+            aggregates,
+        }
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct BuildListRow {
+    /// Cursor of the list, in the order builds were first recorded
+    id: i64,
+    #[sqlx(flatten)]
+    build: BuildRow,
+}
+
+#[derive(Debug, Deserialize)]
+struct BuildFilter {
+    /// Only builds with this status, e.g. "failed"
+    status: Option<String>,
+    /// Only builds requested by this workflow
+    workflow: Option<i64>,
+    system: Option<String>,
+    /// Only builds whose names contain this, ignoring case
+    q: Option<String>,
+}
+
+/// Recorded builds, latest first; the aggregate relations are only given by
+/// `/api/builds/{drv}`
+async fn builds(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiQuery(page): ApiQuery<PageParams>,
+    ApiQuery(filter): ApiQuery<BuildFilter>,
+) -> Result<Json<Page<BuildResponse>>, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::View).await?;
+    let limit = page.limit();
+    // Builds only needed by workflows of hidden repositories are left out
+    let rows = sqlx::query_as::<_, BuildListRow>(
+        r#"
+        SELECT rowid AS id, drv_path, name, system, status, started_at, finished_at,
+            error_message, failure_cause, retries, signed, upload_status, upload_error
+        FROM builds
+        WHERE rowid < ?1
+          AND (?2 IS NULL OR status = ?2)
+          AND (?3 IS NULL OR drv_path IN (
+              SELECT drv_path FROM build_workflows WHERE workflow_id = ?3))
+          AND (?4 IS NULL OR system = ?4)
+          AND (?5 IS NULL OR INSTR(LOWER(name), LOWER(?5)) > 0)
+          AND (NOT EXISTS (
+                  SELECT 1 FROM build_workflows bw JOIN workflows w ON w.id = bw.workflow_id
+                  WHERE bw.drv_path = builds.drv_path)
+              OR EXISTS (
+                  SELECT 1 FROM build_workflows bw JOIN workflows w ON w.id = bw.workflow_id
+                  WHERE bw.drv_path = builds.drv_path
+                    AND w.repository NOT IN (SELECT value FROM json_each(?7))))
+        ORDER BY rowid DESC LIMIT ?6
+        "#,
+    )
+    .bind(page.after()?.unwrap_or(i64::MAX))
+    .bind(&filter.status)
+    .bind(filter.workflow)
+    .bind(&filter.system)
+    .bind(&filter.q)
+    .bind(limit + 1)
+    .bind(principal.hidden_json())
+    .fetch_all(&app_state.db_pool)
+    .await?;
+
+    let page = Page::new(rows, limit, |row| row.id);
+    Ok(Json(Page {
+        items: page
+            .items
+            .into_iter()
+            .map(|row| BuildResponse::new(&app_state, row.build, Vec::new(), Vec::new()))
+            .collect(),
+        next_cursor: page.next_cursor,
+    }))
+}
+
+/// A build of a derivation (given by its store path basename)
+async fn build(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(drv): ApiPath<String>,
+) -> Result<Json<BuildResponse>, ApiError> {
+    let drv_path = drv_store_path(&drv)?;
+    authorize_build(&app_state, &headers, Permission::View, &drv_path).await?;
+
+    let row = sqlx::query_as::<_, BuildRow>(
+        r#"
+        SELECT drv_path, name, system, status, started_at, finished_at, error_message,
+            failure_cause, retries, signed, upload_status, upload_error
+        FROM builds WHERE drv_path = ?
+        "#,
+    )
+    .bind(&drv_path)
+    .fetch_optional(&app_state.db_pool)
+    .await?
+    .ok_or_else(|| ApiError::not_found(format!("No build for {}", drv_path)))?;
+
+    let constituents = sqlx::query_scalar::<_, String>(
+        "SELECT constituent FROM aggregate_constituents WHERE aggregate = ? ORDER BY constituent",
+    )
+    .bind(&drv_path)
+    .fetch_all(&app_state.db_pool)
+    .await?;
+    let aggregates = sqlx::query_scalar::<_, String>(
+        "SELECT aggregate FROM aggregate_constituents WHERE constituent = ? ORDER BY aggregate",
+    )
+    .bind(&drv_path)
+    .fetch_all(&app_state.db_pool)
+    .await?;
+
+    Ok(Json(BuildResponse::new(
+        &app_state,
+        row,
+        constituents,
+        aggregates,
+    )))
+}
+
+/// Upload the outputs of a successful build (given by its store path basename) to
+/// the cache again, e.g. after its upload failed
+async fn reupload_build(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(drv): ApiPath<String>,
+) -> Result<Json<Value>, ApiError> {
+    let drv_path = drv_store_path(&drv)?;
+    authorize_build(&app_state, &headers, Permission::Trigger, &drv_path).await?;
+
+    let status = sqlx::query_scalar::<_, String>("SELECT status FROM builds WHERE drv_path = ?")
+        .bind(&drv_path)
+        .fetch_optional(&app_state.db_pool)
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("No build for {}", drv_path)))?;
+    if !app_state.uploads.reupload(&drv_path).await? {
+        return Err(ApiError::conflict(if status == "success" {
+            format!("The upload of {} is already queued", drv_path)
+        } else {
+            format!("{} did not build successfully", drv_path)
+        }));
+    }
+    info!("Upload of {} queued again via API", drv_path);
+
+    Ok(Json(
+        json!({ "drv_path": drv_path, "upload_status": "pending" }),
+    ))
+}
+
+/// Fetch the build log of a derivation (given by its store path basename) from nix
+async fn build_log(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(drv): ApiPath<String>,
+) -> Result<String, ApiError> {
+    let drv_path = drv_store_path(&drv)?;
+    authorize_build(&app_state, &headers, Permission::View, &drv_path).await?;
+
+    nix::build_log(&drv_path)
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("No build log for {}", drv_path)))
+}
+
+/// Turn a derivation basename from the URL into its store path
+fn drv_store_path(drv: &str) -> Result<String, ApiError> {
+    if drv.contains('/') || !drv.ends_with(".drv") {
+        return Err(ApiError::bad_request(format!(
+            "'{}' is not a derivation basename",
+            drv
+        )));
+    }
+    Ok(format!("/nix/store/{}", drv))
+}
+
+/// Download an output of a successful build: single files are streamed from
+/// the local store, anything else redirects to the NAR in the binary cache
+async fn build_output(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath((drv, output)): ApiPath<(String, String)>,
+) -> Result<Response, ApiError> {
+    let drv_path = drv_store_path(&drv)?;
+    authorize_build(&app_state, &headers, Permission::View, &drv_path).await?;
+
+    let status: Option<String> = sqlx::query_scalar("SELECT status FROM builds WHERE drv_path = ?")
+        .bind(&drv_path)
+        .fetch_optional(&app_state.db_pool)
+        .await?;
+    match status.as_deref() {
+        None => return Err(ApiError::not_found(format!("No build for {}", drv_path))),
+        Some("success") | Some("cached") => {}
+        Some(status) => {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                "build_not_successful",
+                format!("Build of {} did not succeed", drv_path),
+            )
+            .with_details(json!({ "status": status })))
+        }
+    }
+
+    let outputs = nix::derivation_outputs(&drv_path).await?;
+    let out_path = outputs
+        .get(&output)
+        .ok_or_else(|| ApiError::not_found(format!("{} has no output '{}'", drv_path, output)))?;
+
+    if let Ok(metadata) = tokio::fs::metadata(out_path).await {
+        if metadata.is_file() {
+            let file = tokio::fs::File::open(out_path)
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to open {}: {}", out_path, e)))?;
+            // Store paths are "<hash>-<name>", offer the name as the file name
+            let file_name = out_path
+                .rsplit('/')
+                .next()
+                .and_then(|base| base.split_once('-'))
+                .map(|(_, name)| name)
+                .unwrap_or(out_path);
+            info!("Streaming artifact {}", out_path);
+            return Ok((
+                [
+                    (CONTENT_TYPE, "application/octet-stream".to_string()),
+                    (CONTENT_LENGTH, metadata.len().to_string()),
+                    (
+                        CONTENT_DISPOSITION,
+                        format!("attachment; filename=\"{}\"", file_name),
+                    ),
+                ],
+                Body::from_stream(ReaderStream::new(file)),
+            )
+                .into_response());
+        }
+    }
+
+    let cache_client = CacheClient::new(app_state.cache_config.clone());
+    match cache_client.nar_url(out_path).await? {
+        Some(url) => Ok(Redirect::temporary(&url).into_response()),
+        None => Err(ApiError::not_found(format!(
+            "{} is not a single file and is not available from the cache",
+            out_path
+        ))),
+    }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct RepositoryRow {
+    id: i64,
+    full_name: String,
+    clone_url: String,
+    created_at: i64,
+    organization: Option<String>,
+    project: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RepositoryFilter {
+    /// Only repositories of this organization
+    org: Option<String>,
+    /// Only repositories of this project (within `org`, if given)
+    project: Option<String>,
+}
+
+async fn repositories(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiQuery(page): ApiQuery<PageParams>,
+    ApiQuery(filter): ApiQuery<RepositoryFilter>,
+) -> Result<Json<Page<RepositoryRow>>, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::View).await?;
+    let limit = page.limit();
+    let repositories = sqlx::query_as::<_, RepositoryRow>(
+        r#"
+        SELECT r.id, r.full_name, r.clone_url, r.created_at,
+               o.name AS organization, p.name AS project
+        FROM repositories r
+        LEFT JOIN projects p ON p.id = r.project_id
+        LEFT JOIN organizations o ON o.id = p.organization_id
+        WHERE r.id > ? AND (? IS NULL OR o.name = ?) AND (? IS NULL OR p.name = ?)
+          AND r.full_name NOT IN (SELECT value FROM json_each(?))
+        ORDER BY r.id LIMIT ?
+        "#,
+    )
+    .bind(page.after()?.unwrap_or(0))
+    .bind(&filter.org)
+    .bind(&filter.org)
+    .bind(&filter.project)
+    .bind(&filter.project)
+    .bind(principal.hidden_json())
+    .bind(limit + 1)
+    .fetch_all(&app_state.db_pool)
+    .await?;
+
+    Ok(Json(Page::new(repositories, limit, |r| r.id)))
+}
+
+#[derive(Debug, Deserialize)]
+struct EvaluateRequest {
+    /// Branch, tag or commit to evaluate
+    #[serde(rename = "ref")]
+    git_ref: String,
+    /// Attribute set to evaluate; defaults to the configured one
+    attribute_set: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct EvaluatedDerivation {
+    name: String,
+    drv_path: String,
+    system: String,
+    outputs: Vec<String>,
+    input_drvs: Vec<String>,
+    cached: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct EvaluateResponse {
+    repository: String,
+    #[serde(rename = "ref")]
+    git_ref: String,
+    attribute_set: String,
+    total: usize,
+    cached: usize,
+    /// Derivations that would have to be built
+    to_build: Vec<String>,
+    derivations: Vec<EvaluatedDerivation>,
+    /// Attributes that failed to evaluate
+    errors: Vec<EvalError>,
+}
+
+/// Clone and evaluate a ref and report what a workflow would build, without enqueueing anything
+async fn evaluate_repository(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+    ApiJson(request): ApiJson<EvaluateRequest>,
+) -> Result<Json<EvaluateResponse>, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Trigger).await?;
+
+    let repository = sqlx::query_as::<_, RepositoryRow>(
+        r#"
+        SELECT r.id, r.full_name, r.clone_url, r.created_at,
+               o.name AS organization, p.name AS project
+        FROM repositories r
+        LEFT JOIN projects p ON p.id = r.project_id
+        LEFT JOIN organizations o ON o.id = p.organization_id
+        WHERE r.id = ?
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(&app_state.db_pool)
+    .await?
+    .filter(|repository| principal.sees(&repository.full_name))
+    .ok_or_else(|| ApiError::not_found(format!("Repository {} not found", id)))?;
+
+    let attribute_set = request
+        .attribute_set
+        .unwrap_or_else(|| app_state.webhook_config().attrset);
+    info!(
+        "Evaluating {} at {} ({}) without building",
+        repository.full_name, request.git_ref, attribute_set
+    );
+
+    let credential = app_state
+        .credentials
+        .get(&repository.full_name)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to load credentials: {}", e)))?;
+    let mut evaluator = app_state.evaluator(&repository.full_name);
+    let evaluation = evaluator
+        .evaluate_repository(
+            &repository.clone_url,
+            &request.git_ref,
+            &attribute_set,
+            credential.as_ref(),
+        )
+        .await
+        .map_err(|e| {
+            warn!("Evaluation of {} failed: {}", repository.full_name, e);
+            ApiError::unprocessable("Evaluation failed").with_details(json!({
+                "error": format!("{:#}", e)
+            }))
+        })?;
+
+    let cached_drvs = CacheClient::new(app_state.cache_config.clone())
+        .cached_derivations(&evaluation.derivations)
+        .await
+        .unwrap_or_else(|e| {
+            warn!(
+                "Failed to check the cache for {}: {:#}",
+                repository.full_name, e
+            );
+            HashSet::new()
+        });
+    let mut evaluated = Vec::with_capacity(evaluation.derivations.len());
+    for d in evaluation.derivations {
+        let cached = cached_drvs.contains(&d.drv_path);
+        evaluated.push(EvaluatedDerivation {
+            name: d.name,
+            drv_path: d.drv_path,
+            system: d.system,
+            outputs: d.outputs,
+            input_drvs: d.input_drvs,
+            cached,
+        });
+    }
+
+    Ok(Json(EvaluateResponse {
+        repository: repository.full_name,
+        git_ref: request.git_ref,
+        attribute_set,
+        total: evaluated.len(),
+        cached: evaluated.iter().filter(|d| d.cached).count(),
+        to_build: evaluated
+            .iter()
+            .filter(|d| !d.cached)
+            .map(|d| d.drv_path.clone())
+            .collect(),
+        derivations: evaluated,
+        errors: evaluation.errors,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{header::AUTHORIZATION, HeaderValue};
+
+    fn admin() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer admin"));
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_evaluate_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_state = crate::AppState::for_tests(dir.path()).await;
+        let clone_url = format!("file://{}/missing", dir.path().display());
+        let id: i64 = sqlx::query_scalar(
+            "INSERT INTO repositories (full_name, clone_url, created_at) VALUES (?, ?, 0) \
+             RETURNING id",
+        )
+        .bind("me/repo")
+        .bind(&clone_url)
+        .fetch_one(&app_state.db_pool)
+        .await
+        .unwrap();
+        let request = |id| {
+            evaluate_repository(
+                State(app_state.clone()),
+                admin(),
+                ApiPath(id),
+                ApiJson(EvaluateRequest {
+                    git_ref: "main".to_string(),
+                    attribute_set: None,
+                }),
+            )
+        };
+
+        // Found, then fails to clone
+        let error = request(id).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let error = request(id + 1).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_create_workflow_keeps_clone_url() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_state = crate::AppState::for_tests(dir.path()).await;
+        let clone_url = "https://github.com/me/private.git";
+        sqlx::query("INSERT INTO repositories (full_name, clone_url, created_at) VALUES (?, ?, 0)")
+            .bind("me/private")
+            .bind(clone_url)
+            .execute(&app_state.db_pool)
+            .await
+            .unwrap();
+
+        // A repository of the caller's, where its credentials would otherwise go
+        let other = dir.path().join("other");
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        let path = other.to_str().unwrap();
+        git(&["init", "-q", "-b", "main", path]);
+        git(&["-C", path, "commit", "-q", "--allow-empty", "-m", "init"]);
+        let error = create_workflow(
+            State(app_state.clone()),
+            admin(),
+            ApiJson(NewWorkflow {
+                clone_url: Some(format!("file://{}", other.display())),
+                git_ref: "main".to_string(),
+                attribute_set: None,
+                repository: Some("me/private".to_string()),
+                branch: None,
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let stored: String =
+            sqlx::query_scalar("SELECT clone_url FROM repositories WHERE full_name = 'me/private'")
+                .fetch_one(&app_state.db_pool)
+                .await
+                .unwrap();
+        assert_eq!(stored, clone_url);
+    }
+
+    /// Headers of a viewer, a member of `organization` if given
+    async fn viewer(
+        app_state: &crate::AppState,
+        name: &str,
+        organization: Option<i64>,
+    ) -> HeaderMap {
+        let db_pool = &app_state.db_pool;
+        let user_id: i64 = sqlx::query_scalar(
+            "INSERT INTO users (name, role, created_at) VALUES (?, 'viewer', 0) RETURNING id",
+        )
+        .bind(name)
+        .fetch_one(db_pool)
+        .await
+        .unwrap();
+        let token = format!("icicle_{}", name);
+        sqlx::query(
+            "INSERT INTO api_tokens (user_id, name, token_hash, scope, created_at) \
+             VALUES (?, 'test', ?, 'read', 0)",
+        )
+        .bind(user_id)
+        .bind(auth::hash_token(&token))
+        .execute(db_pool)
+        .await
+        .unwrap();
+        if let Some(organization) = organization {
+            sqlx::query(
+                "INSERT INTO organization_members (organization_id, user_id) VALUES (?, ?)",
+            )
+            .bind(organization)
+            .bind(user_id)
+            .execute(db_pool)
+            .await
+            .unwrap();
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+        );
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_organization_isolation() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_state = crate::AppState::for_tests(dir.path()).await;
+        let db_pool = &app_state.db_pool;
+        let organization: i64 = sqlx::query_scalar(
+            "INSERT INTO organizations (name, created_at) VALUES ('acme', 0) RETURNING id",
+        )
+        .fetch_one(db_pool)
+        .await
+        .unwrap();
+        let project: i64 = sqlx::query_scalar(
+            "INSERT INTO projects (organization_id, name, created_at) VALUES (?, 'web', 0) \
+             RETURNING id",
+        )
+        .bind(organization)
+        .fetch_one(db_pool)
+        .await
+        .unwrap();
+        // acme/app belongs to the organization, tools/lint to none
+        for (name, project) in [("acme/app", Some(project)), ("tools/lint", None)] {
+            sqlx::query(
+                "INSERT INTO repositories (full_name, clone_url, created_at, project_id) \
+                 VALUES (?, ?, 0, ?)",
+            )
+            .bind(name)
+            .bind(format!("https://example.com/{}.git", name))
+            .bind(project)
+            .execute(db_pool)
+            .await
+            .unwrap();
+        }
+        let mut workflow_ids = Vec::new();
+        for (repository, drv_path) in [
+            ("acme/app", "/nix/store/aaaa-app.drv"),
+            ("tools/lint", "/nix/store/bbbb-lint.drv"),
+        ] {
+            let id: i64 = sqlx::query_scalar(
+                "INSERT INTO workflows (repository, commit_sha, attribute_set, status, created_at) \
+                 VALUES (?, 'abc', 'checks', 'Completed', 0) RETURNING id",
+            )
+            .bind(repository)
+            .fetch_one(db_pool)
+            .await
+            .unwrap();
+            sqlx::query("INSERT INTO builds (drv_path, name, system, status) VALUES (?, ?, 'x86_64-linux', 'success')")
+                .bind(drv_path)
+                .bind(repository)
+                .execute(db_pool)
+                .await
+                .unwrap();
+            sqlx::query("INSERT INTO build_workflows (drv_path, workflow_id) VALUES (?, ?)")
+                .bind(drv_path)
+                .bind(id)
+                .execute(db_pool)
+                .await
+                .unwrap();
+            workflow_ids.push(id);
+        }
+        let member = viewer(&app_state, "alice", Some(organization)).await;
+        let outsider = viewer(&app_state, "bob", None).await;
+
+        let repositories_of = |headers: HeaderMap| {
+            let app_state = app_state.clone();
+            async move {
+                let page = workflows(
+                    State(app_state),
+                    headers,
+                    ApiQuery(PageParams {
+                        limit: None,
+                        cursor: None,
+                    }),
+                    ApiQuery(WorkflowFilter {
+                        repository: None,
+                        branch: None,
+                        status: None,
+                    }),
+                )
+                .await
+                .unwrap();
+                let mut repositories: Vec<String> = page
+                    .0
+                    .items
+                    .into_iter()
+                    .map(|w| w.workflow.repository)
+                    .collect();
+                repositories.sort();
+                repositories
+            }
+        };
+        assert_eq!(repositories_of(admin()).await, ["acme/app", "tools/lint"]);
+        assert_eq!(
+            repositories_of(member.clone()).await,
+            ["acme/app", "tools/lint"]
+        );
+        assert_eq!(repositories_of(outsider.clone()).await, ["tools/lint"]);
+        // Anonymous visitors of the public instance are members of nothing
+        assert_eq!(repositories_of(HeaderMap::new()).await, ["tools/lint"]);
+
+        let repos = |headers| {
+            repositories(
+                State(app_state.clone()),
+                headers,
+                ApiQuery(PageParams {
+                    limit: None,
+                    cursor: None,
+                }),
+                ApiQuery(RepositoryFilter {
+                    org: None,
+                    project: None,
+                }),
+            )
+        };
+        let names = |page: Json<Page<RepositoryRow>>| -> Vec<String> {
+            page.0.items.into_iter().map(|r| r.full_name).collect()
+        };
+        assert_eq!(
+            names(repos(member.clone()).await.unwrap()),
+            ["acme/app", "tools/lint"]
+        );
+        assert_eq!(
+            names(repos(outsider.clone()).await.unwrap()),
+            ["tools/lint"]
+        );
+
+        let listed_builds = |headers| {
+            builds(
+                State(app_state.clone()),
+                headers,
+                ApiQuery(PageParams {
+                    limit: None,
+                    cursor: None,
+                }),
+                ApiQuery(BuildFilter {
+                    status: None,
+                    workflow: None,
+                    system: None,
+                    q: None,
+                }),
+            )
+        };
+        let drv_paths = |page: Json<Page<BuildResponse>>| -> Vec<String> {
+            page.0.items.into_iter().map(|b| b.drv_path).collect()
+        };
+        assert_eq!(
+            drv_paths(listed_builds(member.clone()).await.unwrap()),
+            ["/nix/store/bbbb-lint.drv", "/nix/store/aaaa-app.drv"]
+        );
+        assert_eq!(
+            drv_paths(listed_builds(outsider.clone()).await.unwrap()),
+            ["/nix/store/bbbb-lint.drv"]
+        );
+
+        // Outsiders are told the organization's workflows and builds do not exist
+        let hidden = workflow_ids[0];
+        let found = workflow(State(app_state.clone()), member.clone(), ApiPath(hidden))
+            .await
+            .unwrap();
+        assert_eq!(found.0.repository, "acme/app");
+        let error = workflow(State(app_state.clone()), outsider.clone(), ApiPath(hidden))
+            .await
+            .unwrap_err();
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+        let found = workflow(
+            State(app_state.clone()),
+            outsider.clone(),
+            ApiPath(workflow_ids[1]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(found.0.repository, "tools/lint");
+
+        let drv = || ApiPath("aaaa-app.drv".to_string());
+        let found = build(State(app_state.clone()), member.clone(), drv())
+            .await
+            .unwrap();
+        assert_eq!(found.0.name, "acme/app");
+        let error = build(State(app_state.clone()), outsider.clone(), drv())
+            .await
+            .unwrap_err();
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+        let error = build_log(State(app_state.clone()), outsider, drv())
+            .await
+            .unwrap_err();
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+    }
+}
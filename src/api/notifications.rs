@@ -0,0 +1,187 @@
+use super::{authorize, ApiError, ApiJson, ApiPath};
+use crate::auth::Permission;
+use crate::notify::{NotifyRules, NOTIFIERS};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::Json,
+    routing::{delete, get},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new()
+        .route(
+            "/api/repos/{id}/notifications",
+            get(list_targets).post(create_target),
+        )
+        .route(
+            "/api/repos/{id}/notifications/{target_id}",
+            delete(delete_target),
+        )
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct TargetRow {
+    id: i64,
+    notifier: String,
+    target: String,
+    rules: Option<String>,
+    created_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct Target {
+    id: i64,
+    notifier: String,
+    target: String,
+    rules: Option<NotifyRules>,
+    created_at: i64,
+}
+
+impl From<TargetRow> for Target {
+    fn from(row: TargetRow) -> Self {
+        Target {
+            id: row.id,
+            notifier: row.notifier,
+            target: row.target,
+            rules: row
+                .rules
+                .and_then(|rules| serde_json::from_str(&rules).ok()),
+            created_at: row.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct NewTarget {
+    notifier: String,
+    target: String,
+    /// Rules for this target; the notifier's rules apply when unset
+    rules: Option<NotifyRules>,
+}
+
+async fn ensure_repository(app_state: &crate::AppState, id: i64) -> Result<(), ApiError> {
+    sqlx::query_scalar::<_, i64>("SELECT id FROM repositories WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&app_state.db_pool)
+        .await?
+        .map(|_| ())
+        .ok_or_else(|| ApiError::not_found(format!("Repository {} not found", id)))
+}
+
+/// Targets can embed secrets (webhook URLs), so listing them needs the admin role too
+async fn list_targets(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<Vec<Target>>, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+    ensure_repository(&app_state, id).await?;
+
+    let rows = sqlx::query_as::<_, TargetRow>(
+        r#"
+        SELECT id, notifier, target, rules, created_at
+        FROM repository_notifications WHERE repository_id = ?
+        ORDER BY id
+        "#,
+    )
+    .bind(id)
+    .fetch_all(&app_state.db_pool)
+    .await?;
+
+    Ok(Json(rows.into_iter().map(Target::from).collect()))
+}
+
+async fn create_target(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+    ApiJson(request): ApiJson<NewTarget>,
+) -> Result<(StatusCode, Json<Target>), ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+    ensure_repository(&app_state, id).await?;
+
+    if !NOTIFIERS.contains(&request.notifier.as_str()) {
+        return Err(ApiError::unprocessable(format!(
+            "Unknown notifier '{}', expected one of: {}",
+            request.notifier,
+            NOTIFIERS.join(", ")
+        )));
+    }
+    let target = request.target.trim();
+    if target.is_empty() {
+        return Err(ApiError::unprocessable("Target must not be empty"));
+    }
+
+    let rules = request
+        .rules
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()
+        .map_err(|e| ApiError::internal(format!("Failed to encode rules: {}", e)))?;
+    let now = chrono::Utc::now().timestamp();
+    let target_id = sqlx::query(
+        r#"
+        INSERT INTO repository_notifications (repository_id, notifier, target, rules, created_at)
+        VALUES (?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(id)
+    .bind(&request.notifier)
+    .bind(target)
+    .bind(rules)
+    .bind(now)
+    .execute(&app_state.db_pool)
+    .await?
+    .last_insert_rowid();
+
+    info!(
+        "Added {} notification target {} to repository {}",
+        request.notifier, target_id, id
+    );
+    Ok((
+        StatusCode::CREATED,
+        Json(Target {
+            id: target_id,
+            notifier: request.notifier,
+            target: target.to_string(),
+            rules: request.rules,
+            created_at: now,
+        }),
+    ))
+}
+
+async fn delete_target(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath((id, target_id)): ApiPath<(i64, i64)>,
+) -> Result<StatusCode, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let deleted = sqlx::query(
+        r#"
+        DELETE FROM repository_notifications WHERE id = ? AND repository_id = ?
+        "#,
+    )
+    .bind(target_id)
+    .bind(id)
+    .execute(&app_state.db_pool)
+    .await?
+    .rows_affected();
+
+    if deleted == 0 {
+        return Err(ApiError::not_found(format!(
+            "Notification target {} not found for repository {}",
+            target_id, id
+        )));
+    }
+    info!(
+        "Removed notification target {} from repository {}",
+        target_id, id
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,819 @@
+use crate::auth::Permission;
+use axum::{response::Json, routing::get, Router};
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new().route("/api/openapi.json", get(openapi))
+}
+
+/// Who may call an operation
+#[derive(Clone, Copy)]
+enum Access {
+    /// Anyone, e.g. health checks
+    Public,
+    /// An API token, or the admin token, whose role allows the permission
+    Token(Permission),
+    /// The workers' shared token
+    Worker,
+    /// A webhook delivery signed with the webhook secret
+    Signed,
+}
+
+/// What an operation answers with when it succeeds
+enum Body {
+    Json(Value),
+    Text(&'static str),
+    Binary,
+    /// Server-sent events, each a JSON object
+    Events,
+    Empty,
+}
+
+struct Operation {
+    method: &'static str,
+    /// As given to the router; `{*rest}` captures are described as plain parameters
+    path: &'static str,
+    id: &'static str,
+    tag: &'static str,
+    summary: &'static str,
+    access: Access,
+    /// Name, JSON schema type and description of each query parameter
+    query: &'static [(&'static str, &'static str, &'static str)],
+    request: Option<Value>,
+    status: u16,
+    response: Body,
+}
+
+fn op(
+    method: &'static str,
+    path: &'static str,
+    id: &'static str,
+    tag: &'static str,
+    summary: &'static str,
+    access: Access,
+) -> Operation {
+    Operation {
+        method,
+        path,
+        id,
+        tag,
+        summary,
+        access,
+        query: &[],
+        request: None,
+        status: 200,
+        response: Body::Json(object()),
+    }
+}
+
+impl Operation {
+    fn query(mut self, query: &'static [(&'static str, &'static str, &'static str)]) -> Self {
+        self.query = query;
+        self
+    }
+
+    fn request(mut self, schema: Value) -> Self {
+        self.request = Some(schema);
+        self
+    }
+
+    fn responds(mut self, status: u16, response: Body) -> Self {
+        self.status = status;
+        self.response = response;
+        self
+    }
+
+    fn json(self, schema: Value) -> Self {
+        self.responds(200, Body::Json(schema))
+    }
+}
+
+fn schema(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn array(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+/// A response whose fields are not described further
+fn object() -> Value {
+    json!({ "type": "object" })
+}
+
+/// A page of a cursor-paginated list
+fn page(items: Value) -> Value {
+    json!({
+        "type": "object",
+        "required": ["items", "next_cursor"],
+        "properties": {
+            "items": array(items),
+            "next_cursor": {
+                "type": ["string", "null"],
+                "description": "Pass as `cursor` to get the next page; null on the last one"
+            }
+        }
+    })
+}
+
+const PAGE: &[(&str, &str, &str)] = &[
+    (
+        "limit",
+        "integer",
+        "Items per page, 50 by default and at most 500",
+    ),
+    ("cursor", "string", "next_cursor of the previous page"),
+];
+
+fn operations() -> Vec<Operation> {
+    use Access::*;
+    use Permission::*;
+    vec![
+        op("get", "/api", "root", "meta", "Name and version of the server", Public),
+        op("get", "/api/openapi.json", "openapi", "meta", "This document", Public),
+        op("get", "/health", "health", "health", "Whether the server is up", Public),
+        op("get", "/health/live", "health_live", "health", "Whether the server is up", Public),
+        op("get", "/health/ready", "health_ready", "health", "Whether the server can take work: database, nix and cache are reachable", Public),
+        op("get", "/api/events", "events", "events", "Stream every status transition as server-sent JSON events", Token(View))
+            .query(&[("workflow", "integer", "Only events concerning this workflow")])
+            .responds(200, Body::Events),
+        // Queue and workflows
+        op("get", "/api/queue", "queue", "queue", "Unfinished jobs in the queue with their blocking edges and workflow membership", Token(View))
+            .query(&[
+                ("status", "string", "Only jobs with this status, e.g. Queued"),
+                ("workflow", "integer", "Only jobs of this workflow"),
+                ("system", "string", "Only jobs for this system"),
+            ])
+            .json(schema("Queue")),
+        op("get", "/api/workflows", "list_workflows", "workflows", "Workflows, newest first", Token(View))
+            .query(&[
+                ("repository", "string", "Only workflows of this repository, e.g. owner/repo"),
+                ("branch", "string", "Only workflows of this branch"),
+                ("status", "string", "Only workflows with this status, ignoring case"),
+                ("limit", "integer", "Items per page, 50 by default and at most 500"),
+                ("cursor", "string", "next_cursor of the previous page"),
+            ])
+            .json(page(schema("WorkflowSummary"))),
+        op("post", "/api/workflows", "create_workflow", "workflows", "Start a workflow the way a webhook would, e.g. to re-run one without pushing", Token(Trigger))
+            .request(schema("NewWorkflow"))
+            .responds(201, Body::Json(schema("Workflow"))),
+        op("post", "/api/workflows/cancel", "cancel_workflows", "workflows", "Cancel every unfinished workflow matching the filter", Token(Cancel))
+            .request(json!({
+                "type": "object",
+                "properties": {
+                    "repository": { "type": "string" },
+                    "branch": { "type": "string" },
+                    "older_than_secs": {
+                        "type": "integer",
+                        "description": "Only cancel workflows created at least this many seconds ago"
+                    }
+                }
+            })),
+        op("get", "/api/workflows/{id}", "get_workflow", "workflows", "A workflow", Token(View))
+            .json(schema("Workflow")),
+        op("get", "/api/workflows/{id}/wait", "wait_workflow", "workflows", "Long-poll until the workflow reaches a terminal status", Token(View))
+            .query(&[("timeout", "integer", "Seconds to wait before giving up")])
+            .json(schema("Workflow")),
+        op("get", "/api/workflows/{id}/graph", "workflow_graph", "workflows", "The jobs of a workflow with their dependencies, live while they are queued", Token(View))
+            .json(schema("JobGraph")),
+        op("post", "/api/workflows/{id}/cancel", "cancel_workflow", "workflows", "Cancel an unfinished workflow and drop the jobs only it needed", Token(Cancel)),
+        op("get", "/api/workflows/{id}/stages", "workflow_stages", "workflows", "The stages of a workflow, in order", Token(View))
+            .json(array(object())),
+        op("post", "/api/workflows/{id}/approve", "approve_stage", "workflows", "Start the stage of a workflow waiting at an approval gate", Token(Trigger)),
+        op("get", "/api/workflows/{id}/artifacts", "workflow_artifacts", "artifacts", "Artifacts of every build a workflow requested", Token(View))
+            .json(array(object())),
+        op("get", "/api/workflows/{id}/tests", "workflow_tests", "workflows", "Summary of the JUnit results reported by a workflow's builds", Token(View)),
+        op("get", "/api/workflows/{id}/inputs", "workflow_inputs", "workflows", "The flake inputs a workflow was evaluated with", Token(View))
+            .json(array(object())),
+        op("get", "/api/workflows/{id}/deployments", "workflow_deployments", "deployments", "Deployments started by a workflow", Token(View))
+            .json(array(object())),
+        op("post", "/api/workflows/{id}/bisect", "start_bisection", "bisections", "Bisect a failed workflow against the last successful workflow of its branch", Token(Trigger))
+            .query(&[("attribute", "string", "The failed attribute to bisect, by default the workflow's first")]),
+        op("get", "/api/workflows/{id}/bisections", "workflow_bisections", "bisections", "Bisections of a workflow", Token(View))
+            .json(array(object())),
+        op("get", "/api/bisections/{id}", "get_bisection", "bisections", "A bisection with the commits it built", Token(View)),
+        // Builds
+        op("get", "/api/builds", "list_builds", "builds", "Recorded builds, latest first", Token(View))
+            .query(&[
+                ("status", "string", "Only builds with this status, e.g. failed"),
+                ("workflow", "integer", "Only builds requested by this workflow"),
+                ("system", "string", "Only builds for this system"),
+                ("q", "string", "Only builds whose names contain this, ignoring case"),
+                ("limit", "integer", "Items per page, 50 by default and at most 500"),
+                ("cursor", "string", "next_cursor of the previous page"),
+            ])
+            .json(page(schema("Build"))),
+        op("get", "/api/builds/{drv}", "get_build", "builds", "A build of a derivation", Token(View))
+            .json(schema("Build")),
+        op("get", "/api/builds/{drv}/log", "build_log", "builds", "The build log nix kept", Token(View))
+            .responds(200, Body::Text("text/plain")),
+        op("post", "/api/builds/{drv}/upload", "reupload_build", "builds", "Upload the outputs of a successful build to the cache again, e.g. after its upload failed", Token(Trigger)),
+        op("get", "/api/builds/{drv}/outputs/{output}", "build_output", "builds", "Download an output of a successful build; anything but a single file redirects to its NAR in the binary cache", Token(View))
+            .responds(200, Body::Binary),
+        op("get", "/api/builds/{drv}/diff", "build_diff", "builds", "What changed in a failed build's derivation since the attribute last built successfully", Token(View)),
+        op("get", "/api/builds/{drv}/sbom", "build_sbom", "builds", "CycloneDX SBOM of a successful build's runtime closure", Token(View)),
+        op("get", "/api/builds/{drv}/provenance", "build_provenance", "builds", "The signed provenance of a successful build, as a DSSE envelope", Token(View)),
+        op("get", "/api/provenance/key", "provenance_key", "builds", "The public key verifying build provenance", Token(View))
+            .responds(200, Body::Text("application/x-pem-file")),
+        op("get", "/api/artifacts/{id}", "download_artifact", "artifacts", "Download a file artifact; directories redirect to their index", Token(View))
+            .responds(200, Body::Binary),
+        op("get", "/api/artifacts/{id}/", "artifact_index", "artifacts", "The index of a directory artifact", Token(View))
+            .responds(200, Body::Binary),
+        op("get", "/api/artifacts/{id}/{*path}", "artifact_file", "artifacts", "A file from within a directory artifact", Token(View))
+            .responds(200, Body::Binary),
+        // Repositories, organizations and users
+        op("get", "/api/repos", "list_repositories", "repositories", "Registered repositories", Token(View))
+            .query(&[
+                ("org", "string", "Only repositories of this organization"),
+                ("project", "string", "Only repositories of this project"),
+                ("limit", "integer", "Items per page, 50 by default and at most 500"),
+                ("cursor", "string", "next_cursor of the previous page"),
+            ])
+            .json(page(schema("Repository"))),
+        op("post", "/api/repos/{id}/evaluate", "evaluate_repository", "repositories", "Evaluate a ref and report what a workflow would build, without enqueueing anything", Token(Trigger))
+            .request(json!({
+                "type": "object",
+                "required": ["ref"],
+                "properties": {
+                    "ref": { "type": "string", "description": "Branch, tag or commit to evaluate" },
+                    "attribute_set": { "type": "string" }
+                }
+            })),
+        op("put", "/api/repos/{id}/project", "assign_repository", "organizations", "Move a repository into a project", Token(Manage))
+            .request(object())
+            .responds(204, Body::Empty),
+        op("get", "/api/repos/{id}/credentials", "show_credential", "repositories", "The credential set through the API; configured ones are not shown", Token(Manage)),
+        op("put", "/api/repos/{id}/credentials", "set_credential", "repositories", "Set the credential used to clone a repository", Token(Manage))
+            .request(object()),
+        op("delete", "/api/repos/{id}/credentials", "delete_credential", "repositories", "Remove the credential set through the API", Token(Manage))
+            .responds(204, Body::Empty),
+        op("get", "/api/repos/{id}/notifications", "list_notification_targets", "repositories", "Where a repository's results are sent", Token(Manage))
+            .json(array(object())),
+        op("post", "/api/repos/{id}/notifications", "create_notification_target", "repositories", "Send a repository's results somewhere more", Token(Manage))
+            .request(object())
+            .responds(201, Body::Json(object())),
+        op("delete", "/api/repos/{id}/notifications/{target_id}", "delete_notification_target", "repositories", "Stop sending a repository's results somewhere", Token(Manage))
+            .responds(204, Body::Empty),
+        op("get", "/api/orgs", "list_organizations", "organizations", "Organizations", Token(View))
+            .json(array(object())),
+        op("post", "/api/orgs", "create_organization", "organizations", "Create an organization", Token(Manage))
+            .request(object())
+            .responds(201, Body::Json(object())),
+        op("get", "/api/orgs/{id}", "get_organization", "organizations", "An organization with its projects", Token(View)),
+        op("patch", "/api/orgs/{id}", "update_organization", "organizations", "Change an organization", Token(Manage))
+            .request(object()),
+        op("delete", "/api/orgs/{id}", "delete_organization", "organizations", "Delete an empty organization", Token(Manage))
+            .responds(204, Body::Empty),
+        op("post", "/api/orgs/{id}/projects", "create_project", "organizations", "Create a project in an organization", Token(Manage))
+            .request(object())
+            .responds(201, Body::Json(object())),
+        op("get", "/api/orgs/{id}/members", "list_members", "organizations", "The members of an organization", Token(View))
+            .json(array(object())),
+        op("put", "/api/orgs/{id}/members/{user_id}", "add_member", "organizations", "Add a user to an organization", Token(Manage))
+            .responds(204, Body::Empty),
+        op("delete", "/api/orgs/{id}/members/{user_id}", "remove_member", "organizations", "Remove a user from an organization", Token(Manage))
+            .responds(204, Body::Empty),
+        op("delete", "/api/projects/{id}", "delete_project", "organizations", "Delete a project; its repositories stay, outside any project", Token(Manage))
+            .responds(204, Body::Empty),
+        op("get", "/api/users", "list_users", "users", "Users", Token(Manage))
+            .json(array(object())),
+        op("post", "/api/users", "create_user", "users", "Create a user", Token(Manage))
+            .request(object())
+            .responds(201, Body::Json(object())),
+        op("get", "/api/users/{id}", "get_user", "users", "A user", Token(Manage)),
+        op("patch", "/api/users/{id}", "update_user", "users", "Change a user", Token(Manage))
+            .request(object()),
+        op("delete", "/api/users/{id}", "delete_user", "users", "Delete a user and revoke their tokens", Token(Manage))
+            .responds(204, Body::Empty),
+        op("get", "/api/users/{id}/tokens", "list_tokens", "users", "A user's API tokens, without their secrets", Token(Manage))
+            .json(array(schema("Token"))),
+        op("post", "/api/users/{id}/tokens", "create_token", "users", "Create an API token for a user; its secret is only shown in the response", Token(Manage))
+            .request(json!({
+                "type": "object",
+                "required": ["name", "scope"],
+                "properties": {
+                    "name": { "type": "string", "description": "What the token is for" },
+                    "scope": schema("Scope")
+                }
+            }))
+            .responds(201, Body::Json(json!({
+                "allOf": [
+                    schema("Token"),
+                    {
+                        "type": "object",
+                        "required": ["secret"],
+                        "properties": { "secret": { "type": "string", "description": "The bearer token" } }
+                    }
+                ]
+            }))),
+        op("delete", "/api/users/{id}/tokens/{token_id}", "delete_token", "users", "Revoke an API token", Token(Manage))
+            .responds(204, Body::Empty),
+        // Deployments, schedules and quotas
+        op("get", "/api/deployments", "latest_deployments", "deployments", "The latest deployment to each target", Token(View))
+            .json(array(object())),
+        op("get", "/api/deployments/{id}", "get_deployment", "deployments", "A deployment with the output of its command", Token(View)),
+        op("get", "/api/schedules", "list_schedules", "schedules", "Scheduled workflows", Token(View))
+            .json(array(object())),
+        op("post", "/api/schedules", "create_schedule", "schedules", "Schedule a workflow", Token(Manage))
+            .request(object())
+            .responds(201, Body::Json(object())),
+        op("delete", "/api/schedules/{id}", "delete_schedule", "schedules", "Delete a schedule", Token(Manage))
+            .responds(204, Body::Empty),
+        op("post", "/api/schedules/{id}/run", "run_schedule", "schedules", "Start a schedule's workflow now, without waiting for it to be due", Token(Trigger)),
+        op("get", "/api/quotas", "quotas", "admin", "This month's build minutes of every repository and organization against their quotas", Token(View))
+            .json(array(object())),
+        op("get", "/api/stats", "stats", "admin", "Success and cache hit rates, durations and queue waits of what finished within a window, in total, as a trend and by repository", Token(View))
+            .query(&[
+                ("window", "string", "day, week, month (the default) or quarter"),
+                ("repository", "string", "Only workflows of this repository and the builds they requested"),
+            ])
+            .json(schema("StatsReport")),
+        // Outgoing webhooks
+        op("get", "/api/hooks", "list_hooks", "hooks", "Endpoints receiving events as JSON", Token(Manage))
+            .json(array(schema("Hook"))),
+        op("post", "/api/hooks", "create_hook", "hooks", "Post events to an endpoint, retrying failed deliveries", Token(Manage))
+            .request(json!({
+                "type": "object",
+                "required": ["url"],
+                "properties": {
+                    "url": { "type": "string" },
+                    "secret": {
+                        "type": "string",
+                        "description": "Key of the HMAC-SHA256 of each payload, sent as sha256=<hex> in X-Icicle-Signature-256"
+                    },
+                    "events": {
+                        "type": "array",
+                        "items": { "type": "string" },
+                        "description": "Event types to post, e.g. workflow_status and job_status; all when empty"
+                    }
+                }
+            }))
+            .responds(201, Body::Json(schema("Hook"))),
+        op("delete", "/api/hooks/{id}", "delete_hook", "hooks", "Stop posting events to an endpoint", Token(Manage))
+            .responds(204, Body::Empty),
+        op("get", "/api/hooks/{id}/deliveries", "list_hook_deliveries", "hooks", "Deliveries to an endpoint, newest first", Token(Manage))
+            .query(PAGE)
+            .json(page(object())),
+        // Builders and workers
+        op("get", "/api/builders", "builders", "workers", "The remote builders with their running builds and whether they are reachable", Token(View))
+            .json(array(object())),
+        op("get", "/api/workers", "list_workers", "workers", "The registered workers and the jobs they are building", Token(View))
+            .json(array(object())),
+        op("post", "/api/workers", "register_worker", "workers", "Register a worker", Worker)
+            .request(object()),
+        op("post", "/api/workers/{id}/lease", "lease_job", "workers", "Lease a job, waiting a while for one; no content when there is none", Worker),
+        op("post", "/api/workers/{id}/leases/{lease_id}/status", "report_job", "workers", "Report the current step of a leased job; gone once the worker should stop it", Worker)
+            .request(object())
+            .responds(204, Body::Empty),
+        op("post", "/api/workers/{id}/leases/{lease_id}/result", "finish_job", "workers", "Report how a leased job ended", Worker)
+            .request(object())
+            .responds(204, Body::Empty),
+        // Administration
+        op("post", "/api/admin/pause", "pause", "admin", "Stop the executor from starting new builds; running builds are left to finish", Token(Manage)),
+        op("post", "/api/admin/resume", "resume", "admin", "Start new builds again", Token(Manage)),
+        op("get", "/api/admin/webhook-events", "list_webhook_events", "admin", "Stored webhook deliveries, newest first", Token(Manage))
+            .query(PAGE)
+            .json(page(object())),
+        op("get", "/api/admin/webhook-events/{id}", "get_webhook_event", "admin", "A stored webhook delivery with its payload and response", Token(Manage)),
+        op("post", "/api/admin/webhook-events/{id}/replay", "replay_webhook_event", "admin", "Handle a stored delivery again, e.g. after a transient failure", Token(Manage)),
+        // Webhooks
+        op("post", "/webhook/github", "github_webhook", "webhooks", "A GitHub delivery, signed in X-Hub-Signature-256", Signed)
+            .request(object()),
+        op("post", "/webhook/bitbucket", "bitbucket_webhook", "webhooks", "A Bitbucket delivery, signed in X-Hub-Signature", Signed)
+            .request(object()),
+    ]
+}
+
+/// Schemas of the main resources; the others are described as plain objects
+fn schemas() -> Value {
+    json!({
+        "Error": {
+            "type": "object",
+            "required": ["code", "message", "details"],
+            "properties": {
+                "code": { "type": "string", "description": "Machine-readable, e.g. not_found" },
+                "message": { "type": "string" },
+                "details": { "description": "More about the error, depending on its code" }
+            }
+        },
+        "EvalError": {
+            "type": "object",
+            "required": ["attr", "error"],
+            "properties": {
+                "attr": { "type": "string" },
+                "error": { "type": "string" }
+            }
+        },
+        "Workflow": {
+            "type": "object",
+            "required": ["id", "repository", "commit_sha", "branch", "attribute_set", "status",
+                "created_at", "release", "priority", "error"],
+            "properties": {
+                "id": { "type": "integer" },
+                "repository": { "type": "string" },
+                "commit_sha": { "type": "string" },
+                "branch": { "type": ["string", "null"] },
+                "attribute_set": { "type": "string" },
+                "status": { "type": "string", "enum": ["Pending", "Running", "Completed", "Failed", "Canceled"] },
+                "created_at": { "type": "integer", "description": "Unix time" },
+                "release": { "type": "boolean", "description": "Triggered by a tag, which branch holds" },
+                "priority": { "type": "integer" },
+                "error": { "type": ["string", "null"], "description": "Why it failed before building" },
+                "evaluation_errors": array(schema("EvalError"))
+            }
+        },
+        "WorkflowSummary": {
+            "allOf": [
+                schema("Workflow"),
+                {
+                    "type": "object",
+                    "required": ["total_builds", "succeeded_builds", "failed_builds"],
+                    "properties": {
+                        "total_builds": { "type": "integer" },
+                        "succeeded_builds": { "type": "integer" },
+                        "failed_builds": { "type": "integer" }
+                    }
+                }
+            ]
+        },
+        "NewWorkflow": {
+            "type": "object",
+            "required": ["ref"],
+            "properties": {
+                "clone_url": { "type": "string", "description": "Required unless the repository is registered" },
+                "ref": { "type": "string", "description": "Branch, tag or full commit SHA to build" },
+                "attribute_set": { "type": "string" },
+                "repository": { "type": "string" },
+                "branch": { "type": "string" }
+            }
+        },
+        "Build": {
+            "type": "object",
+            "required": ["drv_path", "name", "system", "status", "started_at", "finished_at",
+                "error_message", "failure", "retries", "signed", "upload_status", "upload_error"],
+            "properties": {
+                "drv_path": { "type": "string" },
+                "name": { "type": "string" },
+                "system": { "type": "string" },
+                "status": { "type": "string", "enum": ["queued", "ready", "running", "success", "cached",
+                    "failed", "timed out", "canceled"] },
+                "started_at": { "type": ["integer", "null"] },
+                "finished_at": { "type": ["integer", "null"] },
+                "error_message": { "type": ["string", "null"] },
+                "failure": { "description": "The likely cause of a failure, when the log gave it away" },
+                "retries": { "type": "integer" },
+                "signed": { "type": ["boolean", "null"] },
+                "upload_status": { "type": ["string", "null"], "enum": ["pending", "uploading", "done", "failed", null] },
+                "upload_error": { "type": ["string", "null"] },
+                "constituents": array(json!({ "type": "string" })),
+                "aggregates": array(json!({ "type": "string" })),
+                "progress": { "description": "What the build is doing, while it runs" }
+            }
+        },
+        "JobStatus": {
+            "type": "string",
+            "enum": ["Queued", "Ready", "Running", "Success", "Cached", "Failed", "Timedout", "Canceled"]
+        },
+        "Queue": {
+            "type": "object",
+            "required": ["queued", "ready", "running", "jobs"],
+            "properties": {
+                "queued": { "type": "integer" },
+                "ready": { "type": "integer" },
+                "running": { "type": "integer" },
+                "jobs": array(json!({
+                    "type": "object",
+                    "required": ["name", "drv_path", "system", "status", "workflows", "blocked_by"],
+                    "properties": {
+                        "name": { "type": "string" },
+                        "drv_path": { "type": "string" },
+                        "system": { "type": "string" },
+                        "status": schema("JobStatus"),
+                        "workflows": array(json!({ "type": "integer" })),
+                        "blocked_by": array(json!({ "type": "string" })),
+                        "progress": { "description": "What the build is doing, while it runs" }
+                    }
+                }))
+            }
+        },
+        "JobGraph": {
+            "type": "object",
+            "required": ["nodes", "edges"],
+            "properties": {
+                "nodes": array(json!({
+                    "type": "object",
+                    "required": ["drv_path", "name", "system", "status"],
+                    "properties": {
+                        "drv_path": { "type": "string" },
+                        "name": { "type": "string" },
+                        "system": { "type": "string" },
+                        "status": schema("JobStatus")
+                    }
+                })),
+                "edges": array(json!({
+                    "type": "object",
+                    "required": ["from", "to", "blocking"],
+                    "properties": {
+                        "from": { "type": "string", "description": "The dependency" },
+                        "to": { "type": "string" },
+                        "blocking": { "type": "boolean", "description": "Whether the dependency did not finish yet" }
+                    }
+                }))
+            }
+        },
+        "Hook": {
+            "type": "object",
+            "required": ["id", "url", "events", "signed", "created_at"],
+            "properties": {
+                "id": { "type": "integer" },
+                "url": { "type": "string" },
+                "events": array(json!({ "type": "string" })),
+                "signed": { "type": "boolean" },
+                "created_at": { "type": "integer" }
+            }
+        },
+        "Stats": {
+            "type": "object",
+            "description": "Of the workflows and builds that finished in a time span; rates are between 0 and 1, and null like the averages when there is nothing to compute them from",
+            "properties": {
+                "workflows": { "type": "integer", "description": "Completed or failed, leaving canceled ones out" },
+                "success_rate": { "type": ["number", "null"] },
+                "avg_workflow_secs": { "type": ["number", "null"] },
+                "builds": { "type": "integer", "description": "Succeeded, failed or found in the cache" },
+                "build_success_rate": { "type": ["number", "null"] },
+                "cache_hit_rate": { "type": ["number", "null"] },
+                "avg_build_secs": { "type": ["number", "null"], "description": "Of the builds that ran" },
+                "avg_queue_wait_secs": { "type": ["number", "null"], "description": "From a build being ready to it starting" }
+            }
+        },
+        "StatsReport": {
+            "type": "object",
+            "required": ["window", "since", "repository", "total", "bucket_secs", "trend", "repositories"],
+            "properties": {
+                "window": { "type": "string" },
+                "since": { "type": "integer", "description": "Unix time" },
+                "repository": { "type": ["string", "null"] },
+                "total": schema("Stats"),
+                "bucket_secs": { "type": "integer" },
+                "trend": array(json!({
+                    "allOf": [
+                        schema("Stats"),
+                        { "type": "object", "properties": { "start": { "type": "integer" } } }
+                    ],
+                    "description": "One point every bucket_secs, oldest first"
+                })),
+                "repositories": array(json!({
+                    "allOf": [
+                        schema("Stats"),
+                        { "type": "object", "properties": { "repository": { "type": "string" } } }
+                    ]
+                }))
+            }
+        },
+        "Scope": {
+            "type": "string",
+            "enum": ["read", "trigger", "admin"],
+            "description": "Caps the role a token acts with: viewer, maintainer and admin respectively"
+        },
+        "Token": {
+            "type": "object",
+            "required": ["id", "name", "scope", "created_at", "last_used_at"],
+            "properties": {
+                "id": { "type": "integer" },
+                "name": { "type": "string" },
+                "scope": schema("Scope"),
+                "created_at": { "type": "integer" },
+                "last_used_at": { "type": ["integer", "null"] }
+            }
+        },
+        "Repository": {
+            "type": "object",
+            "required": ["id", "full_name", "clone_url", "created_at", "organization", "project"],
+            "properties": {
+                "id": { "type": "integer" },
+                "full_name": { "type": "string" },
+                "clone_url": { "type": "string" },
+                "created_at": { "type": "integer" },
+                "organization": { "type": ["string", "null"] },
+                "project": { "type": ["string", "null"] }
+            }
+        }
+    })
+}
+
+/// The router's `{*rest}` captures in OpenAPI's syntax
+fn openapi_path(path: &str) -> String {
+    path.replace("{*", "{")
+}
+
+fn parameters(operation: &Operation) -> Vec<Value> {
+    let path = openapi_path(operation.path);
+    let mut parameters: Vec<Value> = path
+        .split('{')
+        .skip(1)
+        .filter_map(|rest| rest.split_once('}'))
+        .map(|(name, _)| {
+            let kind = match name {
+                "drv" | "output" | "path" => json!({ "type": "string" }),
+                _ => json!({ "type": "integer" }),
+            };
+            let mut parameter =
+                json!({ "name": name, "in": "path", "required": true, "schema": kind });
+            if name == "drv" {
+                parameter["description"] =
+                    json!("Store path basename of the derivation, e.g. abc...-hello.drv");
+            }
+            parameter
+        })
+        .collect();
+    parameters.extend(operation.query.iter().map(|(name, kind, description)| {
+        json!({
+            "name": name,
+            "in": "query",
+            "schema": { "type": kind },
+            "description": description
+        })
+    }));
+    parameters
+}
+
+fn describe(operation: &Operation) -> Value {
+    let content = |media: &str, schema: Value| json!({ media: { "schema": schema } });
+    let success = match &operation.response {
+        Body::Json(schema) => {
+            json!({ "description": "OK", "content": content("application/json", schema.clone()) })
+        }
+        Body::Text(media) => {
+            json!({ "description": "OK", "content": content(media, json!({ "type": "string" })) })
+        }
+        Body::Binary => json!({
+            "description": "The file",
+            "content": content("application/octet-stream", json!({ "type": "string", "format": "binary" }))
+        }),
+        Body::Events => json!({
+            "description": "Server-sent events, each with a JSON object as data",
+            "content": content("text/event-stream", json!({ "type": "string" }))
+        }),
+        Body::Empty => json!({ "description": "Done" }),
+    };
+
+    let mut described = json!({
+        "operationId": operation.id,
+        "tags": [operation.tag],
+        "summary": operation.summary,
+        "responses": {
+            operation.status.to_string(): success,
+            "default": {
+                "description": "An error",
+                "content": content("application/json", schema("Error"))
+            }
+        }
+    });
+    let parameters = parameters(operation);
+    if !parameters.is_empty() {
+        described["parameters"] = Value::Array(parameters);
+    }
+    if let Some(request) = &operation.request {
+        described["requestBody"] = json!({
+            "required": true,
+            "content": content("application/json", request.clone())
+        });
+    }
+    match operation.access {
+        Access::Public | Access::Signed => described["security"] = json!([]),
+        Access::Token(permission) => {
+            let role = match permission {
+                Permission::View => "viewer",
+                Permission::Trigger | Permission::Cancel => "maintainer",
+                Permission::Manage => "admin",
+            };
+            let anonymous = if matches!(permission, Permission::View) {
+                ", unless api.public lets anonymous callers view"
+            } else {
+                ""
+            };
+            described["description"] = json!(format!(
+                "Needs a token acting as {} or above{}",
+                role, anonymous
+            ));
+        }
+        Access::Worker => {
+            described["description"] = json!("Needs the workers' token, workers.token");
+        }
+    }
+    described
+}
+
+/// The OpenAPI 3.1 description of every API route
+fn document() -> Value {
+    let mut paths = Map::new();
+    for operation in operations() {
+        let path = paths
+            .entry(openapi_path(operation.path))
+            .or_insert_with(|| json!({}));
+        path[operation.method] = describe(&operation);
+    }
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "icicle",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Nix-based CI builder and dashboard"
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": {
+                "token": { "type": "http", "scheme": "bearer" }
+            }
+        },
+        "security": [{ "token": [] }]
+    })
+}
+
+/// Serve the document, to anyone: it tells what the API offers, not what it holds
+async fn openapi() -> Json<Value> {
+    Json(document())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// Route paths given to the routers in these sources, e.g. `.route("/api/queue", ...)`
+    fn routed_paths(sources: &[&str]) -> HashSet<String> {
+        let mut paths = HashSet::new();
+        for source in sources {
+            for literal in source.split('"').skip(1).step_by(2) {
+                let routed = ["/api", "/webhook/", "/health"]
+                    .iter()
+                    .any(|prefix| literal.starts_with(prefix));
+                // format! strings building URLs are not routes
+                if routed && !literal.contains("{}") {
+                    paths.insert(literal.to_string());
+                }
+            }
+        }
+        paths
+    }
+
+    #[test]
+    fn test_every_route_is_documented() {
+        let routed = routed_paths(&[
+            include_str!("mod.rs"),
+            include_str!("artifacts.rs"),
+            include_str!("bisections.rs"),
+            include_str!("builders.rs"),
+            include_str!("credentials.rs"),
+            include_str!("deployments.rs"),
+            include_str!("diffs.rs"),
+            include_str!("hooks.rs"),
+            include_str!("inputs.rs"),
+            include_str!("notifications.rs"),
+            include_str!("organizations.rs"),
+            include_str!("provenance.rs"),
+            include_str!("quotas.rs"),
+            include_str!("schedules.rs"),
+            include_str!("stages.rs"),
+            include_str!("stats.rs"),
+            include_str!("test_results.rs"),
+            include_str!("tokens.rs"),
+            include_str!("users.rs"),
+            include_str!("webhook_events.rs"),
+            include_str!("workers.rs"),
+            include_str!("../webhook/mod.rs"),
+            include_str!("../health/mod.rs"),
+            include_str!("../main.rs"),
+        ]);
+        let documented: HashSet<String> = operations()
+            .iter()
+            .map(|operation| operation.path.to_string())
+            .collect();
+        let mut missing: Vec<_> = routed.difference(&documented).collect();
+        missing.sort();
+        assert!(missing.is_empty(), "Undocumented routes: {:?}", missing);
+    }
+
+    #[test]
+    fn test_document() {
+        let document = document();
+        let build = &document["paths"]["/api/builds/{drv}"]["get"];
+        assert_eq!(build["operationId"], "get_build");
+        assert_eq!(build["parameters"][0]["name"], "drv");
+        assert_eq!(
+            build["responses"]["200"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/Build"
+        );
+        assert!(document["paths"]["/api/artifacts/{id}/{path}"]["get"].is_object());
+        assert_eq!(
+            document["paths"]["/webhook/github"]["post"]["security"],
+            json!([])
+        );
+
+        let ids: Vec<_> = operations().iter().map(|operation| operation.id).collect();
+        let unique: HashSet<_> = ids.iter().collect();
+        assert_eq!(ids.len(), unique.len());
+    }
+}
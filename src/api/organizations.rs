@@ -0,0 +1,441 @@
+use super::{authorize, ApiError, ApiJson, ApiPath};
+use crate::auth::Permission;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::Json,
+    routing::{delete, get, post, put},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new()
+        .route(
+            "/api/orgs",
+            get(list_organizations).post(create_organization),
+        )
+        .route(
+            "/api/orgs/{id}",
+            get(get_organization)
+                .patch(update_organization)
+                .delete(delete_organization),
+        )
+        .route("/api/orgs/{id}/projects", post(create_project))
+        .route("/api/orgs/{id}/members", get(list_members))
+        .route(
+            "/api/orgs/{id}/members/{user_id}",
+            put(add_member).delete(remove_member),
+        )
+        .route("/api/projects/{id}", delete(delete_project))
+        .route("/api/repos/{id}/project", put(assign_repository))
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct Organization {
+    id: i64,
+    name: String,
+    /// Cache the organization's builds are pushed to; the configured one when unset
+    attic_cache_name: Option<String>,
+    created_at: i64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct Project {
+    id: i64,
+    name: String,
+    created_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct OrganizationDetails {
+    #[serde(flatten)]
+    organization: Organization,
+    projects: Vec<Project>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct Member {
+    id: i64,
+    name: String,
+    role: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewOrganization {
+    name: String,
+    attic_cache_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OrganizationUpdate {
+    /// Null resets the organization to the configured cache
+    attic_cache_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewProject {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProjectAssignment {
+    /// Null moves the repository out of any project
+    project_id: Option<i64>,
+}
+
+fn non_empty(value: &str, what: &str) -> Result<String, ApiError> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(ApiError::unprocessable(format!(
+            "{} must not be empty",
+            what
+        )));
+    }
+    Ok(value.to_string())
+}
+
+async fn fetch_organization(
+    app_state: &crate::AppState,
+    id: i64,
+) -> Result<Organization, ApiError> {
+    sqlx::query_as::<_, Organization>(
+        "SELECT id, name, attic_cache_name, created_at FROM organizations WHERE id = ?",
+    )
+    .bind(id)
+    .fetch_optional(&app_state.db_pool)
+    .await?
+    .ok_or_else(|| ApiError::not_found(format!("Organization {} not found", id)))
+}
+
+async fn list_organizations(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Organization>>, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let organizations = sqlx::query_as::<_, Organization>(
+        "SELECT id, name, attic_cache_name, created_at FROM organizations ORDER BY name",
+    )
+    .fetch_all(&app_state.db_pool)
+    .await?;
+    Ok(Json(organizations))
+}
+
+async fn get_organization(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<OrganizationDetails>, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let organization = fetch_organization(&app_state, id).await?;
+    let projects = sqlx::query_as::<_, Project>(
+        "SELECT id, name, created_at FROM projects WHERE organization_id = ? ORDER BY name",
+    )
+    .bind(id)
+    .fetch_all(&app_state.db_pool)
+    .await?;
+    Ok(Json(OrganizationDetails {
+        organization,
+        projects,
+    }))
+}
+
+async fn create_organization(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiJson(request): ApiJson<NewOrganization>,
+) -> Result<(StatusCode, Json<Organization>), ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let name = non_empty(&request.name, "Name")?;
+    let attic_cache_name = request
+        .attic_cache_name
+        .as_deref()
+        .map(|cache| non_empty(cache, "Cache name"))
+        .transpose()?;
+    let now = chrono::Utc::now().timestamp();
+    let result = sqlx::query(
+        r#"
+        INSERT INTO organizations (name, attic_cache_name, created_at) VALUES (?, ?, ?)
+        ON CONFLICT(name) DO NOTHING
+        "#,
+    )
+    .bind(&name)
+    .bind(&attic_cache_name)
+    .bind(now)
+    .execute(&app_state.db_pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::conflict(format!(
+            "Organization '{}' already exists",
+            name
+        )));
+    }
+
+    info!("{} created organization {}", principal.name, name);
+    Ok((
+        StatusCode::CREATED,
+        Json(Organization {
+            id: result.last_insert_rowid(),
+            name,
+            attic_cache_name,
+            created_at: now,
+        }),
+    ))
+}
+
+async fn update_organization(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+    ApiJson(request): ApiJson<OrganizationUpdate>,
+) -> Result<Json<Organization>, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let attic_cache_name = request
+        .attic_cache_name
+        .as_deref()
+        .map(|cache| non_empty(cache, "Cache name"))
+        .transpose()?;
+    let updated = sqlx::query("UPDATE organizations SET attic_cache_name = ? WHERE id = ?")
+        .bind(&attic_cache_name)
+        .bind(id)
+        .execute(&app_state.db_pool)
+        .await?
+        .rows_affected();
+    if updated == 0 {
+        return Err(ApiError::not_found(format!(
+            "Organization {} not found",
+            id
+        )));
+    }
+
+    let organization = fetch_organization(&app_state, id).await?;
+    info!(
+        "{} set the cache of organization {} to {:?}",
+        principal.name, organization.name, organization.attic_cache_name
+    );
+    Ok(Json(organization))
+}
+
+/// Only empty organizations can be deleted, so no repository silently changes hands
+async fn delete_organization(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<StatusCode, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let organization = fetch_organization(&app_state, id).await?;
+    let projects: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM projects WHERE organization_id = ?")
+            .bind(id)
+            .fetch_one(&app_state.db_pool)
+            .await?;
+    if projects > 0 {
+        return Err(ApiError::conflict(format!(
+            "Organization '{}' still has {} projects",
+            organization.name, projects
+        )));
+    }
+
+    let mut tx = app_state.db_pool.begin().await?;
+    sqlx::query("DELETE FROM organization_members WHERE organization_id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM organizations WHERE id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    info!(
+        "{} deleted organization {}",
+        principal.name, organization.name
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn create_project(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+    ApiJson(request): ApiJson<NewProject>,
+) -> Result<(StatusCode, Json<Project>), ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let organization = fetch_organization(&app_state, id).await?;
+    let name = non_empty(&request.name, "Name")?;
+    let now = chrono::Utc::now().timestamp();
+    let result = sqlx::query(
+        r#"
+        INSERT INTO projects (organization_id, name, created_at) VALUES (?, ?, ?)
+        ON CONFLICT(organization_id, name) DO NOTHING
+        "#,
+    )
+    .bind(id)
+    .bind(&name)
+    .bind(now)
+    .execute(&app_state.db_pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::conflict(format!(
+            "Project '{}' already exists in organization '{}'",
+            name, organization.name
+        )));
+    }
+
+    info!(
+        "{} created project {}/{}",
+        principal.name, organization.name, name
+    );
+    Ok((
+        StatusCode::CREATED,
+        Json(Project {
+            id: result.last_insert_rowid(),
+            name,
+            created_at: now,
+        }),
+    ))
+}
+
+/// Repositories of a deleted project stay, outside any project
+async fn delete_project(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<StatusCode, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let mut tx = app_state.db_pool.begin().await?;
+    sqlx::query("UPDATE repositories SET project_id = NULL WHERE project_id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    let deleted = sqlx::query("DELETE FROM projects WHERE id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(ApiError::not_found(format!("Project {} not found", id)));
+    }
+    tx.commit().await?;
+
+    info!("{} deleted project {}", principal.name, id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn assign_repository(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+    ApiJson(request): ApiJson<ProjectAssignment>,
+) -> Result<StatusCode, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Manage).await?;
+
+    if let Some(project_id) = request.project_id {
+        sqlx::query_scalar::<_, i64>("SELECT id FROM projects WHERE id = ?")
+            .bind(project_id)
+            .fetch_optional(&app_state.db_pool)
+            .await?
+            .ok_or_else(|| ApiError::unprocessable(format!("Project {} not found", project_id)))?;
+    }
+    let updated = sqlx::query("UPDATE repositories SET project_id = ? WHERE id = ?")
+        .bind(request.project_id)
+        .bind(id)
+        .execute(&app_state.db_pool)
+        .await?
+        .rows_affected();
+    if updated == 0 {
+        return Err(ApiError::not_found(format!("Repository {} not found", id)));
+    }
+
+    info!(
+        "{} moved repository {} to project {:?}",
+        principal.name, id, request.project_id
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_members(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<Vec<Member>>, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+    fetch_organization(&app_state, id).await?;
+
+    let members = sqlx::query_as::<_, Member>(
+        r#"
+        SELECT u.id, u.name, u.role
+        FROM organization_members m JOIN users u ON u.id = m.user_id
+        WHERE m.organization_id = ?
+        ORDER BY u.name
+        "#,
+    )
+    .bind(id)
+    .fetch_all(&app_state.db_pool)
+    .await?;
+    Ok(Json(members))
+}
+
+async fn add_member(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath((id, user_id)): ApiPath<(i64, i64)>,
+) -> Result<StatusCode, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let organization = fetch_organization(&app_state, id).await?;
+    let user: String = sqlx::query_scalar("SELECT name FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(&app_state.db_pool)
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("User {} not found", user_id)))?;
+    sqlx::query(
+        "INSERT OR IGNORE INTO organization_members (organization_id, user_id) VALUES (?, ?)",
+    )
+    .bind(id)
+    .bind(user_id)
+    .execute(&app_state.db_pool)
+    .await?;
+
+    info!(
+        "{} added {} to organization {}",
+        principal.name, user, organization.name
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_member(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath((id, user_id)): ApiPath<(i64, i64)>,
+) -> Result<StatusCode, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let removed =
+        sqlx::query("DELETE FROM organization_members WHERE organization_id = ? AND user_id = ?")
+            .bind(id)
+            .bind(user_id)
+            .execute(&app_state.db_pool)
+            .await?
+            .rows_affected();
+    if removed == 0 {
+        return Err(ApiError::not_found(format!(
+            "User {} is not a member of organization {}",
+            user_id, id
+        )));
+    }
+    info!(
+        "{} removed user {} from organization {}",
+        principal.name, user_id, id
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
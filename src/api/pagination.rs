@@ -0,0 +1,71 @@
+use super::ApiError;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 500;
+
+/// Query parameters accepted by every list endpoint
+#[derive(Debug, Deserialize)]
+pub struct PageParams {
+    pub limit: Option<i64>,
+    /// Opaque cursor taken from `next_cursor` of the previous page
+    pub cursor: Option<String>,
+}
+
+impl PageParams {
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+
+    /// The row ID the page continues from, if any
+    pub fn after(&self) -> Result<Option<i64>, ApiError> {
+        self.cursor
+            .as_deref()
+            .map(|c| {
+                c.parse()
+                    .map_err(|_| ApiError::bad_request(format!("Invalid cursor '{}'", c)))
+            })
+            .transpose()
+    }
+}
+
+/// A page of results. Lists are keyed by row ID, so the cursor is the last ID returned.
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    /// Build a page from rows fetched with `LIMIT limit + 1`; the extra row only signals
+    /// that another page exists
+    pub fn new(mut rows: Vec<T>, limit: i64, id: impl Fn(&T) -> i64) -> Self {
+        let has_more = rows.len() as i64 > limit;
+        rows.truncate(limit as usize);
+        let next_cursor = if has_more {
+            rows.last().map(|row| id(row).to_string())
+        } else {
+            None
+        };
+        Page {
+            items: rows,
+            next_cursor,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_cursor_only_when_more_rows() {
+        let page = Page::new(vec![1, 2, 3], 2, |id| *id);
+        assert_eq!(page.items, vec![1, 2]);
+        assert_eq!(page.next_cursor.as_deref(), Some("2"));
+
+        let page = Page::new(vec![1, 2], 2, |id| *id);
+        assert_eq!(page.items, vec![1, 2]);
+        assert_eq!(page.next_cursor, None);
+    }
+}
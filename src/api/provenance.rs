@@ -0,0 +1,40 @@
+use super::{
+    artifacts::generated_artifact, authorize, authorize_build, drv_store_path, ApiError, ApiPath,
+};
+use crate::{auth::Permission, provenance::ATTESTATION_NAME};
+use axum::{extract::State, http::HeaderMap, response::Response, routing::get, Router};
+use std::sync::Arc;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new()
+        .route("/api/builds/{drv}/provenance", get(build_provenance))
+        .route("/api/provenance/key", get(provenance_key))
+}
+
+/// The signed provenance of a successful build, as a DSSE envelope
+async fn build_provenance(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(drv): ApiPath<String>,
+) -> Result<Response, ApiError> {
+    authorize_build(
+        &app_state,
+        &headers,
+        Permission::View,
+        &drv_store_path(&drv)?,
+    )
+    .await?;
+    generated_artifact(&app_state, &drv, ATTESTATION_NAME).await
+}
+
+/// The public key verifying build provenance, as PEM
+async fn provenance_key(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+) -> Result<String, ApiError> {
+    authorize(&app_state, &headers, Permission::View).await?;
+    app_state
+        .provenance_key
+        .clone()
+        .ok_or_else(|| ApiError::not_found("Provenance is not enabled"))
+}
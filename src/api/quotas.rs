@@ -0,0 +1,40 @@
+use super::{authorize, ApiError};
+use crate::{
+    auth::Permission,
+    quota::{self, Scope, Usage},
+};
+use axum::{extract::State, http::HeaderMap, response::Json, routing::get, Router};
+use std::{collections::HashSet, sync::Arc};
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new().route("/api/quotas", get(quotas))
+}
+
+/// This month's build minutes of every repository and organization the caller sees
+/// against their quotas
+async fn quotas(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Usage>>, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::View).await?;
+    let mut usage = quota::all(&app_state.db_pool, &app_state.quota_config).await?;
+    // Organizations owning hidden repositories are those the caller is not a member of
+    let hidden_organizations: HashSet<String> = sqlx::query_scalar(
+        r#"
+        SELECT DISTINCT o.name FROM organizations o
+        JOIN projects p ON p.organization_id = o.id
+        JOIN repositories r ON r.project_id = p.id
+        WHERE r.full_name IN (SELECT value FROM json_each(?))
+        "#,
+    )
+    .bind(principal.hidden_json())
+    .fetch_all(&app_state.db_pool)
+    .await?
+    .into_iter()
+    .collect();
+    usage.retain(|usage| match usage.scope {
+        Scope::Repository => principal.sees(&usage.name),
+        Scope::Organization => !hidden_organizations.contains(&usage.name),
+    });
+    Ok(Json(usage))
+}
@@ -0,0 +1,122 @@
+use super::{authorize, ApiError, ApiJson, ApiPath};
+use crate::{
+    auth::{Permission, Principal},
+    schedule::{self, Schedule, StoredSchedule},
+};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::Json,
+    routing::{delete, get, post},
+    Router,
+};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::info;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new()
+        .route("/api/schedules", get(list_schedules).post(create_schedule))
+        .route("/api/schedules/{id}", delete(delete_schedule))
+        .route("/api/schedules/{id}/run", post(run_schedule))
+}
+
+/// A schedule of a repository the caller sees
+async fn fetch_schedule(
+    app_state: &crate::AppState,
+    principal: &Principal,
+    id: i64,
+) -> Result<StoredSchedule, ApiError> {
+    schedule::get(&app_state.db_pool, id)
+        .await?
+        .filter(|schedule| principal.sees(&schedule.repository))
+        .ok_or_else(|| ApiError::not_found(format!("Schedule {} not found", id)))
+}
+
+async fn list_schedules(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<StoredSchedule>>, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::View).await?;
+    let mut schedules = schedule::list(&app_state.db_pool).await?;
+    schedules.retain(|schedule| principal.sees(&schedule.repository));
+    Ok(Json(schedules))
+}
+
+async fn create_schedule(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiJson(request): ApiJson<Schedule>,
+) -> Result<(StatusCode, Json<StoredSchedule>), ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Manage).await?;
+    request
+        .validate()
+        .map_err(|e| ApiError::unprocessable(format!("{:#}", e)))?;
+    let known: Option<i64> = sqlx::query_scalar("SELECT id FROM repositories WHERE full_name = ?")
+        .bind(&request.repository)
+        .fetch_optional(&app_state.db_pool)
+        .await?;
+    if known.is_none() || !principal.sees(&request.repository) {
+        return Err(ApiError::unprocessable(format!(
+            "Repository {} is unknown until it sends a webhook",
+            request.repository
+        )));
+    }
+
+    let id = sqlx::query(
+        r#"
+        INSERT INTO schedules (repository, branch, cron, attribute_set, created_at)
+        VALUES (?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(&request.repository)
+    .bind(&request.branch)
+    .bind(&request.cron)
+    .bind(&request.attribute_set)
+    .bind(chrono::Utc::now().timestamp())
+    .execute(&app_state.db_pool)
+    .await?
+    .last_insert_rowid();
+
+    info!(
+        "{} scheduled {} branch {} at '{}'",
+        principal.name, request.repository, request.branch, request.cron
+    );
+    Ok((
+        StatusCode::CREATED,
+        Json(fetch_schedule(&app_state, &principal, id).await?),
+    ))
+}
+
+async fn delete_schedule(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<StatusCode, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Manage).await?;
+    if fetch_schedule(&app_state, &principal, id).await?.configured {
+        return Err(ApiError::conflict(format!(
+            "Schedule {} is defined in the configuration",
+            id
+        )));
+    }
+
+    sqlx::query("DELETE FROM schedules WHERE id = ?")
+        .bind(id)
+        .execute(&app_state.db_pool)
+        .await?;
+    info!("{} removed schedule {}", principal.name, id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Start a schedule's workflow now, without waiting for it to be due
+async fn run_schedule(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<Value>, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Trigger).await?;
+    let schedule = fetch_schedule(&app_state, &principal, id).await?;
+    let workflow_id = schedule::trigger(&app_state, &schedule).await?;
+    Ok(Json(json!({ "workflow_id": workflow_id })))
+}
@@ -0,0 +1,46 @@
+use super::{authorize_workflow, ApiError, ApiPath};
+use crate::{auth::Permission, pipeline};
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::Json,
+    routing::{get, post},
+    Router,
+};
+use std::sync::Arc;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new()
+        .route("/api/workflows/{id}/stages", get(workflow_stages))
+        .route("/api/workflows/{id}/approve", post(approve_stage))
+}
+
+/// The stages of a workflow, in order
+async fn workflow_stages(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<Vec<pipeline::Stage>>, ApiError> {
+    authorize_workflow(&app_state, &headers, Permission::View, id).await?;
+    super::fetch_workflow(&app_state, id).await?;
+    Ok(Json(pipeline::list(&app_state.db_pool, id).await?))
+}
+
+/// Start the stage of a workflow waiting at an approval gate
+async fn approve_stage(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<pipeline::Stage>, ApiError> {
+    let principal = authorize_workflow(&app_state, &headers, Permission::Trigger, id).await?;
+    super::fetch_workflow(&app_state, id).await?;
+
+    let stage = app_state
+        .pipeline
+        .approve(id, &principal.name)
+        .await?
+        .ok_or_else(|| {
+            ApiError::conflict(format!("Workflow {} has no stage awaiting approval", id))
+        })?;
+    Ok(Json(stage))
+}
@@ -0,0 +1,40 @@
+use super::{authorize, ApiError, ApiQuery};
+use crate::{
+    auth::Permission,
+    stats::{self, Report, Window},
+};
+use axum::{extract::State, http::HeaderMap, response::Json, routing::get, Router};
+use serde::Deserialize;
+use std::sync::Arc;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new().route("/api/stats", get(stats))
+}
+
+#[derive(Debug, Deserialize)]
+struct StatsQuery {
+    #[serde(default)]
+    window: Window,
+    /// Only this repository, e.g. owner/repo
+    repository: Option<String>,
+}
+
+/// Success and cache hit rates, durations and queue waits of what finished within
+/// a window, in total, as a trend and by repository
+async fn stats(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiQuery(query): ApiQuery<StatsQuery>,
+) -> Result<Json<Report>, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::View).await?;
+    let repository = query.repository.as_deref().filter(|r| !r.is_empty());
+    Ok(Json(
+        stats::report(
+            &app_state.db_pool,
+            query.window,
+            repository,
+            &principal.hidden,
+        )
+        .await?,
+    ))
+}
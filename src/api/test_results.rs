@@ -0,0 +1,81 @@
+use super::{authorize_workflow, ApiError, ApiPath};
+use crate::auth::Permission;
+use axum::{extract::State, http::HeaderMap, response::Json, routing::get, Router};
+use serde::Serialize;
+use std::sync::Arc;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new().route("/api/workflows/{id}/tests", get(workflow_tests))
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct TestFailure {
+    build: String,
+    drv_path: String,
+    suite: String,
+    classname: Option<String>,
+    name: String,
+    status: String,
+    duration_ms: Option<i64>,
+    message: Option<String>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct TestCounts {
+    total: i64,
+    passed: i64,
+    failed: i64,
+    error: i64,
+    skipped: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct TestSummary {
+    #[serde(flatten)]
+    counts: TestCounts,
+    /// Failed and errored test cases
+    failures: Vec<TestFailure>,
+}
+
+/// Summary of the JUnit results reported by a workflow's builds
+async fn workflow_tests(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<TestSummary>, ApiError> {
+    authorize_workflow(&app_state, &headers, Permission::View, id).await?;
+    super::fetch_workflow(&app_state, id).await?;
+
+    let counts = sqlx::query_as::<_, TestCounts>(
+        r#"
+        SELECT COUNT(*) AS total,
+               COALESCE(SUM(t.status = 'passed'), 0) AS passed,
+               COALESCE(SUM(t.status = 'failed'), 0) AS failed,
+               COALESCE(SUM(t.status = 'error'), 0) AS error,
+               COALESCE(SUM(t.status = 'skipped'), 0) AS skipped
+        FROM test_results t
+        JOIN build_workflows bw ON bw.drv_path = t.drv_path
+        WHERE bw.workflow_id = ?
+        "#,
+    )
+    .bind(id)
+    .fetch_one(&app_state.db_pool)
+    .await?;
+
+    let failures = sqlx::query_as::<_, TestFailure>(
+        r#"
+        SELECT b.name AS build, t.drv_path, t.suite, t.classname, t.name, t.status,
+               t.duration_ms, t.message
+        FROM test_results t
+        JOIN build_workflows bw ON bw.drv_path = t.drv_path
+        JOIN builds b ON b.drv_path = t.drv_path
+        WHERE bw.workflow_id = ? AND t.status IN ('failed', 'error')
+        ORDER BY b.name, t.suite, t.id
+        "#,
+    )
+    .bind(id)
+    .fetch_all(&app_state.db_pool)
+    .await?;
+
+    Ok(Json(TestSummary { counts, failures }))
+}
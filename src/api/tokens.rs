@@ -0,0 +1,169 @@
+use super::{authorize, ApiError, ApiJson, ApiPath};
+use crate::auth::{self, Permission, Scope};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::Json,
+    routing::{delete, get},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new()
+        .route(
+            "/api/users/{id}/tokens",
+            get(list_tokens).post(create_token),
+        )
+        .route("/api/users/{id}/tokens/{token_id}", delete(delete_token))
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct TokenRow {
+    id: i64,
+    name: String,
+    scope: String,
+    created_at: i64,
+    last_used_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Token {
+    id: i64,
+    name: String,
+    scope: Scope,
+    created_at: i64,
+    last_used_at: Option<i64>,
+}
+
+impl TryFrom<TokenRow> for Token {
+    type Error = ApiError;
+
+    fn try_from(row: TokenRow) -> Result<Self, ApiError> {
+        Ok(Token {
+            scope: row.scope.parse().map_err(ApiError::internal)?,
+            id: row.id,
+            name: row.name,
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct NewToken {
+    /// What the token is for, e.g. "deploy script"
+    name: String,
+    scope: Scope,
+}
+
+/// A token as created; the secret is only ever shown here
+#[derive(Debug, Serialize)]
+struct CreatedToken {
+    #[serde(flatten)]
+    token: Token,
+    secret: String,
+}
+
+async fn user_name(app_state: &crate::AppState, id: i64) -> Result<String, ApiError> {
+    sqlx::query_scalar::<_, String>("SELECT name FROM users WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&app_state.db_pool)
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("User {} not found", id)))
+}
+
+async fn list_tokens(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<Vec<Token>>, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+    user_name(&app_state, id).await?;
+
+    let rows = sqlx::query_as::<_, TokenRow>(
+        "SELECT id, name, scope, created_at, last_used_at FROM api_tokens WHERE user_id = ? ORDER BY id",
+    )
+    .bind(id)
+    .fetch_all(&app_state.db_pool)
+    .await?;
+
+    Ok(Json(
+        rows.into_iter()
+            .map(Token::try_from)
+            .collect::<Result<_, _>>()?,
+    ))
+}
+
+async fn create_token(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+    ApiJson(request): ApiJson<NewToken>,
+) -> Result<(StatusCode, Json<CreatedToken>), ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Manage).await?;
+    let user = user_name(&app_state, id).await?;
+
+    let name = request.name.trim();
+    if name.is_empty() {
+        return Err(ApiError::unprocessable("Name must not be empty"));
+    }
+    let secret = auth::generate_token();
+    let now = chrono::Utc::now().timestamp();
+    let result = sqlx::query(
+        "INSERT INTO api_tokens (user_id, name, token_hash, scope, created_at) VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(id)
+    .bind(name)
+    .bind(auth::hash_token(&secret))
+    .bind(request.scope.as_str())
+    .bind(now)
+    .execute(&app_state.db_pool)
+    .await?;
+
+    info!(
+        "{} created {} token '{}' for user {}",
+        principal.name, request.scope, name, user
+    );
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedToken {
+            token: Token {
+                id: result.last_insert_rowid(),
+                name: name.to_string(),
+                scope: request.scope,
+                created_at: now,
+                last_used_at: None,
+            },
+            secret,
+        }),
+    ))
+}
+
+async fn delete_token(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath((id, token_id)): ApiPath<(i64, i64)>,
+) -> Result<StatusCode, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let deleted = sqlx::query("DELETE FROM api_tokens WHERE id = ? AND user_id = ?")
+        .bind(token_id)
+        .bind(id)
+        .execute(&app_state.db_pool)
+        .await?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(ApiError::not_found(format!(
+            "Token {} of user {} not found",
+            token_id, id
+        )));
+    }
+    info!(
+        "{} revoked token {} of user {}",
+        principal.name, token_id, id
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
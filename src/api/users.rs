@@ -0,0 +1,191 @@
+use super::{authorize, ApiError, ApiJson, ApiPath};
+use crate::auth::{Permission, Role};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::Json,
+    routing::get,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new()
+        .route("/api/users", get(list_users).post(create_user))
+        .route(
+            "/api/users/{id}",
+            get(get_user).patch(update_user).delete(delete_user),
+        )
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct UserRow {
+    id: i64,
+    name: String,
+    role: String,
+    created_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct User {
+    id: i64,
+    name: String,
+    role: Role,
+    created_at: i64,
+}
+
+impl TryFrom<UserRow> for User {
+    type Error = ApiError;
+
+    fn try_from(row: UserRow) -> Result<Self, ApiError> {
+        Ok(User {
+            role: row.role.parse().map_err(ApiError::internal)?,
+            id: row.id,
+            name: row.name,
+            created_at: row.created_at,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct NewUser {
+    name: String,
+    role: Role,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserUpdate {
+    role: Role,
+}
+
+async fn fetch_user(app_state: &crate::AppState, id: i64) -> Result<User, ApiError> {
+    sqlx::query_as::<_, UserRow>("SELECT id, name, role, created_at FROM users WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&app_state.db_pool)
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("User {} not found", id)))?
+        .try_into()
+}
+
+async fn list_users(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<User>>, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let rows =
+        sqlx::query_as::<_, UserRow>("SELECT id, name, role, created_at FROM users ORDER BY id")
+            .fetch_all(&app_state.db_pool)
+            .await?;
+
+    Ok(Json(
+        rows.into_iter()
+            .map(User::try_from)
+            .collect::<Result<_, _>>()?,
+    ))
+}
+
+async fn get_user(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<User>, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+    Ok(Json(fetch_user(&app_state, id).await?))
+}
+
+async fn create_user(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiJson(request): ApiJson<NewUser>,
+) -> Result<(StatusCode, Json<User>), ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let name = request.name.trim();
+    if name.is_empty() {
+        return Err(ApiError::unprocessable("Name must not be empty"));
+    }
+    let now = chrono::Utc::now().timestamp();
+    let result = sqlx::query(
+        "INSERT INTO users (name, role, created_at) VALUES (?, ?, ?) ON CONFLICT(name) DO NOTHING",
+    )
+    .bind(name)
+    .bind(request.role.as_str())
+    .bind(now)
+    .execute(&app_state.db_pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::conflict(format!(
+            "User '{}' already exists",
+            name
+        )));
+    }
+
+    info!("{} added user {} as {}", principal.name, name, request.role);
+    Ok((
+        StatusCode::CREATED,
+        Json(User {
+            id: result.last_insert_rowid(),
+            name: name.to_string(),
+            role: request.role,
+            created_at: now,
+        }),
+    ))
+}
+
+async fn update_user(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+    ApiJson(request): ApiJson<UserUpdate>,
+) -> Result<Json<User>, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let updated = sqlx::query("UPDATE users SET role = ? WHERE id = ?")
+        .bind(request.role.as_str())
+        .bind(id)
+        .execute(&app_state.db_pool)
+        .await?
+        .rows_affected();
+    if updated == 0 {
+        return Err(ApiError::not_found(format!("User {} not found", id)));
+    }
+
+    let user = fetch_user(&app_state, id).await?;
+    info!(
+        "{} changed the role of user {} to {}",
+        principal.name, user.name, user.role
+    );
+    Ok(Json(user))
+}
+
+async fn delete_user(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<StatusCode, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::Manage).await?;
+
+    let mut tx = app_state.db_pool.begin().await?;
+    sqlx::query("DELETE FROM organization_members WHERE user_id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM api_tokens WHERE user_id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    let deleted = sqlx::query("DELETE FROM users WHERE id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(ApiError::not_found(format!("User {} not found", id)));
+    }
+    tx.commit().await?;
+    info!("{} removed user {}", principal.name, id);
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,112 @@
+use super::{authorize, ApiError, ApiPath, ApiQuery, Page, PageParams};
+use crate::{auth::Permission, webhook};
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::Json,
+    routing::{get, post},
+    Router,
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::Arc;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new()
+        .route("/api/admin/webhook-events", get(list_events))
+        .route("/api/admin/webhook-events/{id}", get(get_event))
+        .route("/api/admin/webhook-events/{id}/replay", post(replay_event))
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct EventSummary {
+    id: i64,
+    provider: String,
+    event: String,
+    delivery_id: Option<String>,
+    received_at: i64,
+    status_code: Option<i64>,
+    replay_of: Option<i64>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct EventRow {
+    #[sqlx(flatten)]
+    summary: EventSummary,
+    headers: String,
+    payload: Vec<u8>,
+    response: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct EventDetails {
+    #[serde(flatten)]
+    summary: EventSummary,
+    headers: Value,
+    payload: Value,
+    /// The response body, or the error message
+    response: Option<Value>,
+}
+
+/// JSON as is, anything else as a string
+fn json_or_string(text: &str) -> Value {
+    serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.to_string()))
+}
+
+/// Stored webhook deliveries, newest first
+async fn list_events(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiQuery(page): ApiQuery<PageParams>,
+) -> Result<Json<Page<EventSummary>>, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+    let limit = page.limit();
+    let events = sqlx::query_as::<_, EventSummary>(
+        r#"
+        SELECT id, provider, event, delivery_id, received_at, status_code, replay_of
+        FROM webhook_events WHERE id < ?
+        ORDER BY id DESC LIMIT ?
+        "#,
+    )
+    .bind(page.after()?.unwrap_or(i64::MAX))
+    .bind(limit + 1)
+    .fetch_all(&app_state.db_pool)
+    .await?;
+    Ok(Json(Page::new(events, limit, |e| e.id)))
+}
+
+async fn get_event(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<EventDetails>, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+    let event = sqlx::query_as::<_, EventRow>(
+        r#"
+        SELECT id, provider, event, delivery_id, received_at, status_code, replay_of,
+               headers, payload, response
+        FROM webhook_events WHERE id = ?
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(&app_state.db_pool)
+    .await?
+    .ok_or_else(|| ApiError::not_found(format!("Webhook event {} not found", id)))?;
+
+    Ok(Json(EventDetails {
+        summary: event.summary,
+        headers: json_or_string(&event.headers),
+        payload: json_or_string(&String::from_utf8_lossy(&event.payload)),
+        response: event.response.as_deref().map(json_or_string),
+    }))
+}
+
+/// Handle a stored delivery again, e.g. after a transient failure
+async fn replay_event(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<i64>,
+) -> Result<Json<Value>, ApiError> {
+    authorize(&app_state, &headers, Permission::Manage).await?;
+    webhook::replay(&app_state, id).await
+}
@@ -0,0 +1,167 @@
+use super::{authorize, ApiError, ApiJson, ApiPath};
+use crate::{
+    auth::Permission,
+    workers::{LeasedJob, WorkerError, WorkerStatus},
+};
+use axum::{
+    extract::State,
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+    routing::{get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new()
+        .route("/api/workers", get(workers).post(register))
+        .route("/api/workers/{id}/lease", post(lease))
+        .route("/api/workers/{id}/leases/{lease_id}/status", post(report))
+        .route("/api/workers/{id}/leases/{lease_id}/result", post(finish))
+}
+
+impl From<WorkerError> for ApiError {
+    fn from(e: WorkerError) -> Self {
+        match e {
+            WorkerError::UnknownWorker => ApiError::not_found("Unknown worker, register again"),
+            WorkerError::LeaseGone => {
+                ApiError::gone("The job is not leased to this worker anymore")
+            }
+        }
+    }
+}
+
+/// Check that the caller presents the workers' token
+fn authorize_worker(app_state: &crate::AppState, headers: &HeaderMap) -> Result<(), ApiError> {
+    if !app_state.workers.enabled() {
+        return Err(ApiError::not_found("Workers are disabled"));
+    }
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .ok_or_else(|| ApiError::unauthorized("Missing bearer token"))?;
+    if !app_state.workers.authenticates(token) {
+        return Err(ApiError::unauthorized("Invalid worker token"));
+    }
+    Ok(())
+}
+
+/// The registered workers and the jobs they are building, of those the caller sees
+async fn workers(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<WorkerStatus>>, ApiError> {
+    let principal = authorize(&app_state, &headers, Permission::View).await?;
+    let mut workers = app_state.workers.status();
+    for worker in &mut workers {
+        let mut jobs = Vec::new();
+        for job in std::mem::take(&mut worker.jobs) {
+            if principal
+                .sees_build(&app_state.db_pool, &job.drv_path)
+                .await?
+            {
+                jobs.push(job);
+            }
+        }
+        worker.jobs = jobs;
+    }
+    Ok(Json(workers))
+}
+
+#[derive(Debug, Deserialize)]
+struct Registration {
+    name: String,
+    systems: Vec<String>,
+    /// System features the worker supports, e.g. "kvm"
+    #[serde(default)]
+    features: Vec<String>,
+    #[serde(default = "default_max_jobs")]
+    max_jobs: usize,
+}
+
+fn default_max_jobs() -> usize {
+    1
+}
+
+#[derive(Debug, Serialize)]
+struct Registered {
+    id: u64,
+    /// Workers have to contact the server more often than this
+    lease_secs: u64,
+    /// Store to fetch derivations from and copy outputs to, if not shared
+    store: Option<String>,
+}
+
+async fn register(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiJson(registration): ApiJson<Registration>,
+) -> Result<Json<Registered>, ApiError> {
+    authorize_worker(&app_state, &headers)?;
+    if registration.systems.is_empty() || registration.max_jobs == 0 {
+        return Err(ApiError::unprocessable(
+            "Workers need at least one system and one slot",
+        ));
+    }
+    let id = app_state.workers.register(
+        registration.name,
+        registration.systems,
+        registration.features,
+        registration.max_jobs,
+    );
+    Ok(Json(Registered {
+        id,
+        lease_secs: app_state.workers.lease_secs(),
+        store: app_state.workers.store().map(str::to_string),
+    }))
+}
+
+/// Lease a job, waiting a while for one; no content when there is none
+async fn lease(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath(id): ApiPath<u64>,
+) -> Result<Response, ApiError> {
+    authorize_worker(&app_state, &headers)?;
+    Ok(match app_state.workers.lease(id).await? {
+        Some(job) => Json::<LeasedJob>(job).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct Report {
+    phase: String,
+}
+
+/// Report the current step of a leased job; gone once the worker should stop it
+async fn report(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath((id, lease_id)): ApiPath<(u64, u64)>,
+    ApiJson(report): ApiJson<Report>,
+) -> Result<StatusCode, ApiError> {
+    authorize_worker(&app_state, &headers)?;
+    app_state.workers.report(id, lease_id, report.phase)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct Outcome {
+    /// Why the build failed; it succeeded when unset
+    error: Option<String>,
+}
+
+async fn finish(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    ApiPath((id, lease_id)): ApiPath<(u64, u64)>,
+    ApiJson(outcome): ApiJson<Outcome>,
+) -> Result<StatusCode, ApiError> {
+    authorize_worker(&app_state, &headers)?;
+    let result = outcome.error.map_or(Ok(()), Err);
+    app_state.workers.finish(id, lease_id, result)?;
+    Ok(StatusCode::NO_CONTENT)
+}
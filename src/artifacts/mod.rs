@@ -0,0 +1,372 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ArtifactConfig {
+    /// Copy the products builds declare in nix-support/hydra-build-products
+    pub enabled: bool,
+    /// Where artifacts are kept, outside the nix store so garbage collection keeps them
+    pub dir: PathBuf,
+    /// Days an artifact is kept; forever when 0
+    pub retention_days: u64,
+    /// Larger artifacts are skipped, in megabytes
+    pub max_size_mb: u64,
+    /// Describe the runtime closure of every successful build in a CycloneDX SBOM
+    pub sbom: bool,
+}
+
+impl Default for ArtifactConfig {
+    fn default() -> Self {
+        ArtifactConfig {
+            enabled: true,
+            dir: PathBuf::from("artifacts"),
+            retention_days: 30,
+            max_size_mb: 1024,
+            sbom: true,
+        }
+    }
+}
+
+/// Manifest listing the products of a build, one `<kind> <subtype> <path>` per line,
+/// as introduced by Hydra
+const MANIFEST: &str = "nix-support/hydra-build-products";
+const STORE_DIR: &str = "/nix/store";
+
+#[derive(Debug, PartialEq)]
+struct Product {
+    kind: String,
+    subtype: String,
+    path: PathBuf,
+}
+
+fn parse_manifest(manifest: &str) -> Vec<Product> {
+    manifest
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let kind = fields.next()?;
+            let subtype = fields.next()?;
+            // Paths may be quoted
+            let path = fields.next()?.trim_matches('"');
+            Some(Product {
+                kind: kind.to_string(),
+                subtype: subtype.to_string(),
+                path: PathBuf::from(path),
+            })
+        })
+        .collect()
+}
+
+/// An artifact as stored on disk
+pub struct StoredArtifact {
+    pub name: String,
+    pub is_dir: bool,
+    pub path: PathBuf,
+}
+
+/// Copies build products out of the store and keeps them for `retention_days`
+#[derive(Clone)]
+pub struct ArtifactStore {
+    config: ArtifactConfig,
+    db_pool: SqlitePool,
+}
+
+impl ArtifactStore {
+    pub fn new(config: ArtifactConfig, db_pool: SqlitePool) -> Result<Self> {
+        fs::create_dir_all(&config.dir).with_context(|| {
+            format!(
+                "Failed to create artifact directory {}",
+                config.dir.display()
+            )
+        })?;
+        Ok(Self { config, db_pool })
+    }
+
+    /// Store the products declared by the outputs of a successful build
+    pub async fn collect(
+        &self,
+        drv_path: &str,
+        outputs: &HashMap<String, String>,
+    ) -> Result<usize> {
+        if !self.config.enabled {
+            return Ok(0);
+        }
+
+        // A rebuild replaces the artifacts of the previous attempt
+        self.remove(drv_path).await?;
+
+        let mut stored = 0;
+        for out_path in outputs.values() {
+            let Ok(manifest) = tokio::fs::read_to_string(Path::new(out_path).join(MANIFEST)).await
+            else {
+                continue;
+            };
+            for product in parse_manifest(&manifest) {
+                match self.store(drv_path, &product).await {
+                    Ok(()) => stored += 1,
+                    Err(e) => warn!(
+                        "Skipping artifact {} of {}: {:#}",
+                        product.path.display(),
+                        drv_path,
+                        e
+                    ),
+                }
+            }
+        }
+        if stored > 0 {
+            info!("Stored {} artifacts of {}", stored, drv_path);
+        }
+        Ok(stored)
+    }
+
+    async fn store(&self, drv_path: &str, product: &Product) -> Result<()> {
+        // Products may be symlinks, but only into the store
+        let source = tokio::fs::canonicalize(&product.path).await?;
+        if !source.starts_with(STORE_DIR) {
+            return Err(anyhow!("{} is outside the nix store", source.display()));
+        }
+        let name = source
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("Invalid artifact path"))?
+            .to_string();
+        let is_dir = source.is_dir();
+        let size = tokio::task::spawn_blocking({
+            let source = source.clone();
+            move || disk_usage(&source)
+        })
+        .await??;
+        if size > self.config.max_size_mb * 1024 * 1024 {
+            return Err(anyhow!(
+                "{} MB exceeds artifacts.max_size_mb",
+                size / 1024 / 1024
+            ));
+        }
+
+        let id = self
+            .insert(
+                drv_path,
+                &name,
+                &product.kind,
+                &product.subtype,
+                is_dir,
+                size,
+            )
+            .await?;
+
+        let target = self.config.dir.join(id.to_string()).join(&name);
+        let copied =
+            tokio::task::spawn_blocking(move || copy_recursively(&source, &target)).await?;
+        if let Err(e) = copied {
+            self.delete(id).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Store a file icicle generated about a build, such as an attestation, replacing
+    /// an earlier one of the same name. Unlike build products this ignores `enabled`.
+    pub async fn store_generated(
+        &self,
+        drv_path: &str,
+        name: &str,
+        kind: &str,
+        contents: Vec<u8>,
+    ) -> Result<i64> {
+        let previous: Vec<i64> =
+            sqlx::query_scalar("SELECT id FROM artifacts WHERE drv_path = ? AND name = ?")
+                .bind(drv_path)
+                .bind(name)
+                .fetch_all(&self.db_pool)
+                .await?;
+        for id in previous {
+            self.delete(id).await;
+        }
+
+        let id = self
+            .insert(drv_path, name, kind, "icicle", false, contents.len() as u64)
+            .await?;
+        let target = self.config.dir.join(id.to_string()).join(name);
+        let written = async {
+            tokio::fs::create_dir_all(target.parent().unwrap()).await?;
+            tokio::fs::write(&target, contents).await
+        }
+        .await;
+        if let Err(e) = written {
+            self.delete(id).await;
+            return Err(e.into());
+        }
+        Ok(id)
+    }
+
+    async fn insert(
+        &self,
+        drv_path: &str,
+        name: &str,
+        kind: &str,
+        subtype: &str,
+        is_dir: bool,
+        size: u64,
+    ) -> Result<i64> {
+        Ok(sqlx::query(
+            r#"
+            INSERT INTO artifacts (drv_path, name, kind, subtype, is_dir, size, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(drv_path)
+        .bind(name)
+        .bind(kind)
+        .bind(subtype)
+        .bind(is_dir)
+        .bind(size as i64)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&self.db_pool)
+        .await?
+        .last_insert_rowid())
+    }
+
+    pub fn sbom_enabled(&self) -> bool {
+        self.config.sbom
+    }
+
+    /// Look up an artifact's location on disk
+    pub async fn get(&self, id: i64) -> Result<Option<StoredArtifact>> {
+        let row: Option<(String, bool)> =
+            sqlx::query_as("SELECT name, is_dir FROM artifacts WHERE id = ?")
+                .bind(id)
+                .fetch_optional(&self.db_pool)
+                .await?;
+        Ok(row.map(|(name, is_dir)| StoredArtifact {
+            path: self.config.dir.join(id.to_string()).join(&name),
+            name,
+            is_dir,
+        }))
+    }
+
+    async fn remove(&self, drv_path: &str) -> Result<()> {
+        let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM artifacts WHERE drv_path = ?")
+            .bind(drv_path)
+            .fetch_all(&self.db_pool)
+            .await?;
+        for id in ids {
+            self.delete(id).await;
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, id: i64) {
+        let dir = self.config.dir.join(id.to_string());
+        if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove artifact {}: {}", dir.display(), e);
+                return;
+            }
+        }
+        if let Err(e) = sqlx::query("DELETE FROM artifacts WHERE id = ?")
+            .bind(id)
+            .execute(&self.db_pool)
+            .await
+        {
+            warn!("Failed to delete artifact {}: {}", id, e);
+        }
+    }
+
+    /// Delete expired artifacts every hour
+    pub async fn run_retention(self) {
+        if self.config.retention_days == 0 {
+            return;
+        }
+        let mut interval = tokio::time::interval(Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            let cutoff =
+                chrono::Utc::now().timestamp() - (self.config.retention_days * 86400) as i64;
+            let expired: Vec<i64> =
+                match sqlx::query_scalar("SELECT id FROM artifacts WHERE created_at < ?")
+                    .bind(cutoff)
+                    .fetch_all(&self.db_pool)
+                    .await
+                {
+                    Ok(expired) => expired,
+                    Err(e) => {
+                        warn!("Failed to look up expired artifacts: {}", e);
+                        continue;
+                    }
+                };
+            for id in &expired {
+                self.delete(*id).await;
+            }
+            if !expired.is_empty() {
+                info!("Deleted {} expired artifacts", expired.len());
+            }
+        }
+    }
+}
+
+fn disk_usage(path: &Path) -> Result<u64> {
+    let metadata = fs::metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        size += disk_usage(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+/// Copy a file or directory tree, following symlinks. Store files are read-only,
+/// which is kept, but directories must stay writable so retention can delete them.
+fn copy_recursively(source: &Path, target: &Path) -> Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if !source.is_dir() {
+        fs::copy(source, target).with_context(|| format!("Failed to copy {}", source.display()))?;
+        return Ok(());
+    }
+    fs::create_dir_all(target)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        copy_recursively(&entry.path(), &target.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = "file binary-dist /nix/store/abc-image/disk.img\n\
+                        \n\
+                        report coverage \"/nix/store/abc-coverage/html\"\n\
+                        doc\n";
+        assert_eq!(
+            parse_manifest(manifest),
+            vec![
+                Product {
+                    kind: "file".to_string(),
+                    subtype: "binary-dist".to_string(),
+                    path: PathBuf::from("/nix/store/abc-image/disk.img"),
+                },
+                Product {
+                    kind: "report".to_string(),
+                    subtype: "coverage".to_string(),
+                    path: PathBuf::from("/nix/store/abc-coverage/html"),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,454 @@
+use crate::api::ApiConfig;
+use axum::http::{
+    header::{AUTHORIZATION, COOKIE},
+    HeaderMap,
+};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::{collections::HashSet, fmt, str::FromStr};
+use subtle::ConstantTimeEq;
+use tracing::warn;
+
+/// Marks icicle API tokens, e.g. for secret scanners
+const TOKEN_PREFIX: &str = "icicle_";
+/// Cookie holding the secret of a dashboard login
+pub const SESSION_COOKIE: &str = "icicle_session";
+
+/// What a user may do; each role includes the permissions of the roles below it
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// See workflows, builds, logs and repositories
+    Viewer,
+    /// Also trigger and cancel workflows
+    Maintainer,
+    /// Also change settings: repositories, notifications, users and the queue
+    Admin,
+}
+
+impl Role {
+    pub fn allows(self, permission: Permission) -> bool {
+        self >= permission.required_role()
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Maintainer => "maintainer",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        [Role::Viewer, Role::Maintainer, Role::Admin]
+            .into_iter()
+            .find(|r| r.as_str() == role)
+            .ok_or_else(|| format!("Unknown role '{}'", role))
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What an API token may do, whatever the role of its user
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Trigger,
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Trigger => "trigger",
+            Scope::Admin => "admin",
+        }
+    }
+
+    /// The role a token acts with: its user's, capped by its scope
+    pub fn cap(self, role: Role) -> Role {
+        let max = match self {
+            Scope::Read => Role::Viewer,
+            Scope::Trigger => Role::Maintainer,
+            Scope::Admin => Role::Admin,
+        };
+        role.min(max)
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(scope: &str) -> Result<Self, Self::Err> {
+        [Scope::Read, Scope::Trigger, Scope::Admin]
+            .into_iter()
+            .find(|s| s.as_str() == scope)
+            .ok_or_else(|| format!("Unknown scope '{}'", scope))
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A new random API token or session secret; only its hash is stored
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    format!("{}{}", TOKEN_PREFIX, hex::encode(bytes))
+}
+
+/// What the database stores of a token
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Whether `token` is the configured secret `expected`, in constant time so the
+/// comparison does not leak how much of it a guess got right
+pub fn token_matches(expected: &str, token: &str) -> bool {
+    let expected = Sha256::digest(expected.as_bytes());
+    let token = Sha256::digest(token.as_bytes());
+    expected.ct_eq(&token).into()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    View,
+    Trigger,
+    Cancel,
+    Manage,
+}
+
+impl Permission {
+    fn required_role(self) -> Role {
+        match self {
+            Permission::View => Role::Viewer,
+            Permission::Trigger | Permission::Cancel => Role::Maintainer,
+            Permission::Manage => Role::Admin,
+        }
+    }
+}
+
+/// Who a request acts as
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub name: String,
+    pub role: Role,
+    /// Signed in to the dashboard, rather than sending a token
+    pub session: bool,
+    /// Repositories of the organizations the principal is not a member of, whose
+    /// workflows and builds it may not see; filled in by `authorize`
+    pub hidden: HashSet<String>,
+}
+
+impl Principal {
+    /// Whether the principal may see the workflows and builds of a repository
+    pub fn sees(&self, repository: &str) -> bool {
+        !self.hidden.contains(repository)
+    }
+
+    /// The hidden repositories as a JSON array, to leave them out of queries with
+    /// `repository NOT IN (SELECT value FROM json_each(?))`
+    pub fn hidden_json(&self) -> String {
+        serde_json::json!(self.hidden).to_string()
+    }
+
+    /// Whether the principal may see a workflow; unknown workflows are left for the
+    /// caller to report
+    pub async fn sees_workflow(&self, db_pool: &SqlitePool, id: i64) -> Result<bool, sqlx::Error> {
+        if self.hidden.is_empty() {
+            return Ok(true);
+        }
+        let repository =
+            sqlx::query_scalar::<_, String>("SELECT repository FROM workflows WHERE id = ?")
+                .bind(id)
+                .fetch_optional(db_pool)
+                .await?;
+        Ok(repository.is_none_or(|repository| self.sees(&repository)))
+    }
+
+    /// Whether the principal may see the build of a derivation, which it does when it
+    /// sees one of the workflows that needed it
+    pub async fn sees_build(
+        &self,
+        db_pool: &SqlitePool,
+        drv_path: &str,
+    ) -> Result<bool, sqlx::Error> {
+        if self.hidden.is_empty() {
+            return Ok(true);
+        }
+        let repositories = sqlx::query_scalar::<_, String>(
+            "SELECT DISTINCT w.repository FROM build_workflows bw \
+             JOIN workflows w ON w.id = bw.workflow_id WHERE bw.drv_path = ?",
+        )
+        .bind(drv_path)
+        .fetch_all(db_pool)
+        .await?;
+        Ok(repositories.is_empty() || repositories.iter().any(|r| self.sees(r)))
+    }
+
+    /// The workflows of the hidden repositories
+    pub async fn hidden_workflows(
+        &self,
+        db_pool: &SqlitePool,
+    ) -> Result<HashSet<i64>, sqlx::Error> {
+        if self.hidden.is_empty() {
+            return Ok(HashSet::new());
+        }
+        let ids = sqlx::query_scalar::<_, i64>(
+            "SELECT id FROM workflows WHERE repository IN (SELECT value FROM json_each(?))",
+        )
+        .bind(self.hidden_json())
+        .fetch_all(db_pool)
+        .await?;
+        Ok(ids.into_iter().collect())
+    }
+}
+
+/// Repositories of the organizations `member` does not belong to, of all of them for
+/// anonymous callers. Repositories outside any project stay visible to everyone.
+async fn hidden_repositories(
+    db_pool: &SqlitePool,
+    member: Option<&str>,
+) -> Result<HashSet<String>, sqlx::Error> {
+    let repositories = sqlx::query_scalar::<_, String>(
+        r#"
+        SELECT r.full_name FROM repositories r
+        JOIN projects p ON p.id = r.project_id
+        WHERE p.organization_id NOT IN (
+            SELECT m.organization_id FROM organization_members m
+            JOIN users u ON u.id = m.user_id WHERE u.name = ?
+        )
+        "#,
+    )
+    .bind(member)
+    .fetch_all(db_pool)
+    .await?;
+    Ok(repositories.into_iter().collect())
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// No or unknown credentials where some are needed
+    Unauthenticated(&'static str),
+    /// Valid credentials without the required role
+    Forbidden(Permission),
+    /// The token could not be looked up
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Unauthenticated(reason) => f.write_str(reason),
+            AuthError::Forbidden(permission) => write!(
+                f,
+                "{:?} requires the {} role",
+                permission,
+                permission.required_role()
+            ),
+            AuthError::Database(e) => write!(f, "Failed to look up the token: {}", e),
+        }
+    }
+}
+
+/// The value of a cookie sent with the request
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// The user signed in to the dashboard with the session cookie. Sessions only ever view:
+/// changes go through API tokens, which cross-site requests cannot carry.
+async fn session(
+    db_pool: &SqlitePool,
+    headers: &HeaderMap,
+) -> Result<Option<Principal>, AuthError> {
+    let Some(secret) = cookie(headers, SESSION_COOKIE) else {
+        return Ok(None);
+    };
+    let name = sqlx::query_scalar::<_, String>(
+        "SELECT name FROM sessions WHERE token_hash = ? AND expires_at > ?",
+    )
+    .bind(hash_token(secret))
+    .bind(chrono::Utc::now().timestamp())
+    .fetch_optional(db_pool)
+    .await
+    .map_err(AuthError::Database)?;
+    // An expired session leaves the caller anonymous, to be asked to sign in again
+    Ok(name.map(|name| Principal {
+        name,
+        role: Role::Viewer,
+        session: true,
+        hidden: HashSet::new(),
+    }))
+}
+
+/// Resolve the caller from the bearer token (the admin token or one of a user's API
+/// tokens), or else the dashboard session; `None` for anonymous requests
+pub async fn authenticate(
+    config: &ApiConfig,
+    db_pool: &SqlitePool,
+    headers: &HeaderMap,
+) -> Result<Option<Principal>, AuthError> {
+    let Some(token) = headers
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+    else {
+        return session(db_pool, headers).await;
+    };
+
+    if config
+        .admin_token
+        .as_deref()
+        .is_some_and(|admin_token| token_matches(admin_token, token))
+    {
+        return Ok(Some(Principal {
+            name: "admin".to_string(),
+            role: Role::Admin,
+            session: false,
+            hidden: HashSet::new(),
+        }));
+    }
+
+    let row = sqlx::query_as::<_, (i64, String, String, String)>(
+        "SELECT t.id, u.name, u.role, t.scope FROM api_tokens t \
+         JOIN users u ON u.id = t.user_id WHERE t.token_hash = ?",
+    )
+    .bind(hash_token(token))
+    .fetch_optional(db_pool)
+    .await
+    .map_err(AuthError::Database)?;
+    let Some((id, name, role, scope)) = row else {
+        warn!("Rejected request: invalid token");
+        return Err(AuthError::Unauthenticated("Invalid token"));
+    };
+    let (Ok(role), Ok(scope)) = (role.parse::<Role>(), scope.parse::<Scope>()) else {
+        warn!(
+            "Rejected request: token {} has an unknown role or scope",
+            id
+        );
+        return Err(AuthError::Unauthenticated("Invalid token"));
+    };
+
+    sqlx::query("UPDATE api_tokens SET last_used_at = ? WHERE id = ?")
+        .bind(chrono::Utc::now().timestamp())
+        .bind(id)
+        .execute(db_pool)
+        .await
+        .map_err(AuthError::Database)?;
+    Ok(Some(Principal {
+        name,
+        role: scope.cap(role),
+        session: false,
+        hidden: HashSet::new(),
+    }))
+}
+
+/// Check that the caller may perform `permission`, and find the repositories hidden
+/// from it: all but admins only see the organizations they are members of.
+/// Anonymous callers may only view, and only if the instance is public.
+pub async fn authorize(
+    config: &ApiConfig,
+    db_pool: &SqlitePool,
+    headers: &HeaderMap,
+    permission: Permission,
+) -> Result<Principal, AuthError> {
+    let mut principal = match authenticate(config, db_pool, headers).await? {
+        Some(principal) if principal.role.allows(permission) => principal,
+        Some(principal) => {
+            warn!(
+                "Rejected {:?} request from {} ({})",
+                permission, principal.name, principal.role
+            );
+            return Err(AuthError::Forbidden(permission));
+        }
+        None if config.public && permission == Permission::View => {
+            let principal = Principal {
+                name: "anonymous".to_string(),
+                role: Role::Viewer,
+                session: false,
+                hidden: hidden_repositories(db_pool, None)
+                    .await
+                    .map_err(AuthError::Database)?,
+            };
+            return Ok(principal);
+        }
+        None => return Err(AuthError::Unauthenticated("Missing bearer token")),
+    };
+    if principal.role != Role::Admin {
+        principal.hidden = hidden_repositories(db_pool, Some(&principal.name))
+            .await
+            .map_err(AuthError::Database)?;
+    }
+    Ok(principal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_role_permissions() {
+        assert!(Role::Viewer.allows(Permission::View));
+        assert!(!Role::Viewer.allows(Permission::Trigger));
+        assert!(Role::Maintainer.allows(Permission::Cancel));
+        assert!(!Role::Maintainer.allows(Permission::Manage));
+        assert!(Role::Admin.allows(Permission::Manage));
+    }
+
+    #[test]
+    fn test_scope_caps_role() {
+        assert_eq!(Scope::Read.cap(Role::Admin), Role::Viewer);
+        assert_eq!(Scope::Trigger.cap(Role::Admin), Role::Maintainer);
+        assert_eq!(Scope::Admin.cap(Role::Maintainer), Role::Maintainer);
+        assert_eq!(Scope::Admin.cap(Role::Admin), Role::Admin);
+    }
+
+    #[test]
+    fn test_token_hash() {
+        let token = generate_token();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_ne!(token, generate_token());
+        assert_eq!(hash_token(&token), hash_token(&token));
+        assert_eq!(hash_token(&token).len(), 64);
+    }
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secret", "secreT"));
+        assert!(!token_matches("secret", "secret2"));
+        assert!(!token_matches("secret", ""));
+    }
+
+    #[test]
+    fn test_cookie() {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, "theme=dark; icicle_session=abc=".parse().unwrap());
+        assert_eq!(cookie(&headers, SESSION_COOKIE), Some("abc="));
+        assert_eq!(cookie(&headers, "theme"), Some("dark"));
+        assert_eq!(cookie(&headers, "missing"), None);
+    }
+}
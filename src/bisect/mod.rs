@@ -0,0 +1,650 @@
+use crate::{
+    build::{BuildStatus, WorkflowStatus},
+    builders::{self, Builders},
+    config::BuildConfig,
+    credentials::Credentials,
+    events::{Event, EventBus},
+    nix::{self, NixEvaluator},
+    workers::Workers,
+};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::{future::Future, path::Path, sync::Arc, time::Duration};
+use tokio::{
+    process::Command,
+    sync::{broadcast::error::RecvError, Mutex, OwnedSemaphorePermit},
+};
+use tracing::{error, info, info_span, warn, Instrument};
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct BisectConfig {
+    /// Bisect as soon as a branch goes from green to red, rather than on request
+    pub auto: bool,
+    /// Refuse to bisect more commits than this
+    pub max_commits: usize,
+    /// Time allowed to build the attribute at each commit
+    pub timeout_secs: u64,
+}
+
+impl Default for BisectConfig {
+    fn default() -> Self {
+        BisectConfig {
+            auto: false,
+            max_commits: 1000,
+            timeout_secs: 3600,
+        }
+    }
+}
+
+impl BisectConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_commits == 0 {
+            return Err(anyhow!("max_commits must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Bisection {
+    pub id: i64,
+    pub workflow_id: i64,
+    pub attribute: String,
+    pub attribute_set: String,
+    pub good_commit: String,
+    pub bad_commit: String,
+    pub status: String,
+    pub culprit_commit: Option<String>,
+    pub error: Option<String>,
+    pub created_at: i64,
+    pub finished_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Step {
+    pub commit_sha: String,
+    pub good: bool,
+    pub finished_at: i64,
+}
+
+/// The failed workflow and the last good one before it on its branch
+#[derive(Debug, sqlx::FromRow)]
+struct Range {
+    repository: String,
+    clone_url: String,
+    attribute_set: String,
+    good_commit: String,
+    bad_commit: String,
+}
+
+const BISECTION_COLUMNS: &str = "id, workflow_id, attribute, attribute_set, good_commit, \
+    bad_commit, status, culprit_commit, error, created_at, finished_at";
+
+pub async fn list(db_pool: &SqlitePool, workflow_id: i64) -> Result<Vec<Bisection>> {
+    Ok(sqlx::query_as::<_, Bisection>(&format!(
+        "SELECT {} FROM bisections WHERE workflow_id = ? ORDER BY id",
+        BISECTION_COLUMNS
+    ))
+    .bind(workflow_id)
+    .fetch_all(db_pool)
+    .await?)
+}
+
+pub async fn get(db_pool: &SqlitePool, id: i64) -> Result<Option<Bisection>> {
+    Ok(sqlx::query_as::<_, Bisection>(&format!(
+        "SELECT {} FROM bisections WHERE id = ?",
+        BISECTION_COLUMNS
+    ))
+    .bind(id)
+    .fetch_optional(db_pool)
+    .await?)
+}
+
+pub async fn steps(db_pool: &SqlitePool, bisection_id: i64) -> Result<Vec<Step>> {
+    Ok(sqlx::query_as::<_, Step>(
+        r#"
+        SELECT commit_sha, good, finished_at FROM bisection_steps
+        WHERE bisection_id = ? ORDER BY rowid
+        "#,
+    )
+    .bind(bisection_id)
+    .fetch_all(db_pool)
+    .await?)
+}
+
+/// How often a bisection waiting for a remote builder checks for a free slot
+const BUILDER_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Finds the commit that broke an attribute by building it between the last good
+/// and the first bad commit of a branch. Bisections run one at a time.
+#[derive(Clone)]
+pub struct Bisector {
+    config: BisectConfig,
+    db_pool: SqlitePool,
+    events: EventBus,
+    credentials: Credentials,
+    running: Arc<Mutex<()>>,
+    /// Systems built locally, as for the executor
+    local_systems: Vec<String>,
+    /// System features of this machine
+    local_features: Vec<String>,
+    builders: Builders,
+    /// Only to know which systems are left to them, bisections don't lease jobs
+    workers: Option<Workers>,
+}
+
+impl Bisector {
+    pub fn new(
+        config: &BisectConfig,
+        build: &BuildConfig,
+        db_pool: SqlitePool,
+        events: EventBus,
+        credentials: Credentials,
+    ) -> Self {
+        Self {
+            config: config.clone(),
+            db_pool,
+            events,
+            credentials,
+            running: Arc::default(),
+            local_systems: build.local_systems.clone(),
+            local_features: build.local_features(),
+            builders: Builders::default(),
+            workers: None,
+        }
+    }
+
+    /// Build the systems of remote builders on them
+    pub fn with_builders(mut self, builders: Builders) -> Self {
+        self.builders = builders;
+        self
+    }
+
+    /// Leave the systems only workers build alone, rather than build them locally
+    pub fn with_workers(mut self, workers: Workers) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    /// Fail the bisections interrupted by a restart and, if enabled, bisect the
+    /// workflows that break their branch
+    pub async fn run(self) {
+        if let Err(e) = sqlx::query(
+            r#"
+            UPDATE bisections
+            SET status = 'failed', error = 'Interrupted by a restart', finished_at = ?
+            WHERE status IN ('queued', 'running')
+            "#,
+        )
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&self.db_pool)
+        .await
+        {
+            warn!("Failed to fail interrupted bisections: {}", e);
+        }
+        if !self.config.auto {
+            return;
+        }
+
+        let mut receiver = self.events.subscribe();
+        loop {
+            let workflow_id = match receiver.recv().await {
+                Ok(Event::WorkflowStatus {
+                    workflow_id,
+                    status: WorkflowStatus::Failed,
+                }) => workflow_id,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Bisections missed {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            match self.breaks_branch(workflow_id).await {
+                Ok(true) => {
+                    if let Err(e) = self.start(workflow_id, None).await {
+                        error!("Failed to bisect workflow {}: {}", workflow_id, e);
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => warn!("Failed to check workflow {}: {}", workflow_id, e),
+            }
+        }
+    }
+
+    /// Whether the previous finished workflow of the branch succeeded
+    async fn breaks_branch(&self, workflow_id: i64) -> Result<bool> {
+        let previous: Option<String> = sqlx::query_scalar(
+            r#"
+            SELECT p.status FROM workflows p JOIN workflows w ON w.id = ?1
+            WHERE p.repository = w.repository AND p.branch IS w.branch AND p.id < ?1
+              AND p.status IN ('Completed', 'Failed')
+            ORDER BY p.id DESC LIMIT 1
+            "#,
+        )
+        .bind(workflow_id)
+        .fetch_optional(&self.db_pool)
+        .await?;
+        Ok(previous.as_deref() == Some("Completed"))
+    }
+
+    /// Start bisecting a failed attribute of a workflow, by default its first failed
+    /// build. Returns the bisection already running for the workflow if there is one,
+    /// and None if there is no failed build or no earlier good workflow on the branch.
+    pub async fn start(
+        &self,
+        workflow_id: i64,
+        attribute: Option<String>,
+    ) -> Result<Option<Bisection>> {
+        let active = list(&self.db_pool, workflow_id)
+            .await?
+            .into_iter()
+            .find(|bisection| matches!(bisection.status.as_str(), "queued" | "running"));
+        if active.is_some() {
+            return Ok(active);
+        }
+
+        let failed: Vec<String> = sqlx::query_scalar(
+            r#"
+            SELECT b.name FROM builds b JOIN build_workflows bw ON bw.drv_path = b.drv_path
+            WHERE bw.workflow_id = ? AND b.status IN (?, ?)
+            ORDER BY b.name
+            "#,
+        )
+        .bind(workflow_id)
+        .bind(BuildStatus::Failed.to_string())
+        .bind(BuildStatus::Timedout.to_string())
+        .fetch_all(&self.db_pool)
+        .await?;
+        let attribute = match attribute {
+            Some(attribute) if failed.contains(&attribute) => attribute,
+            Some(_) => return Ok(None),
+            None => match failed.into_iter().next() {
+                Some(attribute) => attribute,
+                None => return Ok(None),
+            },
+        };
+
+        // Builds of a pipeline's later stages come from the stage's attribute set
+        let Some(range) = sqlx::query_as::<_, Range>(
+            r#"
+            SELECT w.repository, r.clone_url,
+                   COALESCE(
+                       (SELECT s.attribute_set FROM workflow_stages s
+                        WHERE s.workflow_id = w.id AND s.status = 'failed'),
+                       w.attribute_set
+                   ) AS attribute_set,
+                   g.commit_sha AS good_commit, w.commit_sha AS bad_commit
+            FROM workflows w
+            JOIN repositories r ON r.full_name = w.repository
+            JOIN workflows g ON g.id = (
+                SELECT MAX(id) FROM workflows
+                WHERE repository = w.repository AND branch IS w.branch AND id < w.id
+                  AND status = 'Completed'
+            )
+            WHERE w.id = ?
+            "#,
+        )
+        .bind(workflow_id)
+        .fetch_optional(&self.db_pool)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let id = sqlx::query(
+            r#"
+            INSERT INTO bisections
+                (workflow_id, attribute, attribute_set, good_commit, bad_commit, status, created_at)
+            VALUES (?, ?, ?, ?, ?, 'queued', ?)
+            "#,
+        )
+        .bind(workflow_id)
+        .bind(&attribute)
+        .bind(&range.attribute_set)
+        .bind(&range.good_commit)
+        .bind(&range.bad_commit)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&self.db_pool)
+        .await?
+        .last_insert_rowid();
+        self.publish(workflow_id, id, "queued", None);
+        info!(
+            "Bisecting {} of {} between {} and {}",
+            attribute, range.repository, range.good_commit, range.bad_commit
+        );
+
+        tokio::spawn(
+            self.clone()
+                .bisect(id, workflow_id, attribute, range)
+                .instrument(info_span!("bisection", id, workflow_id)),
+        );
+        get(&self.db_pool, id).await
+    }
+
+    async fn bisect(self, id: i64, workflow_id: i64, attribute: String, range: Range) {
+        let _guard = self.running.lock().await;
+        if let Err(e) = sqlx::query("UPDATE bisections SET status = 'running' WHERE id = ?")
+            .bind(id)
+            .execute(&self.db_pool)
+            .await
+        {
+            warn!("Failed to update bisection {}: {}", id, e);
+        }
+        self.publish(workflow_id, id, "running", None);
+
+        let (status, culprit, error) = match self.search(id, &attribute, &range).await {
+            Ok(culprit) => {
+                info!("{} was broken by {}", attribute, culprit);
+                ("found", Some(culprit), None)
+            }
+            Err(e) => {
+                error!("Bisection {} failed: {:#}", id, e);
+                ("failed", None, Some(format!("{:#}", e)))
+            }
+        };
+
+        if let Err(e) = sqlx::query(
+            r#"
+            UPDATE bisections SET status = ?, culprit_commit = ?, error = ?, finished_at = ?
+            WHERE id = ?
+            "#,
+        )
+        .bind(status)
+        .bind(&culprit)
+        .bind(error)
+        .bind(chrono::Utc::now().timestamp())
+        .bind(id)
+        .execute(&self.db_pool)
+        .await
+        {
+            warn!("Failed to update bisection {}: {}", id, e);
+        }
+        self.publish(workflow_id, id, status, culprit);
+    }
+
+    /// Binary search the first-parent history between the good and bad commits
+    /// for the first commit where the attribute fails to build
+    async fn search(&self, id: i64, attribute: &str, range: &Range) -> Result<String> {
+        let credential = self.credentials.get(&range.repository).await?;
+        let mut checkout = NixEvaluator::new();
+        checkout
+            .clone_repository(&range.clone_url, &range.bad_commit, credential.as_ref())
+            .await?;
+        let repo_path = checkout.repo_path().unwrap();
+        if git(repo_path, &["rev-parse", "--is-shallow-repository"]).await? == "true" {
+            checkout.unshallow().await?;
+        }
+        git(
+            repo_path,
+            &[
+                "merge-base",
+                "--is-ancestor",
+                &range.good_commit,
+                &range.bad_commit,
+            ],
+        )
+        .await
+        .context("The good commit is not an ancestor of the bad one")?;
+
+        let commits: Vec<String> = git(
+            repo_path,
+            &[
+                "rev-list",
+                "--first-parent",
+                "--reverse",
+                &format!("{}..{}", range.good_commit, range.bad_commit),
+            ],
+        )
+        .await?
+        .lines()
+        .map(str::to_string)
+        .collect();
+        if commits.is_empty() {
+            return Err(anyhow!("No commits between the good and bad commit"));
+        }
+        if commits.len() > self.config.max_commits {
+            return Err(anyhow!(
+                "{} commits to bisect, more than max_commits ({})",
+                commits.len(),
+                self.config.max_commits
+            ));
+        }
+
+        // Whatever breaks at the good commit already was not broken in the range
+        if !self
+            .step(
+                id,
+                repo_path,
+                &range.good_commit,
+                &range.attribute_set,
+                attribute,
+            )
+            .await?
+        {
+            return Err(anyhow!(
+                "{} fails to build at the good commit {} too",
+                attribute,
+                range.good_commit
+            ));
+        }
+
+        info!("Bisecting {} commits", commits.len());
+        first_failing(&commits, move |commit| async move {
+            self.step(id, repo_path, &commit, &range.attribute_set, attribute)
+                .await
+        })
+        .await
+    }
+
+    /// Build the attribute at a commit and record whether it built
+    async fn step(
+        &self,
+        id: i64,
+        repo_path: &Path,
+        commit: &str,
+        attribute_set: &str,
+        attribute: &str,
+    ) -> Result<bool> {
+        let builds = self
+            .builds_at(repo_path, commit, attribute_set, attribute)
+            .await?;
+        sqlx::query(
+            r#"
+                INSERT OR REPLACE INTO bisection_steps (bisection_id, commit_sha, good, finished_at)
+                VALUES (?, ?, ?, ?)
+                "#,
+        )
+        .bind(id)
+        .bind(commit)
+        .bind(builds)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&self.db_pool)
+        .await?;
+        info!(
+            "{} {} at {}",
+            attribute,
+            if builds { "builds" } else { "fails" },
+            commit
+        );
+        Ok(builds)
+    }
+
+    /// Whether the attribute evaluates and builds at a commit, on a machine the
+    /// executor would build it on
+    async fn builds_at(
+        &self,
+        repo_path: &Path,
+        commit: &str,
+        attribute_set: &str,
+        attribute: &str,
+    ) -> Result<bool> {
+        git(repo_path, &["checkout", "--force", commit]).await?;
+        let installable = format!(".#{}", nix::job_attribute(attribute_set, attribute));
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        let eval = Command::new("nix")
+            .current_dir(repo_path)
+            .args(["eval", "--json", &installable, "--apply"])
+            .arg("d: { inherit (d) system; features = d.requiredSystemFeatures or [ ]; }")
+            .kill_on_drop(true)
+            .output();
+        let Some(eval) = tokio::time::timeout(timeout, eval)
+            .await
+            .ok()
+            .transpose()
+            .context("Failed to run nix eval")?
+            .filter(|output| output.status.success())
+        else {
+            return Ok(false);
+        };
+        let requirements: Requirements =
+            serde_json::from_slice(&eval.stdout).context("Invalid requirements")?;
+
+        let mut build = Command::new("nix");
+        build
+            .current_dir(repo_path)
+            .args(["build", "--no-link", &installable])
+            .kill_on_drop(true);
+        let _permit = self.place(&mut build, &requirements).await?;
+        let output = tokio::time::timeout(timeout, build.output())
+            .await
+            .ok()
+            .transpose()
+            .context("Failed to run nix build")?;
+        Ok(output.is_some_and(|output| output.status.success()))
+    }
+
+    /// Pick where to build like the executor does: a free remote builder of the
+    /// system, this machine, or else wait for a remote builder. Returns the remote
+    /// builder's slot, to hold until the build is done.
+    async fn place(
+        &self,
+        build: &mut Command,
+        requirements: &Requirements,
+    ) -> Result<Option<OwnedSemaphorePermit>> {
+        let Requirements { system, features } = requirements;
+        let on_workers = self
+            .workers
+            .as_ref()
+            .is_some_and(|workers| workers.builds(system, features));
+        let remote = self.builders.builds(system, features);
+        let local = builders::builds_locally(
+            &self.local_systems,
+            &self.local_features,
+            remote || on_workers,
+            system,
+            features,
+        );
+        if !remote && !local {
+            return Err(anyhow!(
+                "Bisections can't build {} with features [{}]: it is left to the workers \
+                 or no builder is configured for it",
+                system,
+                features.join(", ")
+            ));
+        }
+        loop {
+            if let Some((machine, permit)) = self.builders.take(system, features) {
+                build.args(["--builders", &machine, "--max-jobs", "0"]);
+                return Ok(Some(permit));
+            }
+            if local {
+                return Ok(None);
+            }
+            tokio::time::sleep(BUILDER_POLL_INTERVAL).await;
+        }
+    }
+
+    fn publish(&self, workflow_id: i64, bisection_id: i64, status: &str, culprit: Option<String>) {
+        self.events.publish(Event::BisectionStatus {
+            workflow_id,
+            bisection_id,
+            status: status.to_string(),
+            culprit_commit: culprit,
+        });
+    }
+}
+
+/// What a derivation needs of the machine building it
+#[derive(Debug, Deserialize)]
+struct Requirements {
+    system: String,
+    features: Vec<String>,
+}
+
+/// Binary search `commits` for the first one that doesn't build, given the last one
+/// doesn't and the one before the first did
+async fn first_failing<F, Fut>(commits: &[String], mut builds: F) -> Result<String>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Result<bool>>,
+{
+    // Every commit before commits[first] built, and commits[last] did not
+    let (mut first, mut last) = (0, commits.len() - 1);
+    while first < last {
+        let mid = (first + last) / 2;
+        if builds(commits[mid].clone()).await? {
+            first = mid + 1;
+        } else {
+            last = mid;
+        }
+    }
+    Ok(commits[last].clone())
+}
+
+/// Run git in a checkout, returning its trimmed output
+async fn git(repo_path: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .current_dir(repo_path)
+        .args(args)
+        .output()
+        .await
+        .context("Failed to execute git")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "git {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[tokio::test]
+    async fn test_first_failing() {
+        let commits: Vec<String> = (0..10).map(|i| i.to_string()).collect();
+        for culprit in 0..commits.len() {
+            let built = RefCell::new(Vec::new());
+            let found = first_failing(&commits, |commit| {
+                built.borrow_mut().push(commit.clone());
+                async move { Ok(commit.parse::<usize>().unwrap() < culprit) }
+            })
+            .await
+            .unwrap();
+            assert_eq!(found, culprit.to_string());
+            // The bad commit is known to fail, so it is never built
+            assert!(built.borrow().len() <= 4);
+            assert!(!built.borrow().contains(&"9".to_string()));
+        }
+
+        let commits = vec!["bad".to_string()];
+        let found = first_failing(&commits, |_| async { panic!("nothing to build") })
+            .await
+            .unwrap();
+        assert_eq!(found, "bad");
+    }
+
+    #[tokio::test]
+    async fn test_first_failing_stops_on_errors() {
+        let commits: Vec<String> = (0..10).map(|i| i.to_string()).collect();
+        let result = first_failing(&commits, |_| async { Err(anyhow!("no checkout")) }).await;
+        assert!(result.is_err());
+    }
+}
@@ -1,10 +1,15 @@
+use crate::{
+    events::{Event, EventBus},
+    progress::BuildProgress,
+};
 use daggy::{stable_dag::StableDag, NodeIndex, Walker};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
     sync::Mutex,
 };
-use tokio::sync::Notify;
+use tokio::sync::{watch, Notify};
+use tokio_util::sync::CancellationToken;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Derivation {
@@ -14,6 +19,23 @@ pub struct Derivation {
     pub system: String,
     pub input_drvs: Vec<String>,
     pub status: BuildStatus,
+    /// SPDX identifiers (or names) of the licenses in the derivation's meta
+    #[serde(default)]
+    pub licenses: Vec<String>,
+    /// Build timeout from the derivation's `meta.timeout`, instead of the configured one
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Features a builder needs to build it, from `requiredSystemFeatures`
+    #[serde(default)]
+    pub required_features: Vec<String>,
+    /// For aggregate jobs, the derivations they are made of; the workflow's result
+    /// is decided by its aggregates when it has any
+    #[serde(default)]
+    pub constituents: Option<Vec<String>>,
+    /// Whether the cache had all its outputs when the workflow was evaluated; None
+    /// if that could not be checked
+    #[serde(default)]
+    pub cached: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,6 +43,71 @@ pub struct BuildJob {
     pub derivation: Derivation,
     pub status: BuildStatus,
     pub requested_by: HashSet<i64>, // workflow IDs that need this derivation
+    /// Highest priority of the workflows that need this derivation
+    pub priority: i64,
+    /// What the build is doing while it runs
+    #[serde(default)]
+    pub progress: Option<BuildProgress>,
+    /// Times the build was retried after failing for a transient reason
+    #[serde(default)]
+    pub retries: u32,
+    /// When the job last became ready, to tell how long it waited for a builder
+    #[serde(default)]
+    pub ready_at: Option<i64>,
+    /// When the job last started running
+    #[serde(default)]
+    pub started_at: Option<i64>,
+}
+
+/// The jobs of a workflow with the dependencies between them
+#[derive(Debug, Serialize)]
+pub struct JobGraph {
+    pub nodes: Vec<JobNode>,
+    pub edges: Vec<JobEdge>,
+}
+
+impl JobGraph {
+    /// Graph of jobs no longer in the build queue, from the stored jobs and their
+    /// inputs; inputs that are not jobs of the workflow are left out
+    pub fn stored(nodes: Vec<JobNode>, inputs: &[(String, String)]) -> Self {
+        let drv_paths: HashSet<&str> = nodes.iter().map(|node| node.drv_path.as_str()).collect();
+        let edges = inputs
+            .iter()
+            .filter(|(_, input)| drv_paths.contains(input.as_str()))
+            .map(|(drv_path, input)| JobEdge {
+                from: input.clone(),
+                to: drv_path.clone(),
+                blocking: false,
+            })
+            .collect();
+        let mut graph = JobGraph { nodes, edges };
+        graph.sort();
+        graph
+    }
+
+    fn sort(&mut self) {
+        self.nodes.sort_by(|a, b| a.name.cmp(&b.name));
+        self.edges
+            .sort_by(|a, b| (&a.to, &a.from).cmp(&(&b.to, &b.from)));
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobNode {
+    pub drv_path: String,
+    pub name: String,
+    pub system: String,
+    pub status: BuildStatus,
+}
+
+/// A job depending on another one through its `input_drvs`
+#[derive(Debug, Serialize)]
+pub struct JobEdge {
+    /// Derivation path of the dependency
+    pub from: String,
+    pub to: String,
+    /// Whether the dependency did not finish yet, keeping the job queued
+    pub blocking: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -73,6 +160,36 @@ impl std::fmt::Display for BuildStatus {
     }
 }
 
+impl std::str::FromStr for BuildStatus {
+    type Err = String;
+
+    /// Parse a status as displayed, e.g. from the builds table
+    fn from_str(status: &str) -> Result<Self, Self::Err> {
+        match status {
+            "queued" => Ok(BuildStatus::Queued),
+            "ready" => Ok(BuildStatus::Ready),
+            "running" => Ok(BuildStatus::Running),
+            "success" => Ok(BuildStatus::Success),
+            "cached" => Ok(BuildStatus::Cached),
+            "failed" => Ok(BuildStatus::Failed),
+            "timed out" => Ok(BuildStatus::Timedout),
+            "canceled" => Ok(BuildStatus::Canceled),
+            _ => Err(format!("Unknown build status {}", status)),
+        }
+    }
+}
+
+/// How ready jobs of the same priority are ordered
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheduling {
+    /// Take turns between repositories, so one large workflow does not hold up the others
+    #[default]
+    Fair,
+    /// In the order jobs became ready
+    Fifo,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum WorkflowStatus {
     Running,
@@ -87,11 +204,25 @@ struct BuildQueueState {
     drv_to_node: HashMap<String, NodeIndex>,
     ready: Vec<NodeIndex>,
     pending_workflows: HashMap<i64, usize>, // workflow_id -> count of unfinished jobs
+    /// Workflows over quota, whose jobs only run when nothing else is ready
+    deprioritized: HashSet<i64>,
+    /// Priority of the jobs of each workflow; 0 when unset
+    priorities: HashMap<i64, i64>,
+    /// Repository of each workflow, to take turns between repositories
+    repositories: HashMap<i64, String>,
+    /// When a job of each repository was last handed out, as a count of jobs handed out
+    served: HashMap<String, u64>,
+    jobs_served: u64,
+    scheduling: Scheduling,
+    /// Cancel the builds that were handed to the executor, by derivation path
+    running: HashMap<String, CancellationToken>,
+    events: EventBus,
 }
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct BuildQueue {
     state: Mutex<BuildQueueState>,
     ready_signal: Notify,
+    paused: watch::Sender<bool>,
 }
 
 impl BuildQueueState {
@@ -100,6 +231,7 @@ impl BuildQueueState {
         let mut roots = Vec::new();
         let mut new_jobs_count = 0;
         let mut duplicate_pending_jobs = 0;
+        let priority = self.priorities.get(&workflow_id).copied().unwrap_or(0);
 
         // Add nodes
         for d in &derivations {
@@ -107,6 +239,7 @@ impl BuildQueueState {
                 // Duplicate job, just add the workflow to requested_by
                 let job = self.dag.node_weight_mut(*idx).unwrap();
                 job.requested_by.insert(workflow_id);
+                job.priority = job.priority.max(priority);
 
                 // Count duplicate jobs that aren't done yet
                 if !job.status.done() {
@@ -129,11 +262,17 @@ impl BuildQueueState {
                     BuildStatus::Queued
                 },
                 requested_by,
+                priority,
+                progress: None,
+                retries: 0,
+                ready_at: ready.then(|| chrono::Utc::now().timestamp()),
+                started_at: None,
             });
             if ready {
                 roots.push(idx);
             }
             self.drv_to_node.insert(d.drv_path.clone(), idx);
+            self.publish_job_status(idx);
         }
 
         // Track pending jobs for this workflow (new jobs + duplicate jobs that aren't done)
@@ -153,7 +292,7 @@ impl BuildQueueState {
             }
         }
         //self.dag.transitive_reduce(roots.clone());
-        self.ready.extend(roots.into_iter());
+        self.ready.extend(roots);
 
         // Workflow is complete if there are no pending jobs
         total_pending == 0
@@ -168,19 +307,40 @@ impl BuildQueueState {
         } else if status.done() {
             self.propagate_success(id, status)
         } else {
-            let job = self.dag.node_weight_mut(id).unwrap();
-            job.status = status;
+            self.set_status(id, status);
             Vec::new()
         }
     }
+    fn set_status(&mut self, id: NodeIndex, status: BuildStatus) {
+        let job = self.dag.node_weight_mut(id).unwrap();
+        job.status = status;
+        match status {
+            BuildStatus::Ready => job.ready_at = Some(chrono::Utc::now().timestamp()),
+            BuildStatus::Running => job.started_at = Some(chrono::Utc::now().timestamp()),
+            _ => {}
+        }
+        if status.done() {
+            self.running.remove(&job.derivation.drv_path);
+            job.progress = None;
+        }
+        self.publish_job_status(id);
+    }
+    fn publish_job_status(&self, id: NodeIndex) {
+        let job = self.dag.node_weight(id).unwrap();
+        self.events.publish(Event::JobStatus {
+            drv_path: job.derivation.drv_path.clone(),
+            name: job.derivation.name.clone(),
+            status: job.status,
+            workflows: job.requested_by.iter().copied().collect(),
+        });
+    }
     fn propagate_error(&mut self, id: NodeIndex, status: BuildStatus) -> Vec<i64> {
         let mut completed_workflows = Vec::new();
 
-        let job = self.dag.node_weight_mut(id).unwrap();
-        job.status = status;
+        self.set_status(id, status);
 
         // Decrement workflow counters for this job
-        let requested_by = job.requested_by.clone();
+        let requested_by = self.dag.node_weight(id).unwrap().requested_by.clone();
         for workflow_id in requested_by {
             if let Some(count) = self.pending_workflows.get_mut(&workflow_id) {
                 *count = count.saturating_sub(1);
@@ -207,11 +367,10 @@ impl BuildQueueState {
     fn propagate_success(&mut self, id: NodeIndex, status: BuildStatus) -> Vec<i64> {
         let mut completed_workflows = Vec::new();
 
-        let job = self.dag.node_weight_mut(id).unwrap();
-        job.status = status;
+        self.set_status(id, status);
 
         // Decrement workflow counters for this job
-        let requested_by = job.requested_by.clone();
+        let requested_by = self.dag.node_weight(id).unwrap().requested_by.clone();
         for workflow_id in requested_by {
             if let Some(count) = self.pending_workflows.get_mut(&workflow_id) {
                 *count = count.saturating_sub(1);
@@ -227,31 +386,96 @@ impl BuildQueueState {
             self.dag.remove_edge(e);
             let nparents = self.dag.parents(n).iter(&self.dag).count();
             if nparents == 0 {
-                let j = self.dag.node_weight_mut(n).unwrap();
-                j.status = BuildStatus::Ready;
+                self.set_status(n, BuildStatus::Ready);
                 self.ready.push(n);
             }
         }
 
         completed_workflows
     }
-    fn clear_workflow(&mut self, workflow_id: i64) {
+    /// Remove a workflow's jobs that no other workflow needs, stopping those being built.
+    /// Returns the removed jobs.
+    fn clear_workflow(&mut self, workflow_id: i64) -> Vec<BuildJob> {
+        let mut removed = Vec::new();
         for (d, i) in self.drv_to_node.clone().into_iter() {
             let empty = {
                 let job = self.dag.node_weight_mut(i).unwrap();
-                job.requested_by.remove(&workflow_id);
+                if job.requested_by.remove(&workflow_id) {
+                    job.priority = job
+                        .requested_by
+                        .iter()
+                        .map(|id| self.priorities.get(id).copied().unwrap_or(0))
+                        .max()
+                        .unwrap_or(0);
+                }
                 job.requested_by.is_empty()
             };
             if empty {
-                self.dag.remove_node(i);
+                removed.extend(self.dag.remove_node(i));
                 self.drv_to_node.remove(&d);
+                if let Some(token) = self.running.remove(&d) {
+                    token.cancel();
+                }
             }
         }
+        self.deprioritized.remove(&workflow_id);
+        self.priorities.remove(&workflow_id);
+        if let Some(repository) = self.repositories.remove(&workflow_id) {
+            if !self.repositories.values().any(|r| *r == repository) {
+                self.served.remove(&repository);
+            }
+        }
+
+        // Jobs of other workflows no longer wait for the removed ones
+        let unblocked: Vec<NodeIndex> = self
+            .drv_to_node
+            .values()
+            .copied()
+            .filter(|&i| {
+                self.dag[i].status == BuildStatus::Queued
+                    && self.dag.parents(i).iter(&self.dag).next().is_none()
+            })
+            .collect();
+        for i in unblocked {
+            self.set_status(i, BuildStatus::Ready);
+            self.ready.push(i);
+        }
+        removed
     }
 }
 impl BuildQueue {
-    pub fn new() -> Self {
-        BuildQueue::default()
+    pub fn new(events: EventBus) -> Self {
+        BuildQueue {
+            state: Mutex::new(BuildQueueState {
+                events,
+                ..Default::default()
+            }),
+            ready_signal: Notify::new(),
+            paused: watch::Sender::new(false),
+        }
+    }
+
+    /// Set how ready jobs of the same priority are ordered
+    pub fn set_scheduling(&self, scheduling: Scheduling) {
+        self.state.lock().unwrap().scheduling = scheduling;
+    }
+
+    /// Stop or resume handing ready jobs to the executor
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.send_replace(paused);
+        self.state
+            .lock()
+            .unwrap()
+            .events
+            .publish(Event::Paused { paused });
+    }
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+    /// Wait until the queue is not paused
+    pub async fn wait_until_resumed(&self) {
+        let mut paused = self.paused.subscribe();
+        let _ = paused.wait_for(|p| !*p).await;
     }
 
     /// Add a batch of derivations from a workflow
@@ -264,19 +488,164 @@ impl BuildQueue {
         }
         is_complete
     }
+
+    /// Add the derivations of a workflow queued before a restart, along with the
+    /// statuses the finished ones reached. Jobs that were running are built again.
+    /// Returns the workflow IDs that are complete.
+    pub fn restore_workflow(
+        &self,
+        derivations: Vec<Derivation>,
+        workflow_id: i64,
+        finished: &HashMap<String, BuildStatus>,
+    ) -> Vec<i64> {
+        let mut state = self.state.lock().unwrap();
+        let mut completed = Vec::new();
+        if state.add_jobs(derivations, workflow_id) {
+            completed.push(workflow_id);
+        }
+        // Dependencies first: a job is only done once its dependencies are
+        loop {
+            let next = finished.iter().find(|(drv_path, status)| {
+                status.done()
+                    && state.drv_to_node.get(*drv_path).is_some_and(|&idx| {
+                        !state.dag[idx].status.done()
+                            && state.dag.parents(idx).iter(&state.dag).next().is_none()
+                    })
+            });
+            let Some((drv_path, &status)) = next else {
+                break;
+            };
+            completed.extend(state.update_status(drv_path, status));
+        }
+        let state = &mut *state;
+        state.ready.retain(|&i| {
+            state
+                .dag
+                .node_weight(i)
+                .is_some_and(|job| !job.status.done())
+        });
+        if !state.ready.is_empty() {
+            self.ready_signal.notify_one();
+        }
+        completed
+    }
+
     pub async fn wait_for_ready_jobs(&self) {
         self.ready_signal.notified().await;
     }
 
-    /// Drain the ready queue
-    pub fn drain_ready_jobs(&self) -> Vec<BuildJob> {
+    /// Take the ready job of highest priority, preferring jobs that a workflow within
+    /// quota needs. Ties go to the repository served least recently under fair
+    /// scheduling, then to the job that became ready first.
+    pub fn pop_ready_job(&self) -> Option<BuildJob> {
+        self.pop_ready_job_with(|_| Some(())).map(|(job, ())| job)
+    }
+
+    /// Like `pop_ready_job`, but only take a job once `take` accepts it, e.g. when a
+    /// builder for its system is free. Jobs `take` declines stay ready.
+    pub fn pop_ready_job_with<T>(
+        &self,
+        mut take: impl FnMut(&BuildJob) -> Option<T>,
+    ) -> Option<(BuildJob, T)> {
         let mut state = self.state.lock().unwrap();
-        let mut ret_ready = Vec::new();
-        std::mem::swap(&mut ret_ready, &mut state.ready);
-        ret_ready
+        // Nodes may have been removed since they became ready (e.g. canceled workflows)
+        let state = &mut *state;
+        state.ready.retain(|&i| state.dag.node_weight(i).is_some());
+        let deprioritized = |i: &NodeIndex| {
+            state.dag[*i]
+                .requested_by
+                .iter()
+                .all(|id| state.deprioritized.contains(id))
+        };
+        // Jobs several repositories need count as served when the least recent one was
+        let last_served = |i: &NodeIndex| match state.scheduling {
+            Scheduling::Fair => state.dag[*i]
+                .requested_by
+                .iter()
+                .filter_map(|id| state.repositories.get(id))
+                .map(|repository| state.served.get(repository).copied().unwrap_or(0))
+                .min()
+                .unwrap_or(0),
+            Scheduling::Fifo => 0,
+        };
+        let mut candidates: Vec<usize> = (0..state.ready.len()).collect();
+        candidates.sort_by_cached_key(|&position| {
+            let i = &state.ready[position];
+            std::cmp::Reverse((
+                !deprioritized(i),
+                state.dag[*i].priority,
+                std::cmp::Reverse(last_served(i)),
+                std::cmp::Reverse(position),
+            ))
+        });
+        let (position, taken) = candidates
             .into_iter()
-            .map(|i| state.dag.node_weight(i).unwrap().clone())
-            .collect()
+            .find_map(|position| Some((position, take(&state.dag[state.ready[position]])?)))?;
+        let i = state.ready.remove(position);
+        let job = state.dag[i].clone();
+        state.jobs_served += 1;
+        for id in &job.requested_by {
+            if let Some(repository) = state.repositories.get(id) {
+                state.served.insert(repository.clone(), state.jobs_served);
+            }
+        }
+        if !job.status.done() {
+            state
+                .running
+                .insert(job.derivation.drv_path.clone(), CancellationToken::new());
+        }
+        Some((job, taken))
+    }
+
+    /// Record what the running build of `drv_path` is doing
+    pub fn set_progress(&self, drv_path: &str, progress: BuildProgress) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(&idx) = state.drv_to_node.get(drv_path) {
+            let job = state.dag.node_weight_mut(idx).unwrap();
+            if job.status == BuildStatus::Running {
+                job.progress = Some(progress);
+            }
+        }
+    }
+
+    pub fn progress(&self, drv_path: &str) -> Option<BuildProgress> {
+        let state = self.state.lock().unwrap();
+        let &idx = state.drv_to_node.get(drv_path)?;
+        state.dag.node_weight(idx)?.progress.clone()
+    }
+
+    /// Cancelled once no workflow needs the job handed out by `pop_ready_job` anymore
+    pub fn cancellation(&self, drv_path: &str) -> CancellationToken {
+        let state = self.state.lock().unwrap();
+        state.running.get(drv_path).cloned().unwrap_or_else(|| {
+            // Already removed from the queue
+            let token = CancellationToken::new();
+            token.cancel();
+            token
+        })
+    }
+
+    /// Run the jobs of a workflow after those of other workflows; undone when the
+    /// workflow's jobs are cleared
+    pub fn deprioritize(&self, workflow_id: i64) {
+        let mut state = self.state.lock().unwrap();
+        state.deprioritized.insert(workflow_id);
+    }
+
+    /// Set the priority of the jobs a workflow adds from now on; undone when the
+    /// workflow's jobs are cleared
+    pub fn set_priority(&self, workflow_id: i64, priority: i64) {
+        let mut state = self.state.lock().unwrap();
+        state.priorities.insert(workflow_id, priority);
+    }
+
+    /// Record the repository of a workflow, whose jobs take turns with those of
+    /// other repositories
+    pub fn set_repository(&self, workflow_id: i64, repository: &str) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .repositories
+            .insert(workflow_id, repository.to_string());
     }
 
     /// Mark a job as done
@@ -290,10 +659,68 @@ impl BuildQueue {
         completed_workflows
     }
 
+    /// Put a running job that failed back to waiting, to be retried once `retry` is
+    /// called. Returns the number of retries including this one.
+    pub fn hold_for_retry(&self, drv_path: &str) -> Option<u32> {
+        let mut state = self.state.lock().unwrap();
+        let &idx = state.drv_to_node.get(drv_path)?;
+        let job = state.dag.node_weight_mut(idx).unwrap();
+        if job.status != BuildStatus::Running {
+            return None;
+        }
+        job.retries += 1;
+        job.progress = None;
+        let retries = job.retries;
+        state.set_status(idx, BuildStatus::Queued);
+        Some(retries)
+    }
+
+    /// Make a job held by `hold_for_retry` ready again
+    pub fn retry(&self, drv_path: &str) {
+        let mut state = self.state.lock().unwrap();
+        let Some(&idx) = state.drv_to_node.get(drv_path) else {
+            return;
+        };
+        // Jobs of other workflows may have unblocked it already
+        if state.dag[idx].status == BuildStatus::Queued {
+            state.set_status(idx, BuildStatus::Ready);
+            state.ready.push(idx);
+            self.ready_signal.notify_one();
+        }
+    }
+
     /// Remove a workflow's jobs from the queue
     pub fn clear_workflow(&self, workflow_id: i64) {
         let mut state = self.state.lock().unwrap();
         state.clear_workflow(workflow_id);
+        if !state.ready.is_empty() {
+            self.ready_signal.notify_one();
+        }
+    }
+
+    /// Drop a workflow that has not finished yet. Jobs only this workflow needed are
+    /// removed, and those being built are stopped. Returns the number of unfinished jobs
+    /// that were canceled.
+    pub fn cancel_workflow(&self, workflow_id: i64) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.pending_workflows.remove(&workflow_id);
+        let canceled: Vec<BuildJob> = state
+            .clear_workflow(workflow_id)
+            .into_iter()
+            .filter(|job| !job.status.done())
+            .collect();
+        for job in &canceled {
+            state.events.publish(Event::JobStatus {
+                drv_path: job.derivation.drv_path.clone(),
+                name: job.derivation.name.clone(),
+                status: BuildStatus::Canceled,
+                workflows: vec![workflow_id],
+            });
+        }
+        if !state.ready.is_empty() {
+            self.ready_signal.notify_one();
+        }
+        canceled.len()
     }
 
     /// Get all jobs for a workflow (for detailed reporting and dashboard display)
@@ -308,8 +735,304 @@ impl BuildQueue {
             .collect()
     }
 
+    /// The part of the job graph a workflow needs, empty once the workflow is cleared
+    pub fn workflow_graph(&self, workflow_id: i64) -> JobGraph {
+        let state = self.state.lock().unwrap();
+        let nodes: HashMap<&str, NodeIndex> = state
+            .drv_to_node
+            .iter()
+            .filter(|(_, &idx)| {
+                state
+                    .dag
+                    .node_weight(idx)
+                    .is_some_and(|job| job.requested_by.contains(&workflow_id))
+            })
+            .map(|(drv_path, &idx)| (drv_path.as_str(), idx))
+            .collect();
+
+        let mut graph = JobGraph {
+            nodes: Vec::new(),
+            edges: Vec::new(),
+        };
+        for (&drv_path, &idx) in &nodes {
+            let job = &state.dag[idx];
+            graph.nodes.push(JobNode {
+                drv_path: drv_path.to_string(),
+                name: job.derivation.name.clone(),
+                system: job.derivation.system.clone(),
+                status: job.status,
+            });
+            for input in &job.derivation.input_drvs {
+                if let Some(&from) = nodes.get(input.as_str()) {
+                    graph.edges.push(JobEdge {
+                        from: input.clone(),
+                        to: drv_path.to_string(),
+                        // Edges are removed as dependencies finish
+                        blocking: state.dag.find_edge(from, idx).is_some(),
+                    });
+                }
+            }
+        }
+        graph.sort();
+        graph
+    }
+
     pub fn get_jobs(&self) -> Vec<BuildJob> {
         let state = self.state.lock().unwrap();
         state.dag.graph().node_weights().cloned().collect()
     }
+
+    /// Get all unfinished jobs together with the derivations still blocking them
+    pub fn get_pending_jobs(&self) -> Vec<(BuildJob, Vec<String>)> {
+        let state = self.state.lock().unwrap();
+        state
+            .drv_to_node
+            .values()
+            .filter_map(|&idx| {
+                let job = state.dag.node_weight(idx).unwrap();
+                if job.status.done() {
+                    return None;
+                }
+                // Edges are removed as dependencies finish, so remaining parents block the job
+                let blocked_by = state
+                    .dag
+                    .parents(idx)
+                    .iter(&state.dag)
+                    .map(|(_, parent)| {
+                        state
+                            .dag
+                            .node_weight(parent)
+                            .unwrap()
+                            .derivation
+                            .drv_path
+                            .clone()
+                    })
+                    .collect();
+                Some((job.clone(), blocked_by))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn derivation(name: &str, input_drvs: &[&str]) -> Derivation {
+        Derivation {
+            name: name.to_string(),
+            drv_path: format!("/nix/store/{}.drv", name),
+            outputs: vec![format!("/nix/store/{}", name)],
+            system: "x86_64-linux".to_string(),
+            input_drvs: input_drvs
+                .iter()
+                .map(|d| format!("/nix/store/{}.drv", d))
+                .collect(),
+            status: BuildStatus::Queued,
+            licenses: Vec::new(),
+            timeout_secs: None,
+            required_features: Vec::new(),
+            constituents: None,
+            cached: None,
+        }
+    }
+
+    #[test]
+    fn test_pending_jobs_report_blocking_edges() {
+        let queue = BuildQueue::new(EventBus::new());
+        queue.add_workflow(vec![derivation("lib", &[]), derivation("app", &["lib"])], 1);
+
+        let pending = queue.get_pending_jobs();
+        let (app, blocked_by) = pending
+            .iter()
+            .find(|(job, _)| job.derivation.name == "app")
+            .unwrap();
+        assert_eq!(app.status, BuildStatus::Queued);
+        assert_eq!(blocked_by, &vec!["/nix/store/lib.drv".to_string()]);
+
+        queue.update_status("/nix/store/lib.drv", BuildStatus::Success);
+        let pending = queue.get_pending_jobs();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].0.status, BuildStatus::Ready);
+        assert!(pending[0].1.is_empty());
+    }
+
+    #[test]
+    fn test_workflow_graph() {
+        let queue = BuildQueue::new(EventBus::new());
+        queue.add_workflow(vec![derivation("lib", &[]), derivation("app", &["lib"])], 1);
+        queue.add_workflow(vec![derivation("other", &[])], 2);
+
+        let graph = queue.workflow_graph(1);
+        let names: Vec<&str> = graph.nodes.iter().map(|node| node.name.as_str()).collect();
+        assert_eq!(names, vec!["app", "lib"]);
+        assert_eq!(graph.edges.len(), 1);
+        assert_eq!(graph.edges[0].from, "/nix/store/lib.drv");
+        assert_eq!(graph.edges[0].to, "/nix/store/app.drv");
+        assert!(graph.edges[0].blocking);
+
+        queue.update_status("/nix/store/lib.drv", BuildStatus::Success);
+        let graph = queue.workflow_graph(1);
+        assert_eq!(graph.edges.len(), 1);
+        assert!(!graph.edges[0].blocking);
+        assert!(queue.workflow_graph(3).nodes.is_empty());
+    }
+
+    #[test]
+    fn test_stored_graph() {
+        let node = |name: &str| JobNode {
+            drv_path: format!("/nix/store/{}.drv", name),
+            name: name.to_string(),
+            system: "x86_64-linux".to_string(),
+            status: BuildStatus::Success,
+        };
+        let inputs = vec![
+            (
+                "/nix/store/app.drv".to_string(),
+                "/nix/store/lib.drv".to_string(),
+            ),
+            (
+                "/nix/store/app.drv".to_string(),
+                "/nix/store/src.drv".to_string(),
+            ),
+        ];
+        let graph = JobGraph::stored(vec![node("lib"), node("app")], &inputs);
+        let names: Vec<&str> = graph.nodes.iter().map(|node| node.name.as_str()).collect();
+        assert_eq!(names, vec!["app", "lib"]);
+        assert_eq!(graph.edges.len(), 1);
+        assert_eq!(graph.edges[0].from, "/nix/store/lib.drv");
+        assert!(!graph.edges[0].blocking);
+    }
+
+    #[test]
+    fn test_deprioritized_jobs_run_last() {
+        let queue = BuildQueue::new(EventBus::new());
+        queue.deprioritize(1);
+        queue.add_workflow(vec![derivation("over-quota", &[])], 1);
+        queue.add_workflow(vec![derivation("shared", &[])], 1);
+        queue.add_workflow(vec![derivation("within-quota", &[])], 2);
+        queue.add_workflow(vec![derivation("shared", &[])], 2);
+
+        let order: Vec<String> = std::iter::from_fn(|| queue.pop_ready_job())
+            .map(|job| job.derivation.name)
+            .collect();
+        assert_eq!(order, vec!["shared", "within-quota", "over-quota"]);
+    }
+
+    #[test]
+    fn test_restore_workflow() {
+        let queue = BuildQueue::new(EventBus::new());
+        let finished = HashMap::from([
+            ("/nix/store/lib.drv".to_string(), BuildStatus::Success),
+            ("/nix/store/app.drv".to_string(), BuildStatus::Running),
+        ]);
+        let derivations = vec![
+            derivation("lib", &[]),
+            derivation("app", &["lib"]),
+            derivation("docs", &["app"]),
+        ];
+        assert!(queue.restore_workflow(derivations, 1, &finished).is_empty());
+
+        // The running build starts over
+        let job = queue.pop_ready_job().unwrap();
+        assert_eq!(job.derivation.name, "app");
+        assert_eq!(job.status, BuildStatus::Ready);
+        assert!(queue.pop_ready_job().is_none());
+
+        let finished = HashMap::from([
+            ("/nix/store/lib.drv".to_string(), BuildStatus::Success),
+            ("/nix/store/app.drv".to_string(), BuildStatus::Failed),
+        ]);
+        let derivations = vec![
+            derivation("lib", &[]),
+            derivation("app", &["lib"]),
+            derivation("docs", &["app"]),
+        ];
+        let queue = BuildQueue::new(EventBus::new());
+        assert_eq!(queue.restore_workflow(derivations, 1, &finished), vec![1]);
+    }
+
+    #[test]
+    fn test_cancel_workflow_stops_unneeded_jobs() {
+        let queue = BuildQueue::new(EventBus::new());
+        queue.add_workflow(
+            vec![derivation("only-1", &[]), derivation("shared", &[])],
+            1,
+        );
+        queue.add_workflow(vec![derivation("shared", &[])], 2);
+        let running = queue.pop_ready_job().unwrap();
+        assert_eq!(running.derivation.name, "only-1");
+        let canceled = queue.cancellation(&running.derivation.drv_path);
+
+        assert_eq!(queue.cancel_workflow(1), 1);
+        assert!(canceled.is_cancelled());
+        let jobs = queue.get_jobs();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].derivation.name, "shared");
+        assert_eq!(jobs[0].requested_by, HashSet::from([2]));
+    }
+
+    #[test]
+    fn test_higher_priority_jobs_run_first() {
+        let queue = BuildQueue::new(EventBus::new());
+        queue.set_priority(1, 0);
+        queue.set_priority(2, 2);
+        queue.add_workflow(vec![derivation("pr-1", &[]), derivation("pr-2", &[])], 1);
+        queue.add_workflow(vec![derivation("main", &[]), derivation("pr-2", &[])], 2);
+
+        let order: Vec<String> = std::iter::from_fn(|| queue.pop_ready_job())
+            .map(|job| job.derivation.name)
+            .collect();
+        assert_eq!(order, vec!["pr-2", "main", "pr-1"]);
+    }
+
+    #[test]
+    fn test_fair_scheduling_takes_turns() {
+        let queue = BuildQueue::new(EventBus::new());
+        queue.set_repository(1, "a/big");
+        queue.set_repository(2, "b/small");
+        let big: Vec<Derivation> = ["a1", "a2", "a3"]
+            .iter()
+            .map(|name| derivation(name, &[]))
+            .collect();
+        queue.add_workflow(big, 1);
+        queue.add_workflow(vec![derivation("b1", &[]), derivation("b2", &[])], 2);
+
+        let order: Vec<String> = std::iter::from_fn(|| queue.pop_ready_job())
+            .map(|job| job.derivation.name)
+            .collect();
+        assert_eq!(order, vec!["a1", "b1", "a2", "b2", "a3"]);
+    }
+
+    #[test]
+    fn test_declined_jobs_stay_ready() {
+        let queue = BuildQueue::new(EventBus::new());
+        let mut arm = derivation("arm", &[]);
+        arm.system = "aarch64-linux".to_string();
+        queue.add_workflow(vec![arm, derivation("x86", &[])], 1);
+
+        let local = |job: &BuildJob| (job.derivation.system == "x86_64-linux").then_some(());
+        let (job, ()) = queue.pop_ready_job_with(local).unwrap();
+        assert_eq!(job.derivation.name, "x86");
+        assert!(queue.pop_ready_job_with(local).is_none());
+        assert_eq!(queue.pop_ready_job().unwrap().derivation.name, "arm");
+    }
+
+    #[test]
+    fn test_held_jobs_are_retried() {
+        let queue = BuildQueue::new(EventBus::new());
+        queue.add_workflow(vec![derivation("flaky", &[])], 1);
+        let job = queue.pop_ready_job().unwrap();
+        let drv_path = &job.derivation.drv_path;
+        queue.update_status(drv_path, BuildStatus::Running);
+
+        assert_eq!(queue.hold_for_retry(drv_path), Some(1));
+        assert_eq!(queue.get_jobs()[0].status, BuildStatus::Queued);
+        assert!(queue.pop_ready_job().is_none());
+
+        queue.retry(drv_path);
+        let job = queue.pop_ready_job().unwrap();
+        assert_eq!(job.status, BuildStatus::Ready);
+        assert_eq!(job.retries, 1);
+    }
 }
@@ -0,0 +1,346 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    process::Command,
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
+    time::{timeout, Duration, MissedTickBehavior},
+};
+use tracing::{info, warn};
+
+/// Upper bound for connecting to a builder's store
+const PING_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct BuilderConfig {
+    /// Store of the machine, e.g. "ssh-ng://builder@arm-builder"
+    pub store: String,
+    /// Systems it builds, e.g. "aarch64-linux"
+    pub systems: Vec<String>,
+    /// Maximum number of builds to run on it concurrently
+    #[serde(default = "default_max_jobs")]
+    pub max_jobs: usize,
+    /// Private SSH key to connect with; the SSH defaults when unset
+    #[serde(default)]
+    pub ssh_key: Option<PathBuf>,
+    /// System features it supports, e.g. "kvm" or "big-parallel"
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+fn default_max_jobs() -> usize {
+    1
+}
+
+/// Whether a machine with the `supported` features can build a job requiring `required`
+pub fn supports(supported: &[String], required: &[String]) -> bool {
+    required.iter().all(|feature| supported.contains(feature))
+}
+
+/// Whether this machine builds a job of `system` requiring `features`. Systems of
+/// remote builders or workers are only built locally when listed explicitly.
+pub fn builds_locally(
+    local_systems: &[String],
+    local_features: &[String],
+    remote: bool,
+    system: &str,
+    features: &[String],
+) -> bool {
+    let local_system = local_systems.iter().any(|s| s == system);
+    (local_system || (!remote && local_systems.is_empty())) && supports(local_features, features)
+}
+
+impl BuilderConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.store.starts_with("ssh://") && !self.store.starts_with("ssh-ng://") {
+            return Err(anyhow!(
+                "Store of builder {} must be an ssh:// or ssh-ng:// URI",
+                self.store
+            ));
+        }
+        if self.systems.is_empty() {
+            return Err(anyhow!("Builder {} needs at least one system", self.store));
+        }
+        if self.max_jobs == 0 {
+            return Err(anyhow!(
+                "max_jobs of builder {} must be at least 1",
+                self.store
+            ));
+        }
+        Ok(())
+    }
+
+    /// The store URI, with the SSH key if any
+    fn store_uri(&self) -> String {
+        match &self.ssh_key {
+            Some(key) => {
+                let separator = if self.store.contains('?') { '&' } else { '?' };
+                format!("{}{}ssh-key={}", self.store, separator, key.display())
+            }
+            None => self.store.clone(),
+        }
+    }
+
+    /// The builder as a machine of nix's `builders` setting, building one job of `system`
+    fn machine(&self, system: &str) -> String {
+        let ssh_key = self
+            .ssh_key
+            .as_ref()
+            .map_or("-".to_string(), |key| key.display().to_string());
+        let features = if self.features.is_empty() {
+            "-".to_string()
+        } else {
+            self.features.join(",")
+        };
+        format!("{} {} {} 1 1 {}", self.store, system, ssh_key, features)
+    }
+
+    fn builds(&self, system: &str, features: &[String]) -> bool {
+        self.systems.iter().any(|s| s == system) && supports(&self.features, features)
+    }
+}
+
+struct Builder {
+    config: BuilderConfig,
+    semaphore: Arc<Semaphore>,
+    /// Whether the last check reached the builder; builders are assumed up until checked
+    alive: AtomicBool,
+    last_error: Mutex<Option<String>>,
+}
+
+/// A builder's current state, as reported by the API
+#[derive(Debug, Serialize)]
+pub struct BuilderStatus {
+    pub store: String,
+    pub systems: Vec<String>,
+    pub features: Vec<String>,
+    pub max_jobs: usize,
+    pub running: usize,
+    pub alive: bool,
+    /// Why the last check failed
+    pub error: Option<String>,
+}
+
+/// Remote machines building jobs of their systems, each with its own slots
+#[derive(Clone, Default)]
+pub struct Builders {
+    builders: Arc<Vec<Builder>>,
+    /// Notified when a builder comes back up
+    changed: Arc<Notify>,
+}
+
+impl Builders {
+    pub fn new(configs: &[BuilderConfig]) -> Self {
+        let builders = configs
+            .iter()
+            .map(|config| Builder {
+                config: config.clone(),
+                semaphore: Arc::new(Semaphore::new(config.max_jobs)),
+                alive: AtomicBool::new(true),
+                last_error: Mutex::new(None),
+            })
+            .collect();
+        Self {
+            builders: Arc::new(builders),
+            changed: Arc::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.builders.is_empty()
+    }
+
+    /// Whether any builder, up or not, builds `system` with the given features
+    pub fn builds(&self, system: &str, features: &[String]) -> bool {
+        self.builders
+            .iter()
+            .any(|b| b.config.builds(system, features))
+    }
+
+    /// Take a slot on the up builder of `system` with the features and the most free
+    /// slots. Returns the builder as a machine to pass to nix-build as --builders.
+    pub fn take(
+        &self,
+        system: &str,
+        features: &[String],
+    ) -> Option<(String, OwnedSemaphorePermit)> {
+        // Ties go to the builder configured first
+        let builder = self
+            .builders
+            .iter()
+            .rev()
+            .filter(|b| b.alive.load(Ordering::SeqCst))
+            .filter(|b| b.config.builds(system, features))
+            .max_by_key(|b| b.semaphore.available_permits())?;
+        let permit = builder.semaphore.clone().try_acquire_owned().ok()?;
+        Some((builder.config.machine(system), permit))
+    }
+
+    /// Wait until a builder comes back up
+    pub async fn changed(&self) {
+        self.changed.notified().await;
+    }
+
+    /// Number of builds running on the builders
+    pub fn running(&self) -> usize {
+        self.builders.iter().map(Builder::running).sum()
+    }
+
+    /// Wait for the builds running on the builders to finish
+    pub async fn wait_idle(&self) {
+        for builder in self.builders.iter() {
+            let _ = builder
+                .semaphore
+                .acquire_many(builder.config.max_jobs as u32)
+                .await;
+        }
+    }
+
+    pub fn status(&self) -> Vec<BuilderStatus> {
+        self.builders
+            .iter()
+            .map(|b| BuilderStatus {
+                store: b.config.store.clone(),
+                systems: b.config.systems.clone(),
+                features: b.config.features.clone(),
+                max_jobs: b.config.max_jobs,
+                running: b.running(),
+                alive: b.alive.load(Ordering::SeqCst),
+                error: b.last_error.lock().unwrap().clone(),
+            })
+            .collect()
+    }
+
+    /// Check every `interval` that the builders are reachable. Jobs are only sent to
+    /// builders that are.
+    pub async fn run_checks(self, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            for builder in self.builders.iter() {
+                let result = ping(&builder.config).await;
+                let alive = result.is_ok();
+                let was_alive = builder.alive.swap(alive, Ordering::SeqCst);
+                match result {
+                    Ok(()) if !was_alive => {
+                        info!("Builder {} is back up", builder.config.store);
+                        self.changed.notify_one();
+                    }
+                    Err(ref e) if was_alive => {
+                        warn!("Builder {} is down: {:#}", builder.config.store, e)
+                    }
+                    _ => {}
+                }
+                *builder.last_error.lock().unwrap() = result.err().map(|e| format!("{:#}", e));
+            }
+        }
+    }
+}
+
+impl Builder {
+    fn running(&self) -> usize {
+        self.config
+            .max_jobs
+            .saturating_sub(self.semaphore.available_permits())
+    }
+}
+
+/// Connect to a builder's store
+async fn ping(config: &BuilderConfig) -> Result<()> {
+    let output = timeout(
+        PING_TIMEOUT,
+        Command::new("nix")
+            .args(["store", "ping", "--store", &config.store_uri()])
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .map_err(|_| anyhow!("timed out after {}s", PING_TIMEOUT.as_secs()))?
+    .context("Failed to execute nix store ping")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_prefers_free_builders() {
+        let config = |store: &str, max_jobs| BuilderConfig {
+            store: store.to_string(),
+            systems: vec!["aarch64-linux".to_string()],
+            max_jobs,
+            ssh_key: None,
+            features: Vec::new(),
+        };
+        let builders = Builders::new(&[config("ssh-ng://a", 1), config("ssh-ng://b", 2)]);
+        assert!(builders.builds("aarch64-linux", &[]));
+        assert!(builders.take("x86_64-linux", &[]).is_none());
+
+        let (machine, _b1) = builders.take("aarch64-linux", &[]).unwrap();
+        assert_eq!(machine, "ssh-ng://b aarch64-linux - 1 1 -");
+        let (machine, a) = builders.take("aarch64-linux", &[]).unwrap();
+        assert_eq!(machine, "ssh-ng://a aarch64-linux - 1 1 -");
+        let (machine, _b2) = builders.take("aarch64-linux", &[]).unwrap();
+        assert_eq!(machine, "ssh-ng://b aarch64-linux - 1 1 -");
+        assert!(builders.take("aarch64-linux", &[]).is_none());
+        assert_eq!(builders.running(), 3);
+
+        builders.builders[0].alive.store(false, Ordering::SeqCst);
+        drop(a);
+        assert!(builders.take("aarch64-linux", &[]).is_none());
+    }
+
+    #[test]
+    fn test_take_requires_features() {
+        let kvm = vec!["kvm".to_string()];
+        let builders = Builders::new(&[
+            BuilderConfig {
+                store: "ssh-ng://plain".to_string(),
+                systems: vec!["x86_64-linux".to_string()],
+                max_jobs: 1,
+                ssh_key: None,
+                features: Vec::new(),
+            },
+            BuilderConfig {
+                store: "ssh-ng://vm".to_string(),
+                systems: vec!["x86_64-linux".to_string()],
+                max_jobs: 1,
+                ssh_key: None,
+                features: vec!["kvm".to_string(), "big-parallel".to_string()],
+            },
+        ]);
+        assert!(!builders.builds("x86_64-linux", &["uid-range".to_string()]));
+        let (machine, _vm) = builders.take("x86_64-linux", &kvm).unwrap();
+        assert_eq!(machine, "ssh-ng://vm x86_64-linux - 1 1 kvm,big-parallel");
+        assert!(builders.take("x86_64-linux", &kvm).is_none());
+        assert!(builders.take("x86_64-linux", &[]).is_some());
+    }
+
+    #[test]
+    fn test_builds_locally() {
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let kvm = strings(&["kvm"]);
+        // Anything nothing else builds, with the features this machine has
+        assert!(builds_locally(&[], &kvm, false, "x86_64-linux", &kvm));
+        assert!(!builds_locally(&[], &[], false, "x86_64-linux", &kvm));
+        assert!(!builds_locally(&[], &kvm, true, "aarch64-linux", &[]));
+        // Only the listed systems, even if remote builders build them too
+        let local = strings(&["x86_64-linux"]);
+        assert!(builds_locally(&local, &[], true, "x86_64-linux", &[]));
+        assert!(!builds_locally(&local, &[], false, "aarch64-linux", &[]));
+    }
+}
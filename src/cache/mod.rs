@@ -1,14 +1,57 @@
+mod s3;
+
+pub use s3::S3Config;
+
+use crate::build::Derivation;
 use anyhow::{anyhow, Context, Result};
-use std::process::Stdio;
-use tokio::process::Command;
+use s3::S3Cache;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    process::Stdio,
+    sync::Arc,
+};
+use tokio::{process::Command, sync::Semaphore, task::JoinSet};
 use tracing::{info, warn};
 
+/// narinfo requests in flight at once when checking many paths of an HTTP cache
+const NARINFO_CONCURRENCY: usize = 32;
+/// Store paths per `nix path-info` run for other caches, keeping the command line short
+const PATH_INFO_BATCH_SIZE: usize = 500;
+
 #[derive(Debug, Clone)]
 pub struct CacheConfig {
     pub cache_url: String,
     pub attic_cache_name: String,
+    pub upload: UploadBackend,
+    /// Store `nix copy` uploads to; `cache_url` when unset
+    pub upload_store: Option<String>,
+    pub s3: Option<S3Config>,
+    /// Further caches checked for existing builds, never uploaded to
+    pub substituters: Vec<String>,
+    /// Nix secret key signing outputs before they are uploaded
+    pub signing_key: Option<String>,
+    /// Upload the runtime closure of outputs rather than only the outputs
+    pub upload_closure: bool,
+    /// `upload_closure` of caches by name, e.g. organizations' caches
+    pub closure_by_cache: HashMap<String, bool>,
 }
 
+/// How successful builds are uploaded
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UploadBackend {
+    /// `attic push` to an attic cache
+    #[default]
+    Attic,
+    /// `nix copy --to` any store nix can write to: S3, SSH, file:// binary caches...
+    NixCopy,
+    /// Straight to the S3 bucket of `[cache.s3]`, which is also where the cache is checked
+    S3,
+}
+
+#[derive(Clone)]
 pub struct CacheClient {
     config: CacheConfig,
 }
@@ -18,28 +61,137 @@ impl CacheClient {
         Self { config }
     }
 
-    /// Check if a store path exists in the cache using nix path-info
-    pub async fn path_exists(&self, store_path: &str) -> Result<bool> {
-        info!("Checking cache for store path: {}", store_path);
+    /// The bucket to upload to and check, with the S3 backend
+    fn s3(&self, bucket: Option<&str>) -> Result<Option<S3Cache>> {
+        if self.config.upload != UploadBackend::S3 {
+            return Ok(None);
+        }
+        let config = self
+            .config
+            .s3
+            .as_ref()
+            .ok_or_else(|| anyhow!("The s3 upload backend needs a [cache.s3] section"))?;
+        S3Cache::new(config, bucket, self.config.signing_key.as_deref()).map(Some)
+    }
 
+    /// Sign outputs in the local store, so attic and `nix copy` upload them with the
+    /// signature; the S3 backend signs the narinfo files it writes instead. Whether
+    /// the outputs end up signed, which needs a signing key.
+    pub async fn sign_outputs(&self, outputs: &[String]) -> Result<bool> {
+        let Some(key) = &self.config.signing_key else {
+            return Ok(false);
+        };
+        if self.config.upload == UploadBackend::S3 {
+            return Ok(true);
+        }
+        // nix reads the key from a file, which only the owner can read
+        let mut key_file = tempfile::NamedTempFile::new()?;
+        key_file.write_all(key.trim().as_bytes())?;
         let output = Command::new("nix")
-            .args(["path-info", "--store", &self.config.cache_url, store_path])
+            .args(["store", "sign", "--key-file"])
+            .arg(key_file.path())
+            .args(outputs)
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .output()
             .await
-            .context("Failed to execute nix path-info")?;
+            .context("Failed to execute nix store sign")?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow!("nix store sign failed: {}", stderr.trim()));
+        }
+        Ok(true)
+    }
+
+    /// Check that the cache and every substituter is reachable
+    pub async fn ping(&self) -> Result<()> {
+        match self.s3(None)? {
+            Some(s3) => s3.ping().await?,
+            None => ping_url(&self.config.cache_url).await?,
+        }
+        for substituter in &self.config.substituters {
+            ping_url(substituter)
+                .await
+                .with_context(|| format!("Substituter {}", substituter))?;
+        }
+        Ok(())
+    }
+
+    /// Resolve the URL of the NAR holding a store path, from the first cache served
+    /// over HTTP that has it. Substituters that cannot be reached are skipped.
+    pub async fn nar_url(&self, store_path: &str) -> Result<Option<String>> {
+        if let Some(nar) = nar_url_at(&self.config.cache_url, store_path).await? {
+            return Ok(Some(nar));
+        }
+        for substituter in &self.config.substituters {
+            match nar_url_at(substituter, store_path).await {
+                Ok(Some(nar)) => return Ok(Some(nar)),
+                Ok(None) => {}
+                Err(e) => warn!("Failed to check substituter {}: {:#}", substituter, e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Check if a store path exists in the cache or one of the substituters
+    pub async fn path_exists(&self, store_path: &str) -> Result<bool> {
+        info!("Checking cache for store path: {}", store_path);
+        let mut exists = match self.s3(None)? {
+            Some(s3) => s3.has_path(store_path).await?,
+            None => path_exists_at(&self.config.cache_url, store_path).await?,
+        };
+        for substituter in &self.config.substituters {
+            if exists {
+                break;
+            }
+            exists = path_exists_at(substituter, store_path).await?;
+        }
+        info!(
+            "Cache {}: {}",
+            if exists { "HIT" } else { "MISS" },
+            store_path
+        );
+        Ok(exists)
+    }
 
-        match output.status.success() {
-            true => {
-                info!("Cache HIT: {} found in cache", store_path);
-                Ok(true)
+    /// Which of `store_paths` the cache or one of the substituters has: concurrent
+    /// narinfo requests for HTTP caches and S3 buckets, and batches of `nix path-info`
+    /// for the others. Substituters that cannot be reached are skipped.
+    pub async fn cached_paths(&self, store_paths: &[&str]) -> Result<HashSet<String>> {
+        let mut cached = match self.s3(None)? {
+            Some(s3) => probe_narinfos(Some(s3), "", store_paths).await?,
+            None => cached_paths_at(&self.config.cache_url, store_paths).await?,
+        };
+        for substituter in &self.config.substituters {
+            let missing: Vec<&str> = store_paths
+                .iter()
+                .filter(|path| !cached.contains(**path))
+                .copied()
+                .collect();
+            if missing.is_empty() {
+                break;
             }
-            false => {
-                info!("Cache MISS: {} not found in cache", store_path);
-                Ok(false)
+            match cached_paths_at(substituter, &missing).await {
+                Ok(found) => cached.extend(found),
+                Err(e) => warn!("Failed to check substituter {}: {:#}", substituter, e),
             }
         }
+        Ok(cached)
+    }
+
+    /// The derivations whose outputs are all cached, checking them all at once
+    pub async fn cached_derivations(&self, derivations: &[Derivation]) -> Result<HashSet<String>> {
+        let outputs: Vec<&str> = derivations
+            .iter()
+            .flat_map(|d| d.outputs.iter().map(String::as_str))
+            .collect();
+        let cached = self.cached_paths(&outputs).await?;
+        info!("{} of {} outputs are cached", cached.len(), outputs.len());
+        Ok(derivations
+            .iter()
+            .filter(|d| d.outputs.iter().all(|output| cached.contains(output)))
+            .map(|d| d.drv_path.clone())
+            .collect())
     }
 
     /// Check if all outputs of a derivation are cached
@@ -67,18 +219,27 @@ impl CacheClient {
         Ok(true)
     }
 
-    /// Upload all outputs of a derivation to the cache
-    pub async fn upload_derivation_outputs(&self, outputs: &[String]) -> Result<()> {
-        info!("Uploading to cache: {:?}", outputs);
-
-        let output = Command::new("attic")
-            .args(["push", &self.config.attic_cache_name])
+    /// Upload all outputs of a derivation to `cache_name`, or the configured cache.
+    /// With `nix copy`, cache names are store URLs; with S3, bucket names.
+    pub async fn upload_derivation_outputs(
+        &self,
+        outputs: &[String],
+        cache_name: Option<&str>,
+    ) -> Result<()> {
+        let Some(mut command) = self.upload_command(cache_name) else {
+            let s3 = self.s3(cache_name)?.context("No S3 bucket to upload to")?;
+            s3.upload(outputs, self.upload_closure(cache_name)).await?;
+            info!("Successfully uploaded to cache: {:?}", outputs);
+            return Ok(());
+        };
+        let program = command.as_std().get_program().to_string_lossy().to_string();
+        let output = command
             .args(outputs)
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .output()
             .await
-            .context("Failed to execute attic push")?;
+            .with_context(|| format!("Failed to execute {}", program))?;
 
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
@@ -88,4 +249,316 @@ impl CacheClient {
         info!("Successfully uploaded to cache: {:?}", outputs);
         Ok(())
     }
+
+    /// Whether uploads to `cache_name`, or the configured cache, include the runtime
+    /// closure of the outputs
+    fn upload_closure(&self, cache_name: Option<&str>) -> bool {
+        cache_name
+            .and_then(|name| self.config.closure_by_cache.get(name))
+            .copied()
+            .unwrap_or(self.config.upload_closure)
+    }
+
+    /// The command uploading the store paths appended to it; none for S3, which is
+    /// uploaded to directly
+    fn upload_command(&self, cache_name: Option<&str>) -> Option<Command> {
+        Some(match self.config.upload {
+            UploadBackend::Attic => {
+                let closure = self.upload_closure(cache_name);
+                let cache_name = cache_name.unwrap_or(&self.config.attic_cache_name);
+                info!("Uploading to attic cache {}", cache_name);
+                let mut command = Command::new("attic");
+                command.arg("push");
+                if !closure {
+                    command.arg("--no-closure");
+                }
+                command.arg(cache_name);
+                command
+            }
+            // Binary caches need the closure of what they hold, so nix copies it always
+            UploadBackend::NixCopy => {
+                let store = cache_name
+                    .or(self.config.upload_store.as_deref())
+                    .unwrap_or(&self.config.cache_url);
+                info!("Uploading to store {}", store);
+                let mut command = Command::new("nix");
+                command.args(["copy", "--to", store]);
+                command
+            }
+            UploadBackend::S3 => return None,
+        })
+    }
+}
+
+/// Check that a nix secret key can sign
+pub fn validate_signing_key(key: &str) -> Result<()> {
+    s3::parse_signing_key(key).map(|_| ())
+}
+
+fn is_http(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+async fn ping_url(url: &str) -> Result<()> {
+    if is_http(url) {
+        let info_url = format!("{}/nix-cache-info", url.trim_end_matches('/'));
+        reqwest::get(&info_url)
+            .await
+            .context("Failed to reach cache")?
+            .error_for_status()
+            .context("Cache returned an error")?;
+        return Ok(());
+    }
+
+    let output = Command::new("nix")
+        .args(["store", "ping", "--store", url])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .context("Failed to execute nix store ping")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("Cache is not reachable: {}", stderr.trim()));
+    }
+    Ok(())
+}
+
+/// The URL of the NAR holding a store path in the cache at `url`, if served over HTTP
+async fn nar_url_at(url: &str, store_path: &str) -> Result<Option<String>> {
+    let url = url.trim_end_matches('/');
+    if !is_http(url) {
+        return Ok(None);
+    }
+    let hash = store_hash(store_path);
+
+    let response = reqwest::get(format!("{}/{}.narinfo", url, hash))
+        .await
+        .context("Failed to reach cache")?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let narinfo = response
+        .error_for_status()
+        .context("Cache returned an error")?
+        .text()
+        .await?;
+
+    Ok(parse_narinfo_url(&narinfo).map(|nar| {
+        if is_http(nar) {
+            nar.to_string()
+        } else {
+            format!("{}/{}", url, nar)
+        }
+    }))
+}
+
+/// Check if a store path exists in the cache at `url` using nix path-info
+async fn path_exists_at(url: &str, store_path: &str) -> Result<bool> {
+    let output = Command::new("nix")
+        .args(["path-info", "--store", url, store_path])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .context("Failed to execute nix path-info")?;
+    Ok(output.status.success())
+}
+
+async fn cached_paths_at(url: &str, store_paths: &[&str]) -> Result<HashSet<String>> {
+    if is_http(url) {
+        return probe_narinfos(None, url.trim_end_matches('/'), store_paths).await;
+    }
+    let mut cached = HashSet::new();
+    for batch in store_paths.chunks(PATH_INFO_BATCH_SIZE) {
+        cached.extend(query_path_info(url, batch).await?);
+    }
+    Ok(cached)
+}
+
+/// Request the narinfo of every store path concurrently, from the bucket or else the
+/// HTTP cache at `url`
+async fn probe_narinfos(
+    s3: Option<S3Cache>,
+    url: &str,
+    store_paths: &[&str],
+) -> Result<HashSet<String>> {
+    let client = reqwest::Client::new();
+    let permits = Arc::new(Semaphore::new(NARINFO_CONCURRENCY));
+    let mut requests = JoinSet::new();
+    for store_path in store_paths {
+        let request = client.get(format!("{}/{}.narinfo", url, store_hash(store_path)));
+        let permits = permits.clone();
+        let store_path = store_path.to_string();
+        let s3 = s3.clone();
+        requests.spawn(async move {
+            let _permit = permits.acquire_owned().await?;
+            if let Some(s3) = s3 {
+                return Ok(s3.has_path(&store_path).await?.then_some(store_path));
+            }
+            let status = request
+                .send()
+                .await
+                .context("Failed to reach cache")?
+                .status();
+            if status.is_success() {
+                Ok(Some(store_path))
+            } else if status == reqwest::StatusCode::NOT_FOUND {
+                Ok(None)
+            } else {
+                Err(anyhow!("Cache returned {} for {}", status, store_path))
+            }
+        });
+    }
+    let mut cached = HashSet::new();
+    while let Some(result) = requests.join_next().await {
+        if let Some(store_path) = result?? {
+            cached.insert(store_path);
+        }
+    }
+    Ok(cached)
+}
+
+async fn query_path_info(url: &str, store_paths: &[&str]) -> Result<HashSet<String>> {
+    let output = Command::new("nix")
+        .args(["path-info", "--json", "--store", url])
+        .args(store_paths)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .context("Failed to execute nix path-info")?;
+    // Paths the store does not have make nix fail, but still appear in its output
+    if !output.status.success() && output.stdout.is_empty() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("nix path-info failed: {}", stderr.trim()));
+    }
+    parse_valid_paths(&output.stdout)
+}
+
+/// The hash part of a store path, naming its narinfo
+fn store_hash(store_path: &str) -> &str {
+    store_path
+        .trim_start_matches("/nix/store/")
+        .split('-')
+        .next()
+        .unwrap_or_default()
+}
+
+#[derive(Deserialize)]
+struct PathValidity {
+    path: String,
+    #[serde(default = "default_valid")]
+    valid: bool,
+}
+
+fn default_valid() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PathInfoOutput {
+    /// Before nix 2.19: a list, marking the paths the store lacks as not valid
+    List(Vec<PathValidity>),
+    /// Since: by path, with null for those the store lacks
+    Map(HashMap<String, Option<serde_json::Value>>),
+}
+
+/// The paths `nix path-info --json` found in the store
+fn parse_valid_paths(json: &[u8]) -> Result<HashSet<String>> {
+    Ok(
+        match serde_json::from_slice(json).context("Invalid nix path-info output")? {
+            PathInfoOutput::List(paths) => paths
+                .into_iter()
+                .filter(|p| p.valid)
+                .map(|p| p.path)
+                .collect(),
+            PathInfoOutput::Map(paths) => paths
+                .into_iter()
+                .filter_map(|(path, info)| info.map(|_| path))
+                .collect(),
+        },
+    )
+}
+
+/// Extract the `URL` field of a narinfo file
+fn parse_narinfo_url(narinfo: &str) -> Option<&str> {
+    narinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("URL:"))
+        .map(str::trim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upload_command() {
+        let client = |upload, upload_store: Option<&str>| {
+            CacheClient::new(CacheConfig {
+                upload_closure: false,
+                closure_by_cache: HashMap::from([("team".to_string(), true)]),
+                cache_url: "https://cache.example.org".to_string(),
+                attic_cache_name: "icicle".to_string(),
+                upload,
+                upload_store: upload_store.map(str::to_string),
+                s3: None,
+                substituters: Vec::new(),
+                signing_key: None,
+            })
+        };
+        let args = |client: CacheClient, cache_name| {
+            let command = client.upload_command(cache_name).unwrap();
+            let command = command.as_std();
+            std::iter::once(command.get_program())
+                .chain(command.get_args())
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert_eq!(
+            args(client(UploadBackend::Attic, None), None),
+            "attic push --no-closure icicle"
+        );
+        assert_eq!(
+            args(client(UploadBackend::Attic, None), Some("team")),
+            "attic push team"
+        );
+        assert_eq!(
+            args(client(UploadBackend::NixCopy, Some("s3://bucket")), None),
+            "nix copy --to s3://bucket"
+        );
+        assert_eq!(
+            args(client(UploadBackend::NixCopy, None), None),
+            "nix copy --to https://cache.example.org"
+        );
+    }
+
+    #[test]
+    fn test_parse_valid_paths() {
+        let old = br#"[{"path":"/nix/store/a-hello","narHash":"sha256-x"},
+                       {"path":"/nix/store/b-world","valid":false}]"#;
+        assert_eq!(
+            parse_valid_paths(old).unwrap(),
+            HashSet::from(["/nix/store/a-hello".to_string()])
+        );
+        let new = br#"{"/nix/store/a-hello":{"narHash":"sha256-x"},"/nix/store/b-world":null}"#;
+        assert_eq!(
+            parse_valid_paths(new).unwrap(),
+            HashSet::from(["/nix/store/a-hello".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_parse_narinfo_url() {
+        let narinfo = "StorePath: /nix/store/abc123-hello\nURL: nar/0xyz.nar.xz\nCompression: xz\n";
+        assert_eq!(parse_narinfo_url(narinfo), Some("nar/0xyz.nar.xz"));
+        assert_eq!(
+            parse_narinfo_url("StorePath: /nix/store/abc123-hello\n"),
+            None
+        );
+    }
 }
@@ -0,0 +1,247 @@
+use super::ServeArgs;
+use crate::{
+    cache::{CacheClient, CacheConfig},
+    config::{self, Settings},
+    health::{check_binary, CHECK_TIMEOUT},
+    notify::{self, NOTIFIERS},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use std::{fmt::Display, future::Future, net::IpAddr, path::Path, str::FromStr};
+
+/// External programs icicle runs to evaluate, build and upload
+const BINARIES: &[&str] = &[
+    "nix",
+    "nix-eval-jobs",
+    "nix-build",
+    "nix-store",
+    "git",
+    "attic",
+];
+
+#[derive(Default)]
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn check(&mut self, name: impl Display, result: Result<()>) {
+        match result {
+            Ok(()) => println!("ok    {}", name),
+            Err(e) => {
+                self.failures += 1;
+                println!("FAIL  {}: {:#}", name, e);
+            }
+        }
+    }
+
+    async fn check_async(&mut self, name: impl Display, check: impl Future<Output = Result<()>>) {
+        let result = tokio::time::timeout(CHECK_TIMEOUT, check)
+            .await
+            .unwrap_or_else(|_| {
+                Err(anyhow!(
+                    "timed out after {} seconds",
+                    CHECK_TIMEOUT.as_secs()
+                ))
+            });
+        self.check(name, result);
+    }
+}
+
+/// Load the configuration `serve` would use and check it can actually run
+pub async fn check_config(args: ServeArgs) -> Result<()> {
+    let mut report = Report::default();
+
+    let files = Settings::loaded_files(args.config.as_deref())
+        .iter()
+        .map(|file| file.display().to_string())
+        .collect::<Vec<_>>();
+    let mut settings = match Settings::new(args.config.as_deref()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            report.check("configuration", Err(e.into()));
+            bail!("Configuration could not be loaded");
+        }
+    };
+    args.apply(&mut settings);
+    if files.is_empty() {
+        report.check(
+            format!(
+                "configuration loaded from the environment (profile {})",
+                config::profile()
+            ),
+            Ok(()),
+        );
+    } else {
+        report.check(
+            format!(
+                "configuration loaded from {} (profile {})",
+                files.join(", "),
+                config::profile()
+            ),
+            Ok(()),
+        );
+    }
+
+    report.check(
+        format!("server.host {}", settings.server.host),
+        settings
+            .server
+            .host
+            .parse::<IpAddr>()
+            .map(|_| ())
+            .context("Not an IP address"),
+    );
+    if let Some(tls) = &settings.server.tls {
+        let result = tls.rustls_config().await.map(|_| ());
+        report.check("server.tls", result);
+    }
+    report.check(
+        "build.max_concurrent_builds",
+        positive(settings.build.max_concurrent_builds as u64),
+    );
+    report.check(
+        "build.build_timeout_secs",
+        positive(settings.build.build_timeout_secs),
+    );
+    report.check(
+        "build.max_concurrent_uploads",
+        positive(settings.build.max_concurrent_uploads as u64),
+    );
+    report.check("build", settings.build.validate());
+    report.check(
+        "nix.eval_timeout_secs",
+        positive(settings.nix.eval_timeout_secs),
+    );
+    report.check(
+        "nix.eval_workers",
+        positive(settings.nix.eval_workers as u64),
+    );
+    report.check(
+        "nix.eval_max_memory_size_mib",
+        positive(settings.nix.eval_max_memory_size_mib),
+    );
+    report.check("cache", settings.cache.validate());
+    report.check("notify", check_notify(&settings));
+    report.check("proxy", settings.proxy.validate());
+    // So the cache check goes through the proxy like the server would
+    settings.proxy.apply_to_env();
+    if settings.gc.enabled {
+        report.check("gc", settings.gc.validate());
+    }
+    if settings.provenance.enabled {
+        report.check("provenance", settings.provenance.validate());
+    }
+    report.check("bisect", settings.bisect.validate());
+    report.check("quota", settings.quota.validate());
+    report.check("priority", settings.priority.validate());
+    report.check("workers", settings.workers.validate());
+    if !settings.credentials.repositories.is_empty() {
+        report.check("credentials", settings.credentials.validate());
+    }
+    if !settings.schedule.workflows.is_empty() {
+        report.check("schedule", settings.schedule.validate());
+    }
+    if !settings.poll.repositories.is_empty() {
+        report.check("poll", settings.poll.validate());
+    }
+    if settings.github.token.is_some() {
+        report.check("github", settings.github.validate());
+    }
+    if !settings.deploy.targets.is_empty() {
+        report.check("deploy", settings.deploy.validate());
+    }
+
+    report
+        .check_async(
+            format!("database {}", settings.database.path),
+            check_database(&settings.database.path),
+        )
+        .await;
+    let cache_client = CacheClient::new(CacheConfig {
+        cache_url: settings.cache.cache_url.clone(),
+        attic_cache_name: settings.cache.attic_cache_name.clone(),
+        upload: settings.cache.upload,
+        upload_store: settings.cache.upload_store.clone(),
+        s3: settings.cache.s3.clone(),
+        substituters: settings.cache.substituters.clone(),
+        signing_key: settings.cache.signing_key.clone(),
+        upload_closure: settings.cache.upload_closure,
+        closure_by_cache: settings.cache.closure_by_cache.clone(),
+    });
+    report
+        .check_async(
+            format!("cache {}", settings.cache.cache_url),
+            cache_client.ping(),
+        )
+        .await;
+    for binary in BINARIES {
+        report
+            .check_async(format!("binary {}", binary), check_binary(binary))
+            .await;
+    }
+
+    if report.failures > 0 {
+        bail!("{} check(s) failed", report.failures);
+    }
+    println!("Configuration is valid");
+    Ok(())
+}
+
+fn positive(value: u64) -> Result<()> {
+    if value == 0 {
+        bail!("Must be greater than 0");
+    }
+    Ok(())
+}
+
+fn check_notify(settings: &Settings) -> Result<()> {
+    notify::configured_notifiers(&settings.notify)?;
+    if let Some(digest) = &settings.notify.digest {
+        if let Some(unknown) = digest
+            .notifiers
+            .iter()
+            .find(|name| !NOTIFIERS.contains(&name.as_str()))
+        {
+            bail!("Unknown notifier '{}' in notify.digest.notifiers", unknown);
+        }
+    }
+    Ok(())
+}
+
+/// Open an existing database read-only, or check a new one can be created
+async fn check_database(path: &str) -> Result<()> {
+    let options = SqliteConnectOptions::from_str(path).context("Invalid database URL")?;
+    let filename = options.get_filename();
+    if filename == Path::new(":memory:") {
+        return Ok(());
+    }
+
+    if !filename.exists() {
+        let dir = filename
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        tempfile::tempfile_in(dir).with_context(|| {
+            format!(
+                "Database does not exist and cannot be created in {}",
+                dir.display()
+            )
+        })?;
+        return Ok(());
+    }
+
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options.read_only(true))
+        .await
+        .context("Failed to open database")?;
+    // Reads the file header, so files that are not SQLite databases fail
+    let result = sqlx::query("PRAGMA schema_version")
+        .execute(&pool)
+        .await
+        .map(|_| ())
+        .context("Database query failed");
+    pool.close().await;
+    result
+}
@@ -0,0 +1,346 @@
+use crate::config::Settings;
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand};
+use reqwest::{Client, RequestBuilder, Response};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+
+mod check;
+mod worker;
+
+const CONFIG_HELP: &str = "\
+Configuration is read, in increasing order of precedence, from:
+  1. config/default.toml
+  2. config/$ICICLE_ENV.toml, e.g. development or staging (production by default)
+  3. the file given with --config
+  4. ICICLE_<SECTION>__<KEY> environment variables,
+     e.g. ICICLE_SERVER__PORT=8080 or ICICLE_WEBHOOK__SECRET=...
+  5. the server flags above";
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "icicle",
+    version,
+    about = "Nix-based CI builder and dashboard",
+    args_conflicts_with_subcommands = true,
+    after_help = CONFIG_HELP
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub serve: ServeArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the icicle server (default)
+    #[command(after_help = CONFIG_HELP)]
+    Serve(ServeArgs),
+    /// Trigger a workflow for a repository branch or commit
+    Trigger(TriggerArgs),
+    /// Follow a workflow until it finishes; exits non-zero if it did not succeed
+    Watch(WatchArgs),
+    /// Print the build log of a derivation
+    Logs(LogsArgs),
+    /// Cancel a running workflow
+    Cancel(CancelArgs),
+    /// Validate the configuration and check the database, cache and required binaries
+    #[command(after_help = CONFIG_HELP)]
+    CheckConfig(ServeArgs),
+    /// Build jobs leased from a server, as a separate machine of a build farm
+    Worker(worker::WorkerArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ServeArgs {
+    /// Configuration file, loaded on top of the files in config/
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Port to listen on
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// SQLite database, e.g. "sqlite:/var/lib/icicle/icicle.db"
+    #[arg(long, value_name = "URL")]
+    pub db: Option<String>,
+    /// Log filter, e.g. "debug" or "icicle=debug,info"
+    #[arg(long, value_name = "FILTER")]
+    pub log_level: Option<String>,
+}
+
+impl ServeArgs {
+    /// Command line flags take precedence over files and environment
+    pub fn apply(&self, settings: &mut Settings) {
+        if let Some(port) = self.port {
+            settings.server.port = port;
+        }
+        if let Some(db) = &self.db {
+            settings.database.path = db.clone();
+        }
+        if let Some(level) = &self.log_level {
+            settings.log.level = level.clone();
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ServerArgs {
+    /// Base URL of the icicle server
+    #[arg(long, env = "ICICLE_URL", default_value = "http://localhost:3000")]
+    url: String,
+    /// API token sent with every request: needed to trigger or cancel workflows,
+    /// to read private instances and to see repositories of your organizations
+    #[arg(long, env = "ICICLE_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct TriggerArgs {
+    #[command(flatten)]
+    server: ServerArgs,
+    /// Repository full name, e.g. "owner/repo"
+    repository: String,
+    /// Branch to build
+    #[arg(short, long, default_value = "main")]
+    branch: String,
+    /// Full commit SHA to build; the server resolves the branch head if omitted
+    #[arg(short, long)]
+    commit: Option<String>,
+    /// Clone URL, needed until the repository is registered, e.g. by a webhook
+    #[arg(long)]
+    clone_url: Option<String>,
+    /// Watch the workflow after triggering it
+    #[arg(short, long)]
+    watch: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct WatchArgs {
+    #[command(flatten)]
+    server: ServerArgs,
+    /// Workflow ID
+    workflow_id: i64,
+}
+
+#[derive(Debug, Args)]
+pub struct LogsArgs {
+    #[command(flatten)]
+    server: ServerArgs,
+    /// Derivation path or its store basename
+    drv: String,
+}
+
+#[derive(Debug, Args)]
+pub struct CancelArgs {
+    #[command(flatten)]
+    server: ServerArgs,
+    /// Workflow ID; omit to cancel all workflows matching the filters instead
+    #[arg(required_unless_present_any = ["repository", "branch", "older_than"])]
+    workflow_id: Option<i64>,
+    /// Cancel unfinished workflows of this repository
+    #[arg(long, conflicts_with = "workflow_id")]
+    repository: Option<String>,
+    /// Cancel unfinished workflows of this branch
+    #[arg(long, conflicts_with = "workflow_id")]
+    branch: Option<String>,
+    /// Cancel unfinished workflows created at least this many seconds ago
+    #[arg(long, conflicts_with = "workflow_id")]
+    older_than: Option<i64>,
+}
+
+/// Run a subcommand other than `serve`
+pub async fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Serve(_) => unreachable!("serve is handled by main"),
+        Command::Trigger(args) => trigger(args).await,
+        Command::Watch(args) => watch(&args.server, args.workflow_id).await,
+        Command::Logs(args) => logs(args).await,
+        Command::Cancel(args) => cancel(args).await,
+        Command::CheckConfig(args) => check::check_config(args).await,
+        Command::Worker(args) => worker::run(args).await,
+    }
+}
+
+impl ServerArgs {
+    fn request(&self, builder: RequestBuilder) -> RequestBuilder {
+        match &self.token {
+            Some(token) => builder.bearer_auth(token),
+            None => builder,
+        }
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}{}", self.url.trim_end_matches('/'), path)
+    }
+}
+
+async fn check_response(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    // API errors carry a JSON body with a human readable message
+    let message = serde_json::from_str::<Value>(&body)
+        .ok()
+        .and_then(|error| error["message"].as_str().map(str::to_string))
+        .unwrap_or(body);
+    Err(anyhow!("Server returned {}: {}", status, message.trim()))
+}
+
+async fn trigger(args: TriggerArgs) -> Result<()> {
+    let request = Client::new()
+        .post(args.server.endpoint("/api/workflows"))
+        .json(&json!({
+            "repository": args.repository,
+            "clone_url": args.clone_url,
+            "ref": args.commit.as_deref().unwrap_or(&args.branch),
+            "branch": args.branch,
+        }));
+    let workflow: Value = check_response(args.server.request(request).send().await?)
+        .await?
+        .json()
+        .await?;
+    let workflow_id = workflow["id"]
+        .as_i64()
+        .ok_or_else(|| anyhow!("Server did not create a workflow: {}", workflow))?;
+    println!(
+        "Triggered workflow {} for {} at {}",
+        workflow_id,
+        args.repository,
+        workflow["commit_sha"].as_str().unwrap_or("?")
+    );
+
+    if args.watch {
+        watch(&args.server, workflow_id).await?;
+    }
+    Ok(())
+}
+
+fn is_terminal(status: &str) -> bool {
+    matches!(status, "Completed" | "Failed" | "Canceled")
+}
+
+fn finish(workflow_id: i64, status: &str) -> Result<()> {
+    println!("Workflow {} finished: {}", workflow_id, status);
+    if status == "Completed" {
+        Ok(())
+    } else {
+        Err(anyhow!("Workflow {} did not succeed", workflow_id))
+    }
+}
+
+async fn watch(server: &ServerArgs, workflow_id: i64) -> Result<()> {
+    let client = Client::new();
+
+    // Subscribe before checking the current status so no transition is missed
+    let mut stream = check_response(
+        server
+            .request(client.get(server.endpoint(&format!("/api/events?workflow={}", workflow_id))))
+            .send()
+            .await?,
+    )
+    .await?;
+
+    let workflow: Value = check_response(
+        server
+            .request(client.get(server.endpoint(&format!("/api/workflows/{}", workflow_id))))
+            .send()
+            .await?,
+    )
+    .await?
+    .json()
+    .await?;
+    let status = workflow["status"].as_str().unwrap_or("unknown");
+    if is_terminal(status) {
+        return finish(workflow_id, status);
+    }
+    println!(
+        "Watching workflow {} ({} at {}): {}",
+        workflow_id,
+        workflow["repository"].as_str().unwrap_or("?"),
+        workflow["commit_sha"].as_str().unwrap_or("?"),
+        status
+    );
+
+    let mut buffer = String::new();
+    while let Some(chunk) = stream.chunk().await? {
+        buffer.push_str(&String::from_utf8_lossy(&chunk));
+        for event in take_sse_events(&mut buffer) {
+            match event["type"].as_str() {
+                Some("job_status") => {
+                    println!(
+                        "  {:>9}  {}",
+                        event["status"].as_str().unwrap_or("?"),
+                        event["name"].as_str().unwrap_or("?")
+                    );
+                }
+                Some("workflow_status") => {
+                    let status = event["status"].as_str().unwrap_or("unknown");
+                    if is_terminal(status) {
+                        return finish(workflow_id, status);
+                    }
+                    println!("Workflow {}: {}", workflow_id, status);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Err(anyhow!("Event stream closed before workflow finished"))
+}
+
+/// Remove complete server-sent events from the buffer and parse their JSON data
+fn take_sse_events(buffer: &mut String) -> Vec<Value> {
+    let mut events = Vec::new();
+    while let Some(end) = buffer.find("\n\n") {
+        let frame: String = buffer.drain(..end + 2).collect();
+        let data: Vec<&str> = frame
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(str::trim_start)
+            .collect();
+        if let Ok(event) = serde_json::from_str(&data.join("\n")) {
+            events.push(event);
+        }
+    }
+    events
+}
+
+async fn logs(args: LogsArgs) -> Result<()> {
+    let drv = args.drv.trim_start_matches("/nix/store/");
+    let request = Client::new().get(args.server.endpoint(&format!("/api/builds/{}/log", drv)));
+    let log = check_response(args.server.request(request).send().await?)
+        .await?
+        .text()
+        .await?;
+    print!("{}", log);
+    Ok(())
+}
+
+async fn cancel(args: CancelArgs) -> Result<()> {
+    let client = Client::new();
+    if let Some(workflow_id) = args.workflow_id {
+        let request = client.post(
+            args.server
+                .endpoint(&format!("/api/workflows/{}/cancel", workflow_id)),
+        );
+        check_response(args.server.request(request).send().await?).await?;
+        println!("Workflow {} canceled", workflow_id);
+        return Ok(());
+    }
+
+    let request = client
+        .post(args.server.endpoint("/api/workflows/cancel"))
+        .json(&json!({
+            "repository": args.repository,
+            "branch": args.branch,
+            "older_than_secs": args.older_than,
+        }));
+    let result: Value = check_response(args.server.request(request).send().await?)
+        .await?
+        .json()
+        .await?;
+    println!("Canceled workflows: {}", result["canceled"]);
+    Ok(())
+}
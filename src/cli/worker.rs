@@ -0,0 +1,256 @@
+use super::ServerArgs;
+use crate::logging::{self, LogFormat};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Args;
+use reqwest::{Client, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::{process::Command, sync::Mutex as AsyncMutex, time::Duration};
+use tracing::{error, info, warn};
+
+/// Pause after the server could not be reached
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Args)]
+pub struct WorkerArgs {
+    /// Server to build for; the token is the server's workers.token
+    #[command(flatten)]
+    server: ServerArgs,
+    /// Name shown in /api/workers, by default <hostname>-<pid>
+    #[arg(long)]
+    name: Option<String>,
+    /// System this machine builds, e.g. "x86_64-linux"; repeat for several
+    #[arg(long = "system", value_name = "SYSTEM", required = true)]
+    systems: Vec<String>,
+    /// System feature this machine supports, e.g. "kvm"; repeat for several
+    #[arg(long = "feature", value_name = "FEATURE")]
+    features: Vec<String>,
+    /// Number of jobs to build concurrently
+    #[arg(long, default_value_t = 1)]
+    max_jobs: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Registration {
+    id: u64,
+    lease_secs: u64,
+    store: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LeasedJob {
+    lease_id: u64,
+    drv_path: String,
+}
+
+struct Worker {
+    args: WorkerArgs,
+    name: String,
+    client: Client,
+    registration: AsyncMutex<Registration>,
+}
+
+/// Build jobs leased from the server until the process is stopped
+pub async fn run(args: WorkerArgs) -> Result<()> {
+    logging::init(LogFormat::Text, "info", None).context("Failed to set up logging")?;
+    if args.max_jobs == 0 {
+        bail!("--max-jobs must be at least 1");
+    }
+    let name = args
+        .name
+        .clone()
+        .unwrap_or_else(crate::lease::default_instance_id);
+    let client = Client::new();
+    let registration = register(&client, &args, &name).await?;
+    let worker = Arc::new(Worker {
+        args,
+        name,
+        client,
+        registration: AsyncMutex::new(registration),
+    });
+
+    let slots: Vec<_> = (0..worker.args.max_jobs)
+        .map(|_| tokio::spawn(worker.clone().run_slot()))
+        .collect();
+    for slot in slots {
+        slot.await?;
+    }
+    Ok(())
+}
+
+async fn register(client: &Client, args: &WorkerArgs, name: &str) -> Result<Registration> {
+    let request = client
+        .post(args.server.endpoint("/api/workers"))
+        .json(&json!({
+            "name": name,
+            "systems": args.systems,
+            "features": args.features,
+            "max_jobs": args.max_jobs,
+        }));
+    let registration: Registration =
+        super::check_response(args.server.request(request).send().await?)
+            .await?
+            .json()
+            .await?;
+    info!(
+        "Registered as worker {} of {} for {}",
+        registration.id,
+        args.server.url,
+        args.systems.join(", ")
+    );
+    Ok(registration)
+}
+
+/// How the server answered a request about a lease
+enum Answer {
+    Ok(Response),
+    /// The server forgot the worker, e.g. after a restart
+    UnknownWorker,
+    /// The job went to another worker or is not needed anymore
+    LeaseGone,
+}
+
+impl Worker {
+    async fn post(&self, path: &str, body: serde_json::Value) -> Result<Answer> {
+        let request = self
+            .client
+            .post(self.args.server.endpoint(path))
+            .json(&body);
+        let response = self.args.server.request(request).send().await?;
+        Ok(match response.status() {
+            StatusCode::NOT_FOUND => Answer::UnknownWorker,
+            StatusCode::GONE => Answer::LeaseGone,
+            _ => Answer::Ok(super::check_response(response).await?),
+        })
+    }
+
+    async fn registration(&self) -> Registration {
+        self.registration.lock().await.clone()
+    }
+
+    /// Register again, unless another slot already did since `id` was current
+    async fn reregister(&self, id: u64) -> Result<()> {
+        let mut registration = self.registration.lock().await;
+        if registration.id == id {
+            warn!("Server forgot worker {}, registering again", id);
+            *registration = register(&self.client, &self.args, &self.name).await?;
+        }
+        Ok(())
+    }
+
+    async fn run_slot(self: Arc<Self>) {
+        loop {
+            if let Err(e) = self.lease_and_build().await {
+                warn!("{:#}, retrying in {}s", e, RETRY_DELAY.as_secs());
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+
+    async fn lease_and_build(&self) -> Result<()> {
+        let registration = self.registration().await;
+        let path = format!("/api/workers/{}/lease", registration.id);
+        let response = match self.post(&path, json!({})).await? {
+            Answer::Ok(response) => response,
+            Answer::UnknownWorker | Answer::LeaseGone => {
+                return self.reregister(registration.id).await
+            }
+        };
+        if response.status() == StatusCode::NO_CONTENT {
+            return Ok(());
+        }
+        let job: LeasedJob = response.json().await?;
+        info!("Building {}", job.drv_path);
+
+        let phase = Mutex::new("fetching");
+        let set_phase = |p| *phase.lock().unwrap_or_else(PoisonError::into_inner) = p;
+        let lease_path = format!("/api/workers/{}/leases/{}", registration.id, job.lease_id);
+        // Keeps the lease while building; stops the build once the server drops it
+        let heartbeat = async {
+            let interval = Duration::from_secs(registration.lease_secs) / 3;
+            loop {
+                tokio::time::sleep(interval).await;
+                let current = *phase.lock().unwrap_or_else(PoisonError::into_inner);
+                match self
+                    .post(
+                        &format!("{}/status", lease_path),
+                        json!({ "phase": current }),
+                    )
+                    .await
+                {
+                    Ok(Answer::Ok(_)) => {}
+                    Ok(Answer::UnknownWorker | Answer::LeaseGone) => return,
+                    // The lease survives a few missed heartbeats
+                    Err(e) => warn!("Failed to report on {}: {:#}", job.drv_path, e),
+                }
+            }
+        };
+        let result = tokio::select! {
+            result = build(&job.drv_path, registration.store.as_deref(), set_phase) => result,
+            _ = heartbeat => {
+                warn!("Server dropped the lease of {}, stopping its build", job.drv_path);
+                return Ok(());
+            }
+        };
+        match &result {
+            Ok(()) => info!("Built {}", job.drv_path),
+            Err(e) => error!("Failed to build {}: {:#}", job.drv_path, e),
+        }
+        let error = result.err().map(|e| format!("{:#}", e));
+        match self
+            .post(&format!("{}/result", lease_path), json!({ "error": error }))
+            .await?
+        {
+            Answer::Ok(_) => Ok(()),
+            Answer::UnknownWorker | Answer::LeaseGone => {
+                warn!("Server dropped the lease of {} meanwhile", job.drv_path);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Build a derivation, fetching it from `store` and copying its outputs back if the
+/// server's store is not shared
+async fn build(
+    drv_path: &str,
+    store: Option<&str>,
+    set_phase: impl Fn(&'static str),
+) -> Result<()> {
+    if let Some(store) = store {
+        run_command(Command::new("nix").args(["copy", "--from", store, drv_path])).await?;
+    }
+    set_phase("building");
+    run_command(Command::new("nix-build").args([drv_path, "--no-out-link"])).await?;
+    if let Some(store) = store {
+        set_phase("uploading");
+        let outputs =
+            run_command(Command::new("nix-store").args(["--query", "--outputs", drv_path])).await?;
+        run_command(
+            Command::new("nix")
+                .args(["copy", "--to", store])
+                .args(outputs.split_whitespace()),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Run a command to completion, returning its output
+async fn run_command(command: &mut Command) -> Result<String> {
+    let program = command.as_std().get_program().to_string_lossy().to_string();
+    let output = command
+        .kill_on_drop(true)
+        .output()
+        .await
+        .with_context(|| format!("Failed to execute {}", program))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
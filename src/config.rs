@@ -1,6 +1,33 @@
-use config::{Config, ConfigError, Environment, File};
+use crate::{
+    artifacts::ArtifactConfig,
+    bisect::BisectConfig,
+    build::Scheduling,
+    builders::BuilderConfig,
+    cache::{S3Config, UploadBackend},
+    credentials::CredentialsConfig,
+    deploy::DeployConfig,
+    gc::GcConfig,
+    github::GitHubConfig,
+    hooks::HookConfig,
+    logging::{LogFileConfig, LogFormat},
+    login::LoginConfig,
+    nix::{EvalLimits, FetchMode, IfdConfig},
+    notify::NotifyConfig,
+    poll::PollConfig,
+    priority::PriorityConfig,
+    provenance::ProvenanceConfig,
+    quota::QuotaConfig,
+    schedule::ScheduleConfig,
+    workers::WorkerConfig,
+};
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use config::{Config, ConfigError, Environment, File, Map, Source, Value, ValueKind};
 use serde::Deserialize;
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
@@ -10,56 +37,467 @@ pub struct Settings {
     pub nix: NixConfig,
     pub build: BuildConfig,
     pub database: DatabaseConfig,
+    #[serde(default)]
+    pub api: ApiConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub notify: NotifyConfig,
+    #[serde(default)]
+    pub ha: HaConfig,
+    #[serde(default)]
+    pub gc: GcConfig,
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+    #[serde(default)]
+    pub artifacts: ArtifactConfig,
+    #[serde(default)]
+    pub deploy: DeployConfig,
+    #[serde(default)]
+    pub provenance: ProvenanceConfig,
+    #[serde(default)]
+    pub bisect: BisectConfig,
+    #[serde(default)]
+    pub quota: QuotaConfig,
+    #[serde(default)]
+    pub priority: PriorityConfig,
+    #[serde(default)]
+    pub github: GitHubConfig,
+    #[serde(default)]
+    pub credentials: CredentialsConfig,
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+    #[serde(default)]
+    pub poll: PollConfig,
+    #[serde(default)]
+    pub workers: WorkerConfig,
+    #[serde(default)]
+    pub login: LoginConfig,
+    #[serde(default)]
+    pub hooks: HookConfig,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
+    /// Serve HTTPS with this certificate instead of plain HTTP
+    pub tls: Option<TlsConfig>,
+    /// How long to wait on SIGTERM/SIGINT for in-flight requests, then for running builds
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+fn default_shutdown_timeout_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf certificate first
+    pub cert: PathBuf,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1)
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct WebhookConfig {
     pub secret: Option<String>,
+    /// Repositories allowed to trigger workflows, as "owner/repo" or "owner/*";
+    /// all repositories when empty
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Repositories never allowed to trigger workflows, in the same format
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Branches whose pushes start workflows (`*` matches anything); all when empty
+    #[serde(default)]
+    pub branches: Vec<String>,
+    /// Branches of specific repositories, by full name, instead of `branches`
+    #[serde(default)]
+    pub repository_branches: HashMap<String, Vec<String>>,
+    /// Days to keep deliveries for replaying; forever when 0
+    #[serde(default = "default_event_retention_days")]
+    pub event_retention_days: u64,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+fn default_event_retention_days() -> u64 {
+    30
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct CacheConfig {
     pub cache_url: String,
     pub attic_cache_name: String,
+    /// Whether successful builds are pushed with attic or `nix copy`
+    #[serde(default)]
+    pub upload: UploadBackend,
+    /// Store `nix copy` uploads to; `cache_url` when unset
+    #[serde(default)]
+    pub upload_store: Option<String>,
+    /// Bucket of the s3 backend
+    #[serde(default)]
+    pub s3: Option<S3Config>,
+    /// Further caches a derivation counts as cached in, e.g. cache.nixos.org next to
+    /// an own cache; builds are never uploaded to them
+    #[serde(default)]
+    pub substituters: Vec<String>,
+    /// Nix secret key (`name:base64`) signing outputs before they are uploaded
+    #[serde(default)]
+    pub signing_key: Option<String>,
+    /// Upload the runtime closure of outputs, so consumers can substitute all of it
+    #[serde(default)]
+    pub upload_closure: bool,
+    /// `upload_closure` of caches by name, e.g. organizations' caches
+    #[serde(default)]
+    pub closure_by_cache: HashMap<String, bool>,
+    /// Upload every path nix builds, intermediate ones too, through a post-build-hook
+    #[serde(default)]
+    pub post_build_hook: bool,
+    /// Where the hook script and the paths it queues are written
+    #[serde(default = "default_hook_dir")]
+    pub hook_dir: PathBuf,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+fn default_hook_dir() -> PathBuf {
+    PathBuf::from("post-build-hook")
+}
+
+impl CacheConfig {
+    /// Check the signing key, and that the s3 backend has a bucket with a usable endpoint
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(key) = &self.signing_key {
+            crate::cache::validate_signing_key(key)?;
+        }
+        match (&self.upload, &self.s3) {
+            (UploadBackend::S3, None) => Err(anyhow::anyhow!(
+                "upload = \"s3\" needs a [cache.s3] section"
+            )),
+            (_, Some(s3)) => s3.validate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct NixConfig {
     /// Timeout for nix-eval-jobs in seconds
     pub eval_timeout_secs: u64,
-    /// Default attribute set to evaluate (e.g., "packages.x86_64-linux")
+    /// Default attribute set to evaluate (e.g., "packages.x86_64-linux"), or several
+    /// separated by commas
     pub default_attr_set: String,
+    /// Attribute set evaluated by workflows triggered by tags; `default_attr_set`
+    /// when unset
+    #[serde(default)]
+    pub release_attr_set: Option<String>,
+    #[serde(default)]
+    pub import_from_derivation: IfdConfig,
+    /// Whether evaluations clone repositories or let nix fetch their flakes
+    #[serde(default)]
+    pub fetch: FetchMode,
+    /// nix-eval-jobs worker processes evaluating in parallel
+    #[serde(default = "default_eval_workers")]
+    pub eval_workers: usize,
+    /// MiB each nix-eval-jobs worker may use before it is replaced by a fresh one
+    #[serde(default = "default_eval_max_memory_size_mib")]
+    pub eval_max_memory_size_mib: u64,
+    /// MiB of resident memory an evaluation may use in all before it fails
+    #[serde(default)]
+    pub eval_memory_limit_mib: Option<u64>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+fn default_eval_workers() -> usize {
+    1
+}
+
+fn default_eval_max_memory_size_mib() -> u64 {
+    4096
+}
+
+impl NixConfig {
+    pub fn eval_limits(&self) -> EvalLimits {
+        EvalLimits {
+            workers: self.eval_workers,
+            max_memory_size_mib: self.eval_max_memory_size_mib,
+            memory_limit_mib: self.eval_memory_limit_mib,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct BuildConfig {
     /// Maximum number of builds to run concurrently
     pub max_concurrent_builds: usize,
-    /// Timeout for individual builds in seconds
+    /// Timeout for individual builds in seconds, unless their `meta.timeout` sets one
     pub build_timeout_secs: u64,
+    /// Upper bound for the timeouts derivations set in `meta.timeout`
+    #[serde(default)]
+    pub max_build_timeout_secs: Option<u64>,
+    /// How ready jobs of the same priority are ordered
+    #[serde(default)]
+    pub scheduling: Scheduling,
+    /// Systems built locally; any system without a remote builder when empty
+    #[serde(default)]
+    pub local_systems: Vec<String>,
+    /// System features of this machine; nix's defaults when unset
+    #[serde(default)]
+    pub local_features: Option<Vec<String>>,
+    /// Remote machines building jobs of their systems
+    #[serde(default)]
+    pub builders: Vec<BuilderConfig>,
+    /// Time between two checks that the builders are reachable
+    #[serde(default = "default_builder_check_secs")]
+    pub builder_check_secs: u64,
+    /// Times a build failing for a transient reason, e.g. a download, is retried
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Wait before the first retry, doubling for each further one
+    #[serde(default = "default_retry_backoff_secs")]
+    pub retry_backoff_secs: u64,
+    /// Uploads of successful builds to the cache running at once, apart from builds
+    #[serde(default = "default_max_concurrent_uploads")]
+    pub max_concurrent_uploads: usize,
+    /// Times a failed upload is attempted again, waiting like build retries
+    #[serde(default = "default_upload_retries")]
+    pub upload_retries: u32,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+fn default_builder_check_secs() -> u64 {
+    60
+}
+
+fn default_max_retries() -> u32 {
+    2
+}
+
+fn default_retry_backoff_secs() -> u64 {
+    30
+}
+
+fn default_max_concurrent_uploads() -> usize {
+    2
+}
+
+fn default_upload_retries() -> u32 {
+    3
+}
+
+impl BuildConfig {
+    /// The configured local system features, or those nix supports by default:
+    /// kvm only where /dev/kvm exists
+    pub fn local_features(&self) -> Vec<String> {
+        if let Some(features) = &self.local_features {
+            return features.clone();
+        }
+        let mut features: Vec<String> = ["benchmark", "big-parallel", "nixos-test"]
+            .map(String::from)
+            .into();
+        if std::path::Path::new("/dev/kvm").exists() {
+            features.push("kvm".to_string());
+        }
+        features
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.max_build_timeout_secs == Some(0) {
+            return Err(anyhow::anyhow!("max_build_timeout_secs must be at least 1"));
+        }
+        if self.builder_check_secs == 0 {
+            return Err(anyhow::anyhow!("builder_check_secs must be at least 1"));
+        }
+        if self.retry_backoff_secs == 0 {
+            return Err(anyhow::anyhow!("retry_backoff_secs must be at least 1"));
+        }
+        for builder in &self.builders {
+            builder.validate()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct DatabaseConfig {
     /// SQLite database file path
     pub path: String,
 }
 
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ApiConfig {
+    /// Bearer token acting with the admin role; admin endpoints are disabled when unset
+    pub admin_token: Option<String>,
+    /// Let anonymous visitors view the dashboard, workflows, builds and logs
+    pub public: bool,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            admin_token: None,
+            public: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct HaConfig {
+    /// Hold a leader lease in the database; instances without it wait as standby
+    pub enabled: bool,
+    /// Identifies this instance in the lease; defaults to "<hostname>-<pid>"
+    pub instance_id: Option<String>,
+    /// Seconds a lease stays valid without renewal
+    pub lease_secs: u64,
+}
+
+impl Default for HaConfig {
+    fn default() -> Self {
+        HaConfig {
+            enabled: false,
+            instance_id: None,
+            lease_secs: 30,
+        }
+    }
+}
+
+/// Outbound proxy for git, nix, attic and every HTTP request icicle makes
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ProxyConfig {
+    /// Proxy URL for plain HTTP requests, e.g. "http://proxy.corp:3128"
+    pub http: Option<String>,
+    /// Proxy URL for HTTPS requests
+    pub https: Option<String>,
+    /// Comma-separated hosts and domains reached directly, e.g. "localhost,.corp"
+    pub no_proxy: Option<String>,
+}
+
+impl ProxyConfig {
+    fn variables(&self) -> [(&'static str, &Option<String>); 3] {
+        [
+            ("http_proxy", &self.http),
+            ("https_proxy", &self.https),
+            ("no_proxy", &self.no_proxy),
+        ]
+    }
+
+    /// Export the settings as the standard proxy environment variables, which
+    /// reqwest and the git, nix and attic child processes all honor.
+    /// Must run before any other thread reads the environment.
+    pub fn apply_to_env(&self) {
+        for (name, value) in self.variables() {
+            if let Some(value) = value {
+                // curl only reads lowercase http_proxy, other tools prefer uppercase
+                std::env::set_var(name, value);
+                std::env::set_var(name.to_uppercase(), value);
+            }
+        }
+    }
+
+    /// Check that the proxy URLs parse
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for url in [&self.http, &self.https].into_iter().flatten() {
+            reqwest::Proxy::all(url).with_context(|| format!("Invalid proxy URL '{}'", url))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Output format: "text" or "json"
+    pub format: LogFormat,
+    /// Default log filter (e.g. "info" or "icicle=debug,info")
+    pub level: String,
+    /// Also log to a rotated file
+    pub file: Option<LogFileConfig>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            format: LogFormat::Text,
+            level: "info".to_string(),
+            file: None,
+        }
+    }
+}
+
+/// Environment variable selecting the config/<profile>.toml overlay
+const PROFILE_VAR: &str = "ICICLE_ENV";
+const DEFAULT_PROFILE: &str = "production";
+
+/// The configuration profile, e.g. "development", "staging" or "production"
+pub fn profile() -> String {
+    std::env::var(PROFILE_VAR)
+        .ok()
+        .filter(|profile| !profile.is_empty())
+        .unwrap_or_else(|| DEFAULT_PROFILE.to_string())
+}
+
+/// Suffix of settings whose value is read from the named file, e.g. `secret_file`
+const FILE_SUFFIX: &str = "_file";
+
+/// Replace every `<key>_file` setting with `<key>` set to the file's contents,
+/// so secrets can come from systemd credentials or agenix instead of the environment.
+/// The file takes precedence over a `<key>` set directly.
+fn resolve_files(table: &mut Map<String, Value>) -> Result<(), ConfigError> {
+    for value in table.values_mut() {
+        if let ValueKind::Table(inner) = &mut value.kind {
+            resolve_files(inner)?;
+        }
+    }
+
+    let file_keys: Vec<String> = table
+        .keys()
+        .filter(|key| key.ends_with(FILE_SUFFIX))
+        .cloned()
+        .collect();
+    for file_key in file_keys {
+        let path = table
+            .remove(&file_key)
+            .map(Value::into_string)
+            .transpose()?
+            .unwrap_or_default();
+        let contents = std::fs::read_to_string(&path).map_err(|e| {
+            ConfigError::Message(format!("Failed to read {} '{}': {}", file_key, path, e))
+        })?;
+        let key = file_key.trim_end_matches(FILE_SUFFIX).to_string();
+        // Files written by editors and `echo` end with a newline that is not part of the secret
+        let secret = contents.trim_end_matches(['\n', '\r']).to_string();
+        table.insert(key, Value::new(None, secret));
+    }
+    Ok(())
+}
+
+impl TlsConfig {
+    /// Load the certificate and key
+    pub async fn rustls_config(&self) -> anyhow::Result<RustlsConfig> {
+        // Several dependencies enable rustls; pick its provider explicitly
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        RustlsConfig::from_pem_file(&self.cert, &self.key)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to load TLS certificate {} and key {}",
+                    self.cert.display(),
+                    self.key.display()
+                )
+            })
+    }
+}
+
 impl Settings {
-    pub fn new() -> Result<Self, ConfigError> {
-        let config_dir = "config";
-
-        let builder = Config::builder()
-            // Start with default config file
-            .add_source(File::from(Path::new(config_dir).join("default.toml")).required(false))
-            // Override with environment-specific config if it exists
-            .add_source(File::from(Path::new(config_dir).join("production.toml")).required(false))
+    /// Load the configuration, with `config_file` (if given) overriding the files in config/
+    pub fn new(config_file: Option<&Path>) -> Result<Self, ConfigError> {
+        let builder = Self::files(config_file)
+            .into_iter()
+            // Only an explicitly requested file has to exist
+            .fold(Config::builder(), |builder, file| {
+                let required = Some(file.as_path()) == config_file;
+                builder.add_source(File::from(file).required(required))
+            })
             // Override with environment variables
             // Example: ICICLE_SERVER__PORT=8080 or ICICLE_WEBHOOK__SECRET=mysecret
             .add_source(
@@ -69,7 +507,29 @@ impl Settings {
                     .try_parsing(true),
             );
 
-        builder.build()?.try_deserialize()
+        let mut values = builder.build()?.collect()?;
+        resolve_files(&mut values)?;
+        Value::new(None, ValueKind::Table(values)).try_deserialize()
+    }
+
+    /// The configuration files loaded by `new`, lowest precedence first
+    pub fn files(config_file: Option<&Path>) -> Vec<PathBuf> {
+        let config_dir = Path::new("config");
+        // Defaults, overridden by the environment-specific config if it exists
+        let mut files = vec![
+            config_dir.join("default.toml"),
+            config_dir.join(format!("{}.toml", profile())),
+        ];
+        files.extend(config_file.map(Path::to_path_buf));
+        files
+    }
+
+    /// The configuration files that exist and are therefore actually loaded
+    pub fn loaded_files(config_file: Option<&Path>) -> Vec<PathBuf> {
+        Self::files(config_file)
+            .into_iter()
+            .filter(|file| file.exists())
+            .collect()
     }
 
     pub fn with_defaults() -> Self {
@@ -77,23 +537,110 @@ impl Settings {
             server: ServerConfig {
                 host: "0.0.0.0".to_string(),
                 port: 3000,
+                tls: None,
+                shutdown_timeout_secs: default_shutdown_timeout_secs(),
+            },
+            webhook: WebhookConfig {
+                secret: None,
+                allow: Vec::new(),
+                deny: Vec::new(),
+                branches: Vec::new(),
+                repository_branches: HashMap::new(),
+                event_retention_days: default_event_retention_days(),
             },
-            webhook: WebhookConfig { secret: None },
             cache: CacheConfig {
                 cache_url: "https://cache.nixos.org".to_string(),
                 attic_cache_name: "icicle".to_string(),
+                upload: UploadBackend::default(),
+                upload_store: None,
+                s3: None,
+                substituters: Vec::new(),
+                signing_key: None,
+                upload_closure: false,
+                closure_by_cache: HashMap::new(),
+                post_build_hook: false,
+                hook_dir: default_hook_dir(),
             },
             nix: NixConfig {
                 eval_timeout_secs: 300,
                 default_attr_set: "packages.x86_64-linux".to_string(),
+                release_attr_set: None,
+                import_from_derivation: IfdConfig::default(),
+                fetch: FetchMode::default(),
+                eval_workers: default_eval_workers(),
+                eval_max_memory_size_mib: default_eval_max_memory_size_mib(),
+                eval_memory_limit_mib: None,
             },
             build: BuildConfig {
                 max_concurrent_builds: 4,
                 build_timeout_secs: 3600,
+                max_build_timeout_secs: None,
+                scheduling: Scheduling::default(),
+                local_systems: Vec::new(),
+                local_features: None,
+                builders: Vec::new(),
+                builder_check_secs: default_builder_check_secs(),
+                max_retries: default_max_retries(),
+                retry_backoff_secs: default_retry_backoff_secs(),
+                max_concurrent_uploads: default_max_concurrent_uploads(),
+                upload_retries: default_upload_retries(),
             },
             database: DatabaseConfig {
                 path: "sqlite:icicle.db".to_string(),
             },
+            api: ApiConfig::default(),
+            log: LogConfig::default(),
+            notify: NotifyConfig::default(),
+            ha: HaConfig::default(),
+            gc: GcConfig::default(),
+            proxy: ProxyConfig::default(),
+            artifacts: ArtifactConfig::default(),
+            deploy: DeployConfig::default(),
+            provenance: ProvenanceConfig::default(),
+            bisect: BisectConfig::default(),
+            quota: QuotaConfig::default(),
+            priority: PriorityConfig::default(),
+            github: GitHubConfig::default(),
+            credentials: CredentialsConfig::default(),
+            schedule: ScheduleConfig::default(),
+            poll: PollConfig::default(),
+            workers: WorkerConfig::default(),
+            login: LoginConfig::default(),
+            hooks: HookConfig::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_resolve_files() {
+        let mut secret = tempfile::NamedTempFile::new().unwrap();
+        writeln!(secret, "s3cret").unwrap();
+        let path = secret.path().to_string_lossy().to_string();
+
+        let mut webhook = Map::new();
+        webhook.insert("secret".to_string(), Value::new(None, "inline"));
+        webhook.insert("secret_file".to_string(), Value::new(None, path));
+        let mut values = Map::new();
+        values.insert(
+            "webhook".to_string(),
+            Value::new(None, ValueKind::Table(webhook)),
+        );
+        resolve_files(&mut values).unwrap();
+
+        let webhook = values.remove("webhook").unwrap().into_table().unwrap();
+        assert_eq!(webhook.len(), 1);
+        assert_eq!(webhook["secret"].clone().into_string().unwrap(), "s3cret");
+
+        let mut missing = Map::new();
+        missing.insert(
+            "token_file".to_string(),
+            Value::new(None, "/nonexistent/token"),
+        );
+        assert!(resolve_files(&mut missing).is_err());
+    }
+}
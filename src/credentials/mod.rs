@@ -0,0 +1,176 @@
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use std::{collections::HashMap, path::PathBuf};
+use tokio::process::Command;
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct CredentialsConfig {
+    /// Credentials of specific repositories, by full name
+    pub repositories: HashMap<String, Credential>,
+}
+
+impl CredentialsConfig {
+    pub fn validate(&self) -> Result<()> {
+        for (repository, credential) in &self.repositories {
+            credential
+                .validate()
+                .with_context(|| format!("Credentials of {}", repository))?;
+        }
+        Ok(())
+    }
+}
+
+/// How to authenticate when cloning a private repository
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Credential {
+    /// Private SSH key, such as a deploy key, for ssh:// and git@ clone URLs
+    pub ssh_key: Option<PathBuf>,
+    /// Token sent as the password of HTTP basic auth for https:// clone URLs
+    pub token: Option<String>,
+    /// User name sent with the token; forges accept any with a token, or a fixed one
+    /// such as "oauth2" on GitLab
+    #[serde(default = "default_username")]
+    pub username: String,
+}
+
+fn default_username() -> String {
+    "x-access-token".to_string()
+}
+
+impl Credential {
+    pub fn validate(&self) -> Result<()> {
+        if self.ssh_key.is_none() && self.token.is_none() {
+            return Err(anyhow!("Needs an ssh_key or a token"));
+        }
+        if let Some(key) = &self.ssh_key {
+            std::fs::File::open(key)
+                .with_context(|| format!("Cannot read SSH key {}", key.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Make the git commands run by `command` authenticate with this credential.
+    /// Nothing ends up on the command line or in the repository's config.
+    pub fn apply(&self, command: &mut Command) {
+        if let Some(key) = &self.ssh_key {
+            // GIT_SSH_COMMAND goes through the shell
+            let key = key.to_string_lossy().replace('\'', r"'\''");
+            command.env(
+                "GIT_SSH_COMMAND",
+                format!(
+                    "ssh -i '{}' -o IdentitiesOnly=yes -o StrictHostKeyChecking=accept-new",
+                    key
+                ),
+            );
+        }
+        if let Some(token) = &self.token {
+            let basic = BASE64.encode(format!("{}:{}", self.username, token));
+            command
+                .env("GIT_CONFIG_COUNT", "1")
+                .env("GIT_CONFIG_KEY_0", "http.extraHeader")
+                .env(
+                    "GIT_CONFIG_VALUE_0",
+                    format!("Authorization: Basic {}", basic),
+                );
+        }
+        // Fail instead of waiting for a password nobody will type
+        command.env("GIT_TERMINAL_PROMPT", "0");
+    }
+
+    /// Make nix fetch flakes with this credential: through git like `apply`, and
+    /// with the token as the access token of github: flake references
+    pub fn apply_nix(&self, command: &mut Command) {
+        self.apply(command);
+        if let Some(token) = &self.token {
+            command.env(
+                "NIX_CONFIG",
+                format!("access-tokens = github.com={}", token),
+            );
+        }
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct CredentialRow {
+    ssh_key: Option<String>,
+    token: Option<String>,
+    username: String,
+}
+
+/// Clone credentials from the configuration and the database, where those set
+/// through the API replace the configured ones
+#[derive(Clone)]
+pub struct Credentials {
+    config: CredentialsConfig,
+    db_pool: SqlitePool,
+}
+
+impl Credentials {
+    pub fn new(config: &CredentialsConfig, db_pool: SqlitePool) -> Self {
+        Self {
+            config: config.clone(),
+            db_pool,
+        }
+    }
+
+    pub async fn get(&self, repository: &str) -> Result<Option<Credential>> {
+        let row = sqlx::query_as::<_, CredentialRow>(
+            r#"
+            SELECT c.ssh_key, c.token, c.username
+            FROM repository_credentials c JOIN repositories r ON r.id = c.repository_id
+            WHERE r.full_name = ?
+            "#,
+        )
+        .bind(repository)
+        .fetch_optional(&self.db_pool)
+        .await?;
+        Ok(match row {
+            Some(row) => Some(Credential {
+                ssh_key: row.ssh_key.map(PathBuf::from),
+                token: row.token,
+                username: row.username,
+            }),
+            None => self.config.repositories.get(repository).cloned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let credential = Credential {
+            ssh_key: Some(PathBuf::from("/keys/it's")),
+            token: Some("s3cret".to_string()),
+            username: default_username(),
+        };
+        let mut command = Command::new("git");
+        credential.apply(&mut command);
+        let env: HashMap<String, String> = command
+            .as_std()
+            .get_envs()
+            .filter_map(|(key, value)| {
+                Some((
+                    key.to_string_lossy().into_owned(),
+                    value?.to_string_lossy().into_owned(),
+                ))
+            })
+            .collect();
+        assert_eq!(
+            env["GIT_SSH_COMMAND"],
+            r"ssh -i '/keys/it'\''s' -o IdentitiesOnly=yes -o StrictHostKeyChecking=accept-new"
+        );
+        assert_eq!(
+            env["GIT_CONFIG_VALUE_0"],
+            format!(
+                "Authorization: Basic {}",
+                BASE64.encode("x-access-token:s3cret")
+            )
+        );
+    }
+}
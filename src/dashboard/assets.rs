@@ -0,0 +1,44 @@
+use axum::{
+    extract::Path,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use rust_embed::RustEmbed;
+use std::sync::Arc;
+
+/// Stylesheets and scripts compiled into the binary, so a deployment needs no
+/// files next to it
+#[derive(RustEmbed)]
+#[folder = "static/"]
+struct Assets;
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new().route("/static/{*path}", get(asset))
+}
+
+async fn asset(Path(path): Path<String>, headers: HeaderMap) -> Response {
+    let Some(file) = Assets::get(&path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let etag = format!("\"{}\"", hex::encode(file.metadata.sha256_hash()));
+    let cached = headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == etag.as_bytes());
+    let content_type = HeaderValue::from_str(file.metadata.mimetype())
+        .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+    let headers = [
+        (header::CONTENT_TYPE, content_type),
+        (header::ETAG, HeaderValue::from_str(&etag).unwrap()),
+        // Asset URLs are not versioned, so revalidate on every use
+        (header::CACHE_CONTROL, HeaderValue::from_static("no-cache")),
+    ];
+
+    if cached {
+        (StatusCode::NOT_MODIFIED, headers).into_response()
+    } else {
+        (headers, file.data).into_response()
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{
+    drvdiff::{self, BuildDiff},
+    hints::Cause,
+    nix,
+    notify::format_duration,
+    progress::BuildProgress,
+};
+use askama::Template;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse},
+    routing::get,
+    Router,
+};
+use std::sync::Arc;
+
+/// Lines of the log shown on the page, the full log is linked
+const LOG_TAIL_LINES: usize = 200;
+
+#[derive(Template)]
+#[template(path = "build.html")]
+struct BuildTemplate {
+    drv: String,
+    build: BuildInfo,
+    workflows: Vec<RequestingWorkflow>,
+    diff: Option<BuildDiff>,
+    cause: Option<Cause>,
+    /// What the build is doing, if it runs
+    progress: Option<String>,
+    /// For an aggregate job, the builds it is made of
+    constituents: Vec<RelatedBuild>,
+    /// The aggregate jobs the build is a constituent of
+    aggregates: Vec<RelatedBuild>,
+    /// The end of the log nix kept of the build
+    log: Option<LogTail>,
+}
+
+/// The last lines of a build log
+struct LogTail {
+    lines: String,
+    /// Lines left out before them
+    skipped: usize,
+}
+
+impl LogTail {
+    fn new(log: &str) -> Self {
+        let lines: Vec<&str> = log.lines().collect();
+        let skipped = lines.len().saturating_sub(LOG_TAIL_LINES);
+        Self {
+            lines: lines[skipped..].join("\n"),
+            skipped,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct BuildInfo {
+    name: String,
+    system: String,
+    status: String,
+    error_message: Option<String>,
+    failure_cause: Option<String>,
+    retries: i64,
+    signed: Option<bool>,
+    upload_status: Option<String>,
+    upload_error: Option<String>,
+    started_at: Option<i64>,
+    finished_at: Option<i64>,
+}
+
+impl BuildInfo {
+    fn started(&self) -> Option<String> {
+        self.started_at.and_then(format_time)
+    }
+
+    fn finished(&self) -> Option<String> {
+        self.finished_at.and_then(format_time)
+    }
+
+    fn duration(&self) -> Option<String> {
+        match (self.started_at, self.finished_at) {
+            (Some(started), Some(finished)) => Some(format_duration(finished - started)),
+            _ => None,
+        }
+    }
+}
+
+fn format_time(secs: i64) -> Option<String> {
+    chrono::DateTime::from_timestamp(secs, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+}
+
+/// A workflow that requested the build
+#[derive(sqlx::FromRow)]
+struct RequestingWorkflow {
+    id: i64,
+    repository: String,
+    branch: Option<String>,
+    commit_sha: String,
+    status: String,
+}
+
+/// A build linked to this one as a constituent or an aggregate
+#[derive(sqlx::FromRow)]
+struct RelatedBuild {
+    /// Store path basename of the derivation
+    drv: String,
+    name: String,
+    status: String,
+}
+
+/// The builds on the other side of `aggregate_constituents`, from `column` to `other`
+async fn related(
+    db_pool: &sqlx::SqlitePool,
+    column: &str,
+    other: &str,
+    drv_path: &str,
+) -> Result<Vec<RelatedBuild>, StatusCode> {
+    sqlx::query_as::<_, RelatedBuild>(&format!(
+        "SELECT substr(a.{other}, length('/nix/store/') + 1) AS drv,
+            coalesce(b.name, a.{other}) AS name, coalesce(b.status, 'queued') AS status
+         FROM aggregate_constituents a LEFT JOIN builds b ON b.drv_path = a.{other}
+         WHERE a.{column} = ? ORDER BY name"
+    ))
+    .bind(drv_path)
+    .fetch_all(db_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new().route("/builds/{drv}", get(build))
+}
+
+async fn build(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    Path(drv): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let principal = super::authorize(&app_state, &headers).await?;
+    if drv.contains('/') {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let drv_path = format!("/nix/store/{}", drv);
+    if !principal
+        .sees_build(&app_state.db_pool, &drv_path)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let build = sqlx::query_as::<_, BuildInfo>(
+        "SELECT name, system, status, error_message, failure_cause, retries, signed,
+            upload_status, upload_error, started_at, finished_at
+         FROM builds WHERE drv_path = ?",
+    )
+    .bind(&drv_path)
+    .fetch_optional(&app_state.db_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut workflows = sqlx::query_as::<_, RequestingWorkflow>(
+        "SELECT w.id, w.repository, w.branch, SUBSTR(w.commit_sha, 1, 8) AS commit_sha, w.status
+         FROM build_workflows bw JOIN workflows w ON w.id = bw.workflow_id
+         WHERE bw.drv_path = ? ORDER BY w.id DESC",
+    )
+    .bind(&drv_path)
+    .fetch_all(&app_state.db_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    workflows.retain(|workflow| principal.sees(&workflow.repository));
+
+    let diff = drvdiff::load(&app_state.db_pool, &drv_path)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let constituents = related(&app_state.db_pool, "aggregate", "constituent", &drv_path).await?;
+    let aggregates = related(&app_state.db_pool, "constituent", "aggregate", &drv_path).await?;
+
+    // Nothing to show before the build ran
+    let log = match build.started_at {
+        Some(_) => nix::build_log(&drv_path)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .map(|log| LogTail::new(&log)),
+        None => None,
+    };
+
+    let cause = build.failure_cause.as_deref().and_then(Cause::parse);
+    let progress = app_state
+        .build_queue
+        .progress(&drv_path)
+        .as_ref()
+        .map(BuildProgress::summary);
+    let template = BuildTemplate {
+        drv,
+        build,
+        workflows,
+        diff,
+        cause,
+        progress,
+        constituents,
+        aggregates,
+        log,
+    };
+    match template.render() {
+        Ok(html) => Ok(Html(html)),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
@@ -0,0 +1,119 @@
+use askama::Template;
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse},
+    routing::get,
+    Router,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Input whose revision is shown per repository unless another is picked
+const DEFAULT_INPUT: &str = "nixpkgs";
+/// Number of input bumps listed
+const RECENT_BUMPS: i64 = 50;
+
+#[derive(Template)]
+#[template(path = "inputs.html")]
+struct InputsTemplate {
+    input: String,
+    repositories: Vec<RepositoryInput>,
+    bumps: Vec<InputBump>,
+}
+
+/// The revision of an input in a repository's latest workflow
+#[derive(sqlx::FromRow)]
+struct RepositoryInput {
+    repository: String,
+    branch: String,
+    workflow_id: i64,
+    source: String,
+    rev: Option<String>,
+    last_modified: Option<i64>,
+}
+
+impl RepositoryInput {
+    fn locked_date(&self) -> String {
+        self.last_modified
+            .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+            .map(|date| date.format("%Y-%m-%d").to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// An input whose revision changed in a workflow
+#[derive(sqlx::FromRow)]
+struct InputBump {
+    workflow_id: i64,
+    repository: String,
+    branch: String,
+    status: String,
+    name: String,
+    previous_rev: Option<String>,
+    rev: Option<String>,
+}
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new().route("/inputs", get(inputs))
+}
+
+#[derive(Deserialize)]
+struct InputsQuery {
+    input: Option<String>,
+}
+
+async fn inputs(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<InputsQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let principal = super::authorize(&app_state, &headers).await?;
+    let input = query.input.unwrap_or_else(|| DEFAULT_INPUT.to_string());
+
+    let repositories = sqlx::query_as::<_, RepositoryInput>(
+        r#"
+        SELECT w.repository, w.branch, i.workflow_id, i.source,
+               SUBSTR(i.rev, 1, 12) AS rev, i.last_modified
+        FROM workflow_inputs i JOIN workflows w ON w.id = i.workflow_id
+        WHERE i.name = ?1 AND i.workflow_id IN (
+            SELECT MAX(i.workflow_id) FROM workflow_inputs i
+            JOIN workflows w ON w.id = i.workflow_id
+            WHERE i.name = ?1
+            GROUP BY w.repository, w.branch
+        )
+          AND w.repository NOT IN (SELECT value FROM json_each(?2))
+        ORDER BY w.repository, w.branch
+        "#,
+    )
+    .bind(&input)
+    .bind(principal.hidden_json())
+    .fetch_all(&app_state.db_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let bumps = sqlx::query_as::<_, InputBump>(
+        r#"
+        SELECT i.workflow_id, w.repository, w.branch, w.status, i.name,
+               SUBSTR(i.previous_rev, 1, 12) AS previous_rev, SUBSTR(i.rev, 1, 12) AS rev
+        FROM workflow_inputs i JOIN workflows w ON w.id = i.workflow_id
+        WHERE i.changed
+        ORDER BY i.workflow_id DESC, i.name
+        LIMIT ?
+        "#,
+    )
+    .bind(RECENT_BUMPS)
+    .fetch_all(&app_state.db_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let template = InputsTemplate {
+        input,
+        repositories,
+        bumps,
+    };
+    match template.render() {
+        Ok(html) => Ok(Html(html)),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
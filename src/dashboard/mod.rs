@@ -1,19 +1,64 @@
-use crate::build::{BuildJob, BuildStatus};
+use crate::{
+    auth::{self, AuthError, Permission, Principal},
+    build::{BuildJob, BuildStatus},
+    durations::Estimates,
+    login,
+    notify::format_duration,
+    progress::BuildProgress,
+};
 use askama::Template;
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::{Html, IntoResponse},
+    extract::{Query, Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Redirect, Response},
     routing::get,
     Router,
 };
-use std::{collections::HashMap, sync::Arc};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+mod assets;
+mod builds;
+mod inputs;
+mod stats;
+mod workflows;
+
+/// Workflows per page of the history
+const HISTORY_PAGE_SIZE: i64 = 20;
+/// Build statuses offered by the status filter
+const STATUSES: [BuildStatus; 8] = [
+    BuildStatus::Queued,
+    BuildStatus::Ready,
+    BuildStatus::Running,
+    BuildStatus::Success,
+    BuildStatus::Cached,
+    BuildStatus::Failed,
+    BuildStatus::Timedout,
+    BuildStatus::Canceled,
+];
 
 #[derive(Template)]
 #[template(path = "dashboard.html")]
 struct DashboardTemplate {
+    /// Who signed in to the dashboard, to offer signing out
+    signed_in: Option<String>,
+    filters: DashboardQuery,
+    statuses: Vec<StatusOption>,
     job_queue: JobQueueSection,
     workflows: WorkflowSection,
+    history: HistorySection,
+    deployments: Vec<DeploymentInfo>,
+    failed_evaluations: Vec<FailedEvaluation>,
+}
+
+/// An entry of the status filter
+struct StatusOption {
+    status: String,
+    selected: bool,
 }
 
 struct JobQueueSection {
@@ -32,7 +77,17 @@ struct JobInfo {
     system: String,
     status: BuildStatus,
     requested_by_count: usize,
-    outputs: Vec<String>,
+    /// What the build is doing, if it runs
+    progress: Option<String>,
+    /// Estimated time left, if it runs and was built before
+    remaining: Option<String>,
+}
+
+impl JobInfo {
+    /// The derivation's path within the store, which the build page is keyed by
+    fn drv_basename(&self) -> &str {
+        self.drv_path.trim_start_matches("/nix/store/")
+    }
 }
 
 struct QueueStats {
@@ -47,6 +102,57 @@ struct QueueStats {
     canceled: usize,
 }
 
+/// A page of past and present workflows, from the database
+struct HistorySection {
+    workflows: Vec<HistoryWorkflow>,
+    /// Link to the next page of older workflows, if there is one
+    older: Option<String>,
+    /// Link back to the first page, when showing another
+    newest: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct HistoryWorkflow {
+    id: i64,
+    repository: String,
+    branch: Option<String>,
+    commit_sha: String,
+    status: String,
+    created_at: i64,
+    total_builds: i64,
+    succeeded_builds: i64,
+    failed_builds: i64,
+}
+
+impl HistoryWorkflow {
+    fn created(&self) -> String {
+        chrono::DateTime::from_timestamp(self.created_at, 0)
+            .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// Latest deployment to a target
+#[derive(sqlx::FromRow)]
+struct DeploymentInfo {
+    id: i64,
+    target: String,
+    status: String,
+    workflow_id: i64,
+    commit_sha: String,
+}
+
+/// Recent workflow that failed before building, e.g. as its evaluation timed out
+#[derive(sqlx::FromRow)]
+struct FailedEvaluation {
+    id: i64,
+    repository: String,
+    commit_sha: String,
+    /// The attribute that failed to evaluate, unless the whole evaluation failed
+    attr: Option<String>,
+    error: String,
+}
+
 #[derive(Clone)]
 struct WorkflowInfo {
     id: i64,
@@ -67,26 +173,194 @@ struct WorkflowSummary {
     failed_jobs: usize,
     cached_jobs: usize,
     progress_percent: u8,
+    /// Estimated time until its jobs are done, when all of them were built before
+    remaining: Option<String>,
 }
 
-pub fn routes() -> Router<Arc<crate::AppState>> {
+pub fn routes(app_state: Arc<crate::AppState>) -> Router<Arc<crate::AppState>> {
     Router::new()
         .route("/", get(dashboard))
         .route("/dashboard", get(dashboard))
+        .merge(builds::routes())
+        .merge(inputs::routes())
+        .merge(stats::routes())
+        .merge(workflows::routes())
+        .route_layer(middleware::from_fn_with_state(app_state, require_login))
+        // The login page is styled too
+        .merge(assets::routes())
+}
+
+/// Send visitors of a private dashboard to sign in, when they can
+async fn require_login(
+    State(app_state): State<Arc<crate::AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if app_state.login.is_some() && !app_state.api_config.public {
+        let signed_in =
+            auth::authenticate(&app_state.api_config, &app_state.db_pool, request.headers()).await;
+        if !matches!(signed_in, Ok(Some(_))) {
+            return Redirect::to(&login::login_path(request.uri())).into_response();
+        }
+    }
+    next.run(request).await
+}
+
+#[derive(Deserialize)]
+struct DashboardQuery {
+    /// Only show the builds of this organization's workflows
+    org: Option<String>,
+    /// Only show the workflows of this repository, e.g. "owner/repo"
+    repository: Option<String>,
+    /// Only show the workflows of this branch
+    branch: Option<String>,
+    /// Only show the builds with this status; in the history, the workflows with such
+    /// a build or with this status themselves
+    status: Option<String>,
+    /// Only show the job with this attribute name
+    attr: Option<String>,
+    /// Only show the jobs whose names contain this, ignoring case
+    q: Option<String>,
+    /// Start the history below this workflow ID
+    before: Option<i64>,
+}
+
+impl DashboardQuery {
+    /// Treat the fields the filter form left empty as unset
+    fn normalize(mut self) -> Self {
+        for field in [
+            &mut self.org,
+            &mut self.repository,
+            &mut self.branch,
+            &mut self.status,
+            &mut self.attr,
+            &mut self.q,
+        ] {
+            if field
+                .as_deref()
+                .is_some_and(|value| value.trim().is_empty())
+            {
+                *field = None;
+            }
+        }
+        self
+    }
+
+    /// Whether only some workflows are shown
+    fn selects_workflows(&self) -> bool {
+        self.org.is_some() || self.repository.is_some() || self.branch.is_some()
+    }
+
+    fn matches_job(&self, job: &BuildJob) -> bool {
+        let name = &job.derivation.name;
+        self.status
+            .as_ref()
+            .is_none_or(|status| job.status.to_string() == *status)
+            && self.attr.as_ref().is_none_or(|attr| name == attr)
+            && self
+                .q
+                .as_ref()
+                .is_none_or(|q| name.to_lowercase().contains(&q.to_lowercase()))
+    }
+
+    /// Link to the dashboard with the same filters, showing the history below `before`
+    fn link(&self, before: Option<i64>) -> String {
+        let before = before.map(|id| id.to_string());
+        let params = [
+            ("org", self.org.as_deref()),
+            ("repository", self.repository.as_deref()),
+            ("branch", self.branch.as_deref()),
+            ("status", self.status.as_deref()),
+            ("attr", self.attr.as_deref()),
+            ("q", self.q.as_deref()),
+            ("before", before.as_deref()),
+        ];
+        let params = params
+            .iter()
+            .filter_map(|(key, value)| value.map(|value| (key, value)));
+        match reqwest::Url::parse_with_params("http://dashboard/", params) {
+            Ok(url) => match url.query() {
+                Some(query) if !query.is_empty() => format!("/?{}", query),
+                _ => "/".to_string(),
+            },
+            Err(_) => "/".to_string(),
+        }
+    }
 }
 
 async fn dashboard(
     State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<DashboardQuery>,
 ) -> Result<impl IntoResponse, StatusCode> {
+    let principal = authorize(&app_state, &headers).await?;
+    let query = query.normalize();
+
+    let mut jobs = app_state.build_queue.get_jobs();
+    let hidden = principal
+        .hidden_workflows(&app_state.db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !hidden.is_empty() {
+        // Jobs only other organizations' workflows need are left out
+        jobs.retain_mut(|job| {
+            if job.requested_by.is_empty() {
+                return true;
+            }
+            job.requested_by.retain(|id| !hidden.contains(id));
+            !job.requested_by.is_empty()
+        });
+    }
+    if query.selects_workflows() {
+        let workflows = active_workflows(&app_state.db_pool, &query)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        for job in &mut jobs {
+            job.requested_by.retain(|id| workflows.contains(id));
+        }
+        jobs.retain(|job| !job.requested_by.is_empty());
+    }
+    jobs.retain(|job| query.matches_job(job));
+
     // Build Job Queue Section
-    let job_queue = build_job_queue_section(&app_state.build_queue);
+    let estimates = Estimates::load(&app_state.db_pool, &jobs)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let now = chrono::Utc::now().timestamp();
+    let job_queue = build_job_queue_section(&jobs, &estimates, now);
 
     // Build Workflows Section
-    let workflows = build_workflow_section(&app_state.build_queue);
+    let workflows = build_workflow_section(&jobs, &estimates, now);
+
+    let hidden = principal.hidden_json();
+    let history = history_section(&app_state.db_pool, &query, &hidden)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let deployments = latest_deployments(&app_state.db_pool, query.org.as_deref(), &hidden)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let failed_evaluations = failed_evaluations(&app_state.db_pool, query.org.as_deref(), &hidden)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
+    let statuses = STATUSES
+        .iter()
+        .map(|status| StatusOption {
+            status: status.to_string(),
+            selected: query.status == Some(status.to_string()),
+        })
+        .collect();
     let template = DashboardTemplate {
+        signed_in: principal.session.then_some(principal.name),
+        filters: query,
+        statuses,
         job_queue,
         workflows,
+        history,
+        deployments,
+        failed_evaluations,
     };
 
     match template.render() {
@@ -95,7 +369,197 @@ async fn dashboard(
     }
 }
 
-fn build_job_queue_section(queue: &crate::build::BuildQueue) -> JobQueueSection {
+/// Check that the caller may view the dashboard
+async fn authorize(
+    app_state: &crate::AppState,
+    headers: &HeaderMap,
+) -> Result<Principal, StatusCode> {
+    auth::authorize(
+        &app_state.api_config,
+        &app_state.db_pool,
+        headers,
+        Permission::View,
+    )
+    .await
+    .map_err(|e| match e {
+        AuthError::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+        AuthError::Forbidden(_) => StatusCode::FORBIDDEN,
+        AuthError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    })
+}
+
+/// Active workflows of the organization's repositories, the repository and the branch
+/// the query selects
+async fn active_workflows(
+    db_pool: &sqlx::SqlitePool,
+    query: &DashboardQuery,
+) -> Result<HashSet<i64>, sqlx::Error> {
+    let ids = sqlx::query_scalar::<_, i64>(
+        r#"
+        SELECT w.id FROM workflows w
+        WHERE w.status IN ('Pending', 'Running')
+          AND (?1 IS NULL OR w.repository IN (
+              SELECT r.full_name FROM repositories r
+              JOIN projects p ON p.id = r.project_id
+              JOIN organizations o ON o.id = p.organization_id
+              WHERE o.name = ?1))
+          AND (?2 IS NULL OR w.repository = ?2)
+          AND (?3 IS NULL OR w.branch = ?3)
+        "#,
+    )
+    .bind(query.org.as_deref())
+    .bind(query.repository.as_deref())
+    .bind(query.branch.as_deref())
+    .fetch_all(db_pool)
+    .await?;
+    Ok(ids.into_iter().collect())
+}
+
+/// A page of the workflows the query selects with how their builds went, newest
+/// first, leaving out those of the `hidden` repositories
+async fn history_section(
+    db_pool: &sqlx::SqlitePool,
+    query: &DashboardQuery,
+    hidden: &str,
+) -> Result<HistorySection, sqlx::Error> {
+    let mut workflows = sqlx::query_as::<_, HistoryWorkflow>(
+        r#"
+        SELECT w.id, w.repository, w.branch, SUBSTR(w.commit_sha, 1, 8) AS commit_sha,
+            w.status, w.created_at, COUNT(b.drv_path) AS total_builds,
+            COALESCE(SUM(b.status IN ('success', 'cached')), 0) AS succeeded_builds,
+            COALESCE(SUM(b.status IN ('failed', 'timed out')), 0) AS failed_builds
+        FROM workflows w
+        LEFT JOIN build_workflows bw ON bw.workflow_id = w.id
+        LEFT JOIN builds b ON b.drv_path = bw.drv_path
+        WHERE (?1 IS NULL OR w.id < ?1)
+          AND (?2 IS NULL OR w.repository IN (
+              SELECT r.full_name FROM repositories r
+              JOIN projects p ON p.id = r.project_id
+              JOIN organizations o ON o.id = p.organization_id
+              WHERE o.name = ?2))
+          AND (?3 IS NULL OR w.repository = ?3)
+          AND (?4 IS NULL OR w.branch = ?4)
+          AND (?5 IS NULL OR LOWER(w.status) = ?5 OR EXISTS (
+              SELECT 1 FROM build_workflows sbw
+              JOIN builds sb ON sb.drv_path = sbw.drv_path
+              WHERE sbw.workflow_id = w.id AND sb.status = ?5))
+          AND ((?6 IS NULL AND ?7 IS NULL) OR EXISTS (
+              SELECT 1 FROM build_workflows nbw
+              JOIN builds nb ON nb.drv_path = nbw.drv_path
+              WHERE nbw.workflow_id = w.id
+                AND (?6 IS NULL OR nb.name = ?6)
+                AND (?7 IS NULL OR INSTR(LOWER(nb.name), LOWER(?7)) > 0)))
+          AND w.repository NOT IN (SELECT value FROM json_each(?9))
+        GROUP BY w.id
+        ORDER BY w.id DESC LIMIT ?8
+        "#,
+    )
+    .bind(query.before)
+    .bind(query.org.as_deref())
+    .bind(query.repository.as_deref())
+    .bind(query.branch.as_deref())
+    .bind(query.status.as_deref())
+    .bind(query.attr.as_deref())
+    .bind(query.q.as_deref())
+    // One more row tells whether there is an older page
+    .bind(HISTORY_PAGE_SIZE + 1)
+    .bind(hidden)
+    .fetch_all(db_pool)
+    .await?;
+
+    let older = if workflows.len() as i64 > HISTORY_PAGE_SIZE {
+        workflows.truncate(HISTORY_PAGE_SIZE as usize);
+        workflows.last().map(|w| query.link(Some(w.id)))
+    } else {
+        None
+    };
+    let newest = query.before.map(|_| query.link(None));
+    Ok(HistorySection {
+        workflows,
+        older,
+        newest,
+    })
+}
+
+/// The latest deployment to each target, of the organization's repositories if given,
+/// leaving out those of the `hidden` repositories
+async fn latest_deployments(
+    db_pool: &sqlx::SqlitePool,
+    organization: Option<&str>,
+    hidden: &str,
+) -> Result<Vec<DeploymentInfo>, sqlx::Error> {
+    sqlx::query_as::<_, DeploymentInfo>(
+        r#"
+        SELECT d.id, d.target, d.status, d.workflow_id, SUBSTR(w.commit_sha, 1, 8) AS commit_sha
+        FROM deployments d JOIN workflows w ON w.id = d.workflow_id
+        WHERE d.id IN (
+            SELECT MAX(sd.id) FROM deployments sd JOIN workflows sw ON sw.id = sd.workflow_id
+            WHERE sw.repository NOT IN (SELECT value FROM json_each(?2))
+            GROUP BY sd.target
+        )
+          AND (?1 IS NULL OR w.repository IN (
+              SELECT r.full_name FROM repositories r
+              JOIN projects p ON p.id = r.project_id
+              JOIN organizations o ON o.id = p.organization_id
+              WHERE o.name = ?1))
+        ORDER BY d.target
+        "#,
+    )
+    .bind(organization)
+    .bind(hidden)
+    .fetch_all(db_pool)
+    .await
+}
+
+/// Workflows of the last day that failed before building, or with attributes that
+/// failed to evaluate, of the organization's repositories if given, leaving out those
+/// of the `hidden` repositories
+async fn failed_evaluations(
+    db_pool: &sqlx::SqlitePool,
+    organization: Option<&str>,
+    hidden: &str,
+) -> Result<Vec<FailedEvaluation>, sqlx::Error> {
+    sqlx::query_as::<_, FailedEvaluation>(
+        r#"
+        SELECT f.id, f.repository, SUBSTR(f.commit_sha, 1, 8) AS commit_sha, f.attr, f.error
+        FROM (
+            SELECT w.id, w.repository, w.commit_sha, NULL AS attr, w.error, w.created_at
+            FROM workflows w WHERE w.error IS NOT NULL
+            UNION ALL
+            SELECT w.id, w.repository, w.commit_sha, e.attr, e.error, w.created_at
+            FROM evaluation_errors e JOIN workflows w ON w.id = e.workflow_id
+        ) f
+        WHERE f.created_at >= ?2
+          AND (?1 IS NULL OR f.repository IN (
+              SELECT r.full_name FROM repositories r
+              JOIN projects p ON p.id = r.project_id
+              JOIN organizations o ON o.id = p.organization_id
+              WHERE o.name = ?1))
+          AND f.repository NOT IN (SELECT value FROM json_each(?3))
+        ORDER BY f.id DESC, f.attr LIMIT 10
+        "#,
+    )
+    .bind(organization)
+    .bind(chrono::Utc::now().timestamp() - 24 * 3600)
+    .bind(hidden)
+    .fetch_all(db_pool)
+    .await
+}
+
+/// Estimated time left, e.g. "about 3m12s left"
+fn format_remaining(secs: i64) -> String {
+    if secs == 0 {
+        "finishing soon".to_string()
+    } else {
+        format!("about {} left", format_duration(secs))
+    }
+}
+
+fn build_job_queue_section(
+    all_jobs: &[BuildJob],
+    estimates: &Estimates,
+    now: i64,
+) -> JobQueueSection {
     let mut jobs = Vec::new();
     let mut stats = QueueStats {
         total: 0,
@@ -109,7 +573,7 @@ fn build_job_queue_section(queue: &crate::build::BuildQueue) -> JobQueueSection
         canceled: 0,
     };
 
-    for job in queue.get_jobs() {
+    for job in all_jobs {
         stats.total += 1;
 
         match job.status {
@@ -127,9 +591,13 @@ fn build_job_queue_section(queue: &crate::build::BuildQueue) -> JobQueueSection
             name: job.derivation.name.clone(),
             drv_path: job.derivation.drv_path.clone(),
             system: job.derivation.system.clone(),
-            status: job.status.clone(),
+            status: job.status,
             requested_by_count: job.requested_by.len(),
-            outputs: job.derivation.outputs.clone(),
+            progress: job.progress.as_ref().map(BuildProgress::summary),
+            remaining: (job.status == BuildStatus::Running)
+                .then(|| estimates.remaining(job, now))
+                .flatten()
+                .map(format_remaining),
         });
     }
 
@@ -151,11 +619,15 @@ fn build_job_queue_section(queue: &crate::build::BuildQueue) -> JobQueueSection
     JobQueueSection { jobs, stats }
 }
 
-fn build_workflow_section(queue: &crate::build::BuildQueue) -> WorkflowSection {
+fn build_workflow_section(
+    all_jobs: &[BuildJob],
+    estimates: &Estimates,
+    now: i64,
+) -> WorkflowSection {
     let mut workflow_map: HashMap<i64, Vec<BuildJob>> = HashMap::new();
 
     // Group jobs by workflow
-    for job in queue.get_jobs() {
+    for job in all_jobs {
         for workflow_id in &job.requested_by {
             workflow_map
                 .entry(*workflow_id)
@@ -171,7 +643,7 @@ fn build_workflow_section(queue: &crate::build::BuildQueue) -> WorkflowSection {
         for job in &jobs {
             job_details.push(WorkflowJobDetail {
                 name: job.derivation.name.clone(),
-                status: job.derivation.status.clone(),
+                status: job.derivation.status,
             });
         }
 
@@ -191,11 +663,10 @@ fn build_workflow_section(queue: &crate::build::BuildQueue) -> WorkflowSection {
             .count();
 
         let finished_jobs = completed_jobs + failed_jobs + cached_jobs;
-        let progress_percent = if total_jobs > 0 {
-            ((finished_jobs * 100) / total_jobs) as u8
-        } else {
-            0
-        };
+        let progress_percent = (finished_jobs * 100).checked_div(total_jobs).unwrap_or(0) as u8;
+        let remaining = estimates
+            .workflow_remaining(&jobs.iter().collect::<Vec<_>>(), now)
+            .map(format_remaining);
 
         workflows.push(WorkflowInfo {
             id: workflow_id,
@@ -206,12 +677,13 @@ fn build_workflow_section(queue: &crate::build::BuildQueue) -> WorkflowSection {
                 failed_jobs,
                 cached_jobs,
                 progress_percent,
+                remaining,
             },
         });
     }
 
     // Sort workflows by ID (most recent first)
-    workflows.sort_by(|a, b| b.id.cmp(&a.id));
+    workflows.sort_by_key(|w| std::cmp::Reverse(w.id));
 
     WorkflowSection { workflows }
 }
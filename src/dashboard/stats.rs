@@ -0,0 +1,115 @@
+use crate::{
+    notify::format_duration,
+    stats::{self, Stats, Window},
+};
+use askama::Template;
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse},
+    routing::get,
+    Router,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+
+#[derive(Template)]
+#[template(path = "stats.html")]
+struct StatsTemplate {
+    window: &'static str,
+    /// Each window, and whether it is shown
+    windows: Vec<(&'static str, bool)>,
+    repository: Option<String>,
+    total: StatsRow,
+    repositories: Vec<StatsRow>,
+}
+
+/// Statistics formatted for display
+struct StatsRow {
+    name: String,
+    workflows: i64,
+    success_rate: String,
+    avg_workflow: String,
+    builds: i64,
+    build_success_rate: String,
+    cache_hit_rate: String,
+    avg_build: String,
+    avg_queue_wait: String,
+}
+
+fn percent(rate: Option<f64>) -> String {
+    rate.map(|rate| format!("{:.0}%", rate * 100.0))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+fn duration(secs: Option<f64>) -> String {
+    secs.map(|secs| format_duration(secs.round() as i64))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+impl StatsRow {
+    fn new(name: String, stats: &Stats) -> Self {
+        StatsRow {
+            name,
+            workflows: stats.workflows,
+            success_rate: percent(stats.success_rate),
+            avg_workflow: duration(stats.avg_workflow_secs),
+            builds: stats.builds,
+            build_success_rate: percent(stats.build_success_rate),
+            cache_hit_rate: percent(stats.cache_hit_rate),
+            avg_build: duration(stats.avg_build_secs),
+            avg_queue_wait: duration(stats.avg_queue_wait_secs),
+        }
+    }
+}
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new().route("/stats", get(stats))
+}
+
+#[derive(Deserialize)]
+struct StatsQuery {
+    #[serde(default)]
+    window: Window,
+    repository: Option<String>,
+}
+
+/// Totals and a table by repository; static/stats.js charts the trends
+async fn stats(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<StatsQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let principal = super::authorize(&app_state, &headers).await?;
+    let repository = query.repository.filter(|r| !r.is_empty());
+
+    let report = stats::report(
+        &app_state.db_pool,
+        query.window,
+        repository.as_deref(),
+        &principal.hidden,
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let template = StatsTemplate {
+        window: query.window.as_str(),
+        windows: Window::ALL
+            .iter()
+            .map(|&window| (window.as_str(), window == query.window))
+            .collect(),
+        total: StatsRow::new(
+            repository.clone().unwrap_or_else(|| "All".to_string()),
+            &report.total,
+        ),
+        repositories: report
+            .repositories
+            .iter()
+            .map(|r| StatsRow::new(r.repository.clone(), &r.stats))
+            .collect(),
+        repository,
+    };
+    match template.render() {
+        Ok(html) => Ok(Html(html)),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
@@ -0,0 +1,111 @@
+use crate::durations::Estimates;
+use askama::Template;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse},
+    routing::get,
+    Router,
+};
+use std::sync::Arc;
+
+#[derive(Template)]
+#[template(path = "workflow.html")]
+struct WorkflowTemplate {
+    workflow: WorkflowInfo,
+    builds: Vec<WorkflowBuild>,
+    /// Estimated time until its queued jobs are done
+    remaining: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct WorkflowInfo {
+    id: i64,
+    repository: String,
+    branch: Option<String>,
+    commit_sha: String,
+    attribute_set: String,
+    status: String,
+    created_at: i64,
+    error: Option<String>,
+}
+
+impl WorkflowInfo {
+    fn created(&self) -> String {
+        chrono::DateTime::from_timestamp(self.created_at, 0)
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// A build the workflow requested, as recorded once it started or finished
+#[derive(sqlx::FromRow)]
+struct WorkflowBuild {
+    /// Store path basename of the derivation
+    drv: String,
+    name: String,
+    system: String,
+    status: String,
+}
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new().route("/workflows/{id}", get(workflow))
+}
+
+async fn workflow(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let principal = super::authorize(&app_state, &headers).await?;
+
+    let workflow = sqlx::query_as::<_, WorkflowInfo>(
+        "SELECT id, repository, branch, commit_sha, attribute_set, status, created_at, error
+         FROM workflows WHERE id = ?",
+    )
+    .bind(id)
+    .fetch_optional(&app_state.db_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .filter(|workflow| principal.sees(&workflow.repository))
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    let builds = sqlx::query_as::<_, WorkflowBuild>(
+        "SELECT substr(b.drv_path, length('/nix/store/') + 1) AS drv, b.name, b.system, b.status
+         FROM build_workflows bw JOIN builds b ON b.drv_path = bw.drv_path
+         WHERE bw.workflow_id = ? ORDER BY b.name",
+    )
+    .bind(id)
+    .fetch_all(&app_state.db_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let jobs: Vec<_> = app_state
+        .build_queue
+        .get_jobs()
+        .into_iter()
+        .filter(|job| job.requested_by.contains(&id))
+        .collect();
+    let remaining = if jobs.iter().all(|job| job.status.done()) {
+        None
+    } else {
+        Estimates::load(&app_state.db_pool, &jobs)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .workflow_remaining(
+                &jobs.iter().collect::<Vec<_>>(),
+                chrono::Utc::now().timestamp(),
+            )
+            .map(super::format_remaining)
+    };
+
+    let template = WorkflowTemplate {
+        workflow,
+        builds,
+        remaining,
+    };
+    match template.render() {
+        Ok(html) => Ok(Html(html)),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
@@ -0,0 +1,503 @@
+use crate::{
+    build::WorkflowStatus,
+    credentials::Credentials,
+    events::{Event, EventBus},
+    nix::NixEvaluator,
+};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{process::Command, sync::broadcast::error::RecvError, sync::Mutex};
+use tracing::{error, info, info_span, warn, Instrument};
+
+/// Deploy output beyond this many bytes is cut off
+const MAX_OUTPUT_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct DeployConfig {
+    pub targets: Vec<DeployTarget>,
+}
+
+/// Where successful workflows of a repository's branch get deployed
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct DeployTarget {
+    pub name: String,
+    /// Full name of the repository, e.g. "owner/repo"
+    pub repository: String,
+    pub branch: String,
+    /// Program and arguments, run in a checkout of the deployed commit
+    pub command: Vec<String>,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_timeout_secs() -> u64 {
+    1800
+}
+
+impl DeployTarget {
+    fn matches(&self, workflow: &DeployedWorkflow) -> bool {
+        self.repository == workflow.repository && self.branch == workflow.branch
+    }
+}
+
+impl DeployConfig {
+    pub fn validate(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for target in &self.targets {
+            if !names.insert(target.name.as_str()) {
+                return Err(anyhow!("Deploy target {} is defined twice", target.name));
+            }
+            if target.command.is_empty() {
+                return Err(anyhow!("Deploy target {} has no command", target.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct DeployedWorkflow {
+    repository: String,
+    branch: String,
+    commit_sha: String,
+    clone_url: String,
+}
+
+/// Runs the deploy command of every target matching a workflow that completed
+pub struct Deployer {
+    targets: Vec<DeployTarget>,
+    db_pool: SqlitePool,
+    events: EventBus,
+    credentials: Credentials,
+    /// Held while deploying to a target, so deployments to it never overlap
+    locks: HashMap<String, Arc<Mutex<()>>>,
+}
+
+impl Deployer {
+    pub fn new(
+        config: &DeployConfig,
+        db_pool: SqlitePool,
+        events: EventBus,
+        credentials: Credentials,
+    ) -> Self {
+        Self {
+            locks: config
+                .targets
+                .iter()
+                .map(|target| (target.name.clone(), Arc::default()))
+                .collect(),
+            targets: config.targets.clone(),
+            db_pool,
+            events,
+            credentials,
+        }
+    }
+
+    pub async fn run(self) {
+        let this = Arc::new(self);
+        // Deployments interrupted by a restart are not resumed
+        if let Err(e) = sqlx::query(
+            r#"
+            UPDATE deployments
+            SET status = 'failed', output = 'Interrupted by a restart', finished_at = ?
+            WHERE status IN ('queued', 'running')
+            "#,
+        )
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&this.db_pool)
+        .await
+        {
+            warn!("Failed to fail interrupted deployments: {}", e);
+        }
+
+        let mut receiver = this.events.subscribe();
+        loop {
+            let workflow_id = match receiver.recv().await {
+                Ok(Event::WorkflowStatus {
+                    workflow_id,
+                    status: WorkflowStatus::Completed,
+                }) => workflow_id,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Deployments missed {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            if let Err(e) = this.clone().handle_workflow(workflow_id).await {
+                error!(
+                    "Failed to start deployments of workflow {}: {}",
+                    workflow_id, e
+                );
+            }
+        }
+    }
+
+    async fn handle_workflow(self: Arc<Self>, workflow_id: i64) -> Result<()> {
+        let workflow = sqlx::query_as::<_, DeployedWorkflow>(
+            r#"
+            SELECT w.repository, w.branch, w.commit_sha, r.clone_url
+            FROM workflows w JOIN repositories r ON r.full_name = w.repository
+            WHERE w.id = ?
+            "#,
+        )
+        .bind(workflow_id)
+        .fetch_one(&self.db_pool)
+        .await?;
+        let workflow = Arc::new(workflow);
+
+        for target in &self.targets {
+            if !target.matches(&workflow) {
+                continue;
+            }
+            let id = sqlx::query(
+                r#"
+                INSERT INTO deployments (workflow_id, target, status, created_at)
+                VALUES (?, ?, 'queued', ?)
+                "#,
+            )
+            .bind(workflow_id)
+            .bind(&target.name)
+            .bind(chrono::Utc::now().timestamp())
+            .execute(&self.db_pool)
+            .await?
+            .last_insert_rowid();
+            self.publish(workflow_id, &target.name, "queued");
+
+            info!(
+                "Queued deployment {} of workflow {} to {}",
+                id, workflow_id, target.name
+            );
+            tokio::spawn(
+                self.clone()
+                    .deploy(id, workflow_id, target.clone(), workflow.clone())
+                    .instrument(info_span!("deployment", id, target = target.name)),
+            );
+        }
+        Ok(())
+    }
+
+    async fn deploy(
+        self: Arc<Self>,
+        id: i64,
+        workflow_id: i64,
+        target: DeployTarget,
+        workflow: Arc<DeployedWorkflow>,
+    ) {
+        let lock = self.locks[&target.name].clone();
+        let _guard = lock.lock().await;
+
+        // Workflows can complete out of order, or queue up behind a slow deployment;
+        // an older commit must not replace what a newer workflow deployed
+        match self.superseded_by(workflow_id, &target.name).await {
+            Ok(Some(newer)) => {
+                info!(
+                    "Skipping deployment {} to {}: workflow {} was deployed already",
+                    id, target.name, newer
+                );
+                let output = format!("Superseded by the deployment of workflow {}", newer);
+                self.finish(id, workflow_id, &target.name, "skipped", output)
+                    .await;
+                return;
+            }
+            Ok(None) => {}
+            Err(e) => warn!(
+                "Failed to look for newer deployments of {}: {}",
+                target.name, e
+            ),
+        }
+
+        if let Err(e) =
+            sqlx::query("UPDATE deployments SET status = 'running', started_at = ? WHERE id = ?")
+                .bind(chrono::Utc::now().timestamp())
+                .bind(id)
+                .execute(&self.db_pool)
+                .await
+        {
+            warn!("Failed to update deployment {}: {}", id, e);
+        }
+        self.publish(workflow_id, &target.name, "running");
+        info!(
+            "Deploying {} at {} to {}",
+            workflow.repository, workflow.commit_sha, target.name
+        );
+
+        let (status, output) =
+            match run_command(workflow_id, &target, &workflow, &self.credentials).await {
+                Ok(output) => ("success", output),
+                Err(e) => ("failed", format!("{:#}", e)),
+            };
+        if status == "success" {
+            info!("Deployment {} to {} succeeded", id, target.name);
+        } else {
+            error!("Deployment {} to {} failed", id, target.name);
+        }
+        self.finish(id, workflow_id, &target.name, status, output)
+            .await;
+    }
+
+    /// A newer workflow whose deployment to the target succeeded or is running
+    async fn superseded_by(&self, workflow_id: i64, target: &str) -> Result<Option<i64>> {
+        let newer: Option<i64> = sqlx::query_scalar(
+            r#"
+            SELECT MAX(workflow_id) FROM deployments
+            WHERE target = ? AND workflow_id > ? AND status IN ('success', 'running')
+            "#,
+        )
+        .bind(target)
+        .bind(workflow_id)
+        .fetch_one(&self.db_pool)
+        .await?;
+        Ok(newer)
+    }
+
+    async fn finish(&self, id: i64, workflow_id: i64, target: &str, status: &str, output: String) {
+        if let Err(e) = sqlx::query(
+            "UPDATE deployments SET status = ?, output = ?, finished_at = ? WHERE id = ?",
+        )
+        .bind(status)
+        .bind(output)
+        .bind(chrono::Utc::now().timestamp())
+        .bind(id)
+        .execute(&self.db_pool)
+        .await
+        {
+            warn!("Failed to update deployment {}: {}", id, e);
+        }
+        self.publish(workflow_id, target, status);
+    }
+
+    fn publish(&self, workflow_id: i64, target: &str, status: &str) {
+        self.events.publish(Event::DeploymentStatus {
+            workflow_id,
+            target: target.to_string(),
+            status: status.to_string(),
+        });
+    }
+}
+
+/// Run a target's command in a checkout of the workflow's commit, returning its output
+async fn run_command(
+    workflow_id: i64,
+    target: &DeployTarget,
+    workflow: &DeployedWorkflow,
+    credentials: &Credentials,
+) -> Result<String> {
+    let credential = credentials.get(&workflow.repository).await?;
+    let mut checkout = NixEvaluator::new();
+    checkout
+        .clone_repository(
+            &workflow.clone_url,
+            &workflow.commit_sha,
+            credential.as_ref(),
+        )
+        .await?;
+
+    let mut command = Command::new(&target.command[0]);
+    command
+        .args(&target.command[1..])
+        .current_dir(checkout.repo_path().unwrap())
+        .env("ICICLE_WORKFLOW_ID", workflow_id.to_string())
+        .env("ICICLE_REPOSITORY", &workflow.repository)
+        .env("ICICLE_BRANCH", &workflow.branch)
+        .env("ICICLE_COMMIT", &workflow.commit_sha)
+        .env("ICICLE_DEPLOY_TARGET", &target.name)
+        .kill_on_drop(true);
+    let output = tokio::time::timeout(Duration::from_secs(target.timeout_secs), command.output())
+        .await
+        .map_err(|_| anyhow!("Timed out after {} seconds", target.timeout_secs))?
+        .map_err(|e| anyhow!("Failed to run {}: {}", target.command[0], e))?;
+
+    let mut combined = String::from_utf8_lossy(&output.stdout).into_owned();
+    combined.push_str(&String::from_utf8_lossy(&output.stderr));
+    let combined = truncate(combined);
+    if !output.status.success() {
+        return Err(anyhow!("{}\n{}", output.status, combined));
+    }
+    Ok(combined)
+}
+
+/// Keep the end of the output, where errors usually are
+fn truncate(output: String) -> String {
+    if output.len() <= MAX_OUTPUT_LEN {
+        return output;
+    }
+    let mut start = output.len() - MAX_OUTPUT_LEN;
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("[truncated]\n{}", &output[start..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(name: &str, command: &str) -> DeployTarget {
+        DeployTarget {
+            name: name.to_string(),
+            repository: "me/site".to_string(),
+            branch: "main".to_string(),
+            command: vec!["sh".to_string(), "-c".to_string(), command.to_string()],
+            timeout_secs: 60,
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let workflow = |repository: &str, branch: &str| DeployedWorkflow {
+            repository: repository.to_string(),
+            branch: branch.to_string(),
+            commit_sha: "abc".to_string(),
+            clone_url: "https://example.com/me/site.git".to_string(),
+        };
+        let target = target("site", "true");
+        assert!(target.matches(&workflow("me/site", "main")));
+        assert!(!target.matches(&workflow("me/site", "dev")));
+        assert!(!target.matches(&workflow("me/other", "main")));
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("short".to_string()), "short");
+
+        let output = format!("{}{}", "a".repeat(10), "é".repeat(MAX_OUTPUT_LEN));
+        let truncated = truncate(output);
+        assert!(truncated.starts_with("[truncated]\né"));
+        assert!(truncated.len() <= MAX_OUTPUT_LEN + "[truncated]\n".len());
+        assert!(truncated.ends_with('é'));
+    }
+
+    /// A deployer with a target whose command logs when it starts and ends, and
+    /// two workflows of a local repository
+    async fn deployer(dir: &std::path::Path) -> (Arc<Deployer>, Arc<DeployedWorkflow>) {
+        let db_pool =
+            crate::db::init_database(&format!("sqlite:{}", dir.join("icicle.db").display()))
+                .await
+                .unwrap();
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(args)
+                .env("GIT_AUTHOR_NAME", "test")
+                .env("GIT_AUTHOR_EMAIL", "test@example.com")
+                .env("GIT_COMMITTER_NAME", "test")
+                .env("GIT_COMMITTER_EMAIL", "test@example.com")
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        let repo = dir.join("site");
+        let repo = repo.to_str().unwrap();
+        git(&["init", "-q", "-b", "main", repo]);
+        git(&["-C", repo, "commit", "-q", "--allow-empty", "-m", "init"]);
+        let head = std::process::Command::new("git")
+            .args(["-C", repo, "rev-parse", "HEAD"])
+            .output()
+            .unwrap();
+        let workflow = Arc::new(DeployedWorkflow {
+            repository: "me/site".to_string(),
+            branch: "main".to_string(),
+            commit_sha: String::from_utf8(head.stdout).unwrap().trim().to_string(),
+            clone_url: repo.to_string(),
+        });
+        for id in [1, 2] {
+            sqlx::query(
+                "INSERT INTO workflows (id, repository, commit_sha, attribute_set, status, created_at) \
+                 VALUES (?, 'me/site', ?, 'checks', 'Completed', 0)",
+            )
+            .bind(id)
+            .bind(&workflow.commit_sha)
+            .execute(&db_pool)
+            .await
+            .unwrap();
+        }
+
+        let log = dir.join("log");
+        let config = DeployConfig {
+            targets: vec![target(
+                "site",
+                &format!(
+                    "echo start $ICICLE_WORKFLOW_ID >> {0}; sleep 0.5; echo end $ICICLE_WORKFLOW_ID >> {0}",
+                    log.display()
+                ),
+            )],
+        };
+        let deployer = Deployer::new(
+            &config,
+            db_pool.clone(),
+            EventBus::new(),
+            Credentials::new(&Default::default(), db_pool),
+        );
+        (Arc::new(deployer), workflow)
+    }
+
+    async fn queue(deployer: &Deployer, workflow_id: i64) -> i64 {
+        sqlx::query(
+            "INSERT INTO deployments (workflow_id, target, status, created_at) VALUES (?, 'site', 'queued', 0)",
+        )
+        .bind(workflow_id)
+        .execute(&deployer.db_pool)
+        .await
+        .unwrap()
+        .last_insert_rowid()
+    }
+
+    async fn statuses(deployer: &Deployer) -> Vec<(i64, String)> {
+        sqlx::query_as("SELECT workflow_id, status FROM deployments ORDER BY id")
+            .fetch_all(&deployer.db_pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_deployments_do_not_overlap() {
+        let dir = tempfile::tempdir().unwrap();
+        let (deployer, workflow) = deployer(dir.path()).await;
+        let target = deployer.targets[0].clone();
+
+        let first = queue(&deployer, 1).await;
+        let second = queue(&deployer, 2).await;
+        tokio::join!(
+            deployer
+                .clone()
+                .deploy(first, 1, target.clone(), workflow.clone()),
+            deployer.clone().deploy(second, 2, target, workflow),
+        );
+
+        let log = std::fs::read_to_string(dir.path().join("log")).unwrap();
+        assert_eq!(log, "start 1\nend 1\nstart 2\nend 2\n");
+        assert_eq!(
+            statuses(&deployer).await,
+            [(1, "success".to_string()), (2, "success".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_older_workflow_is_not_deployed_over_a_newer_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let (deployer, workflow) = deployer(dir.path()).await;
+        let target = deployer.targets[0].clone();
+
+        let newer = queue(&deployer, 2).await;
+        deployer
+            .clone()
+            .deploy(newer, 2, target.clone(), workflow.clone())
+            .await;
+        let older = queue(&deployer, 1).await;
+        deployer.clone().deploy(older, 1, target, workflow).await;
+
+        let log = std::fs::read_to_string(dir.path().join("log")).unwrap();
+        assert_eq!(log, "start 2\nend 2\n");
+        assert_eq!(
+            statuses(&deployer).await,
+            [(2, "success".to_string()), (1, "skipped".to_string())]
+        );
+    }
+}
@@ -0,0 +1,357 @@
+use crate::nix::{self, DerivationContents};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::collections::{BTreeMap, BTreeSet};
+use tracing::info;
+
+/// Environment values are cut off beyond this many characters
+const MAX_VALUE_LEN: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    System,
+    Builder,
+    Args,
+    /// A derivation the build depends on
+    Input,
+    /// A source path copied into the store
+    Source,
+    Env,
+}
+
+impl std::fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChangeKind::System => write!(f, "system"),
+            ChangeKind::Builder => write!(f, "builder"),
+            ChangeKind::Args => write!(f, "args"),
+            ChangeKind::Input => write!(f, "input"),
+            ChangeKind::Source => write!(f, "source"),
+            ChangeKind::Env => write!(f, "env"),
+        }
+    }
+}
+
+/// Something that differs between the baseline derivation and the failed one.
+/// `old` is missing for additions and `new` for removals.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub name: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// The changes of a failed build since the last successful build of the same attribute
+#[derive(Debug, Serialize)]
+pub struct BuildDiff {
+    pub drv_path: String,
+    pub baseline_drv_path: String,
+    pub changes: Vec<Change>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct DiffRow {
+    drv_path: String,
+    baseline_drv_path: String,
+    changes: String,
+    created_at: i64,
+}
+
+/// The name of a store path, without the directory and hash
+fn store_name(path: &str) -> &str {
+    let basename = path.rsplit('/').next().unwrap_or(path);
+    match basename.split_once('-') {
+        Some((hash, name)) if hash.len() == 32 => name,
+        _ => basename,
+    }
+}
+
+/// Blank out the hashes of store paths, so values that only differ because
+/// an input changed compare equal
+fn without_hashes(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(i) = rest.find("/nix/store/") {
+        let (before, after) = rest.split_at(i + "/nix/store/".len());
+        result.push_str(before);
+        match after.get(..33) {
+            Some(hash) if hash.ends_with('-') => {
+                result.push_str("…-");
+                rest = &after[33..];
+            }
+            _ => rest = after,
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn truncate(value: &str) -> String {
+    match value.char_indices().nth(MAX_VALUE_LEN) {
+        Some((i, _)) => format!("{}…", &value[..i]),
+        None => value.to_string(),
+    }
+}
+
+fn path_change(kind: ChangeKind, name: &str, old: Option<&str>, new: Option<&str>) -> Change {
+    Change {
+        kind,
+        name: name.trim_end_matches(".drv").to_string(),
+        old: old.map(str::to_string),
+        new: new.map(str::to_string),
+    }
+}
+
+/// Compare store paths, pairing them by package name so a version bump reads as
+/// one change. Names that appear several times on a side are compared whole.
+fn diff_paths<'a>(
+    kind: ChangeKind,
+    old: impl Iterator<Item = &'a String>,
+    new: impl Iterator<Item = &'a String>,
+    changes: &mut Vec<Change>,
+) {
+    type Packages<'a> = BTreeMap<&'a str, Vec<&'a str>>;
+    let by_package = |paths: Vec<&'a String>| -> Packages<'a> {
+        let mut packages = Packages::new();
+        for path in paths {
+            let name = store_name(path).trim_end_matches(".drv");
+            packages
+                .entry(nix::split_version(name).0)
+                .or_default()
+                .push(path.as_str());
+        }
+        packages
+    };
+    let old = by_package(old.collect());
+    let new = by_package(new.collect());
+    let packages: BTreeSet<&str> = old.keys().chain(new.keys()).copied().collect();
+    for package in packages {
+        let old = old.get(package).map(Vec::as_slice).unwrap_or_default();
+        let new = new.get(package).map(Vec::as_slice).unwrap_or_default();
+        match (old, new) {
+            ([old], [new]) => {
+                if old != new {
+                    changes.push(path_change(kind, store_name(new), Some(old), Some(new)));
+                }
+            }
+            _ => {
+                for path in old.iter().filter(|path| !new.contains(path)) {
+                    changes.push(path_change(kind, store_name(path), Some(path), None));
+                }
+                for path in new.iter().filter(|path| !old.contains(path)) {
+                    changes.push(path_change(kind, store_name(path), None, Some(path)));
+                }
+            }
+        }
+    }
+}
+
+/// Compare two derivations the way nix-diff does, leaving out differences that
+/// only follow from changed inputs: output paths and store path hashes
+pub fn diff(old: &DerivationContents, new: &DerivationContents) -> Vec<Change> {
+    let mut changes = Vec::new();
+    let mut field = |kind: ChangeKind, old: &str, new: &str| {
+        if without_hashes(old) != without_hashes(new) {
+            changes.push(Change {
+                kind,
+                name: kind.to_string(),
+                old: Some(truncate(old)),
+                new: Some(truncate(new)),
+            });
+        }
+    };
+    field(ChangeKind::System, &old.system, &new.system);
+    field(ChangeKind::Builder, &old.builder, &new.builder);
+    field(ChangeKind::Args, &old.args.join(" "), &new.args.join(" "));
+
+    diff_paths(
+        ChangeKind::Input,
+        old.input_drvs.keys(),
+        new.input_drvs.keys(),
+        &mut changes,
+    );
+    diff_paths(
+        ChangeKind::Source,
+        old.input_srcs.iter(),
+        new.input_srcs.iter(),
+        &mut changes,
+    );
+
+    let names: BTreeSet<&String> = old.env.keys().chain(new.env.keys()).collect();
+    for name in names {
+        if old.outputs.contains_key(name) || new.outputs.contains_key(name) {
+            continue;
+        }
+        let (old, new) = (old.env.get(name), new.env.get(name));
+        let unchanged = match (old, new) {
+            (Some(old), Some(new)) => without_hashes(old) == without_hashes(new),
+            _ => false,
+        };
+        if !unchanged {
+            changes.push(Change {
+                kind: ChangeKind::Env,
+                name: name.clone(),
+                old: old.map(|value| truncate(value)),
+                new: new.map(|value| truncate(value)),
+            });
+        }
+    }
+    changes
+}
+
+/// The last successful build of the same attribute and system in one of the
+/// repositories that requested `drv_path`
+async fn find_baseline(
+    db_pool: &SqlitePool,
+    drv_path: &str,
+    name: &str,
+    system: &str,
+) -> Result<Option<String>> {
+    Ok(sqlx::query_scalar::<_, String>(
+        r#"
+        SELECT b.drv_path FROM builds b
+        WHERE b.name = ?1 AND b.system = ?2 AND b.status IN ('success', 'cached')
+          AND b.drv_path != ?3
+          AND EXISTS (
+            SELECT 1 FROM build_workflows bw JOIN workflows w ON w.id = bw.workflow_id
+            WHERE bw.drv_path = b.drv_path AND w.repository IN (
+                SELECT w.repository FROM build_workflows bw
+                JOIN workflows w ON w.id = bw.workflow_id
+                WHERE bw.drv_path = ?3
+            )
+          )
+        ORDER BY b.finished_at DESC
+        LIMIT 1
+        "#,
+    )
+    .bind(name)
+    .bind(system)
+    .bind(drv_path)
+    .fetch_optional(db_pool)
+    .await?)
+}
+
+/// Diff a failed build against the last successful build of the same attribute
+/// and store the result. Does nothing if the attribute never succeeded.
+pub async fn record(db_pool: &SqlitePool, drv_path: &str, name: &str, system: &str) -> Result<()> {
+    let Some(baseline) = find_baseline(db_pool, drv_path, name, system).await? else {
+        return Ok(());
+    };
+    let changes = diff(
+        &nix::show_derivation(&baseline).await?,
+        &nix::show_derivation(drv_path).await?,
+    );
+
+    sqlx::query(
+        r#"
+        INSERT OR REPLACE INTO build_diffs (drv_path, baseline_drv_path, changes, created_at)
+        VALUES (?, ?, ?, ?)
+        "#,
+    )
+    .bind(drv_path)
+    .bind(&baseline)
+    .bind(serde_json::to_string(&changes)?)
+    .bind(chrono::Utc::now().timestamp())
+    .execute(db_pool)
+    .await?;
+    info!(
+        "{} changed in {} ways since {} succeeded",
+        drv_path,
+        changes.len(),
+        baseline
+    );
+    Ok(())
+}
+
+/// The stored diff of a failed build, if it has one
+pub async fn load(db_pool: &SqlitePool, drv_path: &str) -> Result<Option<BuildDiff>> {
+    let row = sqlx::query_as::<_, DiffRow>(
+        r#"
+        SELECT drv_path, baseline_drv_path, changes, created_at
+        FROM build_diffs WHERE drv_path = ?
+        "#,
+    )
+    .bind(drv_path)
+    .fetch_optional(db_pool)
+    .await?;
+    row.map(|row| {
+        Ok(BuildDiff {
+            changes: serde_json::from_str(&row.changes)?,
+            drv_path: row.drv_path,
+            baseline_drv_path: row.baseline_drv_path,
+            created_at: row.created_at,
+        })
+    })
+    .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn derivation(json: &str) -> DerivationContents {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = derivation(
+            r#"{
+              "builder": "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2/bin/bash",
+              "args": ["-e", "builder.sh"],
+              "system": "x86_64-linux",
+              "env": {
+                "out": "/nix/store/cccccccccccccccccccccccccccccccc-hello-2.12",
+                "buildInputs": "/nix/store/dddddddddddddddddddddddddddddddd-openssl-3.0.13",
+                "doCheck": "1",
+                "removed": "x"
+              },
+              "inputDrvs": {
+                "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-openssl-3.0.13.drv": ["out"],
+                "/nix/store/ffffffffffffffffffffffffffffffff-bash-5.2.drv": ["out"]
+              },
+              "inputSrcs": ["/nix/store/gggggggggggggggggggggggggggggggg-builder.sh"],
+              "outputs": {"out": {"path": "/nix/store/cccccccccccccccccccccccccccccccc-hello-2.12"}}
+            }"#,
+        );
+        let new = derivation(
+            r#"{
+              "builder": "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-bash-5.2/bin/bash",
+              "args": ["-e", "builder.sh"],
+              "system": "x86_64-linux",
+              "env": {
+                "out": "/nix/store/hhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhh-hello-2.12",
+                "buildInputs": "/nix/store/iiiiiiiiiiiiiiiiiiiiiiiiiiiiiiii-openssl-3.0.14",
+                "doCheck": "1",
+                "added": "y"
+              },
+              "inputDrvs": {
+                "/nix/store/jjjjjjjjjjjjjjjjjjjjjjjjjjjjjjjj-openssl-3.0.14.drv": {"outputs": ["out"]},
+                "/nix/store/ffffffffffffffffffffffffffffffff-bash-5.2.drv": {"outputs": ["out"]}
+              },
+              "inputSrcs": ["/nix/store/gggggggggggggggggggggggggggggggg-builder.sh"],
+              "outputs": {"out": {"path": "/nix/store/hhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhh-hello-2.12"}}
+            }"#,
+        );
+
+        let changes = diff(&old, &new);
+        let summary: Vec<(ChangeKind, &str, bool, bool)> = changes
+            .iter()
+            .map(|c| (c.kind, c.name.as_str(), c.old.is_some(), c.new.is_some()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (ChangeKind::Input, "openssl-3.0.14", true, true),
+                (ChangeKind::Env, "added", false, true),
+                (ChangeKind::Env, "buildInputs", true, true),
+                (ChangeKind::Env, "removed", true, false),
+            ]
+        );
+        assert!(diff(&old, &old).is_empty());
+    }
+}
@@ -0,0 +1,202 @@
+use crate::build::{BuildJob, BuildStatus};
+use anyhow::Result;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+
+/// Weight of the latest duration in the running average, so estimates follow
+/// builds getting slower or faster within a few runs
+const WEIGHT: f64 = 0.3;
+
+/// Record how long a successful build of an attribute took
+pub async fn record(db_pool: &SqlitePool, attr: &str, system: &str, secs: i64) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO build_durations (attr, system, avg_secs, last_secs, samples, updated_at)
+        VALUES (?1, ?2, ?3, ?3, 1, ?4)
+        ON CONFLICT(attr, system) DO UPDATE
+        SET avg_secs = avg_secs + (excluded.last_secs - avg_secs) * ?5,
+            last_secs = excluded.last_secs, samples = samples + 1,
+            updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(attr)
+    .bind(system)
+    .bind(secs.max(0))
+    .bind(chrono::Utc::now().timestamp())
+    .bind(WEIGHT)
+    .execute(db_pool)
+    .await?;
+    Ok(())
+}
+
+/// Expected build durations of attributes, by attribute and system
+#[derive(Debug, Default)]
+pub struct Estimates(HashMap<(String, String), f64>);
+
+impl Estimates {
+    /// The estimates of the given jobs' attributes
+    pub async fn load(db_pool: &SqlitePool, jobs: &[BuildJob]) -> Result<Self> {
+        let attrs: Vec<&str> = jobs
+            .iter()
+            .map(|job| job.derivation.name.as_str())
+            .collect();
+        let rows = sqlx::query_as::<_, (String, String, f64)>(
+            r#"
+            SELECT attr, system, avg_secs FROM build_durations
+            WHERE attr IN (SELECT value FROM json_each(?))
+            "#,
+        )
+        .bind(serde_json::to_string(&attrs)?)
+        .fetch_all(db_pool)
+        .await?;
+        Ok(Estimates(
+            rows.into_iter()
+                .map(|(attr, system, secs)| ((attr, system), secs))
+                .collect(),
+        ))
+    }
+
+    /// Seconds the job still takes: 0 once done or when found in the cache, the
+    /// estimate less what already went by while it runs. None when unknown.
+    pub fn remaining(&self, job: &BuildJob, now: i64) -> Option<i64> {
+        if job.status.done() || job.derivation.cached == Some(true) {
+            return Some(0);
+        }
+        let derivation = &job.derivation;
+        let expected = *self
+            .0
+            .get(&(derivation.name.clone(), derivation.system.clone()))?;
+        let elapsed = match (job.status, job.started_at) {
+            (BuildStatus::Running, Some(started_at)) => now - started_at,
+            _ => 0,
+        };
+        Some((expected.round() as i64 - elapsed).max(0))
+    }
+
+    /// Seconds until the unfinished jobs of a workflow are done, following the
+    /// longest chain of dependencies and assuming there are enough builders for
+    /// the rest to run alongside. None when a job was never built before.
+    pub fn workflow_remaining(&self, jobs: &[&BuildJob], now: i64) -> Option<i64> {
+        let by_drv: HashMap<&str, &BuildJob> = jobs
+            .iter()
+            .map(|job| (job.derivation.drv_path.as_str(), *job))
+            .collect();
+        let mut finish: HashMap<&str, i64> = HashMap::new();
+        let mut longest = 0;
+        for job in jobs {
+            longest = longest.max(self.finish(job, &by_drv, &mut finish, now)?);
+        }
+        Some(longest)
+    }
+
+    /// Seconds until a job is done, after the jobs it depends on
+    fn finish<'a>(
+        &self,
+        job: &'a BuildJob,
+        by_drv: &HashMap<&'a str, &'a BuildJob>,
+        finish: &mut HashMap<&'a str, i64>,
+        now: i64,
+    ) -> Option<i64> {
+        let drv_path = job.derivation.drv_path.as_str();
+        if let Some(&secs) = finish.get(drv_path) {
+            return Some(secs);
+        }
+        let mut inputs = 0;
+        if !job.status.done() {
+            for input in &job.derivation.input_drvs {
+                if let Some(input) = by_drv.get(input.as_str()) {
+                    inputs = inputs.max(self.finish(input, by_drv, finish, now)?);
+                }
+            }
+        }
+        let secs = inputs + self.remaining(job, now)?;
+        finish.insert(drv_path, secs);
+        Some(secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::Derivation;
+    use std::collections::HashSet;
+
+    fn job(name: &str, inputs: &[&str], status: BuildStatus) -> BuildJob {
+        BuildJob {
+            derivation: Derivation {
+                name: name.to_string(),
+                drv_path: format!("/nix/store/{}.drv", name),
+                outputs: vec![format!("/nix/store/{}", name)],
+                system: "x86_64-linux".to_string(),
+                input_drvs: inputs
+                    .iter()
+                    .map(|input| format!("/nix/store/{}.drv", input))
+                    .collect(),
+                status,
+                licenses: Vec::new(),
+                timeout_secs: None,
+                required_features: Vec::new(),
+                constituents: None,
+                cached: None,
+            },
+            status,
+            requested_by: HashSet::new(),
+            priority: 0,
+            progress: None,
+            retries: 0,
+            ready_at: None,
+            started_at: None,
+        }
+    }
+
+    fn estimates(secs: &[(&str, f64)]) -> Estimates {
+        Estimates(
+            secs.iter()
+                .map(|&(name, secs)| ((name.to_string(), "x86_64-linux".to_string()), secs))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_remaining() {
+        let estimates = estimates(&[("app", 100.0)]);
+        let mut running = job("app", &[], BuildStatus::Running);
+        running.started_at = Some(1000);
+        assert_eq!(estimates.remaining(&running, 1030), Some(70));
+        assert_eq!(estimates.remaining(&running, 1200), Some(0));
+        assert_eq!(
+            estimates.remaining(&job("app", &[], BuildStatus::Queued), 0),
+            Some(100)
+        );
+        assert_eq!(
+            estimates.remaining(&job("app", &[], BuildStatus::Success), 0),
+            Some(0)
+        );
+        assert_eq!(
+            estimates.remaining(&job("new", &[], BuildStatus::Queued), 0),
+            None
+        );
+    }
+
+    #[test]
+    fn test_workflow_remaining() {
+        let estimates = estimates(&[("lib", 60.0), ("app", 30.0), ("docs", 50.0)]);
+        let lib = job("lib", &[], BuildStatus::Ready);
+        let app = job("app", &["lib"], BuildStatus::Queued);
+        let docs = job("docs", &[], BuildStatus::Ready);
+        // lib then app outlasts docs, built alongside
+        assert_eq!(
+            estimates.workflow_remaining(&[&app, &lib, &docs], 0),
+            Some(90)
+        );
+
+        let lib = job("lib", &[], BuildStatus::Success);
+        assert_eq!(
+            estimates.workflow_remaining(&[&app, &lib, &docs], 0),
+            Some(50)
+        );
+
+        let new = job("new", &["lib"], BuildStatus::Queued);
+        assert_eq!(estimates.workflow_remaining(&[&new, &lib], 0), None);
+    }
+}
@@ -0,0 +1,125 @@
+use crate::build::{BuildStatus, WorkflowStatus};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Number of events buffered per subscriber before it starts lagging
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// A status transition somewhere in the system
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    WorkflowCreated {
+        workflow_id: i64,
+        repository: String,
+        commit_sha: String,
+    },
+    WorkflowStatus {
+        workflow_id: i64,
+        status: WorkflowStatus,
+    },
+    StageStatus {
+        workflow_id: i64,
+        stage: String,
+        status: String,
+    },
+    DeploymentStatus {
+        workflow_id: i64,
+        target: String,
+        status: String,
+    },
+    BisectionStatus {
+        workflow_id: i64,
+        bisection_id: i64,
+        status: String,
+        culprit_commit: Option<String>,
+    },
+    JobStatus {
+        drv_path: String,
+        name: String,
+        status: BuildStatus,
+        workflows: Vec<i64>,
+    },
+    Paused {
+        paused: bool,
+    },
+}
+
+/// The `type` of every event, as serialized
+pub const EVENT_KINDS: &[&str] = &[
+    "workflow_created",
+    "workflow_status",
+    "stage_status",
+    "deployment_status",
+    "bisection_status",
+    "job_status",
+    "paused",
+];
+
+impl Event {
+    /// The `type` the event is serialized with
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::WorkflowCreated { .. } => "workflow_created",
+            Event::WorkflowStatus { .. } => "workflow_status",
+            Event::StageStatus { .. } => "stage_status",
+            Event::DeploymentStatus { .. } => "deployment_status",
+            Event::BisectionStatus { .. } => "bisection_status",
+            Event::JobStatus { .. } => "job_status",
+            Event::Paused { .. } => "paused",
+        }
+    }
+
+    /// The workflow the event is about, if it is about a single one
+    pub fn workflow_id(&self) -> Option<i64> {
+        match self {
+            Event::WorkflowCreated { workflow_id, .. }
+            | Event::WorkflowStatus { workflow_id, .. }
+            | Event::StageStatus { workflow_id, .. }
+            | Event::DeploymentStatus { workflow_id, .. }
+            | Event::BisectionStatus { workflow_id, .. } => Some(*workflow_id),
+            Event::JobStatus { .. } | Event::Paused { .. } => None,
+        }
+    }
+
+    /// Whether this event is relevant to the given workflow
+    pub fn concerns_workflow(&self, id: i64) -> bool {
+        match self {
+            Event::WorkflowCreated { workflow_id, .. } => *workflow_id == id,
+            Event::WorkflowStatus { workflow_id, .. } => *workflow_id == id,
+            Event::StageStatus { workflow_id, .. } => *workflow_id == id,
+            Event::DeploymentStatus { workflow_id, .. } => *workflow_id == id,
+            Event::BisectionStatus { workflow_id, .. } => *workflow_id == id,
+            Event::JobStatus { workflows, .. } => workflows.contains(&id),
+            Event::Paused { .. } => false,
+        }
+    }
+}
+
+/// Broadcast channel carrying events from the queue and executor to any listener
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    /// Publish an event. Events are dropped if nobody is listening.
+    pub fn publish(&self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,19 +1,84 @@
 use crate::{
+    artifacts::ArtifactStore,
     build::{BuildJob, BuildQueue, BuildStatus},
+    builders::{self, Builders},
     cache::CacheClient,
+    config::BuildConfig,
+    drvdiff, durations,
+    gc::GcRoots,
+    hints::{self, Cause},
+    junit, nix,
+    pipeline::Pipeline,
+    progress::LogParser,
+    provenance::Attestor,
+    quota, sbom, stats,
+    upload::UploadQueue,
+    workers::{WorkerSlot, Workers},
 };
 use sqlx::SqlitePool;
-use std::{collections::VecDeque, sync::Arc};
-use tokio::sync::Semaphore;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, ChildStderr, Command};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
 use tokio::time::{timeout, Duration};
-use tracing::{error, info, warn};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, info_span, warn, Instrument};
+
+/// How long stopped builds get to exit on SIGTERM before they are killed
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+/// Longest wait before retrying a build
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(3600);
 
 pub struct BuildExecutor {
     build_queue: Arc<BuildQueue>,
     db_pool: SqlitePool,
     cache_client: CacheClient,
-    max_concurrent_builds: usize,
+    gc_roots: Arc<GcRoots>,
+    /// Uploads the outputs of successful builds
+    uploads: Arc<UploadQueue>,
+    artifact_store: ArtifactStore,
+    pipeline: Pipeline,
+    /// Signs provenance for successful builds, if enabled
+    attestor: Option<Attestor>,
+    max_concurrent_builds: AtomicUsize,
+    semaphore: Arc<Semaphore>,
+    /// Systems built locally; any system without a remote builder when empty
+    local_systems: Vec<String>,
+    /// System features of this machine
+    local_features: Vec<String>,
+    builders: Builders,
+    workers: Workers,
+    /// Notified when a build gives its slot back
+    slot_freed: Notify,
     build_timeout: Duration,
+    /// Upper bound for the timeouts of derivations
+    max_build_timeout: Option<Duration>,
+    /// Times a build failing for a transient reason is retried
+    max_retries: u32,
+    /// Wait before the first retry, doubled for each further one
+    retry_backoff: Duration,
+    /// Process group of each running nix-build, which includes the builders it started
+    processes: Mutex<HashMap<String, i32>>,
+    /// Cancelled on shutdown to stop starting builds
+    stopping: CancellationToken,
+}
+
+/// Where a job is built
+enum Target {
+    Local,
+    /// On the given machine of nix's `builders` setting
+    Remote(String),
+    /// By whichever `icicle worker` leases it
+    Worker(WorkerSlot),
+    /// Nothing can build the job's system with its features, so it fails unless it
+    /// is cached
+    Missing(String),
 }
 
 impl BuildExecutor {
@@ -21,63 +86,239 @@ impl BuildExecutor {
         build_queue: Arc<BuildQueue>,
         db_pool: SqlitePool,
         cache_client: CacheClient,
-        max_concurrent_builds: usize,
-        build_timeout_secs: u64,
+        gc_roots: Arc<GcRoots>,
+        artifact_store: ArtifactStore,
+        pipeline: Pipeline,
+        config: &BuildConfig,
     ) -> Self {
+        build_queue.set_scheduling(config.scheduling);
+        let uploads = UploadQueue::new(
+            db_pool.clone(),
+            cache_client.clone(),
+            gc_roots.clone(),
+            config,
+        );
         Self {
             build_queue,
             db_pool,
             cache_client,
-            max_concurrent_builds,
-            build_timeout: Duration::from_secs(build_timeout_secs),
+            gc_roots,
+            uploads: Arc::new(uploads),
+            artifact_store,
+            pipeline,
+            attestor: None,
+            max_concurrent_builds: AtomicUsize::new(config.max_concurrent_builds),
+            semaphore: Arc::new(Semaphore::new(config.max_concurrent_builds)),
+            local_systems: config.local_systems.clone(),
+            local_features: config.local_features(),
+            builders: Builders::default(),
+            workers: Workers::new(Default::default()),
+            slot_freed: Notify::new(),
+            processes: Mutex::default(),
+            stopping: CancellationToken::new(),
+            build_timeout: Duration::from_secs(config.build_timeout_secs),
+            max_build_timeout: config.max_build_timeout_secs.map(Duration::from_secs),
+            max_retries: config.max_retries,
+            retry_backoff: Duration::from_secs(config.retry_backoff_secs),
         }
     }
 
-    /// Start the build executor loop
+    /// Attest every successful build
+    pub fn with_provenance(mut self, attestor: Attestor) -> Self {
+        self.attestor = Some(attestor);
+        self
+    }
+
+    /// Upload through a queue shared with the API, e.g. for re-uploads
+    pub fn with_uploads(mut self, uploads: Arc<UploadQueue>) -> Self {
+        self.uploads = uploads;
+        self
+    }
+
+    /// Send jobs of the systems of remote builders to them
+    pub fn with_builders(mut self, builders: Builders) -> Self {
+        self.builders = builders;
+        self
+    }
+
+    /// Hand jobs of the systems of registered workers to them
+    pub fn with_workers(mut self, workers: Workers) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// Change the number of concurrent builds; running builds are never interrupted
+    pub fn set_max_concurrent_builds(&self, max: usize) {
+        let previous = self.max_concurrent_builds.swap(max, Ordering::SeqCst);
+        if max > previous {
+            self.semaphore.add_permits(max - previous);
+            self.slot_freed.notify_one();
+        } else if max < previous {
+            // Take the surplus permits out of circulation as builds release them
+            let semaphore = self.semaphore.clone();
+            let surplus = (previous - max) as u32;
+            tokio::spawn(async move {
+                if let Ok(permits) = semaphore.acquire_many_owned(surplus).await {
+                    permits.forget();
+                }
+            });
+        }
+        info!("Max concurrent builds changed from {} to {}", previous, max);
+    }
+
+    /// Stop starting builds; running builds carry on (see `drain`)
+    pub fn stop(&self) {
+        self.stopping.cancel();
+    }
+
+    /// Wait up to `timeout` for the running builds to finish
+    pub async fn drain(&self, drain_timeout: Duration) {
+        let max = self.max_concurrent_builds.load(Ordering::SeqCst);
+        let running = max.saturating_sub(self.semaphore.available_permits())
+            + self.builders.running()
+            + self.workers.running();
+        if running == 0 {
+            return;
+        }
+        info!("Waiting for {} running builds to finish", running);
+        // All permits are back once every running build has released its own
+        let all_released = async {
+            let _ = self.semaphore.acquire_many(max as u32).await;
+            self.builders.wait_idle().await;
+            self.workers.wait_idle().await;
+        };
+        match timeout(drain_timeout, all_released).await {
+            Ok(_) => info!("All running builds finished"),
+            Err(_) => {
+                warn!(
+                    "Running builds did not finish within {}s, killing them",
+                    drain_timeout.as_secs()
+                );
+                for (_, pgid) in self.processes.lock().unwrap().drain() {
+                    signal_group(pgid, libc::SIGKILL);
+                }
+            }
+        }
+    }
+
+    /// Start the build executor loop, until `stop` is called
     pub async fn run(self: Arc<Self>) {
+        self.uploads.resume().await;
+        let stopping = self.stopping.clone();
+        tokio::select! {
+            _ = self.schedule() => {}
+            _ = stopping.cancelled() => info!("Build executor stopped starting builds"),
+        }
+    }
+
+    async fn schedule(self: Arc<Self>) {
         info!(
             "Build executor started with max {} concurrent builds",
-            self.max_concurrent_builds
+            self.max_concurrent_builds.load(Ordering::SeqCst)
         );
 
-        let mut run_queue = VecDeque::new();
-        let semaphore = Arc::new(Semaphore::new(self.max_concurrent_builds));
         loop {
-            if run_queue.is_empty() {
-                self.build_queue.wait_for_ready_jobs().await;
-                run_queue.extend(self.build_queue.drain_ready_jobs());
+            if self.build_queue.is_paused() {
+                info!("Build executor paused, holding ready jobs");
+                self.build_queue.wait_until_resumed().await;
+                info!("Build executor resumed");
             }
-            info!("Got {} jobs to run", run_queue.len());
-            assert!(!run_queue.is_empty());
-            let job = run_queue.pop_front().unwrap();
+            // Pick the job once a slot for its system is free, so jobs that became
+            // ready meanwhile can go first
+            let (job, (target, permit)) = loop {
+                if let Some(taken) = self
+                    .build_queue
+                    .pop_ready_job_with(|job| self.take_slot(job))
+                {
+                    break taken;
+                }
+                tokio::select! {
+                    _ = self.build_queue.wait_for_ready_jobs() => {}
+                    _ = self.slot_freed.notified() => {}
+                    _ = self.builders.changed() => {}
+                    _ = self.workers.changed() => {}
+                }
+            };
             if job.status.error() {
                 continue;
             }
             assert!(job.status == BuildStatus::Ready);
-            let permit = semaphore.clone().acquire_owned().await.unwrap();
             let executor = self.clone();
-            tokio::spawn(async move {
-                let res = executor.execute_build(job.clone()).await;
-                drop(permit);
-                if let Err(e) = res {
-                    error!(
-                        "Build execution error for {}: {}",
-                        job.derivation.drv_path, e
-                    );
+            let span = info_span!(
+                "build",
+                drv = %job.derivation.drv_path,
+                workflows = ?job.requested_by,
+            );
+            tokio::spawn(
+                async move {
+                    let res = executor.execute_build(job.clone(), target).await;
+                    if permit.is_some() {
+                        drop(permit);
+                        executor.slot_freed.notify_one();
+                    }
+                    if let Err(e) = res {
+                        error!(
+                            "Build execution error for {}: {}",
+                            job.derivation.drv_path, e
+                        );
+                    }
                 }
-            });
+                .instrument(span),
+            );
+        }
+    }
+
+    /// Take a free slot of a builder of the job's system, if any
+    fn take_slot(&self, job: &BuildJob) -> Option<(Target, Option<OwnedSemaphorePermit>)> {
+        // Failed jobs are only taken to be skipped
+        if job.status.error() {
+            return Some((Target::Local, None));
+        }
+        let system = &job.derivation.system;
+        let features = &job.derivation.required_features;
+        let remote =
+            self.builders.builds(system, features) || self.workers.builds(system, features);
+        if let Some((machine, permit)) = self.builders.take(system, features) {
+            return Some((Target::Remote(machine), Some(permit)));
+        }
+        if let Some(slot) = self.workers.reserve(system, features) {
+            return Some((Target::Worker(slot), None));
+        }
+        if builders::builds_locally(
+            &self.local_systems,
+            &self.local_features,
+            remote,
+            system,
+            features,
+        ) {
+            let permit = self.semaphore.clone().try_acquire_owned().ok()?;
+            return Some((Target::Local, Some(permit)));
         }
+        if remote {
+            // Waits for a builder or worker of its system to be free or back up
+            return None;
+        }
+        if features.is_empty() {
+            return Some((Target::Missing(system.clone()), None));
+        }
+        let missing = format!("{} with {}", system, features.join(", "));
+        Some((Target::Missing(missing), None))
     }
 
     /// Execute a single build
-    async fn execute_build(&self, job: BuildJob) -> anyhow::Result<()> {
-        let drv_path = job.derivation.drv_path;
-        info!("Checking cache status for derivation: {}", drv_path);
-        let status = if self
-            .cache_client
-            .derivation_cached(&job.derivation.outputs)
-            .await?
-        {
+    async fn execute_build(&self, job: BuildJob, target: Target) -> anyhow::Result<()> {
+        let drv_path = job.derivation.drv_path.clone();
+        // Its workflow usually checked the cache for all its jobs at once already
+        let cached = match job.derivation.cached {
+            Some(cached) => cached,
+            None => {
+                info!("Checking cache status for derivation: {}", drv_path);
+                self.cache_client
+                    .derivation_cached(&job.derivation.outputs)
+                    .await?
+            }
+        };
+        let status = if cached {
             info!("Derivation {} is cached", drv_path);
             BuildStatus::Cached
         } else {
@@ -85,27 +326,27 @@ impl BuildExecutor {
             BuildStatus::Running
         };
 
-        let completed_workflows = self.build_queue.update_status(&drv_path, status);
-
-        // Handle workflow completions
-        for workflow_id in completed_workflows {
-            self.handle_workflow_completion(workflow_id).await;
-        }
-
-        // Update database
+        // Update database before the queue, so workflow completion sees the build
         let now = chrono::Utc::now().timestamp();
+        let finished_at = (status == BuildStatus::Cached).then_some(now);
         if let Err(e) = sqlx::query(
             r#"
-                INSERT INTO builds (drv_path, name, system, status, started_at)
-                VALUES (?, ?, ?, 'Running', ?)
-                ON CONFLICT(drv_path) DO UPDATE SET status = 'Running', started_at = ?
+                INSERT INTO builds (drv_path, name, system, status, started_at, finished_at, retries)
+                VALUES (?, ?, ?, ?, ?, ?, ?)
+                ON CONFLICT(drv_path) DO UPDATE
+                SET status = excluded.status, started_at = excluded.started_at,
+                    finished_at = excluded.finished_at, error_message = NULL,
+                    failure_cause = NULL, retries = excluded.retries, signed = NULL,
+                    upload_status = NULL, upload_error = NULL
                 "#,
         )
         .bind(&drv_path)
         .bind(&job.derivation.name)
         .bind(&job.derivation.system)
+        .bind(status.to_string())
         .bind(now)
-        .bind(now)
+        .bind(finished_at)
+        .bind(job.retries)
         .execute(&self.db_pool)
         .await
         {
@@ -127,183 +368,465 @@ impl BuildExecutor {
                 warn!("Failed to link build to workflow: {}", e);
             }
         }
+
+        let completed_workflows = self.build_queue.update_status(&drv_path, status);
+
+        // Handle workflow completions
+        for workflow_id in completed_workflows {
+            self.pipeline
+                .jobs_finished(workflow_id)
+                .instrument(info_span!("workflow", workflow_id))
+                .await;
+        }
         if status == BuildStatus::Cached {
+            if let Err(e) = stats::record_build(&self.db_pool, &job, status, now, now).await {
+                warn!("Failed to record the run of {}: {}", drv_path, e);
+            }
             return Ok(());
         }
 
         info!("Starting build for derivation: {}", drv_path);
-        // Execute the build with timeout
-        let result = timeout(self.build_timeout, self.run_nix_build(&drv_path)).await;
+        // Execute the build with timeout, unless every workflow needing it is canceled
+        let canceled = self.build_queue.cancellation(&drv_path);
+        let build_timeout = self.build_timeout(&job);
+        let result = tokio::select! {
+            result = timeout(build_timeout, self.run_nix_build(&drv_path, &target)) => {
+                Some(result)
+            }
+            _ = canceled.cancelled() => None,
+        };
+        // Dropping the unfinished build stopped its processes, see `BuildProcess`
 
         // Process result and update status
         let (final_status, error_message) = match result {
-            Ok(Ok(())) => {
+            None => {
+                info!("Build canceled: {}", drv_path);
+                (
+                    BuildStatus::Canceled,
+                    Some("Canceled as no workflow needs it anymore".to_string()),
+                )
+            }
+            Some(Ok(Ok(()))) => {
                 info!("Build succeeded: {}", drv_path);
 
-                // Upload to cache
-                if let Err(e) = self.upload_to_cache(&drv_path).await {
-                    warn!("Failed to upload {} to cache: {}", drv_path, e);
-                }
+                self.collect_reports(&job, now).await;
+                // Without holding the build slot; releases the outputs' roots when done
+                self.uploads.enqueue(&drv_path).await;
 
                 (BuildStatus::Success, None)
             }
-            Ok(Err(e)) => {
+            Some(Ok(Err(e))) => {
                 error!("Build failed for {}: {}", drv_path, e);
                 (BuildStatus::Failed, Some(e.to_string()))
             }
-            Err(_) => {
+            Some(Err(_)) => {
                 error!("Build timed out for {}", drv_path);
                 (
                     BuildStatus::Failed,
                     Some(format!(
                         "Build timed out after {} seconds",
-                        self.build_timeout.as_secs()
+                        build_timeout.as_secs()
                     )),
                 )
             }
         };
 
+        if final_status != BuildStatus::Success {
+            self.gc_roots.build_finished(&drv_path, false);
+        }
+
+        let failure_cause = error_message.as_deref().and_then(hints::analyze);
+        if let Some(cause) = failure_cause {
+            info!(
+                "{} likely failed because of: {}{}",
+                drv_path,
+                cause.label(),
+                if cause.infrastructure() {
+                    " (infrastructure)"
+                } else {
+                    ""
+                }
+            );
+        }
+
+        let finished_at = chrono::Utc::now().timestamp();
+        if let Err(e) = quota::record_usage(
+            &self.db_pool,
+            &drv_path,
+            &job.requested_by,
+            finished_at - now,
+            finished_at,
+        )
+        .await
+        {
+            warn!("Failed to record the build time of {}: {}", drv_path, e);
+        }
+
+        let transient = final_status == BuildStatus::Failed
+            && failure_cause.is_some_and(Cause::transient)
+            && job.retries < self.max_retries;
+        if transient {
+            // Taken before the job is held, as retries only happen while it is needed
+            let canceled = self.build_queue.cancellation(&drv_path);
+            if let Some(retries) = self.build_queue.hold_for_retry(&drv_path) {
+                self.retry_later(&drv_path, retries, canceled, &error_message, failure_cause)
+                    .await;
+                return Ok(());
+            }
+        }
+
+        if final_status == BuildStatus::Failed {
+            // Usually explains the failure when the attribute built before
+            let derivation = &job.derivation;
+            if let Err(e) = drvdiff::record(
+                &self.db_pool,
+                &drv_path,
+                &derivation.name,
+                &derivation.system,
+            )
+            .await
+            {
+                warn!("Failed to diff {} with its last success: {:#}", drv_path, e);
+            }
+        }
+
+        if let Err(e) =
+            stats::record_build(&self.db_pool, &job, final_status, now, finished_at).await
+        {
+            warn!("Failed to record the run of {}: {}", drv_path, e);
+        }
+        if final_status == BuildStatus::Success {
+            let derivation = &job.derivation;
+            if let Err(e) = durations::record(
+                &self.db_pool,
+                &derivation.name,
+                &derivation.system,
+                finished_at - now,
+            )
+            .await
+            {
+                warn!("Failed to record the duration of {}: {}", drv_path, e);
+            }
+        }
+
+        // Update database before the queue, so workflow completion sees the final status
+        if let Err(e) = sqlx::query(
+            r#"
+            UPDATE builds
+            SET status = ?, finished_at = ?, error_message = ?, failure_cause = ?
+            WHERE drv_path = ?
+            "#,
+        )
+        .bind(final_status.to_string())
+        .bind(finished_at)
+        .bind(&error_message)
+        .bind(failure_cause.map(Cause::as_str))
+        .bind(&drv_path)
+        .execute(&self.db_pool)
+        .await
+        {
+            warn!("Failed to update final build status in database: {}", e);
+        }
+
         // Update queue status
         let completed_workflows = self.build_queue.update_status(&drv_path, final_status);
 
         // Handle workflow completions
         for workflow_id in completed_workflows {
-            self.handle_workflow_completion(workflow_id).await;
+            self.pipeline
+                .jobs_finished(workflow_id)
+                .instrument(info_span!("workflow", workflow_id))
+                .await;
         }
 
-        // Update database
-        let finished_at = chrono::Utc::now().timestamp();
+        Ok(())
+    }
+
+    /// Make a job held for its `retries`th retry ready again after the backoff,
+    /// unless no workflow needs it anymore by then
+    async fn retry_later(
+        &self,
+        drv_path: &str,
+        retries: u32,
+        canceled: CancellationToken,
+        error_message: &Option<String>,
+        failure_cause: Option<Cause>,
+    ) {
+        let backoff = self.retry_backoff(retries);
+        warn!(
+            "Retrying {} in {}s ({} of {} retries)",
+            drv_path,
+            backoff.as_secs(),
+            retries,
+            self.max_retries
+        );
         if let Err(e) = sqlx::query(
             r#"
             UPDATE builds
-            SET status = ?, finished_at = ?, error_message = ?
+            SET status = ?, finished_at = NULL, error_message = ?, failure_cause = ?, retries = ?
             WHERE drv_path = ?
             "#,
         )
-        .bind(final_status.to_string())
-        .bind(finished_at)
+        .bind(BuildStatus::Queued.to_string())
         .bind(error_message)
+        .bind(failure_cause.map(Cause::as_str))
+        .bind(retries)
         .bind(drv_path)
         .execute(&self.db_pool)
         .await
         {
-            warn!("Failed to update final build status in database: {}", e);
+            warn!(
+                "Failed to record the retry of {} in database: {}",
+                drv_path, e
+            );
         }
 
-        Ok(())
+        let build_queue = self.build_queue.clone();
+        let db_pool = self.db_pool.clone();
+        let drv_path = drv_path.to_string();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => build_queue.retry(&drv_path),
+                _ = canceled.cancelled() => {
+                    info!("Retry canceled: {}", drv_path);
+                    if let Err(e) = sqlx::query(
+                        "UPDATE builds SET status = ?, finished_at = ?, error_message = ? \
+                         WHERE drv_path = ?",
+                    )
+                    .bind(BuildStatus::Canceled.to_string())
+                    .bind(chrono::Utc::now().timestamp())
+                    .bind("Canceled as no workflow needs it anymore")
+                    .bind(&drv_path)
+                    .execute(&db_pool)
+                    .await
+                    {
+                        warn!("Failed to update build status in database: {}", e);
+                    }
+                }
+            }
+        });
     }
 
-    /// Run nix-build for a derivation
-    async fn run_nix_build(&self, drv_path: &str) -> anyhow::Result<()> {
-        info!("Executing: nix-build {}", drv_path);
+    /// Wait before the `retries`th retry: the base backoff, doubled for each retry
+    /// before it
+    fn retry_backoff(&self, retries: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retries.saturating_sub(1));
+        self.retry_backoff
+            .checked_mul(factor)
+            .map_or(MAX_RETRY_BACKOFF, |backoff| backoff.min(MAX_RETRY_BACKOFF))
+    }
 
-        let output = tokio::process::Command::new("nix-build")
+    /// The derivation's own timeout if it has one, within the configured bound
+    fn build_timeout(&self, job: &BuildJob) -> Duration {
+        let Some(secs) = job.derivation.timeout_secs else {
+            return self.build_timeout;
+        };
+        let requested = Duration::from_secs(secs);
+        self.max_build_timeout
+            .map_or(requested, |max| requested.min(max))
+    }
+
+    /// Run nix-build for a derivation, rooting its outputs in the GC roots directory
+    async fn run_nix_build(&self, drv_path: &str, target: &Target) -> anyhow::Result<()> {
+        let mut command = Command::new("nix-build");
+        command
             .arg(drv_path)
-            .output()
-            .await?;
+            .arg("--out-link")
+            .arg(self.gc_roots.out_link(drv_path));
+        if let Some(hook) = self.uploads.post_build_hook() {
+            command.arg("--option").arg("post-build-hook").arg(hook);
+        }
+        match target {
+            Target::Local => info!("Executing: nix-build {}", drv_path),
+            Target::Remote(machine) => {
+                info!("Executing: nix-build {} on {}", drv_path, machine);
+                // Only the remote builder can build it; nix copies the outputs back
+                command.args(["--builders", machine, "--max-jobs", "0"]);
+            }
+            Target::Worker(slot) => {
+                info!("Handing {} over to the workers", drv_path);
+                slot.build(drv_path).await?;
+                // The worker put the outputs in this store; only root them here
+                command.args(["--max-jobs", "0"]);
+            }
+            Target::Missing(system) => {
+                return Err(anyhow::anyhow!("No builder is configured for {}", system));
+            }
+        }
+
+        // Structured logs tell what the build is doing, see `BuildQueue::set_progress`
+        let mut process = BuildProcess::spawn(
+            command
+                .args(["--log-format", "internal-json"])
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::piped()),
+            drv_path,
+            &self.processes,
+        )?;
+        let mut lines = BufReader::new(process.stderr().unwrap()).lines();
+        let mut parser = LogParser::default();
+        let mut stderr = String::new();
+        while let Some(line) = lines.next_line().await? {
+            let previous = parser.progress().clone();
+            if let Some(line) = parser.feed(&line) {
+                stderr.push_str(&line);
+                stderr.push('\n');
+            }
+            if *parser.progress() != previous {
+                self.build_queue
+                    .set_progress(drv_path, parser.progress().clone());
+            }
+        }
 
-        if output.status.success() {
+        if process.wait().await?.success() {
             Ok(())
         } else {
-            let stderr = String::from_utf8_lossy(&output.stderr);
             Err(anyhow::anyhow!("nix-build failed: {}", stderr))
         }
     }
 
-    /// Upload build outputs to cache
-    async fn upload_to_cache(&self, drv_path: &str) -> anyhow::Result<()> {
-        info!("Uploading {} to cache", drv_path);
-
-        // Query the outputs of the derivation
-        let output = tokio::process::Command::new("nix-store")
-            .args(&["--query", "--outputs", drv_path])
-            .output()
-            .await?;
-
-        if !output.status.success() {
-            return Err(anyhow::anyhow!("Failed to query derivation outputs"));
+    /// Store the artifacts and test results found in the outputs of a successful build,
+    /// and its SBOM and provenance
+    async fn collect_reports(&self, job: &BuildJob, started_at: i64) {
+        let drv_path = &job.derivation.drv_path;
+        let outputs = match nix::derivation_outputs(drv_path).await {
+            Ok(outputs) => outputs,
+            Err(e) => {
+                warn!("Failed to query the outputs of {}: {:#}", drv_path, e);
+                return;
+            }
+        };
+        if let Err(e) = self.artifact_store.collect(drv_path, &outputs).await {
+            warn!("Failed to collect artifacts of {}: {:#}", drv_path, e);
         }
+        if let Err(e) = junit::collect(&self.db_pool, drv_path, &outputs).await {
+            warn!("Failed to collect test results of {}: {:#}", drv_path, e);
+        }
+        if self.artifact_store.sbom_enabled() {
+            if let Err(e) = sbom::collect(&self.artifact_store, &job.derivation, &outputs).await {
+                warn!("Failed to generate the SBOM of {}: {:#}", drv_path, e);
+            }
+        }
+        if let Some(attestor) = &self.attestor {
+            if let Err(e) = attestor
+                .attest(drv_path, &outputs, &job.requested_by, started_at)
+                .await
+            {
+                warn!("Failed to attest {}: {:#}", drv_path, e);
+            }
+        }
+    }
+}
 
-        let outputs_str = String::from_utf8(output.stdout)?;
-        let outputs: Vec<String> = outputs_str
-            .lines()
-            .map(|s| s.trim().to_string())
-            .filter(|s| !s.is_empty())
-            .collect();
-
-        // Upload each output
-        self.cache_client
-            .upload_derivation_outputs(&outputs)
-            .await?;
+/// The processes of a running build, in a process group led by nix-build so stopping
+/// it also stops the builders it forked. Dropped before nix-build exited, e.g. as the
+/// build was canceled or timed out, it stops the group.
+struct BuildProcess<'a> {
+    /// Until reaped
+    child: Option<Child>,
+    drv_path: &'a str,
+    /// Group ids of the running builds, by derivation
+    processes: &'a Mutex<HashMap<String, i32>>,
+}
 
-        Ok(())
+impl<'a> BuildProcess<'a> {
+    fn spawn(
+        command: &mut Command,
+        drv_path: &'a str,
+        processes: &'a Mutex<HashMap<String, i32>>,
+    ) -> std::io::Result<Self> {
+        let child = command.process_group(0).spawn()?;
+        if let Some(pid) = child.id() {
+            processes
+                .lock()
+                .unwrap()
+                .insert(drv_path.to_string(), pid as i32);
+        }
+        Ok(BuildProcess {
+            child: Some(child),
+            drv_path,
+            processes,
+        })
     }
 
-    /// Handle workflow completion: update DB, log summary, clear queue
-    async fn handle_workflow_completion(&self, workflow_id: i64) {
-        info!("Workflow {} completed, generating summary", workflow_id);
+    fn stderr(&mut self) -> Option<ChildStderr> {
+        self.child.as_mut()?.stderr.take()
+    }
 
-        // Get all jobs for this workflow
-        let jobs = self.build_queue.get_workflow_jobs(workflow_id);
+    async fn wait(mut self) -> std::io::Result<std::process::ExitStatus> {
+        let status = self.child.as_mut().unwrap().wait().await;
+        self.child = None;
+        status
+    }
+}
 
-        if jobs.is_empty() {
-            warn!("Workflow {} has no jobs", workflow_id);
-            return;
+impl Drop for BuildProcess<'_> {
+    fn drop(&mut self) {
+        self.processes.lock().unwrap().remove(self.drv_path);
+        if let Some(child) = self.child.take() {
+            info!("Stopping the processes of {}", self.drv_path);
+            tokio::spawn(stop(child));
         }
+    }
+}
 
-        // Calculate summary statistics
-        let total = jobs.len();
-        let success = jobs
-            .iter()
-            .filter(|j| j.status == BuildStatus::Success)
-            .count();
-        let failed = jobs
-            .iter()
-            .filter(|j| j.status == BuildStatus::Failed)
-            .count();
-        let cached = jobs
-            .iter()
-            .filter(|j| j.status == BuildStatus::Cached)
-            .count();
-        let timedout = jobs
-            .iter()
-            .filter(|j| j.status == BuildStatus::Timedout)
-            .count();
-        let canceled = jobs
-            .iter()
-            .filter(|j| j.status == BuildStatus::Canceled)
-            .count();
-
-        // Determine final workflow status
-        let has_errors = jobs.iter().any(|j| j.status.error());
-        let final_status = if has_errors { "Failed" } else { "Completed" };
+/// Stop the process group led by `child`: SIGTERM lets nix clean up, and SIGKILL
+/// follows for the processes still there after a while. The leader is only reaped
+/// then, so until that its group id cannot be taken by another group.
+async fn stop(mut child: Child) {
+    let Some(pgid) = child.id() else {
+        return;
+    };
+    signal_group(pgid as i32, libc::SIGTERM);
+    tokio::time::sleep(KILL_GRACE_PERIOD).await;
+    signal_group(pgid as i32, libc::SIGKILL);
+    let _ = child.wait().await;
+}
 
-        info!(
-            "Workflow {} {}: {} total jobs ({} success, {} cached, {} failed, {} timedout, {} canceled)",
-            workflow_id, final_status, total, success, cached, failed, timedout, canceled
-        );
+/// Send `signal` to every process of a group; groups that already exited are ignored
+pub(crate) fn signal_group(pgid: i32, signal: libc::c_int) {
+    // SAFETY: kill only takes plain integers
+    unsafe {
+        libc::kill(-pgid, signal);
+    }
+}
 
-        // Update workflow status in database
-        if let Err(e) = sqlx::query(
-            r#"
-            UPDATE workflows
-            SET status = ?
-            WHERE id = ?
-            "#,
-        )
-        .bind(final_status)
-        .bind(workflow_id)
-        .execute(&self.db_pool)
-        .await
-        {
-            error!(
-                "Failed to update workflow {} status in database: {}",
-                workflow_id, e
-            );
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether the process is running, rather than gone or exited and waiting to be
+    /// reaped
+    fn running(pid: i32) -> bool {
+        std::fs::read_to_string(format!("/proc/{}/stat", pid)).is_ok_and(|stat| {
+            let state = stat.rsplit(')').next().unwrap_or_default().trim_start();
+            !state.starts_with('Z')
+        })
+    }
 
-        // Clear workflow from queue (jobs are persisted in DB)
-        self.build_queue.clear_workflow(workflow_id);
-        info!("Workflow {} cleared from queue", workflow_id);
+    #[tokio::test]
+    async fn test_stopped_build_stops_forked_processes() {
+        let processes = Mutex::default();
+        let mut command = Command::new("sh");
+        command
+            .args(["-c", "sleep 100 & echo $! >&2; wait"])
+            .stderr(std::process::Stdio::piped());
+        let mut process =
+            BuildProcess::spawn(&mut command, "/nix/store/x.drv", &processes).unwrap();
+        assert_eq!(processes.lock().unwrap().len(), 1);
+        let mut lines = BufReader::new(process.stderr().unwrap()).lines();
+        let forked: i32 = lines.next_line().await.unwrap().unwrap().parse().unwrap();
+        assert!(running(forked));
+
+        // As when the build is canceled
+        drop(process);
+        assert!(processes.lock().unwrap().is_empty());
+        for _ in 0..50 {
+            if !running(forked) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("Process {} of the stopped build is still running", forked);
     }
 }
@@ -0,0 +1,322 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use std::{collections::HashSet, path::PathBuf, process::Stdio, sync::Arc, time::Duration};
+use tokio::{process::Command, sync::watch};
+use tracing::{info, warn};
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct GcConfig {
+    /// Periodically run the nix store garbage collector
+    pub enabled: bool,
+    /// Seconds between free space checks
+    pub interval_secs: u64,
+    /// Only collect once the store has less free space than this, in megabytes
+    pub min_free_mb: Option<u64>,
+    /// Stop collecting once the store has this much free space, in megabytes
+    pub max_free_mb: Option<u64>,
+    /// Directory holding the GC roots of build outputs not uploaded yet
+    pub roots_dir: PathBuf,
+    /// Outputs built for this many latest successful workflows of each repository
+    /// and branch stay rooted; 0 releases them once uploaded
+    pub keep_workflows: usize,
+}
+
+impl GcConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let (Some(min_free), Some(max_free)) = (self.min_free_mb, self.max_free_mb) {
+            if max_free < min_free {
+                return Err(anyhow!(
+                    "gc.max_free_mb ({}) must not be below gc.min_free_mb ({})",
+                    max_free,
+                    min_free
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            enabled: false,
+            interval_secs: 3600,
+            min_free_mb: None,
+            max_free_mb: None,
+            roots_dir: PathBuf::from("gcroots"),
+            keep_workflows: 3,
+        }
+    }
+}
+
+const STORE_DIR: &str = "/nix/store";
+const MB: u64 = 1024 * 1024;
+
+/// GC roots keeping build outputs alive until they are uploaded, or while recent
+/// workflows need them, and the count of uploads in flight that garbage collection
+/// waits for
+pub struct GcRoots {
+    dir: PathBuf,
+    /// Keep the roots of successful builds until the collector prunes them
+    keep_outputs: bool,
+    pending_uploads: watch::Sender<usize>,
+}
+
+/// Marks an upload as pending until dropped
+pub struct PendingUpload<'a>(&'a GcRoots);
+
+impl Drop for PendingUpload<'_> {
+    fn drop(&mut self) {
+        self.0.pending_uploads.send_modify(|n| *n -= 1);
+    }
+}
+
+impl GcRoots {
+    pub fn new(dir: PathBuf, keep_outputs: bool) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create GC roots directory {}", dir.display()))?;
+        // nix registers out links as indirect roots by their absolute path
+        let dir = dir.canonicalize()?;
+        Ok(Self {
+            dir,
+            keep_outputs,
+            pending_uploads: watch::Sender::new(0),
+        })
+    }
+
+    /// Where nix-build links the outputs of `drv_path`; extra outputs get a `-<output>` suffix
+    pub fn out_link(&self, drv_path: &str) -> PathBuf {
+        self.dir.join(root_name(drv_path))
+    }
+
+    /// Remove the roots of `drv_path`'s outputs, letting the collector free them
+    pub fn release(&self, drv_path: &str) {
+        let name = root_name(drv_path);
+        self.remove_roots(|file_name| {
+            file_name == name || file_name.starts_with(&format!("{}-", name))
+        });
+    }
+
+    /// Release the roots of a finished build, unless it succeeded and recent
+    /// workflows may need its outputs (see `GarbageCollector::prune_roots`)
+    pub fn build_finished(&self, drv_path: &str, success: bool) {
+        if !(success && self.keep_outputs) {
+            self.release(drv_path);
+        }
+    }
+
+    /// Remove the roots whose file names match, returning how many were removed
+    fn remove_roots(&self, matches: impl Fn(&str) -> bool) -> usize {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return 0;
+        };
+        let mut removed = 0;
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            if !matches(&entry.file_name().to_string_lossy()) {
+                continue;
+            }
+            match std::fs::remove_file(entry.path()) {
+                Ok(()) => removed += 1,
+                Err(e) => warn!("Failed to remove GC root {}: {}", entry.path().display(), e),
+            }
+        }
+        removed
+    }
+
+    pub fn upload_started(&self) -> PendingUpload<'_> {
+        self.pending_uploads.send_modify(|n| *n += 1);
+        PendingUpload(self)
+    }
+
+    async fn wait_for_uploads(&self) {
+        let mut pending = self.pending_uploads.subscribe();
+        let count = *pending.borrow();
+        if count > 0 {
+            info!("Garbage collection waits for {} pending uploads", count);
+        }
+        // The sender lives as long as self, so this cannot fail
+        let _ = pending.wait_for(|n| *n == 0).await;
+    }
+}
+
+/// The store path hash is unique per derivation and keeps names short
+fn root_name(drv_path: &str) -> String {
+    drv_path
+        .trim_start_matches("/nix/store/")
+        .trim_end_matches(".drv")
+        .to_string()
+}
+
+/// The store path hash a root name starts with
+fn root_hash(name: &str) -> &str {
+    name.split('-').next().unwrap_or_default()
+}
+
+pub struct GarbageCollector {
+    config: GcConfig,
+    roots: Arc<GcRoots>,
+    db_pool: SqlitePool,
+}
+
+impl GarbageCollector {
+    pub fn new(config: GcConfig, roots: Arc<GcRoots>, db_pool: SqlitePool) -> Self {
+        Self {
+            config,
+            roots,
+            db_pool,
+        }
+    }
+
+    pub async fn run(self) {
+        info!(
+            "Garbage collection every {}s (min free: {:?} MB, max free: {:?} MB)",
+            self.config.interval_secs, self.config.min_free_mb, self.config.max_free_mb
+        );
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(self.config.interval_secs.max(1)));
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.prune_roots().await {
+                warn!("Failed to release the GC roots of older workflows: {:#}", e);
+            }
+            if let Err(e) = self.collect().await {
+                warn!("Garbage collection failed: {:#}", e);
+            }
+        }
+    }
+
+    /// Release the roots of outputs that neither an unfinished workflow nor one of the
+    /// latest successful workflows of a repository and branch built, unless they are
+    /// still to be uploaded
+    async fn prune_roots(&self) -> Result<()> {
+        let needed: Vec<String> = sqlx::query_scalar(
+            r#"
+            SELECT drv_path FROM build_workflows WHERE workflow_id IN (
+                SELECT id FROM workflows WHERE status NOT IN ('Completed', 'Failed', 'Canceled')
+                UNION
+                SELECT id FROM (
+                    SELECT id, ROW_NUMBER() OVER (
+                        PARTITION BY repository, branch ORDER BY id DESC
+                    ) AS position
+                    FROM workflows WHERE status = 'Completed'
+                ) WHERE position <= ?
+            )
+            UNION
+            SELECT drv_path FROM builds WHERE status IN ('queued', 'ready', 'running')
+                OR upload_status IN ('pending', 'uploading')
+            "#,
+        )
+        .bind(self.config.keep_workflows as i64)
+        .fetch_all(&self.db_pool)
+        .await?;
+        let needed: HashSet<String> = needed
+            .iter()
+            .map(|drv_path| root_hash(&root_name(drv_path)).to_string())
+            .collect();
+        let released = self
+            .roots
+            .remove_roots(|file_name| !needed.contains(root_hash(file_name)));
+        if released > 0 {
+            info!("Released the GC roots of {} older outputs", released);
+        }
+        Ok(())
+    }
+
+    async fn collect(&self) -> Result<()> {
+        let free = store_free_bytes().await?;
+        if let Some(min_free) = self.config.min_free_mb {
+            if free >= min_free * MB {
+                return Ok(());
+            }
+        }
+        let max_freed = match self.config.max_free_mb {
+            Some(max_free) if free >= max_free * MB => return Ok(()),
+            Some(max_free) => Some(max_free * MB - free),
+            None => None,
+        };
+
+        self.roots.wait_for_uploads().await;
+        info!(
+            "Collecting garbage with {} MB free in {}",
+            free / MB,
+            STORE_DIR
+        );
+        let mut command = Command::new("nix-store");
+        command.arg("--gc");
+        if let Some(max_freed) = max_freed {
+            command.args(["--max-freed", &max_freed.to_string()]);
+        }
+        let output = command
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()
+            .await
+            .context("Failed to execute nix-store --gc")?;
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
+            return Err(anyhow!("nix-store --gc failed: {}", stderr.trim()));
+        }
+        // nix-store ends with e.g. "1234 store paths deleted, 567.89 MiB freed"
+        info!(
+            "Garbage collection done: {}",
+            stderr.lines().last().unwrap_or_default()
+        );
+        Ok(())
+    }
+}
+
+/// Bytes available to unprivileged users on the store's filesystem
+async fn store_free_bytes() -> Result<u64> {
+    let output = Command::new("df")
+        .args(["-Pk", STORE_DIR])
+        .output()
+        .await
+        .context("Failed to execute df")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "df failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    parse_df_available(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| anyhow!("Unexpected df output for {}", STORE_DIR))
+}
+
+/// The "Available" column of `df -Pk`, in bytes
+fn parse_df_available(output: &str) -> Option<u64> {
+    let kilobytes: u64 = output
+        .lines()
+        .nth(1)?
+        .split_whitespace()
+        .nth(3)?
+        .parse()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_df_available() {
+        let output = "Filesystem     1024-blocks      Used Available Capacity Mounted on\n\
+                      /dev/nvme0n1p2   479151816 312503352 142240808      69% /\n";
+        assert_eq!(parse_df_available(output), Some(142240808 * 1024));
+        assert_eq!(parse_df_available("garbage"), None);
+    }
+
+    #[test]
+    fn test_root_hash() {
+        let name = root_name("/nix/store/0c8sy5qkvyb3zhlh1q8n4ak7m8x4bnxs-hello-2.12.drv");
+        assert_eq!(name, "0c8sy5qkvyb3zhlh1q8n4ak7m8x4bnxs-hello-2.12");
+        assert_eq!(root_hash(&name), "0c8sy5qkvyb3zhlh1q8n4ak7m8x4bnxs");
+        assert_eq!(
+            root_hash(&format!("{}-dev", name)),
+            "0c8sy5qkvyb3zhlh1q8n4ak7m8x4bnxs"
+        );
+    }
+}
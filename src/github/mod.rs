@@ -0,0 +1,521 @@
+use crate::{
+    build::{BuildStatus, WorkflowStatus},
+    events::{Event, EventBus},
+    notify::format_duration,
+};
+use anyhow::{anyhow, Context, Result};
+use reqwest::{header, Client, Method, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, info_span, warn, Instrument};
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct GitHubConfig {
+    /// Token allowed to write commit statuses; nothing is reported when unset
+    pub token: Option<String>,
+    /// REST API base URL, to point at a GitHub Enterprise Server
+    pub api_url: String,
+    /// Host of the repositories to report on, recognized in their clone URL
+    pub host: String,
+    /// Name of the status check, which branch protection rules refer to
+    pub context: String,
+    /// Keep a comment summarizing the build results on pull requests
+    pub comments: bool,
+}
+
+impl Default for GitHubConfig {
+    fn default() -> Self {
+        GitHubConfig {
+            token: None,
+            api_url: "https://api.github.com".to_string(),
+            host: "github.com".to_string(),
+            context: "icicle".to_string(),
+            comments: true,
+        }
+    }
+}
+
+impl GitHubConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.api_url.starts_with("https://") && !self.api_url.starts_with("http://") {
+            return Err(anyhow!("api_url must be an http(s) URL"));
+        }
+        if self.context.is_empty() {
+            return Err(anyhow!("context must not be empty"));
+        }
+        Ok(())
+    }
+
+    /// Whether a repository is hosted on the configured GitHub, going by its
+    /// HTTPS (`https://github.com/...`) or SSH (`git@github.com:...`) clone URL
+    fn hosts(&self, clone_url: &str) -> bool {
+        let rest = clone_url
+            .strip_prefix("https://")
+            .or_else(|| clone_url.strip_prefix("ssh://"))
+            .unwrap_or(clone_url);
+        let rest = rest.split_once('@').map_or(rest, |(_, host)| host);
+        rest.strip_prefix(self.host.as_str())
+            .is_some_and(|path| path.starts_with('/') || path.starts_with(':'))
+    }
+}
+
+/// State of a commit status, as the Statuses API names them
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommitState {
+    Pending,
+    Success,
+    Failure,
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+struct CommitStatus<'a> {
+    state: CommitState,
+    target_url: Option<&'a str>,
+    description: &'a str,
+    context: &'a str,
+}
+
+/// The few REST API calls icicle makes to GitHub
+#[derive(Clone)]
+pub struct GitHubClient {
+    client: Client,
+    api_url: String,
+    token: String,
+}
+
+impl GitHubClient {
+    pub fn new(api_url: &str, token: &str) -> Self {
+        Self {
+            client: Client::new(),
+            api_url: api_url.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(method, format!("{}{}", self.api_url, path))
+            .bearer_auth(&self.token)
+            .header(header::ACCEPT, "application/vnd.github+json")
+            // GitHub refuses requests without a user agent
+            .header(header::USER_AGENT, "icicle")
+    }
+
+    /// Set the status of a commit in `repository` ("owner/name") under `context`
+    pub async fn create_status(
+        &self,
+        repository: &str,
+        commit_sha: &str,
+        context: &str,
+        state: CommitState,
+        description: &str,
+        target_url: Option<&str>,
+    ) -> Result<()> {
+        self.request(
+            Method::POST,
+            &format!("/repos/{}/statuses/{}", repository, commit_sha),
+        )
+        .json(&CommitStatus {
+            state,
+            target_url,
+            description,
+            context,
+        })
+        .send()
+        .await
+        .context("Failed to reach GitHub")?
+        .error_for_status()
+        .context("GitHub refused the commit status")?;
+        Ok(())
+    }
+
+    /// Comment on a pull request (or issue), returning the comment's id
+    pub async fn create_comment(&self, repository: &str, number: u64, body: &str) -> Result<i64> {
+        let comment: Comment = self
+            .request(
+                Method::POST,
+                &format!("/repos/{}/issues/{}/comments", repository, number),
+            )
+            .json(&CommentBody { body })
+            .send()
+            .await
+            .context("Failed to reach GitHub")?
+            .error_for_status()
+            .context("GitHub refused the comment")?
+            .json()
+            .await
+            .context("Invalid comment in GitHub's response")?;
+        Ok(comment.id)
+    }
+
+    /// Replace the body of a comment. Returns false if the comment no longer exists.
+    pub async fn update_comment(&self, repository: &str, id: i64, body: &str) -> Result<bool> {
+        let response = self
+            .request(
+                Method::PATCH,
+                &format!("/repos/{}/issues/comments/{}", repository, id),
+            )
+            .json(&CommentBody { body })
+            .send()
+            .await
+            .context("Failed to reach GitHub")?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        response
+            .error_for_status()
+            .context("GitHub refused the comment")?;
+        Ok(true)
+    }
+
+    /// Login of the user the token belongs to
+    pub async fn user(&self) -> Result<String> {
+        let user: Account = self
+            .request(Method::GET, "/user")
+            .send()
+            .await
+            .context("Failed to reach GitHub")?
+            .error_for_status()
+            .context("GitHub refused to tell the user")?
+            .json()
+            .await
+            .context("Invalid user in GitHub's response")?;
+        Ok(user.login)
+    }
+
+    /// Logins of the organizations the token's user is a member of
+    pub async fn organizations(&self) -> Result<Vec<String>> {
+        let organizations: Vec<Account> = self
+            .request(Method::GET, "/user/orgs?per_page=100")
+            .send()
+            .await
+            .context("Failed to reach GitHub")?
+            .error_for_status()
+            .context("GitHub refused to list the user's organizations")?
+            .json()
+            .await
+            .context("Invalid organizations in GitHub's response")?;
+        Ok(organizations.into_iter().map(|o| o.login).collect())
+    }
+}
+
+/// A user or organization, of which only the login matters
+#[derive(Debug, Deserialize)]
+struct Account {
+    login: String,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct WorkflowRow {
+    repository: String,
+    commit_sha: String,
+    branch: Option<String>,
+    clone_url: String,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct JobRow {
+    name: String,
+    status: String,
+    started_at: Option<i64>,
+    finished_at: Option<i64>,
+}
+
+/// The pull request a workflow was triggered by, from its `pr-<number>` branch
+fn pull_request(branch: Option<&str>) -> Option<u64> {
+    branch?.strip_prefix("pr-")?.parse().ok()
+}
+
+/// Markdown summary of a finished workflow's jobs, one row per attribute
+fn summary(
+    workflow_id: i64,
+    commit_sha: &str,
+    status: &WorkflowStatus,
+    jobs: &[JobRow],
+    workflow_url: Option<&str>,
+) -> String {
+    let verb = match status {
+        WorkflowStatus::Completed => "succeeded",
+        WorkflowStatus::Failed => "failed",
+        WorkflowStatus::Running => "is running",
+        WorkflowStatus::Canceled => "was canceled",
+    };
+    let short_sha = commit_sha.get(..7).unwrap_or(commit_sha);
+    let workflow = match workflow_url {
+        Some(url) => format!("[Workflow {}]({})", workflow_id, url),
+        None => format!("Workflow {}", workflow_id),
+    };
+    let mut body = format!("### icicle: {} {} for {}\n\n", workflow, verb, short_sha);
+    if jobs.is_empty() {
+        body.push_str("No jobs were built.\n");
+        return body;
+    }
+
+    body.push_str("| Attribute | Status | Duration | Cached |\n");
+    body.push_str("| --- | --- | --- | --- |\n");
+    for job in jobs {
+        let status = job.status.parse::<BuildStatus>().ok();
+        let icon = match status {
+            Some(BuildStatus::Success | BuildStatus::Cached) => "✅",
+            Some(BuildStatus::Failed | BuildStatus::Timedout) => "❌",
+            _ => "⏹️",
+        };
+        let cached = status == Some(BuildStatus::Cached);
+        let duration = match (job.started_at, job.finished_at) {
+            (Some(started_at), Some(finished_at)) if !cached => {
+                format_duration(finished_at - started_at)
+            }
+            _ => "-".to_string(),
+        };
+        let cached = if cached { "yes" } else { "no" };
+        body.push_str(&format!(
+            "| `{}` | {} {} | {} | {} |\n",
+            job.name.replace('|', "\\|"),
+            icon,
+            job.status,
+            duration,
+            cached
+        ));
+    }
+    body
+}
+
+#[derive(Debug, Serialize)]
+struct CommentBody<'a> {
+    body: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct Comment {
+    id: i64,
+}
+
+/// What a workflow status shows up as on GitHub
+fn commit_state(status: &WorkflowStatus) -> (CommitState, &'static str) {
+    match status {
+        WorkflowStatus::Running => (CommitState::Pending, "Building"),
+        WorkflowStatus::Completed => (CommitState::Success, "All jobs succeeded"),
+        WorkflowStatus::Failed => (CommitState::Failure, "Some jobs failed"),
+        WorkflowStatus::Canceled => (CommitState::Error, "Canceled"),
+    }
+}
+
+/// Reports the status of workflows on their commit, so pull requests can be
+/// gated on icicle, and summarizes their builds on pull requests
+pub struct GitHubReporter {
+    config: GitHubConfig,
+    client: GitHubClient,
+    db_pool: SqlitePool,
+    /// Externally reachable base URL of icicle, for links back to the workflow
+    public_url: Option<String>,
+}
+
+impl GitHubReporter {
+    /// A reporter, unless no token is configured
+    pub fn new(
+        config: &GitHubConfig,
+        db_pool: SqlitePool,
+        public_url: Option<&str>,
+    ) -> Option<Self> {
+        let token = config.token.as_ref()?;
+        Some(Self {
+            client: GitHubClient::new(&config.api_url, token),
+            config: config.clone(),
+            db_pool,
+            public_url: public_url.map(|url| url.trim_end_matches('/').to_string()),
+        })
+    }
+
+    pub async fn run(self, events: EventBus) {
+        info!("Reporting commit statuses to {}", self.config.api_url);
+        let mut receiver = events.subscribe();
+        loop {
+            let (workflow_id, status) = match receiver.recv().await {
+                Ok(Event::WorkflowStatus {
+                    workflow_id,
+                    status,
+                }) => (workflow_id, status),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Commit status reporting missed {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            if let Err(e) = self
+                .report(workflow_id, status)
+                .instrument(info_span!("workflow", workflow_id))
+                .await
+            {
+                error!(
+                    "Failed to report status of workflow {} to GitHub: {:#}",
+                    workflow_id, e
+                );
+            }
+        }
+    }
+
+    async fn report(&self, workflow_id: i64, status: WorkflowStatus) -> Result<()> {
+        let Some(workflow) = sqlx::query_as::<_, WorkflowRow>(
+            r#"
+            SELECT w.repository, w.commit_sha, w.branch, r.clone_url
+            FROM workflows w JOIN repositories r ON r.full_name = w.repository
+            WHERE w.id = ?
+            "#,
+        )
+        .bind(workflow_id)
+        .fetch_optional(&self.db_pool)
+        .await?
+        else {
+            return Ok(());
+        };
+        if !self.config.hosts(&workflow.clone_url) {
+            return Ok(());
+        }
+
+        let (state, description) = commit_state(&status);
+        let target_url = self
+            .public_url
+            .as_ref()
+            .map(|url| format!("{}/workflows/{}", url, workflow_id));
+        self.client
+            .create_status(
+                &workflow.repository,
+                &workflow.commit_sha,
+                &self.config.context,
+                state,
+                description,
+                target_url.as_deref(),
+            )
+            .await?;
+        info!(
+            "Set GitHub status of {} {} to {:?}",
+            workflow.repository, workflow.commit_sha, state
+        );
+
+        let finished = matches!(status, WorkflowStatus::Completed | WorkflowStatus::Failed);
+        if let Some(number) = pull_request(workflow.branch.as_deref()) {
+            if finished && self.config.comments {
+                let body = self
+                    .summarize(workflow_id, &workflow, &status, target_url.as_deref())
+                    .await?;
+                self.comment(&workflow.repository, number, &body).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn summarize(
+        &self,
+        workflow_id: i64,
+        workflow: &WorkflowRow,
+        status: &WorkflowStatus,
+        workflow_url: Option<&str>,
+    ) -> Result<String> {
+        let jobs = sqlx::query_as::<_, JobRow>(
+            r#"
+            SELECT b.name, b.status, b.started_at, b.finished_at
+            FROM builds b JOIN build_workflows bw ON bw.drv_path = b.drv_path
+            WHERE bw.workflow_id = ?1
+            UNION ALL
+            -- Attributes that failed to evaluate show as failed jobs
+            SELECT attr, 'failed', NULL, NULL FROM evaluation_errors WHERE workflow_id = ?1
+            ORDER BY 1
+            "#,
+        )
+        .bind(workflow_id)
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(summary(
+            workflow_id,
+            &workflow.commit_sha,
+            status,
+            &jobs,
+            workflow_url,
+        ))
+    }
+
+    /// Update the pull request's summary comment, or post one if there is none yet
+    async fn comment(&self, repository: &str, number: u64, body: &str) -> Result<()> {
+        let existing: Option<i64> = sqlx::query_scalar(
+            "SELECT comment_id FROM pull_request_comments WHERE repository = ? AND pull_request = ?",
+        )
+        .bind(repository)
+        .bind(number as i64)
+        .fetch_optional(&self.db_pool)
+        .await?;
+        if let Some(id) = existing {
+            if self.client.update_comment(repository, id, body).await? {
+                info!("Updated summary comment on {}#{}", repository, number);
+                return Ok(());
+            }
+        }
+
+        // Nothing posted yet, or the comment was deleted
+        let id = self.client.create_comment(repository, number, body).await?;
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO pull_request_comments (repository, pull_request, comment_id)
+            VALUES (?, ?, ?)
+            "#,
+        )
+        .bind(repository)
+        .bind(number as i64)
+        .bind(id)
+        .execute(&self.db_pool)
+        .await?;
+        info!("Posted summary comment on {}#{}", repository, number);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hosts() {
+        let config = GitHubConfig::default();
+        assert!(config.hosts("https://github.com/owner/repo.git"));
+        assert!(config.hosts("git@github.com:owner/repo.git"));
+        assert!(config.hosts("ssh://git@github.com/owner/repo.git"));
+        assert!(!config.hosts("https://github.com.evil.org/owner/repo.git"));
+        assert!(!config.hosts("https://bitbucket.org/owner/repo.git"));
+        assert!(!config.hosts("/srv/git/repo"));
+    }
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(pull_request(Some("pr-42")), Some(42));
+        assert_eq!(pull_request(Some("main")), None);
+
+        let job = |name: &str, status: &str, started_at, finished_at| JobRow {
+            name: name.to_string(),
+            status: status.to_string(),
+            started_at,
+            finished_at,
+        };
+        let jobs = [
+            job("hello", "success", Some(100), Some(165)),
+            job("lib", "cached", Some(100), Some(100)),
+            job("tests", "failed", Some(100), Some(103)),
+            job("vm-test", "timed out", Some(100), Some(700)),
+            job("docs", "canceled", None, None),
+        ];
+        assert_eq!(
+            summary(7, "0123456789abcdef", &WorkflowStatus::Failed, &jobs, None),
+            "### icicle: Workflow 7 failed for 0123456\n\n\
+             | Attribute | Status | Duration | Cached |\n\
+             | --- | --- | --- | --- |\n\
+             | `hello` | ✅ success | 1m05s | no |\n\
+             | `lib` | ✅ cached | - | yes |\n\
+             | `tests` | ❌ failed | 3s | no |\n\
+             | `vm-test` | ❌ timed out | 10m00s | no |\n\
+             | `docs` | ⏹️ canceled | - | no |\n"
+        );
+    }
+}
@@ -0,0 +1,152 @@
+use crate::{builders::Builders, cache::CacheClient};
+use anyhow::{anyhow, Context, Result};
+use axum::{extract::State, http::StatusCode, response::Json, routing::get, Router};
+use serde::Serialize;
+use std::{collections::BTreeMap, future::Future, sync::Arc};
+use tokio::{
+    process::Command,
+    time::{timeout, Duration},
+};
+use tracing::warn;
+
+/// Upper bound for each individual readiness check
+pub(crate) const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+struct HealthResponse {
+    status: &'static str,
+    paused: bool,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    checks: BTreeMap<&'static str, CheckResult>,
+}
+
+#[derive(Debug, Serialize)]
+struct CheckResult {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new()
+        .route("/health", get(live))
+        .route("/health/live", get(live))
+        .route("/health/ready", get(ready))
+}
+
+/// Liveness: the process is up and serving requests
+async fn live(State(app_state): State<Arc<crate::AppState>>) -> Json<HealthResponse> {
+    Json(HealthResponse {
+        status: "ok",
+        paused: app_state.build_queue.is_paused(),
+        checks: BTreeMap::new(),
+    })
+}
+
+/// Readiness: the dependencies needed to run workflows are available
+async fn ready(
+    State(app_state): State<Arc<crate::AppState>>,
+) -> (StatusCode, Json<HealthResponse>) {
+    let cache_client = CacheClient::new(app_state.cache_config.clone());
+    let (database, nix, cache) = tokio::join!(
+        run_check(check_database(&app_state.db_pool)),
+        run_check(check_nix()),
+        run_check(cache_client.ping()),
+    );
+
+    let mut checks = BTreeMap::new();
+    checks.insert("database", database);
+    checks.insert("nix", nix);
+    checks.insert("cache", cache);
+    if !app_state.builders.is_empty() {
+        checks.insert("builders", check_builders(&app_state.builders));
+    }
+
+    let healthy = checks.values().all(|c| c.ok);
+    if !healthy {
+        warn!("Readiness check degraded: {:?}", checks);
+    }
+
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(HealthResponse {
+            status: if healthy { "ok" } else { "degraded" },
+            paused: app_state.build_queue.is_paused(),
+            checks,
+        }),
+    )
+}
+
+async fn run_check<F>(check: F) -> CheckResult
+where
+    F: Future<Output = Result<()>>,
+{
+    match timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(())) => CheckResult {
+            ok: true,
+            detail: None,
+        },
+        Ok(Err(e)) => CheckResult {
+            ok: false,
+            detail: Some(format!("{:#}", e)),
+        },
+        Err(_) => CheckResult {
+            ok: false,
+            detail: Some(format!(
+                "timed out after {} seconds",
+                CHECK_TIMEOUT.as_secs()
+            )),
+        },
+    }
+}
+
+async fn check_database(pool: &sqlx::SqlitePool) -> Result<()> {
+    sqlx::query("SELECT 1")
+        .execute(pool)
+        .await
+        .context("Database query failed")?;
+    Ok(())
+}
+
+/// Uses the result of the builders' last periodic check, as pinging them can be slow
+fn check_builders(builders: &Builders) -> CheckResult {
+    let down: Vec<String> = builders
+        .status()
+        .into_iter()
+        .filter(|b| !b.alive)
+        .map(|b| b.store)
+        .collect();
+    CheckResult {
+        ok: down.is_empty(),
+        detail: (!down.is_empty()).then(|| format!("unreachable: {}", down.join(", "))),
+    }
+}
+
+async fn check_nix() -> Result<()> {
+    for binary in ["nix", "nix-eval-jobs"] {
+        check_binary(binary).await?;
+    }
+    Ok(())
+}
+
+/// Check that `binary` is on the PATH and runs
+pub(crate) async fn check_binary(binary: &str) -> Result<()> {
+    let output = Command::new(binary)
+        .arg("--version")
+        .output()
+        .await
+        .with_context(|| format!("Failed to execute {}", binary))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} --version exited with {}",
+            binary,
+            output.status
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,225 @@
+use serde::Serialize;
+
+/// A common reason for builds to fail, recognized in their log
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Cause {
+    HashMismatch,
+    OutOfMemory,
+    MissingDependency,
+    NetworkFetch,
+    DiskFull,
+    BuilderUnreachable,
+}
+
+/// Log excerpts that give a cause away, checked in order, in lowercase
+const PATTERNS: &[(Cause, &[&str])] = &[
+    (
+        Cause::DiskFull,
+        &["no space left on device", "disk quota exceeded"],
+    ),
+    (
+        Cause::OutOfMemory,
+        &[
+            "out of memory",
+            "cannot allocate memory",
+            "std::bad_alloc",
+            "memory exhausted",
+            "killed by signal 9",
+            "signal: killed",
+            "exit code 137",
+        ],
+    ),
+    (
+        Cause::HashMismatch,
+        &["hash mismatch in fixed-output derivation"],
+    ),
+    (
+        Cause::BuilderUnreachable,
+        &[
+            "cannot connect to",
+            "failed to start ssh connection",
+            "ssh: connect to host",
+            "ssh: could not resolve hostname",
+            "connection closed by remote host",
+            "connection reset by peer",
+        ],
+    ),
+    (
+        Cause::NetworkFetch,
+        &[
+            "unable to download",
+            "could not resolve host",
+            "temporary failure in name resolution",
+            "couldn't resolve host",
+            "connection timed out",
+            "connection refused",
+            "network is unreachable",
+            "ssl connect error",
+            "tls handshake",
+        ],
+    ),
+    (
+        Cause::MissingDependency,
+        &[
+            ": command not found",
+            "was not found in the pkg-config search path",
+            "could not find a package configuration file",
+            "modulenotfounderror",
+            "no module named",
+            "cannot find -l",
+            "fatal error: ",
+        ],
+    ),
+];
+
+impl Cause {
+    pub fn parse(cause: &str) -> Option<Self> {
+        match cause {
+            "hash_mismatch" => Some(Cause::HashMismatch),
+            "out_of_memory" => Some(Cause::OutOfMemory),
+            "missing_dependency" => Some(Cause::MissingDependency),
+            "network_fetch" => Some(Cause::NetworkFetch),
+            "disk_full" => Some(Cause::DiskFull),
+            "builder_unreachable" => Some(Cause::BuilderUnreachable),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Cause::HashMismatch => "hash_mismatch",
+            Cause::OutOfMemory => "out_of_memory",
+            Cause::MissingDependency => "missing_dependency",
+            Cause::NetworkFetch => "network_fetch",
+            Cause::DiskFull => "disk_full",
+            Cause::BuilderUnreachable => "builder_unreachable",
+        }
+    }
+
+    /// Whether the builder rather than the code is likely at fault,
+    /// so a retry may well succeed
+    pub fn infrastructure(self) -> bool {
+        matches!(
+            self,
+            Cause::OutOfMemory | Cause::NetworkFetch | Cause::DiskFull | Cause::BuilderUnreachable
+        )
+    }
+
+    /// Whether the failure tends to go away by itself, so the build is retried
+    /// automatically. A full disk stays full until someone acts.
+    pub fn transient(self) -> bool {
+        self.infrastructure() && self != Cause::DiskFull
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Cause::HashMismatch => "hash mismatch",
+            Cause::OutOfMemory => "out of memory",
+            Cause::MissingDependency => "missing dependency",
+            Cause::NetworkFetch => "network fetch failure",
+            Cause::DiskFull => "disk full",
+            Cause::BuilderUnreachable => "builder unreachable",
+        }
+    }
+
+    /// What to do about it
+    pub fn hint(self) -> &'static str {
+        match self {
+            Cause::HashMismatch => {
+                "A fixed-output derivation produced a different hash than declared. \
+                 Update the hash to the one nix reports if the source legitimately changed."
+            }
+            Cause::OutOfMemory => {
+                "The build ran out of memory. Retry it, lower its parallelism, \
+                 or give the builder more memory."
+            }
+            Cause::MissingDependency => {
+                "A command, library or header was not found. \
+                 Add the missing package to nativeBuildInputs or buildInputs."
+            }
+            Cause::NetworkFetch => {
+                "Downloading a source failed. The retry may succeed; \
+                 otherwise check that the URL still exists and the builder is online."
+            }
+            Cause::DiskFull => {
+                "The builder ran out of disk space. \
+                 Free space, for example by collecting garbage, and retry."
+            }
+            Cause::BuilderUnreachable => {
+                "The machine building it could not be reached. \
+                 Check that the builder is up and accepts SSH connections."
+            }
+        }
+    }
+}
+
+/// What a failed build's cause is likely to be, for the API
+#[derive(Debug, Serialize)]
+pub struct Diagnosis {
+    pub cause: Cause,
+    pub hint: &'static str,
+    pub infrastructure: bool,
+}
+
+impl From<Cause> for Diagnosis {
+    fn from(cause: Cause) -> Self {
+        Diagnosis {
+            cause,
+            hint: cause.hint(),
+            infrastructure: cause.infrastructure(),
+        }
+    }
+}
+
+/// Recognize the cause of a failure in a build log
+pub fn analyze(log: &str) -> Option<Cause> {
+    let log = log.to_lowercase();
+    PATTERNS
+        .iter()
+        .find(|(_, patterns)| patterns.iter().any(|pattern| log.contains(pattern)))
+        .map(|(cause, _)| *cause)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_analyze() {
+        let hash = "error: hash mismatch in fixed-output derivation '/nix/store/x-src.drv':\n\
+                    specified: sha256-AAAA\n   got:    sha256-BBBB";
+        assert_eq!(analyze(hash), Some(Cause::HashMismatch));
+        assert_eq!(
+            analyze("cc1plus: out of memory allocating 65536 bytes"),
+            Some(Cause::OutOfMemory)
+        );
+        assert_eq!(
+            analyze("curl: (6) Could not resolve host: example.org\nerror: unable to download"),
+            Some(Cause::NetworkFetch)
+        );
+        assert_eq!(
+            analyze("main.c:1:10: fatal error: openssl/ssl.h: No such file or directory"),
+            Some(Cause::MissingDependency)
+        );
+        // The disk filling up explains the errors that follow
+        assert_eq!(
+            analyze("fatal error: error writing to /build/tmp: No space left on device"),
+            Some(Cause::DiskFull)
+        );
+        assert_eq!(analyze("test_parse failed: assertion failed"), None);
+        assert!(Cause::DiskFull.infrastructure());
+        assert!(!Cause::MissingDependency.infrastructure());
+        assert_eq!(
+            analyze("error: cannot connect to 'ssh-ng://builder@arm'"),
+            Some(Cause::BuilderUnreachable)
+        );
+        assert!(Cause::BuilderUnreachable.transient());
+        assert!(Cause::OutOfMemory.transient());
+        assert!(!Cause::DiskFull.transient());
+        assert_eq!(
+            Cause::parse(Cause::NetworkFetch.as_str()),
+            Some(Cause::NetworkFetch)
+        );
+    }
+}
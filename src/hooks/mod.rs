@@ -0,0 +1,498 @@
+use crate::events::{Event, EventBus};
+use anyhow::{anyhow, Context, Result};
+use hmac::{Hmac, Mac};
+use reqwest::{header, Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use sqlx::SqlitePool;
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast::error::RecvError, Notify},
+    task::JoinSet,
+    time::{interval, Instant, MissedTickBehavior},
+};
+use tracing::{info, warn};
+
+/// How often deliveries due for a retry are looked for
+const RETRY_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the delivery log is pruned
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+/// Deliveries sent per round, so a backlog doesn't hold up pruning
+const DELIVERY_BATCH: i64 = 100;
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct HookConfig {
+    /// Attempts at a delivery before it is given up on
+    pub max_attempts: u32,
+    /// Wait before retrying a failed delivery, doubling with each attempt
+    pub retry_backoff_secs: u64,
+    /// Limit for an endpoint to answer
+    pub timeout_secs: u64,
+    /// Days to keep the log of deliveries; forever when 0
+    pub retention_days: u64,
+}
+
+impl Default for HookConfig {
+    fn default() -> Self {
+        HookConfig {
+            max_attempts: 5,
+            retry_backoff_secs: 30,
+            timeout_secs: 10,
+            retention_days: 30,
+        }
+    }
+}
+
+impl HookConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_attempts == 0 {
+            return Err(anyhow!("max_attempts must be at least 1"));
+        }
+        if self.timeout_secs == 0 {
+            return Err(anyhow!("timeout_secs must be positive"));
+        }
+        Ok(())
+    }
+
+    /// Wait after the given failed attempt (1 for the first) before the next one
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        let factor = 1u64 << attempt.saturating_sub(1).min(16);
+        Duration::from_secs(self.retry_backoff_secs.saturating_mul(factor))
+    }
+}
+
+/// `X-Icicle-Signature-256` of a payload: its HMAC-SHA256 under the hook's secret,
+/// formatted like GitHub's `X-Hub-Signature-256`
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// The workflow an event is about, so receivers need not look it up
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct WorkflowContext {
+    id: i64,
+    repository: String,
+    commit_sha: String,
+    branch: Option<String>,
+    status: String,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct HookRow {
+    id: i64,
+    /// JSON list of event types; all when empty
+    events: String,
+}
+
+impl HookRow {
+    fn wants(&self, event: &Event) -> bool {
+        let events: Vec<String> = serde_json::from_str(&self.events).unwrap_or_default();
+        events.is_empty() || events.iter().any(|e| e == event.kind())
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct DueDelivery {
+    id: i64,
+    hook_id: i64,
+    event: String,
+    payload: String,
+    attempts: i64,
+    url: String,
+    secret: Option<String>,
+}
+
+/// Posts events to the registered hooks, retrying failed deliveries with backoff.
+/// Deliveries are recorded before they are sent, so retries survive restarts.
+pub struct Dispatcher {
+    config: HookConfig,
+    db_pool: SqlitePool,
+    client: Client,
+    /// Wakes the sender when deliveries were recorded
+    recorded: Notify,
+}
+
+impl Dispatcher {
+    pub fn new(config: &HookConfig, db_pool: SqlitePool) -> Arc<Self> {
+        Arc::new(Dispatcher {
+            config: config.clone(),
+            db_pool,
+            client: Client::builder()
+                .timeout(Duration::from_secs(config.timeout_secs))
+                .build()
+                .unwrap_or_default(),
+            recorded: Notify::new(),
+        })
+    }
+
+    /// Record a delivery to each hook wanting the events, as they are published
+    pub async fn run(self: Arc<Self>, events: EventBus) {
+        tokio::spawn(self.clone().send_loop());
+        let mut receiver = events.subscribe();
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Outgoing webhooks missed {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            match self.record(&event).await {
+                Ok(0) => {}
+                Ok(_) => self.recorded.notify_one(),
+                Err(e) => warn!("Failed to record {} deliveries: {:#}", event.kind(), e),
+            }
+        }
+    }
+
+    /// Store a pending delivery of the event to each hook wanting it, returning how many
+    async fn record(&self, event: &Event) -> Result<usize> {
+        let hooks: Vec<HookRow> = sqlx::query_as("SELECT id, events FROM hooks")
+            .fetch_all(&self.db_pool)
+            .await?;
+        let hooks: Vec<_> = hooks.into_iter().filter(|h| h.wants(event)).collect();
+        if hooks.is_empty() {
+            return Ok(0);
+        }
+
+        let workflow =
+            match event.workflow_id() {
+                Some(id) => sqlx::query_as::<_, WorkflowContext>(
+                    "SELECT id, repository, commit_sha, branch, status FROM workflows WHERE id = ?",
+                )
+                .bind(id)
+                .fetch_optional(&self.db_pool)
+                .await?,
+                None => None,
+            };
+        let now = chrono::Utc::now().timestamp();
+        let payload = json!({
+            "event": event,
+            "workflow": workflow,
+            "timestamp": now,
+        })
+        .to_string();
+
+        for hook in &hooks {
+            sqlx::query(
+                r#"
+                INSERT INTO hook_deliveries
+                    (hook_id, event, payload, status, attempts, next_attempt_at, created_at)
+                VALUES (?, ?, ?, 'pending', 0, ?, ?)
+                "#,
+            )
+            .bind(hook.id)
+            .bind(event.kind())
+            .bind(&payload)
+            .bind(now)
+            .bind(now)
+            .execute(&self.db_pool)
+            .await?;
+        }
+        Ok(hooks.len())
+    }
+
+    async fn send_loop(self: Arc<Self>) {
+        let mut retries = interval(RETRY_POLL_INTERVAL);
+        retries.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut pruned: Option<Instant> = None;
+        loop {
+            tokio::select! {
+                _ = self.recorded.notified() => {}
+                _ = retries.tick() => {}
+            }
+            if pruned.is_none_or(|at| at.elapsed() >= PRUNE_INTERVAL) {
+                if let Err(e) = self.prune().await {
+                    warn!("Failed to prune the outgoing webhook deliveries: {}", e);
+                }
+                pruned = Some(Instant::now());
+            }
+            if let Err(e) = self.send_due().await {
+                warn!("Failed to send outgoing webhooks: {:#}", e);
+            }
+        }
+    }
+
+    async fn prune(&self) -> Result<(), sqlx::Error> {
+        if self.config.retention_days > 0 {
+            let cutoff =
+                chrono::Utc::now().timestamp() - self.config.retention_days as i64 * 24 * 3600;
+            sqlx::query("DELETE FROM hook_deliveries WHERE created_at < ? AND status != 'pending'")
+                .bind(cutoff)
+                .execute(&self.db_pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Send the deliveries due until none are left, each hook's oldest first and the
+    /// hooks in parallel, so a slow endpoint only holds up its own deliveries. A hook
+    /// failing a delivery is skipped for the rest of the round, its next ones would
+    /// most likely wait out the timeout too.
+    async fn send_due(self: &Arc<Self>) -> Result<()> {
+        let mut failing = HashSet::new();
+        loop {
+            let due: Vec<DueDelivery> = sqlx::query_as(
+                r#"
+                SELECT d.id, d.hook_id, d.event, d.payload, d.attempts, h.url, h.secret
+                FROM hook_deliveries d JOIN hooks h ON h.id = d.hook_id
+                WHERE d.status = 'pending' AND d.next_attempt_at <= ?
+                    AND d.hook_id NOT IN (SELECT value FROM json_each(?))
+                ORDER BY d.id LIMIT ?
+                "#,
+            )
+            .bind(chrono::Utc::now().timestamp())
+            .bind(serde_json::to_string(&failing)?)
+            .bind(DELIVERY_BATCH)
+            .fetch_all(&self.db_pool)
+            .await?;
+            if due.is_empty() {
+                return Ok(());
+            }
+
+            let mut by_hook: BTreeMap<i64, Vec<DueDelivery>> = BTreeMap::new();
+            for delivery in due {
+                by_hook.entry(delivery.hook_id).or_default().push(delivery);
+            }
+            let mut hooks = JoinSet::new();
+            for (hook_id, deliveries) in by_hook {
+                let dispatcher = self.clone();
+                hooks.spawn(async move {
+                    for delivery in deliveries {
+                        let result = dispatcher.send(&delivery).await;
+                        let delivered = matches!(&result, Ok(status) if status.is_success());
+                        dispatcher.finish(&delivery, result).await?;
+                        if !delivered {
+                            return Ok(Some(hook_id));
+                        }
+                    }
+                    Ok::<_, anyhow::Error>(None)
+                });
+            }
+            while let Some(sent) = hooks.join_next().await {
+                if let Some(hook_id) = sent?? {
+                    failing.insert(hook_id);
+                }
+            }
+        }
+    }
+
+    /// Post a delivery, returning the status the endpoint answered with
+    async fn send(&self, delivery: &DueDelivery) -> Result<StatusCode> {
+        let mut request = self
+            .client
+            .post(&delivery.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::USER_AGENT, "icicle")
+            .header("X-Icicle-Event", &delivery.event)
+            .header("X-Icicle-Delivery", delivery.id.to_string());
+        if let Some(secret) = &delivery.secret {
+            request = request.header(
+                "X-Icicle-Signature-256",
+                signature(secret, delivery.payload.as_bytes()),
+            );
+        }
+        let response = request
+            .body(delivery.payload.clone())
+            .send()
+            .await
+            .context("Failed to reach the endpoint")?;
+        Ok(response.status())
+    }
+
+    /// Store how an attempt went, scheduling a retry if it failed and attempts remain
+    async fn finish(&self, delivery: &DueDelivery, result: Result<StatusCode>) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        let attempts = delivery.attempts + 1;
+        let response_status = result.as_ref().ok().map(|status| status.as_u16());
+        let error = match result {
+            Ok(status) if status.is_success() => None,
+            Ok(status) => Some(format!("The endpoint answered {}", status)),
+            Err(e) => Some(format!("{:#}", e)),
+        };
+
+        let Some(error) = error else {
+            if attempts > 1 {
+                info!(
+                    "Delivered {} of {} after {} attempts",
+                    delivery.id, delivery.event, attempts
+                );
+            }
+            sqlx::query(
+                r#"
+                UPDATE hook_deliveries
+                SET status = 'delivered', attempts = ?, response_status = ?, error = NULL,
+                    delivered_at = ?, next_attempt_at = NULL
+                WHERE id = ?
+                "#,
+            )
+            .bind(attempts)
+            .bind(response_status)
+            .bind(now)
+            .bind(delivery.id)
+            .execute(&self.db_pool)
+            .await?;
+            return Ok(());
+        };
+
+        let next_attempt_at = (attempts < self.config.max_attempts as i64)
+            .then(|| now + self.config.retry_delay(attempts as u32).as_secs() as i64);
+        match next_attempt_at {
+            Some(_) => warn!(
+                "Delivery {} of {} failed, retrying: {}",
+                delivery.id, delivery.event, error
+            ),
+            None => warn!(
+                "Delivery {} of {} failed {} times, giving up: {}",
+                delivery.id, delivery.event, attempts, error
+            ),
+        }
+        sqlx::query(
+            r#"
+            UPDATE hook_deliveries
+            SET status = ?, attempts = ?, response_status = ?, error = ?, next_attempt_at = ?
+            WHERE id = ?
+            "#,
+        )
+        .bind(if next_attempt_at.is_some() {
+            "pending"
+        } else {
+            "failed"
+        })
+        .bind(attempts)
+        .bind(response_status)
+        .bind(&error)
+        .bind(next_attempt_at)
+        .bind(delivery.id)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::WorkflowStatus;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    #[test]
+    fn test_signature() {
+        assert_eq!(
+            signature("key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let config = HookConfig::default();
+        assert_eq!(config.retry_delay(1), Duration::from_secs(30));
+        assert_eq!(config.retry_delay(2), Duration::from_secs(60));
+        assert_eq!(config.retry_delay(4), Duration::from_secs(240));
+    }
+
+    #[test]
+    fn test_wants() {
+        let event = Event::WorkflowStatus {
+            workflow_id: 1,
+            status: WorkflowStatus::Completed,
+        };
+        assert_eq!(serde_json::to_value(&event).unwrap()["type"], event.kind());
+
+        let hook = |events: &str| HookRow {
+            id: 1,
+            events: events.to_string(),
+        };
+        assert!(hook("[]").wants(&event));
+        assert!(hook(r#"["job_status", "workflow_status"]"#).wants(&event));
+        assert!(!hook(r#"["job_status"]"#).wants(&event));
+    }
+
+    #[tokio::test]
+    async fn test_dead_endpoint_does_not_stall_other_hooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_pool = crate::db::init_database(&format!(
+            "sqlite:{}",
+            dir.path().join("icicle.db").display()
+        ))
+        .await
+        .unwrap();
+        let config = HookConfig {
+            timeout_secs: 2,
+            ..HookConfig::default()
+        };
+        let dispatcher = Dispatcher::new(&config, db_pool.clone());
+
+        // One endpoint accepts connections and never answers, the other answers 200
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_url = format!("http://{}/", dead.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((connection, _)) = dead.accept().await {
+                connections.push(connection);
+            }
+        });
+        let alive = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let alive_url = format!("http://{}/", alive.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut connection, _)) = alive.accept().await {
+                let _ = connection
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                    .await;
+            }
+        });
+
+        for (id, url) in [(1, &dead_url), (2, &alive_url)] {
+            sqlx::query("INSERT INTO hooks (id, url, events, created_at) VALUES (?, ?, '[]', 0)")
+                .bind(id)
+                .bind(url)
+                .execute(&db_pool)
+                .await
+                .unwrap();
+        }
+        for hook_id in [1, 1, 1, 2] {
+            sqlx::query(
+                r#"
+                INSERT INTO hook_deliveries
+                    (hook_id, event, payload, status, attempts, next_attempt_at, created_at)
+                VALUES (?, 'workflow_status', '{}', 'pending', 0, 0, 0)
+                "#,
+            )
+            .bind(hook_id)
+            .execute(&db_pool)
+            .await
+            .unwrap();
+        }
+
+        let started = Instant::now();
+        dispatcher.send_due().await.unwrap();
+        // Waiting out the timeout once, not once per delivery to the dead endpoint
+        assert!(started.elapsed() < Duration::from_secs(4));
+
+        let statuses: Vec<(i64, String, i64)> =
+            sqlx::query_as("SELECT hook_id, status, attempts FROM hook_deliveries ORDER BY id")
+                .fetch_all(&db_pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            statuses,
+            [
+                (1, "pending".to_string(), 1),
+                (1, "pending".to_string(), 0),
+                (1, "pending".to_string(), 0),
+                (2, "delivered".to_string(), 1),
+            ]
+        );
+    }
+}
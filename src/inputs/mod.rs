@@ -0,0 +1,199 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sqlx::SqlitePool;
+use std::{collections::HashMap, path::Path};
+use tracing::info;
+
+#[derive(Debug, Deserialize)]
+struct FlakeLock {
+    nodes: HashMap<String, LockNode>,
+    root: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct LockNode {
+    /// Input name to node name, or to the path of the input it follows
+    inputs: HashMap<String, Value>,
+    locked: Option<Map<String, Value>>,
+    original: Option<Map<String, Value>>,
+}
+
+/// A direct input of a flake, as locked in flake.lock
+#[derive(Debug, PartialEq)]
+pub struct FlakeInput {
+    pub name: String,
+    pub kind: String,
+    pub source: String,
+    pub rev: Option<String>,
+    pub last_modified: Option<i64>,
+}
+
+/// Parse the direct inputs of the root flake; inputs that follow another are left out
+pub fn parse_flake_lock(lock: &str) -> Result<Vec<FlakeInput>> {
+    let lock: FlakeLock = serde_json::from_str(lock).context("Invalid flake.lock")?;
+    let root = lock
+        .nodes
+        .get(&lock.root)
+        .ok_or_else(|| anyhow!("flake.lock has no root node"))?;
+
+    let mut inputs: Vec<FlakeInput> = root
+        .inputs
+        .iter()
+        .filter_map(|(name, node)| {
+            let node = lock.nodes.get(node.as_str()?)?;
+            let locked = node.locked.as_ref()?;
+            let field = |name: &str| locked.get(name).and_then(Value::as_str);
+            let kind = field("type").unwrap_or("unknown").to_string();
+            Some(FlakeInput {
+                name: name.clone(),
+                source: describe(&kind, node.original.as_ref().unwrap_or(locked)),
+                rev: field("rev").map(str::to_string),
+                last_modified: locked.get("lastModified").and_then(Value::as_i64),
+                kind,
+            })
+        })
+        .collect();
+    inputs.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(inputs)
+}
+
+/// Format an input reference the way it is written in flake.nix
+fn describe(kind: &str, reference: &Map<String, Value>) -> String {
+    let field = |name: &str| reference.get(name).and_then(Value::as_str);
+    match kind {
+        "github" | "gitlab" | "sourcehut" => {
+            let mut source = format!(
+                "{}:{}/{}",
+                kind,
+                field("owner").unwrap_or_default(),
+                field("repo").unwrap_or_default()
+            );
+            if let Some(git_ref) = field("ref") {
+                source.push('/');
+                source.push_str(git_ref);
+            }
+            source
+        }
+        "path" => format!("path:{}", field("path").unwrap_or_default()),
+        "indirect" => format!("flake:{}", field("id").unwrap_or_default()),
+        _ => field("url").unwrap_or(kind).to_string(),
+    }
+}
+
+/// Store the flake inputs of a workflow's checkout, noting which changed since the
+/// previous workflow of the same branch
+pub async fn record(db_pool: &SqlitePool, workflow_id: i64, repo_path: &Path) -> Result<()> {
+    let lock = match tokio::fs::read_to_string(repo_path.join("flake.lock")).await {
+        Ok(lock) => lock,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context("Failed to read flake.lock"),
+    };
+    let inputs = parse_flake_lock(&lock)?;
+
+    let previous: HashMap<String, Option<String>> = sqlx::query_as::<_, (String, Option<String>)>(
+        r#"
+        SELECT name, rev FROM workflow_inputs
+        WHERE workflow_id = (
+            SELECT MAX(i.workflow_id) FROM workflow_inputs i
+            JOIN workflows w ON w.id = i.workflow_id
+            JOIN workflows current ON current.id = ?1
+            WHERE w.repository = current.repository AND w.branch = current.branch
+              AND i.workflow_id < ?1
+        )
+        "#,
+    )
+    .bind(workflow_id)
+    .fetch_all(db_pool)
+    .await?
+    .into_iter()
+    .collect();
+
+    let mut changed = Vec::new();
+    let mut tx = db_pool.begin().await?;
+    for input in &inputs {
+        // Nothing changes on a branch's first workflow
+        let previous_rev = previous.get(&input.name).cloned().flatten();
+        let is_changed = !previous.is_empty() && previous_rev != input.rev;
+        if is_changed {
+            changed.push(input.name.clone());
+        }
+        sqlx::query(
+            r#"
+            INSERT INTO workflow_inputs
+                (workflow_id, name, type, source, rev, last_modified, previous_rev, changed)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(workflow_id)
+        .bind(&input.name)
+        .bind(&input.kind)
+        .bind(&input.source)
+        .bind(&input.rev)
+        .bind(input.last_modified)
+        .bind(previous_rev)
+        .bind(is_changed)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    if !changed.is_empty() {
+        info!(
+            "Workflow {} bumps flake inputs: {}",
+            workflow_id,
+            changed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_flake_lock() {
+        let lock = r#"{
+          "nodes": {
+            "flake-utils": {
+              "locked": {"lastModified": 1700000000, "narHash": "sha256-a", "owner": "numtide",
+                         "repo": "flake-utils", "rev": "1111", "type": "github"},
+              "original": {"owner": "numtide", "repo": "flake-utils", "type": "github"}
+            },
+            "nixpkgs": {
+              "locked": {"lastModified": 1710000000, "narHash": "sha256-b", "owner": "NixOS",
+                         "repo": "nixpkgs", "rev": "2222", "type": "github"},
+              "original": {"owner": "NixOS", "ref": "nixos-unstable", "repo": "nixpkgs",
+                           "type": "github"}
+            },
+            "root": {
+              "inputs": {
+                "flake-utils": "flake-utils",
+                "nixpkgs": "nixpkgs",
+                "systems": ["flake-utils", "systems"]
+              }
+            }
+          },
+          "root": "root",
+          "version": 7
+        }"#;
+        let inputs = parse_flake_lock(lock).unwrap();
+        assert_eq!(inputs.len(), 2);
+        assert_eq!(inputs[0].name, "flake-utils");
+        assert_eq!(inputs[0].source, "github:numtide/flake-utils");
+        assert_eq!(
+            inputs[1],
+            FlakeInput {
+                name: "nixpkgs".to_string(),
+                kind: "github".to_string(),
+                source: "github:NixOS/nixpkgs/nixos-unstable".to_string(),
+                rev: Some("2222".to_string()),
+                last_modified: Some(1710000000),
+            }
+        );
+
+        assert!(parse_flake_lock("{}").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::{collections::HashMap, fmt, path::Path};
+use tracing::{info, warn};
+
+/// Directory of a build output holding JUnit XML reports
+const REPORT_DIR: &str = "junit";
+/// Failure output beyond this many bytes is cut off
+const MAX_MESSAGE_LEN: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TestStatus {
+    Passed,
+    Failed,
+    Error,
+    Skipped,
+}
+
+impl fmt::Display for TestStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TestStatus::Passed => "passed",
+            TestStatus::Failed => "failed",
+            TestStatus::Error => "error",
+            TestStatus::Skipped => "skipped",
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TestCase {
+    pub suite: String,
+    pub classname: Option<String>,
+    pub name: String,
+    pub status: TestStatus,
+    pub duration_ms: Option<i64>,
+    pub message: Option<String>,
+}
+
+/// Parse a JUnit XML report, either a `<testsuites>` or a single `<testsuite>`
+pub fn parse_report(xml: &str) -> Result<Vec<TestCase>> {
+    let document = roxmltree::Document::parse(xml).context("Invalid JUnit XML")?;
+    let cases = document
+        .descendants()
+        .filter(|node| node.has_tag_name("testcase"))
+        .map(|case| {
+            let suite = case
+                .ancestors()
+                .find(|node| node.has_tag_name("testsuite"))
+                .and_then(|suite| suite.attribute("name"))
+                .unwrap_or_default()
+                .to_string();
+            let outcome = case.children().find_map(|child| {
+                let status = match child.tag_name().name() {
+                    "failure" => TestStatus::Failed,
+                    "error" => TestStatus::Error,
+                    "skipped" => TestStatus::Skipped,
+                    _ => return None,
+                };
+                Some((status, child))
+            });
+            let (status, message) = match outcome {
+                None => (TestStatus::Passed, None),
+                Some((status, node)) => {
+                    let message = [node.attribute("message"), node.text()]
+                        .into_iter()
+                        .flatten()
+                        .map(str::trim)
+                        .filter(|text| !text.is_empty())
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    (status, Some(truncate(message)).filter(|m| !m.is_empty()))
+                }
+            };
+            TestCase {
+                suite,
+                classname: case.attribute("classname").map(str::to_string),
+                name: case.attribute("name").unwrap_or_default().to_string(),
+                status,
+                duration_ms: case
+                    .attribute("time")
+                    .and_then(|time| time.parse::<f64>().ok())
+                    .map(|secs| (secs * 1000.0).round() as i64),
+                message,
+            }
+        })
+        .collect();
+    Ok(cases)
+}
+
+fn truncate(mut message: String) -> String {
+    if message.len() > MAX_MESSAGE_LEN {
+        let mut end = MAX_MESSAGE_LEN;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+        message.push_str("\n[truncated]");
+    }
+    message
+}
+
+/// Store the test cases reported by a build's outputs, replacing those of
+/// earlier attempts; returns the number of cases stored
+pub async fn collect(
+    db_pool: &SqlitePool,
+    drv_path: &str,
+    outputs: &HashMap<String, String>,
+) -> Result<usize> {
+    let mut cases = Vec::new();
+    for out_path in outputs.values() {
+        let Ok(mut entries) = tokio::fs::read_dir(Path::new(out_path).join(REPORT_DIR)).await
+        else {
+            continue;
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "xml") {
+                continue;
+            }
+            let parsed = tokio::fs::read_to_string(&path)
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|xml| parse_report(&xml));
+            match parsed {
+                Ok(parsed) => cases.extend(parsed),
+                Err(e) => warn!("Skipping test report {}: {:#}", path.display(), e),
+            }
+        }
+    }
+
+    let mut tx = db_pool.begin().await?;
+    sqlx::query("DELETE FROM test_results WHERE drv_path = ?")
+        .bind(drv_path)
+        .execute(&mut *tx)
+        .await?;
+    for case in &cases {
+        sqlx::query(
+            r#"
+            INSERT INTO test_results
+                (drv_path, suite, classname, name, status, duration_ms, message)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(drv_path)
+        .bind(&case.suite)
+        .bind(&case.classname)
+        .bind(&case.name)
+        .bind(case.status.to_string())
+        .bind(case.duration_ms)
+        .bind(&case.message)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    if !cases.is_empty() {
+        let failed = cases
+            .iter()
+            .filter(|case| matches!(case.status, TestStatus::Failed | TestStatus::Error))
+            .count();
+        info!(
+            "Recorded {} test cases of {} ({} failed)",
+            cases.len(),
+            drv_path,
+            failed
+        );
+    }
+    Ok(cases.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_report() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <testsuites>
+              <testsuite name="parser" tests="3">
+                <testcase classname="parser.lexer" name="tokens" time="0.25"/>
+                <testcase classname="parser.lexer" name="unicode" time="1">
+                  <failure message="assertion failed">left: 1, right: 2</failure>
+                </testcase>
+                <testcase name="slow"><skipped/></testcase>
+              </testsuite>
+            </testsuites>"#;
+        let cases = parse_report(xml).unwrap();
+        assert_eq!(cases.len(), 3);
+        assert_eq!(cases[0].status, TestStatus::Passed);
+        assert_eq!(cases[0].duration_ms, Some(250));
+        assert_eq!(cases[1].suite, "parser");
+        assert_eq!(cases[1].status, TestStatus::Failed);
+        assert_eq!(
+            cases[1].message.as_deref(),
+            Some("assertion failed\nleft: 1, right: 2")
+        );
+        assert_eq!(cases[2].status, TestStatus::Skipped);
+        assert_eq!(cases[2].message, None);
+
+        assert!(parse_report("<testsuite>").is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use crate::systemd;
+use anyhow::{Context, Result};
+use sqlx::SqlitePool;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Name of the single lease guarding the build queue
+const LEADER_LEASE: &str = "leader";
+
+/// Leader election between instances sharing a database. The build queue lives in
+/// memory, so only the lease holder serves webhooks and runs builds; the other
+/// instances wait as hot standby and take over once the leader stops renewing it.
+/// This is failover only: standby instances serve nothing, and neither a shared
+/// Postgres database nor a queue in the database are supported.
+#[derive(Clone)]
+pub struct Lease {
+    db_pool: SqlitePool,
+    holder: String,
+    duration: Duration,
+}
+
+impl Lease {
+    /// Wait until this instance holds the leader lease
+    pub async fn acquire(
+        db_pool: SqlitePool,
+        instance_id: Option<String>,
+        lease_secs: u64,
+    ) -> Result<Self> {
+        let lease = Self {
+            db_pool,
+            holder: instance_id.unwrap_or_else(default_instance_id),
+            duration: Duration::from_secs(lease_secs.max(3)),
+        };
+
+        let mut announced = false;
+        while !lease.try_acquire().await? {
+            if !announced {
+                let leader = lease.current_holder().await?.unwrap_or_default();
+                info!("Instance {} is standby, leader is {}", lease.holder, leader);
+                // Waiting is the expected state of a standby, not a slow start
+                systemd::ready();
+                systemd::status(&format!("Standby, leader is {}", leader));
+                announced = true;
+            }
+            tokio::time::sleep(lease.renew_interval()).await;
+        }
+        info!("Instance {} holds the leader lease", lease.holder);
+        if announced {
+            systemd::status("Taking over as leader");
+        }
+        Ok(lease)
+    }
+
+    fn renew_interval(&self) -> Duration {
+        self.duration / 3
+    }
+
+    /// Take the lease if it is free, expired or already ours; also renews it
+    async fn try_acquire(&self) -> Result<bool> {
+        let now = chrono::Utc::now().timestamp();
+        let expires_at = now + self.duration.as_secs() as i64;
+        let updated = sqlx::query(
+            r#"
+            INSERT INTO instance_leases (name, holder, expires_at) VALUES (?, ?, ?)
+            ON CONFLICT(name) DO UPDATE SET holder = excluded.holder, expires_at = excluded.expires_at
+            WHERE instance_leases.holder = excluded.holder OR instance_leases.expires_at < ?
+            "#,
+        )
+        .bind(LEADER_LEASE)
+        .bind(&self.holder)
+        .bind(expires_at)
+        .bind(now)
+        .execute(&self.db_pool)
+        .await
+        .context("Failed to acquire the leader lease")?
+        .rows_affected();
+        Ok(updated == 1)
+    }
+
+    async fn current_holder(&self) -> Result<Option<String>> {
+        Ok(
+            sqlx::query_scalar("SELECT holder FROM instance_leases WHERE name = ?")
+                .bind(LEADER_LEASE)
+                .fetch_optional(&self.db_pool)
+                .await?,
+        )
+    }
+
+    /// Renew the lease until it is lost, then exit so a standby takes over cleanly
+    pub async fn keep(self) {
+        let mut interval = tokio::time::interval(self.renew_interval());
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match self.try_acquire().await {
+                Ok(true) => {}
+                Ok(false) => {
+                    error!("Leader lease was taken over by another instance, exiting");
+                    std::process::exit(1);
+                }
+                // A standby only takes over once the lease expires, so keep trying until then
+                Err(e) => warn!("Failed to renew the leader lease: {:#}", e),
+            }
+        }
+    }
+
+    /// Give up the lease so a standby can take over without waiting for it to expire
+    pub async fn release(&self) {
+        let result = sqlx::query("DELETE FROM instance_leases WHERE name = ? AND holder = ?")
+            .bind(LEADER_LEASE)
+            .bind(&self.holder)
+            .execute(&self.db_pool)
+            .await;
+        if let Err(e) = result {
+            warn!("Failed to release the leader lease: {}", e);
+        }
+    }
+}
+
+pub(crate) fn default_instance_id() -> String {
+    let hostname = std::fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "icicle".to_string());
+    format!("{}-{}", hostname, std::process::id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lease(db_pool: &SqlitePool, holder: &str) -> Lease {
+        Lease {
+            db_pool: db_pool.clone(),
+            holder: holder.to_string(),
+            duration: Duration::from_secs(30),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_single_leader() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_pool = crate::db::init_database(&format!(
+            "sqlite:{}",
+            dir.path().join("icicle.db").display()
+        ))
+        .await
+        .unwrap();
+        let a = lease(&db_pool, "a");
+        let b = lease(&db_pool, "b");
+
+        assert!(a.try_acquire().await.unwrap());
+        assert!(!b.try_acquire().await.unwrap());
+        // Renewing is acquiring again
+        assert!(a.try_acquire().await.unwrap());
+        assert_eq!(b.current_holder().await.unwrap().as_deref(), Some("a"));
+
+        // A released lease is free right away
+        a.release().await;
+        assert!(b.try_acquire().await.unwrap());
+        assert!(!a.try_acquire().await.unwrap());
+
+        // An expired lease is taken over, and its former holder can't renew it
+        sqlx::query("UPDATE instance_leases SET expires_at = ?")
+            .bind(chrono::Utc::now().timestamp() - 1)
+            .execute(&db_pool)
+            .await
+            .unwrap();
+        assert!(a.try_acquire().await.unwrap());
+        assert!(!b.try_acquire().await.unwrap());
+        // Releasing only gives up one's own lease
+        b.release().await;
+        assert_eq!(b.current_holder().await.unwrap().as_deref(), Some("a"));
+    }
+
+    #[tokio::test]
+    async fn test_acquire_waits_for_the_leader() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_pool = crate::db::init_database(&format!(
+            "sqlite:{}",
+            dir.path().join("icicle.db").display()
+        ))
+        .await
+        .unwrap();
+        let leader = Lease::acquire(db_pool.clone(), Some("a".to_string()), 3)
+            .await
+            .unwrap();
+
+        let standby = tokio::spawn(Lease::acquire(db_pool, Some("b".to_string()), 3));
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(!standby.is_finished());
+        leader.release().await;
+        let standby = tokio::time::timeout(Duration::from_secs(5), standby)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(standby.holder, "b");
+    }
+}
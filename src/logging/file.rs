@@ -0,0 +1,192 @@
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    Hourly,
+    #[default]
+    Daily,
+    /// Only rotate on size
+    Never,
+}
+
+impl Rotation {
+    /// Files written in the same period share this key
+    fn period(self, time: DateTime<Local>) -> Option<String> {
+        match self {
+            Rotation::Hourly => Some(time.format("%Y%m%d%H").to_string()),
+            Rotation::Daily => Some(time.format("%Y%m%d").to_string()),
+            Rotation::Never => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct LogFileConfig {
+    /// Log file; rotated files are kept next to it with a timestamp suffix
+    pub path: PathBuf,
+    #[serde(default)]
+    pub rotation: Rotation,
+    /// Also rotate once the file reaches this many megabytes
+    pub max_size_mb: Option<u64>,
+    /// Number of rotated files to keep
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+}
+
+fn default_max_files() -> usize {
+    7
+}
+
+/// Appends to a log file, rotating it by time and size and pruning old rotations
+pub struct RotatingFile {
+    config: LogFileConfig,
+    file: File,
+    size: u64,
+    period: Option<String>,
+}
+
+impl RotatingFile {
+    pub fn open(config: LogFileConfig) -> io::Result<Self> {
+        if let Some(dir) = config
+            .path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+        {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let metadata = file.metadata()?;
+        // A file left over from an earlier run belongs to the period it was written in
+        let modified = metadata
+            .modified()
+            .map(DateTime::<Local>::from)
+            .unwrap_or_else(|_| Local::now());
+        Ok(Self {
+            period: config.rotation.period(modified),
+            size: metadata.len(),
+            config,
+            file,
+        })
+    }
+
+    fn needs_rotation(&self, incoming: usize) -> bool {
+        let period_over = self.config.rotation.period(Local::now()) != self.period;
+        let too_big = self
+            .config
+            .max_size_mb
+            .is_some_and(|max| self.size > 0 && self.size + incoming as u64 > max * 1024 * 1024);
+        period_over || too_big
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let stamp = Local::now().format("%Y%m%d-%H%M%S").to_string();
+        let mut rotated = suffixed(&self.config.path, &stamp);
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = suffixed(&self.config.path, &format!("{}.{}", stamp, n));
+            n += 1;
+        }
+        fs::rename(&self.config.path, rotated)?;
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)?;
+        self.size = 0;
+        self.period = self.config.rotation.period(Local::now());
+        self.prune()
+    }
+
+    /// Delete the oldest rotated files beyond `max_files`
+    fn prune(&self) -> io::Result<()> {
+        let Some(name) = self.config.path.file_name().and_then(|n| n.to_str()) else {
+            return Ok(());
+        };
+        let prefix = format!("{}.", name);
+        let dir = match self.config.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        let mut rotated: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .map(|entry| entry.path())
+            .collect();
+        // Timestamp suffixes sort chronologically
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(self.config.max_files);
+        for old in &rotated[..excess] {
+            fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation(buf.len()) {
+            // Keep logging to the current file rather than losing lines
+            if let Err(e) = self.rotate() {
+                eprintln!("Failed to rotate {}: {}", self.config.path.display(), e);
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotates_by_size_and_prunes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("icicle.log");
+        let mut file = RotatingFile::open(LogFileConfig {
+            path: path.clone(),
+            rotation: Rotation::Never,
+            max_size_mb: Some(1),
+            max_files: 2,
+        })
+        .unwrap();
+
+        let line = vec![b'x'; 600 * 1024];
+        for _ in 0..5 {
+            file.write_all(&line).unwrap();
+        }
+
+        let mut names: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 3, "{:?}", names);
+        assert_eq!(names[0], "icicle.log");
+        assert_eq!(fs::metadata(&path).unwrap().len(), line.len() as u64);
+    }
+}
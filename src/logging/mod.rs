@@ -0,0 +1,87 @@
+use axum::{extract::Request, Router};
+use serde::Deserialize;
+use std::{io, sync::Mutex};
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::info_span;
+use tracing_subscriber::{
+    layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+mod file;
+
+pub use file::LogFileConfig;
+use file::RotatingFile;
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, including the fields of all enclosing spans
+    Json,
+}
+
+/// Install the global tracing subscriber, logging to stdout and optionally to `file`.
+/// `RUST_LOG` takes precedence over the configured level when set.
+pub fn init(format: LogFormat, level: &str, file: Option<&LogFileConfig>) -> io::Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+    let mut layers = vec![fmt_layer(format, true, io::stdout)];
+    if let Some(file) = file {
+        let writer = Mutex::new(RotatingFile::open(file.clone())?);
+        layers.push(fmt_layer(format, false, writer));
+    }
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(filter)
+        .init();
+    Ok(())
+}
+
+fn fmt_layer<W>(format: LogFormat, ansi: bool, writer: W) -> BoxedLayer
+where
+    W: for<'w> tracing_subscriber::fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_ansi(ansi)
+        .with_writer(writer);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    }
+}
+
+/// Assign every request an `x-request-id` (keeping one supplied by a proxy) and log within a
+/// span carrying it, so all lines emitted while handling the request can be correlated
+pub fn layer<S>(router: Router<S>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    router
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(
+            TraceLayer::new_for_http().make_span_with(|request: &Request| {
+                let request_id = request
+                    .extensions()
+                    .get::<RequestId>()
+                    .and_then(|id| id.header_value().to_str().ok())
+                    .unwrap_or_default();
+                info_span!(
+                    "request",
+                    request_id,
+                    method = %request.method(),
+                    uri = %request.uri(),
+                )
+            }),
+        )
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+}
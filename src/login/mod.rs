@@ -0,0 +1,568 @@
+use crate::{
+    auth::{self, SESSION_COOKIE},
+    github::{GitHubClient, GitHubConfig},
+};
+use anyhow::{anyhow, Context, Result};
+use askama::Template;
+use axum::{
+    extract::{Query, State},
+    http::{header::SET_COOKIE, HeaderMap, StatusCode, Uri},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::get,
+    Router,
+};
+use reqwest::{header, Client, Url};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// How long a visitor may take to sign in with the provider
+const PENDING_TTL: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    /// GitHub's OAuth apps, on the host of [github]
+    GitHub,
+    /// Any OpenID Connect provider
+    Oidc,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct LoginConfig {
+    /// Where visitors sign in to the dashboard; there is no login when unset
+    pub provider: Option<Provider>,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// OIDC issuer URL, whose discovery document gives the provider's endpoints
+    pub issuer: Option<String>,
+    /// Users who may sign in: GitHub logins, or OIDC subjects or verified emails
+    pub allowed_users: Vec<String>,
+    /// Whose members may sign in: GitHub organizations, or OIDC groups
+    pub allowed_orgs: Vec<String>,
+    /// How long a login lasts
+    pub session_hours: u64,
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        LoginConfig {
+            provider: None,
+            client_id: String::new(),
+            client_secret: None,
+            issuer: None,
+            allowed_users: Vec::new(),
+            allowed_orgs: Vec::new(),
+            session_hours: 24 * 7,
+        }
+    }
+}
+
+impl LoginConfig {
+    pub fn validate(&self, public_url: Option<&str>) -> Result<()> {
+        if public_url.is_none() {
+            return Err(anyhow!(
+                "public_url in [notify] must be set, for the provider to redirect back to"
+            ));
+        }
+        if self.client_id.is_empty() || self.client_secret.is_none() {
+            return Err(anyhow!("client_id and client_secret must be set"));
+        }
+        if self.provider == Some(Provider::Oidc) && self.issuer.is_none() {
+            return Err(anyhow!("issuer must be set for the oidc provider"));
+        }
+        if self.allowed_users.is_empty() && self.allowed_orgs.is_empty() {
+            return Err(anyhow!(
+                "allowed_users or allowed_orgs must be set, or anyone could sign in"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether the identity is on the allow lists, ignoring case as providers do
+    fn allows(&self, identity: &Identity) -> bool {
+        let listed = |list: &[String], names: &[String]| {
+            list.iter()
+                .any(|allowed| names.iter().any(|name| name.eq_ignore_ascii_case(allowed)))
+        };
+        listed(&self.allowed_users, &identity.names) || listed(&self.allowed_orgs, &identity.groups)
+    }
+}
+
+/// Who signed in with the provider
+#[derive(Debug)]
+struct Identity {
+    /// What the session is known by first, then other names the user goes by; only
+    /// names no other user can take, as they map to users and their organizations
+    names: Vec<String>,
+    /// How the user is shown in messages, e.g. their OIDC username
+    display: String,
+    /// Organizations or groups the user belongs to
+    groups: Vec<String>,
+}
+
+/// Endpoints from an OIDC discovery document
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserInfo {
+    sub: String,
+    preferred_username: Option<String>,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+impl UserInfo {
+    /// Who signed in, known by their verified email or else their subject. Usernames
+    /// are neither unique nor stable, users can often rename themselves to another
+    /// user's name, so they are only shown. The email only counts once the provider
+    /// verified it, as anyone could claim an allowed address otherwise.
+    fn identity(self) -> Identity {
+        let email = self.email.filter(|_| self.email_verified);
+        let names: Vec<String> = email.into_iter().chain([self.sub]).collect();
+        Identity {
+            display: self.preferred_username.unwrap_or_else(|| names[0].clone()),
+            names,
+            groups: self.groups,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// A visitor sent to the provider, with the page to return to
+struct Pending {
+    started: Instant,
+    next: String,
+}
+
+/// Signs visitors in to the dashboard through the configured provider
+pub struct Login {
+    config: LoginConfig,
+    provider: Provider,
+    github: GitHubConfig,
+    /// Where the provider sends visitors back to
+    callback_url: String,
+    /// Whether the dashboard is served over HTTPS, so the cookie must only travel there
+    secure: bool,
+    client: Client,
+    /// Logins under way, by the state passed through the provider
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+impl Login {
+    /// The login of `config`, if it has a provider
+    pub fn new(config: &LoginConfig, github: &GitHubConfig, public_url: &str) -> Option<Self> {
+        let public_url = public_url.trim_end_matches('/');
+        Some(Login {
+            provider: config.provider?,
+            config: config.clone(),
+            github: github.clone(),
+            callback_url: format!("{}/login/callback", public_url),
+            secure: public_url.starts_with("https://"),
+            client: Client::new(),
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn discover(&self) -> Result<Discovery> {
+        let issuer = self.config.issuer.as_deref().unwrap_or_default();
+        self.client
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                issuer.trim_end_matches('/')
+            ))
+            .send()
+            .await
+            .context("Failed to reach the OIDC provider")?
+            .error_for_status()
+            .context("The OIDC provider refused the discovery request")?
+            .json()
+            .await
+            .context("Invalid OIDC discovery document")
+    }
+
+    /// Where to send a visitor to sign in, remembering the page to return to
+    async fn authorize_url(&self, next: String) -> Result<Url> {
+        let state = auth::generate_token();
+        let (endpoint, scope) = match self.provider {
+            Provider::GitHub => (
+                format!("https://{}/login/oauth/authorize", self.github.host),
+                // Private organization memberships are only listed with read:org
+                if self.config.allowed_orgs.is_empty() {
+                    ""
+                } else {
+                    "read:org"
+                },
+            ),
+            Provider::Oidc => (
+                self.discover().await?.authorization_endpoint,
+                "openid profile email",
+            ),
+        };
+        let url = Url::parse_with_params(
+            &endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", self.config.client_id.as_str()),
+                ("redirect_uri", self.callback_url.as_str()),
+                ("scope", scope),
+                ("state", state.as_str()),
+            ],
+        )
+        .context("Invalid authorization endpoint")?;
+
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        pending.retain(|_, p| p.started.elapsed() < PENDING_TTL);
+        pending.insert(
+            state,
+            Pending {
+                started: Instant::now(),
+                next,
+            },
+        );
+        Ok(url)
+    }
+
+    /// The page to return to after signing in, if `state` is a login under way
+    fn take_pending(&self, state: &str) -> Option<String> {
+        self.pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(state)
+            .filter(|p| p.started.elapsed() < PENDING_TTL)
+            .map(|p| p.next)
+    }
+
+    /// Trade the code the provider sent the visitor back with for an access token
+    async fn access_token(&self, endpoint: &str, code: &str) -> Result<String> {
+        let response: TokenResponse = self
+            .client
+            .post(endpoint)
+            .header(header::ACCEPT, "application/json")
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", self.callback_url.as_str()),
+                ("client_id", self.config.client_id.as_str()),
+                (
+                    "client_secret",
+                    self.config.client_secret.as_deref().unwrap_or_default(),
+                ),
+            ])
+            .send()
+            .await
+            .context("Failed to reach the provider")?
+            .json()
+            .await
+            .context("Invalid token response")?;
+        response.access_token.ok_or_else(|| {
+            anyhow!(
+                "The provider refused the code: {}",
+                response
+                    .error_description
+                    .or(response.error)
+                    .unwrap_or_default()
+            )
+        })
+    }
+
+    async fn identify(&self, code: &str) -> Result<Identity> {
+        match self.provider {
+            Provider::GitHub => {
+                let endpoint = format!("https://{}/login/oauth/access_token", self.github.host);
+                let token = self.access_token(&endpoint, code).await?;
+                let github = GitHubClient::new(&self.github.api_url, &token);
+                let groups = if self.config.allowed_orgs.is_empty() {
+                    Vec::new()
+                } else {
+                    github.organizations().await?
+                };
+                let login = github.user().await?;
+                Ok(Identity {
+                    display: login.clone(),
+                    names: vec![login],
+                    groups,
+                })
+            }
+            Provider::Oidc => {
+                let discovery = self.discover().await?;
+                let token = self.access_token(&discovery.token_endpoint, code).await?;
+                let info: UserInfo = self
+                    .client
+                    .get(&discovery.userinfo_endpoint)
+                    .bearer_auth(token)
+                    .send()
+                    .await
+                    .context("Failed to reach the OIDC provider")?
+                    .error_for_status()
+                    .context("The OIDC provider refused the userinfo request")?
+                    .json()
+                    .await
+                    .context("Invalid userinfo")?;
+                Ok(info.identity())
+            }
+        }
+    }
+
+    /// Cookie setting the session secret, or clearing it when `None`
+    fn cookie(&self, secret: Option<&str>) -> String {
+        let max_age = match secret {
+            Some(_) => self.config.session_hours * 3600,
+            None => 0,
+        };
+        format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+            SESSION_COOKIE,
+            secret.unwrap_or_default(),
+            max_age,
+            if self.secure { "; Secure" } else { "" }
+        )
+    }
+}
+
+/// The path to sign in at, coming back to `uri` afterwards
+pub fn login_path(uri: &Uri) -> String {
+    let next = uri.path_and_query().map_or("/", |p| p.as_str());
+    Url::parse_with_params("http://icicle/login", &[("next", next)])
+        .map(|url| format!("/login?{}", url.query().unwrap_or_default()))
+        .unwrap_or_else(|_| "/login".to_string())
+}
+
+pub fn routes() -> Router<Arc<crate::AppState>> {
+    Router::new()
+        .route("/login", get(login))
+        .route("/login/callback", get(callback))
+        .route("/logout", get(logout))
+}
+
+#[derive(Template)]
+#[template(path = "login.html")]
+struct LoginTemplate {
+    message: String,
+}
+
+fn failure(status: StatusCode, message: impl Into<String>) -> Response {
+    let page = LoginTemplate {
+        message: message.into(),
+    };
+    match page.render() {
+        Ok(html) => (status, Html(html)).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginQuery {
+    next: Option<String>,
+}
+
+async fn login(
+    State(app_state): State<Arc<crate::AppState>>,
+    Query(query): Query<LoginQuery>,
+) -> Response {
+    let Some(login) = &app_state.login else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let next = query
+        .next
+        .filter(|next| is_local_path(next))
+        .unwrap_or_else(|| "/".to_string());
+    match login.authorize_url(next).await {
+        Ok(url) => Redirect::to(url.as_str()).into_response(),
+        Err(e) => {
+            warn!("Failed to start a login: {:#}", e);
+            failure(
+                StatusCode::BAD_GATEWAY,
+                "The login provider is unavailable.",
+            )
+        }
+    }
+}
+
+/// Whether `next` is a page of this dashboard rather than another site: browsers
+/// read `//host` and `/\host` as protocol-relative, and drop tabs and newlines
+fn is_local_path(next: &str) -> bool {
+    next.starts_with('/')
+        && !matches!(next.as_bytes().get(1), Some(b'/' | b'\\'))
+        && !next.chars().any(char::is_control)
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+async fn callback(
+    State(app_state): State<Arc<crate::AppState>>,
+    Query(query): Query<CallbackQuery>,
+) -> Response {
+    let Some(login) = &app_state.login else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let Some(next) = query.state.as_deref().and_then(|s| login.take_pending(s)) else {
+        return failure(
+            StatusCode::BAD_REQUEST,
+            "The login expired, please try again.",
+        );
+    };
+    let Some(code) = query.code else {
+        let error = query.error.unwrap_or_default();
+        return failure(
+            StatusCode::BAD_REQUEST,
+            format!("The provider did not sign you in: {}", error),
+        );
+    };
+
+    let identity = match login.identify(&code).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            warn!("Failed to identify a login: {:#}", e);
+            return failure(
+                StatusCode::BAD_GATEWAY,
+                "The login provider could not tell who you are.",
+            );
+        }
+    };
+    let name = identity.names[0].clone();
+    if !login.config.allows(&identity) {
+        warn!(
+            "Refused login of {} ({}): not an allowed user or member",
+            identity.display, name
+        );
+        return failure(
+            StatusCode::FORBIDDEN,
+            format!("{} is not allowed to see this dashboard.", identity.display),
+        );
+    }
+
+    let secret = auth::generate_token();
+    let now = chrono::Utc::now().timestamp();
+    let expires_at = now + (login.config.session_hours * 3600) as i64;
+    let stored = async {
+        sqlx::query("DELETE FROM sessions WHERE expires_at <= ?")
+            .bind(now)
+            .execute(&app_state.db_pool)
+            .await?;
+        sqlx::query(
+            "INSERT INTO sessions (token_hash, name, created_at, expires_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(auth::hash_token(&secret))
+        .bind(&name)
+        .bind(now)
+        .bind(expires_at)
+        .execute(&app_state.db_pool)
+        .await
+    };
+    if let Err(e) = stored.await {
+        warn!("Failed to store the session of {}: {}", name, e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    info!("{} signed in to the dashboard", name);
+    (
+        [(SET_COOKIE, login.cookie(Some(&secret)))],
+        Redirect::to(&next),
+    )
+        .into_response()
+}
+
+async fn logout(State(app_state): State<Arc<crate::AppState>>, headers: HeaderMap) -> Response {
+    let Some(login) = &app_state.login else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if let Some(secret) = auth::cookie(&headers, SESSION_COOKIE) {
+        if let Err(e) = sqlx::query("DELETE FROM sessions WHERE token_hash = ?")
+            .bind(auth::hash_token(secret))
+            .execute(&app_state.db_pool)
+            .await
+        {
+            warn!("Failed to end a session: {}", e);
+        }
+    }
+    ([(SET_COOKIE, login.cookie(None))], Redirect::to("/")).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        let config = LoginConfig {
+            allowed_users: vec!["Alice".to_string()],
+            allowed_orgs: vec!["ci-team".to_string()],
+            ..LoginConfig::default()
+        };
+        let identity = |names: &[&str], groups: &[&str]| Identity {
+            display: names[0].to_string(),
+            names: names.iter().map(|n| n.to_string()).collect(),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+        };
+        assert!(config.allows(&identity(&["alice"], &[])));
+        assert!(config.allows(&identity(&["bob"], &["CI-team"])));
+        assert!(!config.allows(&identity(&["bob"], &["other"])));
+        assert!(!config.allows(&identity(&["mallory"], &[])));
+    }
+
+    #[test]
+    fn test_is_local_path() {
+        assert!(is_local_path("/"));
+        assert!(is_local_path("/builds/abc-hello.drv?x=1"));
+        assert!(!is_local_path("https://evil.example"));
+        assert!(!is_local_path("//evil.example"));
+        assert!(!is_local_path("/\\evil.example"));
+        assert!(!is_local_path("/\t/evil.example"));
+    }
+
+    #[test]
+    fn test_oidc_identity() {
+        let info = |email_verified| UserInfo {
+            sub: "1234".to_string(),
+            preferred_username: Some("alice".to_string()),
+            email: Some("admin@example.com".to_string()),
+            email_verified,
+            groups: Vec::new(),
+        };
+        let identity = info(true).identity();
+        assert_eq!(identity.names, ["admin@example.com", "1234"]);
+        assert_eq!(identity.display, "alice");
+        assert_eq!(info(false).identity().names, ["1234"]);
+
+        // Whoever renames themselves to an allowed username is not let in
+        let config = LoginConfig {
+            allowed_users: vec!["alice".to_string()],
+            ..LoginConfig::default()
+        };
+        assert!(!config.allows(&info(false).identity()));
+    }
+
+    #[test]
+    fn test_login_path() {
+        let uri: Uri = "/builds/abc-hello.drv?x=1&y=2".parse().unwrap();
+        assert_eq!(
+            login_path(&uri),
+            "/login?next=%2Fbuilds%2Fabc-hello.drv%3Fx%3D1%26y%3D2"
+        );
+    }
+}
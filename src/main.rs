@@ -1,114 +1,643 @@
-use axum::{http::StatusCode, response::Json, routing::get, Router};
+use anyhow::Context;
+use axum::{response::Json, routing::get, Router};
+use clap::Parser;
 use serde_json::{json, Value};
 use std::{
     net::SocketAddr,
-    sync::{atomic::AtomicU64, Arc},
+    sync::{atomic::AtomicU64, Arc, PoisonError, RwLock},
 };
-use tracing::{info, Level};
+use tokio::{signal, time::Duration};
+use tracing::{info, info_span, Instrument};
 
+mod api;
+mod artifacts;
+mod auth;
+mod bisect;
 mod build;
+mod builders;
 mod cache;
+mod cli;
 mod config;
+mod credentials;
 mod dashboard;
 mod db;
+mod deploy;
+mod drvdiff;
+mod durations;
+mod events;
 mod executor;
+mod gc;
+mod github;
+mod health;
+mod hints;
+mod hooks;
+mod inputs;
+mod junit;
+mod lease;
+mod logging;
+mod login;
 mod nix;
+mod notify;
+mod pipeline;
+mod poll;
+mod priority;
+mod progress;
+mod provenance;
+mod quota;
+mod reload;
+mod sbom;
+mod schedule;
+mod stats;
+mod systemd;
+mod upload;
 mod webhook;
+mod workers;
 
+use api::ApiConfig;
 use build::BuildQueue;
 use cache::CacheConfig;
+use cli::{Cli, Command, ServeArgs};
 use config::Settings;
+use events::EventBus;
 use webhook::WebhookConfig;
 
 pub struct AppState {
     pub build_queue: Arc<BuildQueue>,
     pub workflow_counter: AtomicU64,
-    pub webhook_config: WebhookConfig,
+    pub webhook_config: RwLock<WebhookConfig>,
     pub cache_config: CacheConfig,
+    /// Uploads the outputs of successful builds
+    pub uploads: Arc<upload::UploadQueue>,
+    pub api_config: ApiConfig,
     pub db_pool: sqlx::SqlitePool,
+    pub events: EventBus,
+    pub artifact_store: artifacts::ArtifactStore,
+    pub pipeline: pipeline::Pipeline,
+    pub bisector: bisect::Bisector,
+    pub credentials: credentials::Credentials,
+    pub quota_config: quota::QuotaConfig,
+    pub priority_config: priority::PriorityConfig,
+    /// Public key verifying build provenance, if attested
+    pub provenance_key: Option<String>,
+    /// Remote machines building jobs of their systems
+    pub builders: builders::Builders,
+    /// `icicle worker` processes leasing jobs
+    pub workers: workers::Workers,
+    /// Signs visitors in to the dashboard, if a provider is configured
+    pub login: Option<login::Login>,
+    /// Limit for cloning a repository, and for evaluating each stage
+    pub eval_timeout: Duration,
+    pub import_from_derivation: nix::IfdConfig,
+    pub fetch: nix::FetchMode,
+    pub eval_limits: nix::EvalLimits,
+}
+
+impl AppState {
+    /// An evaluator for a repository, with the configured limits and IFD policy
+    pub fn evaluator(&self, repository: &str) -> nix::NixEvaluator {
+        let evaluator = nix::NixEvaluator::new()
+            .with_timeout(self.eval_timeout)
+            .with_fetch(self.fetch)
+            .with_limits(self.eval_limits);
+        if self.import_from_derivation.allows(repository) {
+            evaluator.with_import_from_derivation(self.import_from_derivation.clone())
+        } else {
+            evaluator
+        }
+    }
+
+    /// The current webhook settings, which can change on reload
+    pub fn webhook_config(&self) -> WebhookConfig {
+        self.webhook_config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn set_webhook_config(&self, config: WebhookConfig) {
+        *self
+            .webhook_config
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = config;
+    }
+
+    /// An instance with the default settings and a fresh database in `dir`, where
+    /// nothing runs in the background, for tests of the request handlers. "admin" is
+    /// the admin token.
+    #[cfg(test)]
+    pub async fn for_tests(dir: &std::path::Path) -> Arc<Self> {
+        let settings = Settings::with_defaults();
+        let db_pool = db::init_database(&format!("sqlite:{}", dir.join("icicle.db").display()))
+            .await
+            .unwrap();
+        let events = EventBus::new();
+        let build_queue = Arc::new(BuildQueue::new(events.clone()));
+        let (pipeline, _) =
+            pipeline::Pipeline::new(build_queue.clone(), db_pool.clone(), events.clone());
+        let cache_config = CacheConfig {
+            cache_url: settings.cache.cache_url.clone(),
+            attic_cache_name: settings.cache.attic_cache_name.clone(),
+            upload: settings.cache.upload,
+            upload_store: None,
+            s3: None,
+            substituters: Vec::new(),
+            signing_key: None,
+            upload_closure: settings.cache.upload_closure,
+            closure_by_cache: Default::default(),
+        };
+        let gc_roots = Arc::new(gc::GcRoots::new(dir.join("gcroots"), false).unwrap());
+        let uploads = upload::UploadQueue::new(
+            db_pool.clone(),
+            cache::CacheClient::new(cache_config.clone()),
+            gc_roots,
+            &settings.build,
+        );
+        let artifact_store = artifacts::ArtifactStore::new(
+            artifacts::ArtifactConfig {
+                dir: dir.join("artifacts"),
+                ..settings.artifacts.clone()
+            },
+            db_pool.clone(),
+        )
+        .unwrap();
+        let credentials = credentials::Credentials::new(&settings.credentials, db_pool.clone());
+        Arc::new(AppState {
+            bisector: bisect::Bisector::new(
+                &settings.bisect,
+                &settings.build,
+                db_pool.clone(),
+                events.clone(),
+                credentials.clone(),
+            ),
+            build_queue,
+            workflow_counter: AtomicU64::new(0),
+            webhook_config: RwLock::new(WebhookConfig {
+                secret: None,
+                allow: Vec::new(),
+                deny: Vec::new(),
+                branches: Vec::new(),
+                repository_branches: Default::default(),
+                event_retention_days: settings.webhook.event_retention_days,
+                attrset: settings.nix.default_attr_set.clone(),
+                release_attrset: None,
+            }),
+            cache_config,
+            uploads: Arc::new(uploads),
+            api_config: ApiConfig {
+                admin_token: Some("admin".to_string()),
+                public: true,
+            },
+            db_pool,
+            events,
+            artifact_store,
+            pipeline,
+            credentials,
+            quota_config: settings.quota.clone(),
+            priority_config: settings.priority.clone(),
+            provenance_key: None,
+            builders: builders::Builders::new(&[]),
+            workers: workers::Workers::new(settings.workers.clone()),
+            login: None,
+            eval_timeout: Duration::from_secs(settings.nix.eval_timeout_secs),
+            import_from_derivation: settings.nix.import_from_derivation.clone(),
+            fetch: settings.nix.fetch,
+            eval_limits: settings.nix.eval_limits(),
+        })
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt().with_max_level(Level::INFO).init();
+    let cli = Cli::parse();
+    match cli.command.unwrap_or(Command::Serve(cli.serve)) {
+        Command::Serve(args) => serve(args).await,
+        command => cli::run(command).await,
+    }
+}
 
+async fn serve(args: ServeArgs) -> anyhow::Result<()> {
     // Load configuration
-    let settings = Settings::new().unwrap_or_else(|e| {
-        tracing::warn!("Failed to load configuration: {}. Using defaults.", e);
-        Settings::with_defaults()
-    });
+    let settings = Settings::new(args.config.as_deref());
+    let mut log_config = settings.as_ref().map(|s| s.log.clone()).unwrap_or_default();
+    if let Some(level) = &args.log_level {
+        log_config.level = level.clone();
+    }
+    logging::init(
+        log_config.format,
+        &log_config.level,
+        log_config.file.as_ref(),
+    )
+    .context("Failed to open the log file")?;
+    let mut settings = match settings {
+        Ok(settings) => settings,
+        // An explicitly requested file has to load
+        Err(e) if args.config.is_some() => {
+            return Err(anyhow::anyhow!("Failed to load configuration: {}", e))
+        }
+        Err(e) => {
+            tracing::warn!("Failed to load configuration: {}. Using defaults.", e);
+            Settings::with_defaults()
+        }
+    };
+
+    settings.log = log_config;
+    args.apply(&mut settings);
+    settings.proxy.validate()?;
+    settings.proxy.apply_to_env();
 
     info!("Configuration loaded:");
+    let files = Settings::loaded_files(args.config.as_deref());
+    info!(
+        "  Files ({} profile): {}",
+        config::profile(),
+        if files.is_empty() {
+            "none".to_string()
+        } else {
+            files
+                .iter()
+                .map(|file| file.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+    );
     info!(
         "  Server: {}:{}",
         settings.server.host, settings.server.port
     );
     info!("  Cache URL: {}", settings.cache.cache_url);
-    info!("  Attic cache: {}", settings.cache.attic_cache_name);
+    if !settings.cache.substituters.is_empty() {
+        info!("  Substituters: {}", settings.cache.substituters.join(", "));
+    }
+    match settings.cache.upload {
+        cache::UploadBackend::Attic => {
+            info!("  Attic cache: {}", settings.cache.attic_cache_name)
+        }
+        cache::UploadBackend::NixCopy => info!(
+            "  Upload store: {}",
+            settings
+                .cache
+                .upload_store
+                .as_ref()
+                .unwrap_or(&settings.cache.cache_url)
+        ),
+        cache::UploadBackend::S3 => {
+            if let Some(s3) = &settings.cache.s3 {
+                info!("  S3 bucket: {} at {}", s3.bucket, s3.endpoint)
+            }
+        }
+    }
     info!("  Nix eval timeout: {}s", settings.nix.eval_timeout_secs);
+    if let Some(proxy) = settings
+        .proxy
+        .https
+        .as_ref()
+        .or(settings.proxy.http.as_ref())
+    {
+        info!("  Proxy: {}", proxy);
+    }
     info!(
         "  Webhook secret configured: {}",
         settings.webhook.secret.is_some()
     );
+    info!(
+        "  Admin API enabled: {}",
+        settings.api.admin_token.is_some()
+    );
 
     // Initialize database
     info!("Initializing database at: {}", settings.database.path);
     let db_pool = db::init_database(&settings.database.path).await?;
     info!("Database initialized successfully");
 
+    // Standby instances wait here until the leader goes away
+    let lease = if settings.ha.enabled {
+        let lease = lease::Lease::acquire(
+            db_pool.clone(),
+            settings.ha.instance_id.clone(),
+            settings.ha.lease_secs,
+        )
+        .await?;
+        Some((lease.clone(), tokio::spawn(lease.keep())))
+    } else {
+        None
+    };
+
+    let artifact_store =
+        artifacts::ArtifactStore::new(settings.artifacts.clone(), db_pool.clone())?;
+    tokio::spawn(artifact_store.clone().run_retention());
+    let attestor = if settings.provenance.enabled {
+        Some(provenance::Attestor::new(
+            &settings.provenance,
+            db_pool.clone(),
+            artifact_store.clone(),
+        )?)
+    } else {
+        None
+    };
+    let provenance_key = attestor
+        .as_ref()
+        .map(provenance::Attestor::public_key_pem)
+        .transpose()?;
+
+    // Without the collector pruning them, kept roots would pile up
+    let keep_outputs = settings.gc.enabled && settings.gc.keep_workflows > 0;
+    let gc_roots = Arc::new(gc::GcRoots::new(
+        settings.gc.roots_dir.clone(),
+        keep_outputs,
+    )?);
+    let cache_config = CacheConfig {
+        cache_url: settings.cache.cache_url.clone(),
+        attic_cache_name: settings.cache.attic_cache_name.clone(),
+        upload: settings.cache.upload,
+        upload_store: settings.cache.upload_store.clone(),
+        s3: settings.cache.s3.clone(),
+        substituters: settings.cache.substituters.clone(),
+        signing_key: settings.cache.signing_key.clone(),
+        upload_closure: settings.cache.upload_closure,
+        closure_by_cache: settings.cache.closure_by_cache.clone(),
+    };
+    let mut uploads = upload::UploadQueue::new(
+        db_pool.clone(),
+        cache::CacheClient::new(cache_config.clone()),
+        gc_roots.clone(),
+        &settings.build,
+    );
+    if settings.cache.post_build_hook {
+        uploads =
+            uploads.with_post_build_hook(upload::PostBuildHook::install(&settings.cache.hook_dir)?);
+    }
+    let uploads = Arc::new(uploads);
+    tokio::spawn(uploads.clone().watch_hook());
+
     // Initialize app state
-    let build_queue = Arc::new(BuildQueue::new());
+    let events = EventBus::new();
+    let build_queue = Arc::new(BuildQueue::new(events.clone()));
+    let (pipeline, mut stage_starts) =
+        pipeline::Pipeline::new(build_queue.clone(), db_pool.clone(), events.clone());
+    settings.bisect.validate()?;
+    settings.build.validate()?;
+    settings.quota.validate()?;
+    settings.priority.validate()?;
+    settings.credentials.validate()?;
+    settings.workers.validate()?;
+    settings.hooks.validate()?;
+    if settings.login.provider.is_some() {
+        settings
+            .login
+            .validate(settings.notify.public_url.as_deref())?;
+    }
+    let login = settings
+        .notify
+        .public_url
+        .as_deref()
+        .and_then(|public_url| login::Login::new(&settings.login, &settings.github, public_url));
+    let builders = builders::Builders::new(&settings.build.builders);
+    let workers = workers::Workers::new(settings.workers.clone());
+    let credentials = credentials::Credentials::new(&settings.credentials, db_pool.clone());
+    let bisector = bisect::Bisector::new(
+        &settings.bisect,
+        &settings.build,
+        db_pool.clone(),
+        events.clone(),
+        credentials.clone(),
+    )
+    .with_builders(builders.clone())
+    .with_workers(workers.clone());
+    tokio::spawn(bisector.clone().run());
 
     let app_state = Arc::new(AppState {
         build_queue: build_queue.clone(),
         workflow_counter: AtomicU64::new(0),
-        webhook_config: WebhookConfig {
+        webhook_config: RwLock::new(WebhookConfig {
             secret: settings.webhook.secret.clone(),
+            allow: settings.webhook.allow.clone(),
+            deny: settings.webhook.deny.clone(),
+            branches: settings.webhook.branches.clone(),
+            repository_branches: settings.webhook.repository_branches.clone(),
+            event_retention_days: settings.webhook.event_retention_days,
             attrset: settings.nix.default_attr_set.clone(),
-        },
-        cache_config: CacheConfig {
-            cache_url: settings.cache.cache_url.clone(),
-            attic_cache_name: settings.cache.attic_cache_name.clone(),
+            release_attrset: settings.nix.release_attr_set.clone(),
+        }),
+        cache_config,
+        uploads: uploads.clone(),
+        api_config: ApiConfig {
+            admin_token: settings.api.admin_token.clone(),
+            public: settings.api.public,
         },
         db_pool: db_pool.clone(),
+        events: events.clone(),
+        artifact_store: artifact_store.clone(),
+        pipeline: pipeline.clone(),
+        bisector: bisector.clone(),
+        credentials: credentials.clone(),
+        quota_config: settings.quota.clone(),
+        priority_config: settings.priority.clone(),
+        provenance_key,
+        builders: builders.clone(),
+        workers: workers.clone(),
+        login,
+        eval_timeout: Duration::from_secs(settings.nix.eval_timeout_secs),
+        import_from_derivation: settings.nix.import_from_derivation.clone(),
+        fetch: settings.nix.fetch,
+        eval_limits: settings.nix.eval_limits(),
     });
 
+    // Later stages are evaluated once the previous one succeeded or they were approved
+    tokio::spawn({
+        let app_state = app_state.clone();
+        async move {
+            while let Some(workflow_id) = stage_starts.recv().await {
+                tokio::spawn(
+                    webhook::run_stage(app_state.clone(), workflow_id)
+                        .instrument(info_span!("workflow", workflow_id)),
+                );
+            }
+        }
+    });
+    // Work queued or running before a restart is picked up where it stopped
+    if let Err(e) = app_state.pipeline.restore().await {
+        tracing::error!("Failed to restore the build queue: {:#}", e);
+    }
+
+    if settings.gc.enabled {
+        settings.gc.validate()?;
+        tokio::spawn(
+            gc::GarbageCollector::new(settings.gc.clone(), gc_roots.clone(), db_pool.clone()).run(),
+        );
+    }
+
     // Initialize and spawn build executor
-    let executor = Arc::new(executor::BuildExecutor::new(
+    let mut executor = executor::BuildExecutor::new(
         build_queue,
-        db_pool,
+        db_pool.clone(),
         cache::CacheClient::new(app_state.cache_config.clone()),
-        settings.build.max_concurrent_builds,
-        settings.build.build_timeout_secs,
-    ));
+        gc_roots,
+        artifact_store,
+        pipeline,
+        &settings.build,
+    );
+    executor = executor.with_uploads(uploads);
+    if let Some(attestor) = attestor {
+        executor = executor.with_provenance(attestor);
+    }
+    if !builders.is_empty() {
+        executor = executor.with_builders(builders.clone());
+        tokio::spawn(
+            builders
+                .clone()
+                .run_checks(Duration::from_secs(settings.build.builder_check_secs)),
+        );
+    }
+    if workers.enabled() {
+        executor = executor.with_workers(workers.clone());
+        tokio::spawn(workers.clone().run_expiry());
+    }
+    let executor = Arc::new(executor);
 
-    tokio::spawn(async move {
-        executor.run().await;
+    tokio::spawn({
+        let executor = executor.clone();
+        async move {
+            executor.run().await;
+        }
     });
 
-    let app = Router::new()
-        .route("/api", get(root))
-        .route("/health", get(health))
-        .merge(webhook::routes())
-        .merge(dashboard::routes())
-        .with_state(app_state);
+    let reloader =
+        reload::Reloader::new(args, settings.clone(), app_state.clone(), executor.clone());
+    let notifications = notify::NotificationService::new(&settings.notify, db_pool.clone())?;
+    if !notifications.is_empty() {
+        if let Some(digest) = notifications.digest() {
+            tokio::spawn(digest.run());
+        }
+    }
+    tokio::spawn(notifications.run(app_state.events.clone(), reloader.notify_config()));
+    if !settings.deploy.targets.is_empty() {
+        settings.deploy.validate()?;
+        let deployer = deploy::Deployer::new(
+            &settings.deploy,
+            db_pool.clone(),
+            app_state.events.clone(),
+            credentials,
+        );
+        tokio::spawn(deployer.run());
+    }
+    if let Some(reporter) = github::GitHubReporter::new(
+        &settings.github,
+        db_pool.clone(),
+        settings.notify.public_url.as_deref(),
+    ) {
+        settings.github.validate()?;
+        tokio::spawn(reporter.run(app_state.events.clone()));
+    }
+    tokio::spawn(
+        hooks::Dispatcher::new(&settings.hooks, db_pool.clone()).run(app_state.events.clone()),
+    );
+    if !settings.schedule.workflows.is_empty() {
+        settings.schedule.validate()?;
+    }
+    tokio::spawn(schedule::Scheduler::new(&settings.schedule, app_state.clone()).run());
+    if !settings.poll.repositories.is_empty() {
+        settings.poll.validate()?;
+        tokio::spawn(poll::Poller::new(&settings.poll, app_state.clone()).run());
+    }
+    tokio::spawn(reloader.run());
 
-    let addr = SocketAddr::from((
-        settings
-            .server
-            .host
-            .parse::<std::net::IpAddr>()
-            .unwrap_or_else(|_| std::net::IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0))),
-        settings.server.port,
-    ));
-    info!("Starting icicle server on {}", addr);
+    let app = logging::layer(
+        Router::new()
+            .route("/api", get(root))
+            .merge(health::routes())
+            .merge(api::routes(app_state.clone()))
+            .merge(webhook::routes())
+            .merge(login::routes())
+            .merge(dashboard::routes(app_state.clone())),
+    )
+    .with_state(app_state);
+
+    // A socket passed by systemd outlives restarts, so no connection is refused meanwhile
+    let listener = match systemd::listener()? {
+        Some(listener) => {
+            info!("Using the socket passed by systemd");
+            tokio::net::TcpListener::from_std(listener)?
+        }
+        None => {
+            let addr = SocketAddr::from((
+                settings
+                    .server
+                    .host
+                    .parse::<std::net::IpAddr>()
+                    .unwrap_or_else(|_| std::net::IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0))),
+                settings.server.port,
+            ));
+            tokio::net::TcpListener::bind(addr).await?
+        }
+    };
+    let addr = listener.local_addr()?;
+    systemd::ready();
+    if lease.is_some() {
+        systemd::status("Leader, serving requests");
+    }
+
+    let shutdown_timeout = Duration::from_secs(settings.server.shutdown_timeout_secs);
+    let handle = axum_server::Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        let executor = executor.clone();
+        async move {
+            shutdown_signal().await;
+            info!("Shutting down, no new builds are started");
+            systemd::stopping();
+            executor.stop();
+            handle.graceful_shutdown(Some(shutdown_timeout));
+        }
+    });
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    let listener = listener.into_std()?;
+    match &settings.server.tls {
+        Some(tls) => {
+            let tls_config = tls.rustls_config().await?;
+            info!("Starting icicle server on https://{}", addr);
+            axum_server::from_tcp_rustls(listener, tls_config)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        None => {
+            info!("Starting icicle server on {}", addr);
+            axum_server::from_tcp(listener)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await?;
+        }
+    }
+    info!("HTTP server stopped");
 
+    executor.drain(shutdown_timeout).await;
+    if let Some((lease, renewal)) = lease {
+        renewal.abort();
+        lease.release().await;
+    }
+    info!("Shutdown complete");
     Ok(())
 }
 
+/// Resolves on SIGINT (Ctrl-C) or SIGTERM
+async fn shutdown_signal() {
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
+
 async fn root() -> Json<Value> {
     Json(json!({
         "name": "icicle",
@@ -116,7 +645,3 @@ async fn root() -> Json<Value> {
         "description": "Nix-based CI builder and dashboard"
     }))
 }
-
-async fn health() -> StatusCode {
-    StatusCode::OK
-}
@@ -1,13 +1,137 @@
-use crate::build::{BuildStatus, Derivation};
+use crate::{
+    build::{BuildStatus, Derivation},
+    credentials::Credential,
+};
 use anyhow::{anyhow, Context, Result};
-use serde::Deserialize;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::time::Duration;
 use tempfile::TempDir;
 use tokio::process::Command;
 use tracing::{error, info, warn};
 
+/// Derivations read per `nix derivation show`, keeping the command line short
+const SHOW_BATCH_SIZE: usize = 500;
+/// Pause between measurements of the memory an evaluation uses
+const MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Resources of the nix-eval-jobs processes evaluating an attribute set
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EvalLimits {
+    /// Worker processes evaluating in parallel (`--workers`)
+    pub workers: usize,
+    /// MiB a worker may use before nix-eval-jobs replaces it (`--max-memory-size`)
+    pub max_memory_size_mib: u64,
+    /// MiB of resident memory all processes of the evaluation may use together
+    pub memory_limit_mib: Option<u64>,
+}
+
+impl Default for EvalLimits {
+    fn default() -> Self {
+        EvalLimits {
+            workers: 1,
+            max_memory_size_mib: 4096,
+            memory_limit_mib: None,
+        }
+    }
+}
+
+/// How evaluations get the source of the commit they evaluate
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FetchMode {
+    /// Clone the repository with git into a temporary directory
+    #[default]
+    Clone,
+    /// Let nix fetch (and cache) the flake reference of the commit, e.g.
+    /// `github:owner/repo/<sha>`
+    Flake,
+}
+
+/// Whether evaluations may build the derivations they import from (IFD), and how much
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct IfdConfig {
+    /// Allow import from derivation in the evaluations of every repository
+    pub allow: bool,
+    /// Repositories allowed it anyway, as "owner/repo" or "owner/*"
+    pub repositories: Vec<String>,
+    /// Derivations built at once for an evaluation's imports
+    pub max_jobs: u32,
+    /// Cores each of those builds may use; all when 0
+    pub cores: u32,
+    /// Seconds each of those builds may take; unlimited when 0
+    pub timeout_secs: u64,
+}
+
+impl Default for IfdConfig {
+    fn default() -> Self {
+        IfdConfig {
+            allow: false,
+            repositories: Vec::new(),
+            max_jobs: 1,
+            cores: 0,
+            timeout_secs: 600,
+        }
+    }
+}
+
+impl IfdConfig {
+    /// Whether evaluations of a repository may import from derivations
+    pub fn allows(&self, repository: &str) -> bool {
+        self.allow
+            || self
+                .repositories
+                .iter()
+                .any(|p| crate::webhook::matches(p, repository))
+    }
+}
+
+/// nix options of an evaluation, allowing import from derivation within `ifd`'s limits
+fn ifd_options(ifd: Option<&IfdConfig>) -> Vec<String> {
+    let Some(ifd) = ifd else {
+        return ["--option", "allow-import-from-derivation", "false"]
+            .map(str::to_string)
+            .to_vec();
+    };
+    [
+        ("allow-import-from-derivation", "true".to_string()),
+        ("max-jobs", ifd.max_jobs.to_string()),
+        ("cores", ifd.cores.to_string()),
+        ("timeout", ifd.timeout_secs.to_string()),
+    ]
+    .into_iter()
+    .flat_map(|(name, value)| ["--option".to_string(), name.to_string(), value])
+    .collect()
+}
+
+/// An attribute nix-eval-jobs failed to evaluate
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, sqlx::FromRow)]
+pub struct EvalError {
+    pub attr: String,
+    pub error: String,
+}
+
+/// What evaluating an attribute set gave: the jobs to build, and the attributes
+/// that did not evaluate
+#[derive(Debug, Default)]
+pub struct Evaluation {
+    pub derivations: Vec<Derivation>,
+    pub errors: Vec<EvalError>,
+}
+
+/// A line of nix-eval-jobs' output
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum EvalLine {
+    Error(EvalError),
+    Job(NixEvalJob),
+}
+
 #[derive(Debug, Deserialize)]
 pub struct NixEvalJob {
     pub attr: String,
@@ -15,19 +139,188 @@ pub struct NixEvalJob {
     pub drv_path: String,
     pub outputs: HashMap<String, String>,
     pub system: String,
+    #[serde(default)]
+    pub meta: Option<serde_json::Value>,
+    /// Derivations an aggregate job (`_hydraAggregate`) is made of
+    #[serde(default)]
+    pub constituents: Option<Vec<String>>,
+    /// Constituents an aggregate job gives by attribute name instead
+    #[serde(default, rename = "namedConstituents")]
+    pub named_constituents: Vec<String>,
+}
+
+impl NixEvalJob {
+    /// Licenses from `meta.license`, which is a license, a list of them, or a plain string
+    pub fn licenses(&self) -> Vec<String> {
+        let Some(license) = self.meta.as_ref().and_then(|meta| meta.get("license")) else {
+            return Vec::new();
+        };
+        let licenses = match license {
+            serde_json::Value::Array(licenses) => licenses.iter().collect(),
+            license => vec![license],
+        };
+        licenses
+            .into_iter()
+            .filter_map(|license| match license {
+                serde_json::Value::String(name) => Some(name.clone()),
+                license => ["spdxId", "shortName", "fullName"]
+                    .iter()
+                    .find_map(|key| license.get(key)?.as_str())
+                    .map(str::to_string),
+            })
+            .collect()
+    }
+
+    /// Seconds the build may take according to `meta.timeout`, as Hydra reads it
+    pub fn timeout_secs(&self) -> Option<u64> {
+        self.meta
+            .as_ref()?
+            .get("timeout")?
+            .as_u64()
+            .filter(|secs| *secs > 0)
+    }
 }
 
 pub struct NixEvaluator {
     temp_dir: Option<TempDir>,
+    /// Used again to fetch more history
+    credential: Option<Credential>,
+    /// Limit for cloning, and for evaluating an attribute set
+    timeout: Option<Duration>,
+    /// Limits of import from derivation, when allowed
+    ifd: Option<IfdConfig>,
+    fetch: FetchMode,
+    limits: EvalLimits,
+    /// Flake reference evaluated, and the source nix fetched for it, in flake mode
+    flake: Option<(String, PathBuf)>,
+}
+
+/// Run one step of an evaluation, failing once it takes longer than `limit`
+async fn limited<T>(
+    limit: Option<Duration>,
+    step: &str,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let Some(limit) = limit else {
+        return future.await;
+    };
+    tokio::time::timeout(limit, future)
+        .await
+        .map_err(|_| anyhow!("{} timed out after {} seconds", step, limit.as_secs()))?
 }
 
 impl NixEvaluator {
     pub fn new() -> Self {
-        Self { temp_dir: None }
+        Self {
+            temp_dir: None,
+            credential: None,
+            timeout: None,
+            ifd: None,
+            fetch: FetchMode::Clone,
+            limits: EvalLimits::default(),
+            flake: None,
+        }
+    }
+
+    /// Evaluate with these workers and memory limits
+    pub fn with_limits(mut self, limits: EvalLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Get the source of commits as configured, instead of by cloning
+    pub fn with_fetch(mut self, fetch: FetchMode) -> Self {
+        self.fetch = fetch;
+        self
+    }
+
+    /// Let evaluations build the derivations they import from, within `ifd`'s limits
+    pub fn with_import_from_derivation(mut self, ifd: IfdConfig) -> Self {
+        self.ifd = Some(ifd);
+        self
+    }
+
+    /// Give up cloning, or evaluating an attribute set, after `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Clone a git repository to a temporary directory, authenticating with
+    /// `credential` if the repository is private; in flake mode, nix fetches the
+    /// commit into the store instead
+    pub async fn clone_repository(
+        &mut self,
+        clone_url: &str,
+        commit_sha: &str,
+        credential: Option<&Credential>,
+    ) -> Result<()> {
+        let limit = self.timeout;
+        match self.fetch {
+            FetchMode::Clone => {
+                limited(
+                    limit,
+                    "Cloning the repository",
+                    self.clone_and_checkout(clone_url, commit_sha, credential),
+                )
+                .await
+            }
+            FetchMode::Flake => {
+                limited(
+                    limit,
+                    "Fetching the flake",
+                    self.fetch_flake(clone_url, commit_sha, credential),
+                )
+                .await
+            }
+        }
     }
 
-    /// Clone a git repository to a temporary directory
-    pub async fn clone_repository(&mut self, clone_url: &str, commit_sha: &str) -> Result<()> {
+    async fn fetch_flake(
+        &mut self,
+        clone_url: &str,
+        commit_sha: &str,
+        credential: Option<&Credential>,
+    ) -> Result<()> {
+        let flake_ref = flake_reference(clone_url, commit_sha);
+        info!("Fetching flake {}", flake_ref);
+
+        let mut prefetch = Command::new("nix");
+        if let Some(credential) = credential {
+            credential.apply_nix(&mut prefetch);
+        }
+        let output = prefetch
+            .args(["flake", "prefetch", "--json", &flake_ref])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .output()
+            .await
+            .context("Failed to execute nix flake prefetch")?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow!("nix flake prefetch failed: {}", stderr));
+        }
+
+        #[derive(Deserialize)]
+        struct Prefetched {
+            #[serde(rename = "storePath")]
+            store_path: PathBuf,
+        }
+        let prefetched: Prefetched = serde_json::from_slice(&output.stdout)
+            .context("Failed to parse nix flake prefetch output")?;
+        info!("Fetched {} to {:?}", flake_ref, prefetched.store_path);
+        self.flake = Some((flake_ref, prefetched.store_path));
+        self.credential = credential.cloned();
+        Ok(())
+    }
+
+    async fn clone_and_checkout(
+        &mut self,
+        clone_url: &str,
+        commit_sha: &str,
+        credential: Option<&Credential>,
+    ) -> Result<()> {
         info!("Cloning repository {} at commit {}", clone_url, commit_sha);
 
         let temp_dir = tempfile::tempdir()
@@ -36,7 +329,11 @@ impl NixEvaluator {
         let repo_path = temp_dir.path();
 
         // Clone the repository
-        let clone_output = Command::new("git")
+        let mut clone = Command::new("git");
+        if let Some(credential) = credential {
+            credential.apply(&mut clone);
+        }
+        let clone_output = clone
             .args([
                 "clone",
                 "--depth=1",
@@ -46,6 +343,7 @@ impl NixEvaluator {
             ])
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
+            .kill_on_drop(true)
             .output()
             .await
             .context("Failed to execute git clone")?;
@@ -55,29 +353,54 @@ impl NixEvaluator {
             return Err(anyhow!("Git clone failed: {}", stderr));
         }
 
-        // Checkout the specific commit
-        let checkout_output = Command::new("git")
-            .current_dir(repo_path)
-            .args(["checkout", commit_sha])
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()
-            .await
-            .context("Failed to execute git checkout")?;
+        self.temp_dir = Some(temp_dir);
+        self.credential = credential.cloned();
 
-        if !checkout_output.status.success() {
-            let stderr = String::from_utf8_lossy(&checkout_output.stderr);
-            return Err(anyhow!("Git checkout failed: {}", stderr));
+        // Checkout the specific commit; the shallow clone only has those at the
+        // tips of branches and tags
+        let repo_path = self.repo_path().unwrap();
+        if checkout(repo_path, commit_sha).await.is_err() {
+            info!("{} is not a tip, fetching the full history", commit_sha);
+            self.unshallow().await?;
         }
+        let repo_path = self.repo_path().unwrap();
+        checkout(repo_path, commit_sha).await?;
 
         info!("Successfully cloned repository to {:?}", repo_path);
-        self.temp_dir = Some(temp_dir);
         Ok(())
     }
 
-    /// Get the path to the cloned repository
+    /// Fetch the full history of the cloned repository
+    pub async fn unshallow(&self) -> Result<()> {
+        let repo_path = self
+            .temp_dir
+            .as_ref()
+            .map(TempDir::path)
+            .ok_or_else(|| anyhow!("No repository cloned"))?;
+        let mut fetch = Command::new("git");
+        if let Some(credential) = &self.credential {
+            credential.apply(&mut fetch);
+        }
+        let output = fetch
+            .current_dir(repo_path)
+            .args(["fetch", "--unshallow", "origin"])
+            .kill_on_drop(true)
+            .output()
+            .await
+            .context("Failed to execute git fetch")?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow!("Git fetch failed: {}", stderr.trim()));
+        }
+        Ok(())
+    }
+
+    /// Get the path to the cloned repository, or to the fetched flake's source
     pub fn repo_path(&self) -> Option<&Path> {
-        self.temp_dir.as_ref().map(|td| td.path())
+        match &self.flake {
+            Some((_, source)) => Some(source),
+            None => self.temp_dir.as_ref().map(|td| td.path()),
+        }
     }
 
     /// Evaluate a flake attribute set using nix-eval-jobs
@@ -85,12 +408,16 @@ impl NixEvaluator {
         &self,
         repo_path: &Path,
         attribute_set: &str,
-    ) -> Result<Vec<Derivation>> {
-        info!(
-            "Evaluating flake at {:?} for attribute set: {}",
-            repo_path, attribute_set
-        );
+    ) -> Result<Evaluation> {
+        limited(
+            self.timeout,
+            "Evaluation",
+            self.run_evaluation(repo_path, attribute_set),
+        )
+        .await
+    }
 
+    async fn run_evaluation(&self, repo_path: &Path, attribute_set: &str) -> Result<Evaluation> {
         let flake_path = repo_path.join("flake.nix");
         if !flake_path.exists() {
             return Err(anyhow!(
@@ -99,22 +426,114 @@ impl NixEvaluator {
             ));
         }
 
+        // Jobs of all the attribute sets end up in one DAG
+        let sets = attribute_sets(attribute_set);
+        if sets.is_empty() {
+            return Err(anyhow!("No attribute set to evaluate"));
+        }
+        let mut jobs = Vec::new();
+        let mut errors = Vec::new();
+        let mut dupes = HashSet::new();
+        for set in &sets {
+            let (set_jobs, set_errors) = self.eval_jobs(repo_path, set).await?;
+            for mut error in set_errors {
+                if sets.len() > 1 {
+                    error.attr = job_attribute(set, &error.attr);
+                }
+                warn!("Failed to evaluate {}: {}", error.attr, error.error);
+                errors.push(error);
+            }
+            for mut job in set_jobs {
+                if !dupes.insert(job.drv_path.clone()) {
+                    continue;
+                }
+                if sets.len() > 1 {
+                    job.attr = job_attribute(set, &job.attr);
+                    for name in &mut job.named_constituents {
+                        *name = job_attribute(set, name);
+                    }
+                }
+                jobs.push(job);
+            }
+        }
+
+        info!("Found {} discrete jobs from evaluation", jobs.len());
+
+        // Now find transitive dependencies between jobs
+        let derivations = self.resolve_job_dependencies(repo_path, jobs).await?;
+
+        info!(
+            "Successfully resolved dependencies for {} derivations",
+            derivations.len()
+        );
+        Ok(Evaluation {
+            derivations,
+            errors,
+        })
+    }
+
+    /// Run nix-eval-jobs on one attribute set, returning its jobs and the attributes
+    /// that failed to evaluate
+    async fn eval_jobs(
+        &self,
+        repo_path: &Path,
+        attribute_set: &str,
+    ) -> Result<(Vec<NixEvalJob>, Vec<EvalError>)> {
+        info!(
+            "Evaluating flake at {:?} for attribute set: {}",
+            repo_path, attribute_set
+        );
+
         // Run nix-eval-jobs to get the discrete jobs
-        let output = Command::new("nix-eval-jobs")
+        let mut eval = Command::new("nix-eval-jobs");
+        let flake_ref = match &self.flake {
+            Some((flake_ref, _)) => {
+                if let Some(credential) = &self.credential {
+                    credential.apply_nix(&mut eval);
+                }
+                flake_ref.as_str()
+            }
+            None => ".",
+        };
+        let child = eval
             .current_dir(repo_path)
             .args([
                 "--flake",
-                &format!(".#{}", attribute_set),
+                &format!("{}#{}", flake_ref, attribute_set),
                 "--log-format",
                 "raw",
                 "--meta",
+                "--constituents",
                 "--show-trace",
+                "--workers",
+                &self.limits.workers.to_string(),
+                "--max-memory-size",
+                &self.limits.max_memory_size_mib.to_string(),
             ])
+            .args(ifd_options(self.ifd.as_ref()))
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
-            .output()
-            .await
+            // In its own process group, so its workers can be measured and stopped
+            .process_group(0)
+            .kill_on_drop(true)
+            .spawn()
             .context("Failed to execute nix-eval-jobs")?;
+        let pgid = child.id().map(|pid| pid as i32);
+        let output = match (self.limits.memory_limit_mib, pgid) {
+            (Some(limit), Some(pgid)) => tokio::select! {
+                output = child.wait_with_output() => output?,
+                rss = exceeds_memory(pgid, limit) => {
+                    crate::executor::signal_group(pgid, libc::SIGKILL);
+                    return Err(anyhow!(
+                        "Evaluation of {} used {} MiB of memory, over the limit of {} MiB",
+                        attribute_set,
+                        rss >> 20,
+                        limit
+                    ));
+                }
+            },
+            _ => child.wait_with_output().await?,
+        };
 
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
@@ -123,42 +542,9 @@ impl NixEvaluator {
         }
 
         let stdout = String::from_utf8_lossy(&output.stdout);
-        info!("nix-eval-jobs completed successfully");
-
-        // Parse the jobs
-        let mut jobs = Vec::new();
-        let mut dupes = HashSet::new();
-        for line in stdout.lines() {
-            if line.trim().is_empty() {
-                continue;
-            }
-
-            match serde_json::from_str::<NixEvalJob>(line) {
-                Ok(job) => {
-                    if dupes.contains(&job.drv_path) {
-                        println!("dupe: {}", job.drv_path);
-                        continue;
-                    }
-                    dupes.insert(job.drv_path.clone());
-                    jobs.push(job);
-                }
-                Err(e) => {
-                    warn!("Failed to parse nix-eval-jobs output line: {}", e);
-                    warn!("Line content: {}", line);
-                }
-            }
-        }
+        info!("nix-eval-jobs completed for {}", attribute_set);
 
-        info!("Found {} discrete jobs from evaluation", jobs.len());
-
-        // Now find transitive dependencies between jobs
-        let derivations = self.resolve_job_dependencies(repo_path, jobs).await?;
-
-        info!(
-            "Successfully resolved dependencies for {} derivations",
-            derivations.len()
-        );
-        Ok(derivations)
+        Ok(parse_eval_output(&stdout))
     }
 
     /// Find transitive dependencies between jobs using nix-store --query
@@ -179,12 +565,41 @@ impl NixEvaluator {
                 system: job.system.clone(),
                 input_drvs: Vec::new(), // Will be filled in later
                 status: BuildStatus::Queued,
+                licenses: job.licenses(),
+                timeout_secs: job.timeout_secs(),
+                required_features: Vec::new(), // Will be filled in later
+                constituents: None,            // Will be filled in later
+                cached: None,
             };
 
             derivations.push(derivation);
             drv_to_job.insert(job.drv_path.clone(), i);
         }
 
+        // Aggregates only finish once their constituents did
+        let attr_to_drv: HashMap<&str, &str> = jobs
+            .iter()
+            .map(|job| (job.attr.as_str(), job.drv_path.as_str()))
+            .collect();
+        for (i, job) in jobs.iter().enumerate() {
+            derivations[i].constituents = aggregate_constituents(job, &attr_to_drv);
+        }
+
+        // Builders without the features a job requires cannot build it
+        let drv_paths: Vec<&str> = jobs.iter().map(|job| job.drv_path.as_str()).collect();
+        for chunk in drv_paths.chunks(SHOW_BATCH_SIZE) {
+            match show_derivations(chunk).await {
+                Ok(contents) => {
+                    for (drv_path, contents) in contents {
+                        if let Some(&i) = drv_to_job.get(&drv_path) {
+                            derivations[i].required_features = contents.required_system_features();
+                        }
+                    }
+                }
+                Err(e) => warn!("Failed to read the required system features: {:#}", e),
+            }
+        }
+
         // For each job, find its transitive dependencies and filter to only include other jobs
         for (i, job) in jobs.iter().enumerate() {
             let job_dependencies = self
@@ -192,10 +607,15 @@ impl NixEvaluator {
                 .await?;
 
             // Filter to only include dependencies that are also jobs (not intermediate derivations)
-            let input_job_drvs: Vec<String> = job_dependencies
+            let mut input_job_drvs: Vec<String> = job_dependencies
                 .into_iter()
                 .filter(|drv_path| drv_to_job.contains_key(drv_path) && &job.drv_path != drv_path)
                 .collect();
+            for constituent in derivations[i].constituents.iter().flatten() {
+                if drv_to_job.contains_key(constituent) && !input_job_drvs.contains(constituent) {
+                    input_job_drvs.push(constituent.clone());
+                }
+            }
 
             derivations[i].input_drvs = input_job_drvs;
         }
@@ -214,6 +634,7 @@ impl NixEvaluator {
             .args(["--query", "--requisites", drv_path])
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
+            .kill_on_drop(true)
             .output()
             .await
             .context("Failed to execute nix-store --query")?;
@@ -240,13 +661,514 @@ impl NixEvaluator {
         clone_url: &str,
         commit_sha: &str,
         attribute_set: &str,
-    ) -> Result<Vec<Derivation>> {
-        self.clone_repository(clone_url, commit_sha).await?;
+        credential: Option<&Credential>,
+    ) -> Result<Evaluation> {
+        self.clone_repository(clone_url, commit_sha, credential)
+            .await?;
         let repo_path = self.repo_path().unwrap();
         self.evaluate_flake(repo_path, attribute_set).await
     }
 }
 
+/// Parse the jobs of nix-eval-jobs' output, and the attributes it failed to evaluate
+fn parse_eval_output(stdout: &str) -> (Vec<NixEvalJob>, Vec<EvalError>) {
+    let mut jobs = Vec::new();
+    let mut errors = Vec::new();
+    for line in stdout.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str::<EvalLine>(line) {
+            Ok(EvalLine::Job(job)) => jobs.push(job),
+            Ok(EvalLine::Error(error)) => errors.push(error),
+            Err(e) => {
+                warn!("Failed to parse nix-eval-jobs output line: {}", e);
+                warn!("Line content: {}", line);
+            }
+        }
+    }
+    (jobs, errors)
+}
+
+/// Wait until the processes of a group use more than `limit_mib` of resident memory,
+/// returning how many bytes they used then
+async fn exceeds_memory(pgid: i32, limit_mib: u64) -> u64 {
+    loop {
+        tokio::time::sleep(MEMORY_CHECK_INTERVAL).await;
+        let rss = group_rss(pgid);
+        if rss > limit_mib << 20 {
+            return rss;
+        }
+    }
+}
+
+/// Resident memory in bytes of the processes of a group, summed from /proc
+fn group_rss(pgid: i32) -> u64 {
+    // SAFETY: sysconf only reads a system setting
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(0) as u64;
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| std::fs::read_to_string(entry.path().join("stat")).ok())
+        .filter_map(|stat| parse_stat(&stat))
+        .filter(|(group, _)| *group == pgid)
+        .map(|(_, pages)| pages * page_size)
+        .sum()
+}
+
+/// Process group and resident pages of a process, from its /proc/<pid>/stat
+fn parse_stat(stat: &str) -> Option<(i32, u64)> {
+    // The command name before the other fields may contain spaces and parentheses
+    let (_, fields) = stat.rsplit_once(')')?;
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    // Fields 5 (pgrp) and 24 (rss) of proc(5), counting from the state as field 3
+    Some((fields.get(2)?.parse().ok()?, fields.get(21)?.parse().ok()?))
+}
+
+/// Locked flake reference of a commit of a repository: GitHub's tarballs for its
+/// HTTPS URLs, nix's git fetcher for the others
+pub fn flake_reference(clone_url: &str, commit_sha: &str) -> String {
+    if let Some(path) = clone_url.strip_prefix("https://github.com/") {
+        let path = path.trim_end_matches('/');
+        let path = path.strip_suffix(".git").unwrap_or(path);
+        return format!("github:{}/{}", path, commit_sha);
+    }
+    let url = match clone_url.split_once("://") {
+        Some(_) => clone_url.to_string(),
+        // scp-like syntax, e.g. git@github.com:owner/repo.git
+        None => match clone_url.split_once(':') {
+            Some((host, path)) => format!("ssh://{}/{}", host, path),
+            None => format!("file://{}", clone_url),
+        },
+    };
+    let url = url.strip_prefix("git+").unwrap_or(&url);
+    format!("git+{}?rev={}", url, commit_sha)
+}
+
+/// The attribute sets of a workflow or stage, which may list several separated by
+/// commas, e.g. "packages.x86_64-linux,checks.x86_64-linux"
+pub fn attribute_sets(list: &str) -> Vec<&str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|set| !set.is_empty())
+        .collect()
+}
+
+/// Flake attribute of a job; when several attribute sets are evaluated together, the
+/// names of their jobs are qualified by their set, e.g. "checks.x86_64-linux.fmt"
+pub fn job_attribute(attribute_set: &str, name: &str) -> String {
+    match attribute_sets(attribute_set)[..] {
+        [set] => format!("{}.{}", set, name),
+        _ => name.to_string(),
+    }
+}
+
+/// The derivations an aggregate job is made of, resolving those named by attribute
+/// in the same evaluation; None for jobs that are not aggregates
+fn aggregate_constituents(
+    job: &NixEvalJob,
+    attr_to_drv: &HashMap<&str, &str>,
+) -> Option<Vec<String>> {
+    let mut constituents = job.constituents.clone()?;
+    for name in &job.named_constituents {
+        match attr_to_drv.get(name.as_str()) {
+            Some(drv_path) => constituents.push(drv_path.to_string()),
+            None => warn!("Constituent {} of {} is not a job", name, job.attr),
+        }
+    }
+    constituents.sort();
+    constituents.dedup();
+    Some(constituents)
+}
+
+async fn checkout(repo_path: &Path, commit_sha: &str) -> Result<()> {
+    let output = Command::new("git")
+        .current_dir(repo_path)
+        .args(["checkout", commit_sha])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .context("Failed to execute git checkout")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("Git checkout failed: {}", stderr));
+    }
+    Ok(())
+}
+
+/// The commit a branch or tag of a remote repository points at
+#[derive(Debug, PartialEq)]
+pub enum RemoteRef {
+    Branch(String),
+    Tag(String),
+}
+
+/// List the refs of a remote repository matching `patterns`, as ref name to commit,
+/// or None if there are none
+async fn ls_remote(
+    clone_url: &str,
+    patterns: &[&str],
+    credential: Option<&Credential>,
+) -> Result<Option<HashMap<String, String>>> {
+    let mut command = Command::new("git");
+    if let Some(credential) = credential {
+        credential.apply(&mut command);
+    }
+    let output = command
+        .args(["ls-remote", "--exit-code", clone_url])
+        .args(patterns)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .context("Failed to execute git ls-remote")?;
+
+    // --exit-code makes a missing ref exit with 2
+    if output.status.code() == Some(2) {
+        return Ok(None);
+    }
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("git ls-remote failed: {}", stderr.trim()));
+    }
+    Ok(Some(parse_ls_remote(&String::from_utf8_lossy(
+        &output.stdout,
+    ))))
+}
+
+fn parse_ls_remote(output: &str) -> HashMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (sha, git_ref) = line.split_once('\t')?;
+            Some((git_ref.trim().to_string(), sha.trim().to_string()))
+        })
+        .collect()
+}
+
+/// Look a branch or tag up in a remote repository without cloning it.
+/// A branch wins over a tag of the same name.
+pub async fn resolve_ref(
+    clone_url: &str,
+    name: &str,
+    credential: Option<&Credential>,
+) -> Result<Option<RemoteRef>> {
+    let patterns = [
+        format!("refs/heads/{}", name),
+        format!("refs/tags/{}", name),
+        // The commit an annotated tag points at rather than the tag object
+        format!("refs/tags/{}^{{}}", name),
+    ];
+    let patterns: Vec<&str> = patterns.iter().map(String::as_str).collect();
+    Ok(ls_remote(clone_url, &patterns, credential)
+        .await?
+        .and_then(|refs| find_ref(&refs, name)))
+}
+
+fn find_ref(refs: &HashMap<String, String>, name: &str) -> Option<RemoteRef> {
+    let find = |git_ref: String| refs.get(&git_ref).cloned();
+    find(format!("refs/heads/{}", name))
+        .map(RemoteRef::Branch)
+        .or_else(|| {
+            find(format!("refs/tags/{}^{{}}", name))
+                .or_else(|| find(format!("refs/tags/{}", name)))
+                .map(RemoteRef::Tag)
+        })
+}
+
+/// The commits at the heads of the branches of a remote repository, by branch
+pub async fn remote_branches(
+    clone_url: &str,
+    credential: Option<&Credential>,
+) -> Result<HashMap<String, String>> {
+    Ok(ls_remote(clone_url, &["refs/heads/*"], credential)
+        .await?
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(git_ref, sha)| Some((git_ref.strip_prefix("refs/heads/")?.to_string(), sha)))
+        .collect())
+}
+
+/// The contents of a derivation, as printed by `nix derivation show`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DerivationContents {
+    pub builder: String,
+    pub args: Vec<String>,
+    pub system: String,
+    pub env: HashMap<String, String>,
+    /// Keyed by derivation path; the outputs used are listed in a format that varies
+    /// between nix versions
+    pub input_drvs: HashMap<String, serde_json::Value>,
+    pub input_srcs: Vec<String>,
+    pub outputs: HashMap<String, DerivationOutput>,
+}
+
+impl DerivationContents {
+    /// Features a builder needs to build it, e.g. "kvm" or "big-parallel"
+    pub fn required_system_features(&self) -> Vec<String> {
+        // With structured attributes the environment is a single JSON document
+        if let Some(attrs) = self.env.get("__json") {
+            let attrs: serde_json::Value = serde_json::from_str(attrs).unwrap_or_default();
+            return attrs
+                .get("requiredSystemFeatures")
+                .and_then(serde_json::Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|feature| Some(feature.as_str()?.to_string()))
+                .collect();
+        }
+        self.env
+            .get("requiredSystemFeatures")
+            .map(|features| features.split_whitespace().map(str::to_string).collect())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DerivationOutput {
+    pub path: Option<String>,
+}
+
+/// Read a derivation from the local store
+pub async fn show_derivation(drv_path: &str) -> Result<DerivationContents> {
+    show_derivations(&[drv_path])
+        .await?
+        .into_values()
+        .next()
+        .ok_or_else(|| anyhow!("nix derivation show returned no derivation"))
+}
+
+/// Read several derivations from the local store, keyed by derivation path
+pub async fn show_derivations(drv_paths: &[&str]) -> Result<HashMap<String, DerivationContents>> {
+    let output = Command::new("nix")
+        .args(["derivation", "show"])
+        .args(drv_paths)
+        .kill_on_drop(true)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .context("Failed to execute nix derivation show")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("nix derivation show failed: {}", stderr.trim()));
+    }
+
+    serde_json::from_slice(&output.stdout).context("Failed to parse nix derivation show output")
+}
+
+/// Look up the output names and store paths of a derivation in the local store
+pub async fn derivation_outputs(drv_path: &str) -> Result<HashMap<String, String>> {
+    Ok(show_derivation(drv_path)
+        .await?
+        .outputs
+        .into_iter()
+        .filter_map(|(name, output)| output.path.map(|path| (name, path)))
+        .collect())
+}
+
+/// Fetch the log nix keeps of a derivation's build, `None` if it has none
+pub async fn build_log(drv_path: &str) -> Result<Option<String>> {
+    let output = Command::new("nix")
+        .args(["log", drv_path])
+        .output()
+        .await
+        .context("Failed to execute nix log")?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+}
+
+/// Split a derivation name into the package name and version, the way nix does:
+/// the version starts after the first dash followed by a digit
+pub fn split_version(name: &str) -> (&str, &str) {
+    let split = name.char_indices().find(|&(i, c)| {
+        c == '-'
+            && name[i + 1..]
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_digit())
+    });
+    match split {
+        Some((i, _)) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    }
+}
+
+/// Alphabet of nix's base-32 encoding, which leaves out e, o, u and t
+const NIX32_ALPHABET: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Decode a hash in nix's base-32 encoding
+fn decode_nix32(encoded: &str) -> Result<Vec<u8>> {
+    let len = encoded.len() * 5 / 8;
+    let mut bytes = vec![0u8; len];
+    for (n, c) in encoded.bytes().rev().enumerate() {
+        let digit = NIX32_ALPHABET
+            .iter()
+            .position(|&a| a == c)
+            .ok_or_else(|| anyhow!("Invalid character {:?} in nix32 hash", c as char))?
+            as u16;
+        let bit = n * 5;
+        let (i, j) = (bit / 8, bit % 8);
+        bytes[i] |= (digit << j) as u8;
+        if i + 1 < len {
+            bytes[i + 1] |= (digit >> (8 - j)) as u8;
+        }
+    }
+    Ok(bytes)
+}
+
+/// Encode a hash in nix's base-32 encoding, as in store paths and narinfo files
+pub(crate) fn encode_nix32(bytes: &[u8]) -> String {
+    let len = (bytes.len() * 8).div_ceil(5);
+    (0..len)
+        .rev()
+        .map(|n| {
+            let bit = n * 5;
+            let (i, j) = (bit / 8, bit % 8);
+            let mut digit = bytes[i] as u16 >> j;
+            if i + 1 < bytes.len() {
+                digit |= (bytes[i + 1] as u16) << (8 - j);
+            }
+            NIX32_ALPHABET[(digit & 0x1f) as usize] as char
+        })
+        .collect()
+}
+
+/// What the store knows about a path
+#[derive(Debug, PartialEq)]
+pub struct PathInfo {
+    pub path: String,
+    /// SHA-256 hash of the path's NAR serialization, hex encoded
+    pub nar_sha256: String,
+    pub references: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PathInfoJson {
+    path: Option<String>,
+    #[serde(rename = "narHash")]
+    nar_hash: String,
+    #[serde(default)]
+    references: Vec<String>,
+}
+
+/// `nix path-info --json` prints a list before nix 2.19 and an object keyed by path since
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PathInfoOutput {
+    List(Vec<PathInfoJson>),
+    Map(HashMap<String, PathInfoJson>),
+}
+
+fn parse_path_info(json: &[u8]) -> Result<Vec<PathInfo>> {
+    let infos = match serde_json::from_slice(json).context("Invalid nix path-info output")? {
+        PathInfoOutput::List(infos) => infos
+            .into_iter()
+            .map(|info| {
+                Ok((
+                    info.path
+                        .clone()
+                        .ok_or_else(|| anyhow!("Path info without path"))?,
+                    info,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        PathInfoOutput::Map(infos) => infos.into_iter().collect(),
+    };
+    let mut infos = infos
+        .into_iter()
+        .map(|(path, info)| {
+            Ok(PathInfo {
+                nar_sha256: hex::encode(decode_nar_hash(&info.nar_hash)?),
+                references: info.references,
+                path,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    infos.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(infos)
+}
+
+/// Order paths so each comes after the paths it references, e.g. to upload them to a
+/// cache that must never list a path whose references are missing
+pub fn dependencies_first(infos: Vec<PathInfo>) -> Vec<PathInfo> {
+    fn visit(
+        index: usize,
+        infos: &[PathInfo],
+        by_path: &HashMap<&str, usize>,
+        visited: &mut [bool],
+        order: &mut Vec<usize>,
+    ) {
+        if std::mem::replace(&mut visited[index], true) {
+            return;
+        }
+        for reference in &infos[index].references {
+            if let Some(&dependency) = by_path.get(reference.as_str()) {
+                visit(dependency, infos, by_path, visited, order);
+            }
+        }
+        order.push(index);
+    }
+
+    let by_path: HashMap<&str, usize> = infos
+        .iter()
+        .enumerate()
+        .map(|(index, info)| (info.path.as_str(), index))
+        .collect();
+    let mut visited = vec![false; infos.len()];
+    let mut order = Vec::with_capacity(infos.len());
+    for index in 0..infos.len() {
+        visit(index, &infos, &by_path, &mut visited, &mut order);
+    }
+    let mut infos: Vec<Option<PathInfo>> = infos.into_iter().map(Some).collect();
+    order
+        .into_iter()
+        .map(|index| infos[index].take().unwrap())
+        .collect()
+}
+
+/// Decode a NAR hash, either `sha256:<nix32>` or an SRI hash `sha256-<base64>`
+fn decode_nar_hash(hash: &str) -> Result<Vec<u8>> {
+    if let Some(encoded) = hash.strip_prefix("sha256:") {
+        decode_nix32(encoded)
+    } else if let Some(encoded) = hash.strip_prefix("sha256-") {
+        Ok(BASE64.decode(encoded)?)
+    } else {
+        Err(anyhow!("Unsupported NAR hash {}", hash))
+    }
+}
+
+/// Look up store paths, with their whole closure if `recursive`
+pub async fn path_info(paths: &[&str], recursive: bool) -> Result<Vec<PathInfo>> {
+    let mut command = Command::new("nix");
+    command.args(["path-info", "--json"]);
+    if recursive {
+        command.arg("--recursive");
+    }
+    let output = command
+        .args(paths)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .context("Failed to execute nix path-info")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("nix path-info failed: {}", stderr.trim()));
+    }
+    parse_path_info(&output.stdout)
+}
+
 impl Drop for NixEvaluator {
     fn drop(&mut self) {
         if let Some(temp_dir) = &self.temp_dir {
@@ -268,4 +1190,291 @@ mod tests {
         assert_eq!(job.system, "x86_64-linux");
         assert!(job.outputs.contains_key("out"));
     }
+
+    #[tokio::test]
+    async fn test_limited() {
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        };
+        let error = limited(Some(Duration::from_millis(10)), "Evaluation", slow)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Evaluation timed out after 0 seconds");
+        assert_eq!(
+            limited(None, "Evaluation", async { Ok(1) }).await.unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_parse_eval_output() {
+        let stdout = r#"
+{"attr":"hello","attrPath":["hello"],"drvPath":"/nix/store/a-hello.drv","outputs":{"out":"/nix/store/a-hello"},"system":"x86_64-linux"}
+{"attr":"broken","attrPath":["broken"],"error":"error: attribute 'foo' missing"}
+not json
+"#;
+        let (jobs, errors) = parse_eval_output(stdout);
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].attr, "hello");
+        assert_eq!(
+            errors,
+            [EvalError {
+                attr: "broken".to_string(),
+                error: "error: attribute 'foo' missing".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "4242 (nix-eval-jobs (x)) S 1 4240 4240 0 -1 4194304 1000 0 0 0 \
+                    10 5 0 0 20 0 3 0 100 123456789 2560 18446744073709551615";
+        assert_eq!(parse_stat(stat), Some((4240, 2560)));
+        assert_eq!(parse_stat("4242 (truncated) S 1"), None);
+    }
+
+    #[test]
+    fn test_flake_reference() {
+        let sha = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(
+            flake_reference("https://github.com/yuri91/icicle.git", sha),
+            format!("github:yuri91/icicle/{}", sha)
+        );
+        assert_eq!(
+            flake_reference("git@github.com:yuri91/icicle.git", sha),
+            format!("git+ssh://git@github.com/yuri91/icicle.git?rev={}", sha)
+        );
+        assert_eq!(
+            flake_reference("https://gitlab.com/group/project.git", sha),
+            format!("git+https://gitlab.com/group/project.git?rev={}", sha)
+        );
+        assert_eq!(
+            flake_reference("/srv/git/repo", sha),
+            format!("git+file:///srv/git/repo?rev={}", sha)
+        );
+    }
+
+    #[test]
+    fn test_ifd_options() {
+        assert_eq!(
+            ifd_options(None),
+            ["--option", "allow-import-from-derivation", "false"]
+        );
+        let ifd = IfdConfig {
+            max_jobs: 2,
+            ..IfdConfig::default()
+        };
+        assert_eq!(
+            ifd_options(Some(&ifd)).join(" "),
+            "--option allow-import-from-derivation true --option max-jobs 2 \
+             --option cores 0 --option timeout 600"
+        );
+        let ifd = IfdConfig {
+            repositories: vec!["nix-community/*".to_string()],
+            ..IfdConfig::default()
+        };
+        assert!(ifd.allows("nix-community/home-manager"));
+        assert!(!ifd.allows("NixOS/nixpkgs"));
+    }
+
+    #[test]
+    fn test_attribute_sets() {
+        assert_eq!(
+            attribute_sets("packages.x86_64-linux, checks.x86_64-linux,"),
+            ["packages.x86_64-linux", "checks.x86_64-linux"]
+        );
+        assert_eq!(
+            job_attribute("packages.x86_64-linux", "hello"),
+            "packages.x86_64-linux.hello"
+        );
+        assert_eq!(
+            job_attribute(
+                "packages.x86_64-linux,checks.x86_64-linux",
+                "checks.x86_64-linux.fmt"
+            ),
+            "checks.x86_64-linux.fmt"
+        );
+    }
+
+    #[test]
+    fn test_aggregate_constituents() {
+        let job: NixEvalJob = serde_json::from_str(
+            r#"{"attr": "release", "drvPath": "/nix/store/r-release.drv", "outputs": {},
+                "system": "x86_64-linux", "constituents": ["/nix/store/a-hello.drv"],
+                "namedConstituents": ["tests.vm", "missing"]}"#,
+        )
+        .unwrap();
+        let jobs = HashMap::from([("tests.vm", "/nix/store/b-vm.drv")]);
+        assert_eq!(
+            aggregate_constituents(&job, &jobs),
+            Some(vec![
+                "/nix/store/a-hello.drv".to_string(),
+                "/nix/store/b-vm.drv".to_string()
+            ])
+        );
+        let plain = NixEvalJob {
+            constituents: None,
+            ..job
+        };
+        assert_eq!(aggregate_constituents(&plain, &jobs), None);
+    }
+
+    #[test]
+    fn test_parse_ls_remote() {
+        let output = "944f519daf8a8da811597422971c4473df3929e3\trefs/tags/v1\n\
+                      34c4bafb5373f179f7402a59b4037a6330f63a57\trefs/tags/v1^{}\n";
+        assert_eq!(
+            find_ref(&parse_ls_remote(output), "v1"),
+            Some(RemoteRef::Tag(
+                "34c4bafb5373f179f7402a59b4037a6330f63a57".to_string()
+            ))
+        );
+        let output = format!(
+            "{}\n709d658dc5b6d6afcd46049c2f332ee3f515a67d\trefs/heads/v1",
+            output
+        );
+        assert_eq!(
+            find_ref(&parse_ls_remote(&output), "v1"),
+            Some(RemoteRef::Branch(
+                "709d658dc5b6d6afcd46049c2f332ee3f515a67d".to_string()
+            ))
+        );
+        assert_eq!(find_ref(&parse_ls_remote(""), "v1"), None);
+    }
+
+    #[test]
+    fn test_licenses() {
+        let job = |meta: serde_json::Value| NixEvalJob {
+            meta: Some(meta),
+            ..serde_json::from_str(
+                r#"{"attr": "hello", "drvPath": "/nix/store/a-hello.drv",
+                    "outputs": {}, "system": "x86_64-linux"}"#,
+            )
+            .unwrap()
+        };
+        let single = job(serde_json::json!({"license": {"spdxId": "MIT", "shortName": "mit"}}));
+        assert_eq!(single.licenses(), vec!["MIT"]);
+        let several = job(serde_json::json!({"license": [
+            {"shortName": "unfree", "fullName": "Unfree"},
+            "LGPL-2.1-only"
+        ]}));
+        assert_eq!(several.licenses(), vec!["unfree", "LGPL-2.1-only"]);
+        assert!(job(serde_json::json!({})).licenses().is_empty());
+    }
+
+    #[test]
+    fn test_timeout_secs() {
+        let job = |meta: serde_json::Value| NixEvalJob {
+            meta: Some(meta),
+            ..serde_json::from_str(
+                r#"{"attr": "hello", "drvPath": "/nix/store/a-hello.drv",
+                    "outputs": {}, "system": "x86_64-linux"}"#,
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            job(serde_json::json!({"timeout": 7200})).timeout_secs(),
+            Some(7200)
+        );
+        assert_eq!(job(serde_json::json!({"timeout": 0})).timeout_secs(), None);
+        assert_eq!(
+            job(serde_json::json!({"timeout": "1h"})).timeout_secs(),
+            None
+        );
+        assert_eq!(job(serde_json::json!({})).timeout_secs(), None);
+    }
+
+    #[test]
+    fn test_required_system_features() {
+        let derivation = |env: serde_json::Value| -> DerivationContents {
+            serde_json::from_value(serde_json::json!({
+                "builder": "/bin/sh", "args": [], "system": "x86_64-linux", "env": env,
+                "inputDrvs": {}, "inputSrcs": [], "outputs": {}
+            }))
+            .unwrap()
+        };
+        let plain = derivation(serde_json::json!({"requiredSystemFeatures": "kvm nixos-test"}));
+        assert_eq!(plain.required_system_features(), ["kvm", "nixos-test"]);
+        let structured = derivation(serde_json::json!({
+            "__json": r#"{"requiredSystemFeatures": ["big-parallel"]}"#
+        }));
+        assert_eq!(structured.required_system_features(), ["big-parallel"]);
+        assert!(derivation(serde_json::json!({}))
+            .required_system_features()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_nix32() {
+        // sha256("abc")
+        assert_eq!(
+            hex::encode(
+                decode_nix32("1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s").unwrap()
+            ),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(decode_nix32("1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5e").is_err());
+        assert_eq!(
+            encode_nix32(
+                &hex::decode("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+                    .unwrap()
+            ),
+            "1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s"
+        );
+    }
+
+    #[test]
+    fn test_parse_path_info() {
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let list = br#"[{"path": "/nix/store/b-hello",
+                        "narHash": "sha256:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s",
+                        "references": ["/nix/store/a-glibc"]}]"#;
+        let map = br#"{"/nix/store/b-hello": {
+                        "narHash": "sha256-ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=",
+                        "references": ["/nix/store/a-glibc"]}}"#;
+        for json in [&list[..], &map[..]] {
+            assert_eq!(
+                parse_path_info(json).unwrap(),
+                vec![PathInfo {
+                    path: "/nix/store/b-hello".to_string(),
+                    nar_sha256: abc.to_string(),
+                    references: vec!["/nix/store/a-glibc".to_string()],
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn test_dependencies_first() {
+        let info = |path: &str, references: &[&str]| PathInfo {
+            path: format!("/nix/store/{}", path),
+            nar_sha256: String::new(),
+            references: references
+                .iter()
+                .map(|r| format!("/nix/store/{}", r))
+                .collect(),
+        };
+        // Sorted by name as path_info returns them, referencing themselves and paths
+        // outside the list
+        let infos = vec![
+            info("a-app", &["a-app", "c-lib", "b-glibc"]),
+            info("b-glibc", &["b-glibc", "z-elsewhere"]),
+            info("c-lib", &["b-glibc"]),
+            info("d-tool", &[]),
+        ];
+        let paths: Vec<String> = dependencies_first(infos)
+            .into_iter()
+            .map(|info| info.path)
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "/nix/store/b-glibc",
+                "/nix/store/c-lib",
+                "/nix/store/a-app",
+                "/nix/store/d-tool"
+            ]
+        );
+    }
 }
@@ -0,0 +1,264 @@
+use super::Notifier;
+use anyhow::Result;
+use askama::Template;
+use chrono::{Datelike, Duration, Local, NaiveDateTime, TimeZone, Weekday};
+use serde::Deserialize;
+use std::{collections::BTreeMap, sync::Arc};
+use tracing::{error, info};
+
+/// Length of the period a digest covers
+const DIGEST_PERIOD_DAYS: i64 = 7;
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DigestConfig {
+    /// Day of the week the digest is sent on, e.g. "mon"
+    pub weekday: Weekday,
+    /// Local hour of the day the digest is sent at
+    pub hour: u32,
+    /// Notifiers to send the digest through; all configured notifiers when empty
+    pub notifiers: Vec<String>,
+    /// Number of slowest builds listed per repository
+    pub slowest_builds: usize,
+}
+
+impl Default for DigestConfig {
+    fn default() -> Self {
+        DigestConfig {
+            weekday: Weekday::Mon,
+            hour: 9,
+            notifiers: Vec::new(),
+            slowest_builds: 5,
+        }
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct WorkflowCountRow {
+    repository: String,
+    total: i64,
+    failed: i64,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct BuildCountRow {
+    repository: String,
+    total: i64,
+    cached: i64,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct BuildDurationRow {
+    repository: String,
+    name: String,
+    duration: i64,
+}
+
+#[derive(Debug, Default)]
+struct RepositoryDigest {
+    workflows: i64,
+    failed_workflows: i64,
+    builds: i64,
+    cached_builds: i64,
+    /// Names and formatted durations of the slowest builds
+    slowest: Vec<(String, String)>,
+}
+
+impl RepositoryDigest {
+    fn failure_rate(&self) -> String {
+        percent(self.failed_workflows, self.workflows)
+    }
+
+    fn cache_hit_rate(&self) -> String {
+        percent(self.cached_builds, self.builds)
+    }
+}
+
+fn percent(part: i64, total: i64) -> String {
+    if total == 0 {
+        return "-".to_string();
+    }
+    format!("{:.0}%", part as f64 * 100.0 / total as f64)
+}
+
+pub fn format_duration(secs: i64) -> String {
+    match secs {
+        s if s >= 3600 => format!("{}h{:02}m", s / 3600, s % 3600 / 60),
+        s if s >= 60 => format!("{}m{:02}s", s / 60, s % 60),
+        s => format!("{}s", s),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "notify/digest.txt")]
+struct DigestTemplate<'a> {
+    since: String,
+    until: String,
+    repositories: &'a BTreeMap<String, RepositoryDigest>,
+}
+
+/// Periodically summarizes the past week of CI activity
+pub struct DigestScheduler {
+    config: DigestConfig,
+    db_pool: sqlx::SqlitePool,
+    notifiers: Vec<Arc<dyn Notifier>>,
+}
+
+impl DigestScheduler {
+    pub fn new(
+        config: DigestConfig,
+        db_pool: sqlx::SqlitePool,
+        notifiers: Vec<Arc<dyn Notifier>>,
+    ) -> Self {
+        let notifiers = notifiers
+            .into_iter()
+            .filter(|n| {
+                config.notifiers.is_empty() || config.notifiers.iter().any(|c| c == n.name())
+            })
+            .collect();
+        Self {
+            config,
+            db_pool,
+            notifiers,
+        }
+    }
+
+    pub async fn run(self) {
+        loop {
+            let now = Local::now();
+            let next = next_run(now.naive_local(), self.config.weekday, self.config.hour);
+            let next = Local
+                .from_local_datetime(&next)
+                .earliest()
+                .unwrap_or(now + Duration::hours(1));
+            info!("Next CI digest at {}", next);
+            let wait = (next - now).to_std().unwrap_or_default();
+            tokio::time::sleep(wait).await;
+
+            if let Err(e) = self.send().await {
+                error!("Failed to send CI digest: {:#}", e);
+            }
+        }
+    }
+
+    async fn send(&self) -> Result<()> {
+        let until = chrono::Utc::now();
+        let since = until - Duration::days(DIGEST_PERIOD_DAYS);
+        let repositories = self.collect(since.timestamp()).await?;
+
+        let body = DigestTemplate {
+            since: since.with_timezone(&Local).format("%Y-%m-%d").to_string(),
+            until: until.with_timezone(&Local).format("%Y-%m-%d").to_string(),
+            repositories: &repositories,
+        }
+        .render()?;
+        let title = "Weekly CI digest";
+
+        for notifier in &self.notifiers {
+            match notifier.send_text(title, &body).await {
+                Ok(()) => info!("Sent CI digest via {}", notifier.name()),
+                Err(e) => error!("Failed to send CI digest via {}: {:#}", notifier.name(), e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn collect(&self, since: i64) -> Result<BTreeMap<String, RepositoryDigest>> {
+        let mut repositories: BTreeMap<String, RepositoryDigest> = BTreeMap::new();
+
+        let workflows = sqlx::query_as::<_, WorkflowCountRow>(
+            r#"
+            SELECT repository, COUNT(*) AS total, SUM(status = 'Failed') AS failed
+            FROM workflows
+            WHERE created_at >= ? AND status IN ('Completed', 'Failed')
+            GROUP BY repository
+            "#,
+        )
+        .bind(since)
+        .fetch_all(&self.db_pool)
+        .await?;
+        for row in workflows {
+            let digest = repositories.entry(row.repository).or_default();
+            digest.workflows = row.total;
+            digest.failed_workflows = row.failed;
+        }
+
+        let builds = sqlx::query_as::<_, BuildCountRow>(
+            r#"
+            SELECT w.repository, COUNT(DISTINCT b.drv_path) AS total,
+                   COUNT(DISTINCT CASE WHEN b.status = 'cached' THEN b.drv_path END) AS cached
+            FROM builds b
+            JOIN build_workflows bw ON bw.drv_path = b.drv_path
+            JOIN workflows w ON w.id = bw.workflow_id
+            WHERE w.created_at >= ?
+            GROUP BY w.repository
+            "#,
+        )
+        .bind(since)
+        .fetch_all(&self.db_pool)
+        .await?;
+        for row in builds {
+            let digest = repositories.entry(row.repository).or_default();
+            digest.builds = row.total;
+            digest.cached_builds = row.cached;
+        }
+
+        let durations = sqlx::query_as::<_, BuildDurationRow>(
+            r#"
+            SELECT DISTINCT w.repository, b.name, b.finished_at - b.started_at AS duration
+            FROM builds b
+            JOIN build_workflows bw ON bw.drv_path = b.drv_path
+            JOIN workflows w ON w.id = bw.workflow_id
+            WHERE w.created_at >= ? AND b.status = 'success' AND b.finished_at IS NOT NULL
+            ORDER BY duration DESC
+            "#,
+        )
+        .bind(since)
+        .fetch_all(&self.db_pool)
+        .await?;
+        for row in durations {
+            let digest = repositories.entry(row.repository).or_default();
+            if digest.slowest.len() < self.config.slowest_builds {
+                digest
+                    .slowest
+                    .push((row.name, format_duration(row.duration)));
+            }
+        }
+
+        Ok(repositories)
+    }
+}
+
+/// The first time after `now` that falls on `weekday` at `hour`:00
+fn next_run(now: NaiveDateTime, weekday: Weekday, hour: u32) -> NaiveDateTime {
+    let today = now.date();
+    (0..=DIGEST_PERIOD_DAYS)
+        .filter_map(|days| {
+            let date = today + Duration::days(days);
+            (date.weekday() == weekday)
+                .then(|| date.and_hms_opt(hour.min(23), 0, 0))
+                .flatten()
+        })
+        .find(|run| *run > now)
+        .unwrap_or(now + Duration::days(DIGEST_PERIOD_DAYS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_next_run() {
+        // 2025-01-06 is a Monday
+        let at = |day, hour| {
+            NaiveDate::from_ymd_opt(2025, 1, day)
+                .unwrap()
+                .and_hms_opt(hour, 0, 0)
+                .unwrap()
+        };
+        assert_eq!(next_run(at(6, 8), Weekday::Mon, 9), at(6, 9));
+        assert_eq!(next_run(at(6, 9), Weekday::Mon, 9), at(13, 9));
+        assert_eq!(next_run(at(8, 12), Weekday::Fri, 17), at(10, 17));
+    }
+}
@@ -0,0 +1,223 @@
+use super::{markdown_code, repository_enabled, Notification, Notifier, NotifyRules, Outcome};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// Discord rejects embed field values longer than this
+const FIELD_VALUE_LIMIT: usize = 1024;
+/// Discord rejects embed descriptions longer than this
+const DESCRIPTION_LIMIT: usize = 4096;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DiscordConfig {
+    /// Default webhook URL for repositories without an entry in `webhooks`
+    pub webhook_url: Option<String>,
+    /// Webhook URL per repository full name
+    #[serde(default)]
+    pub webhooks: HashMap<String, String>,
+    /// Repositories to send notifications for; all repositories when empty
+    #[serde(default)]
+    pub repositories: Vec<String>,
+    /// Overrides the global notification rules
+    pub rules: Option<NotifyRules>,
+}
+
+pub struct DiscordNotifier {
+    config: DiscordConfig,
+    client: Client,
+}
+
+impl DiscordNotifier {
+    pub fn new(config: DiscordConfig) -> Self {
+        Self {
+            config,
+            client: Client::new(),
+        }
+    }
+
+    fn webhook_for(&self, repository: &str) -> Option<&str> {
+        self.config
+            .webhooks
+            .get(repository)
+            .or(self.config.webhook_url.as_ref())
+            .map(String::as_str)
+    }
+}
+
+fn embed(notification: &Notification) -> Value {
+    let status = match notification.outcome {
+        Outcome::Failure => "Failed",
+        Outcome::Recovery => "Recovered",
+        Outcome::Success => "Succeeded",
+    };
+    let mut fields = vec![
+        json!({ "name": "Status", "value": status, "inline": true }),
+        json!({
+            "name": "Commit",
+            "value": format!("`{}`", notification.commit_sha.get(..7).unwrap_or(&notification.commit_sha)),
+            "inline": true,
+        }),
+    ];
+    if let Some(branch) = &notification.branch {
+        fields.push(json!({ "name": "Branch", "value": escape_markdown(branch), "inline": true }));
+    }
+
+    if !notification.failed_jobs.is_empty() {
+        let mut value = String::new();
+        let mut shown = 0;
+        for job in notification.listed_jobs().0 {
+            let name = markdown_code(&job.name);
+            let line = match &job.log_url {
+                Some(url) => format!("• {} ({}, [log]({}))\n", name, job.detail(), url),
+                None => format!("• {} ({})\n", name, job.detail()),
+            };
+            // Keep room for the "and N more" suffix
+            if value.len() + line.len() > FIELD_VALUE_LIMIT - 20 {
+                break;
+            }
+            value.push_str(&line);
+            shown += 1;
+        }
+        let more = notification.failed_jobs.len() - shown;
+        if more > 0 {
+            value.push_str(&format!("… and {} more", more));
+        }
+        fields.push(json!({ "name": "Failed jobs", "value": value, "inline": false }));
+    }
+
+    let mut embed = json!({
+        "title": notification.summary(),
+        "color": notification.outcome.color(),
+        "fields": fields,
+    });
+    if let Some(url) = &notification.workflow_url {
+        embed["url"] = json!(url);
+    }
+    embed
+}
+
+/// Escape the characters Discord treats as Markdown
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(
+            c,
+            '\\' | '*' | '_' | '~' | '`' | '|' | '>' | '#' | '[' | ']' | '(' | ')'
+        ) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[async_trait]
+impl Notifier for DiscordNotifier {
+    fn name(&self) -> &'static str {
+        "discord"
+    }
+
+    fn accepts(&self, notification: &Notification) -> bool {
+        repository_enabled(&self.config.repositories, &notification.repository)
+            && self.webhook_for(&notification.repository).is_some()
+    }
+
+    async fn send(&self, notification: &Notification) -> Result<()> {
+        match self.webhook_for(&notification.repository) {
+            Some(webhook_url) => self.send_to(notification, webhook_url).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn send_to(&self, notification: &Notification, webhook_url: &str) -> Result<()> {
+        self.client
+            .post(webhook_url)
+            .json(&json!({
+                "username": "icicle",
+                "embeds": [embed(notification)],
+            }))
+            .send()
+            .await
+            .context("Failed to reach Discord")?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn send_text(&self, title: &str, body: &str) -> Result<()> {
+        let Some(webhook_url) = &self.config.webhook_url else {
+            return Ok(());
+        };
+        let mut description = format!("```\n{}\n```", body);
+        if description.len() > DESCRIPTION_LIMIT {
+            let mut end = DESCRIPTION_LIMIT - 8;
+            while !body.is_char_boundary(end) {
+                end -= 1;
+            }
+            description = format!("```\n{}\n```", &body[..end]);
+        }
+        self.client
+            .post(webhook_url)
+            .json(&json!({
+                "username": "icicle",
+                "embeds": [{ "title": title, "description": description }],
+            }))
+            .send()
+            .await
+            .context("Failed to reach Discord")?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::failed_notification;
+
+    #[test]
+    fn test_embed() {
+        let log = |i| format!("https://ci.example.com/builds/hash{:02}-job.drv", i);
+        let listed: String = (1..10)
+            .map(|i| format!("• `job{:02}` (failed, [log]({}))\n", i, log(i)))
+            .collect();
+        assert_eq!(
+            embed(&failed_notification()),
+            json!({
+                "title": "me/repo fix/<b>&`c`_* failed (0123456)",
+                "url": "https://ci.example.com/workflows/42",
+                "color": 0xd50200,
+                "fields": [
+                    { "name": "Status", "value": "Failed", "inline": true },
+                    { "name": "Commit", "value": "`0123456`", "inline": true },
+                    { "name": "Branch", "value": "fix/<b\\>&\\`c\\`\\_\\*", "inline": true },
+                    {
+                        "name": "Failed jobs",
+                        "value": format!(
+                            "• `` tests.<a>&`b`_* `` (failed, [log]({}))\n{}… and 2 more",
+                            log(0),
+                            listed
+                        ),
+                        "inline": false,
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_embed_field_limit() {
+        let mut notification = failed_notification();
+        notification.failed_jobs.truncate(3);
+        for job in &mut notification.failed_jobs {
+            job.name = "x".repeat(400);
+        }
+        let embed = embed(&notification);
+        let value = embed["fields"][3]["value"].as_str().unwrap();
+        assert!(value.len() <= FIELD_VALUE_LIMIT);
+        assert!(value.ends_with("… and 1 more"));
+        assert_eq!(value.matches(&"x".repeat(400)).count(), 2);
+    }
+}
@@ -0,0 +1,219 @@
+use super::{
+    repository_enabled, FailedJob, Notification, Notifier, NotifyRules, Outcome, ALERT_OUTCOMES,
+};
+use anyhow::{Context, Result};
+use askama::Template;
+use async_trait::async_trait;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Plain connection upgraded with STARTTLS (usually port 587)
+    Starttls,
+    /// TLS from the start (usually port 465)
+    Tls,
+    /// Unencrypted, only for local relays
+    None,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct EmailConfig {
+    pub smtp_host: String,
+    pub smtp_port: Option<u16>,
+    #[serde(default = "default_security")]
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sender address, e.g. "icicle <ci@example.com>"
+    pub from: String,
+    /// Addresses that receive every notification
+    #[serde(default)]
+    pub to: Vec<String>,
+    /// Also email the author of the commit that was built
+    #[serde(default)]
+    pub notify_authors: bool,
+    /// Repositories to send notifications for; all repositories when empty
+    #[serde(default)]
+    pub repositories: Vec<String>,
+    /// Overrides the global notification rules
+    pub rules: Option<NotifyRules>,
+}
+
+fn default_security() -> SmtpSecurity {
+    SmtpSecurity::Starttls
+}
+
+#[derive(Template)]
+#[template(path = "notify/email.txt")]
+struct EmailTemplate<'a> {
+    notification: &'a Notification,
+    short_sha: &'a str,
+    failed_jobs: &'a [FailedJob],
+    /// Failed jobs left out of the list
+    more_jobs: usize,
+}
+
+fn message_body(notification: &Notification) -> askama::Result<String> {
+    let (failed_jobs, more_jobs) = notification.listed_jobs();
+    EmailTemplate {
+        notification,
+        short_sha: notification
+            .commit_sha
+            .get(..7)
+            .unwrap_or(&notification.commit_sha),
+        failed_jobs,
+        more_jobs,
+    }
+    .render()
+}
+
+pub struct EmailNotifier {
+    config: EmailConfig,
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl EmailNotifier {
+    pub fn new(config: EmailConfig) -> Result<Self> {
+        let from = config
+            .from
+            .parse()
+            .with_context(|| format!("Invalid sender address '{}'", config.from))?;
+
+        let mut builder = match config.security {
+            SmtpSecurity::Starttls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host)?
+            }
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp_host)?,
+            SmtpSecurity::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.smtp_host)
+            }
+        };
+        if let Some(port) = config.smtp_port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(Self {
+            config,
+            from,
+            transport: builder.build(),
+        })
+    }
+
+    fn recipients(&self, notification: &Notification) -> Vec<String> {
+        let mut recipients = self.config.to.clone();
+        if self.config.notify_authors {
+            if let Some(author) = &notification.author_email {
+                if !recipients.contains(author) {
+                    recipients.push(author.clone());
+                }
+            }
+        }
+        recipients
+    }
+
+    async fn deliver(&self, subject: &str, body: String, recipients: &[String]) -> Result<()> {
+        if recipients.is_empty() {
+            return Ok(());
+        }
+
+        let mut message = Message::builder().from(self.from.clone()).subject(subject);
+        for recipient in recipients {
+            let mailbox: Mailbox = recipient
+                .parse()
+                .with_context(|| format!("Invalid recipient address '{}'", recipient))?;
+            message = message.to(mailbox);
+        }
+
+        self.transport
+            .send(message.body(body)?)
+            .await
+            .context("Failed to send email")?;
+        Ok(())
+    }
+
+    async fn deliver_notification(
+        &self,
+        notification: &Notification,
+        recipients: &[String],
+    ) -> Result<()> {
+        let body = message_body(notification)?;
+        let subject = format!("[icicle] {}", notification.summary());
+        self.deliver(&subject, body, recipients).await
+    }
+}
+
+#[async_trait]
+impl Notifier for EmailNotifier {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    fn default_outcomes(&self) -> &'static [Outcome] {
+        ALERT_OUTCOMES
+    }
+
+    fn accepts(&self, notification: &Notification) -> bool {
+        repository_enabled(&self.config.repositories, &notification.repository)
+    }
+
+    async fn send(&self, notification: &Notification) -> Result<()> {
+        self.deliver_notification(notification, &self.recipients(notification))
+            .await
+    }
+
+    async fn send_to(&self, notification: &Notification, target: &str) -> Result<()> {
+        self.deliver_notification(notification, &[target.to_string()])
+            .await
+    }
+
+    async fn send_text(&self, title: &str, body: &str) -> Result<()> {
+        self.deliver(
+            &format!("[icicle] {}", title),
+            body.to_string(),
+            &self.config.to,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::failed_notification;
+
+    #[test]
+    fn test_message_body() {
+        let body = message_body(&failed_notification()).unwrap();
+        // Plain text, so names go as they are
+        assert!(body.starts_with(
+            "Workflow 42 for me/repo failed.\n\
+             \n\
+             Commit: 0123456789abcdef (0123456)\n\
+             Branch: fix/<b>&`c`_*\n\
+             Details: https://ci.example.com/workflows/42\n\
+             \n\
+             Failed jobs:\n  \
+             - tests.<a>&`b`_* (failed)\n    \
+             /nix/store/hash00-job.drv\n    \
+             Log: https://ci.example.com/builds/hash00-job.drv\n"
+        ));
+        assert!(body.contains("  - job09 (failed)\n"));
+        assert!(!body.contains("job10"));
+        assert!(body.ends_with(
+            "Log: https://ci.example.com/builds/hash09-job.drv\n  \
+             ... and 2 more\n\
+             \n\
+             --\n\
+             Sent by icicle"
+        ));
+    }
+}
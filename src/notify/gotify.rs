@@ -0,0 +1,158 @@
+use super::{
+    markdown_code, repository_enabled, Notification, Notifier, NotifyRules, Outcome, ALERT_OUTCOMES,
+};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct GotifyConfig {
+    /// Gotify server URL, e.g. "https://gotify.example.com"
+    pub url: String,
+    /// Application token messages are posted with
+    pub token: String,
+    /// Repositories to send notifications for; all repositories when empty
+    #[serde(default)]
+    pub repositories: Vec<String>,
+    /// Overrides the global notification rules
+    pub rules: Option<NotifyRules>,
+}
+
+pub struct GotifyNotifier {
+    config: GotifyConfig,
+    client: Client,
+}
+
+impl GotifyNotifier {
+    pub fn new(config: GotifyConfig) -> Self {
+        Self {
+            config,
+            client: Client::new(),
+        }
+    }
+}
+
+fn message_markdown(notification: &Notification) -> String {
+    let mut message = format!("Commit `{}`", notification.commit_sha);
+    if let Some(branch) = &notification.branch {
+        message.push_str(&format!(" on {}", markdown_code(branch)));
+    }
+    if let Some(url) = &notification.workflow_url {
+        message.push_str(&format!(
+            "\n\n[Workflow {}]({})",
+            notification.workflow_id, url
+        ));
+    }
+    let (listed, more) = notification.listed_jobs();
+    if !listed.is_empty() {
+        message.push_str("\n\nFailed jobs:\n");
+        for job in listed {
+            let name = markdown_code(&job.name);
+            match &job.log_url {
+                Some(url) => {
+                    message.push_str(&format!("\n- {} ({}, [log]({}))", name, job.detail(), url))
+                }
+                None => message.push_str(&format!("\n- {} ({})", name, job.detail())),
+            }
+        }
+        if more > 0 {
+            message.push_str(&format!("\n- … and {} more", more));
+        }
+    }
+    message
+}
+
+#[async_trait]
+impl Notifier for GotifyNotifier {
+    fn name(&self) -> &'static str {
+        "gotify"
+    }
+
+    fn default_outcomes(&self) -> &'static [Outcome] {
+        ALERT_OUTCOMES
+    }
+
+    fn accepts(&self, notification: &Notification) -> bool {
+        repository_enabled(&self.config.repositories, &notification.repository)
+    }
+
+    async fn send(&self, notification: &Notification) -> Result<()> {
+        self.send_to(notification, &self.config.token).await
+    }
+
+    /// Targets are application tokens on the configured server
+    async fn send_to(&self, notification: &Notification, token: &str) -> Result<()> {
+        let priority = match notification.outcome {
+            Outcome::Failure => 8,
+            Outcome::Recovery | Outcome::Success => 4,
+        };
+        let mut extras = json!({
+            "client::display": { "contentType": "text/markdown" },
+        });
+        if let Some(url) = &notification.workflow_url {
+            extras["client::notification"] = json!({ "click": { "url": url } });
+        }
+
+        self.client
+            .post(format!("{}/message", self.config.url.trim_end_matches('/')))
+            .header("X-Gotify-Key", token)
+            .json(&json!({
+                "title": notification.summary(),
+                "message": message_markdown(notification),
+                "priority": priority,
+                "extras": extras,
+            }))
+            .send()
+            .await
+            .context("Failed to reach Gotify server")?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn send_text(&self, title: &str, body: &str) -> Result<()> {
+        self.client
+            .post(format!("{}/message", self.config.url.trim_end_matches('/')))
+            .header("X-Gotify-Key", &self.config.token)
+            .json(&json!({
+                "title": title,
+                "message": body,
+                "priority": 4,
+            }))
+            .send()
+            .await
+            .context("Failed to reach Gotify server")?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::failed_notification;
+
+    #[test]
+    fn test_message_markdown() {
+        let log = |i| format!("https://ci.example.com/builds/hash{:02}-job.drv", i);
+        let listed: String = (1..10)
+            .map(|i| format!("\n- `job{:02}` (failed, [log]({}))", i, log(i)))
+            .collect();
+        assert_eq!(
+            message_markdown(&failed_notification()),
+            format!(
+                "Commit `0123456789abcdef` on `` fix/<b>&`c`_* ``\n\
+                 \n\
+                 [Workflow 42](https://ci.example.com/workflows/42)\n\
+                 \n\
+                 Failed jobs:\n\
+                 \n\
+                 - `` tests.<a>&`b`_* `` (failed, [log]({})){}\n\
+                 - … and 2 more",
+                log(0),
+                listed
+            )
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use super::{escape_html, repository_enabled, Notification, Notifier, NotifyRules};
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MatrixConfig {
+    /// Homeserver base URL, e.g. "https://matrix.org"
+    pub homeserver: String,
+    /// Access token of the account posting the messages
+    pub access_token: String,
+    /// Default room ID, e.g. "!abcdef:matrix.org"
+    pub room_id: Option<String>,
+    /// Room ID per repository full name
+    #[serde(default)]
+    pub rooms: HashMap<String, String>,
+    /// Repositories to send notifications for; all repositories when empty
+    #[serde(default)]
+    pub repositories: Vec<String>,
+    /// Overrides the global notification rules
+    pub rules: Option<NotifyRules>,
+}
+
+pub struct MatrixNotifier {
+    config: MatrixConfig,
+    homeserver: Url,
+    client: Client,
+    /// Distinguishes transaction IDs of messages sent in the same instant
+    txn_counter: AtomicU64,
+}
+
+impl MatrixNotifier {
+    pub fn new(config: MatrixConfig) -> Result<Self> {
+        let homeserver = Url::parse(&config.homeserver)
+            .with_context(|| format!("Invalid Matrix homeserver URL '{}'", config.homeserver))?;
+        if homeserver.cannot_be_a_base() {
+            return Err(anyhow!(
+                "Invalid Matrix homeserver URL '{}'",
+                config.homeserver
+            ));
+        }
+        Ok(Self {
+            config,
+            homeserver,
+            client: Client::new(),
+            txn_counter: AtomicU64::new(0),
+        })
+    }
+
+    fn room_for(&self, repository: &str) -> Option<&str> {
+        self.config
+            .rooms
+            .get(repository)
+            .or(self.config.room_id.as_ref())
+            .map(String::as_str)
+    }
+
+    fn send_url(&self, room_id: &str) -> Url {
+        let txn_id = format!(
+            "icicle-{}-{}",
+            chrono::Utc::now().timestamp_millis(),
+            self.txn_counter.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut url = self.homeserver.clone();
+        // Segments are percent-encoded as needed, so room IDs and aliases are safe to pass
+        url.path_segments_mut()
+            .expect("homeserver URL is checked to be a base")
+            .pop_if_empty()
+            .extend([
+                "_matrix",
+                "client",
+                "v3",
+                "rooms",
+                room_id,
+                "send",
+                "m.room.message",
+                &txn_id,
+            ]);
+        url
+    }
+}
+
+/// Plain text and HTML bodies of the message
+fn message_bodies(notification: &Notification) -> (String, String) {
+    let mut body = notification.summary();
+    let mut html = match &notification.workflow_url {
+        Some(url) => format!(
+            "<a href=\"{}\">{}</a>",
+            escape_html(url),
+            escape_html(&notification.summary())
+        ),
+        None => escape_html(&notification.summary()),
+    };
+    html = format!(
+        "<font color=\"#{:06x}\"><b>{}</b></font>",
+        notification.outcome.color(),
+        html
+    );
+    if let Some(url) = &notification.workflow_url {
+        body.push_str(&format!("\n{}", url));
+    }
+
+    let (listed, more) = notification.listed_jobs();
+    if !listed.is_empty() {
+        body.push_str("\nFailed jobs:");
+        html.push_str("<br>Failed jobs:<ul>");
+        for job in listed {
+            body.push_str(&format!("\n- {} ({})", job.name, job.detail()));
+            html.push_str(&format!(
+                "<li><code>{}</code> ({})",
+                escape_html(&job.name),
+                escape_html(&job.detail())
+            ));
+            if let Some(log_url) = &job.log_url {
+                body.push_str(&format!(" {}", log_url));
+                html.push_str(&format!(" <a href=\"{}\">log</a>", escape_html(log_url)));
+            }
+            html.push_str("</li>");
+        }
+        if more > 0 {
+            body.push_str(&format!("\n… and {} more", more));
+            html.push_str(&format!("<li>… and {} more</li>", more));
+        }
+        html.push_str("</ul>");
+    }
+    (body, html)
+}
+
+#[async_trait]
+impl Notifier for MatrixNotifier {
+    fn name(&self) -> &'static str {
+        "matrix"
+    }
+
+    fn accepts(&self, notification: &Notification) -> bool {
+        repository_enabled(&self.config.repositories, &notification.repository)
+            && self.room_for(&notification.repository).is_some()
+    }
+
+    async fn send(&self, notification: &Notification) -> Result<()> {
+        match self.room_for(&notification.repository) {
+            Some(room_id) => self.send_to(notification, room_id).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn send_to(&self, notification: &Notification, room_id: &str) -> Result<()> {
+        let (body, formatted_body) = message_bodies(notification);
+
+        self.client
+            .put(self.send_url(room_id))
+            .bearer_auth(&self.config.access_token)
+            .json(&json!({
+                "msgtype": "m.notice",
+                "body": body,
+                "format": "org.matrix.custom.html",
+                "formatted_body": formatted_body,
+            }))
+            .send()
+            .await
+            .context("Failed to reach Matrix homeserver")?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn send_text(&self, title: &str, body: &str) -> Result<()> {
+        let Some(room_id) = &self.config.room_id else {
+            return Ok(());
+        };
+        self.client
+            .put(self.send_url(room_id))
+            .bearer_auth(&self.config.access_token)
+            .json(&json!({
+                "msgtype": "m.notice",
+                "body": format!("{}\n\n{}", title, body),
+                "format": "org.matrix.custom.html",
+                "formatted_body": format!(
+                    "<b>{}</b><pre>{}</pre>",
+                    escape_html(title),
+                    escape_html(body)
+                ),
+            }))
+            .send()
+            .await
+            .context("Failed to reach Matrix homeserver")?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::failed_notification;
+
+    #[test]
+    fn test_message_bodies() {
+        let (body, html) = message_bodies(&failed_notification());
+
+        let log = |i| format!("https://ci.example.com/builds/hash{:02}-job.drv", i);
+        let listed: String = (1..10)
+            .map(|i| format!("\n- job{:02} (failed) {}", i, log(i)))
+            .collect();
+        assert_eq!(
+            body,
+            format!(
+                "me/repo fix/<b>&`c`_* failed (0123456)\n\
+                 https://ci.example.com/workflows/42\n\
+                 Failed jobs:\n\
+                 - tests.<a>&`b`_* (failed) {}{}\n\
+                 … and 2 more",
+                log(0),
+                listed
+            )
+        );
+
+        let listed: String = (1..10)
+            .map(|i| {
+                format!(
+                    "<li><code>job{:02}</code> (failed) <a href=\"{}\">log</a></li>",
+                    i,
+                    log(i)
+                )
+            })
+            .collect();
+        assert_eq!(
+            html,
+            format!(
+                "<font color=\"#d50200\"><b><a href=\"https://ci.example.com/workflows/42\">\
+                 me/repo fix/&lt;b&gt;&amp;`c`_* failed (0123456)</a></b></font>\
+                 <br>Failed jobs:<ul>\
+                 <li><code>tests.&lt;a&gt;&amp;`b`_*</code> (failed) <a href=\"{}\">log</a></li>\
+                 {}<li>… and 2 more</li></ul>",
+                log(0),
+                listed
+            )
+        );
+    }
+}
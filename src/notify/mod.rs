@@ -0,0 +1,619 @@
+use crate::{
+    build::{BuildStatus, WorkflowStatus},
+    events::{Event, EventBus},
+    hints::Cause,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::{broadcast::error::RecvError, watch};
+use tracing::{error, info, info_span, warn, Instrument};
+
+mod digest;
+mod discord;
+mod email;
+mod gotify;
+mod matrix;
+mod ntfy;
+mod rules;
+mod slack;
+mod telegram;
+
+pub use digest::{format_duration, DigestConfig, DigestScheduler};
+pub use discord::{DiscordConfig, DiscordNotifier};
+pub use email::{EmailConfig, EmailNotifier};
+pub use gotify::{GotifyConfig, GotifyNotifier};
+pub use matrix::{MatrixConfig, MatrixNotifier};
+pub use ntfy::{NtfyConfig, NtfyNotifier};
+pub use rules::{matches_pattern, NotifyRules};
+pub use slack::{SlackConfig, SlackNotifier};
+pub use telegram::{TelegramConfig, TelegramNotifier};
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct NotifyConfig {
+    /// Externally reachable base URL of icicle, used for links in notifications
+    pub public_url: Option<String>,
+    /// Rules for notifiers that don't set their own
+    pub rules: NotifyRules,
+    pub email: Option<EmailConfig>,
+    pub slack: Option<SlackConfig>,
+    pub matrix: Option<MatrixConfig>,
+    pub discord: Option<DiscordConfig>,
+    pub telegram: Option<TelegramConfig>,
+    pub ntfy: Option<NtfyConfig>,
+    pub gotify: Option<GotifyConfig>,
+    /// Weekly summary sent through the configured notifiers
+    pub digest: Option<DigestConfig>,
+}
+
+/// What happened to a workflow, relative to the previous one on the same branch
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// The workflow failed
+    Failure,
+    /// The workflow succeeded after the previous one failed
+    Recovery,
+    /// The workflow succeeded, as did the previous one
+    Success,
+}
+
+impl Outcome {
+    /// RGB color chat integrations use to highlight the outcome
+    pub fn color(self) -> u32 {
+        match self {
+            Outcome::Failure => 0xd50200,
+            Outcome::Recovery | Outcome::Success => 0x2eb886,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FailedJob {
+    pub name: String,
+    pub drv_path: String,
+    pub status: String,
+    /// What the log suggests went wrong
+    pub cause: Option<Cause>,
+    pub log_url: Option<String>,
+}
+
+impl FailedJob {
+    /// The status, followed by the likely cause of the failure when known
+    pub fn detail(&self) -> String {
+        match self.cause {
+            Some(cause) if cause.infrastructure() => {
+                format!("{}: {}, likely infrastructure", self.status, cause.label())
+            }
+            Some(cause) => format!("{}: {}", self.status, cause.label()),
+            None => self.status.clone(),
+        }
+    }
+}
+
+/// Everything a notifier needs to report a finished workflow
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub workflow_id: i64,
+    pub repository: String,
+    pub commit_sha: String,
+    pub branch: Option<String>,
+    pub author_email: Option<String>,
+    pub outcome: Outcome,
+    /// Whether the previous workflow on the branch had a different result
+    pub state_changed: bool,
+    pub failed_jobs: Vec<FailedJob>,
+    pub workflow_url: Option<String>,
+}
+
+impl Notification {
+    /// One line summary, e.g. "owner/repo main failed (abc1234)"
+    pub fn summary(&self) -> String {
+        let verb = match self.outcome {
+            Outcome::Failure => "failed",
+            Outcome::Recovery => "recovered",
+            Outcome::Success => "succeeded",
+        };
+        let short_sha = self.commit_sha.get(..7).unwrap_or(&self.commit_sha);
+        match &self.branch {
+            Some(branch) => format!("{} {} {} ({})", self.repository, branch, verb, short_sha),
+            None => format!("{} {} ({})", self.repository, verb, short_sha),
+        }
+    }
+
+    /// The failed jobs to list, and how many more failed that are left out
+    pub fn listed_jobs(&self) -> (&[FailedJob], usize) {
+        let listed = &self.failed_jobs[..self.failed_jobs.len().min(MAX_LISTED_JOBS)];
+        (listed, self.failed_jobs.len() - listed.len())
+    }
+}
+
+/// Failed jobs listed in a notification, the rest are only counted: long lists
+/// bury the first failures and go over the message limits of chat services
+pub const MAX_LISTED_JOBS: usize = 10;
+
+/// Names of all notifiers, as used for per-repository targets
+pub const NOTIFIERS: &[&str] = &[
+    "email", "slack", "matrix", "discord", "telegram", "ntfy", "gotify",
+];
+
+/// Outcomes for notifiers that report every result
+const ALL_OUTCOMES: &[Outcome] = &[Outcome::Failure, Outcome::Recovery, Outcome::Success];
+/// Outcomes for notifiers that only alert about problems
+const ALERT_OUTCOMES: &[Outcome] = &[Outcome::Failure, Outcome::Recovery];
+
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Outcomes notified about when the rules don't say otherwise
+    fn default_outcomes(&self) -> &'static [Outcome] {
+        ALL_OUTCOMES
+    }
+
+    /// Whether this notifier has somewhere to send the notification to
+    fn accepts(&self, notification: &Notification) -> bool;
+
+    /// Send using the targets from the notifier's own configuration
+    async fn send(&self, notification: &Notification) -> Result<()>;
+
+    /// Send to a single target (address, channel, room, ...) configured for a repository
+    async fn send_to(&self, notification: &Notification, target: &str) -> Result<()>;
+
+    /// Send a free-form plain text message, such as a digest, to the configured targets
+    async fn send_text(&self, title: &str, body: &str) -> Result<()>;
+}
+
+/// Whether a repository passes a notifier's repository list; an empty list allows all
+pub fn repository_enabled(repositories: &[String], repository: &str) -> bool {
+    repositories.is_empty() || repositories.iter().any(|r| r == repository)
+}
+
+/// Escape text for inclusion in HTML message bodies
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A Markdown code span of `text`, fenced by more backticks than it holds in a row
+pub fn markdown_code(text: &str) -> String {
+    let backticks = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(backticks + 1);
+    if backticks == 0 {
+        format!("{}{}{}", fence, text, fence)
+    } else {
+        // Spaces keep backticks at the ends apart from the fence
+        format!("{} {} {}", fence, text, fence)
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct WorkflowRow {
+    repository: String,
+    commit_sha: String,
+    branch: Option<String>,
+    author_email: Option<String>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct TargetRow {
+    notifier: String,
+    target: String,
+    rules: Option<String>,
+}
+
+/// A target configured for a repository in the database
+struct RepositoryTarget {
+    notifier: String,
+    target: String,
+    rules: Option<NotifyRules>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct FailedBuildRow {
+    name: String,
+    drv_path: String,
+    status: String,
+    failure_cause: Option<String>,
+}
+
+fn public_url(config: &NotifyConfig) -> Option<String> {
+    config
+        .public_url
+        .as_ref()
+        .map(|url| url.trim_end_matches('/').to_string())
+}
+
+/// The dashboard page of a workflow, which sends visitors through the login of
+/// private instances where the API would answer 401
+fn workflow_url(public_url: &str, workflow_id: i64) -> String {
+    format!("{}/workflows/{}", public_url, workflow_id)
+}
+
+/// The dashboard page of a build, ending with its log
+fn log_url(public_url: &str, drv_path: &str) -> String {
+    format!(
+        "{}/builds/{}",
+        public_url,
+        drv_path.trim_start_matches("/nix/store/")
+    )
+}
+
+/// The configured notifiers, each with its own rules or the global ones
+fn build_notifiers(config: &NotifyConfig) -> Result<Vec<(Arc<dyn Notifier>, NotifyRules)>> {
+    let mut notifiers: Vec<(Arc<dyn Notifier>, NotifyRules)> = Vec::new();
+    let rules = |own: &Option<NotifyRules>| own.clone().unwrap_or_else(|| config.rules.clone());
+    if let Some(email) = &config.email {
+        notifiers.push((
+            Arc::new(EmailNotifier::new(email.clone())?),
+            rules(&email.rules),
+        ));
+    }
+    if let Some(slack) = &config.slack {
+        notifiers.push((
+            Arc::new(SlackNotifier::new(slack.clone())?),
+            rules(&slack.rules),
+        ));
+    }
+    if let Some(matrix) = &config.matrix {
+        notifiers.push((
+            Arc::new(MatrixNotifier::new(matrix.clone())?),
+            rules(&matrix.rules),
+        ));
+    }
+    if let Some(discord) = &config.discord {
+        notifiers.push((
+            Arc::new(DiscordNotifier::new(discord.clone())),
+            rules(&discord.rules),
+        ));
+    }
+    if let Some(telegram) = &config.telegram {
+        notifiers.push((
+            Arc::new(TelegramNotifier::new(telegram.clone())),
+            rules(&telegram.rules),
+        ));
+    }
+    if let Some(ntfy) = &config.ntfy {
+        notifiers.push((
+            Arc::new(NtfyNotifier::new(ntfy.clone())),
+            rules(&ntfy.rules),
+        ));
+    }
+    if let Some(gotify) = &config.gotify {
+        notifiers.push((
+            Arc::new(GotifyNotifier::new(gotify.clone())),
+            rules(&gotify.rules),
+        ));
+    }
+    Ok(notifiers)
+}
+
+/// Names of the notifiers enabled by `config`, or why one of them is misconfigured
+pub fn configured_notifiers(config: &NotifyConfig) -> Result<Vec<&'static str>> {
+    Ok(build_notifiers(config)?
+        .iter()
+        .map(|(notifier, _)| notifier.name())
+        .collect())
+}
+
+/// Turns workflow completion events into notifications for the configured notifiers
+pub struct NotificationService {
+    db_pool: sqlx::SqlitePool,
+    public_url: Option<String>,
+    notifiers: Vec<(Arc<dyn Notifier>, NotifyRules)>,
+    digest: Option<DigestConfig>,
+}
+
+impl NotificationService {
+    pub fn new(config: &NotifyConfig, db_pool: sqlx::SqlitePool) -> Result<Self> {
+        Ok(Self {
+            db_pool,
+            public_url: public_url(config),
+            notifiers: build_notifiers(config)?,
+            digest: config.digest.clone(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notifiers.is_empty()
+    }
+
+    /// The weekly digest, if one is configured
+    pub fn digest(&self) -> Option<DigestScheduler> {
+        self.digest.clone().map(|config| {
+            DigestScheduler::new(
+                config,
+                self.db_pool.clone(),
+                self.notifiers.iter().map(|(n, _)| n.clone()).collect(),
+            )
+        })
+    }
+
+    fn log_enabled(&self) {
+        if self.notifiers.is_empty() {
+            info!("Notifications disabled");
+            return;
+        }
+        info!(
+            "Notifications enabled: {}",
+            self.notifiers
+                .iter()
+                .map(|(n, _)| n.name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    /// Send notifications for finished workflows, picking up new settings from `config`
+    pub async fn run(mut self, events: EventBus, mut config: watch::Receiver<NotifyConfig>) {
+        self.log_enabled();
+
+        let mut receiver = events.subscribe();
+        loop {
+            let event = tokio::select! {
+                event = receiver.recv() => event,
+                changed = config.changed() => {
+                    if changed.is_ok() {
+                        let config = config.borrow_and_update().clone();
+                        self.reload(&config);
+                    }
+                    continue;
+                }
+            };
+            let (workflow_id, status) = match event {
+                Ok(Event::WorkflowStatus {
+                    workflow_id,
+                    status: status @ (WorkflowStatus::Completed | WorkflowStatus::Failed),
+                }) => (workflow_id, status),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Notifications missed {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            self.handle_workflow(workflow_id, status)
+                .instrument(info_span!("workflow", workflow_id))
+                .await;
+        }
+    }
+
+    /// Replace the notifiers; the current ones are kept if the new config is invalid.
+    /// The digest keeps the notifiers it was started with.
+    fn reload(&mut self, config: &NotifyConfig) {
+        match build_notifiers(config) {
+            Ok(notifiers) => {
+                self.notifiers = notifiers;
+                self.public_url = public_url(config);
+                self.log_enabled();
+            }
+            Err(e) => error!("Keeping previous notification settings: {:#}", e),
+        }
+    }
+
+    async fn handle_workflow(&self, workflow_id: i64, status: WorkflowStatus) {
+        let notification = match self.build_notification(workflow_id, status).await {
+            Ok(notification) => notification,
+            Err(e) => {
+                error!(
+                    "Failed to prepare notification for workflow {}: {}",
+                    workflow_id, e
+                );
+                return;
+            }
+        };
+
+        let targets = match self.repository_targets(&notification.repository).await {
+            Ok(targets) => targets,
+            Err(e) => {
+                error!(
+                    "Failed to load notification targets of {}: {}",
+                    notification.repository, e
+                );
+                Vec::new()
+            }
+        };
+
+        let now = chrono::Local::now().time();
+        for (notifier, rules) in &self.notifiers {
+            let defaults = notifier.default_outcomes();
+            let repository_targets: Vec<&RepositoryTarget> = targets
+                .iter()
+                .filter(|t| t.notifier == notifier.name())
+                .collect();
+
+            // Targets configured for the repository replace the global ones
+            let result = if repository_targets.is_empty() {
+                if !notifier.accepts(&notification) || !rules.allows(&notification, defaults, now) {
+                    continue;
+                }
+                notifier.send(&notification).await
+            } else {
+                let allowed: Vec<&RepositoryTarget> = repository_targets
+                    .into_iter()
+                    .filter(|t| {
+                        t.rules
+                            .as_ref()
+                            .unwrap_or(rules)
+                            .allows(&notification, defaults, now)
+                    })
+                    .collect();
+                if allowed.is_empty() {
+                    continue;
+                }
+                let mut result = Ok(());
+                for target in allowed {
+                    if let Err(e) = notifier.send_to(&notification, &target.target).await {
+                        result = Err(e.context(format!("Target {}", target.target)));
+                    }
+                }
+                result
+            };
+
+            match result {
+                Ok(()) => info!(
+                    "Sent {} notification for workflow {}",
+                    notifier.name(),
+                    workflow_id
+                ),
+                Err(e) => error!(
+                    "Failed to send {} notification for workflow {}: {:#}",
+                    notifier.name(),
+                    workflow_id,
+                    e
+                ),
+            }
+        }
+    }
+
+    async fn repository_targets(&self, repository: &str) -> Result<Vec<RepositoryTarget>> {
+        let rows = sqlx::query_as::<_, TargetRow>(
+            r#"
+            SELECT n.notifier, n.target, n.rules
+            FROM repository_notifications n JOIN repositories r ON r.id = n.repository_id
+            WHERE r.full_name = ?
+            "#,
+        )
+        .bind(repository)
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| RepositoryTarget {
+                rules: row.rules.and_then(|rules| {
+                    serde_json::from_str(&rules)
+                        .map_err(|e| warn!("Ignoring invalid rules of {}: {}", row.target, e))
+                        .ok()
+                }),
+                notifier: row.notifier,
+                target: row.target,
+            })
+            .collect())
+    }
+
+    async fn build_notification(
+        &self,
+        workflow_id: i64,
+        status: WorkflowStatus,
+    ) -> Result<Notification> {
+        let workflow = sqlx::query_as::<_, WorkflowRow>(
+            r#"
+            SELECT repository, commit_sha, branch, author_email
+            FROM workflows WHERE id = ?
+            "#,
+        )
+        .bind(workflow_id)
+        .fetch_one(&self.db_pool)
+        .await?;
+
+        // The previous finished workflow on the same branch decides failure vs. recovery
+        let previous: Option<String> = sqlx::query_scalar(
+            r#"
+            SELECT status FROM workflows
+            WHERE repository = ? AND branch IS ? AND id < ? AND status IN ('Completed', 'Failed')
+            ORDER BY id DESC LIMIT 1
+            "#,
+        )
+        .bind(&workflow.repository)
+        .bind(&workflow.branch)
+        .bind(workflow_id)
+        .fetch_optional(&self.db_pool)
+        .await?;
+
+        let current = match status {
+            WorkflowStatus::Failed => "Failed",
+            _ => "Completed",
+        };
+        let state_changed = previous.as_deref() != Some(current);
+        let outcome = match (status, previous.as_deref()) {
+            (WorkflowStatus::Failed, _) => Outcome::Failure,
+            (_, Some("Failed")) => Outcome::Recovery,
+            _ => Outcome::Success,
+        };
+
+        let failed_jobs = sqlx::query_as::<_, FailedBuildRow>(
+            r#"
+            SELECT b.name, b.drv_path, b.status, b.failure_cause
+            FROM builds b JOIN build_workflows bw ON bw.drv_path = b.drv_path
+            WHERE bw.workflow_id = ? AND b.status IN (?, ?)
+            ORDER BY b.name
+            "#,
+        )
+        .bind(workflow_id)
+        .bind(BuildStatus::Failed.to_string())
+        .bind(BuildStatus::Timedout.to_string())
+        .fetch_all(&self.db_pool)
+        .await?
+        .into_iter()
+        .map(|row| FailedJob {
+            log_url: self
+                .public_url
+                .as_ref()
+                .map(|url| log_url(url, &row.drv_path)),
+            name: row.name,
+            cause: row.failure_cause.as_deref().and_then(Cause::parse),
+            drv_path: row.drv_path,
+            status: row.status,
+        })
+        .collect();
+
+        Ok(Notification {
+            workflow_id,
+            repository: workflow.repository,
+            commit_sha: workflow.commit_sha,
+            branch: workflow.branch,
+            author_email: workflow.author_email,
+            outcome,
+            state_changed,
+            failed_jobs,
+            workflow_url: self
+                .public_url
+                .as_ref()
+                .map(|url| workflow_url(url, workflow_id)),
+        })
+    }
+}
+
+/// A failed workflow with more failed jobs than are listed, whose first job name and
+/// branch need escaping in markup
+#[cfg(test)]
+fn failed_notification() -> Notification {
+    let public_url = public_url(&NotifyConfig {
+        public_url: Some("https://ci.example.com/".to_string()),
+        ..NotifyConfig::default()
+    })
+    .unwrap();
+    let failed_jobs = (0..MAX_LISTED_JOBS + 2)
+        .map(|i| {
+            let name = match i {
+                0 => "tests.<a>&`b`_*".to_string(),
+                i => format!("job{:02}", i),
+            };
+            let drv_path = format!("/nix/store/hash{:02}-job.drv", i);
+            FailedJob {
+                log_url: Some(log_url(&public_url, &drv_path)),
+                name,
+                drv_path,
+                status: BuildStatus::Failed.to_string(),
+                cause: None,
+            }
+        })
+        .collect();
+    Notification {
+        workflow_id: 42,
+        repository: "me/repo".to_string(),
+        commit_sha: "0123456789abcdef".to_string(),
+        branch: Some("fix/<b>&`c`_*".to_string()),
+        author_email: None,
+        outcome: Outcome::Failure,
+        state_changed: true,
+        failed_jobs,
+        workflow_url: Some(workflow_url(&public_url, 42)),
+    }
+}
@@ -0,0 +1,168 @@
+use super::{repository_enabled, Notification, Notifier, NotifyRules, Outcome, ALERT_OUTCOMES};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct NtfyConfig {
+    /// ntfy server, either ntfy.sh or a self-hosted instance
+    #[serde(default = "default_server")]
+    pub server: String,
+    /// Default topic for repositories without an entry in `topics`
+    pub topic: Option<String>,
+    /// Topic per repository full name
+    #[serde(default)]
+    pub topics: HashMap<String, String>,
+    /// Access token for protected topics
+    pub token: Option<String>,
+    /// Repositories to send notifications for; all repositories when empty
+    #[serde(default)]
+    pub repositories: Vec<String>,
+    /// Overrides the global notification rules
+    pub rules: Option<NotifyRules>,
+}
+
+fn default_server() -> String {
+    "https://ntfy.sh".to_string()
+}
+
+pub struct NtfyNotifier {
+    config: NtfyConfig,
+    client: Client,
+}
+
+impl NtfyNotifier {
+    pub fn new(config: NtfyConfig) -> Self {
+        Self {
+            config,
+            client: Client::new(),
+        }
+    }
+
+    fn topic_for(&self, repository: &str) -> Option<&str> {
+        self.config
+            .topics
+            .get(repository)
+            .or(self.config.topic.as_ref())
+            .map(String::as_str)
+    }
+
+    async fn publish(&self, payload: Value) -> Result<()> {
+        // Publishing as JSON to the server root allows any characters in the title
+        let mut request = self
+            .client
+            .post(self.config.server.trim_end_matches('/'))
+            .json(&payload);
+        if let Some(token) = &self.config.token {
+            request = request.bearer_auth(token);
+        }
+        request
+            .send()
+            .await
+            .context("Failed to reach ntfy server")?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// The message published for a notification, in plain text as ntfy shows it
+fn payload(notification: &Notification, topic: &str) -> Value {
+    let (priority, tag) = match notification.outcome {
+        Outcome::Failure => (4, "x"),
+        Outcome::Recovery | Outcome::Success => (3, "white_check_mark"),
+    };
+    let (listed, more) = notification.listed_jobs();
+    let mut names: Vec<String> = listed.iter().map(|job| job.name.clone()).collect();
+    if more > 0 {
+        names.push(format!("… and {} more", more));
+    }
+    let mut message = match notification.failed_jobs.len() {
+        0 => format!("Commit {}", notification.commit_sha),
+        n => format!(
+            "{} failed job{}: {}",
+            n,
+            if n == 1 { "" } else { "s" },
+            names.join(", ")
+        ),
+    };
+    if let Some(branch) = &notification.branch {
+        message = format!("{}\nBranch {}", message, branch);
+    }
+
+    let mut payload = json!({
+        "topic": topic,
+        "title": notification.summary(),
+        "message": message,
+        "priority": priority,
+        "tags": [tag],
+    });
+    if let Some(url) = &notification.workflow_url {
+        payload["click"] = json!(url);
+    }
+    payload
+}
+
+#[async_trait]
+impl Notifier for NtfyNotifier {
+    fn name(&self) -> &'static str {
+        "ntfy"
+    }
+
+    fn default_outcomes(&self) -> &'static [Outcome] {
+        ALERT_OUTCOMES
+    }
+
+    fn accepts(&self, notification: &Notification) -> bool {
+        repository_enabled(&self.config.repositories, &notification.repository)
+            && self.topic_for(&notification.repository).is_some()
+    }
+
+    async fn send(&self, notification: &Notification) -> Result<()> {
+        match self.topic_for(&notification.repository) {
+            Some(topic) => self.send_to(notification, topic).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn send_to(&self, notification: &Notification, topic: &str) -> Result<()> {
+        self.publish(payload(notification, topic)).await
+    }
+
+    async fn send_text(&self, title: &str, body: &str) -> Result<()> {
+        let Some(topic) = &self.config.topic else {
+            return Ok(());
+        };
+        self.publish(json!({
+            "topic": topic,
+            "title": title,
+            "message": body,
+        }))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::failed_notification;
+
+    #[test]
+    fn test_payload() {
+        assert_eq!(
+            payload(&failed_notification(), "ci"),
+            json!({
+                "topic": "ci",
+                "title": "me/repo fix/<b>&`c`_* failed (0123456)",
+                "message": "12 failed jobs: tests.<a>&`b`_*, job01, job02, job03, job04, \
+                            job05, job06, job07, job08, job09, … and 2 more\n\
+                            Branch fix/<b>&`c`_*",
+                "priority": 4,
+                "tags": ["x"],
+                "click": "https://ci.example.com/workflows/42",
+            })
+        );
+    }
+}
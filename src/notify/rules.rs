@@ -0,0 +1,135 @@
+use super::{Notification, Outcome};
+use chrono::NaiveTime;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Filters deciding which notifications a notifier actually sends
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct NotifyRules {
+    /// Outcomes to notify about; each notifier has its own default when unset
+    pub on: Option<Vec<Outcome>>,
+    /// Only notify when the outcome differs from the previous workflow on the branch
+    pub only_state_changes: bool,
+    /// Branch patterns to notify for (`*` matches anything); all branches when empty
+    pub branches: Vec<String>,
+    /// Local time window during which nothing is sent
+    pub quiet_hours: Option<QuietHours>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub struct QuietHours {
+    /// Start of the window, "HH:MM"
+    #[serde(
+        deserialize_with = "deserialize_time",
+        serialize_with = "serialize_time"
+    )]
+    pub start: NaiveTime,
+    /// End of the window, "HH:MM"; may be earlier than `start` to span midnight
+    #[serde(
+        deserialize_with = "deserialize_time",
+        serialize_with = "serialize_time"
+    )]
+    pub end: NaiveTime,
+}
+
+fn serialize_time<S>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_str(&time.format("%H:%M"))
+}
+
+fn deserialize_time<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&value, "%H:%M").map_err(serde::de::Error::custom)
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl NotifyRules {
+    /// Whether a notification passes the rules at the given local time
+    pub fn allows(
+        &self,
+        notification: &Notification,
+        defaults: &[Outcome],
+        now: NaiveTime,
+    ) -> bool {
+        let outcomes = self.on.as_deref().unwrap_or(defaults);
+        if !outcomes.contains(&notification.outcome) {
+            return false;
+        }
+        if self.only_state_changes && !notification.state_changed {
+            return false;
+        }
+        if !self.branches.is_empty() {
+            let Some(branch) = &notification.branch else {
+                return false;
+            };
+            if !self.branches.iter().any(|p| matches_pattern(p, branch)) {
+                return false;
+            }
+        }
+        !self.quiet_hours.is_some_and(|q| q.contains(now))
+    }
+}
+
+/// Match a value against a pattern where `*` stands for any (possibly empty) substring
+pub fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard at all
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("main", "main"));
+        assert!(!matches_pattern("main", "maintenance"));
+        assert!(matches_pattern("release/*", "release/1.0"));
+        assert!(!matches_pattern("release/*", "feature/x"));
+        assert!(matches_pattern("*", "anything"));
+        assert!(matches_pattern("pr-*-fix", "pr-12-fix"));
+        assert!(!matches_pattern("a*a", "a"));
+    }
+
+    #[test]
+    fn test_quiet_hours_across_midnight() {
+        let time = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        let night = QuietHours {
+            start: time(22, 0),
+            end: time(7, 0),
+        };
+        assert!(night.contains(time(23, 30)));
+        assert!(night.contains(time(6, 59)));
+        assert!(!night.contains(time(7, 0)));
+        assert!(!night.contains(time(12, 0)));
+    }
+}
@@ -0,0 +1,243 @@
+use super::{repository_enabled, Notification, Notifier, NotifyRules};
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+const POST_MESSAGE_URL: &str = "https://slack.com/api/chat.postMessage";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SlackConfig {
+    /// Incoming webhook URL; posts to the channel the webhook was created for
+    pub webhook_url: Option<String>,
+    /// Bot token for chat.postMessage, used instead of the webhook when set
+    pub token: Option<String>,
+    /// Default channel for repositories without an entry in `channels`
+    pub channel: Option<String>,
+    /// Channel per repository full name
+    #[serde(default)]
+    pub channels: HashMap<String, String>,
+    /// Repositories to send notifications for; all repositories when empty
+    #[serde(default)]
+    pub repositories: Vec<String>,
+    /// Overrides the global notification rules
+    pub rules: Option<NotifyRules>,
+}
+
+pub struct SlackNotifier {
+    config: SlackConfig,
+    client: Client,
+}
+
+impl SlackNotifier {
+    pub fn new(config: SlackConfig) -> Result<Self> {
+        if config.webhook_url.is_none() && config.token.is_none() {
+            return Err(anyhow!("Slack notifier needs a webhook_url or a token"));
+        }
+        Ok(Self {
+            config,
+            client: Client::new(),
+        })
+    }
+
+    fn channel_for(&self, repository: &str) -> Option<&str> {
+        self.config
+            .channels
+            .get(repository)
+            .or(self.config.channel.as_ref())
+            .map(String::as_str)
+    }
+
+    fn message(&self, notification: &Notification, channel: Option<&str>) -> Value {
+        let mut fields = vec![json!({
+            "title": "Commit",
+            "value": notification.commit_sha.get(..7).unwrap_or(&notification.commit_sha),
+            "short": true,
+        })];
+        if let Some(branch) = &notification.branch {
+            fields.push(json!({ "title": "Branch", "value": escape(branch), "short": true }));
+        }
+
+        let (listed, more) = notification.listed_jobs();
+        let mut failed_jobs: Vec<String> = listed
+            .iter()
+            .map(|job| match &job.log_url {
+                Some(url) => format!("• {} ({}, <{}|log>)", escape(&job.name), job.detail(), url),
+                None => format!("• {} ({})", escape(&job.name), job.detail()),
+            })
+            .collect();
+        if more > 0 {
+            failed_jobs.push(format!("… and {} more", more));
+        }
+
+        let mut attachment = json!({
+            "color": format!("#{:06x}", notification.outcome.color()),
+            "fallback": notification.summary(),
+            "title": escape(&notification.summary()),
+            "fields": fields,
+        });
+        if let Some(url) = &notification.workflow_url {
+            attachment["title_link"] = json!(url);
+        }
+        if !failed_jobs.is_empty() {
+            attachment["text"] = json!(format!("Failed jobs:\n{}", failed_jobs.join("\n")));
+        }
+
+        let mut message = json!({
+            "text": escape(&notification.summary()),
+            "attachments": [attachment],
+        });
+        if let Some(channel) = channel {
+            message["channel"] = json!(channel);
+        }
+        message
+    }
+
+    async fn post_message(&self, token: &str, message: &Value) -> Result<()> {
+        let response: Value = self
+            .client
+            .post(POST_MESSAGE_URL)
+            .bearer_auth(token)
+            .json(message)
+            .send()
+            .await
+            .context("Failed to reach Slack")?
+            .error_for_status()?
+            .json()
+            .await?;
+        // The Web API reports errors in the body with a 200 status
+        if response["ok"].as_bool() != Some(true) {
+            return Err(anyhow!(
+                "Slack API error: {}",
+                response["error"].as_str().unwrap_or("unknown")
+            ));
+        }
+        Ok(())
+    }
+
+    async fn post_webhook(&self, webhook_url: &str, message: &Value) -> Result<()> {
+        self.client
+            .post(webhook_url)
+            .json(message)
+            .send()
+            .await
+            .context("Failed to reach Slack")?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Escape the characters Slack treats as markup
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[async_trait]
+impl Notifier for SlackNotifier {
+    fn name(&self) -> &'static str {
+        "slack"
+    }
+
+    fn accepts(&self, notification: &Notification) -> bool {
+        // The Web API needs to know where to post, webhooks have a fixed channel
+        repository_enabled(&self.config.repositories, &notification.repository)
+            && (self.config.token.is_none() || self.channel_for(&notification.repository).is_some())
+    }
+
+    async fn send(&self, notification: &Notification) -> Result<()> {
+        let channel = self.channel_for(&notification.repository);
+        let message = self.message(notification, channel);
+        match (&self.config.token, &self.config.webhook_url) {
+            (Some(token), _) => self.post_message(token, &message).await,
+            (None, Some(webhook_url)) => self.post_webhook(webhook_url, &message).await,
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// Targets are either incoming webhook URLs or channels for the bot token
+    async fn send_to(&self, notification: &Notification, target: &str) -> Result<()> {
+        if target.starts_with("https://") {
+            return self
+                .post_webhook(target, &self.message(notification, None))
+                .await;
+        }
+        let token = self
+            .config
+            .token
+            .as_ref()
+            .ok_or_else(|| anyhow!("Posting to channel {} needs a Slack token", target))?;
+        self.post_message(token, &self.message(notification, Some(target)))
+            .await
+    }
+
+    async fn send_text(&self, title: &str, body: &str) -> Result<()> {
+        let mut message = json!({
+            "text": format!("*{}*\n```{}```", escape(title), escape(body)),
+        });
+        if let Some(channel) = &self.config.channel {
+            message["channel"] = json!(channel);
+        }
+        match (&self.config.token, &self.config.webhook_url) {
+            (Some(token), _) => self.post_message(token, &message).await,
+            (None, Some(webhook_url)) => self.post_webhook(webhook_url, &message).await,
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::failed_notification;
+
+    #[test]
+    fn test_message() {
+        let notifier = SlackNotifier::new(SlackConfig {
+            webhook_url: Some("https://hooks.slack.com/services/x".to_string()),
+            token: None,
+            channel: None,
+            channels: HashMap::new(),
+            repositories: Vec::new(),
+            rules: None,
+        })
+        .unwrap();
+        let message = notifier.message(&failed_notification(), Some("#ci"));
+
+        let summary = "me/repo fix/&lt;b&gt;&amp;`c`_* failed (0123456)";
+        let listed: String = (1..10)
+            .map(|i| {
+                format!(
+                    "\n• job{:02} (failed, <https://ci.example.com/builds/hash{:02}-job.drv|log>)",
+                    i, i
+                )
+            })
+            .collect();
+        assert_eq!(
+            message,
+            json!({
+                "text": summary,
+                "channel": "#ci",
+                "attachments": [{
+                    "color": "#d50200",
+                    "fallback": "me/repo fix/<b>&`c`_* failed (0123456)",
+                    "title": summary,
+                    "title_link": "https://ci.example.com/workflows/42",
+                    "fields": [
+                        { "title": "Commit", "value": "0123456", "short": true },
+                        { "title": "Branch", "value": "fix/&lt;b&gt;&amp;`c`_*", "short": true },
+                    ],
+                    "text": format!(
+                        "Failed jobs:\n• tests.&lt;a&gt;&amp;`b`_* (failed, \
+                         <https://ci.example.com/builds/hash00-job.drv|log>){}\n\
+                         … and 2 more",
+                        listed
+                    ),
+                }],
+            })
+        );
+    }
+}
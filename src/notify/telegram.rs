@@ -0,0 +1,194 @@
+use super::{
+    escape_html, repository_enabled, Notification, Notifier, NotifyRules, Outcome, ALERT_OUTCOMES,
+};
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// A numeric chat ID or an "@channelusername"
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum ChatId {
+    Id(i64),
+    Username(String),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TelegramConfig {
+    pub bot_token: String,
+    /// Chats notified for repositories without an entry in `chats`
+    #[serde(default)]
+    pub chat_ids: Vec<ChatId>,
+    /// Chats per repository full name
+    #[serde(default)]
+    pub chats: HashMap<String, Vec<ChatId>>,
+    /// Repositories to send notifications for; all repositories when empty
+    #[serde(default)]
+    pub repositories: Vec<String>,
+    /// Overrides the global notification rules
+    pub rules: Option<NotifyRules>,
+}
+
+pub struct TelegramNotifier {
+    config: TelegramConfig,
+    client: Client,
+}
+
+impl TelegramNotifier {
+    pub fn new(config: TelegramConfig) -> Self {
+        Self {
+            config,
+            client: Client::new(),
+        }
+    }
+
+    fn chats_for(&self, repository: &str) -> &[ChatId] {
+        self.config
+            .chats
+            .get(repository)
+            .unwrap_or(&self.config.chat_ids)
+    }
+
+    async fn send_message(&self, chat_id: &ChatId, notification: &Notification) -> Result<()> {
+        self.send_html(chat_id, &message_text(notification)).await
+    }
+
+    async fn send_html(&self, chat_id: &ChatId, text: &str) -> Result<()> {
+        let url = format!(
+            "https://api.telegram.org/bot{}/sendMessage",
+            self.config.bot_token
+        );
+        let response: Value = self
+            .client
+            .post(&url)
+            .json(&json!({
+                "chat_id": chat_id,
+                "text": text,
+                "parse_mode": "HTML",
+                "disable_web_page_preview": true,
+            }))
+            .send()
+            .await
+            .context("Failed to reach Telegram")?
+            .json()
+            .await?;
+        if response["ok"].as_bool() != Some(true) {
+            return Err(anyhow!(
+                "Telegram API error for chat {:?}: {}",
+                chat_id,
+                response["description"].as_str().unwrap_or("unknown")
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn message_text(notification: &Notification) -> String {
+    let icon = match notification.outcome {
+        Outcome::Failure => "❌",
+        Outcome::Recovery | Outcome::Success => "✅",
+    };
+    let summary = escape_html(&notification.summary());
+    let mut text = match &notification.workflow_url {
+        Some(url) => format!("{} <a href=\"{}\">{}</a>", icon, escape_html(url), summary),
+        None => format!("{} <b>{}</b>", icon, summary),
+    };
+
+    let (listed, more) = notification.listed_jobs();
+    if !listed.is_empty() {
+        text.push_str("\n\nFailed jobs:");
+        for job in listed {
+            text.push_str(&format!(
+                "\n• <code>{}</code> ({})",
+                escape_html(&job.name),
+                escape_html(&job.detail())
+            ));
+            if let Some(log_url) = &job.log_url {
+                text.push_str(&format!(" <a href=\"{}\">log</a>", escape_html(log_url)));
+            }
+        }
+        if more > 0 {
+            text.push_str(&format!("\n… and {} more", more));
+        }
+    }
+    text
+}
+
+#[async_trait]
+impl Notifier for TelegramNotifier {
+    fn name(&self) -> &'static str {
+        "telegram"
+    }
+
+    fn default_outcomes(&self) -> &'static [Outcome] {
+        ALERT_OUTCOMES
+    }
+
+    fn accepts(&self, notification: &Notification) -> bool {
+        repository_enabled(&self.config.repositories, &notification.repository)
+            && !self.chats_for(&notification.repository).is_empty()
+    }
+
+    async fn send(&self, notification: &Notification) -> Result<()> {
+        for chat_id in self.chats_for(&notification.repository) {
+            self.send_message(chat_id, notification).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_to(&self, notification: &Notification, target: &str) -> Result<()> {
+        let chat_id = match target.parse() {
+            Ok(id) => ChatId::Id(id),
+            Err(_) => ChatId::Username(target.to_string()),
+        };
+        self.send_message(&chat_id, notification).await
+    }
+
+    async fn send_text(&self, title: &str, body: &str) -> Result<()> {
+        let text = format!(
+            "<b>{}</b>\n<pre>{}</pre>",
+            escape_html(title),
+            escape_html(body)
+        );
+        for chat_id in &self.config.chat_ids {
+            self.send_html(chat_id, &text).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::failed_notification;
+
+    #[test]
+    fn test_message_text() {
+        let log = |i| format!("https://ci.example.com/builds/hash{:02}-job.drv", i);
+        let listed: String = (1..10)
+            .map(|i| {
+                format!(
+                    "\n• <code>job{:02}</code> (failed) <a href=\"{}\">log</a>",
+                    i,
+                    log(i)
+                )
+            })
+            .collect();
+        assert_eq!(
+            message_text(&failed_notification()),
+            format!(
+                "❌ <a href=\"https://ci.example.com/workflows/42\">\
+                 me/repo fix/&lt;b&gt;&amp;`c`_* failed (0123456)</a>\n\
+                 \n\
+                 Failed jobs:\n\
+                 • <code>tests.&lt;a&gt;&amp;`b`_*</code> (failed) <a href=\"{}\">log</a>{}\n\
+                 … and 2 more",
+                log(0),
+                listed
+            )
+        );
+    }
+}
@@ -0,0 +1,770 @@
+use crate::{
+    build::{BuildJob, BuildQueue, BuildStatus, Derivation, JobGraph, JobNode, WorkflowStatus},
+    events::{Event, EventBus},
+    nix::Evaluation,
+};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::Arc,
+};
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+/// Per-repository configuration, read from the root of the evaluated commit
+const REPO_CONFIG: &str = ".icicle.toml";
+/// Name of the only stage of repositories that define none
+const DEFAULT_STAGE: &str = "build";
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct StageConfig {
+    pub name: String,
+    /// Flake attribute set evaluated and built by this stage, or several separated by commas
+    pub attribute_set: String,
+    /// Wait for someone to approve the stage before starting it
+    #[serde(default)]
+    pub approval: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RepoConfig {
+    #[serde(default)]
+    stages: Vec<StageConfig>,
+}
+
+/// Parse the stages of a repository configuration, falling back to a single
+/// stage building `default_attr_set`
+pub fn parse_stages(contents: &str, default_attr_set: &str) -> Result<Vec<StageConfig>> {
+    let config: RepoConfig = config::Config::builder()
+        .add_source(config::File::from_str(contents, config::FileFormat::Toml))
+        .build()?
+        .try_deserialize()?;
+    if config.stages.is_empty() {
+        return Ok(vec![StageConfig {
+            name: DEFAULT_STAGE.to_string(),
+            attribute_set: default_attr_set.to_string(),
+            approval: false,
+        }]);
+    }
+    let mut names = HashSet::new();
+    for stage in &config.stages {
+        if stage.name.is_empty() || crate::nix::attribute_sets(&stage.attribute_set).is_empty() {
+            return Err(anyhow!("Stages need a name and an attribute_set"));
+        }
+        if !names.insert(stage.name.as_str()) {
+            return Err(anyhow!("Stage {} is defined twice", stage.name));
+        }
+    }
+    Ok(config.stages)
+}
+
+/// Read the stages defined by a cloned repository
+pub async fn load_stages(repo_path: &Path, default_attr_set: &str) -> Result<Vec<StageConfig>> {
+    let contents = match tokio::fs::read_to_string(repo_path.join(REPO_CONFIG)).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).context("Failed to read .icicle.toml"),
+    };
+    parse_stages(&contents, default_attr_set).context("Invalid .icicle.toml")
+}
+
+/// A stage of a workflow as stored in the database
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Stage {
+    pub position: i64,
+    pub name: String,
+    pub attribute_set: String,
+    pub approval: bool,
+    pub status: String,
+    pub approved_by: Option<String>,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+}
+
+/// What a workflow does once the jobs of its running stage are done
+#[derive(Debug)]
+enum Next {
+    /// Every stage ran, or one failed
+    Finished,
+    /// A stage was started and needs evaluating
+    Start,
+    /// The next stage waits for approval
+    AwaitApproval,
+}
+
+/// The stages of a workflow, in order
+pub async fn list(db_pool: &SqlitePool, workflow_id: i64) -> Result<Vec<Stage>, sqlx::Error> {
+    sqlx::query_as::<_, Stage>(
+        r#"
+        SELECT position, name, attribute_set, approval, status, approved_by,
+               started_at, finished_at
+        FROM workflow_stages WHERE workflow_id = ?
+        ORDER BY position
+        "#,
+    )
+    .bind(workflow_id)
+    .fetch_all(db_pool)
+    .await
+}
+
+/// The stage whose jobs a workflow is currently building
+pub async fn running_stage(db_pool: &SqlitePool, workflow_id: i64) -> Result<Option<Stage>> {
+    Ok(list(db_pool, workflow_id)
+        .await?
+        .into_iter()
+        .find(|stage| stage.status == "running"))
+}
+
+/// Skip the stages that did not run yet as the workflow ended early,
+/// canceling the running one
+pub async fn skip_remaining(db_pool: &SqlitePool, workflow_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        UPDATE workflow_stages
+        SET status = CASE status WHEN 'running' THEN 'canceled' ELSE 'skipped' END
+        WHERE workflow_id = ? AND status IN ('pending', 'waiting_approval', 'running')
+        "#,
+    )
+    .bind(workflow_id)
+    .execute(db_pool)
+    .await?;
+    Ok(())
+}
+
+/// Moves workflows from one stage to the next once the build queue is done with a stage
+#[derive(Clone)]
+pub struct Pipeline {
+    build_queue: Arc<BuildQueue>,
+    db_pool: SqlitePool,
+    events: EventBus,
+    /// Workflows whose running stage needs evaluating
+    starts: mpsc::UnboundedSender<i64>,
+}
+
+impl Pipeline {
+    pub fn new(
+        build_queue: Arc<BuildQueue>,
+        db_pool: SqlitePool,
+        events: EventBus,
+    ) -> (Self, mpsc::UnboundedReceiver<i64>) {
+        let (starts, receiver) = mpsc::unbounded_channel();
+        let pipeline = Self {
+            build_queue,
+            db_pool,
+            events,
+            starts,
+        };
+        (pipeline, receiver)
+    }
+
+    /// Store the stages of a new workflow and start the first one.
+    /// Returns whether it can be evaluated right away rather than awaiting approval.
+    pub async fn begin(&self, workflow_id: i64, stages: &[StageConfig]) -> Result<bool> {
+        let mut tx = self.db_pool.begin().await?;
+        for (position, stage) in stages.iter().enumerate() {
+            sqlx::query(
+                r#"
+                INSERT INTO workflow_stages
+                    (workflow_id, position, name, attribute_set, approval, status)
+                VALUES (?, ?, ?, ?, ?, 'pending')
+                "#,
+            )
+            .bind(workflow_id)
+            .bind(position as i64)
+            .bind(&stage.name)
+            .bind(&stage.attribute_set)
+            .bind(stage.approval)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(match self.next_stage(workflow_id).await? {
+            Next::Start => true,
+            Next::AwaitApproval | Next::Finished => false,
+        })
+    }
+
+    /// Start the first pending stage, unless it needs approval first
+    async fn next_stage(&self, workflow_id: i64) -> Result<Next> {
+        let Some(stage) = list(&self.db_pool, workflow_id)
+            .await?
+            .into_iter()
+            .find(|stage| stage.status == "pending")
+        else {
+            return Ok(Next::Finished);
+        };
+        let status = if stage.approval {
+            "waiting_approval"
+        } else {
+            "running"
+        };
+        let started_at = (!stage.approval).then(|| chrono::Utc::now().timestamp());
+        sqlx::query(
+            r#"
+            UPDATE workflow_stages SET status = ?, started_at = ?
+            WHERE workflow_id = ? AND position = ?
+            "#,
+        )
+        .bind(status)
+        .bind(started_at)
+        .bind(workflow_id)
+        .bind(stage.position)
+        .execute(&self.db_pool)
+        .await?;
+        self.stage_status(workflow_id, &stage.name, status);
+
+        Ok(if stage.approval {
+            info!(
+                "Workflow {} stage {} awaits approval",
+                workflow_id, stage.name
+            );
+            Next::AwaitApproval
+        } else {
+            info!("Workflow {} stage {} started", workflow_id, stage.name);
+            Next::Start
+        })
+    }
+
+    /// Finish the running stage and move on to the next one
+    async fn advance(&self, workflow_id: i64, succeeded: bool) -> Result<Next> {
+        let status = if succeeded { "succeeded" } else { "failed" };
+        if let Some(stage) = running_stage(&self.db_pool, workflow_id).await? {
+            sqlx::query(
+                r#"
+                UPDATE workflow_stages SET status = ?, finished_at = ?
+                WHERE workflow_id = ? AND position = ?
+                "#,
+            )
+            .bind(status)
+            .bind(chrono::Utc::now().timestamp())
+            .bind(workflow_id)
+            .bind(stage.position)
+            .execute(&self.db_pool)
+            .await?;
+            self.stage_status(workflow_id, &stage.name, status);
+        }
+
+        if !succeeded {
+            skip_remaining(&self.db_pool, workflow_id).await?;
+            return Ok(Next::Finished);
+        }
+        self.next_stage(workflow_id).await
+    }
+
+    /// Start the stage awaiting approval of a running workflow.
+    /// Returns the approved stage, if any was waiting.
+    pub async fn approve(&self, workflow_id: i64, approved_by: &str) -> Result<Option<Stage>> {
+        let Some(mut stage) = list(&self.db_pool, workflow_id)
+            .await?
+            .into_iter()
+            .find(|stage| stage.status == "waiting_approval")
+        else {
+            return Ok(None);
+        };
+        let started_at = chrono::Utc::now().timestamp();
+        let result = sqlx::query(
+            r#"
+            UPDATE workflow_stages SET status = 'running', approved_by = ?, started_at = ?
+            WHERE workflow_id = ? AND position = ? AND status = 'waiting_approval'
+              AND (SELECT status FROM workflows WHERE id = ?) = 'Running'
+            "#,
+        )
+        .bind(approved_by)
+        .bind(started_at)
+        .bind(workflow_id)
+        .bind(stage.position)
+        .bind(workflow_id)
+        .execute(&self.db_pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        info!(
+            "Workflow {} stage {} approved by {}",
+            workflow_id, stage.name, approved_by
+        );
+        stage.status = "running".to_string();
+        stage.approved_by = Some(approved_by.to_string());
+        stage.started_at = Some(started_at);
+        self.stage_status(workflow_id, &stage.name, "running");
+        let _ = self.starts.send(workflow_id);
+        Ok(Some(stage))
+    }
+
+    /// Queue the jobs of a workflow's running stage, storing them so they survive a restart,
+    /// along with the attributes that failed to evaluate and the graph of the jobs.
+    /// Returns true if they are all done already.
+    pub async fn queue(
+        &self,
+        workflow_id: i64,
+        stage: &str,
+        evaluation: Evaluation,
+    ) -> Result<bool> {
+        let Evaluation {
+            derivations,
+            errors,
+        } = evaluation;
+        let now = chrono::Utc::now().timestamp();
+        let mut tx = self.db_pool.begin().await?;
+        for error in &errors {
+            sqlx::query(
+                r#"
+                INSERT OR REPLACE INTO evaluation_errors (workflow_id, stage, attr, error, created_at)
+                VALUES (?, ?, ?, ?, ?)
+                "#,
+            )
+            .bind(workflow_id)
+            .bind(stage)
+            .bind(&error.attr)
+            .bind(&error.error)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        }
+        for derivation in &derivations {
+            sqlx::query(
+                "INSERT OR REPLACE INTO queued_jobs (workflow_id, drv_path, derivation) VALUES (?, ?, ?)",
+            )
+            .bind(workflow_id)
+            .bind(&derivation.drv_path)
+            .bind(serde_json::to_string(derivation)?)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query("INSERT OR IGNORE INTO jobs (drv_path, name, system) VALUES (?, ?, ?)")
+                .bind(&derivation.drv_path)
+                .bind(&derivation.name)
+                .bind(&derivation.system)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(
+                "INSERT OR IGNORE INTO workflow_jobs (workflow_id, drv_path, stage) VALUES (?, ?, ?)",
+            )
+            .bind(workflow_id)
+            .bind(&derivation.drv_path)
+            .bind(stage)
+            .execute(&mut *tx)
+            .await?;
+            for input in &derivation.input_drvs {
+                sqlx::query("INSERT OR IGNORE INTO job_edges (drv_path, input_drv) VALUES (?, ?)")
+                    .bind(&derivation.drv_path)
+                    .bind(input)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            for constituent in derivation.constituents.iter().flatten() {
+                sqlx::query(
+                    "INSERT OR IGNORE INTO aggregate_constituents (aggregate, constituent) VALUES (?, ?)",
+                )
+                .bind(&derivation.drv_path)
+                .bind(constituent)
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(self.build_queue.add_workflow(derivations, workflow_id))
+    }
+
+    /// Fail a workflow that could not get to building, e.g. as its evaluation timed
+    /// out, recording why
+    pub async fn fail(&self, workflow_id: i64, error: &str) -> Result<()> {
+        let result = sqlx::query(
+            r#"
+            UPDATE workflows SET status = 'Failed', error = ?, finished_at = ?
+            WHERE id = ? AND status IN ('Pending', 'Running')
+            "#,
+        )
+        .bind(error)
+        .bind(chrono::Utc::now().timestamp())
+        .bind(workflow_id)
+        .execute(&self.db_pool)
+        .await?;
+        // Canceled meanwhile
+        if result.rows_affected() == 0 {
+            return Ok(());
+        }
+        self.advance(workflow_id, false).await?;
+        self.events.publish(Event::WorkflowStatus {
+            workflow_id,
+            status: WorkflowStatus::Failed,
+        });
+        info!("Workflow {} Failed: {}", workflow_id, error);
+        self.clear(workflow_id).await;
+        Ok(())
+    }
+
+    /// Remove a workflow's jobs from the queue and from the database
+    async fn clear(&self, workflow_id: i64) {
+        self.build_queue.clear_workflow(workflow_id);
+        if let Err(e) = sqlx::query("DELETE FROM queued_jobs WHERE workflow_id = ?")
+            .bind(workflow_id)
+            .execute(&self.db_pool)
+            .await
+        {
+            warn!(
+                "Failed to remove queued jobs of workflow {}: {}",
+                workflow_id, e
+            );
+        }
+    }
+
+    /// The job graph of a workflow: from the build queue while its jobs are queued,
+    /// otherwise as stored when its stages were queued, with the status of their builds.
+    /// Jobs that never finished a build are shown as canceled.
+    pub async fn graph(&self, workflow_id: i64) -> Result<JobGraph, sqlx::Error> {
+        let graph = self.build_queue.workflow_graph(workflow_id);
+        if !graph.nodes.is_empty() {
+            return Ok(graph);
+        }
+
+        let nodes = sqlx::query_as::<_, (String, String, String, Option<String>)>(
+            r#"
+            SELECT j.drv_path, j.name, j.system, b.status
+            FROM workflow_jobs wj
+            JOIN jobs j ON j.drv_path = wj.drv_path
+            LEFT JOIN builds b ON b.drv_path = wj.drv_path
+            WHERE wj.workflow_id = ?
+            "#,
+        )
+        .bind(workflow_id)
+        .fetch_all(&self.db_pool)
+        .await?
+        .into_iter()
+        .map(|(drv_path, name, system, status)| JobNode {
+            drv_path,
+            name,
+            system,
+            status: status
+                .and_then(|status| status.parse().ok())
+                .filter(|status: &BuildStatus| status.done())
+                .unwrap_or(BuildStatus::Canceled),
+        })
+        .collect();
+        let inputs: Vec<(String, String)> = sqlx::query_as(
+            r#"
+            SELECT e.drv_path, e.input_drv
+            FROM workflow_jobs wj JOIN job_edges e ON e.drv_path = wj.drv_path
+            WHERE wj.workflow_id = ?
+            "#,
+        )
+        .bind(workflow_id)
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(JobGraph::stored(nodes, &inputs))
+    }
+
+    /// Rebuild the build queue after a restart from the jobs of running workflows.
+    /// Running stages whose jobs were not queued yet are evaluated again.
+    pub async fn restore(&self) -> Result<()> {
+        // Jobs of workflows that finished or were canceled are of no use anymore
+        sqlx::query(
+            r#"
+            DELETE FROM queued_jobs WHERE workflow_id NOT IN
+                (SELECT id FROM workflows WHERE status = 'Running')
+            "#,
+        )
+        .execute(&self.db_pool)
+        .await?;
+
+        let workflows: Vec<(i64, String, bool, i64)> = sqlx::query_as(
+            r#"
+            SELECT id, repository, deprioritized, priority FROM workflows w
+            WHERE status = 'Running' AND EXISTS (
+                SELECT 1 FROM workflow_stages s
+                WHERE s.workflow_id = w.id AND s.status = 'running'
+            )
+            ORDER BY id
+            "#,
+        )
+        .fetch_all(&self.db_pool)
+        .await?;
+        for (workflow_id, repository, deprioritized, priority) in workflows {
+            let derivations: Vec<Derivation> = sqlx::query_scalar::<_, String>(
+                "SELECT derivation FROM queued_jobs WHERE workflow_id = ?",
+            )
+            .bind(workflow_id)
+            .fetch_all(&self.db_pool)
+            .await?
+            .iter()
+            .map(|json| serde_json::from_str(json))
+            .collect::<Result<_, _>>()?;
+            if derivations.is_empty() {
+                info!("Evaluating workflow {} stage again", workflow_id);
+                let _ = self.starts.send(workflow_id);
+                continue;
+            }
+
+            // Builds of this workflow that finished; those still running are built again
+            let finished: HashMap<String, BuildStatus> = sqlx::query_as::<_, (String, String)>(
+                r#"
+                SELECT b.drv_path, b.status
+                FROM builds b JOIN build_workflows bw ON bw.drv_path = b.drv_path
+                WHERE bw.workflow_id = ?
+                "#,
+            )
+            .bind(workflow_id)
+            .fetch_all(&self.db_pool)
+            .await?
+            .into_iter()
+            .filter_map(|(drv_path, status)| Some((drv_path, status.parse::<BuildStatus>().ok()?)))
+            .filter(|(_, status)| status.done())
+            .collect();
+
+            if deprioritized {
+                self.build_queue.deprioritize(workflow_id);
+            }
+            self.build_queue.set_priority(workflow_id, priority);
+            self.build_queue.set_repository(workflow_id, &repository);
+            info!(
+                "Restoring {} jobs of workflow {} ({} finished)",
+                derivations.len(),
+                workflow_id,
+                finished.len()
+            );
+            let completed = self
+                .build_queue
+                .restore_workflow(derivations, workflow_id, &finished);
+            for workflow_id in completed {
+                self.jobs_finished(workflow_id).await;
+            }
+        }
+        Ok(())
+    }
+
+    fn stage_status(&self, workflow_id: i64, stage: &str, status: &str) {
+        self.events.publish(Event::StageStatus {
+            workflow_id,
+            stage: stage.to_string(),
+            status: status.to_string(),
+        });
+    }
+
+    /// Handle the jobs of a workflow's stage being done: start the next stage,
+    /// or update DB, log summary and clear queue
+    pub async fn jobs_finished(&self, workflow_id: i64) {
+        info!(
+            "Workflow {} jobs completed, generating summary",
+            workflow_id
+        );
+
+        // Get all jobs for this workflow
+        let jobs = self.build_queue.get_workflow_jobs(workflow_id);
+
+        if jobs.is_empty() {
+            warn!("Workflow {} has no jobs", workflow_id);
+        }
+
+        // Calculate summary statistics
+        let total = jobs.len();
+        let success = jobs
+            .iter()
+            .filter(|j| j.status == BuildStatus::Success)
+            .count();
+        let failed = jobs
+            .iter()
+            .filter(|j| j.status == BuildStatus::Failed)
+            .count();
+        let cached = jobs
+            .iter()
+            .filter(|j| j.status == BuildStatus::Cached)
+            .count();
+        let timedout = jobs
+            .iter()
+            .filter(|j| j.status == BuildStatus::Timedout)
+            .count();
+        let canceled = jobs
+            .iter()
+            .filter(|j| j.status == BuildStatus::Canceled)
+            .count();
+        // Attributes of the running stage that did not evaluate count as failed jobs
+        let eval_errors: i64 = sqlx::query_scalar(
+            r#"
+            SELECT COUNT(*) FROM evaluation_errors e
+            JOIN workflow_stages s ON s.workflow_id = e.workflow_id AND s.name = e.stage
+            WHERE e.workflow_id = ? AND s.status = 'running'
+            "#,
+        )
+        .bind(workflow_id)
+        .fetch_one(&self.db_pool)
+        .await
+        .unwrap_or_else(|e| {
+            error!(
+                "Failed to count evaluation errors of workflow {}: {}",
+                workflow_id, e
+            );
+            0
+        });
+        let has_errors = stage_failed(&jobs, eval_errors as usize);
+
+        info!(
+            "Workflow {} stage: {} total jobs ({} success, {} cached, {} failed, {} timedout, {} canceled), {} evaluation errors",
+            workflow_id, total, success, cached, failed, timedout, canceled, eval_errors
+        );
+
+        // Later stages queue their own jobs, which would otherwise count towards this one
+        match self.advance(workflow_id, !has_errors).await {
+            Ok(Next::Finished) => {}
+            Ok(next) => {
+                self.clear(workflow_id).await;
+                if let Next::Start = next {
+                    let _ = self.starts.send(workflow_id);
+                }
+                return;
+            }
+            Err(e) => warn!("Failed to advance workflow {} stages: {}", workflow_id, e),
+        }
+
+        // Determine final workflow status
+        let final_status = if has_errors { "Failed" } else { "Completed" };
+        self.events.publish(Event::WorkflowStatus {
+            workflow_id,
+            status: if has_errors {
+                WorkflowStatus::Failed
+            } else {
+                WorkflowStatus::Completed
+            },
+        });
+        info!("Workflow {} {}", workflow_id, final_status);
+
+        // Update workflow status in database
+        if let Err(e) = sqlx::query(
+            r#"
+            UPDATE workflows
+            SET status = ?, finished_at = ?
+            WHERE id = ?
+            "#,
+        )
+        .bind(final_status)
+        .bind(chrono::Utc::now().timestamp())
+        .bind(workflow_id)
+        .execute(&self.db_pool)
+        .await
+        {
+            error!(
+                "Failed to update workflow {} status in database: {}",
+                workflow_id, e
+            );
+        }
+
+        // Clear workflow from queue (jobs are persisted in DB)
+        self.clear(workflow_id).await;
+        info!("Workflow {} cleared from queue", workflow_id);
+    }
+}
+
+/// Whether a stage's jobs, and the attributes of it that did not evaluate, failed it:
+/// with aggregate jobs, only theirs decide, so jobs outside every aggregate may fail
+/// without failing the workflow
+fn stage_failed(jobs: &[BuildJob], eval_errors: usize) -> bool {
+    let aggregates: Vec<&BuildJob> = jobs
+        .iter()
+        .filter(|j| j.derivation.constituents.is_some())
+        .collect();
+    if aggregates.is_empty() {
+        eval_errors > 0 || jobs.iter().any(|j| j.status.error())
+    } else {
+        aggregates.iter().any(|j| j.status.error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(name: &str, status: BuildStatus, constituents: Option<Vec<String>>) -> BuildJob {
+        BuildJob {
+            derivation: Derivation {
+                name: name.to_string(),
+                drv_path: format!("/nix/store/{}.drv", name),
+                outputs: vec![format!("/nix/store/{}", name)],
+                system: "x86_64-linux".to_string(),
+                input_drvs: Vec::new(),
+                status,
+                licenses: Vec::new(),
+                timeout_secs: None,
+                required_features: Vec::new(),
+                constituents,
+                cached: None,
+            },
+            status,
+            requested_by: HashSet::new(),
+            priority: 0,
+            progress: None,
+            retries: 0,
+            ready_at: None,
+            started_at: None,
+        }
+    }
+
+    #[test]
+    fn test_stage_failed() {
+        let broken = job("broken", BuildStatus::Failed, None);
+        let lib = job("lib", BuildStatus::Success, None);
+        assert!(stage_failed(&[broken.clone(), lib.clone()], 0));
+        assert!(stage_failed(std::slice::from_ref(&lib), 1));
+        assert!(stage_failed(&[], 1));
+
+        let release = job(
+            "release",
+            BuildStatus::Success,
+            Some(vec![lib.derivation.drv_path.clone()]),
+        );
+        assert!(!stage_failed(&[broken.clone(), lib, release.clone()], 1));
+
+        let release = BuildJob {
+            status: BuildStatus::Failed,
+            ..release
+        };
+        assert!(stage_failed(&[broken, release], 0));
+    }
+
+    #[test]
+    fn test_parse_stages() {
+        let stages = parse_stages("", "hydraJobs").unwrap();
+        assert_eq!(stages.len(), 1);
+        assert_eq!(stages[0].name, "build");
+        assert_eq!(stages[0].attribute_set, "hydraJobs");
+
+        let contents = r#"
+            [[stages]]
+            name = "build"
+            attribute_set = "packages"
+
+            [[stages]]
+            name = "deploy"
+            attribute_set = "deployments"
+            approval = true
+        "#;
+        let stages = parse_stages(contents, "hydraJobs").unwrap();
+        assert_eq!(
+            stages,
+            vec![
+                StageConfig {
+                    name: "build".to_string(),
+                    attribute_set: "packages".to_string(),
+                    approval: false,
+                },
+                StageConfig {
+                    name: "deploy".to_string(),
+                    attribute_set: "deployments".to_string(),
+                    approval: true,
+                },
+            ]
+        );
+
+        let duplicate = r#"
+            [[stages]]
+            name = "build"
+            attribute_set = "packages"
+            [[stages]]
+            name = "build"
+            attribute_set = "checks"
+        "#;
+        assert!(parse_stages(duplicate, "hydraJobs").is_err());
+    }
+}
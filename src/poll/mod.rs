@@ -0,0 +1,162 @@
+use crate::{
+    nix,
+    notify::matches_pattern,
+    webhook::{self, WorkflowOptions},
+};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::time::MissedTickBehavior;
+use tracing::{error, info, info_span, Instrument};
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct PollConfig {
+    /// Time between two fetches of each repository
+    pub interval_secs: u64,
+    pub repositories: Vec<PolledRepository>,
+}
+
+impl Default for PollConfig {
+    fn default() -> Self {
+        PollConfig {
+            interval_secs: 60,
+            repositories: Vec::new(),
+        }
+    }
+}
+
+impl PollConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.interval_secs == 0 {
+            return Err(anyhow!("interval_secs must be at least 1"));
+        }
+        for repository in &self.repositories {
+            if repository.repository.trim().is_empty() || repository.clone_url.trim().is_empty() {
+                return Err(anyhow!(
+                    "Polled repositories need a repository and a clone_url"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A repository whose branches are built when their head changes, for forges
+/// that cannot deliver webhooks
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct PolledRepository {
+    /// Full name the workflows are recorded under, e.g. "owner/repo"
+    pub repository: String,
+    pub clone_url: String,
+    /// Branches to build (`*` matches anything)
+    #[serde(default = "default_branches")]
+    pub branches: Vec<String>,
+}
+
+fn default_branches() -> Vec<String> {
+    vec!["main".to_string()]
+}
+
+/// Fetches the heads of the branches of the polled repositories and starts a
+/// workflow for each new one. Heads are stored, so a restart builds nothing twice.
+pub struct Poller {
+    config: PollConfig,
+    app_state: Arc<crate::AppState>,
+}
+
+impl Poller {
+    pub fn new(config: &PollConfig, app_state: Arc<crate::AppState>) -> Self {
+        Self {
+            config: config.clone(),
+            app_state,
+        }
+    }
+
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.interval_secs));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            for repository in &self.config.repositories {
+                if let Err(e) = self
+                    .poll(repository)
+                    .instrument(info_span!("poll", repository = repository.repository))
+                    .await
+                {
+                    error!("Failed to poll {}: {:#}", repository.repository, e);
+                }
+            }
+        }
+    }
+
+    async fn poll(&self, polled: &PolledRepository) -> Result<()> {
+        let db_pool = &self.app_state.db_pool;
+        let credential = self.app_state.credentials.get(&polled.repository).await?;
+        let heads: HashMap<String, String> =
+            nix::remote_branches(&polled.clone_url, credential.as_ref())
+                .await?
+                .into_iter()
+                .filter(|(branch, _)| polled.branches.iter().any(|p| matches_pattern(p, branch)))
+                .collect();
+        let seen: HashMap<String, String> = sqlx::query_as::<_, (String, String)>(
+            "SELECT branch, commit_sha FROM polled_branches WHERE repository = ?",
+        )
+        .bind(&polled.repository)
+        .fetch_all(db_pool)
+        .await?
+        .into_iter()
+        .collect();
+
+        for (branch, commit_sha) in &heads {
+            if seen.get(branch) == Some(commit_sha) {
+                continue;
+            }
+            info!(
+                "Head of {} branch {} is now {}",
+                polled.repository, branch, commit_sha
+            );
+            let deprioritized = webhook::check_quota(&self.app_state, &polled.repository)
+                .await
+                .map_err(|e| anyhow!("{}", e))?;
+            webhook::create_workflow(
+                &self.app_state,
+                &polled.repository,
+                commit_sha,
+                branch,
+                &polled.clone_url,
+                None,
+                WorkflowOptions {
+                    deprioritized,
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+            // Only once the workflow exists, so a failure is retried on the next poll
+            sqlx::query(
+                r#"
+                INSERT INTO polled_branches (repository, branch, commit_sha, updated_at)
+                VALUES (?, ?, ?, ?)
+                ON CONFLICT(repository, branch) DO UPDATE
+                SET commit_sha = excluded.commit_sha, updated_at = excluded.updated_at
+                "#,
+            )
+            .bind(&polled.repository)
+            .bind(branch)
+            .bind(commit_sha)
+            .bind(chrono::Utc::now().timestamp())
+            .execute(db_pool)
+            .await?;
+        }
+
+        for branch in seen.keys().filter(|branch| !heads.contains_key(*branch)) {
+            sqlx::query("DELETE FROM polled_branches WHERE repository = ? AND branch = ?")
+                .bind(&polled.repository)
+                .bind(branch)
+                .execute(db_pool)
+                .await?;
+        }
+        Ok(())
+    }
+}
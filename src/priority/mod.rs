@@ -0,0 +1,105 @@
+use crate::notify::matches_pattern;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct PriorityConfig {
+    /// Branches whose workflows get the `default_branch` priority (`*` matches anything)
+    pub default_branches: Vec<String>,
+    /// Priority of the workflows of default branches and tags
+    pub default_branch: i64,
+    /// Priority of the workflows of other branches
+    pub branch: i64,
+    /// Priority of the workflows of pull requests
+    pub pull_request: i64,
+    /// Priorities of specific repositories or branches; the first match wins
+    pub overrides: Vec<PriorityOverride>,
+}
+
+impl Default for PriorityConfig {
+    fn default() -> Self {
+        PriorityConfig {
+            default_branches: vec!["main".to_string(), "master".to_string()],
+            default_branch: 2,
+            branch: 1,
+            pull_request: 0,
+            overrides: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct PriorityOverride {
+    /// Full name of the repository (`*` matches anything)
+    pub repository: String,
+    /// Branch, or `pr-<number>` for pull requests; any when unset
+    pub branch: Option<String>,
+    pub priority: i64,
+}
+
+impl PriorityConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self
+            .overrides
+            .iter()
+            .any(|o| o.repository.trim().is_empty())
+        {
+            return Err(anyhow!("Priority overrides need a repository"));
+        }
+        Ok(())
+    }
+
+    /// Priority of a workflow's jobs; jobs of higher priority are built first
+    pub fn priority(&self, repository: &str, branch: &str, release: bool) -> i64 {
+        let matching = self.overrides.iter().find(|o| {
+            matches_pattern(&o.repository, repository)
+                && o.branch.as_ref().is_none_or(|b| matches_pattern(b, branch))
+        });
+        if let Some(o) = matching {
+            return o.priority;
+        }
+        if release
+            || self
+                .default_branches
+                .iter()
+                .any(|b| matches_pattern(b, branch))
+        {
+            self.default_branch
+        } else if branch.starts_with("pr-") {
+            self.pull_request
+        } else {
+            self.branch
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_priority() {
+        let mut config = PriorityConfig::default();
+        assert_eq!(config.priority("a/b", "main", false), 2);
+        assert_eq!(config.priority("a/b", "v1.0", true), 2);
+        assert_eq!(config.priority("a/b", "feature/x", false), 1);
+        assert_eq!(config.priority("a/b", "pr-42", false), 0);
+
+        config.overrides = vec![
+            PriorityOverride {
+                repository: "a/urgent".to_string(),
+                branch: None,
+                priority: 10,
+            },
+            PriorityOverride {
+                repository: "*".to_string(),
+                branch: Some("release/*".to_string()),
+                priority: 5,
+            },
+        ];
+        assert_eq!(config.priority("a/urgent", "pr-1", false), 10);
+        assert_eq!(config.priority("a/b", "release/1.0", false), 5);
+        assert_eq!(config.priority("a/b", "main", false), 2);
+    }
+}
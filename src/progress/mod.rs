@@ -0,0 +1,203 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Prefix of the lines nix writes with `--log-format internal-json`
+const PREFIX: &str = "@nix ";
+
+// Activity types of nix (ActivityType)
+const ACT_COPY_PATH: u64 = 100;
+const ACT_COPY_PATHS: u64 = 103;
+const ACT_BUILDS: u64 = 104;
+const ACT_BUILD: u64 = 105;
+const ACT_SUBSTITUTE: u64 = 108;
+
+// Result types of nix (ResultType)
+const RES_BUILD_LOG_LINE: u64 = 101;
+const RES_SET_PHASE: u64 = 104;
+const RES_PROGRESS: u64 = 105;
+const RES_SET_EXPECTED: u64 = 106;
+
+/// What a running build is doing, as told by nix's structured log
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct BuildProgress {
+    /// What nix works on, e.g. "building '/nix/store/...-hello-2.12.drv'"
+    pub activity: Option<String>,
+    /// Phase of the derivation being built, e.g. "buildPhase"
+    pub phase: Option<String>,
+    pub builds_done: u64,
+    pub builds_expected: u64,
+    pub downloads_done: u64,
+    pub downloads_expected: u64,
+}
+
+impl BuildProgress {
+    /// One line for the dashboard, e.g. "buildPhase, 3/10 builds, 2/5 downloads"
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(phase) = self.phase.as_ref().or(self.activity.as_ref()) {
+            parts.push(phase.clone());
+        }
+        if self.builds_expected > 0 {
+            parts.push(format!(
+                "{}/{} builds",
+                self.builds_done, self.builds_expected
+            ));
+        }
+        if self.downloads_expected > 0 {
+            parts.push(format!(
+                "{}/{} downloads",
+                self.downloads_done, self.downloads_expected
+            ));
+        }
+        parts.join(", ")
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum Message {
+    Start {
+        id: u64,
+        #[serde(rename = "type", default)]
+        kind: u64,
+        #[serde(default)]
+        text: String,
+    },
+    Stop {
+        id: u64,
+    },
+    Result {
+        id: u64,
+        #[serde(rename = "type")]
+        kind: u64,
+        #[serde(default)]
+        fields: Vec<Value>,
+    },
+    Msg {
+        msg: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+struct Activity {
+    kind: u64,
+    text: String,
+    phase: Option<String>,
+}
+
+/// Follows the activities of a nix command run with `--log-format internal-json`,
+/// turning its stderr back into the plain log along the way
+#[derive(Default)]
+pub struct LogParser {
+    activities: HashMap<u64, Activity>,
+    /// Started builds and copies still running, oldest first
+    shown: Vec<u64>,
+    progress: BuildProgress,
+}
+
+impl LogParser {
+    /// Take a line of stderr; returns the line of the plain log, if it has one
+    pub fn feed(&mut self, line: &str) -> Option<String> {
+        let Some(json) = line.strip_prefix(PREFIX) else {
+            return Some(line.to_string());
+        };
+        let Ok(message) = serde_json::from_str::<Message>(json) else {
+            return Some(line.to_string());
+        };
+        match message {
+            Message::Start { id, kind, text } => {
+                if matches!(kind, ACT_BUILD | ACT_SUBSTITUTE | ACT_COPY_PATH) {
+                    self.shown.push(id);
+                }
+                self.activities.insert(
+                    id,
+                    Activity {
+                        kind,
+                        text,
+                        phase: None,
+                    },
+                );
+            }
+            Message::Stop { id } => {
+                self.activities.remove(&id);
+                self.shown.retain(|shown| *shown != id);
+            }
+            Message::Result { id, kind, fields } => return self.result(id, kind, &fields),
+            Message::Msg { msg } => return Some(msg),
+            Message::Other => {}
+        }
+        self.update();
+        None
+    }
+
+    fn result(&mut self, id: u64, kind: u64, fields: &[Value]) -> Option<String> {
+        let number = |i: usize| fields.get(i).and_then(Value::as_u64).unwrap_or(0);
+        let activity_kind = self.activities.get(&id).map(|a| a.kind);
+        match (kind, activity_kind) {
+            (RES_BUILD_LOG_LINE, _) => {
+                return fields.first().and_then(Value::as_str).map(str::to_string)
+            }
+            (RES_SET_PHASE, _) => {
+                if let Some(activity) = self.activities.get_mut(&id) {
+                    activity.phase = fields.first().and_then(Value::as_str).map(str::to_string);
+                }
+            }
+            (RES_PROGRESS, Some(ACT_BUILDS)) => {
+                self.progress.builds_done = number(0);
+                self.progress.builds_expected = number(1);
+            }
+            (RES_PROGRESS, Some(ACT_COPY_PATHS)) => {
+                self.progress.downloads_done = number(0);
+                self.progress.downloads_expected = number(1);
+            }
+            (RES_SET_EXPECTED, _) if number(0) == ACT_BUILDS => {
+                self.progress.builds_expected = self.progress.builds_expected.max(number(1));
+            }
+            _ => {}
+        }
+        self.update();
+        None
+    }
+
+    fn update(&mut self) {
+        let current = self.shown.last().and_then(|id| self.activities.get(id));
+        self.progress.activity = current.map(|a| a.text.clone());
+        self.progress.phase = current.and_then(|a| a.phase.clone());
+    }
+
+    pub fn progress(&self) -> &BuildProgress {
+        &self.progress
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_parser() {
+        let mut parser = LogParser::default();
+        let lines = [
+            r#"@nix {"action":"start","id":1,"level":0,"type":104,"text":"","parent":0}"#,
+            r#"@nix {"action":"result","id":1,"type":105,"fields":[0,2,0,0]}"#,
+            r#"@nix {"action":"start","id":2,"level":3,"type":105,"text":"building '/nix/store/a-hello.drv'","parent":0}"#,
+            r#"@nix {"action":"result","id":2,"type":104,"fields":["buildPhase"]}"#,
+            r#"@nix {"action":"result","id":2,"type":101,"fields":["gcc -o hello hello.c"]}"#,
+            r#"@nix {"action":"msg","level":1,"msg":"warning: dirty"}"#,
+            "plain line",
+        ];
+        let log: Vec<String> = lines.iter().filter_map(|l| parser.feed(l)).collect();
+        assert_eq!(
+            log,
+            ["gcc -o hello hello.c", "warning: dirty", "plain line"]
+        );
+        assert_eq!(parser.progress().summary(), "buildPhase, 0/2 builds");
+
+        parser.feed(r#"@nix {"action":"stop","id":2}"#);
+        parser.feed(r#"@nix {"action":"result","id":1,"type":105,"fields":[1,2,0,0]}"#);
+        assert_eq!(parser.progress().activity, None);
+        assert_eq!(parser.progress().summary(), "1/2 builds");
+    }
+}
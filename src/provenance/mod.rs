@@ -0,0 +1,273 @@
+use crate::{artifacts::ArtifactStore, nix};
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ed25519_dalek::{
+    pkcs8::{spki::der::pem::LineEnding, DecodePrivateKey, EncodePrivateKey, EncodePublicKey},
+    Signer, SigningKey,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+use tracing::info;
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ProvenanceConfig {
+    /// Attest every successful build
+    pub enabled: bool,
+    /// PKCS#8 PEM Ed25519 key signing the attestations, generated if missing
+    pub signing_key: PathBuf,
+    /// URI identifying this builder in the attestations
+    pub builder_id: String,
+}
+
+impl Default for ProvenanceConfig {
+    fn default() -> Self {
+        ProvenanceConfig {
+            enabled: false,
+            signing_key: PathBuf::from("provenance.pem"),
+            builder_id: "https://github.com/yuri91/icicle".to_string(),
+        }
+    }
+}
+
+impl ProvenanceConfig {
+    /// Check that an existing signing key can be read
+    pub fn validate(&self) -> Result<()> {
+        if self.signing_key.exists() {
+            SigningKey::read_pkcs8_pem_file(&self.signing_key).with_context(|| {
+                format!("Failed to read signing key {}", self.signing_key.display())
+            })?;
+        }
+        Ok(())
+    }
+}
+
+const STATEMENT_TYPE: &str = "https://in-toto.io/Statement/v1";
+const PREDICATE_TYPE: &str = "https://slsa.dev/provenance/v1";
+const BUILD_TYPE: &str = "https://github.com/yuri91/icicle/nix-build/v1";
+const PAYLOAD_TYPE: &str = "application/vnd.in-toto+json";
+/// Name of the attestation among a build's artifacts
+pub const ATTESTATION_NAME: &str = "provenance.intoto.json";
+
+/// A workflow that requested a build, making its commit part of the build's source
+#[derive(Debug, sqlx::FromRow)]
+struct Source {
+    id: i64,
+    repository: String,
+    commit_sha: String,
+    attribute_set: String,
+    clone_url: String,
+}
+
+/// DSSE envelope carrying a signed in-toto statement
+#[derive(Debug, Serialize)]
+struct Envelope {
+    #[serde(rename = "payloadType")]
+    payload_type: &'static str,
+    payload: String,
+    signatures: Vec<Signature>,
+}
+
+#[derive(Debug, Serialize)]
+struct Signature {
+    keyid: String,
+    sig: String,
+}
+
+/// Signs SLSA provenance for successful builds and stores it as an artifact
+pub struct Attestor {
+    key: SigningKey,
+    key_id: String,
+    builder_id: String,
+    db_pool: SqlitePool,
+    artifact_store: ArtifactStore,
+}
+
+impl Attestor {
+    pub fn new(
+        config: &ProvenanceConfig,
+        db_pool: SqlitePool,
+        artifact_store: ArtifactStore,
+    ) -> Result<Self> {
+        let key = load_or_generate_key(&config.signing_key)?;
+        let key_id = hex::encode(Sha256::digest(key.verifying_key().as_bytes()));
+        info!("Signing build provenance with key {}", key_id);
+        Ok(Self {
+            key,
+            key_id,
+            builder_id: config.builder_id.clone(),
+            db_pool,
+            artifact_store,
+        })
+    }
+
+    /// The public key verifying the attestations, as SPKI PEM
+    pub fn public_key_pem(&self) -> Result<String> {
+        Ok(self.key.verifying_key().to_public_key_pem(LineEnding::LF)?)
+    }
+
+    /// Attest a successful build of `drv_path` with the given outputs
+    pub async fn attest(
+        &self,
+        drv_path: &str,
+        outputs: &HashMap<String, String>,
+        requested_by: &HashSet<i64>,
+        started_at: i64,
+    ) -> Result<()> {
+        let mut subjects = Vec::new();
+        // Sorted, so attestations of the same build read the same
+        for (name, path) in outputs.iter().collect::<BTreeMap<_, _>>() {
+            let info = nix::path_info(&[path], false)
+                .await
+                .with_context(|| format!("Failed to hash output {}", name))?;
+            let digest = info
+                .first()
+                .map(|info| info.nar_sha256.clone())
+                .unwrap_or_default();
+            subjects.push(json!({
+                "name": path,
+                "digest": { "sha256": digest },
+                "annotations": { "output": name },
+            }));
+        }
+
+        let mut sources = Vec::new();
+        for workflow_id in requested_by {
+            let source = sqlx::query_as::<_, Source>(
+                r#"
+                SELECT w.id, w.repository, w.commit_sha, w.attribute_set, r.clone_url
+                FROM workflows w JOIN repositories r ON r.full_name = w.repository
+                WHERE w.id = ?
+                "#,
+            )
+            .bind(workflow_id)
+            .fetch_optional(&self.db_pool)
+            .await?;
+            sources.extend(source);
+        }
+        sources.sort_by_key(|source| source.id);
+
+        let statement = json!({
+            "_type": STATEMENT_TYPE,
+            "subject": subjects,
+            "predicateType": PREDICATE_TYPE,
+            "predicate": {
+                "buildDefinition": {
+                    "buildType": BUILD_TYPE,
+                    "externalParameters": {
+                        "derivation": drv_path,
+                        "workflows": sources.iter().map(|source| json!({
+                            "id": source.id,
+                            "repository": source.repository,
+                            "commit": source.commit_sha,
+                            "attributeSet": source.attribute_set,
+                        })).collect::<Vec<_>>(),
+                    },
+                    "resolvedDependencies": sources.iter().map(|source| json!({
+                        "uri": format!("git+{}", source.clone_url),
+                        "digest": { "gitCommit": source.commit_sha },
+                    })).collect::<Vec<_>>(),
+                },
+                "runDetails": {
+                    "builder": { "id": self.builder_id },
+                    "metadata": {
+                        "invocationId": drv_path,
+                        "startedOn": timestamp(started_at),
+                        "finishedOn": timestamp(chrono::Utc::now().timestamp()),
+                    },
+                },
+            },
+        });
+
+        let envelope = self.sign(&statement)?;
+        self.artifact_store
+            .store_generated(
+                drv_path,
+                ATTESTATION_NAME,
+                "provenance",
+                serde_json::to_vec_pretty(&envelope)?,
+            )
+            .await?;
+        info!("Stored provenance of {}", drv_path);
+        Ok(())
+    }
+
+    fn sign(&self, statement: &Value) -> Result<Envelope> {
+        let payload = serde_json::to_vec(statement)?;
+        let signature = self.key.sign(&pre_auth_encoding(PAYLOAD_TYPE, &payload));
+        Ok(Envelope {
+            payload_type: PAYLOAD_TYPE,
+            payload: BASE64.encode(&payload),
+            signatures: vec![Signature {
+                keyid: self.key_id.clone(),
+                sig: BASE64.encode(signature.to_bytes()),
+            }],
+        })
+    }
+}
+
+/// The bytes a DSSE signature covers
+fn pre_auth_encoding(payload_type: &str, payload: &[u8]) -> Vec<u8> {
+    let mut encoded = format!(
+        "DSSEv1 {} {} {} ",
+        payload_type.len(),
+        payload_type,
+        payload.len()
+    )
+    .into_bytes();
+    encoded.extend_from_slice(payload);
+    encoded
+}
+
+fn timestamp(secs: i64) -> String {
+    chrono::DateTime::from_timestamp(secs, 0)
+        .unwrap_or_default()
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+fn load_or_generate_key(path: &Path) -> Result<SigningKey> {
+    if path.exists() {
+        return SigningKey::read_pkcs8_pem_file(path)
+            .with_context(|| format!("Failed to read signing key {}", path.display()));
+    }
+
+    let key = SigningKey::generate(&mut rand_core::OsRng);
+    let pem = key.to_pkcs8_pem(LineEnding::LF)?;
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Only the server may read the key
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(pem.as_bytes()))
+        .with_context(|| format!("Failed to write signing key {}", path.display()))?;
+    info!("Generated provenance signing key {}", path.display());
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pre_auth_encoding() {
+        assert_eq!(
+            pre_auth_encoding(PAYLOAD_TYPE, b"{}"),
+            b"DSSEv1 28 application/vnd.in-toto+json 2 {}"
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use anyhow::Result;
+use chrono::{Datelike, TimeZone};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// Monthly build minutes of each repository; 0 for no limit
+    pub repository_minutes: u64,
+    /// Monthly build minutes of all the repositories of an organization; 0 for no limit
+    pub organization_minutes: u64,
+    /// Limits of specific repositories, by full name
+    pub repositories: HashMap<String, u64>,
+    /// Limits of specific organizations, by name
+    pub organizations: HashMap<String, u64>,
+    /// Share of a quota after which new workflows come with a warning
+    pub warn_percent: u64,
+    /// What happens to new workflows over quota
+    pub action: QuotaAction,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        QuotaConfig {
+            repository_minutes: 0,
+            organization_minutes: 0,
+            repositories: HashMap::new(),
+            organizations: HashMap::new(),
+            warn_percent: 80,
+            action: QuotaAction::Deprioritize,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaAction {
+    /// Build their jobs only when no other job is ready
+    Deprioritize,
+    /// Refuse to create them
+    Reject,
+}
+
+impl QuotaConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.warn_percent > 100 {
+            return Err(anyhow::anyhow!("warn_percent must be at most 100"));
+        }
+        Ok(())
+    }
+
+    fn repository_limit(&self, repository: &str) -> u64 {
+        self.repositories
+            .get(repository)
+            .copied()
+            .unwrap_or(self.repository_minutes)
+    }
+
+    fn organization_limit(&self, organization: &str) -> u64 {
+        self.organizations
+            .get(organization)
+            .copied()
+            .unwrap_or(self.organization_minutes)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaState {
+    Ok,
+    Warning,
+    Exceeded,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Repository,
+    Organization,
+}
+
+/// Build time consumed this month against a quota
+#[derive(Debug, Clone, Serialize)]
+pub struct Usage {
+    pub scope: Scope,
+    pub name: String,
+    pub used_minutes: u64,
+    /// None when unlimited
+    pub limit_minutes: Option<u64>,
+    pub state: QuotaState,
+}
+
+impl Usage {
+    fn new(scope: Scope, name: String, used_secs: i64, limit: u64, warn_percent: u64) -> Self {
+        Usage {
+            scope,
+            name,
+            used_minutes: used_secs.max(0) as u64 / 60,
+            limit_minutes: (limit > 0).then_some(limit),
+            state: state(used_secs, limit, warn_percent),
+        }
+    }
+}
+
+fn state(used_secs: i64, limit_minutes: u64, warn_percent: u64) -> QuotaState {
+    if limit_minutes == 0 {
+        return QuotaState::Ok;
+    }
+    let used = used_secs.max(0) as u64;
+    let limit = limit_minutes * 60;
+    if used >= limit {
+        QuotaState::Exceeded
+    } else if used * 100 >= limit * warn_percent {
+        QuotaState::Warning
+    } else {
+        QuotaState::Ok
+    }
+}
+
+/// Quotas reset at the start of every month, UTC
+fn month_start() -> i64 {
+    let now = chrono::Utc::now();
+    chrono::Utc
+        .with_ymd_and_hms(now.year(), now.month(), 1, 0, 0, 0)
+        .unwrap()
+        .timestamp()
+}
+
+/// Charge a finished build to the repositories of the workflows that requested it
+pub async fn record_usage(
+    db_pool: &SqlitePool,
+    drv_path: &str,
+    requested_by: &HashSet<i64>,
+    seconds: i64,
+    finished_at: i64,
+) -> Result<()> {
+    let mut repositories = BTreeSet::new();
+    for workflow_id in requested_by {
+        let repository: Option<String> =
+            sqlx::query_scalar("SELECT repository FROM workflows WHERE id = ?")
+                .bind(workflow_id)
+                .fetch_optional(db_pool)
+                .await?;
+        repositories.extend(repository);
+    }
+    for repository in repositories {
+        sqlx::query(
+            r#"
+            INSERT INTO build_usage (drv_path, repository, seconds, finished_at)
+            VALUES (?, ?, ?, ?)
+            "#,
+        )
+        .bind(drv_path)
+        .bind(repository)
+        .bind(seconds)
+        .bind(finished_at)
+        .execute(db_pool)
+        .await?;
+    }
+    Ok(())
+}
+
+/// This month's usage of a repository and of its organization, if any
+pub async fn check(
+    db_pool: &SqlitePool,
+    config: &QuotaConfig,
+    repository: &str,
+) -> Result<Vec<Usage>> {
+    let since = month_start();
+    let used: i64 = sqlx::query_scalar(
+        r#"
+        SELECT COALESCE(SUM(seconds), 0) FROM build_usage
+        WHERE repository = ? AND finished_at >= ?
+        "#,
+    )
+    .bind(repository)
+    .bind(since)
+    .fetch_one(db_pool)
+    .await?;
+    let mut usage = vec![Usage::new(
+        Scope::Repository,
+        repository.to_string(),
+        used,
+        config.repository_limit(repository),
+        config.warn_percent,
+    )];
+
+    let organization: Option<String> = sqlx::query_scalar(
+        r#"
+        SELECT o.name FROM repositories r
+        JOIN projects p ON p.id = r.project_id
+        JOIN organizations o ON o.id = p.organization_id
+        WHERE r.full_name = ?
+        "#,
+    )
+    .bind(repository)
+    .fetch_optional(db_pool)
+    .await?;
+    if let Some(organization) = organization {
+        let used = organization_usage(db_pool, &organization, since).await?;
+        usage.push(Usage::new(
+            Scope::Organization,
+            organization.clone(),
+            used,
+            config.organization_limit(&organization),
+            config.warn_percent,
+        ));
+    }
+    Ok(usage)
+}
+
+async fn organization_usage(db_pool: &SqlitePool, organization: &str, since: i64) -> Result<i64> {
+    Ok(sqlx::query_scalar(
+        r#"
+        SELECT COALESCE(SUM(u.seconds), 0) FROM build_usage u
+        JOIN repositories r ON r.full_name = u.repository
+        JOIN projects p ON p.id = r.project_id
+        JOIN organizations o ON o.id = p.organization_id
+        WHERE o.name = ? AND u.finished_at >= ?
+        "#,
+    )
+    .bind(organization)
+    .bind(since)
+    .fetch_one(db_pool)
+    .await?)
+}
+
+/// This month's usage of every repository and organization
+pub async fn all(db_pool: &SqlitePool, config: &QuotaConfig) -> Result<Vec<Usage>> {
+    let since = month_start();
+    let repositories = sqlx::query_as::<_, (String, i64)>(
+        r#"
+        SELECT r.full_name, COALESCE(SUM(u.seconds), 0)
+        FROM repositories r
+        LEFT JOIN build_usage u ON u.repository = r.full_name AND u.finished_at >= ?
+        GROUP BY r.full_name ORDER BY r.full_name
+        "#,
+    )
+    .bind(since)
+    .fetch_all(db_pool)
+    .await?;
+    let organizations: Vec<String> =
+        sqlx::query_scalar("SELECT name FROM organizations ORDER BY name")
+            .fetch_all(db_pool)
+            .await?;
+
+    let mut usage: Vec<Usage> = repositories
+        .into_iter()
+        .map(|(repository, used)| {
+            let limit = config.repository_limit(&repository);
+            Usage::new(
+                Scope::Repository,
+                repository,
+                used,
+                limit,
+                config.warn_percent,
+            )
+        })
+        .collect();
+    for organization in organizations {
+        let used = organization_usage(db_pool, &organization, since).await?;
+        let limit = config.organization_limit(&organization);
+        usage.push(Usage::new(
+            Scope::Organization,
+            organization,
+            used,
+            limit,
+            config.warn_percent,
+        ));
+    }
+    Ok(usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state() {
+        assert_eq!(state(1_000_000, 0, 80), QuotaState::Ok);
+        assert_eq!(state(79 * 60, 100, 80), QuotaState::Ok);
+        assert_eq!(state(80 * 60, 100, 80), QuotaState::Warning);
+        assert_eq!(state(100 * 60, 100, 80), QuotaState::Exceeded);
+    }
+}
@@ -0,0 +1,230 @@
+use crate::{cli::ServeArgs, config::Settings, executor::BuildExecutor, notify::NotifyConfig};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+use tracing::{error, info, warn};
+
+/// How often the configuration files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Re-reads the configuration on SIGHUP or when a configuration file changes and
+/// applies the settings that can change without a restart: the webhook secret and
+/// default attribute set, notifiers, and the number of concurrent builds.
+pub struct Reloader {
+    args: ServeArgs,
+    settings: Settings,
+    app_state: Arc<crate::AppState>,
+    executor: Arc<BuildExecutor>,
+    notify: watch::Sender<NotifyConfig>,
+}
+
+impl Reloader {
+    pub fn new(
+        args: ServeArgs,
+        settings: Settings,
+        app_state: Arc<crate::AppState>,
+        executor: Arc<BuildExecutor>,
+    ) -> Self {
+        let notify = watch::Sender::new(settings.notify.clone());
+        Self {
+            args,
+            settings,
+            app_state,
+            executor,
+            notify,
+        }
+    }
+
+    /// Notification settings, updated on every reload
+    pub fn notify_config(&self) -> watch::Receiver<NotifyConfig> {
+        self.notify.subscribe()
+    }
+
+    pub async fn run(mut self) {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => Some(hangup),
+            Err(e) => {
+                warn!("Reloading on SIGHUP is unavailable: {}", e);
+                None
+            }
+        };
+        let files = Settings::files(self.args.config.as_deref());
+        let mut modified = modification_times(&files);
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+
+        loop {
+            tokio::select! {
+                Some(()) = async { hangup.as_mut()?.recv().await } => {
+                    info!("Received SIGHUP, reloading configuration");
+                }
+                _ = interval.tick() => {
+                    let current = modification_times(&files);
+                    if current == modified {
+                        continue;
+                    }
+                    modified = current;
+                    info!("Configuration files changed, reloading configuration");
+                }
+            }
+            self.reload();
+        }
+    }
+
+    fn reload(&mut self) {
+        let mut settings = match Settings::new(self.args.config.as_deref()) {
+            Ok(settings) => settings,
+            Err(e) => {
+                error!("Keeping previous configuration: {}", e);
+                return;
+            }
+        };
+        self.args.apply(&mut settings);
+        if settings.build.max_concurrent_builds == 0 {
+            error!(
+                "Keeping previous configuration: build.max_concurrent_builds must be at least 1"
+            );
+            return;
+        }
+
+        let restart_only = [
+            ("server", settings.server != self.settings.server),
+            ("database", settings.database != self.settings.database),
+            ("cache", settings.cache != self.settings.cache),
+            ("api", settings.api != self.settings.api),
+            ("log", settings.log != self.settings.log),
+            ("gc", settings.gc != self.settings.gc),
+            ("proxy", settings.proxy != self.settings.proxy),
+            ("artifacts", settings.artifacts != self.settings.artifacts),
+            ("deploy", settings.deploy != self.settings.deploy),
+            (
+                "provenance",
+                settings.provenance != self.settings.provenance,
+            ),
+            ("bisect", settings.bisect != self.settings.bisect),
+            ("quota", settings.quota != self.settings.quota),
+            ("priority", settings.priority != self.settings.priority),
+            ("workers", settings.workers != self.settings.workers),
+            ("github", settings.github != self.settings.github),
+            (
+                "credentials",
+                settings.credentials != self.settings.credentials,
+            ),
+            ("schedule", settings.schedule != self.settings.schedule),
+            ("poll", settings.poll != self.settings.poll),
+            (
+                "nix.eval_timeout_secs",
+                settings.nix.eval_timeout_secs != self.settings.nix.eval_timeout_secs,
+            ),
+            ("nix.fetch", settings.nix.fetch != self.settings.nix.fetch),
+            (
+                "nix.eval_workers",
+                settings.nix.eval_workers != self.settings.nix.eval_workers,
+            ),
+            (
+                "nix.eval_max_memory_size_mib",
+                settings.nix.eval_max_memory_size_mib != self.settings.nix.eval_max_memory_size_mib,
+            ),
+            (
+                "nix.eval_memory_limit_mib",
+                settings.nix.eval_memory_limit_mib != self.settings.nix.eval_memory_limit_mib,
+            ),
+            (
+                "nix.import_from_derivation",
+                settings.nix.import_from_derivation != self.settings.nix.import_from_derivation,
+            ),
+            (
+                "build.scheduling",
+                settings.build.scheduling != self.settings.build.scheduling,
+            ),
+            (
+                "build.local_systems",
+                settings.build.local_systems != self.settings.build.local_systems,
+            ),
+            (
+                "build.local_features",
+                settings.build.local_features != self.settings.build.local_features,
+            ),
+            (
+                "build.builders",
+                settings.build.builders != self.settings.build.builders,
+            ),
+            (
+                "build.builder_check_secs",
+                settings.build.builder_check_secs != self.settings.build.builder_check_secs,
+            ),
+            (
+                "build.build_timeout_secs",
+                settings.build.build_timeout_secs != self.settings.build.build_timeout_secs,
+            ),
+            (
+                "build.max_build_timeout_secs",
+                settings.build.max_build_timeout_secs != self.settings.build.max_build_timeout_secs,
+            ),
+            (
+                "build.max_retries",
+                settings.build.max_retries != self.settings.build.max_retries,
+            ),
+            (
+                "build.retry_backoff_secs",
+                settings.build.retry_backoff_secs != self.settings.build.retry_backoff_secs,
+            ),
+            (
+                "build.max_concurrent_uploads",
+                settings.build.max_concurrent_uploads != self.settings.build.max_concurrent_uploads,
+            ),
+            (
+                "build.upload_retries",
+                settings.build.upload_retries != self.settings.build.upload_retries,
+            ),
+        ];
+        for (name, _) in restart_only.iter().filter(|(_, changed)| *changed) {
+            warn!("Changes to {} only take effect after a restart", name);
+        }
+
+        if settings.webhook != self.settings.webhook
+            || settings.nix.default_attr_set != self.settings.nix.default_attr_set
+            || settings.nix.release_attr_set != self.settings.nix.release_attr_set
+        {
+            self.app_state
+                .set_webhook_config(crate::webhook::WebhookConfig {
+                    secret: settings.webhook.secret.clone(),
+                    allow: settings.webhook.allow.clone(),
+                    deny: settings.webhook.deny.clone(),
+                    branches: settings.webhook.branches.clone(),
+                    repository_branches: settings.webhook.repository_branches.clone(),
+                    event_retention_days: settings.webhook.event_retention_days,
+                    attrset: settings.nix.default_attr_set.clone(),
+                    release_attrset: settings.nix.release_attr_set.clone(),
+                });
+            info!("Webhook settings reloaded");
+        }
+        if settings.build.max_concurrent_builds != self.settings.build.max_concurrent_builds {
+            self.executor
+                .set_max_concurrent_builds(settings.build.max_concurrent_builds);
+        }
+        // Rebuilding the notifiers is cheap, so they are replaced on every reload
+        self.notify.send_replace(settings.notify.clone());
+
+        self.settings = settings;
+        info!("Configuration reloaded");
+    }
+}
+
+fn modification_times(files: &[PathBuf]) -> HashMap<PathBuf, Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| {
+            let modified = std::fs::metadata(file)
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            (file.clone(), modified)
+        })
+        .collect()
+}
@@ -0,0 +1,140 @@
+use crate::{artifacts::ArtifactStore, build::Derivation, nix};
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+use tracing::info;
+
+/// Name of the SBOM among a build's artifacts
+pub const SBOM_NAME: &str = "sbom.cdx.json";
+
+/// Split a store path into the package name and version
+fn parse_store_path(path: &str) -> Option<(String, String)> {
+    let basename = path.strip_prefix("/nix/store/")?;
+    // Store paths are `<32 character hash>-<name>`
+    let name = basename
+        .get(33..)
+        .filter(|_| basename.as_bytes()[32] == b'-')?;
+    let (name, version) = nix::split_version(name);
+    Some((name.to_string(), version.to_string()))
+}
+
+fn component(path: &str, kind: &str, hash: &str) -> Value {
+    let (name, version) =
+        parse_store_path(path).unwrap_or_else(|| (path.to_string(), String::new()));
+    let mut component = json!({
+        "type": kind,
+        "bom-ref": path,
+        "name": name,
+        "hashes": [{ "alg": "SHA-256", "content": hash }],
+        "properties": [{ "name": "nix:store_path", "value": path }],
+    });
+    if !version.is_empty() {
+        component["version"] = json!(version);
+        component["purl"] = json!(format!("pkg:nix/{}@{}", name, version));
+    }
+    component
+}
+
+/// Describe the runtime closure of a build's outputs as a CycloneDX SBOM.
+/// Licenses are only known for the built derivation itself, from its meta.
+async fn generate(derivation: &Derivation, outputs: &HashMap<String, String>) -> Result<Value> {
+    let output_paths: BTreeSet<&str> = outputs.values().map(String::as_str).collect();
+    if output_paths.is_empty() {
+        return Err(anyhow!("{} has no outputs", derivation.drv_path));
+    }
+    let roots: Vec<&str> = output_paths.iter().copied().collect();
+    let closure = nix::path_info(&roots, true).await?;
+
+    let licenses: Vec<Value> = derivation
+        .licenses
+        .iter()
+        .map(|license| json!({ "license": { "id": license } }))
+        .collect();
+    let mut root = json!({
+        "type": "application",
+        "bom-ref": derivation.drv_path,
+        "name": derivation.name,
+        "properties": [{ "name": "nix:drv_path", "value": derivation.drv_path }],
+    });
+    if !licenses.is_empty() {
+        root["licenses"] = json!(licenses);
+    }
+
+    let components: Vec<Value> = closure
+        .iter()
+        .map(|info| {
+            let kind = if output_paths.contains(info.path.as_str()) {
+                "application"
+            } else {
+                "library"
+            };
+            component(&info.path, kind, &info.nar_sha256)
+        })
+        .collect();
+    let mut dependencies: Vec<Value> = closure
+        .iter()
+        .map(|info| {
+            let references: Vec<&String> = info
+                .references
+                .iter()
+                .filter(|reference| **reference != info.path)
+                .collect();
+            json!({ "ref": info.path, "dependsOn": references })
+        })
+        .collect();
+    dependencies.push(json!({ "ref": derivation.drv_path, "dependsOn": roots }));
+
+    Ok(json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "version": 1,
+        "metadata": {
+            "timestamp": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            "tools": { "components": [{ "type": "application", "name": "icicle" }] },
+            "component": root,
+        },
+        "components": components,
+        "dependencies": dependencies,
+    }))
+}
+
+/// Generate the SBOM of a successful build and store it among its artifacts
+pub async fn collect(
+    artifact_store: &ArtifactStore,
+    derivation: &Derivation,
+    outputs: &HashMap<String, String>,
+) -> Result<()> {
+    let sbom = generate(derivation, outputs).await?;
+    artifact_store
+        .store_generated(
+            &derivation.drv_path,
+            SBOM_NAME,
+            "sbom",
+            serde_json::to_vec_pretty(&sbom)?,
+        )
+        .await?;
+    info!("Stored SBOM of {}", derivation.drv_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_store_path() {
+        assert_eq!(
+            parse_store_path("/nix/store/0c7c9j3el7wvfbbkzbb2lqdxijrbgbhw-openssl-3.0.13"),
+            Some(("openssl".to_string(), "3.0.13".to_string()))
+        );
+        assert_eq!(
+            parse_store_path("/nix/store/0c7c9j3el7wvfbbkzbb2lqdxijrbgbhw-gcc-13.2.0-lib"),
+            Some(("gcc".to_string(), "13.2.0-lib".to_string()))
+        );
+        assert_eq!(
+            parse_store_path("/nix/store/0c7c9j3el7wvfbbkzbb2lqdxijrbgbhw-hello"),
+            Some(("hello".to_string(), String::new()))
+        );
+        assert_eq!(parse_store_path("/tmp/hello"), None);
+    }
+}
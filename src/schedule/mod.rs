@@ -0,0 +1,492 @@
+use crate::{
+    api::ApiError,
+    nix::{self, RemoteRef},
+    webhook::{self, WorkflowOptions},
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::{Datelike, Duration, Local, NaiveDateTime, Timelike};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::{str::FromStr, sync::Arc};
+use tracing::{error, info, info_span, warn, Instrument};
+
+/// How far ahead to look for the next run of a schedule
+const HORIZON_DAYS: i64 = 5 * 366;
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    /// Schedules kept in sync with the database on startup
+    pub workflows: Vec<Schedule>,
+}
+
+impl ScheduleConfig {
+    pub fn validate(&self) -> Result<()> {
+        for schedule in &self.workflows {
+            schedule
+                .validate()
+                .with_context(|| format!("Schedule of {}", schedule.repository))?;
+        }
+        Ok(())
+    }
+}
+
+/// A workflow to start on a schedule, such as a nightly build of main
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Schedule {
+    /// Full name of the repository, which must have sent a webhook before
+    pub repository: String,
+    #[serde(default = "default_branch")]
+    pub branch: String,
+    /// When to run, e.g. "0 3 * * *" for every night at 3:00 local time
+    pub cron: String,
+    /// Evaluated instead of nix.default_attr_set
+    pub attribute_set: Option<String>,
+}
+
+fn default_branch() -> String {
+    "main".to_string()
+}
+
+impl Schedule {
+    pub fn validate(&self) -> Result<()> {
+        if self.repository.trim().is_empty() {
+            return Err(anyhow!("Needs a repository"));
+        }
+        if self.branch.trim().is_empty() {
+            return Err(anyhow!("Needs a branch"));
+        }
+        let cron: Cron = self.cron.parse()?;
+        if cron.next(Local::now().naive_local()).is_none() {
+            return Err(anyhow!("'{}' never runs", self.cron));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct StoredSchedule {
+    pub id: i64,
+    pub repository: String,
+    pub branch: String,
+    pub cron: String,
+    pub attribute_set: Option<String>,
+    /// Defined in the configuration, so it cannot be removed through the API
+    pub configured: bool,
+    pub created_at: i64,
+    pub last_run_at: Option<i64>,
+    pub last_workflow_id: Option<i64>,
+    #[sqlx(skip)]
+    pub next_run_at: Option<i64>,
+}
+
+impl StoredSchedule {
+    fn cron(&self) -> Option<Cron> {
+        self.cron.parse().ok()
+    }
+}
+
+const SCHEDULE_COLUMNS: &str = "id, repository, branch, cron, attribute_set, configured, \
+    created_at, last_run_at, last_workflow_id";
+
+/// Every schedule, with the time of its next run
+pub async fn list(db_pool: &SqlitePool) -> Result<Vec<StoredSchedule>> {
+    let mut schedules = sqlx::query_as::<_, StoredSchedule>(&format!(
+        "SELECT {} FROM schedules ORDER BY id",
+        SCHEDULE_COLUMNS
+    ))
+    .fetch_all(db_pool)
+    .await?;
+    let now = Local::now().naive_local();
+    for schedule in &mut schedules {
+        schedule.next_run_at = schedule
+            .cron()
+            .and_then(|cron| cron.next(now))
+            .and_then(|next| next.and_local_timezone(Local).earliest())
+            .map(|next| next.timestamp());
+    }
+    Ok(schedules)
+}
+
+pub async fn get(db_pool: &SqlitePool, id: i64) -> Result<Option<StoredSchedule>> {
+    Ok(list(db_pool)
+        .await?
+        .into_iter()
+        .find(|schedule| schedule.id == id))
+}
+
+/// Start a workflow of a schedule's branch at its current head
+pub async fn trigger(
+    app_state: &Arc<crate::AppState>,
+    schedule: &StoredSchedule,
+) -> Result<i64, ApiError> {
+    let clone_url: String =
+        sqlx::query_scalar("SELECT clone_url FROM repositories WHERE full_name = ?")
+            .bind(&schedule.repository)
+            .fetch_optional(&app_state.db_pool)
+            .await?
+            .ok_or_else(|| {
+                ApiError::unprocessable(format!(
+                    "Repository {} is unknown until it sends a webhook",
+                    schedule.repository
+                ))
+            })?;
+    let credential = app_state.credentials.get(&schedule.repository).await?;
+    let commit_sha = match nix::resolve_ref(&clone_url, &schedule.branch, credential.as_ref())
+        .await
+        .map_err(|e| ApiError::unprocessable(format!("{:#}", e)))?
+    {
+        Some(RemoteRef::Branch(commit_sha)) => commit_sha,
+        _ => {
+            return Err(ApiError::unprocessable(format!(
+                "Branch {} not found in {}",
+                schedule.branch, clone_url
+            )))
+        }
+    };
+
+    info!(
+        "Scheduled build of {} branch {} at {}",
+        schedule.repository, schedule.branch, commit_sha
+    );
+    let deprioritized = webhook::check_quota(app_state, &schedule.repository).await?;
+    let workflow_id = webhook::create_workflow(
+        app_state,
+        &schedule.repository,
+        &commit_sha,
+        &schedule.branch,
+        &clone_url,
+        None,
+        WorkflowOptions {
+            deprioritized,
+            attribute_set: schedule.attribute_set.clone(),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    sqlx::query("UPDATE schedules SET last_run_at = ?, last_workflow_id = ? WHERE id = ?")
+        .bind(chrono::Utc::now().timestamp())
+        .bind(workflow_id)
+        .bind(schedule.id)
+        .execute(&app_state.db_pool)
+        .await?;
+    Ok(workflow_id)
+}
+
+/// Starts the workflows of the schedules in the database when they are due.
+/// Runs missed while icicle was down are skipped, like cron does.
+pub struct Scheduler {
+    config: ScheduleConfig,
+    app_state: Arc<crate::AppState>,
+}
+
+impl Scheduler {
+    pub fn new(config: &ScheduleConfig, app_state: Arc<crate::AppState>) -> Self {
+        Self {
+            config: config.clone(),
+            app_state,
+        }
+    }
+
+    pub async fn run(self) {
+        if let Err(e) = self.sync().await {
+            error!("Failed to store the configured schedules: {:#}", e);
+        }
+
+        let mut last_tick = None;
+        loop {
+            let now = Local::now().naive_local();
+            let minute = truncate(now);
+            let wait = (minute + Duration::minutes(1) - now)
+                .to_std()
+                .unwrap_or_default();
+            tokio::time::sleep(wait).await;
+
+            // Sleeping may end a little early
+            let minute = truncate(Local::now().naive_local());
+            if last_tick == Some(minute) {
+                continue;
+            }
+            last_tick = Some(minute);
+            if let Err(e) = self.tick(minute).await {
+                error!("Failed to run schedules: {:#}", e);
+            }
+        }
+    }
+
+    /// Make the configured schedules in the database match the configuration,
+    /// keeping the history of those that did not change
+    async fn sync(&self) -> Result<()> {
+        let mut tx = self.app_state.db_pool.begin().await?;
+        let stored = sqlx::query_as::<_, StoredSchedule>(&format!(
+            "SELECT {} FROM schedules WHERE configured = 1",
+            SCHEDULE_COLUMNS
+        ))
+        .fetch_all(&mut *tx)
+        .await?;
+        let same = |stored: &StoredSchedule, schedule: &Schedule| {
+            stored.repository == schedule.repository
+                && stored.branch == schedule.branch
+                && stored.cron == schedule.cron
+                && stored.attribute_set == schedule.attribute_set
+        };
+
+        for stored in &stored {
+            if !self.config.workflows.iter().any(|s| same(stored, s)) {
+                sqlx::query("DELETE FROM schedules WHERE id = ?")
+                    .bind(stored.id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+        let now = chrono::Utc::now().timestamp();
+        for schedule in &self.config.workflows {
+            if !stored.iter().any(|stored| same(stored, schedule)) {
+                sqlx::query(
+                    r#"
+                    INSERT INTO schedules
+                        (repository, branch, cron, attribute_set, configured, created_at)
+                    VALUES (?, ?, ?, ?, 1, ?)
+                    "#,
+                )
+                .bind(&schedule.repository)
+                .bind(&schedule.branch)
+                .bind(&schedule.cron)
+                .bind(&schedule.attribute_set)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn tick(&self, minute: NaiveDateTime) -> Result<()> {
+        for schedule in list(&self.app_state.db_pool).await? {
+            let Some(cron) = schedule.cron() else {
+                warn!("Schedule {} has an invalid cron expression", schedule.id);
+                continue;
+            };
+            if !cron.matches(minute) {
+                continue;
+            }
+            let app_state = self.app_state.clone();
+            let id = schedule.id;
+            tokio::spawn(
+                async move {
+                    if let Err(e) = trigger(&app_state, &schedule).await {
+                        error!(
+                            "Failed to start scheduled workflow of {}: {}",
+                            schedule.repository, e
+                        );
+                    }
+                }
+                .instrument(info_span!("schedule", id)),
+            );
+        }
+        Ok(())
+    }
+}
+
+fn truncate(time: NaiveDateTime) -> NaiveDateTime {
+    time.with_second(0)
+        .and_then(|time| time.with_nanosecond(0))
+        .unwrap_or(time)
+}
+
+/// A cron expression: minute, hour, day of month, month and day of week, each
+/// `*`, a value, a range or a list of them, optionally with a `/step`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of the month or of the week was `*`; when neither was,
+    /// matching either of them is enough, as in cron
+    any_day: bool,
+    any_weekday: bool,
+}
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl FromStr for Cron {
+    type Err = anyhow::Error;
+
+    fn from_str(expression: &str) -> Result<Self> {
+        let expression = match expression.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            expression => expression,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(anyhow!(
+                "'{}' does not have the five fields minute, hour, day of month, month \
+                 and day of week",
+                expression
+            ));
+        };
+
+        let weekdays = field(weekday, 0, 7, WEEKDAYS).context("Invalid day of week")?;
+        Ok(Cron {
+            minutes: field(minute, 0, 59, &[]).context("Invalid minute")?,
+            hours: field(hour, 0, 23, &[]).context("Invalid hour")?,
+            days: field(day, 1, 31, &[]).context("Invalid day of month")?,
+            months: field(month, 1, 12, MONTHS).context("Invalid month")?,
+            // 7 is Sunday too
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: day.starts_with('*'),
+            any_weekday: weekday.starts_with('*'),
+        })
+    }
+}
+
+/// Parse a field into the bit set of the values it matches
+fn field(spec: &str, min: u32, max: u32, names: &[&str]) -> Result<u64> {
+    let value = |value: &str| -> Result<u32> {
+        let lowercase = value.to_lowercase();
+        let value = match names.iter().position(|name| *name == lowercase) {
+            Some(index) => index as u32 + min,
+            None => value
+                .parse()
+                .map_err(|_| anyhow!("'{}' is not a number", value))?,
+        };
+        if !(min..=max).contains(&value) {
+            return Err(anyhow!("{} is not within {}-{}", value, min, max));
+        }
+        Ok(value)
+    };
+
+    let mut bits = 0;
+    for part in spec.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (part, None),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            // A single value with a step repeats until the end of the range
+            None if step.is_some() => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        let step = match step {
+            Some(step) => step
+                .parse()
+                .ok()
+                .filter(|step| *step > 0)
+                .ok_or_else(|| anyhow!("'{}' is not a valid step", step))?,
+            None => 1,
+        };
+        if start > end {
+            return Err(anyhow!("{}-{} is empty", start, end));
+        }
+        for value in (start..=end).step_by(step) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+fn has(bits: u64, value: u32) -> bool {
+    bits & (1 << value) != 0
+}
+
+impl Cron {
+    fn matches_day(&self, time: NaiveDateTime) -> bool {
+        let day = has(self.days, time.day());
+        let weekday = has(self.weekdays, time.weekday().num_days_from_sunday());
+        has(self.months, time.month())
+            && if self.any_day || self.any_weekday {
+                day && weekday
+            } else {
+                day || weekday
+            }
+    }
+
+    /// Whether the schedule runs in the minute of `time`
+    pub fn matches(&self, time: NaiveDateTime) -> bool {
+        self.matches_day(time) && has(self.hours, time.hour()) && has(self.minutes, time.minute())
+    }
+
+    /// The first minute after `after` the schedule runs in, if it ever does
+    pub fn next(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut time = truncate(after) + Duration::minutes(1);
+        let horizon = time + Duration::days(HORIZON_DAYS);
+        while time < horizon {
+            if !self.matches_day(time) {
+                time = time.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !has(self.hours, time.hour()) {
+                time = time.with_minute(0)? + Duration::hours(1);
+            } else if !has(self.minutes, time.minute()) {
+                time += Duration::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_cron() {
+        let nightly: Cron = "0 3 * * *".parse().unwrap();
+        assert!(nightly.matches(at("2025-03-10 03:00")));
+        assert!(!nightly.matches(at("2025-03-10 03:01")));
+        assert_eq!(
+            nightly.next(at("2025-03-10 03:00")),
+            Some(at("2025-03-11 03:00"))
+        );
+
+        let weekdays: Cron = "*/15 9-17 * * mon-fri".parse().unwrap();
+        // 2025-03-08 is a Saturday
+        assert_eq!(
+            weekdays.next(at("2025-03-07 17:50")),
+            Some(at("2025-03-10 09:00"))
+        );
+        assert!(weekdays.matches(at("2025-03-10 12:45")));
+        assert!(!weekdays.matches(at("2025-03-10 12:50")));
+
+        // Either the day of the month or the day of the week
+        let either: Cron = "30 2 1 * 0".parse().unwrap();
+        assert!(either.matches(at("2025-03-01 02:30")));
+        assert!(either.matches(at("2025-03-02 02:30")));
+        assert!(!either.matches(at("2025-03-03 02:30")));
+        let sunday: Cron = "0 0 * * 7".parse().unwrap();
+        assert!(sunday.matches(at("2025-03-02 00:00")));
+
+        assert_eq!(
+            "@daily".parse::<Cron>().unwrap(),
+            "0 0 * * *".parse().unwrap()
+        );
+        assert_eq!(
+            "0 0 30 feb *"
+                .parse::<Cron>()
+                .unwrap()
+                .next(at("2025-01-01 00:00")),
+            None
+        );
+        assert!("0 3 * *".parse::<Cron>().is_err());
+        assert!("60 * * * *".parse::<Cron>().is_err());
+        assert!("*/0 * * * *".parse::<Cron>().is_err());
+        assert!("0 5-3 * * *".parse::<Cron>().is_err());
+    }
+}
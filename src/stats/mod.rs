@@ -0,0 +1,417 @@
+use crate::build::{BuildJob, BuildStatus};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::{Sqlite, SqlitePool};
+use std::{
+    collections::{BTreeMap, HashSet},
+    ops::Add,
+};
+
+/// Statuses of builds that ran and did not succeed
+const BUILD_FAILED: &str = "('failed', 'timed out')";
+
+/// Time span statistics are computed over, ending now
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Window {
+    Day,
+    Week,
+    #[default]
+    Month,
+    Quarter,
+}
+
+impl Window {
+    pub const ALL: [Window; 4] = [Window::Day, Window::Week, Window::Month, Window::Quarter];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Window::Day => "day",
+            Window::Week => "week",
+            Window::Month => "month",
+            Window::Quarter => "quarter",
+        }
+    }
+
+    pub fn secs(self) -> i64 {
+        match self {
+            Window::Day => 86400,
+            Window::Week => 7 * 86400,
+            Window::Month => 30 * 86400,
+            Window::Quarter => 91 * 86400,
+        }
+    }
+
+    /// Length of the points of its trends: hours of a day, weeks of a quarter,
+    /// days otherwise
+    pub fn bucket_secs(self) -> i64 {
+        match self {
+            Window::Day => 3600,
+            Window::Week | Window::Month => 86400,
+            Window::Quarter => 7 * 86400,
+        }
+    }
+}
+
+/// Sums over finished workflows and builds, as aggregated by the database
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Counts {
+    workflows_completed: i64,
+    workflows_failed: i64,
+    workflow_secs: i64,
+    builds_succeeded: i64,
+    builds_failed: i64,
+    builds_cached: i64,
+    build_secs: i64,
+    /// Builds that were seen becoming ready, and their total wait for a builder
+    waited: i64,
+    wait_secs: i64,
+}
+
+impl Add for Counts {
+    type Output = Counts;
+
+    fn add(self, other: Counts) -> Counts {
+        Counts {
+            workflows_completed: self.workflows_completed + other.workflows_completed,
+            workflows_failed: self.workflows_failed + other.workflows_failed,
+            workflow_secs: self.workflow_secs + other.workflow_secs,
+            builds_succeeded: self.builds_succeeded + other.builds_succeeded,
+            builds_failed: self.builds_failed + other.builds_failed,
+            builds_cached: self.builds_cached + other.builds_cached,
+            build_secs: self.build_secs + other.build_secs,
+            waited: self.waited + other.waited,
+            wait_secs: self.wait_secs + other.wait_secs,
+        }
+    }
+}
+
+fn ratio(part: i64, total: i64) -> Option<f64> {
+    (total > 0).then(|| part as f64 / total as f64)
+}
+
+/// Statistics of workflows and builds that finished in a time span; rates and
+/// averages are null when there is nothing to compute them from
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Stats {
+    /// Workflows that completed or failed; canceled ones are left out
+    pub workflows: i64,
+    /// Share of those workflows that completed
+    pub success_rate: Option<f64>,
+    /// From creation to the end of the last stage
+    pub avg_workflow_secs: Option<f64>,
+    /// Builds that succeeded, failed or were found in the cache
+    pub builds: i64,
+    /// Share of the builds that ran that succeeded
+    pub build_success_rate: Option<f64>,
+    /// Share of the builds found in the cache
+    pub cache_hit_rate: Option<f64>,
+    /// Of the builds that ran
+    pub avg_build_secs: Option<f64>,
+    /// From a build being ready to it starting on a builder
+    pub avg_queue_wait_secs: Option<f64>,
+}
+
+impl From<Counts> for Stats {
+    fn from(counts: Counts) -> Self {
+        let workflows = counts.workflows_completed + counts.workflows_failed;
+        let ran = counts.builds_succeeded + counts.builds_failed;
+        let builds = ran + counts.builds_cached;
+        Stats {
+            workflows,
+            success_rate: ratio(counts.workflows_completed, workflows),
+            avg_workflow_secs: ratio(counts.workflow_secs, workflows),
+            builds,
+            build_success_rate: ratio(counts.builds_succeeded, ran),
+            cache_hit_rate: ratio(counts.builds_cached, builds),
+            avg_build_secs: ratio(counts.build_secs, ran),
+            avg_queue_wait_secs: ratio(counts.wait_secs, counts.waited),
+        }
+    }
+}
+
+/// Statistics of the time span starting at `start`
+#[derive(Debug, Serialize)]
+pub struct Point {
+    pub start: i64,
+    #[serde(flatten)]
+    pub stats: Stats,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RepositoryStats {
+    pub repository: String,
+    #[serde(flatten)]
+    pub stats: Stats,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub window: Window,
+    pub since: i64,
+    /// Only workflows of this repository, and the builds they requested
+    pub repository: Option<String>,
+    pub total: Stats,
+    /// One point every `bucket_secs`, oldest first
+    pub bucket_secs: i64,
+    pub trend: Vec<Point>,
+    /// Statistics of each repository, when not computed for a single one
+    pub repositories: Vec<RepositoryStats>,
+}
+
+/// Record how a build went; cached builds are finished as they start
+pub async fn record_build(
+    db_pool: &SqlitePool,
+    job: &BuildJob,
+    status: BuildStatus,
+    started_at: i64,
+    finished_at: i64,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO build_runs (drv_path, status, ready_at, started_at, finished_at)
+        VALUES (?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(&job.derivation.drv_path)
+    .bind(status.to_string())
+    .bind(job.ready_at)
+    .bind(started_at)
+    .bind(finished_at)
+    .execute(db_pool)
+    .await?;
+    Ok(())
+}
+
+/// What counts are grouped by
+#[derive(Clone, Copy)]
+enum Group {
+    /// Periods of this many seconds
+    Bucket(i64),
+    Repository,
+}
+
+/// Counts of workflows and builds finished since `since`, by group, leaving out the
+/// repositories of the `hidden` JSON array and the builds only they requested
+async fn counts<K>(
+    db_pool: &SqlitePool,
+    since: i64,
+    repository: Option<&str>,
+    hidden: &str,
+    group: Group,
+) -> Result<BTreeMap<K, Counts>>
+where
+    K: for<'r> sqlx::Decode<'r, Sqlite> + sqlx::Type<Sqlite> + Ord + Send + Unpin,
+{
+    let (workflow_key, build_key) = match group {
+        Group::Bucket(secs) => (
+            format!("(finished_at / {0}) * {0}", secs),
+            format!("(r.finished_at / {0}) * {0}", secs),
+        ),
+        Group::Repository => ("repository".to_string(), "rw.repository".to_string()),
+    };
+    let filter = if repository.is_some() {
+        "AND repository = ?3"
+    } else {
+        ""
+    };
+    let mut counts: BTreeMap<K, Counts> = BTreeMap::new();
+
+    let sql = format!(
+        r#"
+        SELECT {}, SUM(status = 'Completed'), SUM(status = 'Failed'),
+               SUM(finished_at - created_at)
+        FROM workflows
+        WHERE finished_at >= ?1 AND status IN ('Completed', 'Failed')
+          AND repository NOT IN (SELECT value FROM json_each(?2)) {}
+        GROUP BY 1
+        "#,
+        workflow_key, filter
+    );
+    let mut query = sqlx::query_as::<_, (K, i64, i64, i64)>(&sql)
+        .bind(since)
+        .bind(hidden);
+    if let Some(repository) = repository {
+        query = query.bind(repository);
+    }
+    let workflows = query.fetch_all(db_pool).await?;
+    for (key, completed, failed, secs) in workflows {
+        let entry = counts.entry(key).or_default();
+        entry.workflows_completed = completed;
+        entry.workflows_failed = failed;
+        entry.workflow_secs = secs;
+    }
+
+    // Builds count for every repository whose workflows requested them
+    let join = if repository.is_some() || matches!(group, Group::Repository) {
+        r#"
+        JOIN (
+            SELECT DISTINCT bw.drv_path, w.repository
+            FROM build_workflows bw JOIN workflows w ON w.id = bw.workflow_id
+        ) rw ON rw.drv_path = r.drv_path
+        "#
+    } else {
+        ""
+    };
+    let visible = if join.is_empty() {
+        r#"
+        AND (NOT EXISTS (
+                SELECT 1 FROM build_workflows bw JOIN workflows w ON w.id = bw.workflow_id
+                WHERE bw.drv_path = r.drv_path)
+            OR EXISTS (
+                SELECT 1 FROM build_workflows bw JOIN workflows w ON w.id = bw.workflow_id
+                WHERE bw.drv_path = r.drv_path
+                  AND w.repository NOT IN (SELECT value FROM json_each(?2))))
+        "#
+    } else {
+        "AND rw.repository NOT IN (SELECT value FROM json_each(?2))"
+    };
+    let filter = if repository.is_some() {
+        "AND rw.repository = ?3"
+    } else {
+        ""
+    };
+    let sql = format!(
+        r#"
+        SELECT {key}, SUM(r.status = 'success'), SUM(r.status IN {failed}),
+               SUM(r.status = 'cached'),
+               COALESCE(SUM(CASE WHEN r.status = 'success' OR r.status IN {failed}
+                   THEN r.finished_at - r.started_at END), 0),
+               COUNT(r.ready_at), COALESCE(SUM(r.started_at - r.ready_at), 0)
+        FROM build_runs r {join}
+        WHERE r.finished_at >= ?1 AND r.status != 'canceled' {visible} {filter}
+        GROUP BY 1
+        "#,
+        key = build_key,
+        failed = BUILD_FAILED,
+        join = join,
+        visible = visible,
+        filter = filter,
+    );
+    let mut query = sqlx::query_as::<_, (K, i64, i64, i64, i64, i64, i64)>(&sql)
+        .bind(since)
+        .bind(hidden);
+    if let Some(repository) = repository {
+        query = query.bind(repository);
+    }
+    let builds = query.fetch_all(db_pool).await?;
+    for (key, succeeded, failed, cached, secs, waited, wait_secs) in builds {
+        let entry = counts.entry(key).or_default();
+        entry.builds_succeeded = succeeded;
+        entry.builds_failed = failed;
+        entry.builds_cached = cached;
+        entry.build_secs = secs;
+        entry.waited = waited;
+        entry.wait_secs = wait_secs;
+    }
+    Ok(counts)
+}
+
+/// Points every `bucket_secs` from the one holding `since` to the one holding `now`,
+/// including those without anything finished
+fn trend(since: i64, now: i64, bucket_secs: i64, counts: &BTreeMap<i64, Counts>) -> Vec<Point> {
+    let first = since / bucket_secs * bucket_secs;
+    (first..=now)
+        .step_by(bucket_secs as usize)
+        .map(|start| Point {
+            start,
+            stats: counts.get(&start).copied().unwrap_or_default().into(),
+        })
+        .collect()
+}
+
+/// Statistics of the workflows and builds that finished within a window, of a
+/// single repository or of all of them but the `hidden` ones
+pub async fn report(
+    db_pool: &SqlitePool,
+    window: Window,
+    repository: Option<&str>,
+    hidden: &HashSet<String>,
+) -> Result<Report> {
+    let now = chrono::Utc::now().timestamp();
+    let since = now - window.secs();
+    let hidden = serde_json::to_string(hidden)?;
+
+    let buckets: BTreeMap<i64, Counts> = counts(
+        db_pool,
+        since,
+        repository,
+        &hidden,
+        Group::Bucket(window.bucket_secs()),
+    )
+    .await?;
+    let total = buckets
+        .values()
+        .fold(Counts::default(), |total, &counts| total + counts);
+    let repositories = match repository {
+        Some(_) => Vec::new(),
+        None => counts::<String>(db_pool, since, None, &hidden, Group::Repository)
+            .await?
+            .into_iter()
+            .map(|(repository, counts)| RepositoryStats {
+                repository,
+                stats: counts.into(),
+            })
+            .collect(),
+    };
+
+    Ok(Report {
+        window,
+        since,
+        repository: repository.map(str::to_string),
+        total: total.into(),
+        bucket_secs: window.bucket_secs(),
+        trend: trend(since, now, window.bucket_secs(), &buckets),
+        repositories,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let counts = Counts {
+            workflows_completed: 3,
+            workflows_failed: 1,
+            workflow_secs: 400,
+            builds_succeeded: 6,
+            builds_failed: 2,
+            builds_cached: 2,
+            build_secs: 80,
+            waited: 5,
+            wait_secs: 50,
+        };
+        let stats = Stats::from(counts);
+        assert_eq!(stats.workflows, 4);
+        assert_eq!(stats.success_rate, Some(0.75));
+        assert_eq!(stats.avg_workflow_secs, Some(100.0));
+        assert_eq!(stats.builds, 10);
+        assert_eq!(stats.build_success_rate, Some(0.75));
+        assert_eq!(stats.cache_hit_rate, Some(0.2));
+        assert_eq!(stats.avg_build_secs, Some(10.0));
+        assert_eq!(stats.avg_queue_wait_secs, Some(10.0));
+
+        let empty = Stats::from(Counts::default());
+        assert_eq!(empty.success_rate, None);
+        assert_eq!(empty.avg_queue_wait_secs, None);
+    }
+
+    #[test]
+    fn test_trend() {
+        let mut counts = BTreeMap::new();
+        counts.insert(
+            7200,
+            Counts {
+                builds_cached: 1,
+                ..Default::default()
+            },
+        );
+        let trend = trend(3700, 10000, 3600, &counts);
+        let starts: Vec<i64> = trend.iter().map(|point| point.start).collect();
+        assert_eq!(starts, vec![3600, 7200]);
+        assert_eq!(trend[0].stats.builds, 0);
+        assert_eq!(trend[1].stats.cache_hit_rate, Some(1.0));
+    }
+}
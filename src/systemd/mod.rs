@@ -0,0 +1,75 @@
+use anyhow::{Context, Result};
+use sd_notify::NotifyState;
+use std::{
+    net::TcpListener,
+    os::fd::{FromRawFd, RawFd},
+    sync::Once,
+    time::Duration,
+};
+use tracing::{info, warn};
+
+/// The listening socket passed by systemd socket activation, if any.
+///
+/// Only the first socket is used; a `.socket` unit with a single
+/// `ListenStream=` is expected.
+pub fn listener() -> Result<Option<TcpListener>> {
+    let mut fds = sd_notify::listen_fds().context("Invalid systemd socket activation")?;
+    let Some(fd) = fds.next() else {
+        return Ok(None);
+    };
+    if fds.next().is_some() {
+        warn!("systemd passed several sockets, only the first one is used");
+    }
+
+    // SAFETY: systemd hands over ownership of the descriptors starting at fd 3
+    let listener = unsafe { TcpListener::from_raw_fd(fd as RawFd) };
+    listener
+        .set_nonblocking(true)
+        .context("Failed to configure the socket passed by systemd")?;
+    Ok(Some(listener))
+}
+
+/// Tell systemd the server accepts connections and start the watchdog keep-alive.
+/// Does nothing when not running under a `Type=notify` unit. Standby instances call
+/// it early, as they may wait for the leader lease longer than any start timeout;
+/// the watchdog is only started once.
+pub fn ready() {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        warn!("Failed to notify systemd of readiness: {}", e);
+    }
+
+    static WATCHDOG: Once = Once::new();
+    WATCHDOG.call_once(start_watchdog);
+}
+
+fn start_watchdog() {
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        // Ping at twice the required rate so a late wake-up does not trip the watchdog
+        let period = Duration::from_micros(usec) / 2;
+        info!("systemd watchdog enabled, pinging every {:?}", period);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                if let Err(e) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+                    warn!("Failed to ping the systemd watchdog: {}", e);
+                }
+            }
+        });
+    }
+}
+
+/// Show what the server is doing in `systemctl status`
+pub fn status(status: &str) {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Status(status)]) {
+        warn!("Failed to notify systemd of the status: {}", e);
+    }
+}
+
+/// Tell systemd the server is shutting down
+pub fn stopping() {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Stopping]) {
+        warn!("Failed to notify systemd of shutdown: {}", e);
+    }
+}
@@ -0,0 +1,437 @@
+use crate::{cache::CacheClient, config::BuildConfig, gc::GcRoots};
+use anyhow::{anyhow, Context, Result};
+use sqlx::SqlitePool;
+use std::{
+    collections::BTreeSet,
+    fmt, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{sync::Semaphore, time::Duration};
+use tracing::{info, info_span, warn, Instrument};
+
+/// Longest wait before retrying an upload
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(3600);
+/// Time between two looks at the paths the post-build hook queued
+const HOOK_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Where the upload of a successful build's outputs stands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UploadStatus {
+    /// Waiting for a free upload slot, or for its next attempt
+    Pending,
+    Uploading,
+    Done,
+    /// Every attempt failed
+    Failed,
+}
+
+impl fmt::Display for UploadStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UploadStatus::Pending => "pending",
+            UploadStatus::Uploading => "uploading",
+            UploadStatus::Done => "done",
+            UploadStatus::Failed => "failed",
+        })
+    }
+}
+
+/// Uploads the outputs of successful builds to the cache in the background, with
+/// their own concurrency limit so slow uploads do not hold build slots
+pub struct UploadQueue {
+    db_pool: SqlitePool,
+    cache_client: CacheClient,
+    gc_roots: Arc<GcRoots>,
+    semaphore: Semaphore,
+    /// Times a failed upload is attempted again
+    max_retries: u32,
+    /// Wait before the first retry, doubled for each further one
+    retry_backoff: Duration,
+    /// Queues every path nix builds for upload, if enabled
+    hook: Option<PostBuildHook>,
+}
+
+impl UploadQueue {
+    pub fn new(
+        db_pool: SqlitePool,
+        cache_client: CacheClient,
+        gc_roots: Arc<GcRoots>,
+        config: &BuildConfig,
+    ) -> Self {
+        Self {
+            db_pool,
+            cache_client,
+            gc_roots,
+            semaphore: Semaphore::new(config.max_concurrent_uploads),
+            max_retries: config.upload_retries,
+            retry_backoff: Duration::from_secs(config.retry_backoff_secs),
+            hook: None,
+        }
+    }
+
+    /// Also upload the paths nix builds on the way to the outputs, as the hook queues them
+    pub fn with_post_build_hook(mut self, hook: PostBuildHook) -> Self {
+        self.hook = Some(hook);
+        self
+    }
+
+    /// The script for nix's `post-build-hook` setting, if enabled
+    pub fn post_build_hook(&self) -> Option<&Path> {
+        self.hook.as_ref().map(|hook| hook.script.as_path())
+    }
+
+    /// Upload the paths the post-build hook queues, until the process stops
+    pub async fn watch_hook(self: Arc<Self>) {
+        let Some(hook) = &self.hook else {
+            return;
+        };
+        let mut interval = tokio::time::interval(HOOK_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            for (drv_path, paths) in hook.take_queued() {
+                let uploads = self.clone();
+                let span = info_span!("upload", drv = %drv_path);
+                tokio::spawn(
+                    async move { uploads.run(&drv_path, Some(&paths)).await }.instrument(span),
+                );
+            }
+        }
+    }
+
+    /// Queue the upload of a successful build's outputs; their GC roots are released
+    /// once it is done, unless recent workflows need them
+    pub async fn enqueue(self: &Arc<Self>, drv_path: &str) {
+        self.set_status(drv_path, UploadStatus::Pending, None).await;
+        self.start(drv_path);
+    }
+
+    /// Queue the upload of a successful build again, unless it is queued already;
+    /// whether it was queued
+    pub async fn reupload(self: &Arc<Self>, drv_path: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE builds SET upload_status = ?, upload_error = NULL
+            WHERE drv_path = ? AND status = 'success'
+                AND (upload_status IS NULL OR upload_status IN (?, ?))
+            "#,
+        )
+        .bind(UploadStatus::Pending.to_string())
+        .bind(drv_path)
+        .bind(UploadStatus::Done.to_string())
+        .bind(UploadStatus::Failed.to_string())
+        .execute(&self.db_pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        self.start(drv_path);
+        Ok(true)
+    }
+
+    fn start(self: &Arc<Self>, drv_path: &str) {
+        let uploads = self.clone();
+        let drv_path = drv_path.to_string();
+        let span = info_span!("upload", drv = %drv_path);
+        tokio::spawn(async move { uploads.run(&drv_path, None).await }.instrument(span));
+    }
+
+    /// Queue the uploads that were pending or running when the server stopped; their
+    /// outputs kept their GC roots meanwhile
+    pub async fn resume(self: &Arc<Self>) {
+        let pending = match sqlx::query_scalar::<_, String>(
+            "SELECT drv_path FROM builds WHERE upload_status IN (?, ?)",
+        )
+        .bind(UploadStatus::Pending.to_string())
+        .bind(UploadStatus::Uploading.to_string())
+        .fetch_all(&self.db_pool)
+        .await
+        {
+            Ok(pending) => pending,
+            Err(e) => {
+                warn!("Failed to look up interrupted uploads: {}", e);
+                return;
+            }
+        };
+        if !pending.is_empty() {
+            info!("Resuming {} interrupted uploads", pending.len());
+        }
+        for drv_path in pending {
+            self.enqueue(&drv_path).await;
+        }
+    }
+
+    /// Upload the outputs of a build, tracking the upload's status, or else `paths`
+    /// the post-build hook queued while building `drv_path`
+    async fn run(&self, drv_path: &str, paths: Option<&[String]>) {
+        let track = paths.is_none();
+        let mut attempt = 0;
+        let status = loop {
+            let result = {
+                let _permit = self.semaphore.acquire().await.expect("never closed");
+                if track {
+                    self.set_status(drv_path, UploadStatus::Uploading, None)
+                        .await;
+                }
+                let _upload = self.gc_roots.upload_started();
+                match paths {
+                    None => self.upload(drv_path).await,
+                    Some(paths) => self.upload_paths(drv_path, paths).await,
+                }
+            };
+            match result {
+                Ok(()) => break UploadStatus::Done,
+                Err(e) if attempt < self.max_retries => {
+                    attempt += 1;
+                    let backoff = self.retry_backoff(attempt);
+                    warn!(
+                        "Failed to upload {} to cache, retrying in {}s ({} of {} retries): {:#}",
+                        drv_path,
+                        backoff.as_secs(),
+                        attempt,
+                        self.max_retries,
+                        e
+                    );
+                    if track {
+                        self.set_status(drv_path, UploadStatus::Pending, Some(&e))
+                            .await;
+                    }
+                    tokio::time::sleep(backoff).await;
+                }
+                Err(e) => {
+                    warn!("Failed to upload {} to cache: {:#}", drv_path, e);
+                    if track {
+                        self.set_status(drv_path, UploadStatus::Failed, Some(&e))
+                            .await;
+                    }
+                    break UploadStatus::Failed;
+                }
+            }
+        };
+        if track && status == UploadStatus::Done {
+            self.set_status(drv_path, status, None).await;
+            // The outputs only need a root until they are uploaded, or while recent
+            // workflows may need them
+            self.gc_roots.build_finished(drv_path, true);
+        }
+        // Those of failed uploads stay for a re-upload, until the collector prunes them
+    }
+
+    /// Upload build outputs to the cache of every organization that requested them
+    async fn upload(&self, drv_path: &str) -> Result<()> {
+        info!("Uploading {} to cache", drv_path);
+
+        // Query the outputs of the derivation
+        let output = tokio::process::Command::new("nix-store")
+            .args(["--query", "--outputs", drv_path])
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            return Err(anyhow!("Failed to query derivation outputs"));
+        }
+
+        let outputs_str = String::from_utf8(output.stdout)?;
+        let outputs: Vec<String> = outputs_str
+            .lines()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        // Unsigned outputs are still useful to clients not requiring signatures
+        let signed = match self.cache_client.sign_outputs(&outputs).await {
+            Ok(signed) => signed,
+            Err(e) => {
+                warn!("Failed to sign the outputs of {}: {:#}", drv_path, e);
+                false
+            }
+        };
+        sqlx::query("UPDATE builds SET signed = ? WHERE drv_path = ?")
+            .bind(signed)
+            .bind(drv_path)
+            .execute(&self.db_pool)
+            .await?;
+
+        // Upload each output
+        for cache_name in self.organization_caches(drv_path).await {
+            self.cache_client
+                .upload_derivation_outputs(&outputs, cache_name.as_deref())
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Upload paths the post-build hook queued to the configured cache, signed like
+    /// build outputs
+    async fn upload_paths(&self, drv_path: &str, paths: &[String]) -> Result<()> {
+        info!(
+            "Uploading the {} paths nix built for {}",
+            paths.len(),
+            drv_path
+        );
+        if let Err(e) = self.cache_client.sign_outputs(paths).await {
+            warn!("Failed to sign the outputs of {}: {:#}", drv_path, e);
+        }
+        self.cache_client
+            .upload_derivation_outputs(paths, None)
+            .await
+    }
+
+    /// The caches of the organizations of the workflows that requested a build; `None`
+    /// stands for the configured cache
+    async fn organization_caches(&self, drv_path: &str) -> BTreeSet<Option<String>> {
+        let caches = sqlx::query_scalar::<_, Option<String>>(
+            r#"
+            SELECT o.attic_cache_name FROM build_workflows bw
+            JOIN workflows w ON w.id = bw.workflow_id
+            LEFT JOIN repositories r ON r.full_name = w.repository
+            LEFT JOIN projects p ON p.id = r.project_id
+            LEFT JOIN organizations o ON o.id = p.organization_id
+            WHERE bw.drv_path = ?
+            "#,
+        )
+        .bind(drv_path)
+        .fetch_all(&self.db_pool)
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to look up the caches of {}: {}", drv_path, e);
+            Vec::new()
+        });
+        let mut caches: BTreeSet<_> = caches.into_iter().collect();
+        if caches.is_empty() {
+            caches.insert(None);
+        }
+        caches
+    }
+
+    async fn set_status(
+        &self,
+        drv_path: &str,
+        status: UploadStatus,
+        error: Option<&anyhow::Error>,
+    ) {
+        if let Err(e) =
+            sqlx::query("UPDATE builds SET upload_status = ?, upload_error = ? WHERE drv_path = ?")
+                .bind(status.to_string())
+                .bind(error.map(|e| format!("{:#}", e)))
+                .bind(drv_path)
+                .execute(&self.db_pool)
+                .await
+        {
+            warn!(
+                "Failed to record the upload status of {} in database: {}",
+                drv_path, e
+            );
+        }
+    }
+
+    fn retry_backoff(&self, retries: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retries.saturating_sub(1));
+        self.retry_backoff
+            .checked_mul(factor)
+            .map_or(MAX_RETRY_BACKOFF, |backoff| backoff.min(MAX_RETRY_BACKOFF))
+    }
+}
+
+/// A nix post-build-hook queueing the outputs of every derivation nix builds, as
+/// files named after the derivation in a spool directory
+pub struct PostBuildHook {
+    script: PathBuf,
+    spool: PathBuf,
+}
+
+impl PostBuildHook {
+    /// Write the hook script and its spool directory into `dir`
+    pub fn install(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir.join("queue")).with_context(|| {
+            format!(
+                "Failed to create post-build hook directory {}",
+                dir.display()
+            )
+        })?;
+        // nix runs the hook from elsewhere
+        let dir = dir.canonicalize()?;
+        let spool = dir.join("queue");
+        let script = dir.join("post-build-hook");
+        fs::write(&script, hook_script(&spool))
+            .with_context(|| format!("Failed to write {}", script.display()))?;
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755))?;
+        Ok(Self { script, spool })
+    }
+
+    /// Take the queued derivations with their outputs out of the spool directory
+    fn take_queued(&self) -> Vec<(String, Vec<String>)> {
+        let entries = match fs::read_dir(&self.spool) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to read {}: {}", self.spool.display(), e);
+                return Vec::new();
+            }
+        };
+        let mut queued = Vec::new();
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            // Still being written by the hook
+            if name.starts_with('.') {
+                continue;
+            }
+            let paths = fs::read_to_string(entry.path());
+            if let Err(e) = fs::remove_file(entry.path()) {
+                warn!("Failed to remove {}: {}", entry.path().display(), e);
+                continue;
+            }
+            match paths {
+                Ok(paths) => queued.push((
+                    format!("/nix/store/{}", name),
+                    paths.split_whitespace().map(str::to_string).collect(),
+                )),
+                Err(e) => warn!("Failed to read {}: {}", entry.path().display(), e),
+            }
+        }
+        queued
+    }
+}
+
+/// nix sets DRV_PATH and OUT_PATHS for the hook, and waits for it before building on
+fn hook_script(spool: &Path) -> String {
+    format!(
+        r#"#!/bin/sh
+# Written by icicle: queues the paths nix built for upload
+set -eu
+name="$(basename "$DRV_PATH")"
+echo $OUT_PATHS > "{spool}/.$name.$$"
+mv "{spool}/.$name.$$" "{spool}/$name"
+"#,
+        spool = spool.display()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_post_build_hook() {
+        let dir = tempfile::tempdir().unwrap();
+        let hook = PostBuildHook::install(&dir.path().join("hook")).unwrap();
+        let status = std::process::Command::new(&hook.script)
+            .env("DRV_PATH", "/nix/store/abc-hello.drv")
+            .env("OUT_PATHS", "/nix/store/def-hello /nix/store/ghi-hello-man")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(
+            hook.take_queued(),
+            [(
+                "/nix/store/abc-hello.drv".to_string(),
+                vec![
+                    "/nix/store/def-hello".to_string(),
+                    "/nix/store/ghi-hello-man".to_string()
+                ]
+            )]
+        );
+        assert!(hook.take_queued().is_empty());
+    }
+}
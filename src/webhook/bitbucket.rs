@@ -0,0 +1,328 @@
+use super::{
+    check_quota, check_repository, create_workflow, deliveries, read_body, verify_signature,
+    Provider, WorkflowOptions,
+};
+use crate::api::ApiError;
+use axum::{
+    extract::{Request, State},
+    http::HeaderMap,
+    response::Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+#[derive(Debug, Deserialize)]
+pub struct BitbucketWebhook {
+    pub repository: BitbucketRepository,
+    pub push: Option<BitbucketPush>, // for repo:push events
+    pub pullrequest: Option<BitbucketPullRequest>, // for pullrequest:* events
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BitbucketRepository {
+    pub full_name: String,
+    pub links: BitbucketLinks,
+}
+
+impl BitbucketRepository {
+    /// Bitbucket payloads carry no clone URL, but it follows from the web URL
+    fn clone_url(&self) -> String {
+        format!("{}.git", self.links.html.href.trim_end_matches('/'))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BitbucketLinks {
+    pub html: BitbucketLink,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BitbucketLink {
+    pub href: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BitbucketPush {
+    pub changes: Vec<BitbucketChange>,
+}
+
+/// One ref updated by a push; `new` is missing when the ref was deleted
+#[derive(Debug, Deserialize)]
+pub struct BitbucketChange {
+    pub new: Option<BitbucketRef>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BitbucketRef {
+    /// "branch" or "tag"
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub name: String,
+    pub target: BitbucketCommit,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BitbucketCommit {
+    pub hash: String,
+    pub author: Option<BitbucketAuthor>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BitbucketAuthor {
+    /// The git author, as in `Name <email>`
+    pub raw: String,
+}
+
+impl BitbucketAuthor {
+    fn email(&self) -> Option<&str> {
+        let (_, rest) = self.raw.split_once('<')?;
+        let (email, _) = rest.split_once('>')?;
+        Some(email.trim()).filter(|email| !email.is_empty())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BitbucketPullRequest {
+    pub id: u64,
+    pub source: BitbucketPREndpoint,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BitbucketPREndpoint {
+    /// Only carries an abbreviated hash
+    pub commit: BitbucketPRCommit,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BitbucketPRCommit {
+    pub hash: String,
+}
+
+pub(super) async fn handle_webhook(
+    State(app_state): State<Arc<crate::AppState>>,
+    headers: HeaderMap,
+    request: Request,
+) -> Result<Json<Value>, ApiError> {
+    let body = read_body(request).await?;
+
+    // Bitbucket signs with the same scheme as GitHub, in a differently named header
+    if let Some(secret) = &app_state.webhook_config().secret {
+        verify_signature(&headers, "X-Hub-Signature", &body, secret)?
+    } else {
+        warn!("Webhook secret not configured - signature verification skipped");
+    }
+
+    deliveries::deliver(&app_state, Provider::Bitbucket, &headers, &body, None)
+        .await
+        .1
+}
+
+/// Handle a Bitbucket delivery whose signature was verified
+pub(super) async fn process_webhook(
+    app_state: &Arc<crate::AppState>,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Json<Value>, ApiError> {
+    let event_key = headers
+        .get("X-Event-Key")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("unknown");
+
+    info!("Received Bitbucket webhook: {}", event_key);
+
+    let webhook: BitbucketWebhook = serde_json::from_slice(body).map_err(|e| {
+        error!("Failed to parse webhook JSON: {}", e);
+        ApiError::bad_request(format!("Failed to parse webhook JSON: {}", e))
+    })?;
+
+    match event_key {
+        "repo:push" => handle_push_event(app_state, &webhook).await,
+        "pullrequest:created" | "pullrequest:updated" => {
+            handle_pull_request_event(app_state, &webhook, event_key).await
+        }
+        _ => {
+            info!("Ignoring event type: {}", event_key);
+            Ok(Json(json!({
+                "status": "ignored",
+                "message": format!("Event type '{}' is not handled", event_key)
+            })))
+        }
+    }
+}
+
+/// A push may update several refs at once; every updated branch gets a workflow,
+/// and every tag a release workflow
+async fn handle_push_event(
+    app_state: &Arc<crate::AppState>,
+    webhook: &BitbucketWebhook,
+) -> Result<Json<Value>, ApiError> {
+    let push = webhook.push.as_ref().ok_or_else(|| {
+        error!("Push event missing push data");
+        ApiError::bad_request("Push event missing push data")
+    })?;
+    let repository = &webhook.repository.full_name;
+    check_repository(app_state, repository)?;
+    let config = app_state.webhook_config();
+    let branches: Vec<&BitbucketRef> = push
+        .changes
+        .iter()
+        .filter_map(|change| change.new.as_ref())
+        .filter(|new| match new.kind.as_str() {
+            "tag" => true,
+            "branch" => config.builds_branch(repository, &new.name),
+            _ => false,
+        })
+        .collect();
+    if branches.is_empty() {
+        return Ok(Json(json!({
+            "status": "ignored",
+            "message": "Push updated no tags or branches that are built"
+        })));
+    }
+
+    let clone_url = webhook.repository.clone_url();
+    let mut workflows = Vec::new();
+    for branch in branches {
+        let commit_sha = &branch.target.hash;
+        let release = branch.kind == "tag";
+        info!(
+            "Processing push to {} {} {} commit {}",
+            repository, branch.kind, branch.name, commit_sha
+        );
+
+        let deprioritized = check_quota(app_state, repository).await?;
+        let workflow_id = create_workflow(
+            app_state,
+            repository,
+            commit_sha,
+            &branch.name,
+            &clone_url,
+            branch
+                .target
+                .author
+                .as_ref()
+                .and_then(BitbucketAuthor::email),
+            WorkflowOptions {
+                deprioritized,
+                release,
+                ..Default::default()
+            },
+        )
+        .await
+        .map_err(|e| {
+            error!("Failed to create workflow: {}", e);
+            ApiError::internal("Failed to create workflow")
+        })?;
+        workflows.push(json!({
+            "branch": branch.name,
+            "release": release,
+            "commit": commit_sha,
+            "workflow_id": workflow_id
+        }));
+    }
+
+    Ok(Json(json!({
+        "status": "processed",
+        "message": "Push event processed",
+        "repository": repository,
+        "workflows": workflows
+    })))
+}
+
+async fn handle_pull_request_event(
+    app_state: &Arc<crate::AppState>,
+    webhook: &BitbucketWebhook,
+    event_key: &str,
+) -> Result<Json<Value>, ApiError> {
+    let pr = webhook.pullrequest.as_ref().ok_or_else(|| {
+        error!("Pull request event missing pullrequest data");
+        ApiError::bad_request("Pull request event missing pullrequest data")
+    })?;
+    let repository = &webhook.repository.full_name;
+    check_repository(app_state, repository)?;
+    let commit_sha = &pr.source.commit.hash;
+
+    info!(
+        "Processing pull request {} ({}) for {}, commit {}",
+        pr.id, event_key, repository, commit_sha
+    );
+
+    let deprioritized = check_quota(app_state, repository).await?;
+    let workflow_id = create_workflow(
+        app_state,
+        repository,
+        commit_sha,
+        &format!("pr-{}", pr.id),
+        &webhook.repository.clone_url(),
+        None,
+        WorkflowOptions {
+            deprioritized,
+            ..Default::default()
+        },
+    )
+    .await
+    .map_err(|e| {
+        error!("Failed to create workflow: {}", e);
+        ApiError::internal("Failed to create workflow")
+    })?;
+
+    Ok(Json(json!({
+        "status": "processed",
+        "message": "Pull request event processed",
+        "repository": repository,
+        "pr_number": pr.id,
+        "action": event_key,
+        "commit": commit_sha,
+        "workflow_id": workflow_id
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_push() {
+        let webhook: BitbucketWebhook = serde_json::from_str(
+            r#"{
+              "actor": {"display_name": "Jane Doe"},
+              "repository": {
+                "name": "repo",
+                "full_name": "team/repo",
+                "links": {"html": {"href": "https://bitbucket.org/team/repo"}}
+              },
+              "push": {"changes": [
+                {
+                  "new": {
+                    "type": "branch",
+                    "name": "main",
+                    "target": {
+                      "type": "commit",
+                      "hash": "709d658dc5b6d6afcd46049c2f332ee3f515a67d",
+                      "author": {"raw": "Jane Doe <jane@example.org>"}
+                    }
+                  },
+                  "old": null
+                },
+                {"new": null, "old": {"type": "branch", "name": "gone"}}
+              ]}
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            webhook.repository.clone_url(),
+            "https://bitbucket.org/team/repo.git"
+        );
+        let changes = &webhook.push.unwrap().changes;
+        assert!(changes[1].new.is_none());
+        let new = changes[0].new.as_ref().unwrap();
+        assert_eq!(new.name, "main");
+        assert_eq!(
+            new.target.author.as_ref().and_then(BitbucketAuthor::email),
+            Some("jane@example.org")
+        );
+    }
+}
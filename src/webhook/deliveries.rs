@@ -0,0 +1,186 @@
+use crate::api::ApiError;
+use anyhow::{anyhow, Result};
+use axum::{
+    http::{header, HeaderMap, HeaderName, HeaderValue},
+    response::Json,
+};
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Where a webhook delivery came from
+#[derive(Debug, Clone, Copy)]
+pub enum Provider {
+    GitHub,
+    Bitbucket,
+}
+
+impl Provider {
+    fn parse(provider: &str) -> Option<Self> {
+        match provider {
+            "github" => Some(Provider::GitHub),
+            "bitbucket" => Some(Provider::Bitbucket),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Provider::GitHub => "github",
+            Provider::Bitbucket => "bitbucket",
+        }
+    }
+
+    fn event_header(self) -> &'static str {
+        match self {
+            Provider::GitHub => "X-GitHub-Event",
+            Provider::Bitbucket => "X-Event-Key",
+        }
+    }
+
+    fn delivery_header(self) -> &'static str {
+        match self {
+            Provider::GitHub => "X-GitHub-Delivery",
+            Provider::Bitbucket => "X-Request-UUID",
+        }
+    }
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|h| h.to_str().ok())
+}
+
+/// Store a verified delivery, handle it, then store how it was handled.
+/// Returns the ID of the stored event along with the response.
+pub(super) async fn deliver(
+    app_state: &Arc<crate::AppState>,
+    provider: Provider,
+    headers: &HeaderMap,
+    body: &[u8],
+    replay_of: Option<i64>,
+) -> (Option<i64>, Result<Json<Value>, ApiError>) {
+    // A delivery that cannot be stored is still handled
+    let id = match store(app_state, provider, headers, body, replay_of).await {
+        Ok(id) => Some(id),
+        Err(e) => {
+            warn!("Failed to store webhook event: {}", e);
+            None
+        }
+    };
+
+    let result = match provider {
+        Provider::GitHub => super::process_github_webhook(app_state, headers, body).await,
+        Provider::Bitbucket => super::bitbucket::process_webhook(app_state, headers, body).await,
+    };
+
+    if let Some(id) = id {
+        let (status_code, response) = match &result {
+            Ok(Json(response)) => (200, response.to_string()),
+            Err(e) => (e.status().as_u16(), e.to_string()),
+        };
+        if let Err(e) =
+            sqlx::query("UPDATE webhook_events SET status_code = ?, response = ? WHERE id = ?")
+                .bind(status_code)
+                .bind(response)
+                .bind(id)
+                .execute(&app_state.db_pool)
+                .await
+        {
+            warn!("Failed to store outcome of webhook event {}: {}", id, e);
+        }
+    }
+    (id, result)
+}
+
+async fn store(
+    app_state: &crate::AppState,
+    provider: Provider,
+    headers: &HeaderMap,
+    body: &[u8],
+    replay_of: Option<i64>,
+) -> Result<i64, sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+    let retention_days = app_state.webhook_config().event_retention_days;
+    if retention_days > 0 {
+        sqlx::query("DELETE FROM webhook_events WHERE received_at < ?")
+            .bind(now - retention_days as i64 * 24 * 3600)
+            .execute(&app_state.db_pool)
+            .await?;
+    }
+
+    // Webhooks carry no credentials, but a proxy in front could add some
+    let stored_headers: Map<String, Value> = headers
+        .iter()
+        .filter(|(name, _)| ![header::AUTHORIZATION, header::COOKIE].contains(name))
+        .filter_map(|(name, value)| Some((name.to_string(), json!(value.to_str().ok()?))))
+        .collect();
+    let id = sqlx::query(
+        r#"
+        INSERT INTO webhook_events
+            (provider, event, delivery_id, headers, payload, received_at, replay_of)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(provider.as_str())
+    .bind(header_str(headers, provider.event_header()).unwrap_or("unknown"))
+    .bind(header_str(headers, provider.delivery_header()))
+    .bind(Value::Object(stored_headers).to_string())
+    .bind(body)
+    .bind(now)
+    .bind(replay_of)
+    .execute(&app_state.db_pool)
+    .await?
+    .last_insert_rowid();
+    Ok(id)
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct StoredEvent {
+    provider: String,
+    headers: String,
+    payload: Vec<u8>,
+}
+
+/// Handle a stored event again, as a new event. Its signature was verified when
+/// it was first received.
+pub async fn replay(app_state: &Arc<crate::AppState>, id: i64) -> Result<Json<Value>, ApiError> {
+    let event = sqlx::query_as::<_, StoredEvent>(
+        "SELECT provider, headers, payload FROM webhook_events WHERE id = ?",
+    )
+    .bind(id)
+    .fetch_optional(&app_state.db_pool)
+    .await?
+    .ok_or_else(|| ApiError::not_found(format!("Webhook event {} not found", id)))?;
+    let provider = Provider::parse(&event.provider)
+        .ok_or_else(|| anyhow!("Unknown webhook provider {}", event.provider))?;
+    let headers = parse_headers(&event.headers)?;
+
+    info!("Replaying webhook event {}", id);
+    let (replay_id, result) =
+        deliver(app_state, provider, &headers, &event.payload, Some(id)).await;
+    let (status_code, response) = match result {
+        Ok(Json(response)) => (200, response),
+        Err(e) => (e.status().as_u16(), json!(e.to_string())),
+    };
+    Ok(Json(json!({
+        "event_id": replay_id,
+        "replay_of": id,
+        "status_code": status_code,
+        "response": response
+    })))
+}
+
+fn parse_headers(stored: &str) -> Result<HeaderMap> {
+    let stored: Map<String, Value> = serde_json::from_str(stored)?;
+    let mut headers = HeaderMap::new();
+    for (name, value) in stored {
+        let Some(value) = value.as_str() else {
+            continue;
+        };
+        headers.insert(
+            HeaderName::try_from(name.as_str())?,
+            HeaderValue::from_str(value)?,
+        );
+    }
+    Ok(headers)
+}
@@ -1,7 +1,15 @@
 use crate::{
+    api::ApiError,
     build::{Workflow, WorkflowStatus},
+    cache::CacheClient,
+    events::Event,
+    inputs,
     nix::NixEvaluator,
+    notify::matches_pattern,
+    pipeline,
+    quota::{self, QuotaAction, QuotaState},
 };
+use anyhow::anyhow;
 use axum::{
     extract::{Request, State},
     http::{HeaderMap, StatusCode},
@@ -11,10 +19,16 @@ use axum::{
 };
 use hmac::{Hmac, Mac};
 use serde::Deserialize;
-use serde_json::Value;
+use serde_json::{json, Value};
 use sha2::Sha256;
-use std::sync::Arc;
-use tracing::{error, info, warn};
+use std::{collections::HashMap, sync::Arc};
+use tracing::{error, info, info_span, warn, Instrument};
+
+mod bitbucket;
+mod deliveries;
+
+pub use deliveries::replay;
+use deliveries::Provider;
 
 type HmacSha256 = Hmac<Sha256>;
 
@@ -22,6 +36,42 @@ type HmacSha256 = Hmac<Sha256>;
 pub struct WebhookConfig {
     pub secret: Option<String>,
     pub attrset: String,
+    /// Evaluated instead of `attrset` by workflows triggered by tags
+    pub release_attrset: Option<String>,
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    pub branches: Vec<String>,
+    pub repository_branches: HashMap<String, Vec<String>>,
+    /// Days to keep deliveries for replaying; forever when 0
+    pub event_retention_days: u64,
+}
+
+/// Whether a repository matches "owner/repo", "owner/*" or "*", ignoring case
+/// like GitHub does
+pub(crate) fn matches(pattern: &str, repository: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => repository
+            .get(..prefix.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(prefix)),
+        None => pattern.eq_ignore_ascii_case(repository),
+    }
+}
+
+impl WebhookConfig {
+    /// Whether a repository may trigger workflows; the denylist wins over the allowlist
+    pub fn allows(&self, repository: &str) -> bool {
+        let listed = |patterns: &[String]| patterns.iter().any(|p| matches(p, repository));
+        (self.allow.is_empty() || listed(&self.allow)) && !listed(&self.deny)
+    }
+
+    /// Whether pushes to a branch of a repository start workflows
+    pub fn builds_branch(&self, repository: &str, branch: &str) -> bool {
+        let patterns = self
+            .repository_branches
+            .get(repository)
+            .unwrap_or(&self.branches);
+        patterns.is_empty() || patterns.iter().any(|p| matches_pattern(p, branch))
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -36,6 +86,7 @@ pub struct GitHubWebhook {
 }
 
 #[derive(Debug, Deserialize)]
+#[allow(dead_code)]
 pub struct GitRepository {
     pub name: String,
     pub full_name: String,
@@ -44,6 +95,7 @@ pub struct GitRepository {
 }
 
 #[derive(Debug, Deserialize)]
+#[allow(dead_code)]
 pub struct GitCommit {
     pub id: String,
     pub message: String,
@@ -51,6 +103,7 @@ pub struct GitCommit {
 }
 
 #[derive(Debug, Deserialize)]
+#[allow(dead_code)]
 pub struct GitAuthor {
     pub name: String,
     pub email: String,
@@ -68,29 +121,46 @@ pub struct GitPRRef {
 }
 
 pub fn routes() -> Router<Arc<crate::AppState>> {
-    Router::new().route("/webhook/github", post(handle_github_webhook))
+    Router::new()
+        .route("/webhook/github", post(handle_github_webhook))
+        .route("/webhook/bitbucket", post(bitbucket::handle_webhook))
+}
+
+async fn read_body(request: Request) -> Result<axum::body::Bytes, ApiError> {
+    axum::body::to_bytes(request.into_body(), usize::MAX)
+        .await
+        .map_err(|_| {
+            error!("Failed to read request body");
+            ApiError::bad_request("Failed to read request body")
+        })
 }
 
 async fn handle_github_webhook(
     State(app_state): State<Arc<crate::AppState>>,
     headers: HeaderMap,
     request: Request,
-) -> Result<Json<Value>, StatusCode> {
+) -> Result<Json<Value>, ApiError> {
     // Extract body for signature verification
-    let body = axum::body::to_bytes(request.into_body(), usize::MAX)
-        .await
-        .map_err(|_| {
-            error!("Failed to read request body");
-            StatusCode::BAD_REQUEST
-        })?;
+    let body = read_body(request).await?;
 
     // Verify GitHub webhook signature if secret is configured
-    if let Some(secret) = &app_state.webhook_config.secret {
-        verify_signature(&headers, &body, secret)?
+    if let Some(secret) = &app_state.webhook_config().secret {
+        verify_signature(&headers, "X-Hub-Signature-256", &body, secret)?
     } else {
         warn!("Webhook secret not configured - signature verification skipped");
     }
 
+    deliveries::deliver(&app_state, Provider::GitHub, &headers, &body, None)
+        .await
+        .1
+}
+
+/// Handle a GitHub delivery whose signature was verified
+async fn process_github_webhook(
+    app_state: &Arc<crate::AppState>,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Json<Value>, ApiError> {
     // Parse the GitHub event
     let event_type = headers
         .get("X-GitHub-Event")
@@ -100,15 +170,15 @@ async fn handle_github_webhook(
     info!("Received GitHub webhook: {}", event_type);
 
     // Parse JSON payload
-    let webhook: GitHubWebhook = serde_json::from_slice(&body).map_err(|e| {
+    let webhook: GitHubWebhook = serde_json::from_slice(body).map_err(|e| {
         error!("Failed to parse webhook JSON: {}", e);
-        StatusCode::BAD_REQUEST
+        ApiError::bad_request(format!("Failed to parse webhook JSON: {}", e))
     })?;
 
     // Process the webhook based on event type
     match event_type {
-        "push" => handle_push_event(&app_state, &webhook).await,
-        "pull_request" => handle_pull_request_event(&app_state, &webhook).await,
+        "push" => handle_push_event(app_state, &webhook).await,
+        "pull_request" => handle_pull_request_event(app_state, &webhook).await,
         _ => {
             info!("Ignoring event type: {}", event_type);
             Ok(Json(serde_json::json!({
@@ -119,25 +189,31 @@ async fn handle_github_webhook(
     }
 }
 
-fn verify_signature(headers: &HeaderMap, body: &[u8], secret: &str) -> Result<(), StatusCode> {
+/// Check the `sha256=<hex HMAC of the body>` signature in `header`
+fn verify_signature(
+    headers: &HeaderMap,
+    header: &str,
+    body: &[u8],
+    secret: &str,
+) -> Result<(), ApiError> {
     let signature_header = headers
-        .get("X-Hub-Signature-256")
+        .get(header)
         .and_then(|h| h.to_str().ok())
         .ok_or_else(|| {
-            warn!("Missing X-Hub-Signature-256 header");
-            StatusCode::UNAUTHORIZED
+            warn!("Missing {} header", header);
+            ApiError::unauthorized(format!("Missing {} header", header))
         })?;
 
     if !signature_header.starts_with("sha256=") {
         warn!("Invalid signature format");
-        return Err(StatusCode::UNAUTHORIZED);
+        return Err(ApiError::unauthorized("Invalid signature format"));
     }
 
     let expected_signature = &signature_header[7..]; // Remove "sha256=" prefix
 
     let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).map_err(|_| {
         error!("Invalid webhook secret");
-        StatusCode::INTERNAL_SERVER_ERROR
+        ApiError::internal("Invalid webhook secret")
     })?;
 
     mac.update(body);
@@ -146,7 +222,9 @@ fn verify_signature(headers: &HeaderMap, body: &[u8], secret: &str) -> Result<()
 
     if computed_signature != expected_signature {
         warn!("Webhook signature verification failed");
-        return Err(StatusCode::UNAUTHORIZED);
+        return Err(ApiError::unauthorized(
+            "Webhook signature verification failed",
+        ));
     }
 
     Ok(())
@@ -155,26 +233,62 @@ fn verify_signature(headers: &HeaderMap, body: &[u8], secret: &str) -> Result<()
 async fn handle_push_event(
     app_state: &Arc<crate::AppState>,
     webhook: &GitHubWebhook,
-) -> Result<Json<Value>, StatusCode> {
+) -> Result<Json<Value>, ApiError> {
     let commit_sha = webhook
         .after
         .as_ref()
         .or_else(|| webhook.head_commit.as_ref().map(|c| &c.id))
         .ok_or_else(|| {
             error!("Push event missing commit SHA");
-            StatusCode::BAD_REQUEST
+            ApiError::bad_request("Push event missing commit SHA")
         })?;
 
-    let branch = webhook
-        .git_ref
-        .as_ref()
-        .and_then(|r| r.strip_prefix("refs/heads/"))
-        .unwrap_or("unknown");
+    // Tags start release workflows, which record the tag as their branch
+    let git_ref = webhook.git_ref.as_deref().unwrap_or_default();
+    let (branch, release) = match git_ref.strip_prefix("refs/tags/") {
+        Some(tag) => (tag, true),
+        None => (
+            git_ref.strip_prefix("refs/heads/").unwrap_or("unknown"),
+            false,
+        ),
+    };
 
-    info!(
-        "Processing push to {} branch {} commit {}",
-        webhook.repository.full_name, branch, commit_sha
-    );
+    check_repository(app_state, &webhook.repository.full_name)?;
+    if commit_sha.chars().all(|c| c == '0') {
+        info!("Ignoring deletion of {}", git_ref);
+        return Ok(Json(serde_json::json!({
+            "status": "ignored",
+            "message": format!("{} was deleted", git_ref)
+        })));
+    }
+    if !release
+        && !app_state
+            .webhook_config()
+            .builds_branch(&webhook.repository.full_name, branch)
+    {
+        info!(
+            "Ignoring push to {} branch {}, which is not built",
+            webhook.repository.full_name, branch
+        );
+        return Ok(Json(serde_json::json!({
+            "status": "ignored",
+            "message": format!("Branch '{}' is not built", branch)
+        })));
+    }
+
+    if release {
+        info!(
+            "Processing push of {} tag {} commit {}",
+            webhook.repository.full_name, branch, commit_sha
+        );
+    } else {
+        info!(
+            "Processing push to {} branch {} commit {}",
+            webhook.repository.full_name, branch, commit_sha
+        );
+    }
+
+    let deprioritized = check_quota(app_state, &webhook.repository.full_name).await?;
 
     // Create workflow and trigger nix evaluation
     let workflow_id = create_workflow(
@@ -183,11 +297,20 @@ async fn handle_push_event(
         commit_sha,
         branch,
         &webhook.repository.clone_url,
+        webhook
+            .head_commit
+            .as_ref()
+            .map(|c| c.author.email.as_str()),
+        WorkflowOptions {
+            deprioritized,
+            release,
+            ..Default::default()
+        },
     )
     .await
     .map_err(|e| {
         error!("Failed to create workflow: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
+        ApiError::internal("Failed to create workflow")
     })?;
 
     Ok(Json(serde_json::json!({
@@ -195,6 +318,7 @@ async fn handle_push_event(
         "message": "Push event processed",
         "repository": webhook.repository.full_name,
         "branch": branch,
+        "release": release,
         "commit": commit_sha,
         "workflow_id": workflow_id
     })))
@@ -203,10 +327,10 @@ async fn handle_push_event(
 async fn handle_pull_request_event(
     app_state: &Arc<crate::AppState>,
     webhook: &GitHubWebhook,
-) -> Result<Json<Value>, StatusCode> {
+) -> Result<Json<Value>, ApiError> {
     let pr = webhook.pull_request.as_ref().ok_or_else(|| {
         error!("Pull request event missing pull_request data");
-        StatusCode::BAD_REQUEST
+        ApiError::bad_request("Pull request event missing pull_request data")
     })?;
 
     let action = webhook.action.as_deref().unwrap_or("unknown");
@@ -219,17 +343,24 @@ async fn handle_pull_request_event(
     // Only process certain PR actions
     match action {
         "opened" | "synchronize" | "reopened" => {
+            check_repository(app_state, &webhook.repository.full_name)?;
+            let deprioritized = check_quota(app_state, &webhook.repository.full_name).await?;
             let workflow_id = create_workflow(
                 app_state,
                 &webhook.repository.full_name,
                 &pr.head.sha,
                 &format!("pr-{}", pr.number),
                 &webhook.repository.clone_url,
+                None,
+                WorkflowOptions {
+                    deprioritized,
+                    ..Default::default()
+                },
             )
             .await
             .map_err(|e| {
                 error!("Failed to create workflow: {}", e);
-                StatusCode::INTERNAL_SERVER_ERROR
+                ApiError::internal("Failed to create workflow")
             })?;
 
             Ok(Json(serde_json::json!({
@@ -249,34 +380,138 @@ async fn handle_pull_request_event(
     }
 }
 
-async fn create_workflow(
+/// Refuse webhooks of repositories that are not allowed to trigger workflows
+pub fn check_repository(app_state: &crate::AppState, repository: &str) -> Result<(), ApiError> {
+    if app_state.webhook_config().allows(repository) {
+        return Ok(());
+    }
+    warn!("Refusing webhook of {}, which is not allowed", repository);
+    Err(ApiError::new(
+        StatusCode::FORBIDDEN,
+        "repository_not_allowed",
+        format!("{} is not allowed to trigger workflows", repository),
+    ))
+}
+
+/// Check the build-minute quotas of a repository before creating a workflow.
+/// Returns whether the workflow's jobs are deprioritized; errors if it is rejected.
+pub async fn check_quota(app_state: &crate::AppState, repository: &str) -> Result<bool, ApiError> {
+    let config = &app_state.quota_config;
+    let usage = quota::check(&app_state.db_pool, config, repository).await?;
+    let mut exceeded = false;
+    for usage in &usage {
+        let limit = usage.limit_minutes.unwrap_or_default();
+        match usage.state {
+            QuotaState::Ok => {}
+            QuotaState::Warning => warn!(
+                "{} has used {} of its {} build minutes this month",
+                usage.name, usage.used_minutes, limit
+            ),
+            QuotaState::Exceeded => {
+                warn!(
+                    "{} is over quota: {} of {} build minutes used this month",
+                    usage.name, usage.used_minutes, limit
+                );
+                exceeded = true;
+            }
+        }
+    }
+
+    if exceeded && config.action == QuotaAction::Reject {
+        return Err(ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "quota_exceeded",
+            format!("{} is over its monthly build-minute quota", repository),
+        )
+        .with_details(json!(usage)));
+    }
+    Ok(exceeded)
+}
+
+/// How a workflow differs from a regular build of a branch
+#[derive(Debug, Clone, Default)]
+pub struct WorkflowOptions {
+    /// Over quota, so its jobs only run when nothing else is ready
+    pub deprioritized: bool,
+    /// Triggered by a tag, building the release attribute set if there is one
+    pub release: bool,
+    /// Evaluated instead of the configured attribute set
+    pub attribute_set: Option<String>,
+    /// Started through the API rather than by the repository's forge, so the clone
+    /// URL of a registered repository is left as it is
+    pub keep_clone_url: bool,
+}
+
+pub async fn create_workflow(
     app_state: &Arc<crate::AppState>,
     repository: &str,
     commit_sha: &str,
     branch: &str,
     clone_url: &str,
+    author_email: Option<&str>,
+    options: WorkflowOptions,
 ) -> Result<i64, anyhow::Error> {
     let now = chrono::Utc::now().timestamp();
+    let config = app_state.webhook_config();
+    let attribute_set = match (options.attribute_set, config.release_attrset) {
+        (Some(attrset), _) => attrset,
+        (None, Some(attrset)) if options.release => attrset,
+        _ => config.attrset,
+    };
+
+    // Register the repository (or refresh its clone URL)
+    sqlx::query(
+        r#"
+        INSERT INTO repositories (full_name, clone_url, created_at)
+        VALUES (?1, ?2, ?3)
+        ON CONFLICT(full_name) DO UPDATE
+        SET clone_url = CASE WHEN ?4 THEN clone_url ELSE excluded.clone_url END
+        "#,
+    )
+    .bind(repository)
+    .bind(clone_url)
+    .bind(now)
+    .bind(options.keep_clone_url)
+    .execute(&app_state.db_pool)
+    .await?;
 
     // Insert workflow into database to get auto-generated ID
     let workflow_id = sqlx::query!(
         r#"
-        INSERT INTO workflows (repository, commit_sha, attribute_set, status, created_at)
-        VALUES (?, ?, ?, 'Pending', ?)
+        INSERT INTO workflows (repository, commit_sha, branch, attribute_set, status, created_at, author_email)
+        VALUES (?, ?, ?, ?, 'Pending', ?, ?)
         "#,
         repository,
         commit_sha,
-        app_state.webhook_config.attrset,
-        now
+        branch,
+        attribute_set,
+        now,
+        author_email
     )
     .execute(&app_state.db_pool)
     .await?
     .last_insert_rowid();
 
+    let priority = app_state
+        .priority_config
+        .priority(repository, branch, options.release);
+    sqlx::query("UPDATE workflows SET deprioritized = ?, release = ?, priority = ? WHERE id = ?")
+        .bind(options.deprioritized)
+        .bind(options.release)
+        .bind(priority)
+        .bind(workflow_id)
+        .execute(&app_state.db_pool)
+        .await?;
+
     info!(
         "Creating workflow {} for {} at {} ({})",
         workflow_id, repository, commit_sha, branch
     );
+    app_state.events.publish(Event::WorkflowCreated {
+        workflow_id,
+        repository: repository.to_string(),
+        commit_sha: commit_sha.to_string(),
+    });
 
     // Spawn background task to process the workflow
     let app_state_clone = app_state.clone();
@@ -284,19 +519,24 @@ async fn create_workflow(
     let commit_sha = commit_sha.to_string();
     let clone_url = clone_url.to_string();
 
-    tokio::spawn(async move {
-        if let Err(e) = process_workflow(
-            &app_state_clone,
-            workflow_id,
-            &repository,
-            &commit_sha,
-            &clone_url,
-        )
-        .await
-        {
-            error!("Failed to process workflow {}: {}", workflow_id, e);
+    tokio::spawn(
+        async move {
+            if let Err(e) = process_workflow(
+                &app_state_clone,
+                workflow_id,
+                &repository,
+                &commit_sha,
+                &clone_url,
+                attribute_set,
+            )
+            .await
+            {
+                error!("Failed to process workflow {}: {:#}", workflow_id, e);
+                fail_workflow(&app_state_clone, workflow_id, e).await;
+            }
         }
-    });
+        .instrument(info_span!("workflow", workflow_id)),
+    );
 
     Ok(workflow_id)
 }
@@ -307,6 +547,7 @@ async fn process_workflow(
     repository: &str,
     commit_sha: &str,
     clone_url: &str,
+    attribute_set: String,
 ) -> Result<(), anyhow::Error> {
     info!("Processing workflow {} for {}", workflow_id, repository);
 
@@ -319,58 +560,229 @@ async fn process_workflow(
     )
     .execute(&app_state.db_pool)
     .await?;
+    app_state.events.publish(Event::WorkflowStatus {
+        workflow_id,
+        status: WorkflowStatus::Running,
+    });
 
     // Create workflow object
     let workflow = Workflow {
         id: workflow_id,
         repository: repository.to_string(),
         commit_sha: commit_sha.to_string(),
-        attribute_set: app_state.webhook_config.attrset.clone(),
+        attribute_set,
         status: WorkflowStatus::Running,
     };
 
-    // Evaluate the repository
-    let mut evaluator = NixEvaluator::new();
-    let derivations = evaluator
-        .evaluate_repository(clone_url, commit_sha, &workflow.attribute_set)
+    // The stages are defined by the commit being built
+    let credential = app_state.credentials.get(repository).await?;
+    let mut evaluator = app_state.evaluator(repository);
+    evaluator
+        .clone_repository(clone_url, commit_sha, credential.as_ref())
         .await?;
+    let repo_path = evaluator.repo_path().unwrap();
+    if let Err(e) = inputs::record(&app_state.db_pool, workflow_id, repo_path).await {
+        warn!(
+            "Failed to record flake inputs of workflow {}: {:#}",
+            workflow_id, e
+        );
+    }
+    let stages = pipeline::load_stages(repo_path, &workflow.attribute_set).await?;
+    info!("Workflow {} has {} stages", workflow_id, stages.len());
+
+    if !app_state.pipeline.begin(workflow_id, &stages).await? {
+        info!("Workflow {} awaits approval", workflow_id);
+        return Ok(());
+    }
+    evaluate_stage(app_state, workflow_id, &evaluator).await
+}
+
+/// Evaluate and queue the running stage of a workflow once the previous stage
+/// succeeded or it was approved
+pub async fn run_stage(app_state: Arc<crate::AppState>, workflow_id: i64) {
+    let result = async {
+        let (repository, commit_sha, clone_url): (String, String, String) = sqlx::query_as(
+            r#"
+            SELECT w.repository, w.commit_sha, r.clone_url
+            FROM workflows w JOIN repositories r ON r.full_name = w.repository
+            WHERE w.id = ?
+            "#,
+        )
+        .bind(workflow_id)
+        .fetch_one(&app_state.db_pool)
+        .await?;
+
+        let credential = app_state.credentials.get(&repository).await?;
+        let mut evaluator = app_state.evaluator(&repository);
+        evaluator
+            .clone_repository(&clone_url, &commit_sha, credential.as_ref())
+            .await?;
+        evaluate_stage(&app_state, workflow_id, &evaluator).await
+    }
+    .await;
+    if let Err(e) = result {
+        error!("Failed to run stage of workflow {}: {:#}", workflow_id, e);
+        fail_workflow(&app_state, workflow_id, e).await;
+    }
+}
+
+/// Mark a workflow whose evaluation went wrong as failed, showing the error
+async fn fail_workflow(app_state: &crate::AppState, workflow_id: i64, error: anyhow::Error) {
+    if let Err(e) = app_state
+        .pipeline
+        .fail(workflow_id, &format!("{:#}", error))
+        .await
+    {
+        error!("Failed to mark workflow {} as failed: {:#}", workflow_id, e);
+    }
+}
+
+/// Evaluate the attribute set of a workflow's running stage and queue its jobs
+async fn evaluate_stage(
+    app_state: &Arc<crate::AppState>,
+    workflow_id: i64,
+    evaluator: &NixEvaluator,
+) -> Result<(), anyhow::Error> {
+    let stage = pipeline::running_stage(&app_state.db_pool, workflow_id)
+        .await?
+        .ok_or_else(|| anyhow!("Workflow {} has no running stage", workflow_id))?;
+    let mut evaluation = evaluator
+        .evaluate_flake(evaluator.repo_path().unwrap(), &stage.attribute_set)
+        .await?;
+    match CacheClient::new(app_state.cache_config.clone())
+        .cached_derivations(&evaluation.derivations)
+        .await
+    {
+        Ok(cached) => {
+            for derivation in &mut evaluation.derivations {
+                derivation.cached = Some(cached.contains(&derivation.drv_path));
+            }
+        }
+        // Builds check the cache themselves then
+        Err(e) => warn!(
+            "Failed to check the cache for workflow {}: {:#}",
+            workflow_id, e
+        ),
+    }
 
     info!(
-        "Found {} derivations for workflow {}",
-        derivations.len(),
+        "Found {} derivations and {} evaluation errors for stage {} of workflow {}",
+        evaluation.derivations.len(),
+        evaluation.errors.len(),
+        stage.name,
         workflow_id
     );
 
-    let is_complete = app_state.build_queue.add_workflow(derivations, workflow_id);
+    // The workflow may have been canceled while it was being evaluated
+    if is_canceled(&app_state.db_pool, workflow_id).await? {
+        info!("Workflow {} was canceled during evaluation", workflow_id);
+        return Ok(());
+    }
 
-    // If workflow is already complete (all jobs were done), handle completion immediately
-    if is_complete {
+    let (repository, deprioritized, priority): (String, bool, i64) =
+        sqlx::query_as("SELECT repository, deprioritized, priority FROM workflows WHERE id = ?")
+            .bind(workflow_id)
+            .fetch_one(&app_state.db_pool)
+            .await?;
+    if deprioritized {
+        info!("Workflow {} is over quota, its jobs run last", workflow_id);
+        app_state.build_queue.deprioritize(workflow_id);
+    }
+    app_state.build_queue.set_priority(workflow_id, priority);
+    app_state
+        .build_queue
+        .set_repository(workflow_id, &repository);
+    let is_complete = app_state
+        .pipeline
+        .queue(workflow_id, &stage.name, evaluation)
+        .await?;
+
+    // A cancel landing while the jobs were queued may have found none of them to
+    // drop; those after it see them in the queue
+    if is_canceled(&app_state.db_pool, workflow_id).await? {
+        let jobs = app_state.build_queue.cancel_workflow(workflow_id);
         info!(
-            "Workflow {} completed immediately (all jobs already done)",
-            workflow_id
+            "Workflow {} was canceled while queueing, dropping {} unfinished jobs",
+            workflow_id, jobs
         );
+        return Ok(());
+    }
 
-        // Get jobs to determine final status
-        let jobs = app_state.build_queue.get_workflow_jobs(workflow_id);
-        let has_errors = jobs.iter().any(|j| j.status.error());
-        let final_status = if has_errors { "Failed" } else { "Completed" };
-
-        // Update workflow status
-        sqlx::query!(
-            r#"
-            UPDATE workflows SET status = ? WHERE id = ?
-            "#,
-            final_status,
-            workflow_id
-        )
-        .execute(&app_state.db_pool)
-        .await?;
-
-        // Clear from queue
-        app_state.build_queue.clear_workflow(workflow_id);
+    // If the stage is already complete (all jobs were done), handle completion immediately
+    if is_complete {
+        info!(
+            "Workflow {} stage {} completed immediately (all jobs already done)",
+            workflow_id, stage.name
+        );
+        app_state.pipeline.jobs_finished(workflow_id).await;
     } else {
         info!("Workflow {} queued with pending jobs", workflow_id);
     }
 
     Ok(())
 }
+
+/// Whether the workflow was canceled, e.g. while it was being evaluated
+async fn is_canceled(db_pool: &sqlx::SqlitePool, workflow_id: i64) -> Result<bool, sqlx::Error> {
+    let status = sqlx::query_scalar!(
+        r#"
+        SELECT status FROM workflows WHERE id = ?
+        "#,
+        workflow_id
+    )
+    .fetch_one(db_pool)
+    .await?;
+    Ok(status == "Canceled")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        let config = |allow: &[&str], deny: &[&str]| WebhookConfig {
+            secret: None,
+            attrset: String::new(),
+            release_attrset: None,
+            event_retention_days: 0,
+            allow: allow.iter().map(|p| p.to_string()).collect(),
+            deny: deny.iter().map(|p| p.to_string()).collect(),
+            branches: Vec::new(),
+            repository_branches: HashMap::new(),
+        };
+        assert!(config(&[], &[]).allows("anyone/anything"));
+
+        let config = config(&["team/*", "alice/dotfiles"], &["team/secret"]);
+        assert!(config.allows("team/app"));
+        assert!(config.allows("Alice/Dotfiles"));
+        assert!(!config.allows("team/secret"));
+        assert!(!config.allows("alice/other"));
+        assert!(!config.allows("teamster/app"));
+    }
+
+    #[test]
+    fn test_builds_branch() {
+        let mut config = WebhookConfig {
+            secret: None,
+            attrset: String::new(),
+            release_attrset: None,
+            event_retention_days: 0,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            branches: Vec::new(),
+            repository_branches: HashMap::new(),
+        };
+        assert!(config.builds_branch("a/b", "feature/x"));
+
+        config.branches = vec!["main".to_string(), "release/*".to_string()];
+        config
+            .repository_branches
+            .insert("a/docs".to_string(), vec!["gh-pages".to_string()]);
+        assert!(config.builds_branch("a/b", "main"));
+        assert!(config.builds_branch("a/b", "release/1.0"));
+        assert!(!config.builds_branch("a/b", "feature/x"));
+        assert!(config.builds_branch("a/docs", "gh-pages"));
+        assert!(!config.builds_branch("a/docs", "main"));
+    }
+}
@@ -0,0 +1,525 @@
+use crate::{auth, builders};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::pin,
+    sync::{Arc, Mutex, PoisonError},
+};
+use tokio::{
+    sync::{oneshot, Notify},
+    time::{timeout, Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// Longest time a lease request waits for a job
+const MAX_LEASE_WAIT: Duration = Duration::from_secs(25);
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct WorkerConfig {
+    /// Token `icicle worker` processes authenticate with; workers are disabled when unset
+    pub token: Option<String>,
+    /// Workers not heard from for this long are dropped and their jobs re-assigned
+    pub lease_secs: u64,
+    /// Store workers fetch derivations from and copy outputs to, e.g.
+    /// "ssh-ng://icicle@ci-server"; unset when they share this machine's store
+    pub store: Option<String>,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        WorkerConfig {
+            token: None,
+            lease_secs: 60,
+            store: None,
+        }
+    }
+}
+
+impl WorkerConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.lease_secs < 3 {
+            return Err(anyhow!("lease_secs must be at least 3"));
+        }
+        if self.token.as_ref().is_some_and(|t| t.trim().is_empty()) {
+            return Err(anyhow!("token must not be empty"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum WorkerError {
+    /// The worker never registered or was dropped, and needs to register again
+    UnknownWorker,
+    /// The job was re-assigned or is not needed anymore, so the worker should stop it
+    LeaseGone,
+}
+
+/// A job handed to a worker
+#[derive(Debug, Serialize)]
+pub struct LeasedJob {
+    pub lease_id: u64,
+    pub drv_path: String,
+}
+
+/// A worker's current state, as reported by the API
+#[derive(Debug, Serialize)]
+pub struct WorkerStatus {
+    pub id: u64,
+    pub name: String,
+    pub systems: Vec<String>,
+    pub features: Vec<String>,
+    pub max_jobs: usize,
+    /// Seconds since the worker last contacted the server
+    pub last_seen_secs: u64,
+    pub jobs: Vec<JobStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobStatus {
+    pub drv_path: String,
+    /// Last step reported by the worker, e.g. "building"
+    pub phase: String,
+}
+
+struct Worker {
+    name: String,
+    systems: Vec<String>,
+    features: Vec<String>,
+    max_jobs: usize,
+    last_seen: Instant,
+}
+
+impl Worker {
+    fn builds(&self, system: &str, features: &[String]) -> bool {
+        self.systems.iter().any(|s| s == system) && builders::supports(&self.features, features)
+    }
+}
+
+struct Lease {
+    system: String,
+    /// System features the job requires
+    features: Vec<String>,
+    /// Set once the executor hands the job over
+    drv_path: Option<String>,
+    worker: Option<u64>,
+    phase: String,
+    result: Option<oneshot::Sender<Result<(), String>>>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    workers: HashMap<u64, Worker>,
+    leases: HashMap<u64, Lease>,
+    /// Handed over leases waiting for a worker, oldest first
+    pending: VecDeque<u64>,
+}
+
+impl State {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn touch(&mut self, worker_id: u64) -> Result<&Worker, WorkerError> {
+        let worker = self
+            .workers
+            .get_mut(&worker_id)
+            .ok_or(WorkerError::UnknownWorker)?;
+        worker.last_seen = Instant::now();
+        Ok(worker)
+    }
+
+    fn worker_leases(&self, worker_id: u64) -> usize {
+        self.leases
+            .values()
+            .filter(|l| l.worker == Some(worker_id))
+            .count()
+    }
+
+    /// The assigned lease `lease_id` of `worker_id`
+    fn lease(&mut self, worker_id: u64, lease_id: u64) -> Result<&mut Lease, WorkerError> {
+        self.touch(worker_id)?;
+        self.leases
+            .get_mut(&lease_id)
+            .filter(|l| l.worker == Some(worker_id))
+            .ok_or(WorkerError::LeaseGone)
+    }
+}
+
+/// Separate `icicle worker` processes, which register, lease the jobs of their systems
+/// and report back how they went. Jobs of workers that stop reporting go to other workers.
+#[derive(Clone)]
+pub struct Workers {
+    config: WorkerConfig,
+    state: Arc<Mutex<State>>,
+    /// Notified when a job is handed over
+    job_added: Arc<Notify>,
+    /// Notified when a worker registers or a job gives its slot back
+    changed: Arc<Notify>,
+    /// Notified when a lease is removed
+    lease_removed: Arc<Notify>,
+}
+
+impl Workers {
+    pub fn new(config: WorkerConfig) -> Self {
+        Self {
+            config,
+            state: Arc::default(),
+            job_added: Arc::default(),
+            changed: Arc::default(),
+            lease_removed: Arc::default(),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.token.is_some()
+    }
+
+    /// Whether `token` is the workers' token
+    pub fn authenticates(&self, token: &str) -> bool {
+        self.config
+            .token
+            .as_deref()
+            .is_some_and(|expected| auth::token_matches(expected, token))
+    }
+
+    pub fn lease_secs(&self) -> u64 {
+        self.config.lease_secs
+    }
+
+    pub fn store(&self) -> Option<&str> {
+        self.config.store.as_deref()
+    }
+
+    pub fn register(
+        &self,
+        name: String,
+        systems: Vec<String>,
+        features: Vec<String>,
+        max_jobs: usize,
+    ) -> u64 {
+        let mut state = self.state();
+        let id = state.next_id();
+        info!(
+            "Worker {} ({}) registered for {} with {} slots",
+            id,
+            name,
+            systems.join(", "),
+            max_jobs
+        );
+        state.workers.insert(
+            id,
+            Worker {
+                name,
+                systems,
+                features,
+                max_jobs,
+                last_seen: Instant::now(),
+            },
+        );
+        drop(state);
+        self.changed.notify_one();
+        id
+    }
+
+    /// Whether any worker builds `system` with the given features
+    pub fn builds(&self, system: &str, features: &[String]) -> bool {
+        self.state()
+            .workers
+            .values()
+            .any(|w| w.builds(system, features))
+    }
+
+    /// Take a slot for a job of `system` requiring `features` if the workers able to
+    /// build it have fewer jobs than slots
+    pub fn reserve(&self, system: &str, features: &[String]) -> Option<WorkerSlot> {
+        let mut state = self.state();
+        let builds = |w: &Worker| w.builds(system, features);
+        let slots: usize = state
+            .workers
+            .values()
+            .filter(|w| builds(w))
+            .map(|w| w.max_jobs)
+            .sum();
+        let used = state
+            .leases
+            .values()
+            .filter(|l| match l.worker.and_then(|id| state.workers.get(&id)) {
+                Some(worker) => builds(worker),
+                None => l.system == system,
+            })
+            .count();
+        if used >= slots {
+            return None;
+        }
+        let lease_id = state.next_id();
+        state.leases.insert(
+            lease_id,
+            Lease {
+                system: system.to_string(),
+                features: features.to_vec(),
+                drv_path: None,
+                worker: None,
+                phase: "reserved".to_string(),
+                result: None,
+            },
+        );
+        Some(WorkerSlot {
+            workers: self.clone(),
+            lease_id,
+        })
+    }
+
+    /// Lease the next job one of the worker's systems, waiting up to `MAX_LEASE_WAIT`
+    /// (and well within the lease) for one
+    pub async fn lease(&self, worker_id: u64) -> Result<Option<LeasedJob>, WorkerError> {
+        let wait = MAX_LEASE_WAIT.min(Duration::from_secs(self.config.lease_secs) / 2);
+        let deadline = Instant::now() + wait;
+        loop {
+            // Listen before looking, so a job added meanwhile is not missed
+            let mut added = pin!(self.job_added.notified());
+            added.as_mut().enable();
+            if let Some(job) = self.try_lease(worker_id)? {
+                return Ok(Some(job));
+            }
+            if timeout(deadline - Instant::now(), added).await.is_err() {
+                self.state().touch(worker_id)?;
+                return Ok(None);
+            }
+        }
+    }
+
+    fn try_lease(&self, worker_id: u64) -> Result<Option<LeasedJob>, WorkerError> {
+        let mut state = self.state();
+        let max_jobs = state.touch(worker_id)?.max_jobs;
+        if state.worker_leases(worker_id) >= max_jobs {
+            return Ok(None);
+        }
+        let worker = &state.workers[&worker_id];
+        let Some(position) = state.pending.iter().position(|id| {
+            let lease = &state.leases[id];
+            worker.builds(&lease.system, &lease.features)
+        }) else {
+            return Ok(None);
+        };
+        let lease_id = state.pending.remove(position).unwrap();
+        let name = state.workers[&worker_id].name.clone();
+        let lease = state.leases.get_mut(&lease_id).unwrap();
+        lease.worker = Some(worker_id);
+        lease.phase = "leased".to_string();
+        let drv_path = lease.drv_path.clone().unwrap_or_default();
+        info!("Worker {} ({}) leased {}", worker_id, name, drv_path);
+        Ok(Some(LeasedJob { lease_id, drv_path }))
+    }
+
+    /// Record the current step of a leased job; also keeps the worker alive
+    pub fn report(&self, worker_id: u64, lease_id: u64, phase: String) -> Result<(), WorkerError> {
+        self.state().lease(worker_id, lease_id)?.phase = phase;
+        Ok(())
+    }
+
+    /// Finish a leased job, with why it failed if it did
+    pub fn finish(
+        &self,
+        worker_id: u64,
+        lease_id: u64,
+        result: Result<(), String>,
+    ) -> Result<(), WorkerError> {
+        let mut state = self.state();
+        let lease = state.lease(worker_id, lease_id)?;
+        lease.phase = "finished".to_string();
+        if let Some(sender) = lease.result.take() {
+            let _ = sender.send(result);
+        }
+        Ok(())
+    }
+
+    /// Drop the workers not heard from within the lease, putting their jobs back first
+    /// in line for the other workers
+    fn expire(&self) {
+        let lease = Duration::from_secs(self.config.lease_secs);
+        let mut state = self.state();
+        let expired: Vec<u64> = state
+            .workers
+            .iter()
+            .filter(|(_, w)| w.last_seen.elapsed() > lease)
+            .map(|(id, _)| *id)
+            .collect();
+        for worker_id in expired {
+            let worker = state.workers.remove(&worker_id).unwrap();
+            let mut reassigned: Vec<u64> = state
+                .leases
+                .iter()
+                .filter(|(_, l)| l.worker == Some(worker_id))
+                .map(|(id, _)| *id)
+                .collect();
+            reassigned.sort_unstable();
+            warn!(
+                "Worker {} ({}) disappeared, re-assigning its {} jobs",
+                worker_id,
+                worker.name,
+                reassigned.len()
+            );
+            for lease_id in reassigned.into_iter().rev() {
+                let lease = state.leases.get_mut(&lease_id).unwrap();
+                lease.worker = None;
+                lease.phase = "pending".to_string();
+                state.pending.push_front(lease_id);
+            }
+        }
+        drop(state);
+        self.job_added.notify_waiters();
+    }
+
+    /// Drop workers that stop reporting, until the process exits
+    pub async fn run_expiry(self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.lease_secs) / 3);
+        loop {
+            interval.tick().await;
+            self.expire();
+        }
+    }
+
+    /// Wait until a worker registers or one of their jobs finishes
+    pub async fn changed(&self) {
+        self.changed.notified().await;
+    }
+
+    /// Number of jobs handed to workers
+    pub fn running(&self) -> usize {
+        self.state().leases.len()
+    }
+
+    /// Wait for the jobs handed to workers to finish
+    pub async fn wait_idle(&self) {
+        loop {
+            let mut removed = pin!(self.lease_removed.notified());
+            removed.as_mut().enable();
+            if self.running() == 0 {
+                return;
+            }
+            removed.await;
+        }
+    }
+
+    pub fn status(&self) -> Vec<WorkerStatus> {
+        let state = self.state();
+        let mut workers: Vec<WorkerStatus> = state
+            .workers
+            .iter()
+            .map(|(id, w)| WorkerStatus {
+                id: *id,
+                name: w.name.clone(),
+                systems: w.systems.clone(),
+                features: w.features.clone(),
+                max_jobs: w.max_jobs,
+                last_seen_secs: w.last_seen.elapsed().as_secs(),
+                jobs: state
+                    .leases
+                    .values()
+                    .filter(|l| l.worker == Some(*id))
+                    .map(|l| JobStatus {
+                        drv_path: l.drv_path.clone().unwrap_or_default(),
+                        phase: l.phase.clone(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        workers.sort_by_key(|w| w.id);
+        workers
+    }
+}
+
+/// A job's slot on the workers, given back when dropped
+pub struct WorkerSlot {
+    workers: Workers,
+    lease_id: u64,
+}
+
+impl WorkerSlot {
+    /// Hand the job over to the workers and wait until one of them built it
+    pub async fn build(&self, drv_path: &str) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut state = self.workers.state();
+            let lease = state
+                .leases
+                .get_mut(&self.lease_id)
+                .ok_or_else(|| anyhow!("Worker slot was given back"))?;
+            lease.drv_path = Some(drv_path.to_string());
+            lease.phase = "pending".to_string();
+            lease.result = Some(sender);
+            state.pending.push_back(self.lease_id);
+        }
+        self.workers.job_added.notify_waiters();
+        match receiver.await {
+            Ok(result) => result.map_err(|e| anyhow!("{}", e)),
+            Err(_) => Err(anyhow!("Worker slot was given back")),
+        }
+    }
+}
+
+impl Drop for WorkerSlot {
+    fn drop(&mut self) {
+        let mut state = self.workers.state();
+        state.leases.remove(&self.lease_id);
+        state.pending.retain(|id| *id != self.lease_id);
+        drop(state);
+        self.workers.changed.notify_one();
+        self.workers.lease_removed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authenticates() {
+        let workers = Workers::new(WorkerConfig {
+            token: Some("t".to_string()),
+            ..Default::default()
+        });
+        assert!(workers.authenticates("t"));
+        assert!(!workers.authenticates("u"));
+        assert!(!Workers::new(WorkerConfig::default()).authenticates(""));
+    }
+
+    #[tokio::test]
+    async fn test_jobs_of_lost_workers_are_reassigned() {
+        let workers = Workers::new(WorkerConfig {
+            token: Some("t".to_string()),
+            ..Default::default()
+        });
+        let system = "x86_64-linux";
+        assert!(workers.reserve(system, &[]).is_none());
+        let first = workers.register("a".to_string(), vec![system.to_string()], Vec::new(), 1);
+        let slot = workers.reserve(system, &[]).unwrap();
+        assert!(workers.reserve(system, &[]).is_none());
+
+        let build = tokio::spawn(async move { slot.build("/nix/store/x.drv").await });
+        let job = workers.lease(first).await.unwrap().unwrap();
+        assert_eq!(job.drv_path, "/nix/store/x.drv");
+
+        // The first worker goes silent, so the job goes to the second one
+        workers.state().workers.get_mut(&first).unwrap().last_seen -= Duration::from_secs(61);
+        workers.expire();
+        assert!(matches!(
+            workers.report(first, job.lease_id, "building".to_string()),
+            Err(WorkerError::UnknownWorker)
+        ));
+        let second = workers.register("b".to_string(), vec![system.to_string()], Vec::new(), 1);
+        let job = workers.lease(second).await.unwrap().unwrap();
+        workers.finish(second, job.lease_id, Ok(())).unwrap();
+        build.await.unwrap().unwrap();
+        assert_eq!(workers.running(), 0);
+    }
+}
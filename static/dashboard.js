@@ -0,0 +1,39 @@
+// Simple auto-refresh functionality
+let countdown = 30;
+const countdownElement = document.getElementById('refresh-countdown');
+
+function updateCountdown() {
+    countdown--;
+    countdownElement.textContent = countdown;
+
+    if (countdown <= 0) {
+        location.reload();
+    }
+}
+
+setInterval(updateCountdown, 1000);
+
+// Pause auto-refresh when user is interacting
+let userActive = false;
+['mousedown', 'mousemove', 'keypress', 'scroll', 'touchstart'].forEach(event => {
+    document.addEventListener(event, () => {
+        userActive = true;
+        setTimeout(() => { userActive = false; }, 5000);
+    });
+});
+
+setInterval(() => {
+    if (!userActive && countdown <= 0) {
+        location.reload();
+    }
+}, 1000);
+
+// Refresh soon after any workflow changes state
+const events = new EventSource('/api/events');
+events.onmessage = (message) => {
+    const event = JSON.parse(message.data);
+    if (event.type !== 'job_status' && countdown > 3) {
+        countdown = 3;
+    }
+};
+
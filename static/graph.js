@@ -0,0 +1,176 @@
+// Draws the job graph of a workflow from /api/workflows/{id}/graph as an SVG:
+// dependencies on the left, the jobs needing them to their right
+const NODE_WIDTH = 180;
+const NODE_HEIGHT = 28;
+const LAYER_GAP = 80;
+const ROW_GAP = 14;
+const REFRESH_MS = 10000;
+const SVG_NS = 'http://www.w3.org/2000/svg';
+
+const container = document.getElementById('job-graph');
+const workflowId = container.dataset.workflow;
+
+function svgElement(name, attributes) {
+    const element = document.createElementNS(SVG_NS, name);
+    for (const [key, value] of Object.entries(attributes)) {
+        element.setAttribute(key, value);
+    }
+    return element;
+}
+
+// Column of each job: one past the column of its furthest dependency
+function layers(graph) {
+    const inputs = new Map(graph.nodes.map(node => [node.drv_path, []]));
+    for (const edge of graph.edges) {
+        inputs.get(edge.to).push(edge.from);
+    }
+    const layer = new Map();
+    const visit = (drvPath) => {
+        if (!layer.has(drvPath)) {
+            layer.set(drvPath, 0);
+            const deps = inputs.get(drvPath);
+            layer.set(drvPath, deps.length ? Math.max(...deps.map(visit)) + 1 : 0);
+        }
+        return layer.get(drvPath);
+    };
+    graph.nodes.forEach(node => visit(node.drv_path));
+    return { layer, inputs };
+}
+
+// Position the jobs of each column, next to the average position of their dependencies
+function layout(graph) {
+    const { layer, inputs } = layers(graph);
+    const columns = [];
+    for (const node of graph.nodes) {
+        const column = layer.get(node.drv_path);
+        (columns[column] = columns[column] || []).push(node);
+    }
+    const row = new Map();
+    columns.forEach(nodes => {
+        const weight = (node) => {
+            const rows = inputs.get(node.drv_path).map(drvPath => row.get(drvPath));
+            return rows.length ? rows.reduce((a, b) => a + b, 0) / rows.length : 0;
+        };
+        nodes.sort((a, b) => weight(a) - weight(b) || a.name.localeCompare(b.name));
+        nodes.forEach((node, i) => row.set(node.drv_path, i));
+    });
+    const position = new Map();
+    for (const [drvPath, column] of layer) {
+        position.set(drvPath, {
+            x: column * (NODE_WIDTH + LAYER_GAP),
+            y: row.get(drvPath) * (NODE_HEIGHT + ROW_GAP),
+        });
+    }
+    const height = Math.max(...columns.map(nodes => nodes.length));
+    return {
+        position,
+        width: columns.length * (NODE_WIDTH + LAYER_GAP) - LAYER_GAP,
+        height: height * (NODE_HEIGHT + ROW_GAP) - ROW_GAP,
+    };
+}
+
+// The jobs a job depends on and the jobs depending on it, through any number of edges
+function related(graph, drvPath) {
+    const found = new Set([drvPath]);
+    for (const [from, to] of [['from', 'to'], ['to', 'from']]) {
+        const pending = [drvPath];
+        while (pending.length) {
+            const current = pending.pop();
+            for (const edge of graph.edges) {
+                if (edge[to] === current && !found.has(edge[from])) {
+                    found.add(edge[from]);
+                    pending.push(edge[from]);
+                }
+            }
+        }
+    }
+    return found;
+}
+
+function render(graph) {
+    container.replaceChildren();
+    if (!graph.nodes.length) {
+        const empty = document.createElement('p');
+        empty.className = 'job-graph-empty';
+        empty.textContent = 'The workflow has no jobs.';
+        container.append(empty);
+        return;
+    }
+
+    const { position, width, height } = layout(graph);
+    const padding = 10;
+    const svg = svgElement('svg', {
+        width: width + 2 * padding,
+        height: height + 2 * padding,
+        viewBox: `${-padding} ${-padding} ${width + 2 * padding} ${height + 2 * padding}`,
+    });
+
+    const edges = graph.edges.map(edge => {
+        const from = position.get(edge.from);
+        const to = position.get(edge.to);
+        const [x1, y1] = [from.x + NODE_WIDTH, from.y + NODE_HEIGHT / 2];
+        const [x2, y2] = [to.x, to.y + NODE_HEIGHT / 2];
+        const middle = (x1 + x2) / 2;
+        const path = svgElement('path', {
+            d: `M ${x1} ${y1} C ${middle} ${y1}, ${middle} ${y2}, ${x2} ${y2}`,
+            class: edge.blocking ? 'edge edge-blocking' : 'edge',
+        });
+        svg.append(path);
+        return { edge, path };
+    });
+
+    const nodes = graph.nodes.map(node => {
+        const { x, y } = position.get(node.drv_path);
+        const group = svgElement('g', {
+            class: `node status-${node.status.toLowerCase()}`,
+            transform: `translate(${x}, ${y})`,
+        });
+        const title = svgElement('title', {});
+        title.textContent = `${node.drv_path}\n${node.status} on ${node.system}`;
+        const label = svgElement('text', { x: 8, y: NODE_HEIGHT / 2 });
+        label.textContent = node.name.length > 24 ? `${node.name.slice(0, 23)}…` : node.name;
+        group.append(
+            title,
+            svgElement('rect', { width: NODE_WIDTH, height: NODE_HEIGHT, rx: 4 }),
+            label,
+        );
+
+        const link = svgElement('a', { href: `/builds/${encodeURIComponent(node.drv_path.replace('/nix/store/', ''))}` });
+        link.append(group);
+        svg.append(link);
+
+        group.addEventListener('mouseenter', () => {
+            const highlighted = related(graph, node.drv_path);
+            svg.classList.add('highlighting');
+            nodes.forEach(other => other.group.classList.toggle('highlight', highlighted.has(other.node.drv_path)));
+            edges.forEach(({ edge, path }) =>
+                path.classList.toggle('highlight', highlighted.has(edge.from) && highlighted.has(edge.to)));
+        });
+        group.addEventListener('mouseleave', () => svg.classList.remove('highlighting'));
+        return { node, group };
+    });
+
+    container.append(svg);
+}
+
+async function refresh() {
+    try {
+        const response = await fetch(`/api/workflows/${workflowId}/graph`);
+        if (!response.ok) {
+            throw new Error(`${response.status} ${response.statusText}`);
+        }
+        const graph = await response.json();
+        render(graph);
+        const running = graph.nodes.some(node => ['Queued', 'Ready', 'Running'].includes(node.status));
+        if (running) {
+            setTimeout(refresh, REFRESH_MS);
+        }
+    } catch (error) {
+        const failed = document.createElement('p');
+        failed.className = 'job-graph-empty';
+        failed.textContent = `Failed to load the job graph: ${error.message}`;
+        container.replaceChildren(failed);
+    }
+}
+
+refresh();
@@ -0,0 +1,133 @@
+// Charts the trends of /api/stats as SVG lines, one chart per statistic; points
+// without anything to compute them from leave gaps
+const WIDTH = 540;
+const HEIGHT = 160;
+const MARGIN = { top: 12, right: 12, bottom: 24, left: 48 };
+const SVG_NS = 'http://www.w3.org/2000/svg';
+
+const CHARTS = [
+    { title: 'Workflow success rate', field: 'success_rate', format: percent, max: 1 },
+    { title: 'Build success rate', field: 'build_success_rate', format: percent, max: 1 },
+    { title: 'Cache hit rate', field: 'cache_hit_rate', format: percent, max: 1 },
+    { title: 'Average build duration', field: 'avg_build_secs', format: duration },
+    { title: 'Average queue wait', field: 'avg_queue_wait_secs', format: duration },
+    { title: 'Average workflow duration', field: 'avg_workflow_secs', format: duration },
+];
+
+const container = document.getElementById('stats-charts');
+
+function svgElement(name, attributes) {
+    const element = document.createElementNS(SVG_NS, name);
+    for (const [key, value] of Object.entries(attributes)) {
+        element.setAttribute(key, value);
+    }
+    return element;
+}
+
+function percent(value) {
+    return `${Math.round(value * 100)}%`;
+}
+
+function duration(secs) {
+    secs = Math.round(secs);
+    if (secs >= 3600) {
+        return `${Math.floor(secs / 3600)}h${String(Math.floor(secs % 3600 / 60)).padStart(2, '0')}m`;
+    }
+    if (secs >= 60) {
+        return `${Math.floor(secs / 60)}m${String(secs % 60).padStart(2, '0')}s`;
+    }
+    return `${secs}s`;
+}
+
+function date(start, bucketSecs) {
+    const iso = new Date(start * 1000).toISOString();
+    return bucketSecs < 86400 ? iso.slice(11, 16) : iso.slice(0, 10);
+}
+
+function label(x, y, text, anchor) {
+    const element = svgElement('text', { x, y, 'text-anchor': anchor });
+    element.textContent = text;
+    return element;
+}
+
+function chart(report, { title, field, format, max }) {
+    const figure = document.createElement('figure');
+    figure.className = 'stats-chart';
+    const caption = document.createElement('figcaption');
+    caption.textContent = title;
+    figure.append(caption);
+
+    const points = report.trend;
+    const values = points.map(point => point[field]).filter(value => value !== null);
+    const top = max ?? Math.max(1, ...values);
+    const innerWidth = WIDTH - MARGIN.left - MARGIN.right;
+    const innerHeight = HEIGHT - MARGIN.top - MARGIN.bottom;
+    const x = i => MARGIN.left + (points.length > 1 ? i * innerWidth / (points.length - 1) : innerWidth / 2);
+    const y = value => MARGIN.top + innerHeight * (1 - value / top);
+
+    const svg = svgElement('svg', { width: WIDTH, height: HEIGHT, viewBox: `0 0 ${WIDTH} ${HEIGHT}` });
+    svg.append(
+        svgElement('line', { class: 'axis', x1: MARGIN.left, y1: y(0), x2: WIDTH - MARGIN.right, y2: y(0) }),
+        label(MARGIN.left - 6, y(top), format(top), 'end'),
+        label(MARGIN.left - 6, y(0), format(0), 'end'),
+    );
+    if (points.length > 0) {
+        svg.append(
+            label(x(0), HEIGHT - 6, date(points[0].start, report.bucket_secs), 'start'),
+            label(x(points.length - 1), HEIGHT - 6, date(points[points.length - 1].start, report.bucket_secs), 'end'),
+        );
+    }
+
+    // One line per run of points with a value
+    let segment = [];
+    const segments = [segment];
+    points.forEach((point, i) => {
+        if (point[field] === null) {
+            segment = [];
+            segments.push(segment);
+        } else {
+            segment.push([x(i), y(point[field]), point]);
+        }
+    });
+    for (const run of segments.filter(run => run.length > 0)) {
+        svg.append(svgElement('polyline', { class: 'line', points: run.map(([px, py]) => `${px},${py}`).join(' ') }));
+        for (const [px, py, point] of run) {
+            const dot = svgElement('circle', { class: 'point', cx: px, cy: py, r: 2.5 });
+            const tooltip = svgElement('title', {});
+            tooltip.textContent = `${date(point.start, report.bucket_secs)}: ${format(point[field])}`;
+            dot.append(tooltip);
+            svg.append(dot);
+        }
+    }
+    figure.append(svg);
+
+    if (values.length === 0) {
+        const empty = document.createElement('p');
+        empty.className = 'job-graph-empty';
+        empty.textContent = 'Nothing finished in this window.';
+        figure.append(empty);
+    }
+    return figure;
+}
+
+async function load() {
+    const params = new URLSearchParams({ window: container.dataset.window });
+    if (container.dataset.repository) {
+        params.set('repository', container.dataset.repository);
+    }
+    try {
+        const response = await fetch(`/api/stats?${params}`);
+        if (!response.ok) {
+            throw new Error(`${response.status} ${response.statusText}`);
+        }
+        const report = await response.json();
+        container.replaceChildren(...CHARTS.map(options => chart(report, options)));
+    } catch (error) {
+        const failed = document.createElement('p');
+        failed.className = 'job-graph-empty';
+        failed.textContent = `Failed to load the statistics: ${error.message}`;
+        container.replaceChildren(failed);
+    }
+}
+
+load();